@@ -107,6 +107,7 @@ export interface components {
       capabilities: components["schemas"]["AgentCapabilities"];
       configError?: string | null;
       configOptions?: unknown[] | null;
+      credentialCheck?: components["schemas"]["CredentialCheck"] | null;
       credentialsAvailable: boolean;
       id: string;
       installed: boolean;
@@ -132,8 +133,18 @@ export interface components {
     AgentListResponse: {
       agents: components["schemas"]["AgentInfo"][];
     };
+    /** @description Result of the startup credential check for one agent. */
+    CredentialCheck: {
+      /** Format: int64 */
+      checkedAtMs: number;
+      message?: string | null;
+      provider?: string | null;
+      status: components["schemas"]["CredentialCheckStatus"];
+    };
     /** @enum {string} */
-    ErrorType: "invalid_request" | "conflict" | "unsupported_agent" | "agent_not_installed" | "install_failed" | "agent_process_exited" | "token_invalid" | "permission_denied" | "not_acceptable" | "unsupported_media_type" | "session_not_found" | "session_already_exists" | "mode_not_supported" | "stream_error" | "timeout";
+    CredentialCheckStatus: "valid" | "invalid" | "missing" | "unverified" | "error";
+    /** @enum {string} */
+    ErrorType: "invalid_request" | "conflict" | "unsupported_agent" | "agent_not_installed" | "install_failed" | "agent_process_exited" | "token_invalid" | "permission_denied" | "not_acceptable" | "unsupported_media_type" | "session_not_found" | "session_already_exists" | "mode_not_supported" | "stream_error" | "timeout" | "resource_limit_exceeded";
     FsActionResponse: {
       path: string;
     };
@@ -186,6 +197,13 @@ export interface components {
       path: string;
     };
     HealthResponse: {
+      /**
+       * @description Credential check results by agent id; present when the server was
+       * started with `--validate-agents`.
+       */
+      agentCredentials?: {
+        [key: string]: components["schemas"]["CredentialCheck"];
+      } | null;
       status: string;
     };
     McpConfigQuery: {
@@ -108,6 +108,11 @@ fn map_error(err: AdapterError) -> Response {
             "spawn_failed",
             &format!("failed to start agent process: {spawn}"),
         ),
+        AdapterError::Limits(limits) => problem(
+            StatusCode::BAD_GATEWAY,
+            "limits_failed",
+            &format!("failed to prepare agent resource limits: {limits}"),
+        ),
         AdapterError::MissingStdin | AdapterError::MissingStdout | AdapterError::MissingStderr => {
             problem(
                 StatusCode::BAD_GATEWAY,
@@ -3,10 +3,12 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use app::build_router;
+use limits::ResourceLimits;
 use process::AdapterRuntime;
 use registry::LaunchSpec;
 
 pub mod app;
+pub mod limits;
 pub mod process;
 pub mod registry;
 
@@ -17,13 +19,15 @@ pub struct ServerConfig {
     pub registry_json: String,
     pub registry_agent_id: Option<String>,
     pub rpc_timeout: Duration,
+    pub limits: ResourceLimits,
 }
 
 pub async fn run_server(
     config: ServerConfig,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let launch =
+    let mut launch =
         LaunchSpec::from_registry_blob(&config.registry_json, config.registry_agent_id.as_deref())?;
+    launch.limits = config.limits;
     let runtime = Arc::new(AdapterRuntime::start(launch, config.rpc_timeout).await?);
     run_server_with_runtime(config.host, config.port, runtime).await
 }
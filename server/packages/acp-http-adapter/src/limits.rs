@@ -0,0 +1,513 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use tokio::process::Command;
+
+/// Lowercased output fragments that mean the agent ran into one of its
+/// configured limits. Only checked for limits that are actually set.
+const LIMIT_SIGNATURES: &[(&str, LimitKind)] = &[
+    ("too many open files", LimitKind::OpenFiles),
+    ("emfile", LimitKind::OpenFiles),
+    ("out of memory", LimitKind::Memory),
+    ("cannot allocate memory", LimitKind::Memory),
+    ("enomem", LimitKind::Memory),
+    ("memoryerror", LimitKind::Memory),
+    ("allocation failed", LimitKind::Memory),
+    ("no space left on device", LimitKind::ScratchDisk),
+    ("enospc", LimitKind::ScratchDisk),
+];
+
+static SCRATCH_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Resource limits applied to an agent process when it is spawned.
+///
+/// Every field is optional; unset fields leave the process unrestricted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ResourceLimits {
+    /// Relative CPU weight written to cgroup v2 `cpu.weight` (1-10000,
+    /// default 100). Only enforced when `cgroup_parent` is set.
+    #[serde(default)]
+    pub cpu_shares: Option<u64>,
+    /// Memory cap in bytes. Written to `memory.max` when `cgroup_parent` is
+    /// set, otherwise applied as `RLIMIT_DATA`.
+    #[serde(default)]
+    pub memory_bytes: Option<u64>,
+    /// Maximum open file descriptors (`RLIMIT_NOFILE`).
+    #[serde(default)]
+    pub max_open_files: Option<u64>,
+    /// Size cap for the per-process scratch directory exported as `TMPDIR`.
+    /// The process is killed once the directory grows past it.
+    #[serde(default)]
+    pub scratch_quota_bytes: Option<u64>,
+    /// Delegated cgroup v2 directory; each agent process gets its own child
+    /// cgroup underneath it.
+    #[serde(default)]
+    pub cgroup_parent: Option<PathBuf>,
+    /// Niceness of the process (-20 to 19). A value below the server's own
+    /// needs `CAP_SYS_NICE`; without it the process keeps the server's.
+    #[serde(default)]
+    pub nice: Option<i32>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Fields set in `overrides` replace the ones in `self`.
+    pub fn merged(&self, overrides: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            cpu_shares: overrides.cpu_shares.or(self.cpu_shares),
+            memory_bytes: overrides.memory_bytes.or(self.memory_bytes),
+            max_open_files: overrides.max_open_files.or(self.max_open_files),
+            scratch_quota_bytes: overrides.scratch_quota_bytes.or(self.scratch_quota_bytes),
+            cgroup_parent: overrides
+                .cgroup_parent
+                .clone()
+                .or_else(|| self.cgroup_parent.clone()),
+            nice: overrides.nice.or(self.nice),
+        }
+    }
+
+    fn is_set(&self, kind: LimitKind) -> bool {
+        match kind {
+            LimitKind::Memory => self.memory_bytes.is_some(),
+            LimitKind::OpenFiles => self.max_open_files.is_some(),
+            LimitKind::ScratchDisk => self.scratch_quota_bytes.is_some(),
+        }
+    }
+
+    fn configured(&self, kind: LimitKind) -> Option<u64> {
+        match kind {
+            LimitKind::Memory => self.memory_bytes,
+            LimitKind::OpenFiles => self.max_open_files,
+            LimitKind::ScratchDisk => self.scratch_quota_bytes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    Memory,
+    OpenFiles,
+    ScratchDisk,
+}
+
+impl LimitKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Memory => "memory",
+            Self::OpenFiles => "open_files",
+            Self::ScratchDisk => "scratch_disk",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        match self {
+            Self::Memory => 1,
+            Self::OpenFiles => 2,
+            Self::ScratchDisk => 4,
+        }
+    }
+}
+
+/// Match a line of agent output against [`LIMIT_SIGNATURES`].
+pub fn limit_exceeded_signature(line: &str) -> Option<LimitKind> {
+    let line = line.to_ascii_lowercase();
+    LIMIT_SIGNATURES
+        .iter()
+        .find(|(fragment, _)| line.contains(fragment))
+        .map(|(_, kind)| *kind)
+}
+
+/// A limit breach detected for a running process.
+#[derive(Debug, Clone)]
+pub(crate) struct LimitBreach {
+    pub kind: LimitKind,
+    pub source: &'static str,
+    pub detail: String,
+    pub configured: Option<u64>,
+    /// Whether the process has to be killed to enforce the limit.
+    pub kill: bool,
+}
+
+/// The limits applied to one spawned process, plus the scratch directory and
+/// cgroup created for it.
+#[derive(Debug)]
+pub(crate) struct AppliedLimits {
+    limits: ResourceLimits,
+    scratch_dir: Option<PathBuf>,
+    cgroup_dir: Option<PathBuf>,
+    reported: AtomicU8,
+}
+
+impl AppliedLimits {
+    /// Create the scratch directory and install the pre-exec rlimits on
+    /// `command`. Cgroup placement happens after spawn in
+    /// [`AppliedLimits::attach`].
+    pub(crate) fn prepare(limits: ResourceLimits, command: &mut Command) -> std::io::Result<Self> {
+        let scratch_dir = match limits.scratch_quota_bytes {
+            Some(_) => {
+                let dir = scratch_root().join(format!(
+                    "{}-{}-{}",
+                    std::process::id(),
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_millis())
+                        .unwrap_or(0),
+                    SCRATCH_COUNTER.fetch_add(1, Ordering::Relaxed)
+                ));
+                std::fs::create_dir_all(&dir)?;
+                for key in ["TMPDIR", "TMP", "TEMP"] {
+                    command.env(key, &dir);
+                }
+                Some(dir)
+            }
+            None => None,
+        };
+
+        #[cfg(unix)]
+        {
+            let nofile = limits.max_open_files;
+            let data = limits
+                .memory_bytes
+                .filter(|_| limits.cgroup_parent.is_none());
+            let nice = limits.nice;
+            if nofile.is_some() || data.is_some() || nice.is_some() {
+                // SAFETY: the closure only calls the async-signal-safe
+                // setrlimit and setpriority.
+                unsafe {
+                    command.pre_exec(move || {
+                        if let Some(nice) = nice {
+                            // Best effort: raising priority may be refused.
+                            libc::setpriority(libc::PRIO_PROCESS, 0, nice.clamp(-20, 19));
+                        }
+                        if let Some(limit) = nofile {
+                            set_rlimit(libc::RLIMIT_NOFILE, limit)?;
+                        }
+                        if let Some(limit) = data {
+                            set_rlimit(libc::RLIMIT_DATA, limit)?;
+                        }
+                        Ok(())
+                    });
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        if limits.max_open_files.is_some() || limits.memory_bytes.is_some() || limits.nice.is_some()
+        {
+            tracing::warn!("process rlimits are not supported on this platform; ignoring");
+        }
+
+        if limits.cpu_shares.is_some() && limits.cgroup_parent.is_none() {
+            tracing::warn!("cpuShares requires cgroupParent; CPU weight will not be applied");
+        }
+
+        Ok(Self {
+            limits,
+            scratch_dir,
+            cgroup_dir: None,
+            reported: AtomicU8::new(0),
+        })
+    }
+
+    /// Move the freshly spawned process into its own cgroup under
+    /// `cgroup_parent` and write the memory and CPU controls. The process
+    /// runs unconstrained for the few instructions before the move; agents
+    /// spend far longer than that starting up.
+    pub(crate) fn attach(&mut self, pid: u32) {
+        let Some(parent) = self.limits.cgroup_parent.clone() else {
+            return;
+        };
+        if self.limits.memory_bytes.is_none() && self.limits.cpu_shares.is_none() {
+            return;
+        }
+        let dir = parent.join(format!("agent-{pid}"));
+        let result = (|| -> std::io::Result<()> {
+            std::fs::create_dir_all(&dir)?;
+            if let Some(memory) = self.limits.memory_bytes {
+                std::fs::write(dir.join("memory.max"), memory.to_string())?;
+                // Swap would let the process sail past the cap unnoticed.
+                let _ = std::fs::write(dir.join("memory.swap.max"), "0");
+            }
+            if let Some(weight) = self.limits.cpu_shares {
+                std::fs::write(dir.join("cpu.weight"), weight.clamp(1, 10_000).to_string())?;
+            }
+            std::fs::write(dir.join("cgroup.procs"), pid.to_string())
+        })();
+        match result {
+            Ok(()) => self.cgroup_dir = Some(dir),
+            Err(err) => {
+                tracing::warn!(
+                    cgroup = %dir.display(),
+                    error = %err,
+                    "failed to place agent process in cgroup; memory and CPU limits not applied"
+                );
+                let _ = std::fs::remove_dir(&dir);
+            }
+        }
+    }
+
+    pub(crate) fn needs_polling(&self) -> bool {
+        self.scratch_dir.is_some() || self.cgroup_dir.is_some()
+    }
+
+    /// Interpret a line of agent output as a limit breach, if it mentions a
+    /// limit that is configured for this process.
+    pub(crate) fn check_output(&self, source: &'static str, line: &str) -> Option<LimitBreach> {
+        let kind = limit_exceeded_signature(line)?;
+        if !self.limits.is_set(kind) {
+            return None;
+        }
+        Some(LimitBreach {
+            kind,
+            source,
+            detail: line.trim().to_string(),
+            configured: self.limits.configured(kind),
+            kill: false,
+        })
+    }
+
+    /// Check the scratch directory size and the cgroup OOM counter.
+    pub(crate) fn poll(&self) -> Option<LimitBreach> {
+        if let (Some(dir), Some(quota)) = (&self.scratch_dir, self.limits.scratch_quota_bytes) {
+            let used = dir_size(dir);
+            if used > quota {
+                return Some(LimitBreach {
+                    kind: LimitKind::ScratchDisk,
+                    source: "scratch",
+                    detail: format!(
+                        "scratch directory {} uses {used} bytes (quota {quota})",
+                        dir.display()
+                    ),
+                    configured: Some(quota),
+                    kill: true,
+                });
+            }
+        }
+        if let Some(dir) = &self.cgroup_dir {
+            let kills = std::fs::read_to_string(dir.join("memory.events"))
+                .ok()
+                .and_then(|events| {
+                    events.lines().find_map(|line| {
+                        line.strip_prefix("oom_kill ")
+                            .and_then(|count| count.trim().parse::<u64>().ok())
+                    })
+                })
+                .unwrap_or(0);
+            if kills > 0 {
+                return Some(LimitBreach {
+                    kind: LimitKind::Memory,
+                    source: "cgroup",
+                    detail: format!("cgroup {} recorded {kills} OOM kill(s)", dir.display()),
+                    configured: self.limits.memory_bytes,
+                    kill: false,
+                });
+            }
+        }
+        None
+    }
+
+    /// Returns true the first time `kind` is reported for this process.
+    pub(crate) fn mark_reported(&self, kind: LimitKind) -> bool {
+        self.reported.fetch_or(kind.bit(), Ordering::SeqCst) & kind.bit() == 0
+    }
+
+    /// Remove the scratch directory and the (now empty) cgroup.
+    pub(crate) fn cleanup(&self) {
+        if let Some(dir) = &self.scratch_dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        if let Some(dir) = &self.cgroup_dir {
+            let _ = std::fs::remove_dir(dir);
+        }
+    }
+}
+
+fn scratch_root() -> PathBuf {
+    std::env::temp_dir().join("acp-scratch")
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_dir() => dir_size(&entry.path()),
+            Ok(kind) if kind.is_file() => entry.metadata().map(|m| m.len()).unwrap_or(0),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(all(unix, not(all(target_os = "linux", target_env = "gnu"))))]
+type RlimitResource = libc::c_int;
+
+/// Lower both the soft and hard limit to `limit`, never above the current
+/// hard limit (raising it would need privileges we don't assume).
+#[cfg(unix)]
+fn set_rlimit(resource: RlimitResource, limit: u64) -> std::io::Result<()> {
+    let mut value = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(resource, &mut value) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let limit = (limit as libc::rlim_t).min(value.rlim_max);
+    value.rlim_cur = limit;
+    value.rlim_max = limit;
+    if unsafe { libc::setrlimit(resource, &value) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Send SIGKILL to `pid` without taking the child lock the exit watcher holds.
+/// On Windows the whole process tree is terminated, since agents installed
+/// through npm run behind a `.cmd` shim whose `node` child would survive.
+pub(crate) fn kill_pid(pid: u32) {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGKILL);
+    }
+    #[cfg(windows)]
+    {
+        let _ = std::process::Command::new("taskkill")
+            .args(["/PID", &pid.to_string(), "/T", "/F"])
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status();
+    }
+    #[cfg(not(any(unix, windows)))]
+    let _ = pid;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_limits(quota: u64) -> (AppliedLimits, PathBuf) {
+        let limits = ResourceLimits {
+            scratch_quota_bytes: Some(quota),
+            ..ResourceLimits::default()
+        };
+        let mut command = Command::new("true");
+        let applied = AppliedLimits::prepare(limits, &mut command).expect("prepare limits");
+        let dir = applied.scratch_dir.clone().expect("scratch dir");
+        let tmpdir = command
+            .as_std()
+            .get_envs()
+            .find(|(key, _)| *key == "TMPDIR")
+            .and_then(|(_, value)| value.map(PathBuf::from));
+        assert_eq!(tmpdir.as_ref(), Some(&dir));
+        (applied, dir)
+    }
+
+    #[test]
+    fn overrides_replace_only_the_fields_they_set() {
+        let defaults = ResourceLimits {
+            memory_bytes: Some(1 << 30),
+            max_open_files: Some(1024),
+            cgroup_parent: Some(PathBuf::from("/sys/fs/cgroup/agents")),
+            nice: Some(5),
+            ..ResourceLimits::default()
+        };
+        let overrides = ResourceLimits {
+            memory_bytes: Some(1 << 20),
+            scratch_quota_bytes: Some(4096),
+            ..ResourceLimits::default()
+        };
+        let merged = defaults.merged(&overrides);
+        assert_eq!(
+            merged,
+            ResourceLimits {
+                cpu_shares: None,
+                memory_bytes: Some(1 << 20),
+                max_open_files: Some(1024),
+                scratch_quota_bytes: Some(4096),
+                cgroup_parent: Some(PathBuf::from("/sys/fs/cgroup/agents")),
+                nice: Some(5),
+            }
+        );
+        assert_eq!(defaults.merged(&ResourceLimits::default()), defaults);
+        assert!(ResourceLimits::default().is_empty());
+        assert!(!merged.is_empty());
+    }
+
+    #[test]
+    fn limit_signatures_match_case_insensitively() {
+        assert_eq!(
+            limit_exceeded_signature("Error: EMFILE: Too many open files, open '/tmp/x'"),
+            Some(LimitKind::OpenFiles)
+        );
+        assert_eq!(
+            limit_exceeded_signature("FATAL ERROR: Reached heap limit Allocation failed"),
+            Some(LimitKind::Memory)
+        );
+        assert_eq!(
+            limit_exceeded_signature("write /tmp/out: no space left on device"),
+            Some(LimitKind::ScratchDisk)
+        );
+        assert_eq!(limit_exceeded_signature("reading 12 files"), None);
+    }
+
+    #[test]
+    fn output_only_counts_as_a_breach_for_configured_limits() {
+        let mut command = Command::new("true");
+        let limits = ResourceLimits {
+            max_open_files: Some(64),
+            ..ResourceLimits::default()
+        };
+        let applied = AppliedLimits::prepare(limits, &mut command).expect("prepare limits");
+        let breach = applied
+            .check_output("stderr", "  EMFILE: too many open files  ")
+            .expect("open files breach");
+        assert_eq!(breach.kind, LimitKind::OpenFiles);
+        assert_eq!(breach.source, "stderr");
+        assert_eq!(breach.detail, "EMFILE: too many open files");
+        assert_eq!(breach.configured, Some(64));
+        assert!(!breach.kill);
+        assert!(applied
+            .check_output("stderr", "JavaScript heap out of memory")
+            .is_none());
+        assert!(!applied.needs_polling());
+    }
+
+    #[test]
+    fn a_scratch_directory_over_quota_is_a_breach_that_kills() {
+        let (applied, dir) = scratch_limits(1024);
+        assert!(applied.needs_polling());
+        std::fs::create_dir_all(dir.join("nested")).expect("create nested dir");
+        std::fs::write(dir.join("nested/small"), vec![0u8; 512]).expect("write file");
+        assert!(applied.poll().is_none());
+
+        std::fs::write(dir.join("large"), vec![0u8; 1024]).expect("write file");
+        let breach = applied.poll().expect("scratch breach");
+        assert_eq!(breach.kind, LimitKind::ScratchDisk);
+        assert_eq!(breach.source, "scratch");
+        assert_eq!(breach.configured, Some(1024));
+        assert!(breach.kill);
+        assert!(breach.detail.contains("1536 bytes"), "{}", breach.detail);
+
+        applied.cleanup();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn each_limit_is_reported_once() {
+        let (applied, _dir) = scratch_limits(1);
+        assert!(applied.mark_reported(LimitKind::ScratchDisk));
+        assert!(!applied.mark_reported(LimitKind::ScratchDisk));
+        assert!(applied.mark_reported(LimitKind::Memory));
+        assert!(!applied.mark_reported(LimitKind::Memory));
+        applied.cleanup();
+    }
+}
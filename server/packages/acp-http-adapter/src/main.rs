@@ -1,5 +1,6 @@
 use std::time::Duration;
 
+use acp_http_adapter::limits::ResourceLimits;
 use acp_http_adapter::{run_server, ServerConfig};
 use clap::Parser;
 
@@ -21,6 +22,11 @@ struct Cli {
 
     #[arg(long)]
     rpc_timeout_ms: Option<u64>,
+
+    /// Resource limits for the agent process as JSON, e.g.
+    /// `{"memoryBytes":2147483648,"maxOpenFiles":1024}`.
+    #[arg(long)]
+    limits_json: Option<String>,
 }
 
 #[tokio::main]
@@ -41,6 +47,10 @@ async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .init();
 
     let cli = Cli::parse();
+    let limits = match cli.limits_json.as_deref() {
+        Some(raw) => serde_json::from_str(raw)?,
+        None => ResourceLimits::default(),
+    };
     run_server(ServerConfig {
         host: cli.host,
         port: cli.port,
@@ -50,6 +60,7 @@ async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             .rpc_timeout_ms
             .map(Duration::from_millis)
             .unwrap_or_else(|| Duration::from_secs(120)),
+        limits,
     })
     .await
 }
@@ -1,7 +1,7 @@
 use std::collections::{HashMap, VecDeque};
 use std::convert::Infallible;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 
 use axum::response::sse::Event;
@@ -13,14 +13,42 @@ use tokio::process::{Child, ChildStdin, Command};
 use tokio::sync::{broadcast, oneshot, Mutex};
 use tokio_stream::wrappers::BroadcastStream;
 
+use crate::limits::{AppliedLimits, LimitBreach};
 use crate::registry::LaunchSpec;
 
 const RING_BUFFER_SIZE: usize = 1024;
+const LIMIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Lowercased output fragments that mean the agent could not authenticate
+/// with its model provider, paired with a stable signature name.
+const AUTH_FAILURE_SIGNATURES: &[(&str, &str)] = &[
+    ("invalid api key", "invalid_api_key"),
+    ("invalid x-api-key", "invalid_api_key"),
+    ("incorrect api key", "invalid_api_key"),
+    ("invalid_api_key", "invalid_api_key"),
+    ("authentication_error", "authentication_error"),
+    ("oauth token has expired", "token_expired"),
+    ("token has expired", "token_expired"),
+    ("failed to refresh token", "token_expired"),
+    ("could not refresh token", "token_expired"),
+    ("invalid bearer token", "token_invalid"),
+    ("please run /login", "login_required"),
+    ("not logged in", "login_required"),
+    ("codex login", "login_required"),
+    ("missing api key", "api_key_missing"),
+    ("api key not found", "api_key_missing"),
+    ("no api key", "api_key_missing"),
+    ("401 unauthorized", "unauthorized"),
+    ("status code 401", "unauthorized"),
+    ("status: 401", "unauthorized"),
+];
 
 #[derive(Debug, Error)]
 pub enum AdapterError {
     #[error("failed to spawn subprocess: {0}")]
     Spawn(std::io::Error),
+    #[error("failed to prepare resource limits: {0}")]
+    Limits(std::io::Error),
     #[error("failed to capture subprocess stdin")]
     MissingStdin,
     #[error("failed to capture subprocess stdout")]
@@ -61,6 +89,11 @@ pub struct AdapterRuntime {
     shutting_down: AtomicBool,
     spawned_at: Instant,
     first_stdout: Arc<AtomicBool>,
+    auth_failure_reported: Arc<AtomicBool>,
+    limits: Arc<AppliedLimits>,
+    pid: u32,
+    exited: Arc<AtomicBool>,
+    correlation_id: Arc<RwLock<Option<String>>>,
 }
 
 impl AdapterRuntime {
@@ -80,10 +113,16 @@ impl AdapterRuntime {
         for (key, value) in &launch.env {
             command.env(key, value);
         }
+        let mut limits =
+            AppliedLimits::prepare(launch.limits.clone(), &mut command).map_err(|err| {
+                tracing::error!(error = %err, "failed to prepare agent resource limits");
+                AdapterError::Limits(err)
+            })?;
 
         tracing::info!(
             program = ?launch.program,
             args = ?launch.args,
+            limits = ?launch.limits,
             "spawning agent process"
         );
 
@@ -93,10 +132,12 @@ impl AdapterRuntime {
                 error = %err,
                 "failed to spawn agent process"
             );
+            limits.cleanup();
             AdapterError::Spawn(err)
         })?;
 
         let pid = child.id().unwrap_or(0);
+        limits.attach(pid);
         let spawn_elapsed = spawn_start.elapsed();
         tracing::info!(
             pid = pid,
@@ -120,11 +161,17 @@ impl AdapterRuntime {
             shutting_down: AtomicBool::new(false),
             spawned_at: spawn_start,
             first_stdout: Arc::new(AtomicBool::new(false)),
+            auth_failure_reported: Arc::new(AtomicBool::new(false)),
+            limits: Arc::new(limits),
+            pid,
+            exited: Arc::new(AtomicBool::new(false)),
+            correlation_id: Arc::new(RwLock::new(None)),
         };
 
         runtime.spawn_stdout_loop(stdout);
         runtime.spawn_stderr_loop(stderr);
         runtime.spawn_exit_watcher();
+        runtime.spawn_limit_watcher();
 
         Ok(runtime)
     }
@@ -293,6 +340,55 @@ impl AdapterRuntime {
         replay_stream.chain(live_stream)
     }
 
+    /// Like [`value_stream`](Self::value_stream), with each payload's
+    /// sequence number so a consumer can resume after the last one it handled.
+    pub async fn sequenced_value_stream(
+        self: Arc<Self>,
+        last_event_id: Option<u64>,
+    ) -> impl Stream<Item = (u64, Value)> + Send + 'static {
+        let (replay, rx) = self.subscribe(last_event_id).await;
+        let live_stream = BroadcastStream::new(rx).filter_map(|item| async move {
+            match item {
+                Ok(message) => Some((message.sequence, message.payload)),
+                Err(_) => None,
+            }
+        });
+        stream::iter(replay).chain(live_stream)
+    }
+
+    /// Publish a runtime-originated notification to stream subscribers as if
+    /// it had been emitted by the agent process.
+    pub async fn publish(&self, payload: Value) {
+        let seq = self.sequence.fetch_add(1, Ordering::SeqCst) + 1;
+        let message = StreamMessage {
+            sequence: seq,
+            payload,
+        };
+
+        {
+            let mut guard = self.ring.lock().await;
+            guard.push_back(message.clone());
+            while guard.len() > RING_BUFFER_SIZE {
+                guard.pop_front();
+            }
+        }
+
+        let _ = self.sender.send(message);
+    }
+
+    /// Tag subsequent agent output log lines with `correlation_id` so they
+    /// can be matched to the request that caused them.
+    pub fn set_correlation_id(&self, correlation_id: Option<String>) {
+        if let Ok(mut guard) = self.correlation_id.write() {
+            *guard = correlation_id;
+        }
+    }
+
+    /// OS process ID of the agent, `0` if it was not known at spawn.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
     pub async fn shutdown(&self) {
         if self.shutting_down.swap(true, Ordering::SeqCst) {
             return;
@@ -308,6 +404,9 @@ impl AdapterRuntime {
         match child.try_wait() {
             Ok(Some(_)) => {}
             Ok(None) => {
+                // Killing the child alone would orphan the shim's children.
+                #[cfg(windows)]
+                crate::limits::kill_pid(self.pid);
                 let _ = child.kill().await;
                 let _ = child.wait().await;
             }
@@ -324,6 +423,10 @@ impl AdapterRuntime {
         let sequence = self.sequence.clone();
         let spawned_at = self.spawned_at;
         let first_stdout = self.first_stdout.clone();
+        let auth_failure_reported = self.auth_failure_reported.clone();
+        let limits = self.limits.clone();
+        let pid = self.pid;
+        let correlation_id = self.correlation_id.clone();
 
         tokio::spawn(async move {
             let mut lines = BufReader::new(stdout).lines();
@@ -351,6 +454,7 @@ impl AdapterRuntime {
                         tracing::warn!(
                             error = %err,
                             line_number = line_count,
+                            correlation_id = current_correlation_id(&correlation_id).as_deref(),
                             raw = %if trimmed.len() > 200 {
                                 format!("{}...", &trimmed[..200])
                             } else {
@@ -358,6 +462,19 @@ impl AdapterRuntime {
                             },
                             "agent stdout: invalid JSON"
                         );
+                        report_auth_failure(
+                            &auth_failure_reported,
+                            &ring,
+                            &sender,
+                            &sequence,
+                            "stdout",
+                            trimmed,
+                        )
+                        .await;
+                        if let Some(breach) = limits.check_output("stdout", trimmed) {
+                            report_limit_exceeded(&limits, &ring, &sender, &sequence, pid, breach)
+                                .await;
+                        }
                         json!({
                             "jsonrpc": "2.0",
                             "method": "_adapter/invalid_stdout",
@@ -445,6 +562,13 @@ impl AdapterRuntime {
 
     fn spawn_stderr_loop(&self, stderr: tokio::process::ChildStderr) {
         let spawned_at = self.spawned_at;
+        let sender = self.sender.clone();
+        let ring = self.ring.clone();
+        let sequence = self.sequence.clone();
+        let auth_failure_reported = self.auth_failure_reported.clone();
+        let limits = self.limits.clone();
+        let pid = self.pid;
+        let correlation_id = self.correlation_id.clone();
 
         tokio::spawn(async move {
             let mut lines = BufReader::new(stderr).lines();
@@ -455,9 +579,22 @@ impl AdapterRuntime {
                 tracing::info!(
                     line_number = line_count,
                     age_ms = spawned_at.elapsed().as_millis() as u64,
+                    correlation_id = current_correlation_id(&correlation_id).as_deref(),
                     "agent stderr: {}",
                     line
                 );
+                report_auth_failure(
+                    &auth_failure_reported,
+                    &ring,
+                    &sender,
+                    &sequence,
+                    "stderr",
+                    &line,
+                )
+                .await;
+                if let Some(breach) = limits.check_output("stderr", &line) {
+                    report_limit_exceeded(&limits, &ring, &sender, &sequence, pid, breach).await;
+                }
             }
 
             tracing::debug!(
@@ -475,12 +612,24 @@ impl AdapterRuntime {
         let sequence = self.sequence.clone();
         let spawned_at = self.spawned_at;
         let pending = self.pending.clone();
+        let limits = self.limits.clone();
+        let pid = self.pid;
+        let exited = self.exited.clone();
 
         tokio::spawn(async move {
             let status = {
                 let mut guard = child.lock().await;
                 guard.wait().await.ok()
             };
+            exited.store(true, Ordering::SeqCst);
+
+            // Report an OOM kill before the exit so subscribers can attribute it.
+            let final_check = limits.clone();
+            if let Ok(Some(breach)) = tokio::task::spawn_blocking(move || final_check.poll()).await
+            {
+                report_limit_exceeded(&limits, &ring, &sender, &sequence, pid, breach).await;
+            }
+            limits.cleanup();
 
             let age_ms = spawned_at.elapsed().as_millis() as u64;
             let pending_count = pending.lock().await.len();
@@ -528,6 +677,37 @@ impl AdapterRuntime {
         });
     }
 
+    /// Poll the scratch directory size and cgroup OOM counter while the
+    /// process runs. Rlimit breaches are picked up from agent output instead.
+    fn spawn_limit_watcher(&self) {
+        if !self.limits.needs_polling() {
+            return;
+        }
+        let limits = self.limits.clone();
+        let sender = self.sender.clone();
+        let ring = self.ring.clone();
+        let sequence = self.sequence.clone();
+        let pid = self.pid;
+        let exited = self.exited.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(LIMIT_POLL_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            while !exited.load(Ordering::SeqCst) {
+                interval.tick().await;
+                let check = limits.clone();
+                let Ok(Some(breach)) = tokio::task::spawn_blocking(move || check.poll()).await
+                else {
+                    continue;
+                };
+                if exited.load(Ordering::SeqCst) {
+                    break;
+                }
+                report_limit_exceeded(&limits, &ring, &sender, &sequence, pid, breach).await;
+            }
+        });
+    }
+
     async fn send_to_subprocess(&self, payload: &Value) -> Result<(), AdapterError> {
         let method = payload
             .get("method")
@@ -562,6 +742,223 @@ impl AdapterRuntime {
     }
 }
 
+fn current_correlation_id(correlation_id: &RwLock<Option<String>>) -> Option<String> {
+    correlation_id.read().ok().and_then(|guard| guard.clone())
+}
+
+/// Match a line of agent output against [`AUTH_FAILURE_SIGNATURES`].
+pub fn auth_failure_signature(line: &str) -> Option<&'static str> {
+    let line = line.to_ascii_lowercase();
+    AUTH_FAILURE_SIGNATURES
+        .iter()
+        .find(|(fragment, _)| line.contains(fragment))
+        .map(|(_, signature)| *signature)
+}
+
+/// Publish `_adapter/auth_failed` the first time agent output looks like an
+/// authentication failure.
+async fn report_auth_failure(
+    reported: &AtomicBool,
+    ring: &Mutex<VecDeque<StreamMessage>>,
+    sender: &broadcast::Sender<StreamMessage>,
+    sequence: &AtomicU64,
+    source: &str,
+    line: &str,
+) {
+    let Some(signature) = auth_failure_signature(line) else {
+        return;
+    };
+    if reported.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    tracing::warn!(
+        source,
+        signature,
+        "agent output indicates an authentication failure"
+    );
+
+    let message = StreamMessage {
+        sequence: sequence.fetch_add(1, Ordering::SeqCst) + 1,
+        payload: json!({
+            "jsonrpc": "2.0",
+            "method": "_adapter/auth_failed",
+            "params": {
+                "source": source,
+                "signature": signature,
+                "line": line.trim(),
+            }
+        }),
+    };
+    {
+        let mut guard = ring.lock().await;
+        guard.push_back(message.clone());
+        while guard.len() > RING_BUFFER_SIZE {
+            guard.pop_front();
+        }
+    }
+    let _ = sender.send(message);
+}
+
+/// Publish `_adapter/limit_exceeded` the first time a limit is breached, and
+/// kill the process when the limit can only be enforced that way.
+async fn report_limit_exceeded(
+    limits: &AppliedLimits,
+    ring: &Mutex<VecDeque<StreamMessage>>,
+    sender: &broadcast::Sender<StreamMessage>,
+    sequence: &AtomicU64,
+    pid: u32,
+    breach: LimitBreach,
+) {
+    if !limits.mark_reported(breach.kind) {
+        return;
+    }
+    tracing::warn!(
+        limit = breach.kind.as_str(),
+        source = breach.source,
+        detail = %breach.detail,
+        kill = breach.kill,
+        "agent process exceeded a resource limit"
+    );
+    if breach.kill {
+        crate::limits::kill_pid(pid);
+    }
+
+    let message = StreamMessage {
+        sequence: sequence.fetch_add(1, Ordering::SeqCst) + 1,
+        payload: json!({
+            "jsonrpc": "2.0",
+            "method": "_adapter/limit_exceeded",
+            "params": {
+                "limit": breach.kind.as_str(),
+                "source": breach.source,
+                "detail": breach.detail,
+                "configured": breach.configured,
+                "killed": breach.kill,
+            }
+        }),
+    };
+    {
+        let mut guard = ring.lock().await;
+        guard.push_back(message.clone());
+        while guard.len() > RING_BUFFER_SIZE {
+            guard.pop_front();
+        }
+    }
+    let _ = sender.send(message);
+}
+
 fn id_key(value: &Value) -> String {
     serde_json::to_string(value).unwrap_or_else(|_| "null".to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::ResourceLimits;
+
+    fn ring_methods(ring: &VecDeque<StreamMessage>) -> Vec<&str> {
+        ring.iter()
+            .filter_map(|message| message.payload["method"].as_str())
+            .collect()
+    }
+
+    #[test]
+    fn auth_failures_are_recognised_by_signature() {
+        assert_eq!(
+            auth_failure_signature("Error: 401 {\"type\":\"authentication_error\"}"),
+            Some("authentication_error")
+        );
+        assert_eq!(
+            auth_failure_signature("API Error: Invalid API key · Please run /login"),
+            Some("invalid_api_key")
+        );
+        assert_eq!(
+            auth_failure_signature("OAuth token has expired. Please obtain a new token"),
+            Some("token_expired")
+        );
+        assert_eq!(
+            auth_failure_signature("Not logged in; run `codex login`"),
+            Some("login_required")
+        );
+        assert_eq!(
+            auth_failure_signature("request failed: status: 401"),
+            Some("unauthorized")
+        );
+        assert_eq!(
+            auth_failure_signature("loaded 401 files from the index"),
+            None
+        );
+        assert_eq!(auth_failure_signature("rotating the api key cache"), None);
+        assert_eq!(auth_failure_signature(""), None);
+    }
+
+    #[tokio::test]
+    async fn an_auth_failure_is_reported_once_per_process() {
+        let reported = AtomicBool::new(false);
+        let ring = Mutex::new(VecDeque::new());
+        let (sender, mut receiver) = broadcast::channel(16);
+        let sequence = AtomicU64::new(0);
+        for (source, line) in [
+            ("stderr", "compiling 12 crates"),
+            ("stderr", "  Invalid API key  "),
+            ("stdout", "OAuth token has expired"),
+            ("stderr", "Invalid API key"),
+        ] {
+            report_auth_failure(&reported, &ring, &sender, &sequence, source, line).await;
+        }
+
+        let message = receiver.try_recv().expect("auth failure reported");
+        assert_eq!(message.sequence, 1);
+        assert_eq!(
+            message.payload["params"],
+            json!({"source": "stderr", "signature": "invalid_api_key", "line": "Invalid API key"})
+        );
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(ring_methods(&*ring.lock().await), ["_adapter/auth_failed"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_scratch_breach_kills_the_process_and_is_reported_once() {
+        let mut command = Command::new("sleep");
+        command.arg("30");
+        let limits = ResourceLimits {
+            scratch_quota_bytes: Some(16),
+            ..ResourceLimits::default()
+        };
+        let applied = AppliedLimits::prepare(limits, &mut command).expect("prepare limits");
+        let scratch = command
+            .as_std()
+            .get_envs()
+            .find(|(key, _)| *key == "TMPDIR")
+            .and_then(|(_, value)| value.map(std::path::PathBuf::from))
+            .expect("scratch dir");
+        let mut child = command.spawn().expect("spawn sleep");
+        let pid = child.id().expect("pid");
+        std::fs::write(scratch.join("output"), [0u8; 64]).expect("write scratch file");
+
+        let ring = Mutex::new(VecDeque::new());
+        let (sender, mut receiver) = broadcast::channel(16);
+        let sequence = AtomicU64::new(0);
+        for _ in 0..2 {
+            let breach = applied.poll().expect("scratch breach");
+            report_limit_exceeded(&applied, &ring, &sender, &sequence, pid, breach).await;
+        }
+
+        let status = tokio::time::timeout(Duration::from_secs(5), child.wait())
+            .await
+            .expect("killed before timeout")
+            .expect("wait");
+        assert!(!status.success());
+        let message = receiver.try_recv().expect("limit reported");
+        assert_eq!(message.payload["params"]["limit"], "scratch_disk");
+        assert_eq!(message.payload["params"]["configured"], 16);
+        assert_eq!(message.payload["params"]["killed"], true);
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(
+            ring_methods(&*ring.lock().await),
+            ["_adapter/limit_exceeded"]
+        );
+        applied.cleanup();
+    }
+}
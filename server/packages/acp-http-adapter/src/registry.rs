@@ -5,11 +5,14 @@ use serde::Deserialize;
 use serde_json::Value;
 use thiserror::Error;
 
+use crate::limits::ResourceLimits;
+
 #[derive(Debug, Clone)]
 pub struct LaunchSpec {
     pub program: PathBuf,
     pub args: Vec<String>,
     pub env: HashMap<String, String>,
+    pub limits: ResourceLimits,
 }
 
 #[derive(Debug, Error)]
@@ -67,6 +70,7 @@ impl LaunchSpec {
                 program: PathBuf::from("npx"),
                 args,
                 env: npx.env,
+                limits: ResourceLimits::default(),
             });
         }
 
@@ -83,6 +87,7 @@ impl LaunchSpec {
                 program: PathBuf::from(&target.cmd),
                 args: target.args.clone(),
                 env: target.env.clone(),
+                limits: ResourceLimits::default(),
             });
         }
 
@@ -22,6 +22,7 @@ pub enum ErrorType {
     ModeNotSupported,
     StreamError,
     Timeout,
+    ResourceLimitExceeded,
 }
 
 impl ErrorType {
@@ -42,6 +43,7 @@ impl ErrorType {
             Self::ModeNotSupported => "urn:sandbox-agent:error:mode_not_supported",
             Self::StreamError => "urn:sandbox-agent:error:stream_error",
             Self::Timeout => "urn:sandbox-agent:error:timeout",
+            Self::ResourceLimitExceeded => "urn:sandbox-agent:error:resource_limit_exceeded",
         }
     }
 
@@ -62,6 +64,7 @@ impl ErrorType {
             Self::ModeNotSupported => "Mode Not Supported",
             Self::StreamError => "Stream Error",
             Self::Timeout => "Timeout",
+            Self::ResourceLimitExceeded => "Resource Limit Exceeded",
         }
     }
 
@@ -82,6 +85,7 @@ impl ErrorType {
             Self::ModeNotSupported => 400,
             Self::StreamError => 502,
             Self::Timeout => 504,
+            Self::ResourceLimitExceeded => 500,
         }
     }
 }
@@ -165,6 +169,12 @@ pub enum SandboxError {
     StreamError { message: String },
     #[error("timeout")]
     Timeout { message: Option<String> },
+    #[error("resource limit exceeded: {agent} {limit}")]
+    ResourceLimitExceeded {
+        agent: String,
+        limit: String,
+        message: String,
+    },
 }
 
 impl SandboxError {
@@ -185,6 +195,7 @@ impl SandboxError {
             Self::ModeNotSupported { .. } => ErrorType::ModeNotSupported,
             Self::StreamError { .. } => ErrorType::StreamError,
             Self::Timeout { .. } => ErrorType::Timeout,
+            Self::ResourceLimitExceeded { .. } => ErrorType::ResourceLimitExceeded,
         }
     }
 
@@ -284,6 +295,16 @@ impl SandboxError {
                 });
                 (None, None, details)
             }
+            Self::ResourceLimitExceeded {
+                agent,
+                limit,
+                message,
+            } => {
+                let mut map = Map::new();
+                map.insert("limit".to_string(), Value::String(limit.clone()));
+                map.insert("message".to_string(), Value::String(message.clone()));
+                (Some(agent.clone()), None, Some(Value::Object(map)))
+            }
         };
 
         AgentError {
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS session_archives (
+  session_id TEXT PRIMARY KEY,
+  object_key TEXT NOT NULL,
+  archived_at INTEGER NOT NULL
+);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS session_turns (
+  session_id TEXT NOT NULL,
+  ordinal INTEGER NOT NULL,
+  user_message_id TEXT NOT NULL,
+  assistant_message_id TEXT,
+  created_at INTEGER NOT NULL,
+  PRIMARY KEY (session_id, ordinal)
+);
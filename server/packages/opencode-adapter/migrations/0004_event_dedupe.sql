@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS event_dedupe (
+  session_id TEXT NOT NULL,
+  dedupe_key TEXT NOT NULL,
+  event_id TEXT NOT NULL,
+  PRIMARY KEY (session_id, dedupe_key)
+);
@@ -0,0 +1,2 @@
+CREATE INDEX IF NOT EXISTS idx_sessions_created
+ON sessions(created_at, id);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS turn_timelines (
+  turn_id TEXT PRIMARY KEY,
+  session_id TEXT NOT NULL,
+  timeline_json TEXT NOT NULL,
+  created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_turn_timelines_session
+ON turn_timelines(session_id);
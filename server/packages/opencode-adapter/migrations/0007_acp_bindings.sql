@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS acp_bindings (
+  server_id TEXT PRIMARY KEY,
+  session_id TEXT NOT NULL,
+  agent TEXT NOT NULL,
+  acp_session_id TEXT NOT NULL,
+  last_event_id INTEGER,
+  updated_at INTEGER NOT NULL
+);
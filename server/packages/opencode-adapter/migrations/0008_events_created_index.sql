@@ -0,0 +1,2 @@
+CREATE INDEX IF NOT EXISTS idx_events_created
+ON events(created_at);
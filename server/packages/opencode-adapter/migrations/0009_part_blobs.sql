@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS part_blobs (
+  part_id TEXT PRIMARY KEY,
+  session_id TEXT NOT NULL,
+  content TEXT NOT NULL,
+  bytes INTEGER NOT NULL,
+  created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_part_blobs_session
+ON part_blobs(session_id);
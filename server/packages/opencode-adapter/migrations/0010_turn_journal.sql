@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS turn_journal (
+  session_id TEXT NOT NULL,
+  user_message_id TEXT NOT NULL,
+  prompt_event_id TEXT NOT NULL,
+  phase TEXT NOT NULL,
+  started_at INTEGER NOT NULL,
+  updated_at INTEGER NOT NULL,
+  PRIMARY KEY (session_id, user_message_id)
+);
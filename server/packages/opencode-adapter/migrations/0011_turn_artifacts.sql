@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS turn_artifacts (
+  turn_id TEXT PRIMARY KEY,
+  session_id TEXT NOT NULL,
+  artifacts_json TEXT NOT NULL,
+  created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_turn_artifacts_session
+ON turn_artifacts(session_id);
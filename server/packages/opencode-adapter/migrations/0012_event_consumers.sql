@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS event_consumers (
+  consumer TEXT PRIMARY KEY,
+  acked_id INTEGER NOT NULL,
+  updated_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS stream_events (
+  id INTEGER PRIMARY KEY,
+  payload_json TEXT NOT NULL,
+  created_at INTEGER NOT NULL
+);
@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS event_count (
+  id INTEGER PRIMARY KEY CHECK (id = 1),
+  total INTEGER NOT NULL
+);
+INSERT OR IGNORE INTO event_count (id, total)
+SELECT 1, COUNT(*) FROM events;
+CREATE TRIGGER IF NOT EXISTS events_counted_on_insert
+AFTER INSERT ON events
+BEGIN
+  UPDATE event_count SET total = total + 1 WHERE id = 1;
+END;
+CREATE TRIGGER IF NOT EXISTS events_counted_on_delete
+AFTER DELETE ON events
+BEGIN
+  UPDATE event_count SET total = total - 1 WHERE id = 1;
+END;
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS workspaces (
+  id TEXT PRIMARY KEY,
+  workspace_json TEXT NOT NULL,
+  created_at INTEGER NOT NULL
+);
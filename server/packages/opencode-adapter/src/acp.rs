@@ -0,0 +1,454 @@
+//! Typed requests and responses for the core ACP methods the adapter drives.
+//!
+//! Only `initialize`, `authenticate`, `session/new`, `session/prompt` and
+//! `session/cancel` are modelled. Anything else (including `_vendor/...` extension methods) goes
+//! through [`AcpCall::Raw`]. Unmodelled fields are kept in each struct's
+//! `extra` map so payloads survive a round trip unchanged.
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{json, Map, Value};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientInfo {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InitializeParams {
+    pub protocol_version: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_capabilities: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_info: Option<ClientInfo>,
+    #[serde(rename = "_meta", default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Value>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// JSON-RPC error code an agent answers with when it needs `authenticate`
+/// before it will open a session.
+pub(crate) const AUTH_REQUIRED_CODE: i64 = -32000;
+
+/// `error.data.reason` an agent sets when it cannot serve the requested
+/// model.
+pub(crate) const MODEL_UNAVAILABLE_REASON: &str = "model_unavailable";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticateParams {
+    /// One of the `authMethods` ids from the agent's `initialize` response.
+    pub method_id: String,
+    #[serde(rename = "_meta", default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Value>,
+    /// Provider-specific credentials or choices for the method.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionNewParams {
+    pub cwd: String,
+    #[serde(default)]
+    pub mcp_servers: Vec<Value>,
+    #[serde(rename = "_meta", default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Value>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionPromptParams {
+    pub session_id: String,
+    /// ACP content blocks (`text`, `resource`, `resource_link`, ...).
+    pub prompt: Vec<Value>,
+    #[serde(rename = "_meta", default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Value>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionCancelParams {
+    pub session_id: String,
+    #[serde(rename = "_meta", default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Value>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A client-to-agent ACP call.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AcpCall {
+    Initialize(InitializeParams),
+    Authenticate(AuthenticateParams),
+    SessionNew(SessionNewParams),
+    SessionPrompt(SessionPromptParams),
+    SessionCancel(SessionCancelParams),
+    /// Escape hatch for methods without a typed model.
+    Raw {
+        method: String,
+        params: Value,
+    },
+}
+
+impl AcpCall {
+    pub fn method(&self) -> &str {
+        match self {
+            Self::Initialize(_) => "initialize",
+            Self::Authenticate(_) => "authenticate",
+            Self::SessionNew(_) => "session/new",
+            Self::SessionPrompt(_) => "session/prompt",
+            Self::SessionCancel(_) => "session/cancel",
+            Self::Raw { method, .. } => method,
+        }
+    }
+
+    pub fn params(&self) -> Value {
+        let params = match self {
+            Self::Initialize(params) => serde_json::to_value(params),
+            Self::Authenticate(params) => serde_json::to_value(params),
+            Self::SessionNew(params) => serde_json::to_value(params),
+            Self::SessionPrompt(params) => serde_json::to_value(params),
+            Self::SessionCancel(params) => serde_json::to_value(params),
+            Self::Raw { params, .. } => Ok(params.clone()),
+        };
+        // The typed params only hold strings, numbers and `Value`s, none of
+        // which can fail to serialize.
+        params.unwrap_or(Value::Null)
+    }
+
+    /// Build the JSON-RPC request envelope for this call.
+    pub fn to_request(&self, id: impl Into<Value>) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id.into(),
+            "method": self.method(),
+            "params": self.params(),
+        })
+    }
+
+    /// Decode the `result` of a response to this call.
+    pub fn parse_result(&self, result: Value) -> Result<AcpResult, serde_json::Error> {
+        // Tolerate agents that answer with a null result.
+        let typed = |result: Value| if result.is_null() { json!({}) } else { result };
+        Ok(match self {
+            Self::Initialize(_) => AcpResult::Initialize(serde_json::from_value(typed(result))?),
+            Self::SessionNew(_) => AcpResult::SessionNew(serde_json::from_value(typed(result))?),
+            Self::SessionPrompt(_) => {
+                AcpResult::SessionPrompt(serde_json::from_value(typed(result))?)
+            }
+            Self::Authenticate(_) | Self::SessionCancel(_) | Self::Raw { .. } => {
+                AcpResult::Raw(result)
+            }
+        })
+    }
+}
+
+impl Serialize for AcpCall {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        json!({"method": self.method(), "params": self.params()}).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for AcpCall {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Envelope {
+            method: String,
+            #[serde(default)]
+            params: Value,
+        }
+
+        let Envelope { method, params } = Envelope::deserialize(deserializer)?;
+        let typed = match method.as_str() {
+            "initialize" => serde_json::from_value(params).map(Self::Initialize),
+            "authenticate" => serde_json::from_value(params).map(Self::Authenticate),
+            "session/new" => serde_json::from_value(params).map(Self::SessionNew),
+            "session/prompt" => serde_json::from_value(params).map(Self::SessionPrompt),
+            "session/cancel" => serde_json::from_value(params).map(Self::SessionCancel),
+            _ => return Ok(Self::Raw { method, params }),
+        };
+        typed.map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InitializeResult {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_capabilities: Option<Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub auth_methods: Vec<Value>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionNewResult {
+    /// Empty when the agent omitted it; some agents answer `session/new`
+    /// before they have assigned an id.
+    #[serde(default)]
+    pub session_id: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    EndTurn,
+    MaxTokens,
+    MaxTurnRequests,
+    Refusal,
+    Cancelled,
+    #[serde(other)]
+    Unknown,
+}
+
+impl StopReason {
+    /// The wire name, e.g. `max_tokens`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StopReason::EndTurn => "end_turn",
+            StopReason::MaxTokens => "max_tokens",
+            StopReason::MaxTurnRequests => "max_turn_requests",
+            StopReason::Refusal => "refusal",
+            StopReason::Cancelled => "cancelled",
+            StopReason::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionPromptResult {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_reason: Option<StopReason>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The decoded `result` of an [`AcpCall`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum AcpResult {
+    Initialize(InitializeResult),
+    SessionNew(SessionNewResult),
+    SessionPrompt(SessionPromptResult),
+    Raw(Value),
+}
+
+/// Outcome of [`crate::AcpDispatch::call`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum AcpCallOutcome {
+    Result(AcpResult),
+    /// The agent accepted the request without an inline response; the
+    /// response (if any) arrives on the notification stream.
+    Accepted,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AcpCallError {
+    /// The request never reached the agent or the connection failed.
+    Transport(String),
+    /// The agent answered with a JSON-RPC error object.
+    Rpc {
+        code: i64,
+        message: String,
+        data: Option<Value>,
+    },
+    /// The agent answered with a result that does not match the method.
+    InvalidResult(String),
+}
+
+impl AcpCallError {
+    /// Whether the agent refused because it needs `authenticate` first.
+    /// `-32000` is also used for other server errors, so the message must
+    /// mention authentication too.
+    pub(crate) fn is_auth_required(&self) -> bool {
+        matches!(self, Self::Rpc { code, message, .. }
+            if *code == AUTH_REQUIRED_CODE && message.to_ascii_lowercase().contains("auth"))
+    }
+
+    /// Whether the agent rejected the session's model (unknown, deprecated,
+    /// or out of quota), so the turn may succeed on another model. Agents
+    /// can say so explicitly with `data.reason = "model_unavailable"`;
+    /// otherwise the message must mention the model and why.
+    pub(crate) fn is_model_unavailable(&self) -> bool {
+        let Self::Rpc { message, data, .. } = self else {
+            return false;
+        };
+        if data
+            .as_ref()
+            .and_then(|data| data.get("reason"))
+            .and_then(Value::as_str)
+            == Some(MODEL_UNAVAILABLE_REASON)
+        {
+            return true;
+        }
+        let message = message.to_ascii_lowercase();
+        message.contains("model")
+            && [
+                "not found",
+                "not available",
+                "unavailable",
+                "does not exist",
+                "deprecated",
+                "not supported",
+                "quota",
+                "overloaded",
+            ]
+            .iter()
+            .any(|cause| message.contains(cause))
+    }
+
+    pub(crate) fn from_error_object(error: &Value) -> Self {
+        Self::Rpc {
+            code: error.get("code").and_then(Value::as_i64).unwrap_or(-32603),
+            message: error
+                .get("message")
+                .and_then(Value::as_str)
+                .unwrap_or("unknown error")
+                .to_string(),
+            data: error.get("data").cloned(),
+        }
+    }
+}
+
+impl std::fmt::Display for AcpCallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transport(message) => write!(f, "{message}"),
+            Self::Rpc { code, message, .. } => write!(f, "{message} (code {code})"),
+            Self::InvalidResult(message) => write!(f, "invalid result: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for AcpCallError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: Value) -> AcpCall {
+        let call: AcpCall = serde_json::from_value(value.clone()).expect("deserialize");
+        assert_eq!(serde_json::to_value(&call).expect("serialize"), value);
+        call
+    }
+
+    #[test]
+    fn core_methods_round_trip() {
+        let initialize = round_trip(json!({
+            "method": "initialize",
+            "params": {
+                "protocolVersion": 1,
+                "capabilities": {},
+                "clientInfo": {"name": "sandbox-agent-opencode-adapter", "version": "0.1.0"},
+                "_meta": {"sandboxagent.dev": {"agent": "claude"}}
+            }
+        }));
+        assert!(
+            matches!(initialize, AcpCall::Initialize(ref p) if p.extra.contains_key("capabilities"))
+        );
+
+        let authenticate = round_trip(json!({
+            "method": "authenticate",
+            "params": {"methodId": "api-key", "apiKey": "sk-test"}
+        }));
+        assert!(
+            matches!(authenticate, AcpCall::Authenticate(ref p) if p.method_id == "api-key" && p.extra.contains_key("apiKey"))
+        );
+
+        let new = round_trip(json!({
+            "method": "session/new",
+            "params": {"cwd": "/work", "mcpServers": []}
+        }));
+        assert!(matches!(new, AcpCall::SessionNew(ref p) if p.cwd == "/work"));
+
+        let prompt = round_trip(json!({
+            "method": "session/prompt",
+            "params": {"sessionId": "s1", "prompt": [{"type": "text", "text": "hi"}]}
+        }));
+        assert!(matches!(prompt, AcpCall::SessionPrompt(ref p) if p.prompt.len() == 1));
+
+        let cancel = round_trip(json!({
+            "method": "session/cancel",
+            "params": {"sessionId": "s1"}
+        }));
+        assert_eq!(cancel.method(), "session/cancel");
+    }
+
+    #[test]
+    fn unknown_methods_use_raw_escape_hatch() {
+        let call = round_trip(json!({
+            "method": "_claude/slash_command",
+            "params": {"command": "/compact"}
+        }));
+        assert_eq!(
+            call,
+            AcpCall::Raw {
+                method: "_claude/slash_command".to_string(),
+                params: json!({"command": "/compact"}),
+            }
+        );
+    }
+
+    #[test]
+    fn malformed_core_params_are_rejected() {
+        let err = serde_json::from_value::<AcpCall>(json!({
+            "method": "session/prompt",
+            "params": {"prompt": []}
+        }));
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn results_decode_for_their_method() {
+        let call = AcpCall::SessionPrompt(SessionPromptParams {
+            session_id: "s1".to_string(),
+            prompt: Vec::new(),
+            meta: None,
+            extra: Map::new(),
+        });
+        let result = call
+            .parse_result(json!({"stopReason": "end_turn"}))
+            .expect("prompt result");
+        assert_eq!(
+            result,
+            AcpResult::SessionPrompt(SessionPromptResult {
+                stop_reason: Some(StopReason::EndTurn),
+                extra: Map::new(),
+            })
+        );
+
+        let request = call.to_request("oc_rpc_1");
+        assert_eq!(request["method"], "session/prompt");
+        assert_eq!(request["params"]["sessionId"], "s1");
+    }
+
+    #[test]
+    fn model_unavailable_errors_are_recognised() {
+        let rpc = |message: &str, data: Option<Value>| AcpCallError::Rpc {
+            code: -32603,
+            message: message.to_string(),
+            data,
+        };
+        assert!(rpc("model claude-opus-4 not found", None).is_model_unavailable());
+        assert!(rpc("Model quota exceeded for this key", None).is_model_unavailable());
+        assert!(rpc("failed", Some(json!({"reason": "model_unavailable"}))).is_model_unavailable());
+        assert!(!rpc("file not found", None).is_model_unavailable());
+        assert!(!rpc("model crashed", None).is_model_unavailable());
+        assert!(!AcpCallError::Transport("model unavailable".to_string()).is_model_unavailable());
+    }
+}
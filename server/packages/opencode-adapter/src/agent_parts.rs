@@ -0,0 +1,118 @@
+use serde_json::{json, Value};
+
+/// The canonical form of an OpenCode `agent` part, which asks the session's
+/// agent to hand the request to a named sub-agent. `None` if the part does
+/// not name one.
+pub(crate) fn normalize(part: &Value) -> Option<Value> {
+    let name = subagent_name(part)?;
+    let mut normalized = json!({"type": "agent", "name": name});
+    if let Some(source) = part.get("source").filter(|source| source.is_object()) {
+        normalized["source"] = source.clone();
+    }
+    Some(normalized)
+}
+
+/// The sub-agents a prompt's `agent` parts delegate to, in prompt order.
+pub(crate) fn requested(parts: &[Value]) -> Vec<String> {
+    parts
+        .iter()
+        .filter_map(subagent_name)
+        .map(str::to_string)
+        .collect()
+}
+
+/// Replace each `agent` part with a text instruction the session's agent
+/// acts on; ACP has no content block for sub-agent delegation.
+pub(crate) fn translate(parts: Vec<Value>, agent: &str) -> Vec<Value> {
+    parts
+        .into_iter()
+        .map(|part| match subagent_name(&part) {
+            Some(name) => json!({"type": "text", "text": delegation_text(agent, name)}),
+            None => part,
+        })
+        .collect()
+}
+
+/// The sub-agent a tool call starts: `subagent_type` for Claude's Task tool,
+/// `subagent` or `agent` for agents modelled on OpenCode's task tool.
+pub(crate) fn started_by(input: &Value) -> Option<&str> {
+    ["subagent_type", "subagent", "agent"]
+        .iter()
+        .find_map(|key| input.get(*key).and_then(Value::as_str))
+        .filter(|name| !name.is_empty())
+}
+
+/// The prompt a sub-agent tool call hands over, if the agent reports one.
+pub(crate) fn delegated_prompt(input: &Value) -> Option<&str> {
+    ["prompt", "description"]
+        .iter()
+        .find_map(|key| input.get(*key).and_then(Value::as_str))
+        .filter(|prompt| !prompt.is_empty())
+}
+
+fn subagent_name(part: &Value) -> Option<&str> {
+    if part.get("type").and_then(Value::as_str) != Some("agent") {
+        return None;
+    }
+    part.get("name")
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+}
+
+fn delegation_text(agent: &str, name: &str) -> String {
+    match agent {
+        "claude" => format!(
+            "Use the Task tool with subagent_type \"{name}\" to handle the request above, \
+             writing its prompt from the message and context."
+        ),
+        "opencode" => format!(
+            "Use the above message and context to generate a prompt and call the task tool \
+             with subagent: {name}"
+        ),
+        _ => format!(
+            "Delegate the request above to your \"{name}\" sub-agent, writing its prompt from \
+             the message and context."
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agent_parts_become_delegation_instructions_for_the_agent() {
+        let parts = vec![
+            json!({"type": "text", "text": "map the crate"}),
+            json!({"type": "agent", "name": " explore ", "source": {"value": "@explore", "start": 0, "end": 8}}),
+        ];
+        assert_eq!(requested(&parts), vec!["explore".to_string()]);
+
+        let translated = translate(parts.clone(), "claude");
+        assert_eq!(translated[0], parts[0]);
+        assert_eq!(translated[1]["type"], "text");
+        let text = translated[1]["text"].as_str().unwrap();
+        assert!(text.contains("subagent_type \"explore\""), "{text}");
+        assert!(translate(parts, "opencode")[1]["text"]
+            .as_str()
+            .unwrap()
+            .ends_with("subagent: explore"));
+
+        let normalized = normalize(&json!({"type": "agent", "name": "explore", "extra": 1}));
+        assert_eq!(
+            normalized,
+            Some(json!({"type": "agent", "name": "explore"}))
+        );
+        assert_eq!(normalize(&json!({"type": "agent", "name": ""})), None);
+    }
+
+    #[test]
+    fn sub_agent_tool_calls_are_recognised_by_their_input() {
+        let task = json!({"subagent_type": "explore", "description": "Map", "prompt": "Map it"});
+        assert_eq!(started_by(&task), Some("explore"));
+        assert_eq!(delegated_prompt(&task), Some("Map it"));
+        assert_eq!(started_by(&json!({"subagent": "general"})), Some("general"));
+        assert_eq!(started_by(&json!({"command": "ls"})), None);
+    }
+}
@@ -0,0 +1,289 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use reqwest::{Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+
+const DEFAULT_REGION: &str = "us-east-1";
+const DEFAULT_PREFIX: &str = "sandbox-agent/sessions/";
+const DEFAULT_ARCHIVE_AFTER_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_ARCHIVE_INTERVAL_SECS: u64 = 5 * 60;
+
+/// Where and when completed sessions are exported for long-term storage.
+#[derive(Debug, Clone)]
+pub struct SessionArchiveConfig {
+    /// Base URL of the S3-compatible service, e.g. `https://s3.us-east-1.amazonaws.com`
+    /// or `http://minio:9000`. Objects are addressed path-style.
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    /// Key prefix for archived sessions; the object key is `{prefix}{session_id}.json`.
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+    /// How long a session must be idle before it is archived.
+    pub archive_after: Duration,
+    /// How often the archival job scans for eligible sessions.
+    pub interval: Duration,
+}
+
+impl SessionArchiveConfig {
+    /// Build from `SANDBOX_AGENT_ARCHIVE_S3_*`. Returns `None` unless a bucket
+    /// and credentials are configured. Credentials fall back to the standard
+    /// `AWS_*` variables.
+    pub fn from_env() -> Option<Self> {
+        let bucket = env_nonempty("SANDBOX_AGENT_ARCHIVE_S3_BUCKET")?;
+        let region = env_nonempty("SANDBOX_AGENT_ARCHIVE_S3_REGION")
+            .or_else(|| env_nonempty("AWS_REGION"))
+            .unwrap_or_else(|| DEFAULT_REGION.to_string());
+        let endpoint = env_nonempty("SANDBOX_AGENT_ARCHIVE_S3_ENDPOINT")
+            .unwrap_or_else(|| format!("https://s3.{region}.amazonaws.com"));
+        let access_key_id = env_nonempty("SANDBOX_AGENT_ARCHIVE_S3_ACCESS_KEY_ID")
+            .or_else(|| env_nonempty("AWS_ACCESS_KEY_ID"))?;
+        let secret_access_key = env_nonempty("SANDBOX_AGENT_ARCHIVE_S3_SECRET_ACCESS_KEY")
+            .or_else(|| env_nonempty("AWS_SECRET_ACCESS_KEY"))?;
+        let session_token = env_nonempty("SANDBOX_AGENT_ARCHIVE_S3_SESSION_TOKEN")
+            .or_else(|| env_nonempty("AWS_SESSION_TOKEN"));
+
+        Some(Self {
+            endpoint,
+            bucket,
+            region,
+            prefix: env_nonempty("SANDBOX_AGENT_ARCHIVE_S3_PREFIX")
+                .unwrap_or_else(|| DEFAULT_PREFIX.to_string()),
+            access_key_id,
+            secret_access_key,
+            session_token,
+            archive_after: Duration::from_secs(
+                env_u64("SANDBOX_AGENT_ARCHIVE_AFTER_SECS").unwrap_or(DEFAULT_ARCHIVE_AFTER_SECS),
+            ),
+            interval: Duration::from_secs(
+                env_u64("SANDBOX_AGENT_ARCHIVE_INTERVAL_SECS")
+                    .filter(|secs| *secs > 0)
+                    .unwrap_or(DEFAULT_ARCHIVE_INTERVAL_SECS),
+            ),
+        })
+    }
+
+    pub(crate) fn object_key(&self, session_id: &str) -> String {
+        format!("{}{session_id}.json", self.prefix)
+    }
+}
+
+/// Minimal S3 client: path-style `PutObject`/`GetObject`/`DeleteObject`
+/// signed with SigV4.
+pub(crate) struct S3Client {
+    config: SessionArchiveConfig,
+    http: reqwest::Client,
+}
+
+impl S3Client {
+    pub(crate) fn new(config: SessionArchiveConfig) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(60))
+                .build()
+                .unwrap_or_else(|_| reqwest::Client::new()),
+        }
+    }
+
+    pub(crate) fn config(&self) -> &SessionArchiveConfig {
+        &self.config
+    }
+
+    pub(crate) async fn put_object(&self, key: &str, body: Vec<u8>) -> Result<(), String> {
+        let response = self.send(Method::PUT, key, body).await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            return Err(format!("s3 put {key} failed ({status}): {text}"));
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn get_object(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let response = self.send(Method::GET, key, Vec::new()).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            return Err(format!("s3 get {key} failed ({status}): {text}"));
+        }
+        let bytes = response.bytes().await.map_err(|err| err.to_string())?;
+        Ok(Some(bytes.to_vec()))
+    }
+
+    /// Delete `key`; deleting an object that is not there succeeds.
+    pub(crate) async fn delete_object(&self, key: &str) -> Result<(), String> {
+        let response = self.send(Method::DELETE, key, Vec::new()).await?;
+        if !response.status().is_success() && response.status() != StatusCode::NOT_FOUND {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            return Err(format!("s3 delete {key} failed ({status}): {text}"));
+        }
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, String> {
+        let canonical_uri = format!(
+            "{}/{}/{}",
+            Url::parse(&self.config.endpoint)
+                .map_err(|err| format!("invalid s3 endpoint: {err}"))?
+                .path()
+                .trim_end_matches('/'),
+            uri_encode(&self.config.bucket, true),
+            uri_encode(key, false)
+        );
+        let url = Url::parse(&self.config.endpoint)
+            .and_then(|base| base.join(&canonical_uri))
+            .map_err(|err| format!("invalid s3 url: {err}"))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err("s3 endpoint has no host".to_string()),
+        };
+
+        let now = time::OffsetDateTime::now_utc();
+        let date = format!(
+            "{:04}{:02}{:02}",
+            now.year(),
+            u8::from(now.month()),
+            now.day()
+        );
+        let amz_date = format!(
+            "{date}T{:02}{:02}{:02}Z",
+            now.hour(),
+            now.minute(),
+            now.second()
+        );
+        let payload_hash = hex::encode(Sha256::digest(&body));
+
+        let mut headers = vec![
+            ("host".to_string(), host),
+            ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+            ("x-amz-date".to_string(), amz_date.clone()),
+        ];
+        if let Some(token) = &self.config.session_token {
+            headers.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect::<String>();
+        let canonical_request = format!(
+            "{method}\n{canonical_uri}\n\n{canonical_headers}\n{signed_headers}\n{payload_hash}"
+        );
+
+        let scope = format!("{date}/{}/s3/aws4_request", self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signing_key = signing_key(
+            &self.config.secret_access_key,
+            &date,
+            &self.config.region,
+            "s3",
+        );
+        let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            self.config.access_key_id
+        );
+
+        let mut request = self
+            .http
+            .request(method, url)
+            .header("authorization", authorization);
+        for (name, value) in headers.into_iter().filter(|(name, _)| name != "host") {
+            request = request.header(name, value);
+        }
+        request
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| format!("s3 request failed: {err}"))
+    }
+}
+
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let k_date = hmac_sha256(format!("AWS4{secret}").as_bytes(), date.as_bytes());
+    let k_region = hmac_sha256(&k_date, region.as_bytes());
+    let k_service = hmac_sha256(&k_region, service.as_bytes());
+    hmac_sha256(&k_service, b"aws4_request")
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// SigV4 URI encoding. `/` is kept in object keys but encoded in bucket names.
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(byte as char)
+            }
+            b'/' if !encode_slash => out.push('/'),
+            _ => out.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    out
+}
+
+pub(crate) fn env_nonempty(key: &str) -> Option<String> {
+    std::env::var(key)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn env_u64(key: &str) -> Option<u64> {
+    env_nonempty(key).and_then(|value| value.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signing_key_matches_aws_example() {
+        // https://docs.aws.amazon.com/IAM/latest/UserGuide/signing-elements.html
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn uri_encode_keeps_key_slashes() {
+        assert_eq!(
+            uri_encode("sessions/ses 1.json", false),
+            "sessions/ses%201.json"
+        );
+        assert_eq!(uri_encode("a/b", true), "a%2Fb");
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{file, find};
+
+/// Most artifacts registered for one turn; a turn that rewrites a whole tree
+/// is cut off here.
+pub(crate) const MAX_TURN_ARTIFACTS: usize = 500;
+/// Artifacts larger than this are listed but not served for download.
+pub(crate) const MAX_DOWNLOAD_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Slack for file systems whose modification times trail the clock.
+const MTIME_SLACK_MS: i64 = 2000;
+
+/// Keys of a tool call's input that name the file it works on.
+const PATH_KEYS: &[&str] = &["path", "file_path", "filePath", "filename", "notebook_path"];
+
+/// A file's size and modification time, to tell whether a turn changed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    size: u64,
+    modified_ms: i64,
+}
+
+/// The files under a session directory as a turn started.
+#[derive(Debug, Clone)]
+pub(crate) struct Baseline {
+    root: PathBuf,
+    files: HashMap<String, FileStamp>,
+    /// When the scan started, in milliseconds since the epoch.
+    started_ms: i64,
+}
+
+impl Baseline {
+    /// Stamp the files under `root` that `/find/file` would list.
+    pub(crate) fn scan(root: &Path) -> Self {
+        let started_ms = millis(SystemTime::now());
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        let files = find::list_files(&root)
+            .into_iter()
+            .filter_map(|path| Some((stamp(&root.join(&path))?, path)))
+            .map(|(stamp, path)| (path, stamp))
+            .collect();
+        Self {
+            root,
+            files,
+            started_ms,
+        }
+    }
+}
+
+/// A file a turn created or modified.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Artifact {
+    pub id: String,
+    /// Relative to the session directory.
+    pub path: String,
+    pub absolute: String,
+    /// `modified` when the baseline listed the file, otherwise `created`.
+    pub change: &'static str,
+    pub size: u64,
+    pub mime_type: &'static str,
+    pub sha256: String,
+    /// The tool call whose input named the file, if any.
+    #[serde(rename = "callID", skip_serializing_if = "Option::is_none")]
+    pub call_id: Option<String>,
+    pub modified: i64,
+}
+
+/// The paths a tool call's input names, e.g. the file an edit tool writes.
+pub(crate) fn tool_paths(input: &Value) -> Vec<String> {
+    PATH_KEYS
+        .iter()
+        .filter_map(|key| input.get(*key).and_then(Value::as_str))
+        .filter(|path| !path.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// The files a turn created or modified, by path: listed files whose size or
+/// modification time differ from `baseline`, and files tool calls named
+/// (`tool_paths` maps each to its call) that changed since the scan, which
+/// also finds files the listing skips, such as ignored ones.
+pub(crate) fn collect(
+    baseline: &Baseline,
+    tool_paths: &BTreeMap<String, String>,
+    mut next_id: impl FnMut() -> String,
+) -> Vec<Artifact> {
+    let root = &baseline.root;
+    let mut changed: BTreeMap<String, Option<String>> = BTreeMap::new();
+    for path in find::list_files(root) {
+        let current = stamp(&root.join(&path));
+        if current.is_some() && baseline.files.get(&path) != current.as_ref() {
+            changed.insert(path, None);
+        }
+    }
+    for (path, call_id) in tool_paths {
+        let Ok(resolved) = file::resolve_in_root(root, path) else {
+            continue;
+        };
+        let Some(current) = stamp(&resolved) else {
+            continue;
+        };
+        let relative = resolved
+            .strip_prefix(root)
+            .unwrap_or(&resolved)
+            .to_string_lossy()
+            .into_owned();
+        let touched = match baseline.files.get(&relative) {
+            Some(before) => *before != current,
+            None => current.modified_ms >= baseline.started_ms - MTIME_SLACK_MS,
+        };
+        if touched {
+            changed.insert(relative, Some(call_id.clone()));
+        }
+    }
+
+    changed
+        .into_iter()
+        .take(MAX_TURN_ARTIFACTS)
+        .filter_map(|(path, call_id)| {
+            let absolute = root.join(&path);
+            let current = stamp(&absolute)?;
+            let sha256 = hash_file(&absolute).ok()?;
+            Some(Artifact {
+                id: next_id(),
+                change: if baseline.files.contains_key(&path) {
+                    "modified"
+                } else {
+                    "created"
+                },
+                absolute: absolute.to_string_lossy().into_owned(),
+                path,
+                size: current.size,
+                mime_type: file::guess_mime(&absolute),
+                sha256,
+                call_id,
+                modified: current.modified_ms,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ReadError {
+    #[error("artifact file no longer exists")]
+    NotFound,
+    #[error("artifact is {size} bytes; artifacts over {MAX_DOWNLOAD_BYTES} bytes are not served")]
+    TooLarge { size: u64 },
+    #[error("failed to read artifact: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// An artifact's content for download.
+pub(crate) fn read(absolute: &Path) -> Result<Vec<u8>, ReadError> {
+    let size = std::fs::metadata(absolute)
+        .map_err(|_| ReadError::NotFound)?
+        .len();
+    if size > MAX_DOWNLOAD_BYTES {
+        return Err(ReadError::TooLarge { size });
+    }
+    Ok(std::fs::read(absolute)?)
+}
+
+/// Hex SHA-256 of a file's content.
+pub(crate) fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn stamp(path: &Path) -> Option<FileStamp> {
+    let metadata = std::fs::metadata(path).ok().filter(|meta| meta.is_file())?;
+    Some(FileStamp {
+        size: metadata.len(),
+        modified_ms: metadata.modified().map_or(0, millis),
+    })
+}
+
+fn millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "sandbox-agent-artifacts-{name}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("create temp dir");
+        dir
+    }
+
+    #[test]
+    fn finds_created_modified_and_tool_named_files() {
+        let root = temp_dir("collect");
+        std::fs::write(root.join(".gitignore"), "out\n").expect("write");
+        std::fs::write(root.join("keep.txt"), "keep").expect("write");
+        std::fs::write(root.join("edit.md"), "before").expect("write");
+        let baseline = Baseline::scan(&root);
+
+        std::fs::write(root.join("edit.md"), "after, longer").expect("write");
+        std::fs::write(root.join("report.json"), "{}").expect("write");
+        std::fs::create_dir_all(root.join("out")).expect("create");
+        std::fs::write(root.join("out/chart.png"), [0x89, b'P', b'N', b'G', 0]).expect("write");
+        std::fs::write(root.join("out/unnamed.bin"), [0]).expect("write");
+        let tool_paths = BTreeMap::from([
+            (
+                root.join("out/chart.png").to_string_lossy().into_owned(),
+                "call_1".to_string(),
+            ),
+            ("keep.txt".to_string(), "call_2".to_string()),
+        ]);
+
+        let mut ids = 0;
+        let artifacts = collect(&baseline, &tool_paths, || {
+            ids += 1;
+            format!("art_{ids}")
+        });
+        let summary: Vec<_> = artifacts
+            .iter()
+            .map(|artifact| {
+                (
+                    artifact.path.as_str(),
+                    artifact.change,
+                    artifact.mime_type,
+                    artifact.call_id.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("edit.md", "modified", "text/markdown", None),
+                ("out/chart.png", "created", "image/png", Some("call_1")),
+                ("report.json", "created", "application/json", None),
+            ]
+        );
+        assert_eq!(
+            artifacts[2].sha256,
+            "44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        );
+        assert_eq!(artifacts[0].id, "art_1");
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn reads_paths_from_tool_input() {
+        assert_eq!(
+            tool_paths(&json!({"file_path": "/work/a.rs", "content": "x"})),
+            vec!["/work/a.rs"]
+        );
+        assert!(tool_paths(&json!({"command": "ls"})).is_empty());
+    }
+}
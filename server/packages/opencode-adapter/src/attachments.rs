@@ -0,0 +1,499 @@
+use std::future::Future;
+use std::io::Read;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Arc;
+
+use base64::Engine;
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+
+use crate::archive::env_nonempty;
+
+/// How many leading bytes of an attachment are inspected.
+const SNIFF_LEN: usize = 512;
+/// Image types every image-capable agent takes; others need transcoding.
+const AGENT_IMAGE_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+/// Image types [`CommandTranscoder`] converts to PNG.
+const TRANSCODED_IMAGE_TYPES: &[&str] = &[
+    "image/heic",
+    "image/heif",
+    "image/avif",
+    "image/tiff",
+    "image/bmp",
+];
+
+/// Resolves to PNG bytes, or `None` if the transcoder does not handle the
+/// image's type.
+pub type TranscodeFuture<'a> =
+    Pin<Box<dyn Future<Output = Option<Result<Vec<u8>, String>>> + Send + 'a>>;
+
+/// Converts images an agent cannot take (e.g. HEIC photos) to PNG before a
+/// prompt is sent. Transcoders are tried in registration order.
+pub trait AttachmentTranscoder: Send + Sync + 'static {
+    /// Convert `data` of type `mime` to PNG.
+    fn to_png<'a>(&'a self, mime: &'a str, data: &'a [u8]) -> TranscodeFuture<'a>;
+}
+
+/// Transcodes by piping the image through an external command that reads it
+/// on stdin and writes PNG to stdout, such as `magick - png:-`.
+#[derive(Debug, Clone)]
+pub struct CommandTranscoder {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl CommandTranscoder {
+    /// Build from `OPENCODE_COMPAT_IMAGE_TRANSCODER`, a whitespace-separated
+    /// command line.
+    pub fn from_env() -> Option<Self> {
+        let command = env_nonempty("OPENCODE_COMPAT_IMAGE_TRANSCODER")?;
+        let mut words = command.split_whitespace().map(str::to_string);
+        Some(Self {
+            program: words.next()?,
+            args: words.collect(),
+        })
+    }
+
+    async fn run(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut child = tokio::process::Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| format!("failed to run {}: {err}", self.program))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(data)
+                .await
+                .map_err(|err| format!("failed to write to {}: {err}", self.program))?;
+        }
+        let output = child
+            .wait_with_output()
+            .await
+            .map_err(|err| format!("{} failed: {err}", self.program))?;
+        if !output.status.success() {
+            return Err(format!(
+                "{} exited with {}: {}",
+                self.program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        if sniff_mime(&output.stdout) != Some("image/png") {
+            return Err(format!("{} did not write a PNG", self.program));
+        }
+        Ok(output.stdout)
+    }
+}
+
+impl AttachmentTranscoder for CommandTranscoder {
+    fn to_png<'a>(&'a self, mime: &'a str, data: &'a [u8]) -> TranscodeFuture<'a> {
+        Box::pin(async move {
+            if !TRANSCODED_IMAGE_TYPES.contains(&mime) {
+                return None;
+            }
+            Some(self.run(data).await)
+        })
+    }
+}
+
+/// The ACP `promptCapabilities` an agent reports from `initialize`. Text and
+/// `file://` links are always accepted; embedded images, audio and other
+/// files need the matching capability.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct PromptCapabilities {
+    pub image: bool,
+    pub audio: bool,
+    pub embedded_context: bool,
+}
+
+impl PromptCapabilities {
+    const ALL: Self = Self {
+        image: true,
+        audio: true,
+        embedded_context: true,
+    };
+
+    /// Read `promptCapabilities` from an `initialize` result's
+    /// `agentCapabilities`.
+    pub(crate) fn from_agent_capabilities(capabilities: &Value) -> Self {
+        let prompt = &capabilities["promptCapabilities"];
+        let flag = |name: &str| prompt[name].as_bool().unwrap_or(false);
+        Self {
+            image: flag("image"),
+            audio: flag("audio"),
+            embedded_context: flag("embeddedContext"),
+        }
+    }
+
+    /// What `agent` is known to accept before it has been initialized, or
+    /// `None` to skip the check until it reports its capabilities.
+    pub(crate) fn known(agent: &str) -> Option<Self> {
+        match agent {
+            "mock" => Some(Self::ALL),
+            "claude" | "codex" => Some(Self {
+                audio: false,
+                ..Self::ALL
+            }),
+            "cursor" => Some(Self {
+                image: true,
+                audio: false,
+                embedded_context: false,
+            }),
+            _ => None,
+        }
+    }
+
+    fn accepts(&self, mime: &str) -> bool {
+        if is_text(mime) {
+            return true;
+        }
+        if mime.starts_with("image/") {
+            return self.image && AGENT_IMAGE_TYPES.contains(&mime);
+        }
+        if mime.starts_with("audio/") {
+            return self.audio;
+        }
+        self.embedded_context
+    }
+
+    /// The attachment types these capabilities accept, for error messages.
+    fn accepted(&self) -> Vec<String> {
+        let mut accepted = vec!["text/*".to_string()];
+        if self.image {
+            accepted.extend(AGENT_IMAGE_TYPES.iter().map(|mime| mime.to_string()));
+        }
+        if self.audio {
+            accepted.push("audio/*".to_string());
+        }
+        if self.embedded_context {
+            accepted.push("*/*".to_string());
+        }
+        accepted
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum AttachmentError {
+    #[error("{agent} does not accept {mime} attachments; it accepts {}", accepted.join(", "))]
+    UnsupportedMediaType {
+        /// Index of the part in the prompt.
+        index: usize,
+        agent: String,
+        mime: String,
+        filename: Option<String>,
+        accepted: Vec<String>,
+    },
+    #[error("attachment {0} is not a valid base64 data URL")]
+    InvalidDataUrl(String),
+    #[error("failed to transcode {mime} attachment: {reason}")]
+    Transcode { mime: String, reason: String },
+}
+
+/// Check the `file` parts of a prompt before it is sent to `agent`: correct
+/// each declared MIME type from the content, transcode embedded images the
+/// agent cannot take, and reject attachments it does not accept.
+/// `capabilities` is `None` when the agent's are not known yet.
+pub(crate) async fn prepare_parts(
+    parts: Vec<Value>,
+    agent: &str,
+    capabilities: Option<PromptCapabilities>,
+    transcoders: &[Arc<dyn AttachmentTranscoder>],
+) -> Result<Vec<Value>, AttachmentError> {
+    let mut prepared = Vec::with_capacity(parts.len());
+    for (index, mut part) in parts.into_iter().enumerate() {
+        if part["type"] == "file" {
+            prepare_file_part(index, &mut part, agent, capabilities, transcoders).await?;
+        }
+        prepared.push(part);
+    }
+    Ok(prepared)
+}
+
+async fn prepare_file_part(
+    index: usize,
+    part: &mut Value,
+    agent: &str,
+    capabilities: Option<PromptCapabilities>,
+    transcoders: &[Arc<dyn AttachmentTranscoder>],
+) -> Result<(), AttachmentError> {
+    let url = part["url"].as_str().unwrap_or_default().to_string();
+    let declared = part["mime"].as_str().map(str::to_string);
+    let filename = part["filename"].as_str().map(str::to_string);
+
+    if let Some(path) = url.strip_prefix("file://") {
+        // Linked files are read by the agent itself; only the type is fixed.
+        if let Some(mime) =
+            read_head(path).and_then(|head| sniffed_mime(&head, declared.as_deref()))
+        {
+            part["mime"] = Value::from(mime);
+        }
+        return Ok(());
+    }
+    let Some(data_url) = url.strip_prefix("data:") else {
+        return Ok(());
+    };
+    let (header, payload) = data_url
+        .split_once(',')
+        .ok_or_else(|| AttachmentError::InvalidDataUrl(display_name(&filename, &url)))?;
+    let Some(url_mime) = header.strip_suffix(";base64") else {
+        // A percent-encoded data URL holds text.
+        return Ok(());
+    };
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(payload)
+        .map_err(|_| AttachmentError::InvalidDataUrl(display_name(&filename, &url)))?;
+    let declared = declared.or_else(|| (!url_mime.is_empty()).then(|| url_mime.to_string()));
+    let mut mime = sniffed_mime(&data, declared.as_deref())
+        .or(declared)
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    let mut data = std::borrow::Cow::Borrowed(&data[..]);
+
+    let agent_takes_image = capabilities.is_none_or(|capabilities| capabilities.image);
+    if mime.starts_with("image/")
+        && !AGENT_IMAGE_TYPES.contains(&mime.as_str())
+        && agent_takes_image
+    {
+        for transcoder in transcoders {
+            if let Some(result) = transcoder.to_png(&mime, &data).await {
+                let png = result.map_err(|reason| AttachmentError::Transcode {
+                    mime: mime.clone(),
+                    reason,
+                })?;
+                mime = "image/png".to_string();
+                data = std::borrow::Cow::Owned(png);
+                if let Some(filename) = filename.as_deref() {
+                    part["filename"] = Value::from(with_extension(filename, "png"));
+                }
+                break;
+            }
+        }
+    }
+
+    if let Some(capabilities) = capabilities.filter(|capabilities| !capabilities.accepts(&mime)) {
+        return Err(AttachmentError::UnsupportedMediaType {
+            index,
+            agent: agent.to_string(),
+            mime,
+            filename,
+            accepted: capabilities.accepted(),
+        });
+    }
+    part["url"] = Value::from(format!(
+        "data:{mime};base64,{}",
+        base64::engine::general_purpose::STANDARD.encode(&data)
+    ));
+    part["mime"] = Value::from(mime);
+    Ok(())
+}
+
+/// The type of `data`, when its content says something more specific than
+/// `declared`. A declared text subtype (e.g. `text/x-rust`) is kept over a
+/// sniffed `text/plain`.
+fn sniffed_mime(data: &[u8], declared: Option<&str>) -> Option<String> {
+    let sniffed = sniff_mime(data)?;
+    if sniffed == "text/plain" && declared.is_some_and(is_text) {
+        return None;
+    }
+    (declared != Some(sniffed)).then(|| sniffed.to_string())
+}
+
+/// Identify a file from its leading bytes.
+pub(crate) fn sniff_mime(data: &[u8]) -> Option<&'static str> {
+    let head = &data[..data.len().min(SNIFF_LEN)];
+    let starts = |magic: &[u8]| head.starts_with(magic);
+    if starts(b"\x89PNG\r\n\x1a\n") {
+        return Some("image/png");
+    }
+    if starts(&[0xff, 0xd8, 0xff]) {
+        return Some("image/jpeg");
+    }
+    if starts(b"GIF87a") || starts(b"GIF89a") {
+        return Some("image/gif");
+    }
+    if starts(b"BM") && head.len() > 14 {
+        return Some("image/bmp");
+    }
+    if starts(b"II*\0") || starts(b"MM\0*") {
+        return Some("image/tiff");
+    }
+    if starts(b"%PDF-") {
+        return Some("application/pdf");
+    }
+    if starts(b"OggS") {
+        return Some("audio/ogg");
+    }
+    if starts(b"fLaC") {
+        return Some("audio/flac");
+    }
+    if starts(b"ID3") || starts(&[0xff, 0xfb]) || starts(&[0xff, 0xf3]) || starts(&[0xff, 0xf2]) {
+        return Some("audio/mpeg");
+    }
+    if starts(b"PK\x03\x04") {
+        return Some("application/zip");
+    }
+    if head.len() >= 12 && starts(b"RIFF") {
+        match &head[8..12] {
+            b"WEBP" => return Some("image/webp"),
+            b"WAVE" => return Some("audio/wav"),
+            _ => {}
+        }
+    }
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        return match &head[8..12] {
+            b"heic" | b"heix" | b"hevc" | b"heim" | b"heis" => Some("image/heic"),
+            b"mif1" | b"msf1" => Some("image/heif"),
+            b"avif" | b"avis" => Some("image/avif"),
+            b"M4A " => Some("audio/mp4"),
+            b"qt  " => Some("video/quicktime"),
+            _ => Some("video/mp4"),
+        };
+    }
+    looks_like_text(head).then_some("text/plain")
+}
+
+/// UTF-8 without NUL bytes, allowing a character cut off by the sniff limit.
+fn looks_like_text(head: &[u8]) -> bool {
+    if head.is_empty() || head.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(err) => err.error_len().is_none() && head.len() == SNIFF_LEN,
+    }
+}
+
+fn is_text(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || matches!(
+            mime,
+            "application/json" | "application/xml" | "application/x-yaml" | "application/toml"
+        )
+}
+
+fn read_head(path: &str) -> Option<Vec<u8>> {
+    let file = std::fs::File::open(path).ok()?;
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    file.take(SNIFF_LEN as u64).read_to_end(&mut head).ok()?;
+    Some(head)
+}
+
+fn display_name(filename: &Option<String>, url: &str) -> String {
+    filename
+        .clone()
+        .unwrap_or_else(|| url.chars().take(32).collect())
+}
+
+fn with_extension(filename: &str, extension: &str) -> String {
+    let stem = filename.rsplit_once('.').map_or(filename, |(stem, _)| stem);
+    format!("{stem}.{extension}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const HEIC: &[u8] = b"\0\0\0\x18ftypheic\0\0\0\0mif1heic";
+
+    fn data_part(mime: &str, data: &[u8], filename: &str) -> Value {
+        json!({
+            "type": "file",
+            "mime": mime,
+            "filename": filename,
+            "url": format!(
+                "data:{mime};base64,{}",
+                base64::engine::general_purpose::STANDARD.encode(data)
+            ),
+        })
+    }
+
+    struct FakePng;
+
+    impl AttachmentTranscoder for FakePng {
+        fn to_png<'a>(&'a self, mime: &'a str, _data: &'a [u8]) -> TranscodeFuture<'a> {
+            Box::pin(async move { (mime == "image/heic").then(|| Ok(PNG.to_vec())) })
+        }
+    }
+
+    #[test]
+    fn sniffs_common_types() {
+        assert_eq!(sniff_mime(PNG), Some("image/png"));
+        assert_eq!(sniff_mime(HEIC), Some("image/heic"));
+        assert_eq!(sniff_mime(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(sniff_mime(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff_mime(b"fn main() {}\n"), Some("text/plain"));
+        assert_eq!(sniff_mime(&[0, 1, 2, 3]), None);
+        assert_eq!(sniffed_mime(b"fn main() {}", Some("text/x-rust")), None);
+    }
+
+    #[tokio::test]
+    async fn corrects_declared_types_and_rejects_unsupported_ones() {
+        let parts = vec![data_part("application/octet-stream", PNG, "shot.png")];
+        let prepared = prepare_parts(parts, "mock", PromptCapabilities::known("mock"), &[])
+            .await
+            .expect("png is accepted");
+        assert_eq!(prepared[0]["mime"], "image/png");
+        assert!(prepared[0]["url"]
+            .as_str()
+            .unwrap()
+            .starts_with("data:image/png;base64,"));
+
+        let text_only = PromptCapabilities::default();
+        let err = prepare_parts(
+            vec![data_part("image/png", PNG, "shot.png")],
+            "amp",
+            Some(text_only),
+            &[],
+        )
+        .await
+        .unwrap_err();
+        let AttachmentError::UnsupportedMediaType { mime, accepted, .. } = err else {
+            panic!("expected UnsupportedMediaType, got {err:?}");
+        };
+        assert_eq!(mime, "image/png");
+        assert_eq!(accepted, ["text/*"]);
+    }
+
+    #[tokio::test]
+    async fn transcodes_images_the_agent_cannot_take() {
+        let claude = PromptCapabilities::known("claude");
+        let err = prepare_parts(
+            vec![data_part("image/heic", HEIC, "a.heic")],
+            "claude",
+            claude,
+            &[],
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, AttachmentError::UnsupportedMediaType { .. }));
+
+        let transcoders: Vec<Arc<dyn AttachmentTranscoder>> = vec![Arc::new(FakePng)];
+        let prepared = prepare_parts(
+            vec![data_part("image/heic", HEIC, "IMG_0001.HEIC")],
+            "claude",
+            claude,
+            &transcoders,
+        )
+        .await
+        .expect("heic is transcoded");
+        assert_eq!(prepared[0]["mime"], "image/png");
+        assert_eq!(prepared[0]["filename"], "IMG_0001.png");
+    }
+
+    #[test]
+    fn reads_prompt_capabilities() {
+        let capabilities = PromptCapabilities::from_agent_capabilities(&json!({
+            "promptCapabilities": {"image": true, "embeddedContext": true}
+        }));
+        assert!(capabilities.accepts("image/jpeg"));
+        assert!(capabilities.accepts("application/pdf"));
+        assert!(!capabilities.accepts("audio/wav"));
+        assert!(!capabilities.accepts("image/heic"));
+        assert!(PromptCapabilities::default().accepts("text/markdown"));
+    }
+}
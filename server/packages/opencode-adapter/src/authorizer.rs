@@ -0,0 +1,130 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+/// How long an authorizer may take before the request goes to a human.
+pub(crate) const AUTHORIZER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A permission request as an [`Authorizer`] sees it.
+#[derive(Debug, Clone)]
+pub struct PermissionContext {
+    pub session_id: String,
+    pub request_id: String,
+    pub agent: String,
+    pub directory: String,
+    /// The tool kind, e.g. `execute`, `edit` or `read`.
+    pub permission: String,
+    /// What the request covers: a command prefix or file paths.
+    pub patterns: Vec<String>,
+    /// Request metadata: the tool call's `tool`, `toolKind`, a summarized
+    /// `input` and the agent's `rawInput` when it sent them.
+    pub metadata: Value,
+}
+
+/// An [`Authorizer`]'s answer to a permission request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthorizerDecision {
+    /// Approve this request once.
+    Allow { reason: Option<String> },
+    /// Reject it; the reason is passed on to the agent.
+    Deny { reason: Option<String> },
+    /// Leave it to the session's rules and the user.
+    Defer,
+}
+
+impl AuthorizerDecision {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Allow { .. } => "allow",
+            Self::Deny { .. } => "deny",
+            Self::Defer => "defer",
+        }
+    }
+
+    pub(crate) fn reason(&self) -> Option<&str> {
+        match self {
+            Self::Allow { reason } | Self::Deny { reason } => reason.as_deref(),
+            Self::Defer => None,
+        }
+    }
+}
+
+/// Answers permission requests from a central policy, e.g. an external
+/// policy service, before they reach the user. Consulted on every request
+/// that dry-run mode does not already deny, ahead of the session's "always"
+/// rules. An authorizer that takes longer than 10 seconds defers.
+pub trait Authorizer: Send + Sync + 'static {
+    fn authorize<'a>(
+        &'a self,
+        context: &'a PermissionContext,
+    ) -> Pin<Box<dyn Future<Output = AuthorizerDecision> + Send + 'a>>;
+}
+
+impl PermissionContext {
+    /// Read the context from an OpenCode permission request.
+    pub(crate) fn from_request(agent: &str, directory: &str, request: &Value) -> Self {
+        let text = |key: &str| {
+            request
+                .get(key)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        Self {
+            session_id: text("sessionID"),
+            request_id: text("id"),
+            agent: agent.to_string(),
+            directory: directory.to_string(),
+            permission: text("permission"),
+            patterns: request
+                .get("patterns")
+                .and_then(Value::as_array)
+                .map(|patterns| {
+                    patterns
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            metadata: request
+                .get("metadata")
+                .cloned()
+                .unwrap_or_else(|| json!({})),
+        }
+    }
+}
+
+/// Ask `authorizer`, deferring when it does not answer in time.
+pub(crate) async fn consult(
+    authorizer: &dyn Authorizer,
+    context: &PermissionContext,
+) -> AuthorizerDecision {
+    tokio::time::timeout(AUTHORIZER_TIMEOUT, authorizer.authorize(context))
+        .await
+        .unwrap_or(AuthorizerDecision::Defer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_context_from_a_permission_request() {
+        let request = json!({
+            "id": "perm_1",
+            "sessionID": "ses_1",
+            "permission": "execute",
+            "patterns": ["git status *"],
+            "metadata": {"toolKind": "execute", "rawInput": {"command": "git status -s"}},
+        });
+        let context = PermissionContext::from_request("claude", "/work", &request);
+        assert_eq!(context.session_id, "ses_1");
+        assert_eq!(context.request_id, "perm_1");
+        assert_eq!(context.permission, "execute");
+        assert_eq!(context.patterns, vec!["git status *"]);
+        assert_eq!(context.metadata["rawInput"]["command"], "git status -s");
+    }
+}
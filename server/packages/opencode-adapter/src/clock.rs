@@ -0,0 +1,115 @@
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the millisecond timestamps the adapter stamps on sessions,
+/// messages and events.
+pub trait Clock: Send + Sync {
+    fn now_ms(&self) -> i64;
+}
+
+/// The wall clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as i64)
+            .unwrap_or(0)
+    }
+}
+
+/// A clock for tests: starts at a fixed time and moves forward by `step_ms`
+/// on every read, so timestamps are reproducible and still ordered.
+#[derive(Debug)]
+pub struct FixedClock {
+    now: AtomicI64,
+    step_ms: i64,
+}
+
+impl FixedClock {
+    pub fn new(start_ms: i64, step_ms: i64) -> Self {
+        Self {
+            now: AtomicI64::new(start_ms),
+            step_ms,
+        }
+    }
+
+    /// Move the clock forward without reading it.
+    pub fn advance(&self, ms: i64) {
+        self.now.fetch_add(ms, Ordering::Relaxed);
+    }
+}
+
+impl Clock for FixedClock {
+    fn now_ms(&self) -> i64 {
+        self.now.fetch_add(self.step_ms, Ordering::Relaxed)
+    }
+}
+
+/// Source of the IDs the adapter allocates for sessions, messages, parts,
+/// requests and events. IDs are `prefix` followed by something unique.
+pub trait IdGenerator: Send + Sync {
+    fn next_id(&self, prefix: &str) -> String;
+}
+
+/// A counter shared by every prefix. The default starts from a seed mixed
+/// from the start time and process ID, so IDs do not repeat across restarts
+/// of a server that keeps its database.
+#[derive(Debug)]
+pub struct SequentialIds {
+    next: AtomicU64,
+}
+
+impl SequentialIds {
+    /// IDs counting up from `start`, for tests.
+    pub fn starting_at(start: u64) -> Self {
+        Self {
+            next: AtomicU64::new(start),
+        }
+    }
+}
+
+impl Default for SequentialIds {
+    fn default() -> Self {
+        Self::starting_at(runtime_unique_seed())
+    }
+}
+
+impl IdGenerator for SequentialIds {
+    fn next_id(&self, prefix: &str) -> String {
+        let value = self.next.fetch_add(1, Ordering::Relaxed);
+        format!("{prefix}{value}")
+    }
+}
+
+fn runtime_unique_seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or(0);
+    nanos ^ ((std::process::id() as u64) << 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_clock_steps_on_every_read() {
+        let clock = FixedClock::new(1_000, 5);
+        assert_eq!(clock.now_ms(), 1_000);
+        assert_eq!(clock.now_ms(), 1_005);
+        clock.advance(100);
+        assert_eq!(clock.now_ms(), 1_110);
+    }
+
+    #[test]
+    fn sequential_ids_share_one_counter() {
+        let ids = SequentialIds::starting_at(1);
+        assert_eq!(ids.next_id("ses_"), "ses_1");
+        assert_eq!(ids.next_id("msg_"), "msg_2");
+        assert_eq!(ids.next_id(""), "3");
+    }
+}
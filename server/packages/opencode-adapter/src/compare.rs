@@ -0,0 +1,381 @@
+use std::collections::BTreeMap;
+
+use serde_json::{json, Value};
+
+use crate::{MessageRecord, SessionMeta};
+
+/// Above this many line pairs the answer diff falls back to one changed block.
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// One user message and the assistant messages answering it.
+struct Turn<'a> {
+    user: &'a MessageRecord,
+    replies: Vec<&'a MessageRecord>,
+}
+
+fn turns(messages: &[MessageRecord]) -> Vec<Turn<'_>> {
+    let mut turns: Vec<Turn> = Vec::new();
+    for record in messages {
+        if record.info.get("role").and_then(Value::as_str) == Some("user") {
+            turns.push(Turn {
+                user: record,
+                replies: Vec::new(),
+            });
+        } else if let Some(turn) = turns.last_mut() {
+            turn.replies.push(record);
+        }
+    }
+    turns
+}
+
+/// A message as a universal item (`{ role, content: ContentPart[] }`), the
+/// shape `initialHistory` accepts. Tool results follow their call, and
+/// assistant messages carry the turn's `stop_reason`.
+pub(crate) fn universal_item(record: &MessageRecord) -> Value {
+    let role = record
+        .info
+        .get("role")
+        .and_then(Value::as_str)
+        .unwrap_or("assistant");
+    let mut content = Vec::new();
+    for part in &record.parts {
+        let text = |key: &str| part.get(key).and_then(Value::as_str).unwrap_or_default();
+        match part.get("type").and_then(Value::as_str) {
+            Some("text") if part.get("synthetic").and_then(Value::as_bool) != Some(true) => {
+                content.push(json!({"type": "text", "text": text("text")}));
+            }
+            Some("reasoning") => {
+                content.push(json!({"type": "reasoning", "text": text("text")}));
+            }
+            Some("tool") => {
+                let state = part.get("state").cloned().unwrap_or(Value::Null);
+                let input = state.get("input").cloned().unwrap_or_else(|| json!({}));
+                content.push(json!({
+                    "type": "tool_call",
+                    "name": text("tool"),
+                    "arguments": input.to_string(),
+                    "call_id": text("callID"),
+                }));
+                let output = state
+                    .get("output")
+                    .or_else(|| state.get("error"))
+                    .and_then(Value::as_str);
+                if let Some(output) = output {
+                    content.push(json!({
+                        "type": "tool_result",
+                        "call_id": text("callID"),
+                        "output": output,
+                    }));
+                }
+            }
+            Some("retry") => {
+                content.push(json!({
+                    "type": "retry",
+                    "attempt": part.get("attempt"),
+                    "error": part.pointer("/error/data/message"),
+                }));
+            }
+            Some("file") => {
+                let path = part
+                    .get("url")
+                    .and_then(Value::as_str)
+                    .map(|url| url.strip_prefix("file://").unwrap_or(url))
+                    .or_else(|| part.get("filename").and_then(Value::as_str));
+                content.push(json!({
+                    "type": "file_ref",
+                    "path": path,
+                    "mime": part.get("mime"),
+                }));
+            }
+            _ => {}
+        }
+    }
+    let mut item = json!({"role": role, "content": content});
+    // Why the agent ended its turn (`end_turn`, `max_tokens`, `refusal`, ...),
+    // when it said.
+    if let Some(stop_reason) = record
+        .info
+        .get("stopReason")
+        .filter(|_| role == "assistant")
+    {
+        item["stop_reason"] = stop_reason.clone();
+    }
+    item
+}
+
+fn item_text(item: &Value) -> String {
+    item["content"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|part| part["type"] == "text")
+        .filter_map(|part| part["text"].as_str())
+        .collect::<Vec<_>>()
+        .join("")
+}
+
+fn tool_names(item: &Value) -> Vec<String> {
+    item["content"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|part| part["type"] == "tool_call")
+        .filter_map(|part| part["name"].as_str().map(str::to_string))
+        .collect()
+}
+
+#[derive(Default)]
+struct Usage {
+    cost: f64,
+    input: u64,
+    output: u64,
+    reasoning: u64,
+    cache_read: u64,
+    cache_write: u64,
+}
+
+impl Usage {
+    fn add(&mut self, info: &Value) {
+        let tokens = &info["tokens"];
+        self.cost += info["cost"].as_f64().unwrap_or(0.0);
+        self.input += tokens["input"].as_u64().unwrap_or(0);
+        self.output += tokens["output"].as_u64().unwrap_or(0);
+        self.reasoning += tokens["reasoning"].as_u64().unwrap_or(0);
+        self.cache_read += tokens["cache"]["read"].as_u64().unwrap_or(0);
+        self.cache_write += tokens["cache"]["write"].as_u64().unwrap_or(0);
+    }
+
+    fn to_value(&self) -> Value {
+        json!({
+            "cost": self.cost,
+            "tokens": {
+                "input": self.input,
+                "output": self.output,
+                "reasoning": self.reasoning,
+                "cache": {"read": self.cache_read, "write": self.cache_write},
+            },
+        })
+    }
+}
+
+/// One side of a turn: its universal items, tools called, final answer and usage.
+fn turn_side(turn: &Turn) -> (Value, String) {
+    let items: Vec<Value> = turn.replies.iter().map(|r| universal_item(r)).collect();
+    let tools: Vec<String> = items.iter().flat_map(tool_names).collect();
+    let final_text = items
+        .iter()
+        .rev()
+        .map(item_text)
+        .find(|text| !text.trim().is_empty())
+        .unwrap_or_default();
+    let mut usage = Usage::default();
+    for reply in &turn.replies {
+        usage.add(&reply.info);
+    }
+    let mut side = json!({
+        "messageID": turn.user.info["id"],
+        "prompt": item_text(&universal_item(turn.user)),
+        "items": items,
+        "tools": tools,
+        "finalText": final_text,
+    });
+    if let (Some(side), Value::Object(usage)) = (side.as_object_mut(), usage.to_value()) {
+        side.extend(usage);
+    }
+    (side, final_text)
+}
+
+fn session_summary(meta: &SessionMeta, messages: &[MessageRecord]) -> Value {
+    let mut tools: BTreeMap<String, u64> = BTreeMap::new();
+    let mut usage = Usage::default();
+    let (mut user, mut assistant) = (0, 0);
+    for record in messages {
+        if record.info["role"] == "user" {
+            user += 1;
+            continue;
+        }
+        assistant += 1;
+        usage.add(&record.info);
+        for name in tool_names(&universal_item(record)) {
+            *tools.entry(name).or_default() += 1;
+        }
+    }
+    let mut summary = json!({
+        "sessionID": meta.id,
+        "agent": meta.agent,
+        "providerID": meta.provider_id,
+        "modelID": meta.model_id,
+        "turns": turns(messages).len(),
+        "messages": {"user": user, "assistant": assistant},
+        "toolCalls": tools.values().sum::<u64>(),
+        "tools": tools,
+    });
+    if let (Some(summary), Value::Object(usage)) = (summary.as_object_mut(), usage.to_value()) {
+        summary.extend(usage);
+    }
+    summary
+}
+
+/// Align the turns of two sessions by position and compare them.
+pub(crate) fn compare_sessions(
+    meta_a: &SessionMeta,
+    messages_a: &[MessageRecord],
+    meta_b: &SessionMeta,
+    messages_b: &[MessageRecord],
+) -> Value {
+    let turns_a = turns(messages_a);
+    let turns_b = turns(messages_b);
+    let compared: Vec<Value> = (0..turns_a.len().max(turns_b.len()))
+        .map(|index| {
+            let a = turns_a.get(index).map(turn_side);
+            let b = turns_b.get(index).map(turn_side);
+            let mut turn = json!({
+                "index": index,
+                "a": a.as_ref().map(|(side, _)| side),
+                "b": b.as_ref().map(|(side, _)| side),
+            });
+            if let (Some((side_a, text_a)), Some((side_b, text_b))) = (&a, &b) {
+                turn["samePrompt"] = json!(side_a["prompt"] == side_b["prompt"]);
+                turn["sameFinalText"] = json!(text_a == text_b);
+                turn["finalTextDiff"] = json!(line_diff(text_a, text_b));
+            }
+            turn
+        })
+        .collect();
+    json!({
+        "sessionA": session_summary(meta_a, messages_a),
+        "sessionB": session_summary(meta_b, messages_b),
+        "turns": compared,
+    })
+}
+
+/// Line diff of `a` against `b` as `{ op: equal | delete | insert, text }`.
+fn line_diff(a: &str, b: &str) -> Vec<Value> {
+    let a: Vec<&str> = a.lines().collect();
+    let b: Vec<&str> = b.lines().collect();
+    let op = |op: &str, text: &str| json!({"op": op, "text": text});
+
+    if a.len().saturating_mul(b.len()) > MAX_DIFF_CELLS {
+        let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+        let suffix = a[prefix..]
+            .iter()
+            .rev()
+            .zip(b[prefix..].iter().rev())
+            .take_while(|(x, y)| x == y)
+            .count();
+        return a[..prefix]
+            .iter()
+            .map(|line| op("equal", line))
+            .chain(
+                a[prefix..a.len() - suffix]
+                    .iter()
+                    .map(|line| op("delete", line)),
+            )
+            .chain(
+                b[prefix..b.len() - suffix]
+                    .iter()
+                    .map(|line| op("insert", line)),
+            )
+            .chain(a[a.len() - suffix..].iter().map(|line| op("equal", line)))
+            .collect();
+    }
+
+    // lcs[i][j]: longest common subsequence of a[i..] and b[j..].
+    let mut lcs = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut ops = Vec::new();
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            ops.push(op("equal", a[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            ops.push(op("delete", a[i]));
+            i += 1;
+        } else {
+            ops.push(op("insert", b[j]));
+            j += 1;
+        }
+    }
+    ops.extend(a[i..].iter().map(|line| op("delete", line)));
+    ops.extend(b[j..].iter().map(|line| op("insert", line)));
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_diff_keeps_common_lines() {
+        let ops = line_diff("a\nb\nc", "a\nx\nc");
+        let rendered: Vec<String> = ops
+            .iter()
+            .map(|op| format!("{} {}", op["op"].as_str().unwrap_or_default(), op["text"]))
+            .collect();
+        assert_eq!(
+            rendered,
+            ["equal \"a\"", "delete \"b\"", "insert \"x\"", "equal \"c\""]
+        );
+    }
+
+    #[test]
+    fn tool_results_follow_their_call() {
+        let record = MessageRecord {
+            info: json!({"role": "assistant"}),
+            parts: vec![
+                json!({"type": "tool", "tool": "bash", "callID": "c1",
+                       "state": {"status": "completed", "input": {"command": "ls"}, "output": "README.md"}}),
+                json!({"type": "text", "text": "done"}),
+            ],
+        };
+        let item = universal_item(&record);
+        let kinds: Vec<_> = item["content"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|part| part["type"].as_str().unwrap_or_default())
+            .collect();
+        assert_eq!(kinds, ["tool_call", "tool_result", "text"]);
+        assert_eq!(item["content"][0]["arguments"], "{\"command\":\"ls\"}");
+    }
+
+    #[test]
+    fn retry_parts_keep_attempt_and_error() {
+        let record = MessageRecord {
+            info: json!({"role": "assistant"}),
+            parts: vec![json!({
+                "type": "retry",
+                "attempt": 2,
+                "error": {"name": "APIError", "data": {"message": "rate limited"}},
+            })],
+        };
+        assert_eq!(
+            universal_item(&record)["content"][0],
+            json!({"type": "retry", "attempt": 2, "error": "rate limited"})
+        );
+    }
+
+    #[test]
+    fn assistant_items_carry_the_stop_reason() {
+        let record = MessageRecord {
+            info: json!({"role": "assistant", "finish": "content-filter", "stopReason": "refusal"}),
+            parts: vec![json!({"type": "text", "text": "I can't help with that."})],
+        };
+        assert_eq!(universal_item(&record)["stop_reason"], "refusal");
+        let unfinished = MessageRecord {
+            info: json!({"role": "assistant"}),
+            parts: Vec::new(),
+        };
+        assert!(universal_item(&unfinished).get("stop_reason").is_none());
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::{BTreeMap, HashMap};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const MAX_BACKENDS: usize = 8;
+const MAX_TARGET_LEN: usize = 32;
+
+/// A `SessionCreateBody.backends` entry: an agent name, or an agent with the
+/// model it should run.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub(crate) enum BackendInput {
+    Agent(String),
+    Detailed {
+        agent: String,
+        #[serde(rename = "modelID", alias = "model_id", alias = "modelId")]
+        model_id: Option<String>,
+    },
+}
+
+/// An agent a composer session can delegate prompts to with `target`. Each
+/// backend runs as its own ACP server instance next to the session's agent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ComposerBackend {
+    pub agent: String,
+    pub provider_id: String,
+    pub model_id: String,
+}
+
+/// Validate `SessionCreateBody.backends`. `defaults` gives the provider and
+/// default model of each agent a backend may run; other agents are rejected.
+pub(crate) fn parse_backends(
+    input: HashMap<String, BackendInput>,
+    defaults: impl Fn(&str) -> Option<(&'static str, &'static str)>,
+) -> Result<BTreeMap<String, ComposerBackend>, String> {
+    if input.len() > MAX_BACKENDS {
+        return Err(format!("backends may name at most {MAX_BACKENDS} targets"));
+    }
+    let mut backends = BTreeMap::new();
+    for (target, backend) in input {
+        if !valid_target(&target) {
+            return Err(format!(
+                "backend target '{target}' must match [a-z0-9][a-z0-9_-]* (at most {MAX_TARGET_LEN} characters)"
+            ));
+        }
+        let (agent, model_id) = match backend {
+            BackendInput::Agent(agent) => (agent, None),
+            BackendInput::Detailed { agent, model_id } => (agent, model_id),
+        };
+        let Some((provider_id, default_model)) = defaults(&agent) else {
+            return Err(format!("backend '{target}' names unknown agent '{agent}'"));
+        };
+        backends.insert(
+            target,
+            ComposerBackend {
+                agent,
+                provider_id: provider_id.to_string(),
+                model_id: model_id.unwrap_or_else(|| default_model.to_string()),
+            },
+        );
+    }
+    Ok(backends)
+}
+
+/// The ACP server instance `target` runs on, next to the session's own
+/// `primary` instance.
+pub(crate) fn server_id(primary: &str, target: &str) -> String {
+    format!("{primary}-{target}")
+}
+
+/// The target whose instance is `server_id`, when it is one of `backends`.
+pub(crate) fn target_for_server<'a>(
+    backends: &'a BTreeMap<String, ComposerBackend>,
+    primary: &str,
+    server_id: &str,
+) -> Option<&'a str> {
+    let target = server_id.strip_prefix(primary)?.strip_prefix('-')?;
+    backends
+        .get_key_value(target)
+        .map(|(target, _)| target.as_str())
+}
+
+fn valid_target(target: &str) -> bool {
+    let mut chars = target.chars();
+    target.len() <= MAX_TARGET_LEN
+        && chars
+            .next()
+            .is_some_and(|first| first.is_ascii_lowercase() || first.is_ascii_digit())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn defaults(agent: &str) -> Option<(&'static str, &'static str)> {
+        match agent {
+            "claude" => Some(("claude", "default")),
+            "codex" => Some(("codex", "gpt-5")),
+            _ => None,
+        }
+    }
+
+    fn parse(value: Value) -> Result<BTreeMap<String, ComposerBackend>, String> {
+        parse_backends(
+            serde_json::from_value(value).expect("backends input"),
+            defaults,
+        )
+    }
+
+    #[test]
+    fn fills_in_provider_and_default_model() {
+        let backends = parse(json!({
+            "code": "codex",
+            "review": {"agent": "claude", "modelID": "opus"},
+        }))
+        .expect("valid backends");
+        assert_eq!(
+            backends["code"],
+            ComposerBackend {
+                agent: "codex".to_string(),
+                provider_id: "codex".to_string(),
+                model_id: "gpt-5".to_string(),
+            }
+        );
+        assert_eq!(backends["review"].model_id, "opus");
+    }
+
+    #[test]
+    fn rejects_unknown_agents_and_invalid_targets() {
+        assert!(parse(json!({"code": "vim"})).is_err());
+        assert!(parse(json!({"Code": "codex"})).is_err());
+        assert!(parse(json!({"-code": "codex"})).is_err());
+        assert!(parse(json!({"a".repeat(MAX_TARGET_LEN + 1): "codex"})).is_err());
+    }
+
+    #[test]
+    fn maps_server_ids_back_to_targets() {
+        let backends = parse(json!({"code": "codex"})).expect("valid backends");
+        let server = server_id("acp_ses_1", "code");
+        assert_eq!(
+            target_for_server(&backends, "acp_ses_1", &server),
+            Some("code")
+        );
+        assert_eq!(target_for_server(&backends, "acp_ses_1", "acp_ses_1"), None);
+        assert_eq!(
+            target_for_server(&backends, "acp_ses_1", "acp_ses_1-review"),
+            None
+        );
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Repo convention files looked up in the session directory, in order.
+const CONTEXT_FILE_NAMES: &[&str] = &["AGENTS.md", "CLAUDE.md", "codex.md"];
+/// Larger files are truncated before injection.
+const MAX_CONTEXT_FILE_BYTES: usize = 64 * 1024;
+
+/// How a context file reaches the agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum ContextInjection {
+    /// The agent reads the file from its working directory on its own.
+    Native,
+    /// Appended to the system prompt through `session/new` `_meta`.
+    Meta,
+    /// Passed as a launch flag of the agent process.
+    Flag,
+    /// Prepended to the first prompt as a text part.
+    Preamble,
+}
+
+/// A context file applied to a session, reported as `contextFiles`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AppliedContextFile {
+    pub name: String,
+    pub path: String,
+    pub injection: ContextInjection,
+    pub bytes: usize,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DiscoveredContext {
+    pub files: Vec<AppliedContextFile>,
+    /// Contents of the files that are not read natively, by file name.
+    contents: HashMap<String, String>,
+}
+
+/// The injection an agent needs for a context file it does not pick up itself.
+fn injection_for(agent: &str, name: &str) -> ContextInjection {
+    match (agent, name) {
+        ("claude", "CLAUDE.md") => ContextInjection::Native,
+        ("claude", _) => ContextInjection::Meta,
+        ("codex", "AGENTS.md") => ContextInjection::Native,
+        ("codex", _) => ContextInjection::Flag,
+        ("opencode" | "cursor", "AGENTS.md" | "CLAUDE.md") | ("amp", "AGENTS.md") => {
+            ContextInjection::Native
+        }
+        _ => ContextInjection::Preamble,
+    }
+}
+
+/// Find the context files in `directory` and decide how each reaches `agent`.
+/// A file reachable under several names (e.g. `CLAUDE.md` symlinked to
+/// `AGENTS.md`) is applied once, natively if any of its names is native.
+pub(crate) fn discover(directory: &str, agent: &str) -> DiscoveredContext {
+    let mut discovered = DiscoveredContext::default();
+    let mut canonical_paths: Vec<std::path::PathBuf> = Vec::new();
+    for name in CONTEXT_FILE_NAMES {
+        let path = Path::new(directory).join(name);
+        let Ok(canonical) = path.canonicalize() else {
+            continue;
+        };
+        if !canonical.is_file() {
+            continue;
+        }
+        let injection = injection_for(agent, name);
+        if let Some(index) = canonical_paths.iter().position(|seen| *seen == canonical) {
+            let existing = &mut discovered.files[index];
+            if injection == ContextInjection::Native
+                && existing.injection != ContextInjection::Native
+            {
+                discovered.contents.remove(&existing.name);
+                existing.name = name.to_string();
+                existing.path = path.to_string_lossy().into_owned();
+                existing.injection = injection;
+            }
+            continue;
+        }
+        let Ok(bytes) = std::fs::read(&canonical) else {
+            continue;
+        };
+        let truncated = bytes.len() > MAX_CONTEXT_FILE_BYTES;
+        let text = String::from_utf8_lossy(&bytes[..bytes.len().min(MAX_CONTEXT_FILE_BYTES)]);
+        if text.trim().is_empty() {
+            continue;
+        }
+        if injection != ContextInjection::Native {
+            discovered
+                .contents
+                .insert(name.to_string(), text.into_owned());
+        }
+        canonical_paths.push(canonical);
+        discovered.files.push(AppliedContextFile {
+            name: name.to_string(),
+            path: path.to_string_lossy().into_owned(),
+            injection,
+            bytes: bytes.len(),
+            truncated,
+        });
+    }
+    discovered
+}
+
+impl DiscoveredContext {
+    fn joined(&self, injection: ContextInjection) -> Option<String> {
+        let sections: Vec<String> = self
+            .files
+            .iter()
+            .filter(|file| file.injection == injection)
+            .filter_map(|file| {
+                let text = self.contents.get(&file.name)?;
+                Some(format!(
+                    "<{}>\n{}\n</{}>",
+                    file.name,
+                    text.trim_end(),
+                    file.name
+                ))
+            })
+            .collect();
+        if sections.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "Instructions from the repository's context files:\n\n{}",
+            sections.join("\n\n")
+        ))
+    }
+
+    /// Text part prepended to the first prompt.
+    pub(crate) fn preamble(&self) -> Option<String> {
+        self.joined(ContextInjection::Preamble)
+    }
+
+    /// Entries merged into the `session/new` `_meta` object.
+    pub(crate) fn session_new_meta(&self) -> Option<Value> {
+        self.joined(ContextInjection::Meta)
+            .map(|text| json!({ "systemPrompt": { "append": text } }))
+    }
+
+    /// Extra arguments for the agent process launch.
+    pub(crate) fn launch_args(&self) -> Vec<String> {
+        match self.joined(ContextInjection::Flag) {
+            Some(text) => vec![
+                "-c".to_string(),
+                format!("developer_instructions={}", Value::String(text)),
+            ],
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "sandbox-agent-context-{name}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("create temp dir");
+        dir
+    }
+
+    #[test]
+    fn injection_depends_on_agent() {
+        let dir = temp_dir("agents");
+        std::fs::write(dir.join("AGENTS.md"), "Use tabs.").expect("write AGENTS.md");
+        std::fs::write(dir.join("CLAUDE.md"), "Run the linter.").expect("write CLAUDE.md");
+        let directory = dir.to_string_lossy();
+
+        let claude = discover(&directory, "claude");
+        let injections: Vec<_> = claude.files.iter().map(|f| f.injection).collect();
+        assert_eq!(
+            injections,
+            [ContextInjection::Meta, ContextInjection::Native]
+        );
+        let meta = claude.session_new_meta().expect("meta");
+        let append = meta["systemPrompt"]["append"].as_str().unwrap_or_default();
+        assert!(append.contains("Use tabs."));
+        assert!(!append.contains("Run the linter."));
+
+        let codex = discover(&directory, "codex");
+        let args = codex.launch_args();
+        assert_eq!(args[0], "-c");
+        assert!(args[1].starts_with("developer_instructions=\""));
+        assert!(args[1].contains("Run the linter."));
+
+        let cursor = discover(&directory, "cursor");
+        assert!(cursor
+            .files
+            .iter()
+            .all(|file| file.injection == ContextInjection::Native));
+        assert!(cursor.preamble().is_none());
+
+        let mock = discover(&directory, "mock");
+        let preamble = mock.preamble().expect("preamble");
+        assert!(preamble.contains("<AGENTS.md>\nUse tabs.\n</AGENTS.md>"));
+        assert!(mock.launch_args().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_files_apply_once() {
+        let dir = temp_dir("symlink");
+        std::fs::write(dir.join("AGENTS.md"), "Shared rules.").expect("write AGENTS.md");
+        std::os::unix::fs::symlink("AGENTS.md", dir.join("CLAUDE.md")).expect("symlink");
+
+        let claude = discover(&dir.to_string_lossy(), "claude");
+        assert_eq!(claude.files.len(), 1);
+        assert_eq!(claude.files[0].name, "CLAUDE.md");
+        assert_eq!(claude.files[0].injection, ContextInjection::Native);
+        assert!(claude.session_new_meta().is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
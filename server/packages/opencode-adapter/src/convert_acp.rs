@@ -0,0 +1,475 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// The ACP `session/update` payloads the adapter understands, decoded once so
+/// the OpenCode translation and the universal item conversion agree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AcpUpdate {
+    AgentMessageChunk {
+        text: String,
+    },
+    AgentThoughtChunk {
+        text: String,
+    },
+    ToolCall {
+        call_id: String,
+        title: String,
+        kind: Option<String>,
+        input: Value,
+        /// ACP status as sent; codex announces calls already `in_progress`,
+        /// and some already `completed`.
+        status: Option<String>,
+    },
+    ToolCallUpdate {
+        call_id: String,
+        status: Option<String>,
+        output: Option<String>,
+        /// Fields the update changes, e.g. the input once codex has parsed
+        /// the call's arguments.
+        title: Option<String>,
+        input: Option<Value>,
+    },
+    Plan {
+        entries: Vec<PlanEntry>,
+    },
+    Retry {
+        attempt: u64,
+        error: String,
+        next: Option<Value>,
+    },
+}
+
+/// An ACP plan entry; the same fields OpenCode uses for a todo.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlanEntry {
+    pub content: String,
+    #[serde(default = "default_priority")]
+    pub priority: String,
+    #[serde(default = "default_status")]
+    pub status: String,
+}
+
+fn default_priority() -> String {
+    "medium".to_string()
+}
+
+fn default_status() -> String {
+    "pending".to_string()
+}
+
+impl AcpUpdate {
+    /// Decode `session/update` params (`{ sessionId, update }`) or the update
+    /// itself. `None` for kinds the adapter does not translate.
+    pub fn from_acp(params: &Value) -> Option<Self> {
+        let update = params.get("update").unwrap_or(params);
+        let text = |key: &str| update.get(key).and_then(Value::as_str).map(str::to_string);
+        let call_id = || text("toolCallId").unwrap_or_else(|| "unknown".to_string());
+        match update.get("sessionUpdate").and_then(Value::as_str)? {
+            // Claude sends its thinking as `thinking` content blocks.
+            "agent_message_chunk" if is_thinking(update) => Some(Self::AgentThoughtChunk {
+                text: chunk_text(update),
+            }),
+            "agent_message_chunk" => Some(Self::AgentMessageChunk {
+                text: chunk_text(update),
+            }),
+            "agent_thought_chunk" => Some(Self::AgentThoughtChunk {
+                text: chunk_text(update),
+            }),
+            "tool_call" => Some(Self::ToolCall {
+                call_id: call_id(),
+                title: text("title").unwrap_or_else(|| "unknown".to_string()),
+                kind: text("kind"),
+                input: update.get("rawInput").cloned().unwrap_or_else(|| json!({})),
+                status: text("status"),
+            }),
+            "tool_call_update" => Some(Self::ToolCallUpdate {
+                call_id: call_id(),
+                status: text("status"),
+                output: tool_output(update),
+                title: text("title"),
+                input: update
+                    .get("rawInput")
+                    .filter(|input| !input.is_null())
+                    .cloned(),
+            }),
+            "plan" => Some(Self::Plan {
+                entries: update
+                    .get("entries")
+                    .and_then(|entries| serde_json::from_value(entries.clone()).ok())
+                    .unwrap_or_default(),
+            }),
+            "retry" => Some(Self::Retry {
+                attempt: update.get("attempt").and_then(Value::as_u64).unwrap_or(1),
+                error: match update.get("error") {
+                    Some(Value::String(message)) => message.clone(),
+                    Some(error) => error
+                        .get("message")
+                        .and_then(Value::as_str)
+                        .unwrap_or("agent error")
+                        .to_string(),
+                    None => "agent error".to_string(),
+                },
+                next: update.get("next").filter(|next| !next.is_null()).cloned(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The update as an ACP `session/update` `update` object.
+    pub fn to_acp(&self) -> Value {
+        match self {
+            Self::AgentMessageChunk { text } => json!({
+                "sessionUpdate": "agent_message_chunk",
+                "content": {"type": "text", "text": text},
+            }),
+            Self::AgentThoughtChunk { text } => json!({
+                "sessionUpdate": "agent_thought_chunk",
+                "content": {"type": "text", "text": text},
+            }),
+            Self::ToolCall {
+                call_id,
+                title,
+                kind,
+                input,
+                status,
+            } => {
+                let mut update = json!({
+                    "sessionUpdate": "tool_call",
+                    "toolCallId": call_id,
+                    "title": title,
+                    "rawInput": input,
+                });
+                if let Some(kind) = kind {
+                    update["kind"] = json!(kind);
+                }
+                if let Some(status) = status {
+                    update["status"] = json!(status);
+                }
+                update
+            }
+            Self::ToolCallUpdate {
+                call_id,
+                status,
+                output,
+                title,
+                input,
+            } => {
+                let mut update = json!({
+                    "sessionUpdate": "tool_call_update",
+                    "toolCallId": call_id,
+                });
+                if let Some(status) = status {
+                    update["status"] = json!(status);
+                }
+                if let Some(title) = title {
+                    update["title"] = json!(title);
+                }
+                if let Some(input) = input {
+                    update["rawInput"] = input.clone();
+                }
+                if let Some(output) = output {
+                    update["content"] = json!([{
+                        "type": "content",
+                        "content": {"type": "text", "text": output},
+                    }]);
+                }
+                update
+            }
+            Self::Plan { entries } => json!({
+                "sessionUpdate": "plan",
+                "entries": entries,
+            }),
+            Self::Retry {
+                attempt,
+                error,
+                next,
+            } => {
+                let mut update = json!({
+                    "sessionUpdate": "retry",
+                    "attempt": attempt,
+                    "error": {"message": error},
+                });
+                if let Some(next) = next {
+                    update["next"] = next.clone();
+                }
+                update
+            }
+        }
+    }
+
+    /// The update as a universal content part (`ContentPart`), the shape of
+    /// `initialHistory` items and `/compare` turns.
+    pub fn to_universal(&self) -> Value {
+        match self {
+            Self::AgentMessageChunk { text } => json!({"type": "text", "text": text}),
+            Self::AgentThoughtChunk { text } => json!({
+                "type": "reasoning",
+                "text": text,
+                "visibility": "public",
+            }),
+            Self::ToolCall {
+                call_id,
+                title,
+                input,
+                ..
+            } => json!({
+                "type": "tool_call",
+                "name": title,
+                "arguments": input.to_string(),
+                "call_id": call_id,
+            }),
+            Self::ToolCallUpdate {
+                call_id, output, ..
+            } => json!({
+                "type": "tool_result",
+                "call_id": call_id,
+                "output": output.as_deref().unwrap_or_default(),
+            }),
+            Self::Plan { entries } => json!({"type": "json", "json": {"plan": entries}}),
+            Self::Retry { attempt, error, .. } => json!({
+                "type": "retry",
+                "attempt": attempt,
+                "error": error,
+            }),
+        }
+    }
+
+    /// Inverse of [`to_universal`](Self::to_universal).
+    pub fn from_universal(part: &Value) -> Option<Self> {
+        let text = |key: &str| {
+            part.get(key)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        match part.get("type").and_then(Value::as_str)? {
+            "text" => Some(Self::AgentMessageChunk { text: text("text") }),
+            "reasoning" => Some(Self::AgentThoughtChunk { text: text("text") }),
+            "tool_call" => {
+                let arguments = text("arguments");
+                Some(Self::ToolCall {
+                    call_id: text("call_id"),
+                    title: text("name"),
+                    kind: None,
+                    input: serde_json::from_str(&arguments)
+                        .unwrap_or_else(|_| json!({"arguments": arguments})),
+                    status: None,
+                })
+            }
+            "tool_result" => Some(Self::ToolCallUpdate {
+                call_id: text("call_id"),
+                status: Some("completed".to_string()),
+                output: Some(text("output")),
+                title: None,
+                input: None,
+            }),
+            "json" => {
+                let entries = part.pointer("/json/plan")?;
+                Some(Self::Plan {
+                    entries: serde_json::from_value(entries.clone()).ok()?,
+                })
+            }
+            "retry" => Some(Self::Retry {
+                attempt: part.get("attempt").and_then(Value::as_u64).unwrap_or(1),
+                error: text("error"),
+                next: None,
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn is_thinking(update: &Value) -> bool {
+    matches!(
+        update.pointer("/content/type").and_then(Value::as_str),
+        Some("thinking" | "redacted_thinking")
+    )
+}
+
+/// `ContentChunk.content` is a content block; only text and thinking are
+/// streamed. Redacted thinking carries no readable text.
+fn chunk_text(update: &Value) -> String {
+    update
+        .pointer("/content/text")
+        .or_else(|| update.pointer("/content/thinking"))
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// The OpenCode tool state status for an ACP tool call status: `pending`,
+/// `running`, `completed` or `error`. `None` for unknown statuses.
+pub(crate) fn tool_state_status(status: &str) -> Option<&'static str> {
+    match status {
+        "pending" => Some("pending"),
+        "in_progress" | "running" => Some("running"),
+        "completed" => Some("completed"),
+        "failed" | "error" => Some("error"),
+        _ => None,
+    }
+}
+
+/// First text of a tool call's content, whether wrapped in a `content` block
+/// as ACP specifies or given as a bare text block. Without any, the call's
+/// `rawOutput`: codex reports a function call's result there.
+fn tool_output(update: &Value) -> Option<String> {
+    update
+        .get("content")
+        .and_then(Value::as_array)
+        .and_then(|content| {
+            content.iter().find_map(|item| {
+                item.pointer("/content/text")
+                    .or_else(|| item.get("text"))
+                    .and_then(Value::as_str)
+            })
+        })
+        .map(str::to_string)
+        .or_else(|| match update.get("rawOutput")? {
+            Value::Null => None,
+            Value::String(output) => Some(output.clone()),
+            raw => Some(
+                ["formatted_output", "output", "aggregated_output"]
+                    .iter()
+                    .find_map(|key| raw.get(*key).and_then(Value::as_str))
+                    .map_or_else(|| raw.to_string(), str::to_string),
+            ),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_session_update_params() {
+        let params = json!({
+            "sessionId": "s-1",
+            "update": {
+                "sessionUpdate": "tool_call_update",
+                "toolCallId": "call-1",
+                "status": "completed",
+                "content": [{"type": "content", "content": {"type": "text", "text": "README.md"}}],
+            },
+        });
+        assert_eq!(
+            AcpUpdate::from_acp(&params),
+            Some(AcpUpdate::ToolCallUpdate {
+                call_id: "call-1".to_string(),
+                status: Some("completed".to_string()),
+                output: Some("README.md".to_string()),
+                title: None,
+                input: None,
+            })
+        );
+        assert_eq!(
+            AcpUpdate::from_acp(&json!({"sessionUpdate": "available_commands_update"})),
+            None
+        );
+    }
+
+    #[test]
+    fn round_trips_through_acp_and_universal() {
+        let updates = [
+            AcpUpdate::AgentMessageChunk {
+                text: "hello".to_string(),
+            },
+            AcpUpdate::AgentThoughtChunk {
+                text: "thinking".to_string(),
+            },
+            AcpUpdate::ToolCall {
+                call_id: "call-1".to_string(),
+                title: "bash".to_string(),
+                kind: None,
+                input: json!({"command": "ls"}),
+                status: None,
+            },
+            AcpUpdate::ToolCallUpdate {
+                call_id: "call-1".to_string(),
+                status: Some("completed".to_string()),
+                output: Some("README.md".to_string()),
+                title: None,
+                input: None,
+            },
+            AcpUpdate::Plan {
+                entries: vec![PlanEntry {
+                    content: "Write tests".to_string(),
+                    priority: "high".to_string(),
+                    status: "in_progress".to_string(),
+                }],
+            },
+            AcpUpdate::Retry {
+                attempt: 2,
+                error: "rate limited".to_string(),
+                next: None,
+            },
+        ];
+        for update in updates {
+            assert_eq!(
+                AcpUpdate::from_acp(&update.to_acp()).as_ref(),
+                Some(&update)
+            );
+            assert_eq!(
+                AcpUpdate::from_universal(&update.to_universal()).as_ref(),
+                Some(&update)
+            );
+        }
+    }
+
+    #[test]
+    fn codex_function_results_come_from_raw_output() {
+        let update = json!({
+            "sessionUpdate": "tool_call_update",
+            "toolCallId": "call_7",
+            "status": "failed",
+            "rawInput": {"command": ["cargo", "test"]},
+            "rawOutput": {"exit_code": 101, "formatted_output": "test result: FAILED"},
+        });
+        assert_eq!(
+            AcpUpdate::from_acp(&update),
+            Some(AcpUpdate::ToolCallUpdate {
+                call_id: "call_7".to_string(),
+                status: Some("failed".to_string()),
+                output: Some("test result: FAILED".to_string()),
+                title: None,
+                input: Some(json!({"command": ["cargo", "test"]})),
+            })
+        );
+        assert_eq!(tool_state_status("in_progress"), Some("running"));
+        assert_eq!(tool_state_status("failed"), Some("error"));
+        assert_eq!(tool_state_status("cancelled"), None);
+    }
+
+    #[test]
+    fn thinking_blocks_are_thoughts() {
+        let thinking = json!({
+            "sessionUpdate": "agent_message_chunk",
+            "content": {"type": "thinking", "thinking": "Check the tests", "signature": "sig"},
+        });
+        assert_eq!(
+            AcpUpdate::from_acp(&thinking),
+            Some(AcpUpdate::AgentThoughtChunk {
+                text: "Check the tests".to_string(),
+            })
+        );
+        let redacted = json!({
+            "sessionUpdate": "agent_thought_chunk",
+            "content": {"type": "redacted_thinking", "data": "opaque"},
+        });
+        assert_eq!(
+            AcpUpdate::from_acp(&redacted),
+            Some(AcpUpdate::AgentThoughtChunk {
+                text: String::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn plan_entries_default_priority_and_status() {
+        let update = json!({"sessionUpdate": "plan", "entries": [{"content": "Ship it"}]});
+        let Some(AcpUpdate::Plan { entries }) = AcpUpdate::from_acp(&update) else {
+            panic!("expected a plan");
+        };
+        assert_eq!(entries[0].priority, "medium");
+        assert_eq!(entries[0].status, "pending");
+    }
+}
@@ -0,0 +1,374 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// `PRAGMA application_id` of adapter databases ("SAOC").
+pub(crate) const SQLITE_APPLICATION_ID: i32 = 0x5341_4f43;
+
+const SQLITE_HEADER_MAGIC: &[u8] = b"SQLite format 3\0";
+const SQLITE_APPLICATION_ID_OFFSET: usize = 68;
+const TAKEOVER_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const DEFAULT_TAKEOVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The instance holding a database lock, as recorded in the lock file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LockOwner {
+    pub pid: u32,
+    pub instance_id: String,
+    /// Incremented on every acquisition; an instance whose epoch is no longer
+    /// the one in the lock file has been fenced.
+    pub epoch: u64,
+    pub acquired_at_ms: i64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DatabaseLockError {
+    #[error(
+        "database {db} is in use by another sandbox-agent (pid {}); stop it or start with --force-takeover",
+        .owner.pid
+    )]
+    Held { db: String, owner: LockOwner },
+    #[error("database {db} is in use by another sandbox-agent and its lock file is unreadable")]
+    HeldByUnknown { db: String },
+    #[error(
+        "the sandbox-agent holding {db} (pid {}) did not release it within {timeout:?} of the takeover",
+        .owner.pid
+    )]
+    TakeoverTimeout {
+        db: String,
+        owner: LockOwner,
+        timeout: Duration,
+    },
+    #[error("{db} is not a sandbox-agent database (application_id {found:#x})")]
+    ForeignDatabase { db: String, found: i32 },
+    #[error("failed to lock database {db}: {source}")]
+    Io {
+        db: String,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+/// An advisory lock on `<db>.lock` that keeps two adapters off one SQLite
+/// database. Held until dropped, or until another instance takes the
+/// database over with [`DatabaseLock::acquire_with_takeover`], at which point
+/// this instance is fenced: [`DatabaseLock::check_fence`] reports it, and
+/// once the instance has stopped using the database,
+/// [`DatabaseLock::release`] lets the new owner proceed.
+#[derive(Debug)]
+pub struct DatabaseLock {
+    db_path: String,
+    lock_path: PathBuf,
+    file: StdMutex<Option<File>>,
+    owner: LockOwner,
+    fenced: AtomicBool,
+}
+
+impl DatabaseLock {
+    /// Lock `db_path`, failing with [`DatabaseLockError::Held`] if another
+    /// instance has it.
+    pub fn acquire(db_path: &str) -> Result<Self, DatabaseLockError> {
+        Self::acquire_inner(db_path, None)
+    }
+
+    /// Lock `db_path`, fencing the instance that holds it and waiting up to
+    /// `timeout` (10s when `None`) for it to let go.
+    pub fn acquire_with_takeover(
+        db_path: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Self, DatabaseLockError> {
+        Self::acquire_inner(db_path, Some(timeout.unwrap_or(DEFAULT_TAKEOVER_TIMEOUT)))
+    }
+
+    fn acquire_inner(db_path: &str, takeover: Option<Duration>) -> Result<Self, DatabaseLockError> {
+        let io_error = |source| DatabaseLockError::Io {
+            db: db_path.to_string(),
+            source,
+        };
+        check_application_id(db_path)?;
+        let lock_path = PathBuf::from(format!("{db_path}.lock"));
+        if let Some(parent) = lock_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent).map_err(io_error)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(io_error)?;
+
+        let previous = read_owner(&mut file);
+        let owner = LockOwner {
+            pid: std::process::id(),
+            instance_id: new_instance_id(),
+            epoch: previous.as_ref().map_or(1, |owner| owner.epoch + 1),
+            acquired_at_ms: now_ms(),
+        };
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::Error(err)) => return Err(io_error(err)),
+            Err(TryLockError::WouldBlock) => {
+                let Some(timeout) = takeover else {
+                    return Err(match previous {
+                        Some(owner) => DatabaseLockError::Held {
+                            db: db_path.to_string(),
+                            owner,
+                        },
+                        None => DatabaseLockError::HeldByUnknown {
+                            db: db_path.to_string(),
+                        },
+                    });
+                };
+                // Recording the new owner is what fences the old one; it
+                // releases the file lock once it notices.
+                write_owner(&mut file, &owner).map_err(io_error)?;
+                let deadline = Instant::now() + timeout;
+                loop {
+                    match file.try_lock() {
+                        Ok(()) => break,
+                        Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                            std::thread::sleep(TAKEOVER_POLL_INTERVAL);
+                        }
+                        Err(TryLockError::WouldBlock) => {
+                            // Hand the database back rather than leave the
+                            // holder to fence itself later with no successor.
+                            if let Some(previous) = previous.as_ref() {
+                                if read_owner(&mut file).is_some_and(|current| current == owner) {
+                                    write_owner(&mut file, previous).map_err(io_error)?;
+                                }
+                            }
+                            return Err(DatabaseLockError::TakeoverTimeout {
+                                db: db_path.to_string(),
+                                owner: previous.unwrap_or_else(|| owner.clone()),
+                                timeout,
+                            });
+                        }
+                        Err(TryLockError::Error(err)) => return Err(io_error(err)),
+                    }
+                }
+                tracing::warn!(
+                    db = db_path,
+                    previous_pid = previous.as_ref().map(|owner| owner.pid),
+                    "took over opencode database from another instance"
+                );
+            }
+        }
+        write_owner(&mut file, &owner).map_err(io_error)?;
+
+        Ok(Self {
+            db_path: db_path.to_string(),
+            lock_path,
+            file: StdMutex::new(Some(file)),
+            owner,
+            fenced: AtomicBool::new(false),
+        })
+    }
+
+    pub fn db_path(&self) -> &str {
+        &self.db_path
+    }
+
+    pub fn owner(&self) -> &LockOwner {
+        &self.owner
+    }
+
+    pub fn is_fenced(&self) -> bool {
+        self.fenced.load(Ordering::SeqCst)
+    }
+
+    /// Whether another instance has taken the database over.
+    pub fn check_fence(&self) -> bool {
+        if self.is_fenced() {
+            return true;
+        }
+        let current = File::open(&self.lock_path)
+            .ok()
+            .and_then(|mut file| read_owner(&mut file));
+        let taken_over = current.is_some_and(|owner| owner.instance_id != self.owner.instance_id);
+        if taken_over {
+            self.fenced.store(true, Ordering::SeqCst);
+        }
+        taken_over
+    }
+
+    /// Give up the file lock after being fenced, so the instance taking over
+    /// can proceed.
+    pub fn release(&self) {
+        self.file.lock().unwrap().take();
+    }
+}
+
+/// Refuse SQLite databases some other application has claimed. Missing,
+/// empty and unclaimed (`application_id` 0) files are fine.
+pub(crate) fn check_application_id(db_path: &str) -> Result<(), DatabaseLockError> {
+    let mut header = [0_u8; 100];
+    let read = match File::open(db_path) {
+        Ok(mut file) => read_prefix(&mut file, &mut header),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(source) => {
+            return Err(DatabaseLockError::Io {
+                db: db_path.to_string(),
+                source,
+            })
+        }
+    };
+    if read < header.len() || !header.starts_with(SQLITE_HEADER_MAGIC) {
+        return Ok(());
+    }
+    let offset = SQLITE_APPLICATION_ID_OFFSET;
+    let found = i32::from_be_bytes([
+        header[offset],
+        header[offset + 1],
+        header[offset + 2],
+        header[offset + 3],
+    ]);
+    validate_application_id(db_path, found)
+}
+
+pub(crate) fn validate_application_id(db_path: &str, found: i32) -> Result<(), DatabaseLockError> {
+    if found == 0 || found == SQLITE_APPLICATION_ID {
+        Ok(())
+    } else {
+        Err(DatabaseLockError::ForeignDatabase {
+            db: db_path.to_string(),
+            found,
+        })
+    }
+}
+
+fn read_prefix(file: &mut File, buffer: &mut [u8]) -> usize {
+    let mut read = 0;
+    while read < buffer.len() {
+        match file.read(&mut buffer[read..]) {
+            Ok(0) | Err(_) => break,
+            Ok(count) => read += count,
+        }
+    }
+    read
+}
+
+fn read_owner(file: &mut File) -> Option<LockOwner> {
+    let mut raw = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut raw).ok()?;
+    serde_json::from_str(raw.trim()).ok()
+}
+
+fn write_owner(file: &mut File, owner: &LockOwner) -> std::io::Result<()> {
+    let raw = serde_json::to_vec(owner).map_err(std::io::Error::other)?;
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&raw)?;
+    file.sync_data()
+}
+
+fn new_instance_id() -> String {
+    let mut bytes = [0_u8; 8];
+    let _ = getrandom::getrandom(&mut bytes);
+    format!("inst_{}", hex::encode(bytes))
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+impl Drop for DatabaseLock {
+    fn drop(&mut self) {
+        // Leave the lock file to a newer owner; only clear our own record.
+        if let Some(mut file) = self.file.lock().unwrap().take() {
+            if read_owner(&mut file)
+                .is_some_and(|owner| owner.instance_id == self.owner.instance_id)
+            {
+                let _ = file.set_len(0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!(
+            "sandbox-agent-db-lock-{name}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("create temp dir");
+        dir.join("opencode.db").to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn second_instance_is_refused_with_the_holder() {
+        let path = db_path("held");
+        let first = DatabaseLock::acquire(&path).unwrap();
+        match DatabaseLock::acquire(&path) {
+            Err(DatabaseLockError::Held { owner, .. }) => assert_eq!(&owner, first.owner()),
+            other => panic!("expected Held, got {other:?}"),
+        }
+        drop(first);
+        let again = DatabaseLock::acquire(&path).unwrap();
+        assert_eq!(again.owner().epoch, 1);
+    }
+
+    #[test]
+    fn takeover_fences_the_previous_owner() {
+        let path = db_path("takeover");
+        let first = std::sync::Arc::new(DatabaseLock::acquire(&path).unwrap());
+        let watcher = {
+            let first = first.clone();
+            std::thread::spawn(move || {
+                while !first.check_fence() {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                first.release();
+            })
+        };
+        let second = DatabaseLock::acquire_with_takeover(&path, Some(Duration::from_secs(5)))
+            .expect("takeover");
+        watcher.join().unwrap();
+        assert!(first.is_fenced());
+        assert!(!second.check_fence());
+        assert_eq!(second.owner().epoch, first.owner().epoch + 1);
+
+        // An owner that never lets go times the takeover out, and keeps the
+        // database.
+        let stuck = DatabaseLock::acquire_with_takeover(&path, Some(Duration::from_millis(100)));
+        assert!(matches!(
+            stuck,
+            Err(DatabaseLockError::TakeoverTimeout { .. })
+        ));
+        assert!(!second.check_fence());
+    }
+
+    #[test]
+    fn foreign_sqlite_files_are_rejected() {
+        let path = db_path("foreign");
+        let mut header = vec![0_u8; 100];
+        header[..16].copy_from_slice(SQLITE_HEADER_MAGIC);
+        header[68..72].copy_from_slice(&0x0f05_5112_i32.to_be_bytes());
+        std::fs::write(&path, &header).unwrap();
+        assert!(matches!(
+            DatabaseLock::acquire(&path),
+            Err(DatabaseLockError::ForeignDatabase {
+                found: 0x0f05_5112,
+                ..
+            })
+        ));
+
+        header[68..72].copy_from_slice(&SQLITE_APPLICATION_ID.to_be_bytes());
+        std::fs::write(&path, &header).unwrap();
+        assert!(DatabaseLock::acquire(&path).is_ok());
+    }
+}
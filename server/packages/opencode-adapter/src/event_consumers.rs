@@ -0,0 +1,198 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::{Row, SqlitePool};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Most events kept in the persisted log; older ones are dropped even when a
+/// consumer has not acknowledged them.
+pub(crate) const EVENT_LOG_RETENTION: i64 = 100_000;
+/// Most events written to the log in one transaction.
+const WRITE_BATCH_SIZE: usize = 256;
+const MAX_CONSUMER_ID_LEN: usize = 128;
+
+/// `POST /event/ack`: the last event a consumer has processed.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AckBody {
+    pub consumer: String,
+    /// Event ID, as sent in the SSE `id` field.
+    pub id: u64,
+}
+
+/// An emitted event on its way to the persisted log.
+#[derive(Debug)]
+pub(crate) struct LoggedEvent {
+    pub id: u64,
+    pub payload: Value,
+    pub created_at: i64,
+}
+
+/// Consumer IDs are chosen by the client: letters, digits and `.`, `_`,
+/// `-`, `:`, up to 128 characters.
+pub(crate) fn validate_consumer_id(consumer: &str) -> Result<(), String> {
+    if consumer.is_empty() || consumer.len() > MAX_CONSUMER_ID_LEN {
+        return Err(format!(
+            "consumer must be 1 to {MAX_CONSUMER_ID_LEN} characters"
+        ));
+    }
+    if !consumer
+        .chars()
+        .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '.' | '_' | '-' | ':'))
+    {
+        return Err("consumer may only contain letters, digits, '.', '_', '-' and ':'".to_string());
+    }
+    Ok(())
+}
+
+/// Spawn the task that appends emitted events to the persisted log, in
+/// batches, and drops events past [`EVENT_LOG_RETENTION`].
+pub(crate) fn spawn_writer(pool: SqlitePool) -> mpsc::UnboundedSender<LoggedEvent> {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<LoggedEvent>();
+    tokio::spawn(async move {
+        while let Some(first) = receiver.recv().await {
+            let mut batch = vec![first];
+            while batch.len() < WRITE_BATCH_SIZE {
+                match receiver.try_recv() {
+                    Ok(event) => batch.push(event),
+                    Err(_) => break,
+                }
+            }
+            if let Err(err) = write_batch(&pool, &batch).await {
+                warn!(?err, count = batch.len(), "failed to persist stream events");
+            }
+        }
+    });
+    sender
+}
+
+async fn write_batch(pool: &SqlitePool, batch: &[LoggedEvent]) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for event in batch {
+        sqlx::query(
+            "INSERT OR IGNORE INTO stream_events (id, payload_json, created_at) VALUES (?1, ?2, ?3)",
+        )
+        .bind(event.id as i64)
+        .bind(event.payload.to_string())
+        .bind(event.created_at)
+        .execute(&mut *tx)
+        .await?;
+    }
+    if let Some(last) = batch.last() {
+        sqlx::query("DELETE FROM stream_events WHERE id <= ?1")
+            .bind(last.id as i64 - EVENT_LOG_RETENTION)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await
+}
+
+/// Whether any consumer is registered, and the highest event ID recorded,
+/// so that IDs keep increasing across restarts.
+pub(crate) async fn load_state(pool: &SqlitePool) -> Result<(bool, u64), sqlx::Error> {
+    let consumers: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM event_consumers")
+        .fetch_one(pool)
+        .await?;
+    let last_id: Option<i64> = sqlx::query_scalar(
+        "SELECT MAX(id) FROM (SELECT MAX(id) AS id FROM stream_events
+                             UNION ALL SELECT MAX(acked_id) FROM event_consumers)",
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok((consumers > 0, last_id.unwrap_or(0).max(0) as u64))
+}
+
+/// The consumer's acknowledged event ID, registering it at `start` when it
+/// is new.
+pub(crate) async fn register(
+    pool: &SqlitePool,
+    consumer: &str,
+    start: u64,
+    now: i64,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query(
+        "INSERT OR IGNORE INTO event_consumers (consumer, acked_id, updated_at) VALUES (?1, ?2, ?3)",
+    )
+    .bind(consumer)
+    .bind(start as i64)
+    .bind(now)
+    .execute(pool)
+    .await?;
+    let acked: i64 = sqlx::query_scalar("SELECT acked_id FROM event_consumers WHERE consumer = ?1")
+        .bind(consumer)
+        .fetch_one(pool)
+        .await?;
+    Ok(acked.max(0) as u64)
+}
+
+/// Move the consumer's acknowledged ID forward to `id`; an older ack leaves
+/// it where it is. Drops logged events every consumer has acknowledged.
+/// Returns the acknowledged ID.
+pub(crate) async fn ack(
+    pool: &SqlitePool,
+    consumer: &str,
+    id: u64,
+    now: i64,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query(
+        r#"INSERT INTO event_consumers (consumer, acked_id, updated_at) VALUES (?1, ?2, ?3)
+           ON CONFLICT(consumer) DO UPDATE SET
+             acked_id = MAX(acked_id, excluded.acked_id),
+             updated_at = excluded.updated_at"#,
+    )
+    .bind(consumer)
+    .bind(id as i64)
+    .bind(now)
+    .execute(pool)
+    .await?;
+    sqlx::query(
+        "DELETE FROM stream_events WHERE id <= (SELECT MIN(acked_id) FROM event_consumers)",
+    )
+    .execute(pool)
+    .await?;
+    let acked: i64 = sqlx::query_scalar("SELECT acked_id FROM event_consumers WHERE consumer = ?1")
+        .bind(consumer)
+        .fetch_one(pool)
+        .await?;
+    Ok(acked.max(0) as u64)
+}
+
+/// Up to `limit` logged events after `after`, oldest first, as
+/// `(id, payload)`.
+pub(crate) async fn events_after(
+    pool: &SqlitePool,
+    after: u64,
+    limit: usize,
+) -> Result<Vec<(u64, Value)>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT id, payload_json FROM stream_events WHERE id > ?1 ORDER BY id LIMIT ?2",
+    )
+    .bind(after as i64)
+    .bind(limit.min(i64::MAX as usize) as i64)
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter()
+        .map(|row| {
+            let id: i64 = row.try_get("id")?;
+            let payload: String = row.try_get("payload_json")?;
+            Ok((
+                id as u64,
+                serde_json::from_str(&payload).unwrap_or(Value::Null),
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_consumer_ids() {
+        assert!(validate_consumer_id("ci-bot:deploy.v2").is_ok());
+        assert!(validate_consumer_id("").is_err());
+        assert!(validate_consumer_id("a b").is_err());
+        assert!(validate_consumer_id(&"x".repeat(129)).is_err());
+    }
+}
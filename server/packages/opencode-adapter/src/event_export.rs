@@ -0,0 +1,128 @@
+use std::io::Write;
+
+use axum::body::Bytes;
+use futures::stream::{self, Stream};
+use serde_json::{json, Value};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+
+/// Rows read from SQLite per query; the export holds at most one page.
+const EXPORT_PAGE_SIZE: i64 = 500;
+const ZSTD_LEVEL: i32 = 3;
+
+/// Where the next page starts: after this `(created_at, rowid)`.
+#[derive(Debug, Clone, Copy)]
+struct Cursor {
+    created_at: i64,
+    rowid: i64,
+}
+
+struct Export<M> {
+    pool: SqlitePool,
+    until: i64,
+    cursor: Cursor,
+    /// Opened with the first page and taken when the frame is finished.
+    encoder: Option<zstd::stream::write::Encoder<'static, Vec<u8>>>,
+    finished: bool,
+    mask: M,
+}
+
+/// Every persisted event with `since < created_at <= until`, oldest first,
+/// as zstd-compressed newline-delimited JSON. Pages are read by keyset, so
+/// memory use does not grow with the log; each page is flushed as one
+/// chunk. `mask` redacts each payload before it is written.
+pub(crate) fn export_events<M>(
+    pool: SqlitePool,
+    since: i64,
+    until: i64,
+    mask: M,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>>
+where
+    M: Fn(&mut Value) + Send + 'static,
+{
+    let export = Export {
+        pool,
+        until,
+        cursor: Cursor {
+            created_at: since,
+            rowid: i64::MAX,
+        },
+        encoder: None,
+        finished: false,
+        mask,
+    };
+    stream::unfold(Some(export), |export| async move {
+        let mut export = export?;
+        match export.next_chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), Some(export))),
+            Ok(None) => None,
+            Err(err) => Some((Err(err), None)),
+        }
+    })
+}
+
+impl<M: Fn(&mut Value)> Export<M> {
+    /// The next compressed page, the end of the frame once the rows run out,
+    /// then `None`.
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, std::io::Error> {
+        if self.finished {
+            return Ok(None);
+        }
+        let rows = sqlx::query(
+            r#"SELECT rowid, id, session_id, created_at, connection_id, sender, payload_json
+               FROM events
+               WHERE (created_at, rowid) > (?1, ?2) AND created_at <= ?3
+               ORDER BY created_at ASC, rowid ASC
+               LIMIT ?4"#,
+        )
+        .bind(self.cursor.created_at)
+        .bind(self.cursor.rowid)
+        .bind(self.until)
+        .bind(EXPORT_PAGE_SIZE)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(std::io::Error::other)?;
+
+        if rows.is_empty() {
+            self.finished = true;
+            let encoder = match self.encoder.take() {
+                Some(encoder) => encoder,
+                None => zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?,
+            };
+            return encoder.finish().map(|bytes| Some(Bytes::from(bytes)));
+        }
+        let encoder = match self.encoder.as_mut() {
+            Some(encoder) => encoder,
+            None => self
+                .encoder
+                .insert(zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?),
+        };
+        for row in rows {
+            let rowid: i64 = column(&row, "rowid")?;
+            let created_at: i64 = column(&row, "created_at")?;
+            let payload_json: String = column(&row, "payload_json")?;
+            let mut payload = serde_json::from_str(&payload_json).unwrap_or(Value::Null);
+            (self.mask)(&mut payload);
+            let line = json!({
+                "id": column::<String>(&row, "id")?,
+                "sessionID": column::<String>(&row, "session_id")?,
+                "createdAt": created_at,
+                "connectionID": column::<String>(&row, "connection_id")?,
+                "sender": column::<String>(&row, "sender")?,
+                "payload": payload,
+            });
+            serde_json::to_writer(&mut *encoder, &line)?;
+            encoder.write_all(b"\n")?;
+            self.cursor = Cursor { created_at, rowid };
+        }
+        encoder.flush()?;
+        Ok(Some(Bytes::from(std::mem::take(encoder.get_mut()))))
+    }
+}
+
+fn column<T>(row: &SqliteRow, name: &str) -> Result<T, std::io::Error>
+where
+    T: for<'r> sqlx::Decode<'r, sqlx::Sqlite> + sqlx::Type<sqlx::Sqlite>,
+{
+    row.try_get(name).map_err(std::io::Error::other)
+}
@@ -0,0 +1,142 @@
+use serde_json::{Map, Value};
+
+/// Most pointers a single `?select=` may list.
+const MAX_SELECT_POINTERS: usize = 32;
+
+/// `?select=` on the event endpoints: a comma-separated list of JSON
+/// pointers (`/type,/properties/sessionID`). Each event is reduced to the
+/// selected fields, nested as they are in the full event. `/type` is always
+/// kept so consumers can still dispatch on it; pointers an event lacks are
+/// skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EventSelect {
+    /// Unescaped reference tokens of each pointer.
+    pointers: Vec<Vec<String>>,
+}
+
+impl EventSelect {
+    pub(crate) fn parse(raw: &str) -> Result<Self, String> {
+        let mut pointers = vec![vec!["type".to_string()]];
+        for pointer in raw.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let Some(rest) = pointer.strip_prefix('/') else {
+                return Err(format!("select pointer {pointer:?} must start with '/'"));
+            };
+            let tokens = rest
+                .split('/')
+                .map(|token| token.replace("~1", "/").replace("~0", "~"))
+                .collect::<Vec<_>>();
+            if !pointers.contains(&tokens) {
+                pointers.push(tokens);
+            }
+        }
+        if pointers.len() > MAX_SELECT_POINTERS + 1 {
+            return Err(format!(
+                "select lists more than {MAX_SELECT_POINTERS} pointers"
+            ));
+        }
+        Ok(Self { pointers })
+    }
+
+    pub(crate) fn apply(&self, event: &Value) -> Value {
+        let mut projected = Value::Object(Map::new());
+        for tokens in &self.pointers {
+            if let Some(value) = lookup(event, tokens) {
+                insert(&mut projected, event, tokens, value.clone());
+            }
+        }
+        projected
+    }
+}
+
+fn lookup<'a>(value: &'a Value, tokens: &[String]) -> Option<&'a Value> {
+    tokens.iter().try_fold(value, |value, token| match value {
+        Value::Object(object) => object.get(token),
+        Value::Array(items) => items.get(token.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+/// Write `leaf` into `out` at `tokens`, creating the objects and arrays
+/// `source` has along the way.
+fn insert(out: &mut Value, source: &Value, tokens: &[String], leaf: Value) {
+    let Some((token, rest)) = tokens.split_first() else {
+        *out = leaf;
+        return;
+    };
+    match source {
+        Value::Object(object) => {
+            if !out.is_object() {
+                *out = Value::Object(Map::new());
+            }
+            let (Some(out), Some(source)) = (out.as_object_mut(), object.get(token)) else {
+                return;
+            };
+            let child = out.entry(token.clone()).or_insert(Value::Null);
+            insert(child, source, rest, leaf);
+        }
+        Value::Array(items) => {
+            let Some(index) = token.parse::<usize>().ok() else {
+                return;
+            };
+            if !out.is_array() {
+                *out = Value::Array(Vec::new());
+            }
+            let (Some(out), Some(source)) = (out.as_array_mut(), items.get(index)) else {
+                return;
+            };
+            if out.len() <= index {
+                out.resize(index + 1, Value::Null);
+            }
+            insert(&mut out[index], source, rest, leaf);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn part_updated() -> Value {
+        json!({
+            "type": "message.part.updated",
+            "properties": {
+                "part": {"id": "prt_1", "sessionID": "ses_1", "text": "a long reply"},
+                "delta": "reply",
+                "a/b": 1,
+                "items": [{"id": "x", "big": "..."}, {"id": "y", "big": "..."}]
+            }
+        })
+    }
+
+    #[test]
+    fn keeps_selected_fields_in_place() {
+        let select = EventSelect::parse("/properties/part/sessionID, /properties/delta").unwrap();
+        assert_eq!(
+            select.apply(&part_updated()),
+            json!({
+                "type": "message.part.updated",
+                "properties": {"part": {"sessionID": "ses_1"}, "delta": "reply"}
+            })
+        );
+        let select =
+            EventSelect::parse("/properties/a~1b,/properties/items/1/id,/missing").unwrap();
+        assert_eq!(
+            select.apply(&part_updated()),
+            json!({
+                "type": "message.part.updated",
+                "properties": {"a/b": 1, "items": [null, {"id": "y"}]}
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_relative_pointers() {
+        assert!(EventSelect::parse("properties/part").is_err());
+        assert_eq!(
+            EventSelect::parse("").unwrap().apply(&part_updated()),
+            json!({"type": "message.part.updated"})
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::archive::env_nonempty;
+
+const DEFAULT_EXPIRY_INTERVAL_SECS: u64 = 60;
+const DEFAULT_SYSTEM_IDLE_TTL_MINS: u64 = 5;
+
+/// How long a session may sit idle before its agent process is stopped, and
+/// how often that is checked.
+#[derive(Debug, Clone)]
+pub struct SessionExpiryConfig {
+    pub idle_ttl: Duration,
+    /// The idle TTL of `system` sessions, whose agents are rarely prompted
+    /// again once their task is done.
+    pub system_idle_ttl: Duration,
+    pub interval: Duration,
+}
+
+impl SessionExpiryConfig {
+    /// Build from `SANDBOX_AGENT_SESSION_IDLE_TTL_MINS`,
+    /// `SANDBOX_AGENT_SYSTEM_SESSION_IDLE_TTL_MINS` and
+    /// `SANDBOX_AGENT_SESSION_EXPIRY_INTERVAL_SECS`. Expiry is off unless the
+    /// TTL is set to a non-zero number of minutes. System sessions default to
+    /// 5 minutes, or the TTL when it is shorter.
+    pub fn from_env() -> Option<Self> {
+        let env_u64 = |key: &str| env_nonempty(key).and_then(|value| value.parse::<u64>().ok());
+        let idle_ttl_mins =
+            env_u64("SANDBOX_AGENT_SESSION_IDLE_TTL_MINS").filter(|mins| *mins > 0)?;
+        let system_idle_ttl_mins = env_u64("SANDBOX_AGENT_SYSTEM_SESSION_IDLE_TTL_MINS")
+            .filter(|mins| *mins > 0)
+            .unwrap_or(DEFAULT_SYSTEM_IDLE_TTL_MINS.min(idle_ttl_mins));
+        Some(Self {
+            idle_ttl: Duration::from_secs(idle_ttl_mins * 60),
+            system_idle_ttl: Duration::from_secs(system_idle_ttl_mins * 60),
+            interval: Duration::from_secs(
+                env_u64("SANDBOX_AGENT_SESSION_EXPIRY_INTERVAL_SECS")
+                    .filter(|secs| *secs > 0)
+                    .unwrap_or(DEFAULT_EXPIRY_INTERVAL_SECS),
+            ),
+        })
+    }
+
+    /// The policy as reported by `GET /session/expiry`.
+    pub(crate) fn policy(&self) -> Value {
+        json!({
+            "idleTtlMs": self.idle_ttl.as_millis() as u64,
+            "systemIdleTtlMs": self.system_idle_ttl.as_millis() as u64,
+            "intervalMs": self.interval.as_millis() as u64,
+        })
+    }
+}
+
+/// What the expiry sweep knows about a session.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct IdleSession {
+    /// When the session last persisted an event, in epoch milliseconds.
+    pub last_activity: i64,
+    /// Whether the session is idle, errored or ended, with no prompt running
+    /// and nothing waiting on the user.
+    pub settled: bool,
+    /// Whether an agent process is bootstrapped for the session.
+    pub agent_running: bool,
+}
+
+/// A session's expiry timer, as reported by the expiry endpoints.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SessionTimer {
+    #[serde(rename = "sessionID")]
+    pub session_id: String,
+    pub last_activity: i64,
+    /// When the agent process is stopped if the session stays idle; `None`
+    /// while the session is busy, or when no process is running.
+    pub expires_at: Option<i64>,
+    pub dormant: bool,
+    pub dormant_at: Option<i64>,
+}
+
+/// When a session's agent process expires, or `None` if it cannot: only
+/// settled sessions with a running agent process expire.
+pub(crate) fn expires_at(session: IdleSession, idle_ttl: Duration) -> Option<i64> {
+    (session.settled && session.agent_running)
+        .then(|| session.last_activity + idle_ttl.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(30 * 60);
+
+    fn idle(last_activity: i64) -> IdleSession {
+        IdleSession {
+            last_activity,
+            settled: true,
+            agent_running: true,
+        }
+    }
+
+    #[test]
+    fn settled_sessions_expire_a_ttl_after_their_last_activity() {
+        assert_eq!(expires_at(idle(1_000), TTL), Some(1_000 + 30 * 60 * 1_000));
+    }
+
+    #[test]
+    fn busy_sessions_and_stopped_agents_never_expire() {
+        let busy = IdleSession {
+            settled: false,
+            ..idle(1_000)
+        };
+        assert_eq!(expires_at(busy, TTL), None);
+        let stopped = IdleSession {
+            agent_running: false,
+            ..idle(1_000)
+        };
+        assert_eq!(expires_at(stopped, TTL), None);
+    }
+}
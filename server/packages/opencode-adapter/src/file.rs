@@ -0,0 +1,332 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use serde_json::{json, Value};
+
+use crate::find::IgnoreRules;
+
+/// Text files are cut off after this many bytes.
+const MAX_TEXT_BYTES: u64 = 1024 * 1024;
+/// Binary files larger than this are refused rather than base64-encoded.
+const MAX_BINARY_BYTES: u64 = 10 * 1024 * 1024;
+/// Bytes inspected for a NUL when deciding whether a file is binary.
+const BINARY_SNIFF_BYTES: usize = 8 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum FileError {
+    #[error("path not found: {0}")]
+    NotFound(String),
+    #[error("path is outside the session directory: {0}")]
+    OutsideRoot(String),
+    #[error("path is a directory: {0}")]
+    IsDirectory(String),
+    #[error("path is not a directory: {0}")]
+    NotADirectory(String),
+    #[error("file is {size} bytes; binary files over {MAX_BINARY_BYTES} bytes are not served")]
+    TooLarge { size: u64 },
+    #[error("failed to read {path}: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+}
+
+/// Resolve `path` (relative to `root`, or absolute) to a canonical path
+/// inside `root`. Symlinks that lead out of `root` are rejected.
+pub(crate) fn resolve_in_root(root: &Path, path: &str) -> Result<PathBuf, FileError> {
+    let root = root
+        .canonicalize()
+        .map_err(|_| FileError::NotFound(root.to_string_lossy().into_owned()))?;
+    let resolved = root
+        .join(path)
+        .canonicalize()
+        .map_err(|_| FileError::NotFound(path.to_string()))?;
+    if !resolved.starts_with(&root) {
+        return Err(FileError::OutsideRoot(path.to_string()));
+    }
+    Ok(resolved)
+}
+
+/// The entries of a directory as OpenCode `FileNode`s, directories first.
+pub(crate) fn list_directory(root: &Path, path: &str) -> Result<Vec<Value>, FileError> {
+    let directory = resolve_in_root(root, path)?;
+    if !directory.is_dir() {
+        return Err(FileError::NotADirectory(path.to_string()));
+    }
+    let canonical_root = resolve_in_root(root, "")?;
+    let rules = IgnoreRules::for_root(&canonical_root);
+    let io_error = |source| FileError::Io {
+        path: path.to_string(),
+        source,
+    };
+    let mut nodes = Vec::new();
+    for entry in std::fs::read_dir(&directory).map_err(io_error)? {
+        let entry = entry.map_err(io_error)?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let absolute = entry.path();
+        let relative = absolute
+            .strip_prefix(&canonical_root)
+            .unwrap_or(&absolute)
+            .to_string_lossy()
+            .into_owned();
+        // Follows symlinks, so a link to a directory lists as one.
+        let is_dir = absolute.is_dir();
+        nodes.push(json!({
+            "name": name,
+            "path": relative,
+            "absolute": absolute.to_string_lossy(),
+            "type": if is_dir { "directory" } else { "file" },
+            "ignored": rules.is_ignored(&name),
+        }));
+    }
+    nodes.sort_by(|a, b| {
+        (a["type"] != "directory")
+            .cmp(&(b["type"] != "directory"))
+            .then_with(|| a["name"].as_str().cmp(&b["name"].as_str()))
+    });
+    Ok(nodes)
+}
+
+/// A file's content. Text is returned as-is with `language` and `lineCount`
+/// for highlighting, binary content as base64.
+pub(crate) fn read_content(root: &Path, path: &str) -> Result<Value, FileError> {
+    let file = resolve_in_root(root, path)?;
+    if file.is_dir() {
+        return Err(FileError::IsDirectory(path.to_string()));
+    }
+    let io_error = |source| FileError::Io {
+        path: path.to_string(),
+        source,
+    };
+    let size = std::fs::metadata(&file).map_err(io_error)?.len();
+    let mut bytes = Vec::new();
+    std::fs::File::open(&file)
+        .and_then(|handle| handle.take(MAX_TEXT_BYTES).read_to_end(&mut bytes))
+        .map_err(io_error)?;
+    let extension = file
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    let mime = mime_type(&extension);
+
+    let sniffed = &bytes[..bytes.len().min(BINARY_SNIFF_BYTES)];
+    let text = (!sniffed.contains(&0))
+        .then(|| utf8_prefix(&bytes, size > MAX_TEXT_BYTES))
+        .flatten();
+    if let Some(text) = text {
+        return Ok(json!({
+            "type": "text",
+            "content": text,
+            "mimeType": mime.unwrap_or("text/plain"),
+            "language": language(&extension, &file),
+            "lineCount": text.lines().count(),
+            "size": size,
+            "truncated": size > MAX_TEXT_BYTES,
+        }));
+    }
+
+    if size > MAX_BINARY_BYTES {
+        return Err(FileError::TooLarge { size });
+    }
+    if size > MAX_TEXT_BYTES {
+        bytes.clear();
+        std::fs::File::open(&file)
+            .and_then(|mut handle| handle.read_to_end(&mut bytes))
+            .map_err(io_error)?;
+    }
+    Ok(json!({
+        "type": "binary",
+        "content": base64::engine::general_purpose::STANDARD.encode(&bytes),
+        "encoding": "base64",
+        "mimeType": mime.unwrap_or("application/octet-stream"),
+        "size": size,
+    }))
+}
+
+/// `bytes` as UTF-8, or `None` if it is not text. A cut-off read may end in
+/// the middle of a character, which is dropped.
+fn utf8_prefix(bytes: &[u8], cut_off: bool) -> Option<&str> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Some(text),
+        Err(err) if cut_off && err.error_len().is_none() => {
+            std::str::from_utf8(&bytes[..err.valid_up_to()]).ok()
+        }
+        Err(_) => None,
+    }
+}
+
+/// The MIME type of a file by extension, falling back to sniffing for text.
+pub(crate) fn guess_mime(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    if let Some(mime) = mime_type(&extension) {
+        return mime;
+    }
+    let mut bytes = Vec::new();
+    let sniffed = std::fs::File::open(file).and_then(|handle| {
+        handle
+            .take(BINARY_SNIFF_BYTES as u64)
+            .read_to_end(&mut bytes)
+    });
+    match sniffed {
+        Ok(_) if !bytes.contains(&0) && utf8_prefix(&bytes, true).is_some() => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+fn mime_type(extension: &str) -> Option<&'static str> {
+    Some(match extension {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "wasm" => "application/wasm",
+        "json" => "application/json",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "md" | "mdx" => "text/markdown",
+        "csv" => "text/csv",
+        "patch" | "diff" => "text/x-diff",
+        "js" | "mjs" | "cjs" => "text/javascript",
+        _ => return None,
+    })
+}
+
+/// Highlighter language ID (the names Shiki and highlight.js share).
+fn language(extension: &str, file: &Path) -> Option<&'static str> {
+    let name = file.file_name().and_then(|name| name.to_str());
+    if name == Some("Dockerfile") {
+        return Some("dockerfile");
+    }
+    if name == Some("Makefile") {
+        return Some("makefile");
+    }
+    Some(match extension {
+        "rs" => "rust",
+        "ts" | "mts" | "cts" => "typescript",
+        "tsx" => "tsx",
+        "js" | "mjs" | "cjs" => "javascript",
+        "jsx" => "jsx",
+        "py" => "python",
+        "go" => "go",
+        "rb" => "ruby",
+        "java" => "java",
+        "kt" | "kts" => "kotlin",
+        "swift" => "swift",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hpp" => "cpp",
+        "cs" => "csharp",
+        "php" => "php",
+        "sh" | "bash" | "zsh" => "bash",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "md" => "markdown",
+        "mdx" => "mdx",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "scss" => "scss",
+        "sql" => "sql",
+        "xml" => "xml",
+        "lua" => "lua",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sandbox-agent-file-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src")).expect("create temp dir");
+        dir
+    }
+
+    #[test]
+    fn reads_text_and_binary() {
+        let dir = temp_dir("read");
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}\n").expect("write main");
+        std::fs::write(dir.join("logo.png"), [0x89, b'P', b'N', b'G', 0, 1]).expect("write png");
+
+        let text = read_content(&dir, "src/main.rs").expect("text");
+        assert_eq!(text["type"], "text");
+        assert_eq!(text["content"], "fn main() {}\n");
+        assert_eq!(text["language"], "rust");
+        assert_eq!(text["lineCount"], 1);
+
+        let binary = read_content(&dir, "logo.png").expect("binary");
+        assert_eq!(binary["type"], "binary");
+        assert_eq!(binary["mimeType"], "image/png");
+        assert_eq!(binary["content"], "iVBORwAB");
+        assert!(matches!(
+            read_content(&dir, "src"),
+            Err(FileError::IsDirectory(_))
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rejects_paths_outside_root() {
+        let dir = temp_dir("escape");
+        assert!(matches!(
+            resolve_in_root(&dir.join("src"), "../"),
+            Err(FileError::OutsideRoot(_))
+        ));
+        assert!(matches!(
+            resolve_in_root(&dir, "missing.txt"),
+            Err(FileError::NotFound(_))
+        ));
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("/etc", dir.join("etc")).expect("symlink");
+            assert!(matches!(
+                resolve_in_root(&dir, "etc/hostname"),
+                Err(FileError::OutsideRoot(_)) | Err(FileError::NotFound(_))
+            ));
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn lists_directories_first() {
+        let dir = temp_dir("list");
+        std::fs::write(dir.join(".gitignore"), "dist\n").expect("write .gitignore");
+        std::fs::create_dir_all(dir.join("dist")).expect("create dist");
+        std::fs::write(dir.join("README.md"), "# hi").expect("write readme");
+
+        let nodes = list_directory(&dir, "").expect("list");
+        let names: Vec<_> = nodes
+            .iter()
+            .map(|node| {
+                (
+                    node["name"].as_str().unwrap_or_default(),
+                    node["ignored"] == true,
+                )
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("dist", true),
+                ("src", false),
+                (".gitignore", false),
+                ("README.md", false)
+            ]
+        );
+        assert_eq!(nodes[1]["path"], "src");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
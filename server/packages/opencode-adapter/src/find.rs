@@ -0,0 +1,351 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use regex::Regex;
+use serde_json::{json, Value};
+
+pub(crate) const DEFAULT_FILE_LIMIT: usize = 100;
+pub(crate) const DEFAULT_TEXT_LIMIT: usize = 100;
+pub(crate) const MAX_LIMIT: usize = 1000;
+/// Files listed before the walk stops, so huge trees stay responsive.
+const MAX_LISTED_FILES: usize = 100_000;
+/// Larger files are skipped by the built-in text search.
+const MAX_SEARCHED_FILE_BYTES: u64 = 1024 * 1024;
+/// Matched lines are cut to this many bytes, as ripgrep does for previews.
+const MAX_LINE_BYTES: usize = 2000;
+/// Skipped by the built-in walker even without a `.gitignore`.
+const SKIPPED_DIRS: &[&str] = &[".git", "node_modules", "target"];
+
+/// What `/find/file` returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FindKind {
+    File,
+    Directory,
+    All,
+}
+
+/// Paths under `root` matching `query`, best match first. Directories end
+/// in `/`. An empty query lists paths in order.
+pub(crate) fn find_files(root: &Path, query: &str, kind: FindKind, limit: usize) -> Vec<String> {
+    let files = list_files(root);
+    let mut paths: Vec<String> = Vec::new();
+    if kind != FindKind::File {
+        let dirs: BTreeSet<String> = files
+            .iter()
+            .flat_map(|file| {
+                let mut parents = Vec::new();
+                let mut current = file.as_str();
+                while let Some(index) = current.rfind('/') {
+                    current = &current[..index];
+                    parents.push(format!("{current}/"));
+                }
+                parents
+            })
+            .collect();
+        paths.extend(dirs);
+    }
+    if kind != FindKind::Directory {
+        paths.extend(files);
+    }
+    rank_paths(paths, query, limit)
+}
+
+/// Lines under `root` matching the regex `pattern`, in ripgrep's `--json`
+/// match shape.
+pub(crate) fn find_text(root: &Path, pattern: &str, limit: usize) -> Result<Vec<Value>, String> {
+    let regex = Regex::new(pattern).map_err(|err| format!("invalid pattern: {err}"))?;
+    if let Some(matches) = rg_text(root, pattern, limit) {
+        return Ok(matches);
+    }
+    Ok(grep_files(root, &list_files(root), &regex, limit))
+}
+
+/// Relative paths of the files under `root`, honoring ignore files. Uses
+/// ripgrep when it is installed and the built-in walker otherwise.
+pub(crate) fn list_files(root: &Path) -> Vec<String> {
+    rg_files(root).unwrap_or_else(|| walk_files(root))
+}
+
+fn rg_command(root: &Path) -> Command {
+    let mut command = Command::new("rg");
+    command
+        .current_dir(root)
+        .args(["--hidden", "--glob", "!.git/"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null());
+    command
+}
+
+fn rg_files(root: &Path) -> Option<Vec<String>> {
+    let mut child = rg_command(root)
+        .arg("--files")
+        .stdout(Stdio::piped())
+        .spawn()
+        .ok()?;
+    let stdout = child.stdout.take()?;
+    let files: Vec<String> = BufReader::new(stdout)
+        .lines()
+        .map_while(Result::ok)
+        .take(MAX_LISTED_FILES)
+        .map(|line| line.trim_start_matches("./").to_string())
+        .collect();
+    let _ = child.kill();
+    let _ = child.wait();
+    Some(files)
+}
+
+fn rg_text(root: &Path, pattern: &str, limit: usize) -> Option<Vec<Value>> {
+    let mut child = rg_command(root)
+        .args(["--json", "--max-columns", &MAX_LINE_BYTES.to_string(), "-e"])
+        .arg(pattern)
+        .stdout(Stdio::piped())
+        .spawn()
+        .ok()?;
+    let stdout = child.stdout.take()?;
+    let matches: Vec<Value> = BufReader::new(stdout)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<Value>(&line).ok())
+        .filter(|message| message["type"] == "match")
+        .map(|mut message| {
+            let mut data = message["data"].take();
+            if let Some(path) = data.pointer_mut("/path/text") {
+                if let Some(relative) = path.as_str().and_then(|p| p.strip_prefix("./")) {
+                    *path = json!(relative);
+                }
+            }
+            data
+        })
+        .take(limit)
+        .collect();
+    let _ = child.kill();
+    let _ = child.wait();
+    Some(matches)
+}
+
+/// The names skipped under a root: [`SKIPPED_DIRS`] and the entries of its
+/// top-level `.gitignore`. Only literal names and `*.ext` patterns are
+/// understood.
+pub(crate) struct IgnoreRules {
+    patterns: Vec<String>,
+}
+
+impl IgnoreRules {
+    pub(crate) fn for_root(root: &Path) -> Self {
+        let patterns = std::fs::read_to_string(root.join(".gitignore"))
+            .unwrap_or_default()
+            .lines()
+            .map(|line| line.trim().trim_start_matches('/').trim_end_matches('/'))
+            .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with('!'))
+            .map(str::to_string)
+            .collect();
+        Self { patterns }
+    }
+
+    pub(crate) fn is_ignored(&self, name: &str) -> bool {
+        SKIPPED_DIRS.contains(&name)
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| match pattern.strip_prefix("*.") {
+                    Some(ext) => name.ends_with(&format!(".{ext}")),
+                    None => pattern == name,
+                })
+    }
+}
+
+/// Walk `root` depth first, skipping what its [`IgnoreRules`] ignore.
+fn walk_files(root: &Path) -> Vec<String> {
+    let rules = IgnoreRules::for_root(root);
+    let mut files = Vec::new();
+    let mut pending = vec![String::new()];
+    while let Some(relative) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(root.join(&relative)) else {
+            continue;
+        };
+        let mut entries: Vec<_> = entries.filter_map(Result::ok).collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.file_name()));
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if rules.is_ignored(&name) {
+                continue;
+            }
+            let path = if relative.is_empty() {
+                name
+            } else {
+                format!("{relative}/{name}")
+            };
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => pending.push(path),
+                Ok(file_type) if file_type.is_file() => {
+                    files.push(path);
+                    if files.len() >= MAX_LISTED_FILES {
+                        return files;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    files
+}
+
+fn grep_files(root: &Path, files: &[String], regex: &Regex, limit: usize) -> Vec<Value> {
+    let mut matches = Vec::new();
+    for file in files {
+        let path = root.join(file);
+        if std::fs::metadata(&path).map_or(true, |meta| meta.len() > MAX_SEARCHED_FILE_BYTES) {
+            continue;
+        }
+        let mut bytes = Vec::new();
+        if std::fs::File::open(&path)
+            .and_then(|mut handle| handle.read_to_end(&mut bytes))
+            .is_err()
+            || bytes.contains(&0)
+        {
+            continue;
+        }
+        let text = String::from_utf8_lossy(&bytes);
+        let mut offset = 0;
+        for (index, line) in text.split_inclusive('\n').enumerate() {
+            let submatches: Vec<Value> = regex
+                .find_iter(line.trim_end_matches('\n'))
+                .map(|found| {
+                    json!({
+                        "match": {"text": found.as_str()},
+                        "start": found.start(),
+                        "end": found.end(),
+                    })
+                })
+                .collect();
+            if !submatches.is_empty() {
+                let mut end = line.len().min(MAX_LINE_BYTES);
+                while !line.is_char_boundary(end) {
+                    end -= 1;
+                }
+                matches.push(json!({
+                    "path": {"text": file},
+                    "lines": {"text": &line[..end]},
+                    "line_number": index + 1,
+                    "absolute_offset": offset,
+                    "submatches": submatches,
+                }));
+                if matches.len() >= limit {
+                    return matches;
+                }
+            }
+            offset += line.len();
+        }
+    }
+    matches
+}
+
+/// Order `paths` by how well they match `query`: a match in the file name
+/// beats one in the path, a contiguous match beats a scattered one, and
+/// shorter paths win ties. Paths that do not contain the query's characters
+/// in order are dropped.
+fn rank_paths(paths: Vec<String>, query: &str, limit: usize) -> Vec<String> {
+    let query = query.trim().to_lowercase();
+    if query.is_empty() {
+        return paths.into_iter().take(limit).collect();
+    }
+    let mut scored: Vec<(u32, String)> = paths
+        .into_iter()
+        .filter_map(|path| Some((match_score(&path, &query)?, path)))
+        .collect();
+    scored.sort_by(|(a, path_a), (b, path_b)| {
+        b.cmp(a)
+            .then(path_a.len().cmp(&path_b.len()))
+            .then(path_a.cmp(path_b))
+    });
+    scored
+        .into_iter()
+        .take(limit)
+        .map(|(_, path)| path)
+        .collect()
+}
+
+fn match_score(path: &str, query: &str) -> Option<u32> {
+    let lower = path.to_lowercase();
+    let name = lower.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    if name == query {
+        return Some(4);
+    }
+    if name.contains(query) {
+        return Some(3);
+    }
+    if lower.contains(query) {
+        return Some(2);
+    }
+    let mut chars = lower.chars();
+    query
+        .chars()
+        .all(|wanted| chars.any(|c| c == wanted))
+        .then_some(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sandbox-agent-find-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src/nested")).expect("create temp dir");
+        dir
+    }
+
+    #[test]
+    fn walker_honors_gitignore() {
+        let dir = temp_dir("walk");
+        std::fs::write(dir.join(".gitignore"), "dist/\n*.log\n").expect("write .gitignore");
+        std::fs::create_dir_all(dir.join("dist")).expect("create dist");
+        std::fs::create_dir_all(dir.join(".git")).expect("create .git");
+        std::fs::write(dir.join("dist/bundle.js"), "x").expect("write bundle");
+        std::fs::write(dir.join(".git/HEAD"), "ref").expect("write HEAD");
+        std::fs::write(dir.join("debug.log"), "x").expect("write log");
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}").expect("write main");
+        std::fs::write(dir.join("src/nested/lib.rs"), "").expect("write lib");
+
+        let mut files = walk_files(&dir);
+        files.sort();
+        assert_eq!(files, [".gitignore", "src/main.rs", "src/nested/lib.rs"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn ranks_file_name_matches_first() {
+        let paths = vec![
+            "docs/main-notes.md".to_string(),
+            "src/main.rs".to_string(),
+            "src/domain/mod.rs".to_string(),
+            "README.md".to_string(),
+        ];
+        assert_eq!(
+            rank_paths(paths, "main", 10),
+            ["src/main.rs", "docs/main-notes.md", "src/domain/mod.rs"]
+        );
+    }
+
+    #[test]
+    fn grep_reports_ripgrep_match_shape() {
+        let dir = temp_dir("grep");
+        std::fs::write(dir.join("src/main.rs"), "use std::io;\nfn main() {}\n").expect("write");
+        let regex = Regex::new("fn \\w+").expect("regex");
+        let matches = grep_files(&dir, &["src/main.rs".to_string()], &regex, 10);
+        assert_eq!(
+            matches,
+            [json!({
+                "path": {"text": "src/main.rs"},
+                "lines": {"text": "fn main() {}\n"},
+                "line_number": 2,
+                "absolute_offset": 13,
+                "submatches": [{"match": {"text": "fn main"}, "start": 0, "end": 7}],
+            })]
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
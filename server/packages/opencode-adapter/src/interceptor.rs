@@ -0,0 +1,279 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::compare::universal_item;
+use crate::MessageRecord;
+
+/// The prompt an interceptor runs around.
+#[derive(Debug, Clone)]
+pub struct PromptContext {
+    pub session_id: String,
+    pub message_id: String,
+    pub agent: String,
+    pub provider_id: String,
+    pub model_id: String,
+    pub directory: String,
+}
+
+/// A message as a universal item (`{ role, content: ContentPart[] }`), the
+/// shape of `initialHistory` items and `/compare` turns.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UniversalMessage {
+    pub role: String,
+    pub content: Vec<Value>,
+}
+
+/// Middleware around `POST /session/:id/message` and `prompt_async`, e.g. for
+/// PII scrubbing, prompt rewriting or policy checks. Interceptors run in
+/// registration order before the prompt and in reverse order after it. Both
+/// methods default to a no-op.
+pub trait PromptInterceptor: Send + Sync + 'static {
+    /// Inspect or rewrite the user message before it is persisted and sent to
+    /// the agent. `Err` rejects the prompt with `403` and the given reason.
+    fn before_prompt<'a>(
+        &'a self,
+        _context: &'a PromptContext,
+        _message: &'a mut UniversalMessage,
+    ) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Inspect or rewrite the assistant message the prompt responds with.
+    /// Only edits to its text parts are applied to the response.
+    fn after_prompt<'a>(
+        &'a self,
+        _context: &'a PromptContext,
+        _response: &'a mut UniversalMessage,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async {})
+    }
+}
+
+/// Run `interceptors` over the prompt parts of a request and return the
+/// parts to send. Parts without a universal equivalent pass through as-is.
+pub(crate) async fn intercept_prompt(
+    interceptors: &[Arc<dyn PromptInterceptor>],
+    context: &PromptContext,
+    parts: Vec<Value>,
+) -> Result<Vec<Value>, String> {
+    let mut message = UniversalMessage {
+        role: "user".to_string(),
+        content: parts.iter().map(prompt_part_to_universal).collect(),
+    };
+    for interceptor in interceptors {
+        interceptor.before_prompt(context, &mut message).await?;
+    }
+    Ok(message
+        .content
+        .iter()
+        .map(universal_to_prompt_part)
+        .collect())
+}
+
+/// Run `interceptors` over a `{ info, parts }` prompt response and write
+/// their text edits back into its parts.
+pub(crate) async fn intercept_response(
+    interceptors: &[Arc<dyn PromptInterceptor>],
+    context: &PromptContext,
+    response: &mut Value,
+) {
+    let record = MessageRecord {
+        info: response.get("info").cloned().unwrap_or_else(|| json!({})),
+        parts: response
+            .get("parts")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default(),
+    };
+    let Ok(mut message) = serde_json::from_value::<UniversalMessage>(universal_item(&record))
+    else {
+        return;
+    };
+    for interceptor in interceptors.iter().rev() {
+        interceptor.after_prompt(context, &mut message).await;
+    }
+    // `universal_item` emits one text item per non-synthetic text part, in order.
+    let mut texts = message
+        .content
+        .iter()
+        .filter(|item| item["type"] == "text")
+        .map(|item| item["text"].clone());
+    let Some(parts) = response.get_mut("parts").and_then(Value::as_array_mut) else {
+        return;
+    };
+    for part in parts
+        .iter_mut()
+        .filter(|part| part["type"] == "text" && part["synthetic"] != json!(true))
+    {
+        let Some(text) = texts.next() else {
+            break;
+        };
+        part["text"] = text;
+    }
+}
+
+/// An OpenCode prompt input part as a universal content part.
+fn prompt_part_to_universal(part: &Value) -> Value {
+    match part.get("type").and_then(Value::as_str) {
+        Some("text") => json!({
+            "type": "text",
+            "text": part.get("text").and_then(Value::as_str).unwrap_or_default(),
+        }),
+        Some("file") => match part
+            .get("url")
+            .and_then(Value::as_str)
+            .and_then(|url| url.strip_prefix("file://"))
+        {
+            Some(path) => json!({
+                "type": "file_ref",
+                "path": path,
+                "mime": part.get("mime"),
+                "filename": part.get("filename"),
+            }),
+            None => part.clone(),
+        },
+        _ => part.clone(),
+    }
+}
+
+/// Inverse of [`prompt_part_to_universal`].
+fn universal_to_prompt_part(item: &Value) -> Value {
+    match item.get("type").and_then(Value::as_str) {
+        Some("text") => json!({"type": "text", "text": item["text"]}),
+        Some("file_ref") => {
+            let path = item["path"].as_str().unwrap_or_default();
+            let mime = item
+                .get("mime")
+                .and_then(Value::as_str)
+                .unwrap_or("application/octet-stream");
+            let mut part = json!({"type": "file", "url": format!("file://{path}"), "mime": mime});
+            if let Some(filename) = item.get("filename").filter(|name| !name.is_null()) {
+                part["filename"] = filename.clone();
+            }
+            part
+        }
+        _ => item.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Redact;
+
+    impl PromptInterceptor for Redact {
+        fn before_prompt<'a>(
+            &'a self,
+            _context: &'a PromptContext,
+            message: &'a mut UniversalMessage,
+        ) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>> {
+            Box::pin(async move {
+                for item in &mut message.content {
+                    if let Some(text) = item["text"].as_str() {
+                        item["text"] = json!(text.replace("555-0100", "[phone]"));
+                    }
+                }
+                Ok(())
+            })
+        }
+
+        fn after_prompt<'a>(
+            &'a self,
+            _context: &'a PromptContext,
+            response: &'a mut UniversalMessage,
+        ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+            Box::pin(async move {
+                for item in &mut response.content {
+                    if let Some(text) = item["text"].as_str() {
+                        item["text"] = json!(text.to_uppercase());
+                    }
+                }
+            })
+        }
+    }
+
+    struct DenyPhones;
+
+    impl PromptInterceptor for DenyPhones {
+        fn before_prompt<'a>(
+            &'a self,
+            _context: &'a PromptContext,
+            message: &'a mut UniversalMessage,
+        ) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>> {
+            let found = message.content.iter().any(|item| {
+                item["text"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .contains("555-0100")
+            });
+            Box::pin(async move {
+                if found {
+                    return Err("prompt contains a phone number".to_string());
+                }
+                Ok(())
+            })
+        }
+    }
+
+    fn context() -> PromptContext {
+        PromptContext {
+            session_id: "ses_1".to_string(),
+            message_id: "msg_1".to_string(),
+            agent: "mock".to_string(),
+            provider_id: "mock".to_string(),
+            model_id: "mock".to_string(),
+            directory: "/tmp".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn interceptors_run_in_registration_order() {
+        let parts = vec![
+            json!({"type": "text", "text": "call 555-0100"}),
+            json!({"type": "file", "url": "file:///repo/a.rs", "mime": "text/x-rust"}),
+        ];
+        let redact_first: Vec<Arc<dyn PromptInterceptor>> =
+            vec![Arc::new(Redact), Arc::new(DenyPhones)];
+        let parts = intercept_prompt(&redact_first, &context(), parts)
+            .await
+            .expect("redacted before the policy check");
+        assert_eq!(parts[0], json!({"type": "text", "text": "call [phone]"}));
+        assert_eq!(
+            parts[1],
+            json!({"type": "file", "url": "file:///repo/a.rs", "mime": "text/x-rust"})
+        );
+
+        let deny_first: Vec<Arc<dyn PromptInterceptor>> =
+            vec![Arc::new(DenyPhones), Arc::new(Redact)];
+        let rejected = intercept_prompt(
+            &deny_first,
+            &context(),
+            vec![json!({"type": "text", "text": "call 555-0100"})],
+        )
+        .await;
+        assert_eq!(rejected, Err("prompt contains a phone number".to_string()));
+    }
+
+    #[tokio::test]
+    async fn response_text_edits_are_written_back() {
+        let mut response = json!({
+            "info": {"role": "assistant"},
+            "parts": [
+                {"id": "p1", "type": "text", "text": "context", "synthetic": true},
+                {"id": "p2", "type": "text", "text": "done"},
+                {"id": "p3", "type": "reasoning", "text": "thinking"},
+            ],
+        });
+        let interceptors: Vec<Arc<dyn PromptInterceptor>> = vec![Arc::new(Redact)];
+        intercept_response(&interceptors, &context(), &mut response).await;
+        assert_eq!(response["parts"][0]["text"], "context");
+        assert_eq!(response["parts"][1]["text"], "DONE");
+        assert_eq!(response["parts"][1]["id"], "p2");
+        assert_eq!(response["parts"][2]["text"], "thinking");
+    }
+}
@@ -1,36 +1,189 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::convert::Infallible;
 use std::future::Future;
 use std::path::PathBuf;
 use std::pin::Pin;
-use std::str::FromStr;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex as StdMutex};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, Once, OnceLock};
+use std::time::{Duration, Instant};
 
 use axum::body::Body;
-use axum::extract::{Path, Query, State};
-use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Request, StatusCode};
+use axum::extract::{DefaultBodyLimit, FromRequest, OriginalUri, Path, Query, RawQuery, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Request, StatusCode, Uri};
 use axum::middleware::Next;
 use axum::response::sse::{Event, KeepAlive};
 use axum::response::{IntoResponse, Response, Sse};
-use axum::routing::{get, patch, post};
+use axum::routing::{delete, get, patch, post};
 use axum::{Json, Router};
 use futures::stream;
 use futures::{Stream, StreamExt};
-use sandbox_agent_opencode_server_manager::OpenCodeServerManager;
+use sandbox_agent_error::{ErrorType, ProblemDetails};
+use sandbox_agent_opencode_server_manager::{OpenCodeServerManager, SidecarState, SidecarStatus};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
 use sqlx::{Row, SqlitePool};
-use tokio::sync::{broadcast, Mutex, OnceCell};
-use tokio::time::interval;
-use tracing::warn;
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex, Notify, OnceCell};
+use tokio::time::{interval, sleep};
+use tracing::{warn, Instrument};
+
+mod acp;
+mod agent_parts;
+mod archive;
+mod artifacts;
+mod attachments;
+mod authorizer;
+mod clock;
+mod compare;
+mod composer;
+mod context_files;
+mod convert_acp;
+mod db_lock;
+mod event_consumers;
+mod event_export;
+mod event_select;
+mod expiry;
+mod file;
+mod find;
+mod interceptor;
+mod logs;
+mod migration;
+#[cfg(any(test, feature = "test-utils"))]
+mod mock_dispatch;
+mod models_catalog;
+mod native_proxy;
+mod page;
+mod part_ids;
+mod part_output;
+mod paths;
+mod permission_batch;
+mod permission_rules;
+mod preflight;
+mod prewarm;
+mod priority;
+mod quota;
+mod reasoning;
+mod replay_select;
+mod request_schema;
+mod session_env;
+mod stream_stats;
+mod system_prompt;
+mod timeline;
+mod transcript;
+mod turn_lock;
+mod turn_resources;
+mod watchdog;
+mod webhook;
+mod workspace_clone;
+mod workspace_snapshot;
+
+pub use acp::{
+    AcpCall, AcpCallError, AcpCallOutcome, AcpResult, AuthenticateParams, ClientInfo,
+    InitializeParams, InitializeResult, SessionCancelParams, SessionNewParams, SessionNewResult,
+    SessionPromptParams, SessionPromptResult, StopReason,
+};
+use archive::S3Client;
+pub use archive::SessionArchiveConfig;
+use attachments::{AttachmentError, PromptCapabilities};
+pub use attachments::{AttachmentTranscoder, CommandTranscoder, TranscodeFuture};
+pub use authorizer::{Authorizer, AuthorizerDecision, PermissionContext};
+pub use clock::{Clock, FixedClock, IdGenerator, SequentialIds, SystemClock};
+use composer::{BackendInput, ComposerBackend};
+use context_files::AppliedContextFile;
+pub use convert_acp::{AcpUpdate, PlanEntry};
+pub use db_lock::{DatabaseLock, DatabaseLockError, LockOwner};
+use event_consumers::{AckBody, LoggedEvent};
+use event_select::EventSelect;
+pub use expiry::SessionExpiryConfig;
+pub use interceptor::{PromptContext, PromptInterceptor, UniversalMessage};
+pub use logs::session_log_layer;
+use migration::{AbortOutcome, MigrateBody, MigrationClient, SessionImports, SessionMigration};
+#[cfg(any(test, feature = "test-utils"))]
+pub use mock_dispatch::{MockAcpDispatch, PostedPayload};
+use models_catalog::ModelCatalog;
+pub use models_catalog::ModelCatalogConfig;
+use native_proxy::{NativeCircuit, NATIVE_FALLBACK};
+use page::{PageCursor, PageQuery};
+use part_ids::{part_id, tool_part_id};
+use permission_batch::{BatchKey, PermissionBatches};
+use permission_rules::PermissionRule;
+pub use prewarm::SessionPrewarmConfig;
+pub use priority::SessionPriority;
+use priority::{SlotClaim, TurnSlots};
+pub use quota::SessionQuotaConfig;
+use quota::{QuotaExceeded, QuotaLimit, QuotaUsage, RecentCreations};
+use reasoning::{ReasoningBudget, TokenUsage};
+use replay_select::ReplaySelection;
+use session_env::{SessionEnvInput, SessionEnvVar};
+use stream_stats::{StreamSnapshot, StreamStats};
+use system_prompt::{SystemPromptMechanism, SystemPromptOverride};
+use timeline::ToolTimeline;
+pub use transcript::{TranscriptFormat, TranscriptTranslator};
+use turn_lock::{TurnLockError, TurnLocks};
+use turn_resources::{TurnResourceUsage, TurnResources};
+pub use watchdog::SessionWatchdogConfig;
+use webhook::WebhookClient;
+pub use webhook::{sign_payload, verify_signature, WebhookConfig, WebhookTarget};
+use workspace_clone::{ClonePlan, CloneRequest, Workspace, WorkspaceStatus};
+use workspace_snapshot::ShadowRepo;
 
 const DEFAULT_REPLAY_MAX_EVENTS: usize = 50;
 const DEFAULT_REPLAY_MAX_CHARS: usize = 12_000;
+const DEFAULT_PART_OUTPUT_MAX_BYTES: usize = 256 * 1024;
 const EVENT_LOG_SIZE: usize = 4096;
 const EVENT_CHANNEL_SIZE: usize = 2048;
+const MAX_EVENT_BATCH_MS: u64 = 1_000;
+const MAX_EVENT_BATCH_SIZE: usize = 256;
+/// Logged events read at a time while replaying a consumer's backlog.
+const CONSUMER_REPLAY_PAGE: usize = 1024;
+/// Translated ACP notifications between cursor writes to `acp_bindings`.
+const ACP_CURSOR_FLUSH_INTERVAL: u64 = 32;
+/// `turn_journal` phases: the turn was recorded, and its prompt was handed
+/// to the agent.
+const TURN_PHASE_STARTED: &str = "started";
+const TURN_PHASE_DISPATCHED: &str = "dispatched";
+/// How long `/event/poll` holds a request open when no event is pending.
+const DEFAULT_EVENT_POLL_WAIT_MS: u64 = 25_000;
+const MAX_EVENT_POLL_WAIT_MS: u64 = 60_000;
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+const DEFAULT_CHUNK_COALESCE_WINDOW: Duration = Duration::from_millis(30);
+const DEFAULT_CHUNK_COALESCE_MAX_CHARS: usize = 256;
+/// How often a locked adapter checks whether another instance took its
+/// database over.
+const DATABASE_FENCE_INTERVAL: Duration = Duration::from_secs(1);
+/// Bounds for the per-connection `heartbeatMs` and `keepAliveMs` overrides.
+const MIN_STREAM_INTERVAL_MS: u64 = 1_000;
+const MAX_STREAM_INTERVAL_MS: u64 = 300_000;
+/// Published by the runtime when an agent cannot authenticate with its provider.
+const ACP_AUTH_REQUIRED_METHOD: &str = "_sandboxagent/agent/auth_required";
+/// Published by the runtime when an agent process breaches a resource limit.
+const ACP_LIMIT_EXCEEDED_METHOD: &str = "_sandboxagent/agent/limit_exceeded";
+/// Sent by an agent when it retries a failed step, e.g. after a rate limit.
+const ACP_SESSION_RETRY_METHOD: &str = "_sandboxagent/session/retry";
+/// Sent by an agent that no longer needs the answer to a permission or
+/// question request it made; `params.id` is that request's JSON-RPC `id`.
+const ACP_CANCEL_REQUEST_METHOD: &str = "$/cancelRequest";
+/// Extension form of [`ACP_CANCEL_REQUEST_METHOD`], which may add a `reason`.
+const ACP_REQUEST_CANCELLED_METHOD: &str = "_sandboxagent/session/request_cancelled";
+/// How long to wait for buffered agent notifications when checking a failed
+/// bootstrap for an authentication failure.
+const AUTH_FAILURE_SCAN_IDLE: Duration = Duration::from_millis(200);
+/// Finished async turns kept for `GET /session/:id/turn/:turnID`.
+const MAX_TRACKED_TURNS: usize = 1024;
+const TURN_TOKEN_HEADER: &str = "x-sandbox-agent-turn-token";
+/// `since` for the next `GET /admin/export/events` pull.
+const EXPORT_WATERMARK_HEADER: &str = "x-sandbox-agent-export-watermark";
+const HEALTH_SQLITE_SLOW_MS: u64 = 250;
+const HEALTH_SIDECAR_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a proxied request waits for a crashed sidecar to come back.
+const SIDECAR_RESTART_WAIT: Duration = Duration::from_secs(15);
+/// Longer than the sidecar manager's exit poll, so a retried request sees the
+/// restart instead of the dead process.
+const SIDECAR_EXIT_GRACE: Duration = Duration::from_millis(600);
 const MODEL_CHANGE_ERROR: &str = "OpenCode compatibility currently does not support changing the model after creating a session. Export with /export and load in to a new session.";
 
 // ---------------------------------------------------------------------------
@@ -41,12 +194,26 @@ const MODEL_CHANGE_ERROR: &str = "OpenCode compatibility currently does not supp
 /// Stream of raw JSON-RPC payloads from the ACP agent process.
 pub type AcpPayloadStream = Pin<Box<dyn Stream<Item = Value> + Send>>;
 
+/// [`AcpPayloadStream`] items paired with their sequence number in the
+/// agent's notification stream.
+pub type AcpSequencedStream = Pin<Box<dyn Stream<Item = (u64, Value)> + Send>>;
+
 #[derive(Debug)]
 pub enum AcpDispatchResult {
     Response(Value),
     Accepted,
 }
 
+/// A live agent process instance, as reported by [`AcpDispatch::instances`].
+#[derive(Debug, Clone)]
+pub struct AcpInstanceSummary {
+    pub server_id: String,
+    pub agent: String,
+    pub created_at_ms: i64,
+    /// OS process ID of the agent, when the backend knows it.
+    pub pid: Option<u32>,
+}
+
 /// Trait for dispatching JSON-RPC payloads to ACP agent process instances.
 ///
 /// Implementors (e.g. `AcpProxyRuntime`) handle launching, bootstrapping, and
@@ -71,15 +238,168 @@ pub trait AcpDispatch: Send + Sync + 'static {
         last_event_id: Option<u64>,
     ) -> Pin<Box<dyn Future<Output = Result<AcpPayloadStream, String>> + Send + '_>>;
 
+    /// Like [`notification_stream`](Self::notification_stream), with the
+    /// sequence number of each payload so the adapter can resume after the
+    /// last one it translated. The default numbers the plain stream on from
+    /// `last_event_id`, which is only exact for backends that replay nothing.
+    fn sequenced_notification_stream(
+        &self,
+        server_id: &str,
+        last_event_id: Option<u64>,
+    ) -> Pin<Box<dyn Future<Output = Result<AcpSequencedStream, String>> + Send + '_>> {
+        let server_id = server_id.to_string();
+        Box::pin(async move {
+            let payloads = self.notification_stream(&server_id, last_event_id).await?;
+            let last = last_event_id.unwrap_or(0);
+            Ok(Box::pin(
+                payloads
+                    .enumerate()
+                    .map(move |(index, payload)| (last + 1 + index as u64, payload)),
+            ) as AcpSequencedStream)
+        })
+    }
+
     /// Destroy the agent process instance.
     fn delete(
         &self,
         server_id: &str,
     ) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + '_>>;
+
+    /// Tag log lines from the agent process behind `server_id` with
+    /// `correlation_id` until it is changed again. Defaults to a no-op.
+    fn set_correlation_id(
+        &self,
+        _server_id: &str,
+        _correlation_id: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async {})
+    }
+
+    /// Extra arguments for the agent process behind `server_id`, applied when
+    /// it is next launched. Defaults to a no-op.
+    fn set_launch_args(
+        &self,
+        _server_id: &str,
+        _args: Vec<String>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async {})
+    }
+
+    /// Extra environment variables for the agent process behind `server_id`,
+    /// applied when it is next launched. Defaults to a no-op.
+    fn set_launch_env(
+        &self,
+        _server_id: &str,
+        _env: HashMap<String, String>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async {})
+    }
+
+    /// Scheduling priority for the agent process behind `server_id`, applied
+    /// when it is next launched. Defaults to a no-op.
+    fn set_launch_priority(
+        &self,
+        _server_id: &str,
+        _priority: SessionPriority,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async {})
+    }
+
+    /// Whether the agent process instance behind `server_id` still exists.
+    /// Defaults to `true` for backends that do not track instances.
+    fn has_instance(&self, _server_id: &str) -> Pin<Box<dyn Future<Output = bool> + Send + '_>> {
+        Box::pin(async { true })
+    }
+
+    /// Snapshot of the running agent process instances, used for health
+    /// reporting. Defaults to none for backends that do not track them.
+    fn instances(&self) -> Pin<Box<dyn Future<Output = Vec<AcpInstanceSummary>> + Send + '_>> {
+        Box::pin(async { Vec::new() })
+    }
+
+    /// Send a typed ACP call and decode its response. Built on [`post`](Self::post),
+    /// which stays available for payloads that need no typing.
+    fn call(
+        &self,
+        server_id: &str,
+        bootstrap_agent: Option<&str>,
+        id: String,
+        call: AcpCall,
+    ) -> Pin<Box<dyn Future<Output = Result<AcpCallOutcome, AcpCallError>> + Send + '_>> {
+        let server_id = server_id.to_string();
+        let bootstrap_agent = bootstrap_agent.map(ToOwned::to_owned);
+        Box::pin(async move {
+            let response = self
+                .post(&server_id, bootstrap_agent.as_deref(), call.to_request(id))
+                .await
+                .map_err(AcpCallError::Transport)?;
+            let AcpDispatchResult::Response(response) = response else {
+                return Ok(AcpCallOutcome::Accepted);
+            };
+            if let Some(error) = response.get("error") {
+                return Err(AcpCallError::from_error_object(error));
+            }
+            let result = response.get("result").cloned().unwrap_or(Value::Null);
+            call.parse_result(result)
+                .map(AcpCallOutcome::Result)
+                .map_err(|err| AcpCallError::InvalidResult(err.to_string()))
+        })
+    }
+}
+
+/// Receives adapter measurements for export, e.g. as Prometheus metrics.
+/// Every method defaults to a no-op.
+pub trait AdapterMetrics: Send + Sync + 'static {
+    /// A prompt turn for `agent` finished, successfully or not.
+    fn prompt_completed(&self, _agent: &str, _elapsed: Duration) {}
+
+    /// A session or event row was written to SQLite.
+    fn sqlite_write(&self, _elapsed: Duration) {}
+
+    /// An `/event` subscriber connected (`1`) or disconnected (`-1`).
+    fn event_subscribers_changed(&self, _delta: i64) {}
+
+    /// `consumer` fell behind the event broadcast and missed `count` events.
+    fn events_dropped(&self, _consumer: &str, _count: u64) {}
+
+    /// An `/event` heartbeat was handed to the connection `lag` after it was
+    /// due, i.e. once the subscriber was ready for the frame after it.
+    fn heartbeat_delivered(&self, _lag: Duration) {}
+
+    /// A request body did not match the schema generated from `body`, the
+    /// type it is read into, in `problems` places.
+    fn schema_drift(&self, _body: &str, _problems: usize) {}
+
+    /// A turn on `agent` finished after its agent processes used `cpu` of
+    /// CPU time, `peak_rss_bytes` of memory at most and wrote
+    /// `disk_write_bytes` to storage.
+    fn turn_resources(
+        &self,
+        _agent: &str,
+        _cpu: Duration,
+        _peak_rss_bytes: u64,
+        _disk_write_bytes: u64,
+    ) {
+    }
+}
+
+/// Decides which bearer tokens are accepted when they can change while the
+/// server runs, e.g. during token rotation.
+pub trait TokenVerifier: Send + Sync + 'static {
+    fn verify(&self, token: &str) -> bool;
+}
+
+/// Sees every event the adapter emits, after secrets are masked and in
+/// emission order, e.g. to publish the stream to an external bus. Called
+/// on the emitting task, so implementations must not block.
+pub trait EventObserver: Send + Sync + 'static {
+    fn observe(&self, event: &Value);
 }
 
 pub struct OpenCodeAdapterConfig {
     pub auth_token: Option<String>,
+    /// Checks bearer tokens in place of `auth_token` when set.
+    pub token_verifier: Option<Arc<dyn TokenVerifier>>,
     pub sqlite_path: Option<String>,
     pub replay_max_events: usize,
     pub replay_max_chars: usize,
@@ -91,12 +411,138 @@ pub struct OpenCodeAdapterConfig {
     /// Optional pre-built provider payload for `/provider` and `/config/providers`.
     /// When `None`, falls back to the hardcoded mock/amp/claude/codex list.
     pub provider_payload: Option<Value>,
+    /// Optional models.dev catalog merged into the provider payload, so
+    /// models carry pricing, modalities and limits. Refreshed in the
+    /// background; a bundled snapshot is used until a fetch succeeds.
+    pub model_catalog: Option<ModelCatalogConfig>,
+    /// JSON-RPC methods `POST /session/:id/rpc` may forward to the agent.
+    /// Entries ending in `*` match by prefix (e.g. `_claude/*`). Empty
+    /// disables the passthrough.
+    pub rpc_method_allowlist: Vec<String>,
+    /// Optional S3-compatible archive for completed sessions. When `Some`, a
+    /// background job exports idle sessions and drops their local event log;
+    /// archived sessions are rehydrated on first access.
+    pub archive: Option<SessionArchiveConfig>,
+    /// Optional webhook targets notified of `permission.asked` and
+    /// `question.asked`. A target may answer inline in its response body.
+    pub webhooks: Option<WebhookConfig>,
+    /// Base URL for locally generated share links (`{base}/share/{id}`).
+    /// Defaults to `OPENCODE_COMPAT_SHARE_BASE_URL`, then to the adapter's
+    /// own URL as seen by the sharing request.
+    pub share_base_url: Option<String>,
+    /// Optional sink for prompt latency, SQLite write latency, `/event`
+    /// subscriber counts and dropped broadcast events.
+    pub metrics: Option<Arc<dyn AdapterMetrics>>,
+    /// Optional observer of the emitted event stream.
+    pub event_observer: Option<Arc<dyn EventObserver>>,
+    /// Format of `/session/:id/transcript.ndjson`; the route answers 404
+    /// without one.
+    pub transcript_format: Option<Arc<dyn TranscriptFormat>>,
+    /// Optional policy consulted on every permission request before it is
+    /// put to the user.
+    pub authorizer: Option<Arc<dyn Authorizer>>,
+    /// Inject `AGENTS.md`, `CLAUDE.md` and `codex.md` from the session
+    /// directory into agents that do not read them natively. Disabled by
+    /// `OPENCODE_COMPAT_CONTEXT_FILES=0`.
+    pub context_files: bool,
+    /// Middleware run around every prompt, in registration order before it
+    /// and in reverse order after it.
+    pub prompt_interceptors: Vec<Arc<dyn PromptInterceptor>>,
+    /// Converters for embedded images the agent cannot take (e.g. HEIC to
+    /// PNG), tried in order. Without one, such images are rejected with 415.
+    pub attachment_transcoders: Vec<Arc<dyn AttachmentTranscoder>>,
+    /// Optional periodic check that returns busy sessions whose turn was
+    /// orphaned (agent exited, or no turn is running) to idle.
+    pub session_watchdog: Option<SessionWatchdogConfig>,
+    /// Optional idle TTL after which a settled session's agent processes are
+    /// stopped. The session keeps its history, is marked dormant, and
+    /// bootstraps a fresh agent on its next prompt.
+    pub session_expiry: Option<SessionExpiryConfig>,
+    /// Optional startup pass that starts the agent processes of recently
+    /// active sessions again, so their first prompt after a restart does not
+    /// wait for the launch.
+    pub session_prewarm: Option<SessionPrewarmConfig>,
+    /// Optional caps on live sessions, sessions created per hour and
+    /// persisted events. Creating or forking a session over a cap is
+    /// rejected and emits `quota.exceeded`.
+    pub session_quota: Option<SessionQuotaConfig>,
+    /// Largest agent payload, in serialized bytes, attached to translated
+    /// events for `/event?include=native` subscribers; larger payloads are
+    /// replaced by a size marker. `0`, the default, disables the flag.
+    /// Overridden by `OPENCODE_COMPAT_NATIVE_EVENT_MAX_BYTES`.
+    pub native_event_max_bytes: usize,
+    /// Largest tool output, in bytes, kept on a part. Longer outputs are
+    /// truncated with a marker before they are persisted or emitted, and the
+    /// full output is served by `GET /part/:partID/full`. `0` disables the
+    /// cap. Overridden by `OPENCODE_COMPAT_PART_OUTPUT_MAX_BYTES`.
+    pub part_output_max_bytes: usize,
+    /// How often `/event` sends a `server.heartbeat` event. Overridden by
+    /// `OPENCODE_COMPAT_HEARTBEAT_MS`, and per connection by `?heartbeatMs=`.
+    pub heartbeat_interval: Duration,
+    /// How often an idle `/event` stream sends an SSE comment to keep
+    /// proxies from closing it. Overridden by `OPENCODE_COMPAT_KEEPALIVE_MS`,
+    /// and per connection by `?keepAliveMs=`.
+    pub keep_alive_interval: Duration,
+    /// How long streamed text and reasoning chunks are merged before a single
+    /// `message.part.updated` carries them. `Duration::ZERO` emits an event
+    /// per chunk. Overridden by `OPENCODE_COMPAT_COALESCE_MS`.
+    pub chunk_coalesce_window: Duration,
+    /// Merged chunks are emitted early once they reach this many bytes.
+    /// Overridden by `OPENCODE_COMPAT_COALESCE_CHARS`.
+    pub chunk_coalesce_max_chars: usize,
+    /// Permission requests for the same tool and directory (or command
+    /// prefix) that arrive within this long of each other are surfaced as
+    /// one aggregated `permission.asked` whose reply answers all of them.
+    /// `Duration::ZERO` asks each request on its own. Overridden by
+    /// `OPENCODE_COMPAT_PERMISSION_BATCH_MS`.
+    pub permission_batch_window: Duration,
+    /// Timestamps for sessions, messages and events. Tests swap in a
+    /// [`FixedClock`] to get reproducible payloads.
+    pub clock: Arc<dyn Clock>,
+    /// IDs for sessions, messages, parts and requests. Tests swap in
+    /// [`SequentialIds::starting_at`] to get reproducible payloads.
+    pub id_generator: Arc<dyn IdGenerator>,
+    /// Lock on the SQLite database, taken by the caller before the router is
+    /// built. Once another instance takes the database over, this adapter
+    /// stops using it.
+    pub database_lock: Option<Arc<DatabaseLock>>,
+    /// Snapshot the session directory into a shadow git repository next to
+    /// the database when each turn starts, so
+    /// `POST /session/:id/turn/:turnID/rollback` can undo the turn's file
+    /// changes. Enabled by `OPENCODE_COMPAT_WORKSPACE_SNAPSHOTS=1`.
+    pub workspace_snapshots: bool,
+    /// Register the files each turn creates or modifies as artifacts,
+    /// listed by `GET /session/:id/turn/:turnID/artifacts`. Disabled by
+    /// `OPENCODE_COMPAT_TURN_ARTIFACTS=0`.
+    pub turn_artifacts: bool,
+    /// Reject request bodies that do not match the JSON schema generated
+    /// from their type (unknown fields, wrong types, missing fields) with a
+    /// 400 problem response. Mismatches are logged either way. Enabled by
+    /// `OPENCODE_COMPAT_STRICT_SCHEMAS=1`.
+    pub strict_schemas: bool,
+    /// Models to retry a turn with when the agent rejects the session's
+    /// model, per provider, most preferred first (e.g. `{"anthropic":
+    /// ["claude-opus-4", "claude-sonnet-4"]}`). A rejected model falls back
+    /// to the models listed after it. Overridden by
+    /// `OPENCODE_COMPAT_MODEL_FALLBACKS` as JSON.
+    pub model_fallbacks: HashMap<String, Vec<String>>,
+    /// Most prompt turns running at once across all sessions. Turns over the
+    /// cap are queued and start by session priority, then in submission
+    /// order. `0` disables the cap. Overridden by
+    /// `OPENCODE_COMPAT_MAX_CONCURRENT_TURNS`.
+    pub max_concurrent_turns: usize,
+    /// How often the agent processes behind a session are sampled from
+    /// `/proc` while a turn runs, for the CPU, memory and disk usage
+    /// reported on the turn. `Duration::ZERO`, the default, disables
+    /// sampling. Overridden by `OPENCODE_COMPAT_TURN_RESOURCE_MS`.
+    pub turn_resource_interval: Duration,
 }
 
 impl Default for OpenCodeAdapterConfig {
     fn default() -> Self {
         Self {
             auth_token: None,
+            token_verifier: None,
             sqlite_path: None,
             replay_max_events: DEFAULT_REPLAY_MAX_EVENTS,
             replay_max_chars: DEFAULT_REPLAY_MAX_CHARS,
@@ -104,6 +550,38 @@ impl Default for OpenCodeAdapterConfig {
             native_proxy_manager: None,
             acp_dispatch: None,
             provider_payload: None,
+            model_catalog: None,
+            rpc_method_allowlist: Vec::new(),
+            archive: None,
+            webhooks: None,
+            share_base_url: None,
+            metrics: None,
+            event_observer: None,
+            transcript_format: None,
+            authorizer: None,
+            context_files: true,
+            prompt_interceptors: Vec::new(),
+            attachment_transcoders: Vec::new(),
+            session_watchdog: None,
+            session_expiry: None,
+            session_prewarm: None,
+            session_quota: None,
+            native_event_max_bytes: 0,
+            part_output_max_bytes: DEFAULT_PART_OUTPUT_MAX_BYTES,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            keep_alive_interval: DEFAULT_KEEP_ALIVE_INTERVAL,
+            chunk_coalesce_window: DEFAULT_CHUNK_COALESCE_WINDOW,
+            chunk_coalesce_max_chars: DEFAULT_CHUNK_COALESCE_MAX_CHARS,
+            permission_batch_window: Duration::ZERO,
+            clock: Arc::new(SystemClock),
+            id_generator: Arc::new(SequentialIds::default()),
+            database_lock: None,
+            workspace_snapshots: false,
+            turn_artifacts: true,
+            strict_schemas: false,
+            model_fallbacks: HashMap::new(),
+            max_concurrent_turns: 0,
+            turn_resource_interval: Duration::ZERO,
         }
     }
 }
@@ -112,14 +590,165 @@ impl Default for OpenCodeAdapterConfig {
 struct OpenCodeStreamEvent {
     id: u64,
     payload: Value,
+    /// The agent payload this event was translated from, if any.
+    native: Option<Arc<Value>>,
+}
+
+impl OpenCodeStreamEvent {
+    /// The event as sent to a subscriber, with the agent payload under
+    /// `native` when the subscriber asked for it.
+    fn into_payload(self, include_native: bool) -> Value {
+        let mut payload = self.payload;
+        if let (true, Some(native), Some(object)) =
+            (include_native, self.native, payload.as_object_mut())
+        {
+            object.insert("native".to_string(), Value::clone(&native));
+        }
+        payload
+    }
 }
 
 #[derive(Clone, Debug)]
 struct SessionState {
     meta: SessionMeta,
     messages: Vec<MessageRecord>,
-    status: String,
-    always_permissions: HashSet<String>,
+    lifecycle: SessionLifecycle,
+    /// What "always" replies approved; matching permission requests are
+    /// answered without asking again.
+    always_rules: Vec<PermissionRule>,
+    /// Context items attached via `POST /session/:id/context` that have not
+    /// yet been injected into a prompt.
+    pending_context: Vec<Value>,
+    /// Object key of the archived event log when the session's events have
+    /// been moved to the archive and not yet rehydrated.
+    archive_key: Option<String>,
+}
+
+impl SessionState {
+    /// Whether the session has messages from a real turn. Messages imported
+    /// through `initialHistory` do not pin the model selection.
+    fn has_turns(&self) -> bool {
+        self.messages
+            .iter()
+            .any(|message| message.info.get("imported") != Some(&Value::Bool(true)))
+    }
+
+    /// Whether the session holds an imported transcript no turn has replayed
+    /// to the agent yet.
+    fn awaits_history_replay(&self) -> bool {
+        !self.messages.is_empty() && !self.has_turns()
+    }
+}
+
+/// Why a session's working directory was rejected.
+#[derive(Debug, thiserror::Error)]
+enum SessionDirectoryError {
+    #[error("session directory must be an absolute path: {0}")]
+    Relative(String),
+    #[error("session directory does not exist: {0}")]
+    Missing(String),
+    #[error("session directory is not a directory: {0}")]
+    NotADirectory(String),
+}
+
+/// Check that `directory` names an existing directory inside the sandbox
+/// before it is handed to an agent as `cwd`.
+fn validate_session_directory(directory: &str) -> Result<(), SessionDirectoryError> {
+    let path = std::path::Path::new(directory);
+    if !path.is_absolute() {
+        return Err(SessionDirectoryError::Relative(directory.to_string()));
+    }
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => Err(SessionDirectoryError::NotADirectory(directory.to_string())),
+        Err(_) => Err(SessionDirectoryError::Missing(directory.to_string())),
+    }
+}
+
+fn session_init_json(directory: &str) -> Value {
+    json!({"cwd": directory, "mcpServers": []})
+}
+
+/// Typed session lifecycle. OpenCode clients only understand `idle`/`busy`
+/// (see [`SessionLifecycle::status_type`]); the full state is served on
+/// `/session/status` and every change is emitted as `session.lifecycle`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SessionLifecycle {
+    Created,
+    Bootstrapping,
+    Idle,
+    Prompting,
+    WaitingPermission,
+    WaitingQuestion,
+    Cancelling,
+    Ended,
+    Errored,
+}
+
+impl SessionLifecycle {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Bootstrapping => "bootstrapping",
+            Self::Idle => "idle",
+            Self::Prompting => "prompting",
+            Self::WaitingPermission => "waiting_permission",
+            Self::WaitingQuestion => "waiting_question",
+            Self::Cancelling => "cancelling",
+            Self::Ended => "ended",
+            Self::Errored => "errored",
+        }
+    }
+
+    /// The OpenCode `SessionStatus.type` this state is reported as.
+    fn status_type(self) -> &'static str {
+        match self {
+            Self::Created | Self::Idle | Self::Ended | Self::Errored => "idle",
+            Self::Bootstrapping
+            | Self::Prompting
+            | Self::WaitingPermission
+            | Self::WaitingQuestion
+            | Self::Cancelling => "busy",
+        }
+    }
+
+    /// Whether reaching this state ends the running turn.
+    fn ends_turn(self) -> bool {
+        matches!(self, Self::Idle | Self::Ended | Self::Errored)
+    }
+
+    /// Map a persisted status envelope back to a state. Envelopes written
+    /// before the typed lifecycle only carry the OpenCode `status` string.
+    fn from_status_params(params: &Value) -> Self {
+        if let Some(state) = params
+            .get("state")
+            .cloned()
+            .and_then(|value| serde_json::from_value(value).ok())
+        {
+            return state;
+        }
+        match params.get("status").and_then(Value::as_str) {
+            Some("busy") => Self::Prompting,
+            _ => Self::Idle,
+        }
+    }
+
+    fn can_transition_to(self, next: Self) -> bool {
+        use SessionLifecycle::*;
+        match (self, next) {
+            (Ended, _) => false,
+            (_, Ended | Errored | Idle) => true,
+            (Created | Idle | Errored, Bootstrapping | Prompting) => true,
+            (Bootstrapping, Prompting) => true,
+            (
+                Prompting | WaitingPermission | WaitingQuestion,
+                Prompting | WaitingPermission | WaitingQuestion | Cancelling,
+            ) => true,
+            (Bootstrapping, Cancelling) => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -140,6 +769,8 @@ struct SessionMeta {
     created_at: i64,
     updated_at: i64,
     share_url: Option<String>,
+    /// Token behind a locally generated `share_url`; see `GET /share/:shareID`.
+    share_id: Option<String>,
     permission_mode: Option<String>,
     agent: String,
     provider_id: String,
@@ -148,6 +779,97 @@ struct SessionMeta {
     last_connection_id: String,
     session_init_json: Option<Value>,
     destroyed_at: Option<i64>,
+    /// Context files applied when the session first prompted.
+    #[serde(default)]
+    context_files: Vec<AppliedContextFile>,
+    /// Variables set on the agent process, from `SessionCreateBody.env`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, SessionEnvVar>,
+    /// Deny permission requests for side-effecting tools without asking, so
+    /// the agent can plan without changing the sandbox.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    dry_run: bool,
+    /// Composer backends by target name; a prompt with `target` runs on that
+    /// backend's agent instead of the session's own.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    backends: BTreeMap<String, ComposerBackend>,
+    /// Scheduling priority of the session's agent processes and turns.
+    #[serde(default, skip_serializing_if = "SessionPriority::is_normal")]
+    priority: SessionPriority,
+    /// Whether the session runs sandbox automation rather than user work.
+    #[serde(default, skip_serializing_if = "SessionKind::is_user")]
+    kind: SessionKind,
+    /// When the session's agent processes were stopped for being idle; the
+    /// next prompt bootstraps them again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dormant_at: Option<i64>,
+    /// Base URL of the instance the session was migrated to; it is served
+    /// there from then on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    migrated_to: Option<String>,
+}
+
+/// Who a session is for. `system` sessions run sandbox automation, such as
+/// maintenance prompts, and stay out of default session listings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum SessionKind {
+    #[default]
+    User,
+    System,
+}
+
+impl SessionKind {
+    fn is_user(&self) -> bool {
+        *self == Self::User
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::System => "system",
+        }
+    }
+}
+
+impl SessionMeta {
+    /// The session as composer backend `target` runs it: that backend's
+    /// agent and model, on its own ACP server instance.
+    fn for_backend(&self, target: &str) -> Option<SessionMeta> {
+        let backend = self.backends.get(target)?;
+        let mut meta = self.clone();
+        meta.agent = backend.agent.clone();
+        meta.provider_id = backend.provider_id.clone();
+        meta.model_id = backend.model_id.clone();
+        meta.agent_session_id = composer::server_id(&self.agent_session_id, target);
+        Some(meta)
+    }
+
+    /// The session's own ACP server instance and one per composer backend.
+    fn acp_server_ids(&self) -> Vec<String> {
+        std::iter::once(self.agent_session_id.clone())
+            .chain(
+                self.backends
+                    .keys()
+                    .map(|target| composer::server_id(&self.agent_session_id, target)),
+            )
+            .collect()
+    }
+}
+
+/// How an ACP server instance was last bootstrapped.
+#[derive(Debug, Clone, Default)]
+struct AcpBackend {
+    /// Bootstraps (`initialize` + `session/new`) since the server started;
+    /// each one is a new agent process connection.
+    generation: u64,
+    bootstrapped_at: i64,
+    /// `agentInfo` from the `initialize` response.
+    agent_info: Option<Value>,
+    /// `authMethods` from the `initialize` response.
+    auth_methods: Vec<Value>,
+    /// The method the instance authenticated with, once it has.
+    authenticated: Option<String>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -157,9 +879,21 @@ struct Projection {
     questions: HashMap<String, Value>,
 }
 
+/// A row of `acp_bindings`: the ACP session an agent process runs for an
+/// OpenCode session, and the last notification translated from it.
+#[derive(Debug, Clone)]
+struct AcpBinding {
+    server_id: String,
+    session_id: String,
+    acp_session_id: String,
+    last_event_id: Option<u64>,
+}
+
 #[derive(Debug, Clone)]
 struct AcpPendingRequest {
     opencode_session_id: String,
+    /// The ACP server instance that asked, which gets the reply.
+    server_id: String,
     /// The JSON-RPC `id` from the ACP agent request (permission or question).
     jsonrpc_id: Value,
     kind: AcpPendingKind,
@@ -171,6 +905,279 @@ enum AcpPendingKind {
     Question,
 }
 
+/// A turn retried on a fallback model after the agent rejected the
+/// session's model.
+#[derive(Debug, Clone)]
+struct ModelFallback {
+    /// The session's model, which the agent rejected.
+    requested: String,
+    /// The model the turn actually ran on.
+    model: String,
+    /// The agent's error for the last rejected model.
+    error: String,
+}
+
+impl ModelFallback {
+    /// Record the model actually used on an assistant message.
+    fn annotate(&self, info: &mut Value) {
+        info["modelID"] = json!(self.model);
+        info["modelFallback"] = json!({
+            "requested": self.requested,
+            "error": self.error,
+        });
+    }
+}
+
+/// A sub-agent tool call linked to the child session that records its work.
+#[derive(Debug, Clone)]
+struct SubagentCall {
+    child_id: String,
+    /// The child's user message holding the delegated prompt.
+    prompt_message_id: String,
+}
+
+/// A prompt turn started through `POST /session/:id/message` or
+/// `POST /session/:id/prompt_async`.
+#[derive(Debug, Clone)]
+struct TurnRecord {
+    session_id: String,
+    message_id: String,
+    /// Secret handed to the prompting client for
+    /// `GET /session/:id/turn/by-token/:token`.
+    token: String,
+    status: TurnStatus,
+    created_at: i64,
+    completed_at: Option<i64>,
+    /// HTTP status of the prompt response once the turn finished.
+    http_status: Option<StatusCode>,
+    /// Body of the prompt response: the assistant message on success, the
+    /// error payload on failure.
+    output: Option<Value>,
+    /// Tool calls the agent made during the turn.
+    timeline: ToolTimeline,
+    /// Shadow commit of the session directory taken as the turn started.
+    snapshot: Option<String>,
+    /// How the prompt's `system` override was forwarded to the agent.
+    system_prompt: Option<SystemPromptMechanism>,
+    /// The thinking budget the prompt's `variant` or `reasoningBudget` asked
+    /// for.
+    reasoning: Option<ReasoningBudget>,
+    /// The session directory's files as the turn started, to find the ones
+    /// it changed.
+    baseline: Option<Arc<artifacts::Baseline>>,
+    /// Files named by the turn's tool calls, with the call that named them.
+    tool_paths: BTreeMap<String, String>,
+    /// Files the turn created or modified, once it finished.
+    artifacts: Vec<artifacts::Artifact>,
+    /// Streaming speed of the turn's reply, live while it streams.
+    stream_stats: Option<StreamSnapshot>,
+    /// Resource usage of the agent processes, live while the turn runs.
+    resources: Option<TurnResourceUsage>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TurnStatus {
+    /// Waiting for the session's running turn to finish (`?queue=true`).
+    Queued,
+    Running,
+    Completed,
+    Error,
+}
+
+impl TurnStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            TurnStatus::Queued => "queued",
+            TurnStatus::Running => "running",
+            TurnStatus::Completed => "completed",
+            TurnStatus::Error => "error",
+        }
+    }
+
+    fn is_finished(self) -> bool {
+        matches!(self, TurnStatus::Completed | TurnStatus::Error)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+enum TurnStartError {
+    #[error(transparent)]
+    Locked(#[from] TurnLockError),
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl TurnStartError {
+    fn into_response(self) -> Response {
+        match &self {
+            TurnStartError::Locked(TurnLockError::Busy {
+                session_id,
+                turn_id,
+            }) => (
+                StatusCode::CONFLICT,
+                Json(json!({"errors":[{
+                    "message": self.to_string(),
+                    "name": "SessionBusyError",
+                    "data": {"sessionID": session_id, "turnID": turn_id},
+                }]})),
+            )
+                .into_response(),
+            TurnStartError::Internal(err) => internal_error(err.clone()),
+        }
+    }
+}
+
+impl TurnRecord {
+    fn to_value(&self, turn_id: &str) -> Value {
+        let mut value = json!({
+            "id": turn_id,
+            "sessionID": self.session_id,
+            "messageID": self.message_id,
+            "status": self.status.as_str(),
+            "time": {"created": self.created_at},
+        });
+        if let Some(completed) = self.completed_at {
+            value["time"]["completed"] = json!(completed);
+        }
+        if let Some(snapshot) = &self.snapshot {
+            value["snapshot"] = json!(snapshot);
+        }
+        if let Some(mechanism) = self.system_prompt {
+            value["systemPrompt"] = json!({"mechanism": mechanism.as_str()});
+        }
+        if let Some(reasoning) = &self.reasoning {
+            value["reasoning"] = reasoning.to_value();
+        }
+        if let Some(stats) = &self.stream_stats {
+            value["stats"] = json!(stats);
+        }
+        if let Some(resources) = &self.resources {
+            value["resources"] = json!(resources);
+        }
+        match (&self.output, self.status) {
+            (Some(output), TurnStatus::Completed) => {
+                if let Some(stop_reason) = output.pointer("/info/stopReason") {
+                    value["stopReason"] = stop_reason.clone();
+                }
+                value["result"] = output.clone();
+            }
+            (Some(output), TurnStatus::Error) => value["error"] = output.clone(),
+            _ => {}
+        }
+        value
+    }
+
+    /// `GET /session/:id/turn/:turnID/artifacts` payload.
+    fn artifacts_value(&self, turn_id: &str) -> Value {
+        let artifacts: Vec<Value> = self
+            .artifacts
+            .iter()
+            .map(|artifact| {
+                let mut value = json!(artifact);
+                value["url"] = json!(format!(
+                    "/session/{}/turn/{turn_id}/artifacts/{}",
+                    self.session_id, artifact.id
+                ));
+                value
+            })
+            .collect();
+        json!({
+            "turnID": turn_id,
+            "sessionID": self.session_id,
+            "status": self.status.as_str(),
+            "artifacts": artifacts,
+        })
+    }
+
+    /// `GET /session/:id/turn/:turnID/timeline` payload.
+    fn timeline_value(&self, turn_id: &str) -> Value {
+        timeline_value(
+            turn_id,
+            &self.session_id,
+            self.status.as_str(),
+            self.created_at,
+            self.completed_at,
+            &self.timeline,
+        )
+    }
+}
+
+/// Skeleton of a `tool.started`, `tool.output`, `tool.completed` or
+/// `tool.failed` event. These mirror a tool part's lifecycle for clients
+/// that would rather not diff part state.
+fn tool_event(
+    event_type: &str,
+    session_id: &str,
+    message_id: Option<&str>,
+    call_id: &str,
+    tool: &str,
+) -> Value {
+    let mut properties = json!({
+        "sessionID": session_id,
+        "callID": call_id,
+        "tool": tool,
+    });
+    if let Some(message_id) = message_id {
+        properties["messageID"] = json!(message_id);
+    }
+    json!({"type": event_type, "properties": properties})
+}
+
+/// `tool.completed` for a completed span; `tool.failed` for a failed or
+/// interrupted one.
+fn tool_end_event(
+    session_id: &str,
+    message_id: Option<&str>,
+    span: &timeline::ToolSpan,
+    output: Option<&str>,
+) -> Value {
+    let event_type = if span.status == "completed" {
+        "tool.completed"
+    } else {
+        "tool.failed"
+    };
+    let mut event = tool_event(
+        event_type,
+        session_id,
+        message_id,
+        &span.call_id,
+        &span.tool,
+    );
+    event["properties"]["status"] = json!(span.status);
+    if let Some(kind) = span.kind.as_deref() {
+        event["properties"]["kind"] = json!(kind);
+    }
+    if let Some(output) = output {
+        event["properties"]["output"] = json!(output);
+    }
+    event["properties"]["durationMs"] = json!(span.duration_ms);
+    event["properties"]["time"] = json!({"start": span.start, "end": span.end});
+    event
+}
+
+fn timeline_value(
+    turn_id: &str,
+    session_id: &str,
+    status: &str,
+    created_at: i64,
+    completed_at: Option<i64>,
+    timeline: &ToolTimeline,
+) -> Value {
+    let mut time = json!({"start": created_at});
+    if let Some(completed) = completed_at {
+        time["end"] = json!(completed);
+        time["durationMs"] = json!(completed - created_at);
+    }
+    json!({
+        "turnID": turn_id,
+        "sessionID": session_id,
+        "status": status,
+        "time": time,
+        "toolMs": timeline.busy_ms(),
+        "tools": timeline.tools,
+    })
+}
+
 struct AdapterState {
     config: OpenCodeAdapterConfig,
     sqlite_path: String,
@@ -185,16 +1192,81 @@ struct AdapterState {
     event_broadcaster: broadcast::Sender<OpenCodeStreamEvent>,
     event_log: StdMutex<VecDeque<OpenCodeStreamEvent>>,
     next_event_id: AtomicU64,
-    next_id: AtomicU64,
+    /// Whether an event consumer is registered; emitted events are then
+    /// written to the persisted log consumers resume from.
+    event_consumers_active: AtomicBool,
+    event_log_writer: OnceLock<mpsc::UnboundedSender<LoggedEvent>>,
+    /// Connected subscribers that asked for agent payloads; none are
+    /// attached to events while there are none.
+    native_subscribers: Arc<AtomicUsize>,
+    /// Last `seq` stamped on a `message.part.updated` event, per session.
+    part_seq: StdMutex<HashMap<String, u64>>,
+    /// Secret session env values, per session, masked in every event.
+    session_secrets: StdMutex<HashMap<String, Vec<String>>>,
+    /// IDs of `system` sessions, whose events are labelled as such.
+    system_sessions: StdMutex<HashSet<String>>,
+    /// JSON schemas generated from request body types, by type name.
+    request_schemas: StdMutex<HashMap<&'static str, Arc<Value>>>,
+    /// The fallback model serving the current turn, per session, when the
+    /// session's own model was rejected.
+    turn_model_fallbacks: Mutex<HashMap<String, ModelFallback>>,
+    /// Sub-agents named by the current turn's `agent` parts, per session,
+    /// that no tool call has started yet.
+    pending_subagents: Mutex<HashMap<String, Vec<String>>>,
+    /// Running sub-agent tool calls, by call ID.
+    subagent_calls: Mutex<HashMap<String, SubagentCall>>,
     /// Tracks which ACP server instances have been initialized (initialize + session/new sent).
     /// Key is the ACP server_id (e.g. "acp_ses_42"), value is the ACP sessionId from session/new.
     acp_initialized: Mutex<HashMap<String, String>>,
+    /// Bootstrap history per ACP server_id, reported by `GET /session/:id/backend`.
+    acp_backends: Mutex<HashMap<String, AcpBackend>>,
+    /// Credentials from `POST /agents/:agent/authenticate`, by agent, sent to
+    /// each new instance of the agent. Kept in memory only.
+    agent_credentials: Mutex<HashMap<String, AuthenticateParams>>,
+    /// `promptCapabilities` by agent, from its latest `initialize` response.
+    prompt_capabilities: StdMutex<HashMap<String, PromptCapabilities>>,
     /// Maps pending ACP JSON-RPC request IDs to (opencode_session_id, request_kind).
     /// Used to correlate permission/question requests from the agent SSE stream.
     acp_request_ids: Mutex<HashMap<String, AcpPendingRequest>>,
+    /// Bursts of similar permission requests folded into aggregated ones.
+    permission_batches: Mutex<PermissionBatches>,
     /// Tracks the last user message ID per session so the SSE translation task
     /// can set the correct `parentID` on assistant messages.
     last_user_message_id: Mutex<HashMap<String, String>>,
+    /// When each session last persisted an event, for the session watchdog.
+    session_activity: StdMutex<HashMap<String, i64>>,
+    /// When each session's latest prompt went to the agent, for the time to
+    /// first token of the reply.
+    prompt_dispatched_at: StdMutex<HashMap<String, i64>>,
+    /// Sessions created within the last hour, for the session quota.
+    recent_sessions: StdMutex<RecentCreations>,
+    /// Held from a quota check until the admitted session is recorded, so
+    /// concurrent creations cannot both take the last slot.
+    quota_lock: Mutex<()>,
+    /// Sessions migrating in from other instances, between prepare and
+    /// commit.
+    session_imports: StdMutex<SessionImports>,
+    /// Latest ACP plan per session, served as `GET /session/:id/todo`.
+    session_todos: Mutex<HashMap<String, Value>>,
+    /// Prompt turns by turn ID, in start order.
+    turns: Mutex<Vec<(String, TurnRecord)>>,
+    /// Serializes prompt turns within each session.
+    turn_locks: TurnLocks,
+    /// Caps prompt turns running across all sessions.
+    turn_slots: TurnSlots,
+    /// Trips when the native sidecar keeps failing.
+    native_circuit: NativeCircuit,
+    /// Woken whenever a turn finishes.
+    turn_finished: Notify,
+    /// Workspaces from `POST /workspace/clone`, by ID. Kept in memory only.
+    workspaces: Mutex<HashMap<String, Workspace>>,
+    archive: Option<S3Client>,
+    /// Serializes archive exports and rehydration so a session is never
+    /// archived and restored concurrently.
+    archive_lock: Mutex<()>,
+    webhooks: Option<WebhookClient>,
+    model_catalog: Option<ModelCatalog>,
+    background_jobs: Once,
 }
 
 impl AdapterState {
@@ -202,6 +1274,19 @@ impl AdapterState {
         self.initialized
             .get_or_try_init(|| async {
                 let pool = self.pool().await?;
+                let application_id: i32 = sqlx::query_scalar("PRAGMA application_id;")
+                    .fetch_one(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                db_lock::validate_application_id(&self.sqlite_path, application_id)
+                    .map_err(|err| err.to_string())?;
+                sqlx::query(&format!(
+                    "PRAGMA application_id = {};",
+                    db_lock::SQLITE_APPLICATION_ID
+                ))
+                .execute(pool)
+                .await
+                .map_err(|err| err.to_string())?;
                 sqlx::query("PRAGMA journal_mode=WAL;")
                     .execute(pool)
                     .await
@@ -216,2827 +1301,10347 @@ impl AdapterState {
                     .execute(pool)
                     .await
                     .map_err(|err| err.to_string())?;
+                sqlx::query(include_str!("../migrations/0002_session_archives.sql"))
+                    .execute(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                sqlx::query(include_str!("../migrations/0003_session_turns.sql"))
+                    .execute(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                sqlx::query(include_str!("../migrations/0004_event_dedupe.sql"))
+                    .execute(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                sqlx::query(include_str!("../migrations/0005_session_created_index.sql"))
+                    .execute(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                sqlx::query(include_str!("../migrations/0006_turn_timelines.sql"))
+                    .execute(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                sqlx::query(include_str!("../migrations/0007_acp_bindings.sql"))
+                    .execute(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                sqlx::query(include_str!("../migrations/0008_events_created_index.sql"))
+                    .execute(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                sqlx::query(include_str!("../migrations/0009_part_blobs.sql"))
+                    .execute(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                sqlx::query(include_str!("../migrations/0010_turn_journal.sql"))
+                    .execute(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                sqlx::query(include_str!("../migrations/0011_turn_artifacts.sql"))
+                    .execute(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                sqlx::query(include_str!("../migrations/0012_event_consumers.sql"))
+                    .execute(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                sqlx::query(include_str!("../migrations/0013_event_count.sql"))
+                    .execute(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                sqlx::query(include_str!("../migrations/0014_workspaces.sql"))
+                    .execute(pool)
+                    .await
+                    .map_err(|err| err.to_string())?;
+
+                self.start_event_log().await?;
 
                 self.rebuild_projection().await?;
+                self.recover_turns().await?;
+                self.restore_turn_links().await?;
+                self.restore_workspaces().await?;
                 Ok(())
             })
             .await
             .map(|_| ())
     }
 
-    async fn rebuild_projection(&self) -> Result<(), String> {
-        let mut projection = Projection::default();
+    /// Reload each session's latest turn so assistant messages streamed after
+    /// a restart keep pointing at the user message that started them.
+    async fn restore_turn_links(&self) -> Result<(), String> {
         let pool = self.pool().await?;
-
         let rows = sqlx::query(
-            r#"SELECT s.id, s.agent, s.agent_session_id, s.last_connection_id, s.created_at, s.destroyed_at, s.session_init_json,
-                      m.metadata_json
-               FROM sessions s
-               JOIN opencode_session_metadata m ON m.session_id = s.id
-               ORDER BY s.created_at ASC, s.id ASC"#,
+            r#"SELECT t.session_id, t.user_message_id
+               FROM session_turns t
+               JOIN (SELECT session_id, MAX(ordinal) AS ordinal
+                     FROM session_turns GROUP BY session_id) latest
+                 ON latest.session_id = t.session_id AND latest.ordinal = t.ordinal"#,
         )
         .fetch_all(pool)
         .await
         .map_err(|err| err.to_string())?;
 
+        let mut links = self.last_user_message_id.lock().await;
         for row in rows {
-            let id: String = row.try_get("id").map_err(|err| err.to_string())?;
-            let agent: String = row.try_get("agent").map_err(|err| err.to_string())?;
-            let agent_session_id: String = row
-                .try_get("agent_session_id")
+            let session_id: String = row.try_get("session_id").map_err(|err| err.to_string())?;
+            let user_message_id: String = row
+                .try_get("user_message_id")
                 .map_err(|err| err.to_string())?;
-            let last_connection_id: String = row
-                .try_get("last_connection_id")
+            links.insert(session_id, user_message_id);
+        }
+        Ok(())
+    }
+
+    /// Reload the workspaces cloned so far. A clone the previous process was
+    /// still running is failed, as nothing finishes it now.
+    async fn restore_workspaces(&self) -> Result<(), String> {
+        let pool = self.pool().await?;
+        let rows = sqlx::query("SELECT workspace_json FROM workspaces")
+            .fetch_all(pool)
+            .await
+            .map_err(|err| err.to_string())?;
+        let mut interrupted = Vec::new();
+        let mut workspaces = self.workspaces.lock().await;
+        for row in rows {
+            let workspace_json: String = row
+                .try_get("workspace_json")
                 .map_err(|err| err.to_string())?;
-            let created_at: i64 = row.try_get("created_at").map_err(|err| err.to_string())?;
-            let destroyed_at: Option<i64> =
-                row.try_get("destroyed_at").map_err(|err| err.to_string())?;
-            let session_init_json: Option<String> = row
-                .try_get("session_init_json")
-                .map_err(|err| err.to_string())?;
-            let metadata_json: String = row
-                .try_get("metadata_json")
-                .map_err(|err| err.to_string())?;
-
-            let mut meta: SessionMeta =
-                serde_json::from_str(&metadata_json).map_err(|err| err.to_string())?;
-            meta.id = id.clone();
-            meta.agent = agent;
-            meta.agent_session_id = agent_session_id;
-            meta.last_connection_id = last_connection_id;
-            meta.created_at = created_at;
-            meta.destroyed_at = destroyed_at;
-            meta.session_init_json = session_init_json
-                .as_deref()
-                .and_then(|raw| serde_json::from_str(raw).ok());
-
-            projection.sessions.insert(
-                id,
-                SessionState {
-                    meta,
-                    messages: Vec::new(),
-                    status: "idle".to_string(),
-                    always_permissions: HashSet::new(),
-                },
-            );
+            let Ok(mut workspace) = serde_json::from_str::<Workspace>(&workspace_json) else {
+                continue;
+            };
+            if workspace.status == WorkspaceStatus::Cloning {
+                workspace.status = WorkspaceStatus::Failed;
+                workspace.error =
+                    Some("the clone was interrupted by an adapter restart".to_string());
+                workspace.finished_at = Some(self.now_ms());
+                interrupted.push(workspace.clone());
+            }
+            workspaces.insert(workspace.id.clone(), workspace);
+        }
+        drop(workspaces);
+        for workspace in &interrupted {
+            self.persist_workspace(workspace).await?;
         }
+        Ok(())
+    }
 
-        let event_rows = sqlx::query(
-            r#"SELECT session_id, sender, payload_json
-               FROM events
-               ORDER BY created_at ASC, id ASC"#,
+    async fn persist_workspace(&self, workspace: &Workspace) -> Result<(), String> {
+        let pool = self.pool().await?;
+        sqlx::query(
+            r#"INSERT OR REPLACE INTO workspaces (id, workspace_json, created_at)
+               VALUES (?1, ?2, ?3)"#,
         )
-        .fetch_all(pool)
+        .bind(&workspace.id)
+        .bind(serde_json::to_string(workspace).map_err(|err| err.to_string())?)
+        .bind(workspace.created_at)
+        .execute(pool)
         .await
         .map_err(|err| err.to_string())?;
-
-        for row in event_rows {
-            let session_id: String = row.try_get("session_id").map_err(|err| err.to_string())?;
-            let sender: String = row.try_get("sender").map_err(|err| err.to_string())?;
-            let payload_json: String =
-                row.try_get("payload_json").map_err(|err| err.to_string())?;
-            let payload: Value =
-                serde_json::from_str(&payload_json).map_err(|err| err.to_string())?;
-            apply_envelope(&mut projection, &session_id, &sender, &payload);
-        }
-
-        let mut guard = self.projection.lock().await;
-        *guard = projection;
         Ok(())
     }
 
-    fn emit_event(&self, payload: Value) {
-        let event = OpenCodeStreamEvent {
-            id: self.next_event_id.fetch_add(1, Ordering::Relaxed),
-            payload,
-        };
-
-        if let Ok(mut guard) = self.event_log.lock() {
-            guard.push_back(event.clone());
-            while guard.len() > EVENT_LOG_SIZE {
-                guard.pop_front();
-            }
-        }
-
-        let _ = self.event_broadcaster.send(event);
-    }
-
-    fn buffered_events_after(&self, last_event_id: Option<u64>) -> Vec<OpenCodeStreamEvent> {
-        let Some(last_event_id) = last_event_id else {
-            return Vec::new();
-        };
-        let Ok(guard) = self.event_log.lock() else {
-            return Vec::new();
-        };
-        guard
-            .iter()
-            .filter(|entry| entry.id > last_event_id)
-            .cloned()
-            .collect()
-    }
-
-    fn subscribe(&self) -> broadcast::Receiver<OpenCodeStreamEvent> {
-        self.event_broadcaster.subscribe()
-    }
-
-    fn next_id(&self, prefix: &str) -> String {
-        let value = self.next_id.fetch_add(1, Ordering::Relaxed);
-        format!("{prefix}{value}")
-    }
-
-    async fn current_connection_for_agent(&self, agent: &str) -> String {
-        let mut guard = self.agent_connections.lock().await;
-        guard
-            .entry(agent.to_string())
-            .or_insert_with(|| format!("conn_{}_{}", agent, now_ms()))
-            .clone()
-    }
-
-    async fn pool(&self) -> Result<&SqlitePool, String> {
-        self.pool
-            .get_or_try_init(|| async {
-                if let Some(parent) = PathBuf::from(&self.sqlite_path).parent() {
-                    if !parent.as_os_str().is_empty() {
-                        std::fs::create_dir_all(parent).map_err(|err| err.to_string())?;
-                    }
-                }
-                SqlitePoolOptions::new()
-                    .max_connections(1)
-                    .connect_with(self.sqlite_connect_options.clone())
-                    .await
-                    .map_err(|err| err.to_string())
-            })
-            .await
-    }
-
-    async fn persist_session(&self, meta: &SessionMeta) -> Result<(), String> {
+    /// Remember which ACP session `server_id` runs for `session_id`, so a
+    /// restarted adapter can re-attach to the agent process.
+    async fn save_acp_binding(
+        &self,
+        server_id: &str,
+        session_id: &str,
+        agent: &str,
+        acp_session_id: &str,
+    ) -> Result<(), String> {
         let pool = self.pool().await?;
-        let session_init_json = meta
-            .session_init_json
-            .as_ref()
-            .map(|value| serde_json::to_string(value).unwrap_or_else(|_| "{}".to_string()));
-
         sqlx::query(
-            r#"INSERT INTO sessions (
-                id, agent, agent_session_id, last_connection_id, created_at, destroyed_at, session_init_json
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
-            ON CONFLICT(id) DO UPDATE SET
-                agent = excluded.agent,
-                agent_session_id = excluded.agent_session_id,
-                last_connection_id = excluded.last_connection_id,
-                created_at = excluded.created_at,
-                destroyed_at = excluded.destroyed_at,
-                session_init_json = excluded.session_init_json"#,
+            r#"INSERT OR REPLACE INTO acp_bindings
+                 (server_id, session_id, agent, acp_session_id, last_event_id, updated_at)
+               VALUES (?1, ?2, ?3, ?4, NULL, ?5)"#,
         )
-        .bind(&meta.id)
-        .bind(&meta.agent)
-        .bind(&meta.agent_session_id)
-        .bind(&meta.last_connection_id)
-        .bind(meta.created_at)
-        .bind(meta.destroyed_at)
-        .bind(session_init_json)
+        .bind(server_id)
+        .bind(session_id)
+        .bind(agent)
+        .bind(acp_session_id)
+        .bind(self.now_ms())
         .execute(pool)
         .await
         .map_err(|err| err.to_string())?;
+        Ok(())
+    }
 
-        let metadata_json = serde_json::to_string(meta).map_err(|err| err.to_string())?;
+    /// Record that the translation task for `session_id` handled the agent's
+    /// notification `seq`.
+    async fn record_acp_cursor(&self, session_id: &str, seq: u64) -> Result<(), String> {
+        let pool = self.pool().await?;
         sqlx::query(
-            r#"INSERT INTO opencode_session_metadata (session_id, metadata_json)
-               VALUES (?1, ?2)
-               ON CONFLICT(session_id) DO UPDATE SET
-                 metadata_json = excluded.metadata_json"#,
+            "UPDATE acp_bindings SET last_event_id = ?1, updated_at = ?2 WHERE session_id = ?3",
         )
-        .bind(&meta.id)
-        .bind(metadata_json)
+        .bind(seq as i64)
+        .bind(self.now_ms())
+        .bind(session_id)
         .execute(pool)
         .await
         .map_err(|err| err.to_string())?;
-
         Ok(())
     }
 
-    async fn delete_session(&self, session_id: &str) -> Result<(), String> {
+    async fn load_acp_bindings(&self) -> Result<Vec<AcpBinding>, String> {
         let pool = self.pool().await?;
-        sqlx::query("DELETE FROM events WHERE session_id = ?1")
-            .bind(session_id)
-            .execute(pool)
-            .await
-            .map_err(|err| err.to_string())?;
-        sqlx::query("DELETE FROM opencode_session_metadata WHERE session_id = ?1")
-            .bind(session_id)
-            .execute(pool)
-            .await
-            .map_err(|err| err.to_string())?;
-        sqlx::query("DELETE FROM sessions WHERE id = ?1")
-            .bind(session_id)
+        let rows = sqlx::query(
+            "SELECT server_id, session_id, acp_session_id, last_event_id FROM acp_bindings",
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+        rows.into_iter()
+            .map(|row| {
+                let last_event_id: Option<i64> = row.try_get("last_event_id")?;
+                Ok(AcpBinding {
+                    server_id: row.try_get("server_id")?,
+                    session_id: row.try_get("session_id")?,
+                    acp_session_id: row.try_get("acp_session_id")?,
+                    last_event_id: last_event_id.map(|seq| seq as u64),
+                })
+            })
+            .collect::<Result<_, sqlx::Error>>()
+            .map_err(|err| err.to_string())
+    }
+
+    async fn delete_acp_binding(&self, server_id: &str) -> Result<(), String> {
+        let pool = self.pool().await?;
+        sqlx::query("DELETE FROM acp_bindings WHERE server_id = ?1")
+            .bind(server_id)
             .execute(pool)
             .await
             .map_err(|err| err.to_string())?;
         Ok(())
     }
 
-    async fn persist_event(
+    /// Start a turn for `session_id`: persist the `session/prompt` envelope
+    /// carrying `user_message_id`, record the turn and open its journal
+    /// entry in one transaction, so a crash never leaves half a turn start.
+    async fn persist_turn_start(
         &self,
         session_id: &str,
-        sender: &str,
-        payload: &Value,
+        user_message_id: &str,
+        prompt: &Value,
     ) -> Result<(), String> {
+        let row = self.event_row(session_id, "client", prompt).await;
         let pool = self.pool().await?;
-        let id = format!("evt_{}", self.next_id(""));
-        let created_at = now_ms();
-        let connection_id = {
-            let projection = self.projection.lock().await;
-            projection
-                .sessions
-                .get(session_id)
-                .map(|state| state.meta.last_connection_id.clone())
-                .unwrap_or_else(|| "conn_unknown".to_string())
-        };
+        let started = Instant::now();
+        let mut tx = pool.begin().await.map_err(|err| err.to_string())?;
+        let inserted = insert_event(&mut tx, &row).await?;
         sqlx::query(
-            r#"INSERT INTO events (id, session_id, created_at, connection_id, sender, payload_json)
-               VALUES (?1, ?2, ?3, ?4, ?5, ?6)"#,
+            r#"INSERT INTO session_turns (session_id, ordinal, user_message_id, created_at)
+               SELECT ?1, COALESCE(MAX(ordinal), 0) + 1, ?2, ?3
+               FROM session_turns WHERE session_id = ?1"#,
         )
-        .bind(id)
         .bind(session_id)
-        .bind(created_at)
-        .bind(connection_id)
-        .bind(sender)
-        .bind(serde_json::to_string(payload).map_err(|err| err.to_string())?)
-        .execute(pool)
+        .bind(user_message_id)
+        .bind(row.created_at)
+        .execute(&mut *tx)
         .await
         .map_err(|err| err.to_string())?;
-
-        let mut projection = self.projection.lock().await;
-        apply_envelope(&mut projection, session_id, sender, payload);
-
+        sqlx::query(
+            r#"INSERT OR REPLACE INTO turn_journal
+                 (session_id, user_message_id, prompt_event_id, phase, started_at, updated_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?5)"#,
+        )
+        .bind(session_id)
+        .bind(user_message_id)
+        .bind(&row.id)
+        .bind(TURN_PHASE_STARTED)
+        .bind(row.created_at)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| err.to_string())?;
+        tx.commit().await.map_err(|err| err.to_string())?;
+        if let Some(metrics) = self.config.metrics.as_ref() {
+            metrics.sqlite_write(started.elapsed());
+        }
+        if inserted {
+            self.event_committed(&row).await;
+        }
+        self.last_user_message_id
+            .lock()
+            .await
+            .insert(session_id.to_string(), user_message_id.to_string());
         Ok(())
     }
 
-    async fn collect_replay_events(
-        &self,
-        session_id: &str,
-        max_events: usize,
-    ) -> Result<Vec<Value>, String> {
+    /// Note in the journal that the running turn of `session_id` was handed
+    /// to its agent, so recovery rolls it forward rather than back.
+    async fn record_turn_dispatched(&self, session_id: &str) -> Result<(), String> {
+        let Some(user_message_id) = self
+            .last_user_message_id
+            .lock()
+            .await
+            .get(session_id)
+            .cloned()
+        else {
+            return Ok(());
+        };
         let pool = self.pool().await?;
-        let rows = sqlx::query(
-            r#"SELECT created_at, sender, payload_json
-               FROM events
-               WHERE session_id = ?1
-               ORDER BY created_at ASC, id ASC"#,
+        sqlx::query(
+            r#"UPDATE turn_journal SET phase = ?3, updated_at = ?4
+               WHERE session_id = ?1 AND user_message_id = ?2"#,
         )
         .bind(session_id)
-        .fetch_all(pool)
+        .bind(user_message_id)
+        .bind(TURN_PHASE_DISPATCHED)
+        .bind(self.now_ms())
+        .execute(pool)
         .await
         .map_err(|err| err.to_string())?;
+        Ok(())
+    }
 
-        let mut values = Vec::new();
-        for row in rows {
-            let created_at: i64 = row.try_get("created_at").map_err(|err| err.to_string())?;
-            let sender: String = row.try_get("sender").map_err(|err| err.to_string())?;
-            let payload_json: String =
-                row.try_get("payload_json").map_err(|err| err.to_string())?;
-            let payload: Value =
-                serde_json::from_str(&payload_json).map_err(|err| err.to_string())?;
-            values.push(json!({
-                "createdAt": created_at,
-                "sender": sender,
-                "payload": payload,
-            }));
+    /// Persist a lifecycle transition: the session row, `envelopes` and,
+    /// when the transition ends the running turn, its journal entry are
+    /// written in one transaction and then applied to the projection.
+    async fn persist_transition(
+        &self,
+        meta: &SessionMeta,
+        envelopes: &[Value],
+        ends_turn: bool,
+    ) -> Result<(), String> {
+        let mut rows = Vec::with_capacity(envelopes.len());
+        for envelope in envelopes {
+            rows.push(self.event_row(&meta.id, "agent", envelope).await);
         }
-
-        if values.len() > max_events {
-            Ok(values.split_off(values.len() - max_events))
-        } else {
-            Ok(values)
+        let pool = self.pool().await?;
+        let started = Instant::now();
+        let mut tx = pool.begin().await.map_err(|err| err.to_string())?;
+        write_session(&mut tx, meta).await?;
+        let mut inserted = Vec::with_capacity(rows.len());
+        for row in &rows {
+            inserted.push(insert_event(&mut tx, row).await?);
+        }
+        if ends_turn {
+            sqlx::query("DELETE FROM turn_journal WHERE session_id = ?1")
+                .bind(&meta.id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        tx.commit().await.map_err(|err| err.to_string())?;
+        if let Some(metrics) = self.config.metrics.as_ref() {
+            metrics.sqlite_write(started.elapsed());
+        }
+        for (row, inserted) in rows.iter().zip(inserted) {
+            if inserted {
+                self.event_committed(row).await;
+            }
         }
+        Ok(())
     }
 
-    async fn maybe_restore_session(&self, session_id: &str) -> Result<(), String> {
-        let (agent, stale) = {
-            let projection = self.projection.lock().await;
-            let Some(state) = projection.sessions.get(session_id) else {
-                return Ok(());
-            };
-            (
-                state.meta.agent.clone(),
-                state.meta.last_connection_id.clone(),
-            )
-        };
-
-        let current = self.current_connection_for_agent(&agent).await;
-        if stale == current {
+    /// Settle the turns a previous run left open in the journal. A turn the
+    /// agent never received is rolled back: its prompt and turn record are
+    /// removed. A turn the agent received is rolled forward: its unfinished
+    /// assistant message is closed as aborted. Either way the session is
+    /// left idle. Turns whose agent instance is still live are left to the
+    /// resumed translation, which finishes them.
+    async fn recover_turns(&self) -> Result<(), String> {
+        let pool = self.pool().await?;
+        let rows = sqlx::query(
+            r#"SELECT session_id, user_message_id, prompt_event_id, phase
+               FROM turn_journal ORDER BY started_at ASC, session_id ASC"#,
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+        if rows.is_empty() {
             return Ok(());
         }
+        let live_sessions = match self.config.acp_dispatch.as_ref() {
+            Some(dispatch) => {
+                let live = dispatch
+                    .instances()
+                    .await
+                    .into_iter()
+                    .map(|instance| instance.server_id)
+                    .collect::<HashSet<_>>();
+                self.load_acp_bindings()
+                    .await?
+                    .into_iter()
+                    .filter(|binding| live.contains(&binding.server_id))
+                    .map(|binding| binding.session_id)
+                    .collect::<HashSet<_>>()
+            }
+            None => HashSet::new(),
+        };
 
-        let replay_source = self
-            .collect_replay_events(session_id, self.config.replay_max_events)
-            .await?;
-        let replay_text = build_replay_text(&replay_source, self.config.replay_max_chars);
-
-        let request_id = self.next_id("oc_req_");
-        let new_agent_session_id = format!("acp_{}", self.next_id("ses_"));
-        let new_request = json!({
-            "jsonrpc": "2.0",
-            "id": request_id,
-            "method": "session/new",
-            "params": {
-                "cwd": "/",
-                "mcpServers": [],
+        for row in rows {
+            let session_id: String = row.try_get("session_id").map_err(|err| err.to_string())?;
+            let user_message_id: String = row
+                .try_get("user_message_id")
+                .map_err(|err| err.to_string())?;
+            let prompt_event_id: String = row
+                .try_get("prompt_event_id")
+                .map_err(|err| err.to_string())?;
+            let phase: String = row.try_get("phase").map_err(|err| err.to_string())?;
+            let dispatched = phase == TURN_PHASE_DISPATCHED;
+            if dispatched && live_sessions.contains(&session_id) {
+                continue;
             }
-        });
-        self.persist_event(session_id, "client", &new_request)
-            .await?;
 
-        let new_response = json!({
-            "jsonrpc": "2.0",
-            "id": request_id,
-            "result": {
-                "sessionId": new_agent_session_id,
+            let aborted = {
+                let projection = self.projection.lock().await;
+                projection.sessions.get(&session_id).map(|session| {
+                    let message = session.messages.iter().rev().find(|message| {
+                        message.info["role"] == "assistant"
+                            && message.info["parentID"] == user_message_id.as_str()
+                            && message.info.pointer("/time/completed").is_none()
+                    })?;
+                    let mut info = message.info.clone();
+                    info["time"]["completed"] = json!(self.now_ms());
+                    info["error"] = json!({
+                        "name": "MessageAbortedError",
+                        "data": {"message": "the turn was interrupted by an adapter restart"}
+                    });
+                    dispatched.then_some(info)
+                })
+            };
+            let Some(aborted) = aborted else {
+                // The session was deleted; nothing is left to settle.
+                sqlx::query(
+                    "DELETE FROM turn_journal WHERE session_id = ?1 AND user_message_id = ?2",
+                )
+                .bind(&session_id)
+                .bind(&user_message_id)
+                .execute(pool)
+                .await
+                .map_err(|err| err.to_string())?;
+                continue;
+            };
+            let reason = if dispatched {
+                "turn_interrupted"
+            } else {
+                "turn_rolled_back"
+            };
+            let mut envelopes = Vec::new();
+            if let Some(info) = aborted {
+                envelopes.push(json!({
+                    "jsonrpc": "2.0",
+                    "method": "_sandboxagent/opencode/message",
+                    "params": {"message": {"info": info, "parts": []}}
+                }));
+            }
+            envelopes.push(json!({
+                "jsonrpc": "2.0",
+                "method": "_sandboxagent/opencode/status",
+                "params": {
+                    "status": SessionLifecycle::Idle.status_type(),
+                    "state": SessionLifecycle::Idle,
+                    "reason": reason,
+                }
+            }));
+            let mut event_rows = Vec::with_capacity(envelopes.len());
+            for envelope in &envelopes {
+                event_rows.push(self.event_row(&session_id, "agent", envelope).await);
             }
-        });
-        self.persist_event(session_id, "agent", &new_response)
-            .await?;
 
-        let mut updated_meta = None;
-        {
-            let mut projection = self.projection.lock().await;
-            if let Some(session) = projection.sessions.get_mut(session_id) {
-                session.meta.agent_session_id = new_agent_session_id;
-                session.meta.last_connection_id = current;
-                session.meta.destroyed_at = None;
-                updated_meta = Some(session.meta.clone());
+            let mut tx = pool.begin().await.map_err(|err| err.to_string())?;
+            if !dispatched {
+                for statement in [
+                    "DELETE FROM events WHERE id = ?2 AND session_id = ?1",
+                    "DELETE FROM event_dedupe WHERE event_id = ?2 AND session_id = ?1",
+                ] {
+                    sqlx::query(statement)
+                        .bind(&session_id)
+                        .bind(&prompt_event_id)
+                        .execute(&mut *tx)
+                        .await
+                        .map_err(|err| err.to_string())?;
+                }
+                sqlx::query(
+                    "DELETE FROM session_turns WHERE session_id = ?1 AND user_message_id = ?2",
+                )
+                .bind(&session_id)
+                .bind(&user_message_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|err| err.to_string())?;
+            }
+            let mut inserted = Vec::with_capacity(event_rows.len());
+            for event_row in &event_rows {
+                inserted.push(insert_event(&mut tx, event_row).await?);
+            }
+            sqlx::query("DELETE FROM turn_journal WHERE session_id = ?1 AND user_message_id = ?2")
+                .bind(&session_id)
+                .bind(&user_message_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|err| err.to_string())?;
+            tx.commit().await.map_err(|err| err.to_string())?;
+
+            if !dispatched {
+                let mut projection = self.projection.lock().await;
+                if let Some(session) = projection.sessions.get_mut(&session_id) {
+                    session.messages.retain(|message| {
+                        message.info.get("id").and_then(Value::as_str)
+                            != Some(user_message_id.as_str())
+                    });
+                }
+            }
+            for (event_row, inserted) in event_rows.iter().zip(inserted) {
+                if inserted {
+                    self.event_committed(event_row).await;
+                }
             }
+            warn!(
+                session_id,
+                user_message_id, reason, "recovered a turn left open by a previous run"
+            );
         }
-        if let Some(meta) = updated_meta {
-            self.persist_session(&meta).await?;
+        Ok(())
+    }
+
+    /// Apply `update` to the timeline of the turn running in `session_id`;
+    /// `None` if no turn is running.
+    async fn update_turn_timeline<R>(
+        &self,
+        session_id: &str,
+        update: impl FnOnce(&mut ToolTimeline) -> R,
+    ) -> Option<R> {
+        let mut turns = self.turns.lock().await;
+        let (_, record) = turns.iter_mut().rev().find(|(_, record)| {
+            record.session_id == session_id && record.status == TurnStatus::Running
+        })?;
+        Some(update(&mut record.timeline))
+    }
+
+    /// Record how the running turn of `session_id` forwarded its `system`
+    /// override.
+    async fn record_turn_system_prompt(&self, session_id: &str, mechanism: SystemPromptMechanism) {
+        let mut turns = self.turns.lock().await;
+        if let Some((_, record)) = turns.iter_mut().rev().find(|(_, record)| {
+            record.session_id == session_id && record.status == TurnStatus::Running
+        }) {
+            record.system_prompt = Some(mechanism);
         }
+    }
 
-        if let Some(text) = replay_text {
-            self.pending_replay
-                .lock()
-                .await
-                .insert(session_id.to_string(), text);
+    async fn record_turn_reasoning(&self, session_id: &str, reasoning: ReasoningBudget) {
+        let mut turns = self.turns.lock().await;
+        if let Some((_, record)) = turns.iter_mut().rev().find(|(_, record)| {
+            record.session_id == session_id && record.status == TurnStatus::Running
+        }) {
+            record.reasoning = Some(reasoning);
         }
+    }
 
-        Ok(())
+    /// The tool part of `call_id` in `message_id` as last recorded.
+    async fn recorded_tool_part(
+        &self,
+        session_id: &str,
+        message_id: &str,
+        call_id: &str,
+    ) -> Option<Value> {
+        let projection = self.projection.lock().await;
+        let message = projection
+            .sessions
+            .get(session_id)?
+            .messages
+            .iter()
+            .find(|message| message.info.get("id").and_then(Value::as_str) == Some(message_id))?;
+        message
+            .parts
+            .iter()
+            .find(|part| {
+                part.get("type").and_then(Value::as_str) == Some("tool")
+                    && part.get("callID").and_then(Value::as_str) == Some(call_id)
+            })
+            .cloned()
     }
 
-    async fn ensure_session(
+    /// Reflect `parts` of the message `info` in the projection without
+    /// persisting them, so the `message.part.updated` events emitted next
+    /// are never ahead of `GET /session/:id/message`. `info` may be just
+    /// `{ id }` for a message the projection already holds.
+    async fn project_parts(&self, session_id: &str, info: &Value, parts: &[Value]) {
+        let mut projection = self.projection.lock().await;
+        if let Some(session) = projection.sessions.get_mut(session_id) {
+            upsert_message(session, info.clone(), parts.to_vec());
+        }
+    }
+
+    /// Persist `parts` of the message `info` as the agent's. They are
+    /// projected even when the write fails, since the caller emits them
+    /// next either way.
+    async fn persist_parts(
         &self,
         session_id: &str,
-        directory: String,
-    ) -> Result<SessionMeta, String> {
-        {
-            let projection = self.projection.lock().await;
-            if let Some(existing) = projection.sessions.get(session_id) {
-                return Ok(existing.meta.clone());
+        info: &Value,
+        parts: &[Value],
+    ) -> Result<(), String> {
+        let env = json!({
+            "jsonrpc":"2.0",
+            "method":"_sandboxagent/opencode/message",
+            "params":{"message":{"info": info, "parts": parts}}
+        });
+        let persisted = self.persist_event(session_id, "agent", &env).await;
+        if persisted.is_err() {
+            self.project_parts(session_id, info, parts).await;
+        }
+        persisted
+    }
+
+    /// Publish the streaming speed of the reply to the latest started turn of
+    /// `session_id`. The reply may finish after the turn was marked done.
+    async fn record_turn_stream_stats(&self, session_id: &str, stats: StreamSnapshot) {
+        let mut turns = self.turns.lock().await;
+        if let Some((_, record)) = turns.iter_mut().rev().find(|(_, record)| {
+            record.session_id == session_id && record.status != TurnStatus::Queued
+        }) {
+            record.stream_stats = Some(stats);
+        }
+    }
+
+    /// Record the files a tool call of the running turn of `session_id` names.
+    async fn record_turn_tool_paths(&self, session_id: &str, call_id: &str, paths: Vec<String>) {
+        let mut turns = self.turns.lock().await;
+        if let Some((_, record)) = turns.iter_mut().rev().find(|(_, record)| {
+            record.session_id == session_id && record.status == TurnStatus::Running
+        }) {
+            for path in paths {
+                record.tool_paths.insert(path, call_id.to_string());
             }
         }
+    }
 
-        let now = now_ms();
-        let connection_id = self.current_connection_for_agent("mock").await;
-        let meta = SessionMeta {
-            id: session_id.to_string(),
-            slug: format!("session-{session_id}"),
-            project_id: self.project_id.clone(),
-            directory,
-            parent_id: None,
-            title: format!("Session {session_id}"),
-            version: "0".to_string(),
-            created_at: now,
-            updated_at: now,
-            share_url: None,
-            permission_mode: None,
-            agent: "mock".to_string(),
-            provider_id: "mock".to_string(),
-            model_id: "mock".to_string(),
-            agent_session_id: format!("acp_{}", self.next_id("ses_")),
-            last_connection_id: connection_id,
-            session_init_json: Some(json!({"cwd": "/", "mcpServers": []})),
-            destroyed_at: None,
+    async fn persist_turn_artifacts(&self, turn_id: &str, listing: &Value) -> Result<(), String> {
+        let pool = self.pool().await?;
+        sqlx::query(
+            r#"INSERT OR REPLACE INTO turn_artifacts (turn_id, session_id, artifacts_json, created_at)
+               VALUES (?1, ?2, ?3, ?4)"#,
+        )
+        .bind(turn_id)
+        .bind(listing["sessionID"].as_str().unwrap_or_default())
+        .bind(listing.to_string())
+        .bind(self.now_ms())
+        .execute(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    async fn load_turn_artifacts(
+        &self,
+        session_id: &str,
+        turn_id: &str,
+    ) -> Result<Option<Value>, String> {
+        let pool = self.pool().await?;
+        let row = sqlx::query(
+            "SELECT artifacts_json FROM turn_artifacts WHERE turn_id = ?1 AND session_id = ?2",
+        )
+        .bind(turn_id)
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let raw: String = row
+            .try_get("artifacts_json")
+            .map_err(|err| err.to_string())?;
+        serde_json::from_str(&raw)
+            .map(Some)
+            .map_err(|err| err.to_string())
+    }
+
+    /// Keep a finished turn's timeline once the turn is no longer tracked.
+    async fn persist_turn_timeline(&self, turn_id: &str, timeline: &Value) -> Result<(), String> {
+        let pool = self.pool().await?;
+        sqlx::query(
+            r#"INSERT OR REPLACE INTO turn_timelines (turn_id, session_id, timeline_json, created_at)
+               VALUES (?1, ?2, ?3, ?4)"#,
+        )
+        .bind(turn_id)
+        .bind(timeline["sessionID"].as_str().unwrap_or_default())
+        .bind(timeline.to_string())
+        .bind(timeline["time"]["start"].as_i64().unwrap_or_default())
+        .execute(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    async fn load_turn_timeline(
+        &self,
+        session_id: &str,
+        turn_id: &str,
+    ) -> Result<Option<Value>, String> {
+        let pool = self.pool().await?;
+        let row = sqlx::query(
+            "SELECT timeline_json FROM turn_timelines WHERE turn_id = ?1 AND session_id = ?2",
+        )
+        .bind(turn_id)
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+        let Some(row) = row else {
+            return Ok(None);
         };
+        let raw: String = row
+            .try_get("timeline_json")
+            .map_err(|err| err.to_string())?;
+        serde_json::from_str(&raw)
+            .map(Some)
+            .map_err(|err| err.to_string())
+    }
 
-        self.persist_session(&meta).await?;
+    /// Attach the assistant message a turn produced to its user message.
+    async fn record_turn_assistant(
+        &self,
+        session_id: &str,
+        user_message_id: &str,
+        assistant_message_id: &str,
+    ) -> Result<(), String> {
+        let pool = self.pool().await?;
+        sqlx::query(
+            r#"UPDATE session_turns SET assistant_message_id = ?3
+               WHERE session_id = ?1 AND user_message_id = ?2"#,
+        )
+        .bind(session_id)
+        .bind(user_message_id)
+        .bind(assistant_message_id)
+        .execute(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    async fn rebuild_projection(&self) -> Result<(), String> {
+        let mut projection = Projection::default();
+        let pool = self.pool().await?;
+
+        let rows = sqlx::query(
+            r#"SELECT s.id, s.agent, s.agent_session_id, s.last_connection_id, s.created_at, s.destroyed_at, s.session_init_json,
+                      m.metadata_json
+               FROM sessions s
+               JOIN opencode_session_metadata m ON m.session_id = s.id
+               ORDER BY s.created_at ASC, s.id ASC"#,
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+
+        for row in rows {
+            let id: String = row.try_get("id").map_err(|err| err.to_string())?;
+            let agent: String = row.try_get("agent").map_err(|err| err.to_string())?;
+            let agent_session_id: String = row
+                .try_get("agent_session_id")
+                .map_err(|err| err.to_string())?;
+            let last_connection_id: String = row
+                .try_get("last_connection_id")
+                .map_err(|err| err.to_string())?;
+            let created_at: i64 = row.try_get("created_at").map_err(|err| err.to_string())?;
+            let destroyed_at: Option<i64> =
+                row.try_get("destroyed_at").map_err(|err| err.to_string())?;
+            let session_init_json: Option<String> = row
+                .try_get("session_init_json")
+                .map_err(|err| err.to_string())?;
+            let metadata_json: String = row
+                .try_get("metadata_json")
+                .map_err(|err| err.to_string())?;
+
+            let mut meta: SessionMeta =
+                serde_json::from_str(&metadata_json).map_err(|err| err.to_string())?;
+            meta.id = id.clone();
+            meta.agent = agent;
+            meta.agent_session_id = agent_session_id;
+            meta.last_connection_id = last_connection_id;
+            meta.created_at = created_at;
+            meta.destroyed_at = destroyed_at;
+            meta.session_init_json = session_init_json
+                .as_deref()
+                .and_then(|raw| serde_json::from_str(raw).ok());
+            // Older rows recorded `cwd: "/"` regardless of the session directory.
+            if let Some(init) = meta
+                .session_init_json
+                .as_mut()
+                .filter(|init| init.is_object())
+            {
+                init["cwd"] = json!(meta.directory);
+            }
+            self.track_session_secrets(&meta);
 
-        let session_value = session_to_value(&meta);
-        {
-            let mut projection = self.projection.lock().await;
             projection.sessions.insert(
-                session_id.to_string(),
+                id,
                 SessionState {
-                    meta: meta.clone(),
+                    meta,
                     messages: Vec::new(),
-                    status: "idle".to_string(),
-                    always_permissions: HashSet::new(),
+                    lifecycle: SessionLifecycle::Created,
+                    always_rules: Vec::new(),
+                    pending_context: Vec::new(),
+                    archive_key: None,
                 },
             );
         }
 
-        self.emit_event(json!({
-            "type": "session.created",
-            "properties": { "info": session_value }
-        }));
+        let archive_rows = sqlx::query("SELECT session_id, object_key FROM session_archives")
+            .fetch_all(pool)
+            .await
+            .map_err(|err| err.to_string())?;
+        for row in archive_rows {
+            let session_id: String = row.try_get("session_id").map_err(|err| err.to_string())?;
+            let object_key: String = row.try_get("object_key").map_err(|err| err.to_string())?;
+            if let Some(session) = projection.sessions.get_mut(&session_id) {
+                session.archive_key = Some(object_key);
+                session.lifecycle = SessionLifecycle::Idle;
+            }
+        }
 
-        Ok(meta)
-    }
-}
+        let event_rows = sqlx::query(
+            r#"SELECT session_id, sender, payload_json
+               FROM events
+               ORDER BY created_at ASC, id ASC"#,
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|err| err.to_string())?;
 
-pub fn build_opencode_router(config: OpenCodeAdapterConfig) -> Result<Router, String> {
-    let proxy_base_url = config
-        .native_proxy_base_url
-        .clone()
-        .or_else(|| std::env::var("OPENCODE_COMPAT_PROXY_URL").ok())
-        .and_then(normalize_proxy_base_url);
-    let config = OpenCodeAdapterConfig {
-        native_proxy_base_url: proxy_base_url,
-        ..config
-    };
+        // Rows written before dedupe keys existed may repeat an envelope.
+        let mut dedupe = ReplayDedupe::default();
+        for row in event_rows {
+            let session_id: String = row.try_get("session_id").map_err(|err| err.to_string())?;
+            let sender: String = row.try_get("sender").map_err(|err| err.to_string())?;
+            let payload_json: String =
+                row.try_get("payload_json").map_err(|err| err.to_string())?;
+            let payload: Value =
+                serde_json::from_str(&payload_json).map_err(|err| err.to_string())?;
+            if dedupe.admit(&session_id, &sender, &payload) {
+                apply_envelope(&mut projection, &session_id, &sender, &payload);
+            }
+        }
 
-    let sqlite_path = config
-        .sqlite_path
-        .clone()
-        .or_else(|| std::env::var("OPENCODE_COMPAT_DB_PATH").ok())
-        .or_else(|| {
-            std::env::var("OPENCODE_COMPAT_STATE")
-                .ok()
-                .map(|base| format!("{base}/opencode-sessions.db"))
-        })
-        .unwrap_or_else(|| "/tmp/sandbox-agent-opencode.db".to_string());
+        let mut guard = self.projection.lock().await;
+        *guard = projection;
+        Ok(())
+    }
 
-    let connect = SqliteConnectOptions::from_str(&format!("sqlite://{sqlite_path}"))
-        .map_err(|err| err.to_string())?
-        .create_if_missing(true)
-        .journal_mode(SqliteJournalMode::Wal)
-        .foreign_keys(true);
+    fn emit_event(&self, mut payload: Value) {
+        self.stamp_part_seq(&mut payload);
+        if let Ok(Some(backend)) = EVENT_BACKEND.try_with(Clone::clone) {
+            if let Some(properties) = payload.get_mut("properties").and_then(Value::as_object_mut) {
+                properties.entry("backend").or_insert(json!(backend));
+            }
+        }
+        self.label_session_kind(&mut payload);
+        self.mask_session_secrets(&mut payload);
+        if let Some(observer) = self.config.event_observer.as_ref() {
+            observer.observe(&payload);
+        }
+        let native = NATIVE_PAYLOAD
+            .try_with(|slot| slot.lock().ok().and_then(|slot| slot.clone()))
+            .ok()
+            .flatten();
+        let event = OpenCodeStreamEvent {
+            id: self.next_event_id.fetch_add(1, Ordering::Relaxed),
+            payload,
+            native,
+        };
 
-    let (event_broadcaster, _) = broadcast::channel(EVENT_CHANNEL_SIZE);
+        if self.event_consumers_active.load(Ordering::Relaxed) {
+            if let Some(writer) = self.event_log_writer.get() {
+                let _ = writer.send(LoggedEvent {
+                    id: event.id,
+                    payload: event.payload.clone(),
+                    created_at: self.now_ms(),
+                });
+            }
+        }
 
-    let state = Arc::new(AdapterState {
-        config,
-        sqlite_path,
-        sqlite_connect_options: connect,
-        proxy_http_client: reqwest::Client::builder()
-            .timeout(Duration::from_secs(10))
-            .build()
-            .unwrap_or_else(|_| reqwest::Client::new()),
-        pool: OnceCell::new(),
-        initialized: OnceCell::new(),
-        project_id: format!("proj_{}", now_ms()),
-        projection: Mutex::new(Projection::default()),
-        pending_replay: Mutex::new(HashMap::new()),
-        agent_connections: Mutex::new(HashMap::new()),
-        event_broadcaster,
-        event_log: StdMutex::new(VecDeque::new()),
-        next_event_id: AtomicU64::new(1),
-        next_id: AtomicU64::new(runtime_unique_seed()),
-        acp_initialized: Mutex::new(HashMap::new()),
-        acp_request_ids: Mutex::new(HashMap::new()),
-        last_user_message_id: Mutex::new(HashMap::new()),
-    });
+        if let Ok(mut guard) = self.event_log.lock() {
+            guard.push_back(event.clone());
+            while guard.len() > EVENT_LOG_SIZE {
+                guard.pop_front();
+            }
+        }
 
-    let mut router = Router::new()
-        .route("/agent", get(oc_agent_list))
-        .route("/command", get(oc_command_list))
-        .route("/config", get(oc_config_get).patch(oc_config_patch))
-        .route("/config/providers", get(oc_config_providers))
-        .route("/event", get(oc_event_subscribe))
-        .route("/global/event", get(oc_global_event))
-        .route("/global/health", get(oc_global_health))
-        .route(
-            "/global/config",
-            get(oc_global_config_get).patch(oc_global_config_patch),
-        )
-        .route("/global/dispose", post(oc_global_dispose))
-        .route("/instance/dispose", post(oc_instance_dispose))
-        .route("/path", get(oc_path))
-        .route("/vcs", get(oc_vcs))
-        .route("/mcp", get(oc_mcp_status))
-        .route("/lsp", get(oc_lsp_status))
-        .route("/formatter", get(oc_formatter_status))
-        .route("/experimental/resource", get(oc_experimental_resource))
-        .route("/skill", get(oc_skill_list))
-        .route("/tui/control/next", get(oc_tui_next))
-        .route("/tui/control/response", post(oc_tui_response))
-        .route("/tui/append-prompt", post(oc_tui_append_prompt))
-        .route("/tui/open-help", post(oc_tui_open_help))
-        .route("/tui/open-sessions", post(oc_tui_open_sessions))
-        .route("/tui/open-themes", post(oc_tui_open_themes))
-        .route("/tui/open-models", post(oc_tui_open_models))
-        .route("/tui/submit-prompt", post(oc_tui_submit_prompt))
-        .route("/tui/clear-prompt", post(oc_tui_clear_prompt))
-        .route("/tui/execute-command", post(oc_tui_execute_command))
-        .route("/tui/show-toast", post(oc_tui_show_toast))
-        .route("/tui/publish", post(oc_tui_publish))
-        .route("/project", get(oc_project_list).post(oc_project_current))
-        .route("/project/current", get(oc_project_current))
-        .route("/session", post(oc_session_create).get(oc_session_list))
-        .route("/session/status", get(oc_session_status))
-        .route(
-            "/session/:sessionID",
-            get(oc_session_get)
-                .patch(oc_session_update)
-                .delete(oc_session_delete),
-        )
-        .route("/session/:sessionID/abort", post(oc_session_abort))
-        .route("/session/:sessionID/children", get(oc_session_children))
-        .route("/session/:sessionID/init", post(oc_session_init))
-        .route("/session/:sessionID/fork", post(oc_session_fork))
-        .route("/session/:sessionID/diff", get(oc_session_diff))
-        .route("/session/:sessionID/todo", get(oc_session_todo))
-        .route("/session/:sessionID/summarize", post(oc_session_summarize))
-        .route(
-            "/session/:sessionID/message",
-            get(oc_session_messages).post(oc_session_prompt),
-        )
-        .route(
-            "/session/:sessionID/message/:messageID",
-            get(oc_session_message_get),
-        )
-        .route(
-            "/session/:sessionID/message/:messageID/part/:partID",
-            patch(oc_part_update).delete(oc_part_delete),
-        )
-        .route(
-            "/session/:sessionID/prompt_async",
-            post(oc_session_prompt_async),
-        )
-        .route(
-            "/session/:sessionID/permissions/:permissionID",
-            post(oc_permission_respond),
-        )
-        .route("/permission", get(oc_permission_list))
-        .route("/permission/:requestID/reply", post(oc_permission_reply))
-        .route("/question", get(oc_question_list))
-        .route("/question/:requestID/reply", post(oc_question_reply))
-        .route("/question/:requestID/reject", post(oc_question_reject))
-        .route("/provider", get(oc_provider_list))
-        .route("/provider/auth", get(oc_provider_auth))
-        .route(
-            "/provider/:providerID/oauth/authorize",
-            post(oc_provider_oauth_authorize),
-        )
-        .route(
-            "/provider/:providerID/oauth/callback",
-            post(oc_provider_oauth_callback),
-        )
-        .with_state(state.clone());
+        let _ = self.event_broadcaster.send(event);
+    }
 
-    if state.config.auth_token.is_some() {
-        router = router.layer(axum::middleware::from_fn_with_state(state, require_token));
+    /// An agent payload as attached to the events translated from it: masked
+    /// like events are, and replaced by `{ truncated, bytes }` when it is
+    /// over the configured cap. `None` when native events are disabled or
+    /// no subscriber asked for them.
+    fn native_payload(&self, payload: &Value) -> Option<Arc<Value>> {
+        let max_bytes = self.config.native_event_max_bytes;
+        if max_bytes == 0 || self.native_subscribers.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let mut native = payload.clone();
+        self.mask_session_secrets(&mut native);
+        let bytes = serde_json::to_vec(&native).map_or(0, |encoded| encoded.len());
+        if bytes > max_bytes {
+            native = json!({"truncated": true, "bytes": bytes});
+        }
+        Some(Arc::new(native))
     }
 
-    Ok(router)
-}
+    /// Mask a tool part's `state.output` and hold it to
+    /// `part_output_max_bytes`: a longer output is stored whole in
+    /// `part_blobs` and replaced by its head and a truncation marker, with
+    /// `state.metadata.truncated` describing the cut. Other parts are left
+    /// alone.
+    async fn cap_part_output(&self, session_id: &str, part: &mut Value) {
+        if part.get("type").and_then(Value::as_str) != Some("tool") {
+            return;
+        }
+        let Some(part_id) = part.get("id").and_then(Value::as_str).map(str::to_owned) else {
+            return;
+        };
+        let limit = self.config.part_output_max_bytes;
+        let Some(output) = part.pointer_mut("/state/output") else {
+            return;
+        };
+        self.mask_session_secrets(output);
+        let Some(text) = output.as_str() else {
+            return;
+        };
+        let Some(truncated) = part_output::truncate_output(text, &part_id, limit) else {
+            return;
+        };
+        if let Err(err) = self.store_part_blob(session_id, &part_id, text).await {
+            warn!(?err, part_id, "failed to store full tool output");
+        }
+        *output = json!(truncated.output);
+        part["state"]["metadata"]["truncated"] = truncated.metadata(&part_id, limit);
+    }
 
-async fn require_token(
-    State(state): State<Arc<AdapterState>>,
-    request: Request<Body>,
-    next: Next,
-) -> Result<Response, Response> {
-    let Some(expected) = state.config.auth_token.as_deref() else {
-        return Ok(next.run(request).await);
-    };
+    /// A message envelope with its tool parts capped, or `None` when none of
+    /// them is over the cap.
+    async fn cap_envelope_parts(&self, session_id: &str, payload: &Value) -> Option<Value> {
+        let limit = self.config.part_output_max_bytes;
+        let parts = payload.pointer("/params/message/parts")?.as_array()?;
+        let over_cap = |part: &Value| {
+            part.pointer("/state/output")
+                .and_then(Value::as_str)
+                .is_some_and(|output| output.len() > limit)
+        };
+        if limit == 0 || !parts.iter().any(over_cap) {
+            return None;
+        }
+        let mut capped = payload.clone();
+        if let Some(parts) = capped
+            .pointer_mut("/params/message/parts")
+            .and_then(Value::as_array_mut)
+        {
+            for part in parts {
+                self.cap_part_output(session_id, part).await;
+            }
+        }
+        Some(capped)
+    }
 
-    let bearer = request
-        .headers()
-        .get(header::AUTHORIZATION)
-        .and_then(|value| value.to_str().ok())
-        .and_then(|value| value.strip_prefix("Bearer "));
+    async fn store_part_blob(
+        &self,
+        session_id: &str,
+        part_id: &str,
+        content: &str,
+    ) -> Result<(), String> {
+        let pool = self.pool().await?;
+        sqlx::query(
+            r#"INSERT OR REPLACE INTO part_blobs (part_id, session_id, content, bytes, created_at)
+               VALUES (?1, ?2, ?3, ?4, ?5)"#,
+        )
+        .bind(part_id)
+        .bind(session_id)
+        .bind(content)
+        .bind(content.len() as i64)
+        .bind(self.now_ms())
+        .execute(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+        Ok(())
+    }
 
-    if bearer == Some(expected) {
-        return Ok(next.run(request).await);
+    /// The untruncated output of a capped part.
+    async fn load_part_blob(&self, part_id: &str) -> Result<Option<String>, String> {
+        let pool = self.pool().await?;
+        sqlx::query_scalar::<_, String>("SELECT content FROM part_blobs WHERE part_id = ?1")
+            .bind(part_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    /// Compare a request body with the schema generated from `T`. A mismatch
+    /// is logged and counted as schema drift; in strict mode it is rejected
+    /// with a 400 problem response listing each problem, which is returned.
+    fn check_request_body<T: JsonSchema>(&self, body: &Value) -> Option<Response> {
+        let schema = match self.request_schemas.lock() {
+            Ok(mut schemas) => schemas
+                .entry(std::any::type_name::<T>())
+                .or_insert_with(|| Arc::new(request_schema::schema_of::<T>()))
+                .clone(),
+            Err(_) => Arc::new(request_schema::schema_of::<T>()),
+        };
+        let problems = request_schema::validate(&schema, body);
+        if problems.is_empty() {
+            return None;
+        }
+        let body_name = T::schema_name();
+        warn!(
+            body = body_name,
+            ?problems,
+            strict = self.config.strict_schemas,
+            "request body does not match its schema"
+        );
+        if let Some(metrics) = self.config.metrics.as_ref() {
+            metrics.schema_drift(&body_name, problems.len());
+        }
+        if !self.config.strict_schemas {
+            return None;
+        }
+        let mut problem = ProblemDetails::new(
+            ErrorType::InvalidRequest,
+            Some(format!(
+                "request body does not match the {body_name} schema"
+            )),
+        );
+        problem
+            .extensions
+            .insert("errors".to_string(), json!(problems));
+        Some(
+            (
+                StatusCode::BAD_REQUEST,
+                [(header::CONTENT_TYPE, "application/problem+json")],
+                Json(problem),
+            )
+                .into_response(),
+        )
     }
 
-    Err((
-        StatusCode::UNAUTHORIZED,
-        Json(json!({"errors":[{"message":"missing or invalid bearer token"}]})),
-    )
-        .into_response())
-}
+    fn track_session_secrets(&self, meta: &SessionMeta) {
+        let secrets = session_env::secret_values(&meta.env);
+        if let Ok(mut tracked) = self.session_secrets.lock() {
+            if secrets.is_empty() {
+                tracked.remove(&meta.id);
+            } else {
+                tracked.insert(meta.id.clone(), secrets);
+            }
+        }
+        if meta.kind == SessionKind::System {
+            if let Ok(mut system) = self.system_sessions.lock() {
+                system.insert(meta.id.clone());
+            }
+        }
+    }
 
-#[derive(Debug, Deserialize)]
-struct DirectoryQuery {
-    directory: Option<String>,
-}
+    /// Label events of `system` sessions with `properties.sessionKind`.
+    fn label_session_kind(&self, payload: &mut Value) {
+        let event_type = payload
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let Some(properties) = payload.get_mut("properties").and_then(Value::as_object_mut) else {
+            return;
+        };
+        let Ok(system) = self.system_sessions.lock() else {
+            return;
+        };
+        if system.is_empty() {
+            return;
+        }
+        if event_session(&event_type, properties).is_some_and(|id| system.contains(&id)) {
+            properties.insert(
+                "sessionKind".to_string(),
+                json!(SessionKind::System.as_str()),
+            );
+        }
+    }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct SessionCreateBody {
-    title: Option<String>,
-    #[serde(rename = "parentID")]
-    parent_id: Option<String>,
-    permission: Option<Value>,
-    #[serde(alias = "permission_mode")]
-    permission_mode: Option<String>,
-}
+    /// Secrets are masked wherever they appear, not only in events of the
+    /// session that set them.
+    fn mask_session_secrets(&self, payload: &mut Value) {
+        let secrets: Vec<String> = match self.session_secrets.lock() {
+            Ok(tracked) if !tracked.is_empty() => tracked.values().flatten().cloned().collect(),
+            _ => return,
+        };
+        session_env::mask_secrets(payload, &secrets);
+    }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct SessionUpdateBody {
-    title: Option<String>,
-    model: Option<Value>,
-    #[serde(rename = "providerID", alias = "provider_id", alias = "providerId")]
-    provider_id: Option<String>,
-    #[serde(rename = "modelID", alias = "model_id", alias = "modelId")]
-    model_id: Option<String>,
-}
+    /// Give `message.part.updated` a per-session `seq` that increases by one
+    /// per event, so a client can tell it missed updates for a session.
+    fn stamp_part_seq(&self, payload: &mut Value) {
+        if payload.get("type").and_then(Value::as_str) != Some("message.part.updated") {
+            return;
+        }
+        let Some(properties) = payload.get_mut("properties").and_then(Value::as_object_mut) else {
+            return;
+        };
+        let Some(session_id) = part_event_session(properties) else {
+            return;
+        };
+        let Ok(mut counters) = self.part_seq.lock() else {
+            return;
+        };
+        let seq = counters.entry(session_id).or_insert(0);
+        *seq += 1;
+        properties.insert("seq".to_string(), json!(*seq));
+    }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct SessionInitBody {
-    #[serde(rename = "providerID")]
-    provider_id: Option<String>,
-    #[serde(rename = "modelID")]
-    model_id: Option<String>,
-    #[serde(rename = "messageID")]
-    message_id: Option<String>,
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct PromptBody {
-    #[serde(rename = "messageID")]
-    message_id: Option<String>,
-    model: Option<ModelSelection>,
-    #[serde(rename = "providerID", alias = "provider_id", alias = "providerId")]
-    provider_id: Option<String>,
-    #[serde(rename = "modelID", alias = "model_id", alias = "modelId")]
-    model_id: Option<String>,
-    agent: Option<String>,
-    system: Option<String>,
-    variant: Option<String>,
-    parts: Option<Vec<Value>>,
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct ModelSelection {
-    #[serde(rename = "providerID", alias = "provider_id", alias = "providerId")]
-    provider_id: Option<String>,
-    #[serde(rename = "modelID", alias = "model_id", alias = "modelId")]
-    model_id: Option<String>,
-}
+    /// Events evicted from the replay log between `last_event_id` and the
+    /// oldest buffered event; these can no longer be replayed.
+    fn evicted_events_after(&self, last_event_id: Option<u64>) -> u64 {
+        let Some(last_event_id) = last_event_id else {
+            return 0;
+        };
+        let Ok(guard) = self.event_log.lock() else {
+            return 0;
+        };
+        guard
+            .front()
+            .map_or(0, |oldest| oldest.id.saturating_sub(last_event_id + 1))
+    }
 
-#[derive(Debug, Deserialize)]
-struct PermissionRespondBody {
-    response: Option<String>,
-}
+    fn buffered_events_after(&self, last_event_id: Option<u64>) -> Vec<OpenCodeStreamEvent> {
+        let Some(last_event_id) = last_event_id else {
+            return Vec::new();
+        };
+        let Ok(guard) = self.event_log.lock() else {
+            return Vec::new();
+        };
+        guard
+            .iter()
+            .filter(|entry| entry.id > last_event_id)
+            .cloned()
+            .collect()
+    }
 
-#[derive(Debug, Deserialize)]
-struct PermissionReplyBody {
-    reply: Option<String>,
-    message: Option<String>,
-}
+    /// Start writing emitted events to the persisted log once a consumer is
+    /// registered, and continue event IDs after the last one recorded.
+    async fn start_event_log(&self) -> Result<(), String> {
+        let pool = self.pool().await?;
+        let (active, last_id) = event_consumers::load_state(pool)
+            .await
+            .map_err(|err| err.to_string())?;
+        self.next_event_id.fetch_max(last_id + 1, Ordering::Relaxed);
+        self.event_log_writer
+            .get_or_init(|| event_consumers::spawn_writer(pool.clone()));
+        self.event_consumers_active.store(active, Ordering::Relaxed);
+        Ok(())
+    }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct QuestionReplyBody {
-    answers: Option<Vec<Vec<String>>>,
-}
+    /// The first page of events a consumer has not acknowledged, from the
+    /// persisted log and the replay buffer, and how many of them are no
+    /// longer available. A new consumer is registered at the newest event.
+    async fn consumer_events(
+        &self,
+        consumer: &str,
+    ) -> Result<(Vec<OpenCodeStreamEvent>, u64), String> {
+        let pool = self.pool().await?;
+        self.event_consumers_active.store(true, Ordering::Relaxed);
+        let acked = event_consumers::register(pool, consumer, self.last_event_id(), self.now_ms())
+            .await
+            .map_err(|err| err.to_string())?;
+        self.logged_events_after(acked, CONSUMER_REPLAY_PAGE).await
+    }
 
-async fn oc_agent_list(State(state): State<Arc<AdapterState>>) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+    /// Up to `limit` events after `after`, from the persisted log and the
+    /// replay buffer, and how many events between `after` and the first one
+    /// are no longer available.
+    async fn logged_events_after(
+        &self,
+        after: u64,
+        limit: usize,
+    ) -> Result<(Vec<OpenCodeStreamEvent>, u64), String> {
+        let pool = self.pool().await?;
+        let mut events: BTreeMap<u64, OpenCodeStreamEvent> =
+            event_consumers::events_after(pool, after, limit)
+                .await
+                .map_err(|err| err.to_string())?
+                .into_iter()
+                .map(|(id, payload)| {
+                    (
+                        id,
+                        OpenCodeStreamEvent {
+                            id,
+                            payload,
+                            native: None,
+                        },
+                    )
+                })
+                .collect();
+        // The buffer holds events the writer has not persisted yet, and
+        // agent payloads the log does not keep.
+        for event in self.buffered_events_after(Some(after)) {
+            events.insert(event.id, event);
+        }
+        let missed = events
+            .keys()
+            .next()
+            .map_or(0, |first| first.saturating_sub(after + 1));
+        Ok((events.into_values().take(limit).collect(), missed))
+    }
+
+    /// Whether an `include=` list asks for agent payloads and the adapter
+    /// attaches them; agent payloads are attached to events for as long as
+    /// the returned subscription is held.
+    fn native_subscription(&self, include: Option<&str>) -> Option<NativeSubscription> {
+        let wanted = self.config.native_event_max_bytes > 0
+            && include
+                .is_some_and(|include| include.split(',').any(|item| item.trim() == "native"));
+        wanted.then(|| {
+            self.native_subscribers.fetch_add(1, Ordering::Relaxed);
+            NativeSubscription(self.native_subscribers.clone())
+        })
     }
-    (
-        StatusCode::OK,
-        Json(json!([
-            {
-                "name": "Sandbox Agent",
-                "description": "Sandbox Agent compatibility layer",
-                "mode": "all",
-                "native": false,
-                "hidden": false,
-                "permission": [],
-                "options": {},
-            }
-        ])),
-    )
-        .into_response()
-}
 
-async fn oc_command_list(State(state): State<Arc<AdapterState>>, headers: HeaderMap) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+    fn last_event_id(&self) -> u64 {
+        self.next_event_id.load(Ordering::Relaxed).saturating_sub(1)
     }
-    if let Some(response) =
-        proxy_native_opencode(&state, reqwest::Method::GET, "/command", &headers, None).await
-    {
-        return response;
+
+    fn subscribe(&self) -> broadcast::Receiver<OpenCodeStreamEvent> {
+        self.event_broadcaster.subscribe()
     }
-    (StatusCode::OK, Json(json!([]))).into_response()
-}
 
-async fn oc_config_get(State(state): State<Arc<AdapterState>>, headers: HeaderMap) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+    fn next_id(&self, prefix: &str) -> String {
+        self.config.id_generator.next_id(prefix)
     }
-    if let Some(response) =
-        proxy_native_opencode(&state, reqwest::Method::GET, "/config", &headers, None).await
-    {
-        return response;
+
+    /// ID of a request the agent made with JSON-RPC ID `jsonrpc_id`. It is
+    /// derived from the agent instance and that ID, so the request replayed
+    /// to a restarted adapter keeps the ID it was asked under.
+    fn agent_request_id(
+        &self,
+        prefix: &str,
+        server_id: &str,
+        jsonrpc_id: Option<&Value>,
+    ) -> String {
+        let Some(jsonrpc_id) = jsonrpc_id else {
+            return self.next_id(prefix);
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(server_id.as_bytes());
+        hasher.update([0]);
+        hasher.update(jsonrpc_id.to_string().as_bytes());
+        format!("{prefix}{}", &hex::encode(hasher.finalize())[..20])
     }
-    (
-        StatusCode::OK,
-        Json(json!({
-            "mcp": {},
-            "agent": {},
-            "provider": {},
-        })),
-    )
-        .into_response()
-}
 
-async fn oc_config_patch(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-    Json(body): Json<Value>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+    fn now_ms(&self) -> i64 {
+        self.config.clock.now_ms()
     }
-    if let Some(response) = proxy_native_opencode(
-        &state,
-        reqwest::Method::PATCH,
-        "/config",
-        &headers,
-        Some(body.clone()),
-    )
-    .await
-    {
-        return response;
+
+    async fn current_connection_for_agent(&self, agent: &str) -> String {
+        let mut guard = self.agent_connections.lock().await;
+        guard
+            .entry(agent.to_string())
+            .or_insert_with(|| format!("conn_{}_{}", agent, self.now_ms()))
+            .clone()
     }
-    (StatusCode::OK, Json(body)).into_response()
-}
 
-async fn oc_config_providers(State(state): State<Arc<AdapterState>>) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+    async fn pool(&self) -> Result<&SqlitePool, String> {
+        if self
+            .config
+            .database_lock
+            .as_ref()
+            .is_some_and(|lock| lock.is_fenced())
+        {
+            return Err(format!(
+                "database {} was taken over by another instance",
+                self.sqlite_path
+            ));
+        }
+        self.pool
+            .get_or_try_init(|| async {
+                if let Some(parent) = PathBuf::from(&self.sqlite_path).parent() {
+                    if !parent.as_os_str().is_empty() {
+                        std::fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+                    }
+                }
+                SqlitePoolOptions::new()
+                    .max_connections(1)
+                    .connect_with(self.sqlite_connect_options.clone())
+                    .await
+                    .map_err(|err| err.to_string())
+            })
+            .await
     }
-    let providers = provider_payload(&state);
-    let mut payload = providers.clone();
-    if let Some(obj) = payload.as_object_mut() {
-        obj.insert("providers".to_string(), providers["all"].clone());
+
+    async fn persist_session(&self, meta: &SessionMeta) -> Result<(), String> {
+        let pool = self.pool().await?;
+        let started = Instant::now();
+        let mut tx = pool.begin().await.map_err(|err| err.to_string())?;
+        write_session(&mut tx, meta).await?;
+        tx.commit().await.map_err(|err| err.to_string())?;
+        if let Some(metrics) = self.config.metrics.as_ref() {
+            metrics.sqlite_write(started.elapsed());
+        }
+        Ok(())
     }
-    (StatusCode::OK, Json(payload)).into_response()
-}
 
-async fn oc_event_subscribe(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-    Query(query): Query<DirectoryQuery>,
-) -> Sse<impl futures::Stream<Item = Result<Event, Infallible>>> {
-    let _ = state.ensure_initialized().await;
+    async fn delete_session(&self, session_id: &str) -> Result<(), String> {
+        let pool = self.pool().await?;
+        let mut tx = pool.begin().await.map_err(|err| err.to_string())?;
+        for statement in [
+            "DELETE FROM events WHERE session_id = ?1",
+            "DELETE FROM event_dedupe WHERE session_id = ?1",
+            "DELETE FROM session_archives WHERE session_id = ?1",
+            "DELETE FROM session_turns WHERE session_id = ?1",
+            "DELETE FROM turn_journal WHERE session_id = ?1",
+            "DELETE FROM turn_timelines WHERE session_id = ?1",
+            "DELETE FROM turn_artifacts WHERE session_id = ?1",
+            "DELETE FROM part_blobs WHERE session_id = ?1",
+            "DELETE FROM opencode_session_metadata WHERE session_id = ?1",
+            "DELETE FROM sessions WHERE id = ?1",
+        ] {
+            sqlx::query(statement)
+                .bind(session_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        tx.commit().await.map_err(|err| err.to_string())?;
+        self.last_user_message_id.lock().await.remove(session_id);
+        self.session_todos.lock().await.remove(session_id);
+        if let Ok(mut activity) = self.session_activity.lock() {
+            activity.remove(session_id);
+        }
+        if let Ok(mut dispatched) = self.prompt_dispatched_at.lock() {
+            dispatched.remove(session_id);
+        }
+        Ok(())
+    }
 
-    let directory = resolve_directory(&headers, query.directory.as_ref());
-    let replay = state.buffered_events_after(parse_last_event_id(&headers));
-    let receiver = state.subscribe();
+    /// Tombstone `message_id`: persist a redaction envelope, delete the
+    /// envelopes and full part outputs that carry the message, strip replay
+    /// text quoting it from later prompts, and drop it from the projection,
+    /// the `/event` replay buffer and any replay text not yet sent. The
+    /// session's archived object, which may still hold the message, is
+    /// deleted; the next sweep archives the redacted log instead.
+    async fn redact_message(
+        &self,
+        session_id: &str,
+        message_id: &str,
+        part_ids: &[String],
+    ) -> Result<(), String> {
+        // Hold off the sweep so the session stays local until its object is
+        // gone, hydrating it again if it was archived since the caller did.
+        let _guard = self.archive_lock.lock().await;
+        if let Some(archive) = self.archive.as_ref() {
+            self.hydrate(session_id).await?;
+            archive
+                .delete_object(&archive.config().object_key(session_id))
+                .await?;
+        }
 
-    state.emit_event(json!({"type":"server.connected","properties":{}}));
-    state.emit_event(
-        json!({"type":"worktree.ready","properties":{"name": directory, "branch": "main"}}),
-    );
+        let envelope = json!({
+            "jsonrpc": "2.0",
+            "method": "_sandboxagent/opencode/message_redacted",
+            "params": {"sessionID": session_id, "messageID": message_id, "redactedAt": self.now_ms()},
+        });
+        self.persist_event(session_id, "client", &envelope).await?;
 
-    let stream = stream::unfold(
-        (
-            receiver,
-            VecDeque::from(replay),
-            interval(Duration::from_secs(30)),
-        ),
-        |(mut rx, mut replay, mut ticker)| async move {
-            if let Some(item) = replay.pop_front() {
-                let evt = Event::default()
-                    .id(item.id.to_string())
-                    .json_data(item.payload)
-                    .unwrap_or_else(|_| Event::default().data("{}"));
-                return Some((Ok(evt), (rx, replay, ticker)));
+        let pool = self.pool().await?;
+        let mut tx = pool.begin().await.map_err(|err| err.to_string())?;
+        sqlx::query(
+            r#"DELETE FROM events
+               WHERE session_id = ?1 AND json_extract(payload_json, '$.params.message.info.id') = ?2"#,
+        )
+        .bind(session_id)
+        .bind(message_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| err.to_string())?;
+        for part_id in part_ids {
+            sqlx::query("DELETE FROM part_blobs WHERE session_id = ?1 AND part_id = ?2")
+                .bind(session_id)
+                .bind(part_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        // Prompts sent after a restore carry replay text that may quote it.
+        let quoting = sqlx::query(
+            r#"SELECT id, payload_json FROM events
+               WHERE session_id = ?1 AND instr(payload_json, ?2) > 0"#,
+        )
+        .bind(session_id)
+        .bind(message_id)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|err| err.to_string())?;
+        for row in quoting {
+            let id: String = row.try_get("id").map_err(|err| err.to_string())?;
+            let payload_json: String =
+                row.try_get("payload_json").map_err(|err| err.to_string())?;
+            let Ok(mut payload) = serde_json::from_str::<Value>(&payload_json) else {
+                continue;
+            };
+            if !strip_quoted_message(&mut payload, message_id) {
+                continue;
             }
+            sqlx::query("UPDATE events SET payload_json = ?1 WHERE id = ?2")
+                .bind(payload.to_string())
+                .bind(id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        tx.commit().await.map_err(|err| err.to_string())?;
 
-            loop {
-                tokio::select! {
-                    _ = ticker.tick() => {
-                        let evt = Event::default().json_data(json!({"type":"server.heartbeat","properties":{}}))
-                            .unwrap_or_else(|_| Event::default().data("{}"));
-                        return Some((Ok(evt), (rx, replay, ticker)));
-                    }
-                    item = rx.recv() => {
-                        match item {
-                            Ok(payload) => {
-                                let evt = Event::default()
-                                    .id(payload.id.to_string())
-                                    .json_data(payload.payload)
-                                    .unwrap_or_else(|_| Event::default().data("{}"));
-                                return Some((Ok(evt), (rx, replay, ticker)));
-                            }
-                            Err(broadcast::error::RecvError::Lagged(_)) => continue,
-                            Err(broadcast::error::RecvError::Closed) => return None,
-                        }
-                    }
-                }
+        {
+            let mut projection = self.projection.lock().await;
+            apply_envelope(&mut projection, session_id, "client", &envelope);
+        }
+        if let Ok(mut log) = self.event_log.lock() {
+            log.retain(|event| !event_mentions_message(&event.payload, message_id));
+        }
+        for (_, record) in self.turns.lock().await.iter_mut() {
+            if record.session_id == session_id
+                && record
+                    .output
+                    .as_ref()
+                    .and_then(|output| output.pointer("/info/id"))
+                    .and_then(Value::as_str)
+                    == Some(message_id)
+            {
+                record.output = None;
             }
-        },
-    );
-
-    Sse::new(stream).keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
-}
+        }
+        let mut pending_replay = self.pending_replay.lock().await;
+        if pending_replay.contains_key(session_id) {
+            match self.replay_text(session_id).await? {
+                Some(text) => pending_replay.insert(session_id.to_string(), text),
+                None => pending_replay.remove(session_id),
+            };
+        }
+        Ok(())
+    }
 
-async fn oc_global_event(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-    Query(query): Query<DirectoryQuery>,
-) -> Sse<impl futures::Stream<Item = Result<Event, Infallible>>> {
-    oc_event_subscribe(State(state), headers, Query(query)).await
-}
+    /// Whether a session can be moved to the archive: a user session that is
+    /// settled, untouched for `archive_after`, and not waiting on the user.
+    fn is_archivable(&self, projection: &Projection, session_id: &str) -> bool {
+        let Some(archive) = self.archive.as_ref() else {
+            return false;
+        };
+        let Some(session) = projection.sessions.get(session_id) else {
+            return false;
+        };
+        let idle_for = self.now_ms().saturating_sub(session.meta.updated_at);
+        let waiting_on_user = projection
+            .permissions
+            .values()
+            .chain(projection.questions.values())
+            .any(|request| request.get("sessionID").and_then(Value::as_str) == Some(session_id));
+        session.archive_key.is_none()
+            && session.meta.kind.is_user()
+            && session.pending_context.is_empty()
+            && matches!(
+                session.lifecycle,
+                SessionLifecycle::Idle | SessionLifecycle::Errored | SessionLifecycle::Ended
+            )
+            && !waiting_on_user
+            && idle_for >= archive.config().archive_after.as_millis() as i64
+    }
 
-async fn oc_global_health() -> Response {
-    (
-        StatusCode::OK,
-        Json(json!({
-            "healthy": true,
-            "version": env!("CARGO_PKG_VERSION"),
-        })),
-    )
-        .into_response()
-}
+    /// Export a session's metadata and event log to the archive, then drop
+    /// the local events. Returns `false` when the session was not eligible.
+    async fn archive_session(&self, session_id: &str) -> Result<bool, String> {
+        let Some(archive) = self.archive.as_ref() else {
+            return Ok(false);
+        };
+        let _guard = self.archive_lock.lock().await;
 
-async fn oc_global_config_get(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-) -> Response {
-    if let Some(response) = proxy_native_opencode(
-        &state,
-        reqwest::Method::GET,
-        "/global/config",
-        &headers,
-        None,
-    )
-    .await
-    {
-        return response;
-    }
-    oc_config_get(State(state), headers).await
-}
+        let meta = {
+            let projection = self.projection.lock().await;
+            if !self.is_archivable(&projection, session_id) {
+                return Ok(false);
+            }
+            projection.sessions[session_id].meta.clone()
+        };
 
-async fn oc_global_config_patch(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-    Json(body): Json<Value>,
-) -> Response {
-    if let Some(response) = proxy_native_opencode(
-        &state,
-        reqwest::Method::PATCH,
-        "/global/config",
-        &headers,
-        Some(body.clone()),
-    )
-    .await
-    {
-        return response;
-    }
-    oc_config_patch(State(state), headers, Json(body)).await
-}
+        let events = self.session_event_log(session_id).await?;
+        if events.is_empty() {
+            return Ok(false);
+        }
+        let archived_at = self.now_ms();
+        let document = json!({
+            "version": 1,
+            "archivedAt": archived_at,
+            "session": meta,
+            "events": events,
+        });
 
-async fn oc_global_dispose() -> Response {
-    bool_ok(true).into_response()
-}
+        let key = archive.config().object_key(session_id);
+        let pool = self.pool().await?;
+        archive
+            .put_object(
+                &key,
+                serde_json::to_vec(&document).map_err(|err| err.to_string())?,
+            )
+            .await?;
 
-async fn oc_instance_dispose() -> Response {
-    bool_ok(true).into_response()
-}
+        // Drop only the events the object holds. Events persisted since they
+        // were read keep the session local until the next sweep archives it.
+        let mut tx = pool.begin().await.map_err(|err| err.to_string())?;
+        for event in &events {
+            sqlx::query("DELETE FROM events WHERE session_id = ?1 AND id = ?2")
+                .bind(session_id)
+                .bind(event.get("id").and_then(Value::as_str).unwrap_or_default())
+                .execute(&mut *tx)
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        let remaining: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM events WHERE session_id = ?1")
+                .bind(session_id)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(|err| err.to_string())?;
+        if remaining > 0 {
+            tx.rollback().await.map_err(|err| err.to_string())?;
+            return Ok(false);
+        }
+        sqlx::query(
+            r#"INSERT INTO session_archives (session_id, object_key, archived_at)
+               VALUES (?1, ?2, ?3)
+               ON CONFLICT(session_id) DO UPDATE SET
+                 object_key = excluded.object_key,
+                 archived_at = excluded.archived_at"#,
+        )
+        .bind(session_id)
+        .bind(&key)
+        .bind(archived_at)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| err.to_string())?;
+        tx.commit().await.map_err(|err| err.to_string())?;
 
-async fn oc_path(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-    Query(query): Query<DirectoryQuery>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+        let mut projection = self.projection.lock().await;
+        if let Some(session) = projection.sessions.get_mut(session_id) {
+            session.messages.clear();
+            session.archive_key = Some(key);
+        }
+        Ok(true)
     }
 
-    let directory = resolve_directory(&headers, query.directory.as_ref());
-    (
-        StatusCode::OK,
-        Json(json!({
-            "home": std::env::var("HOME").unwrap_or_else(|_| "/".to_string()),
-            "state": std::env::var("OPENCODE_COMPAT_STATE").unwrap_or_else(|_| "/tmp".to_string()),
-            "config": std::env::var("OPENCODE_COMPAT_CONFIG").unwrap_or_else(|_| "/tmp".to_string()),
-            "worktree": directory,
-            "directory": resolve_directory(&headers, query.directory.as_ref()),
-        })),
-    )
-        .into_response()
-}
+    /// Restore an archived session's event log into SQLite and the
+    /// projection. No-op for sessions that are not archived.
+    async fn ensure_hydrated(&self, session_id: &str) -> Result<(), String> {
+        let is_archived = |projection: &Projection| {
+            projection
+                .sessions
+                .get(session_id)
+                .and_then(|session| session.archive_key.clone())
+        };
+        if is_archived(&*self.projection.lock().await).is_none() {
+            return Ok(());
+        }
 
-async fn oc_vcs(State(state): State<Arc<AdapterState>>) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+        let _guard = self.archive_lock.lock().await;
+        self.hydrate(session_id).await
     }
-    (StatusCode::OK, Json(json!({"branch":"main"}))).into_response()
-}
 
-async fn oc_mcp_status(State(state): State<Arc<AdapterState>>) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+    /// [`Self::ensure_hydrated`] for a caller holding `archive_lock`.
+    async fn hydrate(&self, session_id: &str) -> Result<(), String> {
+        let Some(key) = self
+            .projection
+            .lock()
+            .await
+            .sessions
+            .get(session_id)
+            .and_then(|session| session.archive_key.clone())
+        else {
+            return Ok(());
+        };
+        let Some(archive) = self.archive.as_ref() else {
+            return Err(format!(
+                "session '{session_id}' is archived but no session archive is configured"
+            ));
+        };
+        let body = archive
+            .get_object(&key)
+            .await?
+            .ok_or_else(|| format!("archived event log '{key}' not found"))?;
+        let document: Value = serde_json::from_slice(&body).map_err(|err| err.to_string())?;
+        let events = document
+            .get("events")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+
+        let pool = self.pool().await?;
+        let mut tx = pool.begin().await.map_err(|err| err.to_string())?;
+        insert_event_log(&mut tx, session_id, &events).await?;
+        sqlx::query("DELETE FROM session_archives WHERE session_id = ?1")
+            .bind(session_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|err| err.to_string())?;
+        tx.commit().await.map_err(|err| err.to_string())?;
+
+        let mut projection = self.projection.lock().await;
+        apply_event_log(&mut projection, session_id, &events);
+        if let Some(session) = projection.sessions.get_mut(session_id) {
+            session.archive_key = None;
+        }
+        Ok(())
     }
-    (StatusCode::OK, Json(json!({}))).into_response()
-}
 
-async fn oc_lsp_status(State(state): State<Arc<AdapterState>>) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+    /// Export a session for [`oc_session_migrate`]: its metadata, event log,
+    /// full tool outputs, todos, ACP bindings and pending requests.
+    async fn session_migration(&self, session_id: &str) -> Result<SessionMigration, String> {
+        let (meta, permissions, questions) = {
+            let projection = self.projection.lock().await;
+            let meta = projection
+                .sessions
+                .get(session_id)
+                .map(|session| session.meta.clone())
+                .ok_or_else(|| format!("session {session_id} not found"))?;
+            let pending = |requests: &HashMap<String, Value>| {
+                requests
+                    .values()
+                    .filter(|request| {
+                        request.get("sessionID").and_then(Value::as_str) == Some(session_id)
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            (
+                meta,
+                pending(&projection.permissions),
+                pending(&projection.questions),
+            )
+        };
+        let events = self.session_event_log(session_id).await?;
+        let pool = self.pool().await?;
+        let part_blobs = sqlx::query(
+            "SELECT part_id, content, created_at FROM part_blobs WHERE session_id = ?1",
+        )
+        .bind(session_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|err| err.to_string())?
+        .into_iter()
+        .map(|row| {
+            Ok(json!({
+                "partID": row.try_get::<String, _>("part_id")?,
+                "content": row.try_get::<String, _>("content")?,
+                "createdAt": row.try_get::<i64, _>("created_at")?,
+            }))
+        })
+        .collect::<Result<Vec<_>, sqlx::Error>>()
+        .map_err(|err| err.to_string())?;
+        let acp = self
+            .load_acp_bindings()
+            .await?
+            .into_iter()
+            .filter(|binding| binding.session_id == session_id)
+            .map(|binding| {
+                json!({
+                    "serverID": binding.server_id,
+                    "acpSessionID": binding.acp_session_id,
+                    "lastEventID": binding.last_event_id,
+                })
+            })
+            .collect();
+        let todos = self
+            .session_todos
+            .lock()
+            .await
+            .get(session_id)
+            .cloned()
+            .unwrap_or(Value::Null);
+        Ok(SessionMigration {
+            version: migration::MIGRATION_VERSION,
+            session: serde_json::to_value(&meta).map_err(|err| err.to_string())?,
+            events,
+            part_blobs,
+            todos,
+            acp,
+            permissions,
+            questions,
+        })
     }
-    (StatusCode::OK, Json(json!([]))).into_response()
-}
 
-async fn oc_formatter_status(State(state): State<Arc<AdapterState>>) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+    /// Create a session migrated in from another instance. Its agent is not
+    /// running here, so it starts dormant: the next prompt bootstraps one and
+    /// replays the history to it.
+    async fn import_session(&self, migration: SessionMigration) -> Result<SessionMeta, String> {
+        let mut meta: SessionMeta =
+            serde_json::from_value(migration.session).map_err(|err| err.to_string())?;
+        let session_id = meta.id.clone();
+        meta.dormant_at = Some(self.now_ms());
+        meta.migrated_to = None;
+        meta.last_connection_id = self.current_connection_for_agent(&meta.agent).await;
+
+        let pool = self.pool().await?;
+        let mut tx = pool.begin().await.map_err(|err| err.to_string())?;
+        write_session(&mut tx, &meta).await?;
+        insert_event_log(&mut tx, &session_id, &migration.events).await?;
+        for blob in &migration.part_blobs {
+            let content = blob["content"].as_str().unwrap_or_default();
+            sqlx::query(
+                r#"INSERT OR IGNORE INTO part_blobs (part_id, session_id, content, bytes, created_at)
+                   VALUES (?1, ?2, ?3, ?4, ?5)"#,
+            )
+            .bind(blob["partID"].as_str().unwrap_or_default())
+            .bind(&session_id)
+            .bind(content)
+            .bind(content.len() as i64)
+            .bind(blob["createdAt"].as_i64().unwrap_or_default())
+            .execute(&mut *tx)
+            .await
+            .map_err(|err| err.to_string())?;
+        }
+        tx.commit().await.map_err(|err| err.to_string())?;
+
+        self.track_session_secrets(&meta);
+        self.record_session_created(&meta);
+        if !migration.todos.is_null() {
+            self.session_todos
+                .lock()
+                .await
+                .insert(session_id.clone(), migration.todos);
+        }
+        let mut projection = self.projection.lock().await;
+        projection.sessions.insert(
+            session_id.clone(),
+            SessionState {
+                meta: meta.clone(),
+                messages: Vec::new(),
+                lifecycle: SessionLifecycle::Idle,
+                always_rules: Vec::new(),
+                pending_context: Vec::new(),
+                archive_key: None,
+            },
+        );
+        apply_event_log(&mut projection, &session_id, &migration.events);
+        // The log may not hold every pending request, e.g. one asked by an
+        // older version, so the exported ones are registered as well.
+        let Projection {
+            permissions,
+            questions,
+            ..
+        } = &mut *projection;
+        for (requests, pending) in [
+            (permissions, &migration.permissions),
+            (questions, &migration.questions),
+        ] {
+            for request in pending {
+                if let Some(id) = request.get("id").and_then(Value::as_str) {
+                    requests.insert(id.to_string(), request.clone());
+                }
+            }
+        }
+        drop(projection);
+        for permission in &migration.permissions {
+            self.emit_event(json!({"type": "permission.asked", "properties": permission}));
+        }
+        for question in &migration.questions {
+            self.emit_event(json!({"type": "question.asked", "properties": question}));
+        }
+        Ok(meta)
     }
-    (StatusCode::OK, Json(json!([]))).into_response()
-}
 
-async fn oc_experimental_resource(State(state): State<Arc<AdapterState>>) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+    /// Hand a session over to the instance at `target` once it holds it:
+    /// drop its pending requests, stop its agent and end it here with
+    /// `migratedTo` set.
+    async fn hand_over_session(
+        &self,
+        session_id: &str,
+        target: &str,
+    ) -> Result<SessionMeta, String> {
+        let meta = {
+            let mut projection = self.projection.lock().await;
+            let belongs =
+                |value: &Value| value.get("sessionID").and_then(Value::as_str) == Some(session_id);
+            projection.permissions.retain(|_, value| !belongs(value));
+            projection.questions.retain(|_, value| !belongs(value));
+            let session = projection
+                .sessions
+                .get_mut(session_id)
+                .ok_or_else(|| format!("session {session_id} not found"))?;
+            session.meta.migrated_to = Some(target.to_string());
+            session.meta.updated_at = self.now_ms();
+            session.meta.clone()
+        };
+        self.acp_request_ids
+            .lock()
+            .await
+            .retain(|_, request| request.opencode_session_id != session_id);
+        for server_id in meta.acp_server_ids() {
+            self.release_acp_instance(&server_id).await;
+        }
+        self.persist_session(&meta).await?;
+        Ok(meta)
     }
-    (StatusCode::OK, Json(json!([]))).into_response()
-}
 
-async fn oc_skill_list() -> Response {
-    (StatusCode::OK, Json(json!([]))).into_response()
-}
+    /// A session's persisted events, oldest first, as archived and migrated:
+    /// `{ id, createdAt, connectionId, sender, payload }`.
+    async fn session_event_log(&self, session_id: &str) -> Result<Vec<Value>, String> {
+        let pool = self.pool().await?;
+        let rows = sqlx::query(
+            r#"SELECT id, created_at, connection_id, sender, payload_json
+               FROM events
+               WHERE session_id = ?1
+               ORDER BY created_at ASC, id ASC"#,
+        )
+        .bind(session_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|err| err.to_string())?;
 
-async fn oc_tui_next(State(state): State<Arc<AdapterState>>, headers: HeaderMap) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-    if let Some(response) = proxy_native_opencode(
-        &state,
-        reqwest::Method::GET,
-        "/tui/control/next",
-        &headers,
-        None,
-    )
-    .await
-    {
-        return response;
+        let mut events = Vec::with_capacity(rows.len());
+        for row in rows {
+            let payload_json: String =
+                row.try_get("payload_json").map_err(|err| err.to_string())?;
+            events.push(json!({
+                "id": row.try_get::<String, _>("id").map_err(|err| err.to_string())?,
+                "createdAt": row.try_get::<i64, _>("created_at").map_err(|err| err.to_string())?,
+                "connectionId": row.try_get::<String, _>("connection_id").map_err(|err| err.to_string())?,
+                "sender": row.try_get::<String, _>("sender").map_err(|err| err.to_string())?,
+                "payload": serde_json::from_str::<Value>(&payload_json).map_err(|err| err.to_string())?,
+            }));
+        }
+        Ok(events)
     }
-    (StatusCode::OK, Json(json!({"path": "", "body": {}}))).into_response()
-}
 
-async fn oc_tui_response(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-    body: Option<Json<Value>>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-    if let Some(response) = proxy_native_opencode(
-        &state,
-        reqwest::Method::POST,
-        "/tui/control/response",
-        &headers,
-        body.map(|json| json.0),
-    )
-    .await
-    {
-        return response;
+    /// Usage of the session quota's resources, with the creation time of the
+    /// oldest session of the last hour.
+    async fn quota_usage(&self) -> Result<(QuotaUsage, Option<i64>), String> {
+        let (live_sessions, known) = {
+            let projection = self.projection.lock().await;
+            let live_sessions = projection
+                .sessions
+                .values()
+                .filter(|session| {
+                    session.lifecycle != SessionLifecycle::Ended
+                        && session.meta.destroyed_at.is_none()
+                })
+                .count();
+            let known = projection
+                .sessions
+                .values()
+                .map(|session| (session.meta.created_at, session.meta.id.clone()))
+                .collect::<Vec<_>>();
+            (live_sessions, known)
+        };
+        let since = self.now_ms() - quota::HOUR_MS;
+        let recent = self
+            .recent_sessions
+            .lock()
+            .map(|mut recent| recent.since(since, known))
+            .unwrap_or_default();
+        // Kept by triggers on `events`, so checking the quota doesn't scan it.
+        let total_events: i64 = sqlx::query_scalar("SELECT total FROM event_count WHERE id = 1")
+            .fetch_one(self.pool().await?)
+            .await
+            .map_err(|err| err.to_string())?;
+        let usage = QuotaUsage {
+            live_sessions,
+            sessions_last_hour: recent.len(),
+            total_events: total_events.max(0) as u64,
+        };
+        Ok((usage, recent.into_iter().min()))
     }
-    bool_ok(true).into_response()
-}
 
-async fn oc_tui_append_prompt(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-    body: Option<Json<Value>>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-    if let Some(response) = proxy_native_opencode(
-        &state,
-        reqwest::Method::POST,
-        "/tui/append-prompt",
-        &headers,
-        body.map(|json| json.0),
-    )
-    .await
-    {
-        return response;
+    /// Count a new session against the hourly quota.
+    fn record_session_created(&self, meta: &SessionMeta) {
+        if let Ok(mut recent) = self.recent_sessions.lock() {
+            recent.record(&meta.id, meta.created_at);
+        }
     }
-    bool_ok(true).into_response()
-}
 
-async fn oc_tui_open_help(State(state): State<Arc<AdapterState>>, headers: HeaderMap) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-    if let Some(response) = proxy_native_opencode(
-        &state,
-        reqwest::Method::POST,
-        "/tui/open-help",
-        &headers,
-        None,
-    )
-    .await
-    {
-        return response;
+    /// Round-trip a trivial query and read the persisted event count the
+    /// `events` triggers keep, rather than scanning the log.
+    async fn sqlite_health(&self) -> Value {
+        let started = std::time::Instant::now();
+        let result = async {
+            self.ensure_initialized().await?;
+            let pool = self.pool().await?;
+            sqlx::query("SELECT 1")
+                .execute(pool)
+                .await
+                .map_err(|err| err.to_string())?;
+            let latency_ms = started.elapsed().as_millis() as u64;
+            let events: i64 = sqlx::query_scalar("SELECT total FROM event_count WHERE id = 1")
+                .fetch_one(pool)
+                .await
+                .map_err(|err| err.to_string())?;
+            Ok::<_, String>((latency_ms, events))
+        }
+        .await;
+
+        match result {
+            Ok((latency_ms, events)) => json!({
+                "status": "ok",
+                "path": self.sqlite_path,
+                "latencyMs": latency_ms,
+                "events": events,
+            }),
+            Err(err) => json!({
+                "status": "error",
+                "path": self.sqlite_path,
+                "error": err,
+            }),
+        }
     }
-    bool_ok(true).into_response()
-}
-
-async fn oc_tui_open_sessions() -> Response {
-    bool_ok(true).into_response()
-}
 
-async fn oc_tui_open_themes(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-    if let Some(response) = proxy_native_opencode(
-        &state,
-        reqwest::Method::POST,
-        "/tui/open-themes",
-        &headers,
-        None,
-    )
-    .await
-    {
-        return response;
+    /// Probe the native OpenCode sidecar without starting it.
+    async fn native_sidecar_health(&self) -> Value {
+        let base_url = match (
+            self.config.native_proxy_base_url.as_ref(),
+            self.config.native_proxy_manager.as_ref(),
+        ) {
+            (Some(base_url), _) => base_url.clone(),
+            (None, Some(manager)) => {
+                let status = manager.status();
+                let supervisor = sidecar_status_value(&status);
+                let Some(base_url) = manager.running_base_url().await else {
+                    let state = match status.state {
+                        SidecarState::Restarting | SidecarState::Failed => status.state.as_str(),
+                        _ => "stopped",
+                    };
+                    return json!({"status": state, "supervisor": supervisor});
+                };
+                let mut health = self.probe_native_sidecar(&base_url).await;
+                health["supervisor"] = supervisor;
+                return health;
+            }
+            (None, None) => return json!({"status": "not_configured"}),
+        };
+        self.probe_native_sidecar(&base_url).await
+    }
+
+    async fn probe_native_sidecar(&self, base_url: &str) -> Value {
+        let started = std::time::Instant::now();
+        let probe = self
+            .proxy_http_client
+            .get(format!("{base_url}/global/health"))
+            .timeout(HEALTH_SIDECAR_TIMEOUT)
+            .send()
+            .await;
+        match probe {
+            Ok(response) if response.status().is_success() => json!({
+                "status": "reachable",
+                "url": base_url,
+                "latencyMs": started.elapsed().as_millis() as u64,
+            }),
+            Ok(response) => json!({
+                "status": "unreachable",
+                "url": base_url,
+                "error": format!("health probe returned {}", response.status()),
+            }),
+            Err(err) => json!({
+                "status": "unreachable",
+                "url": base_url,
+                "error": err.to_string(),
+            }),
+        }
     }
-    bool_ok(true).into_response()
-}
 
-async fn oc_tui_open_models(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-    if let Some(response) = proxy_native_opencode(
-        &state,
-        reqwest::Method::POST,
-        "/tui/open-models",
-        &headers,
-        None,
-    )
-    .await
-    {
-        return response;
+    /// Persist an envelope and apply it to the projection. An envelope
+    /// already persisted in the same turn (e.g. by a retried restore) is
+    /// skipped, so re-applying is idempotent.
+    async fn persist_event(
+        &self,
+        session_id: &str,
+        sender: &str,
+        payload: &Value,
+    ) -> Result<(), String> {
+        self.persist_new_event(session_id, sender, payload)
+            .await
+            .map(|_| ())
     }
-    bool_ok(true).into_response()
-}
 
-async fn oc_tui_submit_prompt(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-    body: Option<Json<Value>>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-    if let Some(response) = proxy_native_opencode(
-        &state,
-        reqwest::Method::POST,
-        "/tui/submit-prompt",
-        &headers,
-        body.map(|json| json.0),
-    )
-    .await
-    {
-        return response;
+    /// [`Self::persist_event`], telling whether the envelope was new; `false`
+    /// when the log already held it, e.g. for a payload replayed to a
+    /// restarted adapter.
+    async fn persist_new_event(
+        &self,
+        session_id: &str,
+        sender: &str,
+        payload: &Value,
+    ) -> Result<bool, String> {
+        let row = self.event_row(session_id, sender, payload).await;
+        let pool = self.pool().await?;
+        let started = Instant::now();
+        let mut tx = pool.begin().await.map_err(|err| err.to_string())?;
+        if !insert_event(&mut tx, &row).await? {
+            return Ok(false);
+        }
+        tx.commit().await.map_err(|err| err.to_string())?;
+        if let Some(metrics) = self.config.metrics.as_ref() {
+            metrics.sqlite_write(started.elapsed());
+        }
+        self.event_committed(&row).await;
+        Ok(true)
     }
-    bool_ok(true).into_response()
-}
 
-async fn oc_tui_clear_prompt(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-    if let Some(response) = proxy_native_opencode(
-        &state,
-        reqwest::Method::POST,
-        "/tui/clear-prompt",
-        &headers,
-        None,
-    )
-    .await
-    {
-        return response;
+    /// Prepare an envelope for the event log: cap its parts and stamp it
+    /// with an ID, the session's connection and the turn it belongs to.
+    async fn event_row(&self, session_id: &str, sender: &str, payload: &Value) -> EventRow {
+        let payload = self
+            .cap_envelope_parts(session_id, payload)
+            .await
+            .unwrap_or_else(|| payload.clone());
+        let connection_id = {
+            let projection = self.projection.lock().await;
+            projection
+                .sessions
+                .get(session_id)
+                .map(|state| state.meta.last_connection_id.clone())
+                .unwrap_or_else(|| "conn_unknown".to_string())
+        };
+        let turn = self
+            .last_user_message_id
+            .lock()
+            .await
+            .get(session_id)
+            .cloned()
+            .unwrap_or_default();
+        EventRow {
+            id: format!("evt_{}", self.next_id("")),
+            session_id: session_id.to_string(),
+            created_at: self.now_ms(),
+            connection_id,
+            turn,
+            sender: sender.to_string(),
+            payload,
+        }
     }
-    bool_ok(true).into_response()
-}
 
-async fn oc_tui_execute_command(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-    body: Option<Json<Value>>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-    if let Some(response) = proxy_native_opencode(
-        &state,
-        reqwest::Method::POST,
-        "/tui/execute-command",
-        &headers,
-        body.map(|json| json.0),
-    )
-    .await
-    {
-        return response;
+    /// Apply a committed envelope to the projection.
+    async fn event_committed(&self, row: &EventRow) {
+        if let Ok(mut activity) = self.session_activity.lock() {
+            activity.insert(row.session_id.clone(), row.created_at);
+        }
+        let mut projection = self.projection.lock().await;
+        apply_envelope(&mut projection, &row.session_id, &row.sender, &row.payload);
     }
-    bool_ok(true).into_response()
-}
 
-async fn oc_tui_show_toast(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-    body: Option<Json<Value>>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-    if let Some(response) = proxy_native_opencode(
-        &state,
-        reqwest::Method::POST,
-        "/tui/show-toast",
-        &headers,
-        body.map(|json| json.0),
-    )
-    .await
-    {
-        return response;
+    /// The history replayed to a fresh agent process for `session_id`, within
+    /// the configured event and character budgets.
+    async fn replay_text(&self, session_id: &str) -> Result<Option<String>, String> {
+        let selection = self
+            .replay_selection(
+                session_id,
+                self.config.replay_max_events,
+                self.config.replay_max_chars,
+            )
+            .await?;
+        Ok(selection.text())
     }
-    bool_ok(true).into_response()
-}
 
-async fn oc_tui_publish(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-    body: Option<Json<Value>>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-    if let Some(response) = proxy_native_opencode(
-        &state,
-        reqwest::Method::POST,
-        "/tui/publish",
-        &headers,
-        body.map(|json| json.0),
-    )
-    .await
-    {
-        return response;
+    async fn replay_selection(
+        &self,
+        session_id: &str,
+        max_events: usize,
+        max_chars: usize,
+    ) -> Result<ReplaySelection, String> {
+        let events = self.collect_replay_events(session_id).await?;
+        Ok(replay_select::select(events, max_events, max_chars))
     }
-    bool_ok(true).into_response()
-}
 
-async fn oc_project_list(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-    Query(query): Query<DirectoryQuery>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-    let directory = resolve_directory(&headers, query.directory.as_ref());
-    let now = now_ms();
-    (
-        StatusCode::OK,
-        Json(json!([{
-            "id": state.project_id,
-            "worktree": directory,
-            "vcs": "git",
-            "name": "sandbox-agent",
-            "time": {"created": now, "updated": now},
-        }])),
-    )
-        .into_response()
-}
+    async fn collect_replay_events(&self, session_id: &str) -> Result<Vec<Value>, String> {
+        let pool = self.pool().await?;
+        let rows = sqlx::query(
+            r#"SELECT created_at, sender, payload_json
+               FROM events
+               WHERE session_id = ?1
+               ORDER BY created_at ASC, id ASC"#,
+        )
+        .bind(session_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|err| err.to_string())?;
 
-async fn oc_project_current(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-    Query(query): Query<DirectoryQuery>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-    let directory = resolve_directory(&headers, query.directory.as_ref());
-    let now = now_ms();
-    (
-        StatusCode::OK,
-        Json(json!({
-            "id": state.project_id,
-            "worktree": directory,
-            "vcs": "git",
-            "name": "sandbox-agent",
-            "time": {"created": now, "updated": now},
-        })),
-    )
-        .into_response()
-}
+        let mut envelopes = Vec::new();
+        let mut redacted = HashSet::new();
+        for row in rows {
+            let created_at: i64 = row.try_get("created_at").map_err(|err| err.to_string())?;
+            let sender: String = row.try_get("sender").map_err(|err| err.to_string())?;
+            let payload_json: String =
+                row.try_get("payload_json").map_err(|err| err.to_string())?;
+            let payload: Value =
+                serde_json::from_str(&payload_json).map_err(|err| err.to_string())?;
+            if let Some(message_id) = redacted_message_id(&payload) {
+                redacted.insert(message_id.to_string());
+                continue;
+            }
+            envelopes.push((created_at, sender, payload));
+        }
 
-async fn oc_session_create(
-    State(state): State<Arc<AdapterState>>,
-    headers: HeaderMap,
-    Query(query): Query<DirectoryQuery>,
-    body: Option<Json<SessionCreateBody>>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+        // Redacted messages stay out even when an older copy of the log,
+        // such as a session archive, still holds them.
+        let mut values = Vec::new();
+        for (created_at, sender, payload) in envelopes {
+            if payload
+                .pointer("/params/message/info/id")
+                .and_then(Value::as_str)
+                .is_some_and(|message_id| redacted.contains(message_id))
+            {
+                continue;
+            }
+            values.push(json!({
+                "createdAt": created_at,
+                "sender": sender,
+                "payload": payload,
+            }));
+        }
+        Ok(values)
     }
 
-    let body = body.map(|value| value.0).unwrap_or(SessionCreateBody {
-        title: None,
-        parent_id: None,
-        permission: None,
-        permission_mode: None,
-    });
+    async fn maybe_restore_session(&self, session_id: &str) -> Result<(), String> {
+        let (agent, stale, directory) = {
+            let projection = self.projection.lock().await;
+            let Some(state) = projection.sessions.get(session_id) else {
+                return Ok(());
+            };
+            (
+                state.meta.agent.clone(),
+                state.meta.last_connection_id.clone(),
+                state.meta.directory.clone(),
+            )
+        };
 
-    let id = state.next_id("ses_");
-    let now = now_ms();
-    let directory = resolve_directory(&headers, query.directory.as_ref());
+        let current = self.current_connection_for_agent(&agent).await;
+        if stale == current {
+            return Ok(());
+        }
 
-    let default_agent = "mock";
-    let connection_id = state.current_connection_for_agent(default_agent).await;
-    let meta = SessionMeta {
-        id: id.clone(),
-        slug: format!("session-{id}"),
-        project_id: state.project_id.clone(),
-        directory,
-        parent_id: body.parent_id,
-        title: body.title.unwrap_or_else(|| format!("Session {id}")),
-        version: "0".to_string(),
-        created_at: now,
-        updated_at: now,
-        share_url: None,
-        permission_mode: body.permission_mode,
-        agent: default_agent.to_string(),
-        provider_id: default_agent.to_string(),
-        model_id: default_model_for_provider(default_agent)
-            .unwrap_or("default")
-            .to_string(),
-        agent_session_id: format!("acp_{}", state.next_id("ses_")),
-        last_connection_id: connection_id,
-        session_init_json: Some(json!({"cwd": "/", "mcpServers": []})),
-        destroyed_at: None,
-    };
+        let replay_text = self.replay_text(session_id).await?;
 
-    if let Err(err) = state.persist_session(&meta).await {
-        return internal_error(err);
+        let request_id = self.next_id("oc_req_");
+        let new_agent_session_id = format!("acp_{}", self.next_id("ses_"));
+        let new_request = json!({
+            "jsonrpc": "2.0",
+            "id": request_id,
+            "method": "session/new",
+            "params": session_init_json(&directory),
+        });
+        self.persist_event(session_id, "client", &new_request)
+            .await?;
+
+        let new_response = json!({
+            "jsonrpc": "2.0",
+            "id": request_id,
+            "result": {
+                "sessionId": new_agent_session_id,
+            }
+        });
+        self.persist_event(session_id, "agent", &new_response)
+            .await?;
+
+        let mut updated_meta = None;
+        {
+            let mut projection = self.projection.lock().await;
+            if let Some(session) = projection.sessions.get_mut(session_id) {
+                session.meta.agent_session_id = new_agent_session_id;
+                session.meta.last_connection_id = current;
+                session.meta.destroyed_at = None;
+                updated_meta = Some(session.meta.clone());
+            }
+        }
+        if let Some(meta) = updated_meta {
+            self.persist_session(&meta).await?;
+        }
+
+        if let Some(text) = replay_text {
+            self.pending_replay
+                .lock()
+                .await
+                .insert(session_id.to_string(), text);
+        }
+
+        Ok(())
     }
 
-    {
-        let mut projection = state.projection.lock().await;
-        projection.sessions.insert(
-            id,
+    fn record_prompt_capabilities(&self, agent: &str, agent_capabilities: &Value) {
+        if let Ok(mut capabilities) = self.prompt_capabilities.lock() {
+            capabilities.insert(
+                agent.to_string(),
+                PromptCapabilities::from_agent_capabilities(agent_capabilities),
+            );
+        }
+    }
+
+    /// The models to retry a turn on when the agent rejects `model_id`: the
+    /// ones after it in the provider's fallback chain.
+    fn fallback_models(&self, provider_id: &str, model_id: &str) -> VecDeque<String> {
+        self.config
+            .model_fallbacks
+            .get(provider_id)
+            .and_then(|chain| {
+                let position = chain.iter().position(|model| model == model_id)?;
+                Some(chain[position + 1..].iter().cloned().collect())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Mark an assistant message with the fallback model serving the
+    /// session's current turn, if any.
+    async fn annotate_model_fallback(&self, session_id: &str, info: &mut Value) {
+        if let Some(fallback) = self.turn_model_fallbacks.lock().await.get(session_id) {
+            fallback.annotate(info);
+        }
+    }
+
+    /// Start a child session for a tool call that runs one of the sub-agents
+    /// the session's current turn delegated to, recording the delegated
+    /// prompt in it. Returns the child's ID; `None` if the call does not
+    /// start a requested sub-agent.
+    async fn start_subagent_session(
+        &self,
+        session_id: &str,
+        call_id: &str,
+        input: &Value,
+    ) -> Option<String> {
+        let name = agent_parts::started_by(input)?;
+        {
+            let mut pending = self.pending_subagents.lock().await;
+            let names = pending.get_mut(session_id)?;
+            let index = names.iter().position(|pending| pending == name)?;
+            names.remove(index);
+        }
+        let parent = {
+            let projection = self.projection.lock().await;
+            projection.sessions.get(session_id)?.meta.clone()
+        };
+        let id = self.next_id("ses_");
+        let now = self.now_ms();
+        let title = match input.get("description").and_then(Value::as_str) {
+            Some(description) if !description.is_empty() => {
+                format!("{description} (@{name} subagent)")
+            }
+            _ => format!("@{name} subagent"),
+        };
+        let meta = SessionMeta {
+            id: id.clone(),
+            slug: format!("session-{id}"),
+            project_id: self.project_id.clone(),
+            directory: parent.directory.clone(),
+            parent_id: Some(session_id.to_string()),
+            title,
+            version: "0".to_string(),
+            created_at: now,
+            updated_at: now,
+            share_url: None,
+            share_id: None,
+            permission_mode: parent.permission_mode.clone(),
+            agent: parent.agent.clone(),
+            provider_id: parent.provider_id.clone(),
+            model_id: parent.model_id.clone(),
+            agent_session_id: format!("acp_{}", self.next_id("ses_")),
+            last_connection_id: parent.last_connection_id.clone(),
+            session_init_json: parent.session_init_json.clone(),
+            destroyed_at: None,
+            context_files: parent.context_files.clone(),
+            env: parent.env.clone(),
+            dry_run: parent.dry_run,
+            backends: parent.backends.clone(),
+            priority: parent.priority,
+            kind: parent.kind,
+            dormant_at: None,
+            migrated_to: None,
+        };
+        if let Err(err) = self.persist_session(&meta).await {
+            warn!(?err, session_id = %session_id, "failed to persist sub-agent session");
+            return None;
+        }
+        self.track_session_secrets(&meta);
+        self.projection.lock().await.sessions.insert(
+            id.clone(),
             SessionState {
                 meta: meta.clone(),
                 messages: Vec::new(),
-                status: "idle".to_string(),
-                always_permissions: HashSet::new(),
+                lifecycle: SessionLifecycle::Created,
+                always_rules: Vec::new(),
+                pending_context: Vec::new(),
+                archive_key: None,
+            },
+        );
+        let mut info = session_to_value(&meta);
+        info["subagent"] = json!(name);
+        self.emit_event(json!({
+            "type": "session.created",
+            "properties": {
+                "info": info,
+                "backend": backend_mapping(&meta),
+                "parent": {"sessionID": session_id, "callID": call_id},
+            }
+        }));
+
+        let prompt_message_id = self.next_id("msg_");
+        let prompt_info = build_user_message(
+            &id,
+            &prompt_message_id,
+            now,
+            &meta.agent,
+            &meta.provider_id,
+            &meta.model_id,
+            None,
+        );
+        let prompt_parts = agent_parts::delegated_prompt(input)
+            .map(|prompt| {
+                vec![json!({
+                    "id": part_id(&prompt_message_id, 0),
+                    "sessionID": id,
+                    "messageID": prompt_message_id,
+                    "type": "text",
+                    "text": prompt,
+                })]
+            })
+            .unwrap_or_default();
+        self.record_subagent_message(&id, prompt_info, prompt_parts)
+            .await;
+        self.subagent_calls.lock().await.insert(
+            call_id.to_string(),
+            SubagentCall {
+                child_id: id.clone(),
+                prompt_message_id,
             },
         );
+        Some(id)
     }
 
-    let value = session_to_value(&meta);
-    state.emit_event(json!({"type":"session.created","properties":{"info":value}}));
+    /// Record a finished sub-agent tool call's output as the reply in its
+    /// child session. Returns the child's ID if the call started one.
+    async fn finish_subagent_session(
+        &self,
+        call_id: &str,
+        status: Option<&str>,
+        output: Option<&str>,
+    ) -> Option<String> {
+        if !matches!(status, Some("completed" | "failed")) {
+            return self
+                .subagent_calls
+                .lock()
+                .await
+                .get(call_id)
+                .map(|call| call.child_id.clone());
+        }
+        let call = self.subagent_calls.lock().await.remove(call_id)?;
+        let meta = {
+            let projection = self.projection.lock().await;
+            projection.sessions.get(&call.child_id)?.meta.clone()
+        };
+        let message_id = self.next_id("msg_");
+        let now = self.now_ms();
+        let mut info = build_assistant_message(
+            &call.child_id,
+            &message_id,
+            &call.prompt_message_id,
+            now,
+            &meta.directory,
+            &meta.agent,
+            &meta.provider_id,
+            &meta.model_id,
+        );
+        info["time"]["completed"] = json!(now);
+        if status == Some("failed") {
+            info["finish"] = json!("error");
+        }
+        let parts = output
+            .filter(|output| !output.is_empty())
+            .map(|output| {
+                vec![json!({
+                    "id": part_id(&message_id, 0),
+                    "sessionID": call.child_id,
+                    "messageID": message_id,
+                    "type": "text",
+                    "text": output,
+                })]
+            })
+            .unwrap_or_default();
+        self.record_subagent_message(&call.child_id, info, parts)
+            .await;
+        Some(call.child_id)
+    }
 
-    (StatusCode::OK, Json(value)).into_response()
-}
+    async fn record_subagent_message(&self, session_id: &str, info: Value, parts: Vec<Value>) {
+        if let Err(err) = self.persist_parts(session_id, &info, &parts).await {
+            warn!(?err, session_id = %session_id, "failed to persist sub-agent message");
+        }
+        self.emit_event(message_event("message.updated", &info));
+        for part in parts {
+            self.emit_event(json!({
+                "type":"message.part.updated",
+                "properties":{
+                    "sessionID": session_id,
+                    "messageID": info["id"],
+                    "part": part
+                }
+            }));
+        }
+    }
 
-async fn oc_session_list(State(state): State<Arc<AdapterState>>) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+    /// What `agent` accepts in prompts: as reported by its last `initialize`,
+    /// else as known for the agent, else `None` (not checked).
+    fn agent_prompt_capabilities(&self, agent: &str) -> Option<PromptCapabilities> {
+        self.prompt_capabilities
+            .lock()
+            .ok()
+            .and_then(|capabilities| capabilities.get(agent).copied())
+            .or_else(|| PromptCapabilities::known(agent))
     }
 
-    let projection = state.projection.lock().await;
-    let mut values = projection
-        .sessions
-        .values()
-        .map(|session| session_to_value(&session.meta))
-        .collect::<Vec<_>>();
-    values.sort_by(|a, b| {
-        let a_id = a.get("id").and_then(Value::as_str).unwrap_or_default();
-        let b_id = b.get("id").and_then(Value::as_str).unwrap_or_default();
-        a_id.cmp(b_id)
-    });
+    /// The bootstrapped ACP server instances running `agent`, for sessions
+    /// and composer backends alike.
+    async fn agent_server_ids(&self, agent: &str) -> Vec<String> {
+        let candidates: Vec<String> = {
+            let projection = self.projection.lock().await;
+            projection
+                .sessions
+                .values()
+                .flat_map(|session| {
+                    let meta = &session.meta;
+                    let primary = (meta.agent == agent).then(|| meta.agent_session_id.clone());
+                    let backends = meta
+                        .backends
+                        .iter()
+                        .filter(|(_, backend)| backend.agent == agent)
+                        .map(|(target, _)| composer::server_id(&meta.agent_session_id, target));
+                    primary.into_iter().chain(backends).collect::<Vec<_>>()
+                })
+                .collect()
+        };
+        let initialized = self.acp_initialized.lock().await;
+        let mut server_ids: Vec<String> = candidates
+            .into_iter()
+            .filter(|server_id| initialized.contains_key(server_id))
+            .collect();
+        server_ids.sort();
+        server_ids
+    }
+
+    /// Expiry timers for every session, or only for `session_id`.
+    async fn session_timers(&self, session_id: Option<&str>) -> Vec<expiry::SessionTimer> {
+        let expiry = self.config.session_expiry.as_ref();
+        let running_turns: HashSet<String> = self
+            .turns
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, record)| !record.status.is_finished())
+            .map(|(_, record)| record.session_id.clone())
+            .collect();
+        let initialized: HashSet<String> =
+            self.acp_initialized.lock().await.keys().cloned().collect();
+        let projection = self.projection.lock().await;
+        let activity = self
+            .session_activity
+            .lock()
+            .map(|activity| activity.clone())
+            .unwrap_or_default();
+        let mut timers = projection
+            .sessions
+            .values()
+            .filter(|session| session_id.is_none_or(|id| session.meta.id == id))
+            .map(|session| {
+                let meta = &session.meta;
+                let last_activity = activity
+                    .get(&meta.id)
+                    .copied()
+                    .unwrap_or(meta.updated_at)
+                    .max(meta.updated_at);
+                let waiting_on_user = projection
+                    .permissions
+                    .values()
+                    .chain(projection.questions.values())
+                    .any(|request| {
+                        request.get("sessionID").and_then(Value::as_str) == Some(meta.id.as_str())
+                    });
+                let observed = expiry::IdleSession {
+                    last_activity,
+                    settled: matches!(
+                        session.lifecycle,
+                        SessionLifecycle::Created
+                            | SessionLifecycle::Idle
+                            | SessionLifecycle::Errored
+                            | SessionLifecycle::Ended
+                    ) && !running_turns.contains(&meta.id)
+                        && !waiting_on_user,
+                    agent_running: meta
+                        .acp_server_ids()
+                        .iter()
+                        .any(|server_id| initialized.contains(server_id)),
+                };
+                expiry::SessionTimer {
+                    session_id: meta.id.clone(),
+                    last_activity,
+                    expires_at: expiry.and_then(|config| {
+                        let ttl = match meta.kind {
+                            SessionKind::User => config.idle_ttl,
+                            SessionKind::System => config.system_idle_ttl,
+                        };
+                        expiry::expires_at(observed, ttl)
+                    }),
+                    dormant: meta.dormant_at.is_some(),
+                    dormant_at: meta.dormant_at,
+                }
+            })
+            .collect::<Vec<_>>();
+        timers.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+        timers
+    }
 
-    (StatusCode::OK, Json(values)).into_response()
-}
+    /// Forget the ACP server instance `server_id` and stop its agent process,
+    /// if one was started.
+    async fn release_acp_instance(&self, server_id: &str) {
+        self.acp_backends.lock().await.remove(server_id);
+        if let Err(err) = self.delete_acp_binding(server_id).await {
+            warn!(?err, server_id, "failed to delete ACP session binding");
+        }
+        if self
+            .acp_initialized
+            .lock()
+            .await
+            .remove(server_id)
+            .is_none()
+        {
+            return;
+        }
+        if let Some(dispatch) = self.config.acp_dispatch.as_ref() {
+            if let Err(err) = dispatch.delete(server_id).await {
+                warn!(?err, server_id, "failed to delete ACP server instance");
+            }
+        }
+    }
 
-async fn oc_session_get(
-    State(state): State<Arc<AdapterState>>,
-    Path(session_id): Path<String>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+    /// Forget `server_id` when the session still counts it as initialized but
+    /// the dispatch no longer runs it, as after a restart or a crash of the
+    /// agent process. The prompt that notices then bootstraps a fresh
+    /// instance under the same ID, which is told the history so far, instead
+    /// of waiting on a stream that will never answer.
+    async fn forget_lost_instance(&self, session_id: &str, server_id: &str) -> bool {
+        let Some(dispatch) = self.config.acp_dispatch.as_ref() else {
+            return false;
+        };
+        if !self.acp_initialized.lock().await.contains_key(server_id)
+            || dispatch.has_instance(server_id).await
+        {
+            return false;
+        }
+        warn!(
+            session_id,
+            server_id, "agent instance is gone; re-binding the session to a new one"
+        );
+        self.acp_initialized.lock().await.remove(server_id);
+        self.acp_backends.lock().await.remove(server_id);
+        if let Err(err) = self.delete_acp_binding(server_id).await {
+            warn!(?err, server_id, "failed to delete ACP session binding");
+        }
+        let mut pending_replay = self.pending_replay.lock().await;
+        if !pending_replay.contains_key(session_id) {
+            match self.replay_text(session_id).await {
+                Ok(Some(text)) => {
+                    pending_replay.insert(session_id.to_string(), text);
+                }
+                Ok(None) => {}
+                Err(err) => warn!(%err, "failed to collect replay for a re-bound session"),
+            }
+        }
+        true
     }
 
-    let projection = state.projection.lock().await;
-    let Some(session) = projection.sessions.get(&session_id) else {
-        return not_found("Session not found");
-    };
+    async fn ensure_session(
+        &self,
+        session_id: &str,
+        directory: String,
+    ) -> Result<SessionMeta, String> {
+        {
+            let projection = self.projection.lock().await;
+            if let Some(existing) = projection.sessions.get(session_id) {
+                return Ok(existing.meta.clone());
+            }
+        }
 
-    (StatusCode::OK, Json(session_to_value(&session.meta))).into_response()
+        let now = self.now_ms();
+        let connection_id = self.current_connection_for_agent("mock").await;
+        let meta = SessionMeta {
+            id: session_id.to_string(),
+            slug: format!("session-{session_id}"),
+            project_id: self.project_id.clone(),
+            parent_id: None,
+            title: format!("Session {session_id}"),
+            version: "0".to_string(),
+            created_at: now,
+            updated_at: now,
+            share_url: None,
+            share_id: None,
+            permission_mode: None,
+            agent: "mock".to_string(),
+            provider_id: "mock".to_string(),
+            model_id: "mock".to_string(),
+            agent_session_id: format!("acp_{}", self.next_id("ses_")),
+            last_connection_id: connection_id,
+            session_init_json: Some(session_init_json(&directory)),
+            directory,
+            destroyed_at: None,
+            context_files: Vec::new(),
+            env: BTreeMap::new(),
+            dry_run: false,
+            backends: BTreeMap::new(),
+            priority: SessionPriority::Normal,
+            kind: SessionKind::User,
+            dormant_at: None,
+            migrated_to: None,
+        };
+
+        self.persist_session(&meta).await?;
+
+        let session_value = session_to_value(&meta);
+        {
+            let mut projection = self.projection.lock().await;
+            projection.sessions.insert(
+                session_id.to_string(),
+                SessionState {
+                    meta: meta.clone(),
+                    messages: Vec::new(),
+                    lifecycle: SessionLifecycle::Created,
+                    always_rules: Vec::new(),
+                    pending_context: Vec::new(),
+                    archive_key: None,
+                },
+            );
+        }
+
+        self.emit_event(json!({
+            "type": "session.created",
+            "properties": { "info": session_value, "backend": backend_mapping(&meta) }
+        }));
+
+        Ok(meta)
+    }
+}
+
+/// The SQLite database the adapter uses: `configured`, else
+/// `OPENCODE_COMPAT_DB_PATH`, else `opencode-sessions.db` under
+/// `OPENCODE_COMPAT_STATE`, else a fixed path under the system temporary
+/// directory.
+pub fn resolve_sqlite_path(configured: Option<String>) -> String {
+    configured
+        .or_else(|| std::env::var("OPENCODE_COMPAT_DB_PATH").ok())
+        .or_else(|| {
+            std::env::var("OPENCODE_COMPAT_STATE")
+                .ok()
+                .map(|base| paths::join(&base, "opencode-sessions.db"))
+        })
+        .unwrap_or_else(|| paths::join(&paths::temp_dir(), "sandbox-agent-opencode.db"))
 }
 
-async fn oc_session_update(
-    State(state): State<Arc<AdapterState>>,
-    Path(session_id): Path<String>,
-    Json(body): Json<SessionUpdateBody>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
+pub fn build_opencode_router(config: OpenCodeAdapterConfig) -> Result<Router, String> {
+    let proxy_base_url = config
+        .native_proxy_base_url
+        .clone()
+        .or_else(|| std::env::var("OPENCODE_COMPAT_PROXY_URL").ok())
+        .and_then(normalize_proxy_base_url);
+    let share_base_url = config
+        .share_base_url
+        .clone()
+        .or_else(|| std::env::var("OPENCODE_COMPAT_SHARE_BASE_URL").ok())
+        .and_then(normalize_proxy_base_url);
+    let context_files = config.context_files
+        && !std::env::var("OPENCODE_COMPAT_CONTEXT_FILES").is_ok_and(|value| {
+            let value = value.trim();
+            value == "0" || value.eq_ignore_ascii_case("false")
+        });
+    let workspace_snapshots = config.workspace_snapshots
+        || std::env::var("OPENCODE_COMPAT_WORKSPACE_SNAPSHOTS").is_ok_and(|value| {
+            let value = value.trim();
+            value == "1" || value.eq_ignore_ascii_case("true")
+        });
+    let turn_artifacts = config.turn_artifacts
+        && !std::env::var("OPENCODE_COMPAT_TURN_ARTIFACTS").is_ok_and(|value| {
+            let value = value.trim();
+            value == "0" || value.eq_ignore_ascii_case("false")
+        });
+    let strict_schemas = config.strict_schemas
+        || std::env::var("OPENCODE_COMPAT_STRICT_SCHEMAS").is_ok_and(|value| {
+            let value = value.trim();
+            value == "1" || value.eq_ignore_ascii_case("true")
+        });
+    let native_event_max_bytes = std::env::var("OPENCODE_COMPAT_NATIVE_EVENT_MAX_BYTES")
+        .ok()
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .unwrap_or(config.native_event_max_bytes);
+    let part_output_max_bytes = std::env::var("OPENCODE_COMPAT_PART_OUTPUT_MAX_BYTES")
+        .ok()
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .unwrap_or(config.part_output_max_bytes);
+    let heartbeat_interval = interval_override(
+        std::env::var("OPENCODE_COMPAT_HEARTBEAT_MS").ok(),
+        config.heartbeat_interval,
+    );
+    let keep_alive_interval = interval_override(
+        std::env::var("OPENCODE_COMPAT_KEEPALIVE_MS").ok(),
+        config.keep_alive_interval,
+    );
+    let chunk_coalesce_window = std::env::var("OPENCODE_COMPAT_COALESCE_MS")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map_or(config.chunk_coalesce_window, Duration::from_millis);
+    let chunk_coalesce_max_chars = std::env::var("OPENCODE_COMPAT_COALESCE_CHARS")
+        .ok()
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .unwrap_or(config.chunk_coalesce_max_chars);
+    let permission_batch_window = std::env::var("OPENCODE_COMPAT_PERMISSION_BATCH_MS")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map_or(config.permission_batch_window, Duration::from_millis);
+    let model_fallbacks = std::env::var("OPENCODE_COMPAT_MODEL_FALLBACKS")
+        .ok()
+        .and_then(|value| serde_json::from_str(&value).ok())
+        .unwrap_or(config.model_fallbacks);
+    let max_concurrent_turns = std::env::var("OPENCODE_COMPAT_MAX_CONCURRENT_TURNS")
+        .ok()
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .unwrap_or(config.max_concurrent_turns);
+    let turn_resource_interval = std::env::var("OPENCODE_COMPAT_TURN_RESOURCE_MS")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map_or(config.turn_resource_interval, Duration::from_millis);
+    let config = OpenCodeAdapterConfig {
+        native_proxy_base_url: proxy_base_url,
+        share_base_url,
+        context_files,
+        workspace_snapshots,
+        turn_artifacts,
+        strict_schemas,
+        native_event_max_bytes,
+        part_output_max_bytes,
+        heartbeat_interval,
+        keep_alive_interval,
+        chunk_coalesce_window,
+        chunk_coalesce_max_chars,
+        permission_batch_window,
+        model_fallbacks,
+        max_concurrent_turns,
+        turn_resource_interval,
+        ..config
+    };
+
+    let sqlite_path = resolve_sqlite_path(config.sqlite_path.clone());
+    if let Some(lock) = config.database_lock.as_ref() {
+        if lock.db_path() != sqlite_path {
+            return Err(format!(
+                "database lock is for {} but the adapter uses {sqlite_path}",
+                lock.db_path()
+            ));
+        }
+    }
+
+    // A filename rather than a `sqlite://` URL, which cannot carry Windows
+    // drive letters and backslashes.
+    let connect = SqliteConnectOptions::new()
+        .filename(&sqlite_path)
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .foreign_keys(true);
+
+    let (event_broadcaster, _) = broadcast::channel(EVENT_CHANNEL_SIZE);
+    let archive_config = config.archive.clone();
+    let webhook_config = config.webhooks.clone();
+    let max_concurrent_turns = config.max_concurrent_turns;
+    let model_catalog = config.model_catalog.clone().map(|mut catalog| {
+        if catalog.cache_path.is_none() {
+            catalog.cache_path =
+                Some(std::path::Path::new(&sqlite_path).with_file_name("opencode-models.json"));
+        }
+        ModelCatalog::new(catalog)
+    });
+    let project_id = format!("proj_{}", config.clock.now_ms());
+
+    let state = Arc::new(AdapterState {
+        config,
+        sqlite_path,
+        sqlite_connect_options: connect,
+        proxy_http_client: reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .unwrap_or_else(|_| reqwest::Client::new()),
+        pool: OnceCell::new(),
+        initialized: OnceCell::new(),
+        project_id,
+        projection: Mutex::new(Projection::default()),
+        pending_replay: Mutex::new(HashMap::new()),
+        agent_connections: Mutex::new(HashMap::new()),
+        event_broadcaster,
+        event_log: StdMutex::new(VecDeque::new()),
+        next_event_id: AtomicU64::new(1),
+        event_consumers_active: AtomicBool::new(false),
+        event_log_writer: OnceLock::new(),
+        native_subscribers: Arc::new(AtomicUsize::new(0)),
+        part_seq: StdMutex::new(HashMap::new()),
+        session_secrets: StdMutex::new(HashMap::new()),
+        system_sessions: StdMutex::new(HashSet::new()),
+        request_schemas: StdMutex::new(HashMap::new()),
+        turn_model_fallbacks: Mutex::new(HashMap::new()),
+        pending_subagents: Mutex::new(HashMap::new()),
+        subagent_calls: Mutex::new(HashMap::new()),
+        acp_initialized: Mutex::new(HashMap::new()),
+        acp_backends: Mutex::new(HashMap::new()),
+        agent_credentials: Mutex::new(HashMap::new()),
+        prompt_capabilities: StdMutex::new(HashMap::new()),
+        session_activity: StdMutex::new(HashMap::new()),
+        prompt_dispatched_at: StdMutex::new(HashMap::new()),
+        recent_sessions: StdMutex::new(RecentCreations::default()),
+        quota_lock: Mutex::new(()),
+        session_imports: StdMutex::new(SessionImports::default()),
+        acp_request_ids: Mutex::new(HashMap::new()),
+        permission_batches: Mutex::new(PermissionBatches::default()),
+        last_user_message_id: Mutex::new(HashMap::new()),
+        session_todos: Mutex::new(HashMap::new()),
+        turns: Mutex::new(Vec::new()),
+        turn_locks: TurnLocks::default(),
+        turn_slots: TurnSlots::new(max_concurrent_turns),
+        native_circuit: NativeCircuit::default(),
+        turn_finished: Notify::new(),
+        workspaces: Mutex::new(HashMap::new()),
+        archive: archive_config.map(S3Client::new),
+        archive_lock: Mutex::new(()),
+        webhooks: webhook_config.map(WebhookClient::new),
+        model_catalog,
+        background_jobs: Once::new(),
+    });
+
+    start_background_jobs(&state);
+
+    let mut router = Router::new()
+        .route("/agent", get(oc_agent_list))
+        .route("/agents/:agent/authenticate", post(oc_agent_authenticate))
+        .route("/command", get(oc_command_list))
+        .route("/config", get(oc_config_get).patch(oc_config_patch))
+        .route("/config/providers", get(oc_config_providers))
+        .route("/event", get(oc_event_subscribe))
+        .route("/event/poll", get(oc_event_poll))
+        .route("/event/ack", post(oc_event_ack))
+        .route("/admin/export/events", get(oc_admin_export_events))
+        .route("/admin/quota", get(oc_admin_quota))
+        .route("/global/event", get(oc_global_event))
+        .route("/global/health", get(oc_global_health))
+        .route(
+            "/global/config",
+            get(oc_global_config_get).patch(oc_global_config_patch),
+        )
+        .route("/global/dispose", post(oc_global_dispose))
+        .route("/instance/dispose", post(oc_instance_dispose))
+        .route("/path", get(oc_path))
+        .route("/vcs", get(oc_vcs))
+        .route("/mcp", get(oc_mcp_status))
+        .route("/lsp", get(oc_lsp_status))
+        .route("/formatter", get(oc_formatter_status))
+        .route("/experimental/resource", get(oc_experimental_resource))
+        .route("/skill", get(oc_skill_list))
+        .route("/tui/control/next", get(oc_tui_next))
+        .route("/tui/control/response", post(oc_tui_response))
+        .route("/tui/append-prompt", post(oc_tui_append_prompt))
+        .route("/tui/open-help", post(oc_tui_open_help))
+        .route("/tui/open-sessions", post(oc_tui_open_sessions))
+        .route("/tui/open-themes", post(oc_tui_open_themes))
+        .route("/tui/open-models", post(oc_tui_open_models))
+        .route("/tui/submit-prompt", post(oc_tui_submit_prompt))
+        .route("/tui/clear-prompt", post(oc_tui_clear_prompt))
+        .route("/tui/execute-command", post(oc_tui_execute_command))
+        .route("/tui/show-toast", post(oc_tui_show_toast))
+        .route("/tui/publish", post(oc_tui_publish))
+        .route("/project", get(oc_project_list).post(oc_project_current))
+        .route("/project/current", get(oc_project_current))
+        .route("/session", post(oc_session_create).get(oc_session_list))
+        .route("/workspace", get(oc_workspace_list))
+        .route("/workspace/clone", post(oc_workspace_clone))
+        .route("/workspace/:workspaceID", get(oc_workspace_get))
+        .route("/session/status", get(oc_session_status))
+        .route("/session/expiry", get(oc_session_expiry))
+        .route(
+            "/session/:sessionID",
+            get(oc_session_get)
+                .patch(oc_session_update)
+                .delete(oc_session_delete),
+        )
+        .route("/session/:sessionID/abort", post(oc_session_abort))
+        .route("/session/:sessionID/children", get(oc_session_children))
+        .route("/session/:sessionID/init", post(oc_session_init))
+        .route("/session/:sessionID/fork", post(oc_session_fork))
+        .route("/session/:sessionID/migrate", post(oc_session_migrate))
+        .route(
+            "/session/import",
+            post(oc_session_import_prepare)
+                .layer(DefaultBodyLimit::max(migration::MAX_IMPORT_BYTES)),
+        )
+        .route(
+            "/session/import/:importID/commit",
+            post(oc_session_import_commit),
+        )
+        .route("/session/import/:importID", delete(oc_session_import_abort))
+        .route("/session/:sessionID/diff", get(oc_session_diff))
+        .route("/session/:sessionID/todo", get(oc_session_todo))
+        .route("/session/:sessionID/backend", get(oc_session_backend))
+        .route("/session/:sessionID/expiry", get(oc_session_expiry_get))
+        .route("/session/:sessionID/replay", get(oc_session_replay))
+        .route("/session/:sessionID/summarize", post(oc_session_summarize))
+        .route(
+            "/session/:sessionID/share",
+            post(oc_session_share).delete(oc_session_unshare),
+        )
+        .route("/share/:shareID", get(oc_share_get))
+        .route("/compare", get(oc_compare))
+        .route(
+            "/session/:sessionID/context",
+            get(oc_session_context_list).post(oc_session_context_add),
+        )
+        .route("/session/:sessionID/rpc", post(oc_session_rpc))
+        .route(
+            "/session/:sessionID/message",
+            get(oc_session_messages).post(oc_session_prompt),
+        )
+        .route(
+            "/session/:sessionID/message/:messageID",
+            get(oc_session_message_get).delete(oc_session_message_delete),
+        )
+        .route(
+            "/session/:sessionID/message/:messageID/part/:partID",
+            patch(oc_part_update).delete(oc_part_delete),
+        )
+        .route("/part/:partID/full", get(oc_part_full))
+        .route(
+            "/session/:sessionID/prompt_async",
+            post(oc_session_prompt_async),
+        )
+        .route("/session/:sessionID/turn/:turnID", get(oc_session_turn_get))
+        .route(
+            "/session/:sessionID/turn/:turnID/timeline",
+            get(oc_session_turn_timeline),
+        )
+        .route(
+            "/session/:sessionID/turn/:turnID/artifacts",
+            get(oc_session_turn_artifacts),
+        )
+        .route(
+            "/session/:sessionID/turn/:turnID/artifacts/:artifactID",
+            get(oc_session_turn_artifact_download),
+        )
+        .route(
+            "/session/:sessionID/turn/:turnID/rollback",
+            post(oc_session_turn_rollback),
+        )
+        .route(
+            "/session/:sessionID/turn/by-token/:token",
+            get(oc_session_turn_attach),
+        )
+        .route("/session/:sessionID/logs", get(oc_session_logs))
+        .route(
+            "/session/:sessionID/transcript.ndjson",
+            get(oc_session_transcript),
+        )
+        .route("/log", post(oc_log))
+        .route(
+            "/session/:sessionID/permissions/:permissionID",
+            post(oc_permission_respond),
+        )
+        .route("/permission", get(oc_permission_list))
+        .route("/permission/:requestID/reply", post(oc_permission_reply))
+        .route("/question", get(oc_question_list))
+        .route("/question/:requestID/reply", post(oc_question_reply))
+        .route("/question/:requestID/reject", post(oc_question_reject))
+        .route("/file", get(oc_file_list))
+        .route("/file/content", get(oc_file_content))
+        .route("/find", get(oc_find_text))
+        .route("/find/file", get(oc_find_file))
+        .route("/find/symbol", get(oc_find_symbol))
+        .route("/provider", get(oc_provider_list))
+        .route("/provider/auth", get(oc_provider_auth))
+        .route(
+            "/provider/:providerID/oauth/authorize",
+            post(oc_provider_oauth_authorize),
+        )
+        .route(
+            "/provider/:providerID/oauth/callback",
+            post(oc_provider_oauth_callback),
+        )
+        .with_state(state.clone());
+
+    // The router may be built before the server's runtime exists; in that
+    // case the jobs start with the first request instead.
+    if state.archive.is_some()
+        || state.webhooks.is_some()
+        || state.config.session_watchdog.is_some()
+        || state.config.session_expiry.is_some()
+    {
+        router = router.layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            ensure_background_jobs,
+        ));
+    }
+
+    if state.archive.is_some() {
+        router = router.layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            hydrate_session,
+        ));
+    }
+
+    if state.config.native_proxy_base_url.is_some() || state.config.native_proxy_manager.is_some() {
+        router = router.layer(axum::middleware::from_fn(native_fallback_header));
+    }
+
+    if state.config.auth_token.is_some() || state.config.token_verifier.is_some() {
+        router = router.layer(axum::middleware::from_fn_with_state(state, require_token));
+    }
+
+    Ok(router)
+}
+
+/// Spawn the archival and webhook jobs, and resume ACP translation, once a tokio runtime is available.
+fn start_background_jobs(state: &Arc<AdapterState>) {
+    let Ok(handle) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    state.background_jobs.call_once(|| {
+        if state.archive.is_some() {
+            handle.spawn(archive_loop(state.clone()));
+        }
+        if state.webhooks.is_some() {
+            handle.spawn(webhook_loop(state.clone(), state.subscribe()));
+        }
+        if state.config.session_watchdog.is_some() {
+            handle.spawn(watchdog_loop(state.clone()));
+        }
+        if state.config.session_expiry.is_some() {
+            handle.spawn(expiry_loop(state.clone()));
+        }
+        if state.config.acp_dispatch.is_some() {
+            let state = state.clone();
+            handle.spawn(async move {
+                resume_acp_translations(state.clone()).await;
+                prewarm_sessions(state).await;
+            });
+        }
+        if state.config.database_lock.is_some() {
+            handle.spawn(database_fence_loop(state.clone()));
+        }
+        if state
+            .model_catalog
+            .as_ref()
+            .is_some_and(|catalog| catalog.refresh_interval().is_some())
+        {
+            handle.spawn(model_catalog_loop(state.clone()));
+        }
+        if let Some(manager) = state.config.native_proxy_manager.as_ref() {
+            handle.spawn(sidecar_status_loop(
+                state.clone(),
+                manager.subscribe_status(),
+            ));
+        }
+    });
+}
+
+async fn ensure_background_jobs(
+    State(state): State<Arc<AdapterState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    start_background_jobs(&state);
+    next.run(request).await
+}
+
+/// Set `x-sandboxagent-fallback: true` on responses the adapter answered
+/// itself because the native sidecar failed.
+async fn native_fallback_header(request: Request<Body>, next: Next) -> Response {
+    let fallback = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let mut response = NATIVE_FALLBACK
+        .scope(fallback.clone(), next.run(request))
+        .await;
+    if fallback.load(Ordering::Relaxed) {
+        response.headers_mut().insert(
+            HeaderName::from_static(native_proxy::FALLBACK_HEADER),
+            HeaderValue::from_static("true"),
+        );
+    }
+    response
+}
+
+/// Restore an archived session before any `/session/:id` handler reads it.
+async fn hydrate_session(
+    State(state): State<Arc<AdapterState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let mut segments = request
+        .uri()
+        .path()
+        .split('/')
+        .skip_while(|s| *s != "session");
+    if let Some(session_id) = segments.nth(1).filter(|id| !id.is_empty()) {
+        let hydrated = match state.ensure_initialized().await {
+            Ok(()) => state.ensure_hydrated(session_id).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = hydrated {
+            return internal_error(err);
+        }
+    }
+    next.run(request).await
+}
+
+async fn require_token(
+    State(state): State<Arc<AdapterState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Result<Response, Response> {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    let accepted = match (
+        state.config.token_verifier.as_ref(),
+        state.config.auth_token.as_deref(),
+    ) {
+        (Some(verifier), _) => bearer.is_some_and(|token| verifier.verify(token)),
+        (None, Some(expected)) => bearer == Some(expected),
+        (None, None) => true,
+    };
+    if accepted {
+        return Ok(next.run(request).await);
+    }
+
+    Err((
+        StatusCode::UNAUTHORIZED,
+        Json(json!({"errors":[{"message":"missing or invalid bearer token"}]})),
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct DirectoryQuery {
+    directory: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct TurnQuery {
+    /// Wait for the session's running turn instead of failing with 409.
+    #[serde(default)]
+    queue: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct FilePathQuery {
+    path: Option<String>,
+    directory: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FindTextQuery {
+    pattern: Option<String>,
+    directory: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FindFileQuery {
+    query: Option<String>,
+    /// `"false"` leaves directories out; OpenCode sends it as a string.
+    dirs: Option<String>,
+    /// `file` or `directory`; overrides `dirs`.
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    directory: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct SessionRpcBody {
+    method: Option<String>,
+    params: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EventQuery {
+    directory: Option<String>,
+    batch_ms: Option<u64>,
+    /// Comma-separated extras; `native` attaches the agent payload each
+    /// translated event came from.
+    include: Option<String>,
+    /// Comma-separated JSON pointers to keep from each event.
+    select: Option<String>,
+    heartbeat_ms: Option<u64>,
+    keep_alive_ms: Option<u64>,
+    /// Consumer to resume for: delivery starts after the last event it
+    /// acknowledged through `POST /event/ack`.
+    consumer: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EventPollQuery {
+    /// `cursor` of the previous poll. Omitted on the first poll, which waits
+    /// for events emitted after it arrives.
+    since: Option<u64>,
+    wait_ms: Option<u64>,
+    limit: Option<usize>,
+    include: Option<String>,
+    select: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+struct SessionCreateBody {
+    title: Option<String>,
+    #[serde(rename = "parentID")]
+    parent_id: Option<String>,
+    permission: Option<Value>,
+    #[serde(alias = "permission_mode")]
+    permission_mode: Option<String>,
+    /// Transcript to seed the session with, as universal items.
+    #[serde(rename = "initialHistory", alias = "initial_history")]
+    initial_history: Option<Vec<HistoryItem>>,
+    /// Variables for the agent process, and so for the commands its tools run.
+    env: Option<HashMap<String, SessionEnvInput>>,
+    /// Deny side-effecting tools automatically; see `SessionMeta::dry_run`.
+    dry_run: Option<bool>,
+    /// Composer backends by target name; see `SessionMeta::backends`.
+    backends: Option<HashMap<String, BackendInput>>,
+    /// Scheduling priority; see `SessionMeta::priority`.
+    priority: Option<SessionPriority>,
+    /// `system` for sandbox automation; see `SessionMeta::kind`.
+    kind: Option<SessionKind>,
+    /// A workspace from `POST /workspace/clone` to run in; its directory
+    /// becomes the session's.
+    #[serde(rename = "workspaceID")]
+    workspace_id: Option<String>,
+}
+
+/// A universal message item (`{ role, content: ContentPart[] }`) imported
+/// through `initialHistory`.
+#[derive(Debug, Deserialize, JsonSchema)]
+struct HistoryItem {
+    role: String,
+    #[serde(default)]
+    content: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+struct SessionUpdateBody {
+    title: Option<String>,
+    /// Moves an idle session to another directory; its agent is restarted
+    /// there on the next prompt.
+    directory: Option<String>,
+    model: Option<Value>,
+    #[serde(rename = "providerID", alias = "provider_id", alias = "providerId")]
+    provider_id: Option<String>,
+    #[serde(rename = "modelID", alias = "model_id", alias = "modelId")]
+    model_id: Option<String>,
+    dry_run: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+struct SessionInitBody {
+    #[serde(rename = "providerID")]
+    provider_id: Option<String>,
+    #[serde(rename = "modelID")]
+    model_id: Option<String>,
+    #[serde(rename = "messageID")]
+    message_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+struct PromptBody {
+    #[serde(rename = "messageID")]
+    message_id: Option<String>,
+    model: Option<ModelSelection>,
+    #[serde(rename = "providerID", alias = "provider_id", alias = "providerId")]
+    provider_id: Option<String>,
+    #[serde(rename = "modelID", alias = "model_id", alias = "modelId")]
+    model_id: Option<String>,
+    agent: Option<String>,
+    system: Option<String>,
+    /// Reasoning effort: `none`, `low`, `medium`, `high` or `max`.
+    variant: Option<String>,
+    /// Thinking budget in tokens; overrides `variant`.
+    reasoning_budget: Option<u32>,
+    parts: Option<Vec<Value>>,
+    /// Composer backend to run the prompt on; the session's own agent when unset.
+    target: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+struct ModelSelection {
+    #[serde(rename = "providerID", alias = "provider_id", alias = "providerId")]
+    provider_id: Option<String>,
+    #[serde(rename = "modelID", alias = "model_id", alias = "modelId")]
+    model_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+struct SessionContextBody {
+    text: Option<String>,
+    label: Option<String>,
+    source: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct PermissionRespondBody {
+    response: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct PermissionReplyBody {
+    reply: Option<String>,
+    message: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+struct QuestionReplyBody {
+    answers: Option<Vec<Vec<String>>>,
+}
+
+/// `Json<T>` whose body is first checked against the schema generated from
+/// `T`; see [`AdapterState::check_request_body`].
+struct StrictJson<T>(T);
+
+#[axum::async_trait]
+impl<T> FromRequest<Arc<AdapterState>> for StrictJson<T>
+where
+    T: DeserializeOwned + JsonSchema,
+{
+    type Rejection = Response;
+
+    async fn from_request(
+        request: Request<Body>,
+        state: &Arc<AdapterState>,
+    ) -> Result<Self, Response> {
+        let Json(value) = Json::<Value>::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        if let Some(rejection) = state.check_request_body::<T>(&value) {
+            return Err(rejection);
+        }
+        serde_json::from_value(value)
+            .map(Self)
+            .map_err(body_rejection)
+    }
+}
+
+/// A checked body that is not a `T`, rejected with 422 as [`Json`] does.
+fn body_rejection(err: serde_json::Error) -> Response {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        format!("Failed to deserialize the JSON body into the target type: {err}"),
+    )
+        .into_response()
+}
+
+/// [`StrictJson`] for endpoints whose body may be omitted: a missing,
+/// unreadable or `null` body is `None`, while JSON that is not a `T` is
+/// rejected.
+struct OptionalStrictJson<T>(Option<T>);
+
+#[axum::async_trait]
+impl<T> FromRequest<Arc<AdapterState>> for OptionalStrictJson<T>
+where
+    T: DeserializeOwned + JsonSchema,
+{
+    type Rejection = Response;
+
+    async fn from_request(
+        request: Request<Body>,
+        state: &Arc<AdapterState>,
+    ) -> Result<Self, Response> {
+        let Ok(Json(value)) = Json::<Value>::from_request(request, state).await else {
+            return Ok(Self(None));
+        };
+        if value.is_null() {
+            return Ok(Self(None));
+        }
+        if let Some(rejection) = state.check_request_body::<T>(&value) {
+            return Err(rejection);
+        }
+        serde_json::from_value(value)
+            .map(|body| Self(Some(body)))
+            .map_err(body_rejection)
+    }
+}
+
+async fn oc_agent_list(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    (
+        StatusCode::OK,
+        Json(json!([
+            {
+                "name": "Sandbox Agent",
+                "description": "Sandbox Agent compatibility layer",
+                "mode": "all",
+                "native": false,
+                "hidden": false,
+                "permission": [],
+                "options": {},
+            }
+        ])),
+    )
+        .into_response()
+}
+
+/// Authenticate `agent` with one of the `authMethods` it advertised. The
+/// credentials go to every running instance of the agent now and to each new
+/// one as it starts; none are stored if an instance rejects them.
+async fn oc_agent_authenticate(
+    State(state): State<Arc<AdapterState>>,
+    Path(agent): Path<String>,
+    Json(body): Json<AuthenticateParams>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let Some(dispatch) = state.config.acp_dispatch.as_ref() else {
+        return bad_request("ACP agents are not available");
+    };
+    if agent == "mock" || default_for_agent(&agent).is_none() {
+        return not_found("Agent not found");
+    }
+    if body.method_id.trim().is_empty() {
+        return bad_request("methodId is required");
+    }
+
+    let server_ids = state.agent_server_ids(&agent).await;
+    for server_id in &server_ids {
+        let authenticate = AcpCall::Authenticate(body.clone());
+        if let Err(err) = dispatch
+            .call(server_id, None, state.next_id("oc_rpc_"), authenticate)
+            .await
+        {
+            let auth_methods = state
+                .acp_backends
+                .lock()
+                .await
+                .get(server_id)
+                .map(|backend| backend.auth_methods.clone())
+                .unwrap_or_default();
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"errors":[{
+                    "message": err.to_string(),
+                    "name": AgentAuthError::Failed.name(),
+                    "data": {
+                        "agent": agent,
+                        "serverID": server_id,
+                        "authMethods": auth_methods,
+                    },
+                }]})),
+            )
+                .into_response();
+        }
+    }
+    {
+        let mut backends = state.acp_backends.lock().await;
+        for server_id in &server_ids {
+            backends.entry(server_id.clone()).or_default().authenticated =
+                Some(body.method_id.clone());
+        }
+    }
+    let method_id = body.method_id.clone();
+    state
+        .agent_credentials
+        .lock()
+        .await
+        .insert(agent.clone(), body);
+    let properties = json!({
+        "agent": agent,
+        "methodID": method_id,
+        "serverIDs": server_ids,
+    });
+    state.emit_event(json!({"type":"agent.authenticated","properties": properties}));
+    (StatusCode::OK, Json(properties)).into_response()
+}
+
+async fn oc_command_list(State(state): State<Arc<AdapterState>>, headers: HeaderMap) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Some(response) =
+        proxy_native_opencode(&state, reqwest::Method::GET, "/command", &headers, None).await
+    {
+        return response;
+    }
+    (StatusCode::OK, Json(json!([]))).into_response()
+}
+
+/// The directory a `/find` or `/file` request is rooted at: `?directory=`,
+/// then the usual directory fallbacks. It must exist inside the sandbox.
+fn find_root(
+    headers: &HeaderMap,
+    directory: Option<&String>,
+) -> Result<String, SessionDirectoryError> {
+    let directory = resolve_directory(headers, directory);
+    validate_session_directory(&directory)?;
+    Ok(directory)
+}
+
+fn with_raw_query(path: &str, raw_query: Option<&str>) -> String {
+    match raw_query {
+        Some(query) if !query.is_empty() => format!("{path}?{query}"),
+        _ => path.to_string(),
+    }
+}
+
+async fn oc_find_text(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    RawQuery(raw_query): RawQuery,
+    Query(query): Query<FindTextQuery>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let path = with_raw_query("/find", raw_query.as_deref());
+    if let Some(response) =
+        proxy_native_opencode(&state, reqwest::Method::GET, &path, &headers, None).await
+    {
+        return response;
+    }
+    let Some(pattern) = query.pattern.filter(|pattern| !pattern.is_empty()) else {
+        return bad_request("pattern is required");
+    };
+    let root = match find_root(&headers, query.directory.as_ref()) {
+        Ok(root) => root,
+        Err(err) => return bad_request(&err.to_string()),
+    };
+    let limit = query
+        .limit
+        .unwrap_or(find::DEFAULT_TEXT_LIMIT)
+        .clamp(1, find::MAX_LIMIT);
+    let search = tokio::task::spawn_blocking(move || {
+        find::find_text(std::path::Path::new(&root), &pattern, limit)
+    });
+    match search.await {
+        Ok(Ok(matches)) => (StatusCode::OK, Json(json!(matches))).into_response(),
+        Ok(Err(err)) => bad_request(&err),
+        Err(err) => internal_error(err.to_string()),
+    }
+}
+
+async fn oc_find_file(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    RawQuery(raw_query): RawQuery,
+    Query(query): Query<FindFileQuery>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let path = with_raw_query("/find/file", raw_query.as_deref());
+    if let Some(response) =
+        proxy_native_opencode(&state, reqwest::Method::GET, &path, &headers, None).await
+    {
+        return response;
+    }
+    let root = match find_root(&headers, query.directory.as_ref()) {
+        Ok(root) => root,
+        Err(err) => return bad_request(&err.to_string()),
+    };
+    let kind = match (query.kind.as_deref(), query.dirs.as_deref()) {
+        (Some("file"), _) | (None, Some("false")) => find::FindKind::File,
+        (Some("directory"), _) => find::FindKind::Directory,
+        _ => find::FindKind::All,
+    };
+    let search_query = query.query.unwrap_or_default();
+    let limit = query
+        .limit
+        .unwrap_or(find::DEFAULT_FILE_LIMIT)
+        .clamp(1, find::MAX_LIMIT);
+    let search = tokio::task::spawn_blocking(move || {
+        find::find_files(std::path::Path::new(&root), &search_query, kind, limit)
+    });
+    match search.await {
+        Ok(paths) => (StatusCode::OK, Json(json!(paths))).into_response(),
+        Err(err) => internal_error(err.to_string()),
+    }
+}
+
+fn file_error(err: file::FileError) -> Response {
+    let status = match &err {
+        file::FileError::NotFound(_) => StatusCode::NOT_FOUND,
+        file::FileError::OutsideRoot(_) => StatusCode::FORBIDDEN,
+        file::FileError::IsDirectory(_) | file::FileError::NotADirectory(_) => {
+            StatusCode::BAD_REQUEST
+        }
+        file::FileError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        file::FileError::Io { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (
+        status,
+        Json(json!({"errors":[{"message": err.to_string()}]})),
+    )
+        .into_response()
+}
+
+/// List a directory under the session directory as OpenCode `FileNode`s.
+async fn oc_file_list(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    RawQuery(raw_query): RawQuery,
+    Query(query): Query<FilePathQuery>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let path = with_raw_query("/file", raw_query.as_deref());
+    if let Some(response) =
+        proxy_native_opencode(&state, reqwest::Method::GET, &path, &headers, None).await
+    {
+        return response;
+    }
+    let root = match find_root(&headers, query.directory.as_ref()) {
+        Ok(root) => root,
+        Err(err) => return bad_request(&err.to_string()),
+    };
+    let path = query.path.unwrap_or_default();
+    let listing = tokio::task::spawn_blocking(move || {
+        file::list_directory(std::path::Path::new(&root), &path)
+    });
+    match listing.await {
+        Ok(Ok(nodes)) => (StatusCode::OK, Json(json!(nodes))).into_response(),
+        Ok(Err(err)) => file_error(err),
+        Err(err) => internal_error(err.to_string()),
+    }
+}
+
+/// Read a file under the session directory.
+async fn oc_file_content(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    RawQuery(raw_query): RawQuery,
+    Query(query): Query<FilePathQuery>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let path = with_raw_query("/file/content", raw_query.as_deref());
+    if let Some(response) =
+        proxy_native_opencode(&state, reqwest::Method::GET, &path, &headers, None).await
+    {
+        return response;
+    }
+    let Some(path) = query.path.filter(|path| !path.is_empty()) else {
+        return bad_request("path is required");
+    };
+    let root = match find_root(&headers, query.directory.as_ref()) {
+        Ok(root) => root,
+        Err(err) => return bad_request(&err.to_string()),
+    };
+    let read =
+        tokio::task::spawn_blocking(move || file::read_content(std::path::Path::new(&root), &path));
+    match read.await {
+        Ok(Ok(content)) => (StatusCode::OK, Json(content)).into_response(),
+        Ok(Err(err)) => file_error(err),
+        Err(err) => internal_error(err.to_string()),
+    }
+}
+
+/// Symbol search needs a language server; only native OpenCode has one.
+async fn oc_find_symbol(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    RawQuery(raw_query): RawQuery,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let path = with_raw_query("/find/symbol", raw_query.as_deref());
+    if let Some(response) =
+        proxy_native_opencode(&state, reqwest::Method::GET, &path, &headers, None).await
+    {
+        return response;
+    }
+    (StatusCode::OK, Json(json!([]))).into_response()
+}
+
+async fn oc_config_get(State(state): State<Arc<AdapterState>>, headers: HeaderMap) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Some(response) =
+        proxy_native_opencode(&state, reqwest::Method::GET, "/config", &headers, None).await
+    {
+        return response;
+    }
+    (
+        StatusCode::OK,
+        Json(json!({
+            "mcp": {},
+            "agent": {},
+            "provider": {},
+        })),
+    )
+        .into_response()
+}
+
+async fn oc_config_patch(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Some(response) = proxy_native_opencode(
+        &state,
+        reqwest::Method::PATCH,
+        "/config",
+        &headers,
+        Some(body.clone()),
+    )
+    .await
+    {
+        return response;
+    }
+    (StatusCode::OK, Json(body)).into_response()
+}
+
+async fn oc_config_providers(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let providers = provider_payload(&state);
+    let mut payload = providers.clone();
+    if let Some(obj) = payload.as_object_mut() {
+        obj.insert("providers".to_string(), providers["all"].clone());
+    }
+    (StatusCode::OK, Json(payload)).into_response()
+}
+
+async fn oc_event_subscribe(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    Query(query): Query<EventQuery>,
+) -> Response {
+    let _ = state.ensure_initialized().await;
+    let select = match query.select.as_deref().map(EventSelect::parse).transpose() {
+        Ok(select) => select,
+        Err(err) => return bad_request(&err),
+    };
+
+    let directory = resolve_directory(&headers, query.directory.as_ref());
+    let mut gaps = StreamGaps {
+        metrics: state.config.metrics.clone(),
+        ..StreamGaps::default()
+    };
+    let native = state.native_subscription(query.include.as_deref());
+    let include_native = native.is_some();
+    let subscriber = EventSubscriber::new(
+        state.config.metrics.clone(),
+        state.config.clock.clone(),
+        native,
+    );
+    // For a consumer, where its backlog continues in the log once `replay`
+    // has been sent.
+    let mut pages = None;
+    let (replay, receiver) = if let Some(consumer) = query.consumer.as_deref() {
+        if let Err(err) = event_consumers::validate_consumer_id(consumer) {
+            return bad_request(&err);
+        }
+        // Subscribe before reading the backlog so nothing falls between the
+        // two; an event in both is delivered twice.
+        let receiver = state.subscribe();
+        match state.consumer_events(consumer).await {
+            Ok((replay, missed)) => {
+                gaps.mark(missed);
+                pages = next_replay_page(&replay);
+                (replay, receiver)
+            }
+            Err(err) => return internal_error(err),
+        }
+    } else {
+        let last_event_id = parse_last_event_id(&headers);
+        gaps.mark(state.evicted_events_after(last_event_id));
+        (
+            state.buffered_events_after(last_event_id),
+            state.subscribe(),
+        )
+    };
+    // `batchMs` opts into array frames: each SSE `data` is a JSON array of
+    // every event received within the window, and the frame `id` is the id
+    // of the last event it contains.
+    let batch_window = query
+        .batch_ms
+        .filter(|ms| *ms > 0)
+        .map(|ms| Duration::from_millis(ms.min(MAX_EVENT_BATCH_MS)));
+    let heartbeat_interval = query
+        .heartbeat_ms
+        .map_or(state.config.heartbeat_interval, stream_interval);
+    let keep_alive_interval = query
+        .keep_alive_ms
+        .map_or(state.config.keep_alive_interval, stream_interval);
+
+    state.emit_event(json!({"type":"server.connected","properties":{}}));
+    state.emit_event(
+        json!({"type":"worktree.ready","properties":{"name": directory, "branch": "main"}}),
+    );
+
+    let stream = stream::unfold(
+        (
+            receiver,
+            VecDeque::from(replay),
+            pages,
+            interval(heartbeat_interval),
+            gaps,
+            subscriber,
+        ),
+        move |(mut rx, mut replay, mut pages, mut ticker, mut gaps, mut subscriber)| {
+            let select = select.clone();
+            let state = state.clone();
+            async move {
+                subscriber.heartbeat_polled();
+                if let (true, Some(after)) = (replay.is_empty(), pages) {
+                    match state.logged_events_after(after, CONSUMER_REPLAY_PAGE).await {
+                        Ok((page, missed)) => {
+                            gaps.mark(missed);
+                            pages = next_replay_page(&page);
+                            replay.extend(page);
+                        }
+                        Err(err) => {
+                            warn!(%err, "failed to read the consumer backlog");
+                            pages = None;
+                        }
+                    }
+                }
+                if let Some(notice) = gaps.take_notice() {
+                    let notice = select_event(select.as_ref(), notice);
+                    let data = if batch_window.is_some() {
+                        json!([notice])
+                    } else {
+                        notice
+                    };
+                    let evt = Event::default()
+                        .json_data(data)
+                        .unwrap_or_else(|_| Event::default().data("{}"));
+                    return Some((
+                        Ok::<_, Infallible>(evt),
+                        (rx, replay, pages, ticker, gaps, subscriber),
+                    ));
+                }
+
+                if let Some(window) = batch_window {
+                    if !replay.is_empty() {
+                        let take = replay.len().min(MAX_EVENT_BATCH_SIZE);
+                        let mut batch = replay.drain(..take).collect::<Vec<_>>();
+                        batch
+                            .iter_mut()
+                            .for_each(|event| gaps.annotate(&mut event.payload));
+                        return Some((
+                            Ok(batch_frame(batch, include_native, select.as_ref())),
+                            (rx, replay, pages, ticker, gaps, subscriber),
+                        ));
+                    }
+
+                    tokio::select! {
+                        due = ticker.tick() => {
+                            let evt = Event::default().json_data(json!([select_event(select.as_ref(), subscriber.heartbeat(due))]))
+                                .unwrap_or_else(|_| Event::default().data("[]"));
+                            return Some((Ok(evt), (rx, replay, pages, ticker, gaps, subscriber)));
+                        }
+                        item = rx.recv() => {
+                            match item {
+                                Ok(first) => {
+                                    let mut batch = vec![first];
+                                    let deadline = tokio::time::Instant::now() + window;
+                                    while batch.len() < MAX_EVENT_BATCH_SIZE {
+                                        match tokio::time::timeout_at(deadline, rx.recv()).await {
+                                            Ok(Ok(next)) => batch.push(next),
+                                            // Flush what arrived before the drop; the
+                                            // gap notice goes out as the next frame.
+                                            Ok(Err(broadcast::error::RecvError::Lagged(missed))) => {
+                                                gaps.lagged(missed);
+                                                break;
+                                            }
+                                            Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => break,
+                                        }
+                                    }
+                                    batch.iter_mut().for_each(|event| gaps.annotate(&mut event.payload));
+                                    return Some((Ok(batch_frame(batch, include_native, select.as_ref())), (rx, replay, pages, ticker, gaps, subscriber)));
+                                }
+                                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                                    gaps.lagged(missed);
+                                    let notice = gaps.take_notice().unwrap_or(Value::Null);
+                                    let evt = Event::default()
+                                        .json_data(json!([select_event(select.as_ref(), notice)]))
+                                        .unwrap_or_else(|_| Event::default().data("[]"));
+                                    return Some((Ok(evt), (rx, replay, pages, ticker, gaps, subscriber)));
+                                }
+                                Err(broadcast::error::RecvError::Closed) => return None,
+                            }
+                        }
+                    }
+                }
+
+                if let Some(mut item) = replay.pop_front() {
+                    gaps.annotate(&mut item.payload);
+                    let evt = Event::default()
+                        .id(item.id.to_string())
+                        .json_data(select_event(
+                            select.as_ref(),
+                            item.into_payload(include_native),
+                        ))
+                        .unwrap_or_else(|_| Event::default().data("{}"));
+                    return Some((Ok(evt), (rx, replay, pages, ticker, gaps, subscriber)));
+                }
+
+                tokio::select! {
+                    due = ticker.tick() => {
+                        let evt = Event::default().json_data(select_event(select.as_ref(), subscriber.heartbeat(due)))
+                            .unwrap_or_else(|_| Event::default().data("{}"));
+                        Some((Ok(evt), (rx, replay, pages, ticker, gaps, subscriber)))
+                    }
+                    item = rx.recv() => {
+                        match item {
+                            Ok(mut payload) => {
+                                gaps.annotate(&mut payload.payload);
+                                let evt = Event::default()
+                                    .id(payload.id.to_string())
+                                    .json_data(select_event(select.as_ref(), payload.into_payload(include_native)))
+                                    .unwrap_or_else(|_| Event::default().data("{}"));
+                                Some((Ok(evt), (rx, replay, pages, ticker, gaps, subscriber)))
+                            }
+                            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                                gaps.lagged(missed);
+                                let notice = gaps.take_notice().unwrap_or(Value::Null);
+                                let evt = Event::default()
+                                    .json_data(select_event(select.as_ref(), notice))
+                                    .unwrap_or_else(|_| Event::default().data("{}"));
+                                Some((Ok(evt), (rx, replay, pages, ticker, gaps, subscriber)))
+                            }
+                            Err(broadcast::error::RecvError::Closed) => None,
+                        }
+                    }
+                }
+            }
+        },
+    );
+
+    Sse::new(stream)
+        .keep_alive(KeepAlive::new().interval(keep_alive_interval))
+        .into_response()
+}
+
+/// Record the last event a consumer has processed; `/event?consumer=`
+/// resumes after it. Acknowledging an older event than before is a no-op.
+async fn oc_event_ack(
+    State(state): State<Arc<AdapterState>>,
+    StrictJson(body): StrictJson<AckBody>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Err(err) = event_consumers::validate_consumer_id(&body.consumer) {
+        return bad_request(&err);
+    }
+    if body.id > state.last_event_id() {
+        return bad_request(&format!(
+            "event {} has not been emitted; the newest event is {}",
+            body.id,
+            state.last_event_id()
+        ));
+    }
+    let pool = match state.pool().await {
+        Ok(pool) => pool,
+        Err(err) => return internal_error(err),
+    };
+    state.event_consumers_active.store(true, Ordering::Relaxed);
+    match event_consumers::ack(pool, &body.consumer, body.id, state.now_ms()).await {
+        Ok(acked) => (
+            StatusCode::OK,
+            Json(json!({
+                "consumer": body.consumer,
+                "ackedID": acked,
+                "pending": state.last_event_id().saturating_sub(acked),
+            })),
+        )
+            .into_response(),
+        Err(err) => internal_error(err.to_string()),
+    }
+}
+
+/// Long-poll alternative to `/event` for clients that cannot hold an SSE
+/// connection. Returns the events after `since` from the persisted log, or
+/// waits up to `waitMs` for the next ones, along with the `cursor` to pass
+/// as `since` on the next poll.
+async fn oc_event_poll(
+    State(state): State<Arc<AdapterState>>,
+    Query(query): Query<EventPollQuery>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    // Pollers resume from the persisted log, which keeps event IDs
+    // increasing across restarts.
+    state.event_consumers_active.store(true, Ordering::Relaxed);
+    let receiver = state.subscribe();
+    let since = query.since.unwrap_or_else(|| state.last_event_id());
+    let limit = query
+        .limit
+        .unwrap_or(MAX_EVENT_BATCH_SIZE)
+        .clamp(1, MAX_EVENT_BATCH_SIZE);
+    let wait = Duration::from_millis(
+        query
+            .wait_ms
+            .unwrap_or(DEFAULT_EVENT_POLL_WAIT_MS)
+            .min(MAX_EVENT_POLL_WAIT_MS),
+    );
+    let native = state.native_subscription(query.include.as_deref());
+    let include_native = native.is_some();
+    let select = match query.select.as_deref().map(EventSelect::parse).transpose() {
+        Ok(select) => select,
+        Err(err) => return bad_request(&err),
+    };
+
+    let (mut events, missed) = match state.logged_events_after(since, limit).await {
+        Ok(logged) => logged,
+        Err(err) => return internal_error(err),
+    };
+    if events.is_empty() && !wait.is_zero() {
+        events = wait_for_events(&state, receiver, since, limit, wait).await;
+    }
+    events.truncate(limit);
+
+    let mut gaps = StreamGaps {
+        metrics: state.config.metrics.clone(),
+        ..StreamGaps::default()
+    };
+    if query.since.is_some() {
+        gaps.mark(missed);
+    }
+    let cursor = events.last().map_or(since, |event| event.id);
+    let mut payloads: Vec<Value> = gaps.take_notice().into_iter().collect();
+    payloads.extend(events.into_iter().map(|mut event| {
+        gaps.annotate(&mut event.payload);
+        event.into_payload(include_native)
+    }));
+    if let Some(select) = select.as_ref() {
+        payloads
+            .iter_mut()
+            .for_each(|payload| *payload = select.apply(payload));
+    }
+    (
+        StatusCode::OK,
+        Json(json!({"events": payloads, "cursor": cursor})),
+    )
+        .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct EventExportQuery {
+    /// Export events created after this time (ms); the watermark of the
+    /// previous pull.
+    since: Option<i64>,
+}
+
+/// Bulk export of the persisted event log for analytics pipelines: every
+/// event created after `since`, across sessions, as zstd-compressed
+/// newline-delimited JSON streamed straight from SQLite. The watermark header
+/// is the `since` of the next incremental pull. Archived sessions no longer
+/// have a local log and are not included.
+async fn oc_admin_export_events(
+    State(state): State<Arc<AdapterState>>,
+    Query(query): Query<EventExportQuery>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let pool = match state.pool().await {
+        Ok(pool) => pool.clone(),
+        Err(err) => return internal_error(err),
+    };
+    let since = query.since.unwrap_or(0).max(0);
+    // Events persisted during this millisecond may not be written yet, so
+    // the export stops before it and the next pull starts there.
+    let watermark = (state.now_ms() - 1).max(since);
+    let mask_state = state.clone();
+    let body = event_export::export_events(pool, since, watermark, move |payload| {
+        mask_state.mask_session_secrets(payload)
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/zstd")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"events-{since}-{watermark}.ndjson.zst\""),
+        )
+        .header(EXPORT_WATERMARK_HEADER, watermark)
+        .body(Body::from_stream(body))
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
+/// The session quota's limits (`null` when off) and current usage.
+async fn oc_admin_quota(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let (usage, _) = match state.quota_usage().await {
+        Ok(usage) => usage,
+        Err(err) => return internal_error(err),
+    };
+    let limits = state
+        .config
+        .session_quota
+        .clone()
+        .unwrap_or_default()
+        .limits();
+    (
+        StatusCode::OK,
+        Json(json!({"limits": limits, "usage": usage})),
+    )
+        .into_response()
+}
+
+/// Where the next page of a consumer's backlog starts: after a full page
+/// there may be more.
+fn next_replay_page(page: &[OpenCodeStreamEvent]) -> Option<u64> {
+    if page.len() < CONSUMER_REPLAY_PAGE {
+        return None;
+    }
+    page.last().map(|event| event.id)
+}
+
+/// Wait up to `wait` for events after `since`, returning the first one and
+/// any that follow it without further waiting.
+async fn wait_for_events(
+    state: &AdapterState,
+    mut receiver: broadcast::Receiver<OpenCodeStreamEvent>,
+    since: u64,
+    limit: usize,
+    wait: Duration,
+) -> Vec<OpenCodeStreamEvent> {
+    let deadline = tokio::time::Instant::now() + wait;
+    let mut events = Vec::new();
+    while events.is_empty() {
+        match tokio::time::timeout_at(deadline, receiver.recv()).await {
+            Ok(Ok(event)) if event.id > since => events.push(event),
+            Ok(Ok(_)) => {}
+            // The buffer still holds what the receiver skipped.
+            Ok(Err(broadcast::error::RecvError::Lagged(_))) => {
+                return state.buffered_events_after(Some(since));
+            }
+            Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => return events,
+        }
+    }
+    while events.len() < limit {
+        match receiver.try_recv() {
+            Ok(event) => events.push(event),
+            Err(broadcast::error::TryRecvError::Lagged(_)) => {
+                return state.buffered_events_after(Some(since));
+            }
+            Err(_) => break,
+        }
+    }
+    events
+}
+
+async fn oc_global_event(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    Query(query): Query<EventQuery>,
+) -> Response {
+    oc_event_subscribe(State(state), headers, Query(query)).await
+}
+
+async fn oc_global_health(State(state): State<Arc<AdapterState>>) -> Response {
+    let sqlite = state.sqlite_health().await;
+    let sqlite_ok = sqlite.get("status").and_then(Value::as_str) == Some("ok");
+    let sqlite_slow = sqlite
+        .get("latencyMs")
+        .and_then(Value::as_u64)
+        .is_some_and(|latency| latency > HEALTH_SQLITE_SLOW_MS);
+
+    let (sessions, archived_sessions, permissions, questions) = {
+        let projection = state.projection.lock().await;
+        (
+            projection.sessions.len(),
+            projection
+                .sessions
+                .values()
+                .filter(|session| session.archive_key.is_some())
+                .count(),
+            projection.permissions.len(),
+            projection.questions.len(),
+        )
+    };
+    let buffered_events = state.event_log.lock().map(|log| log.len()).unwrap_or(0);
+    let agent_requests = state.acp_request_ids.lock().await.len();
+
+    let now = state.now_ms();
+    let instances = match state.config.acp_dispatch.as_ref() {
+        Some(dispatch) => dispatch.instances().await,
+        None => Vec::new(),
+    };
+    let mut per_agent = serde_json::Map::new();
+    for instance in &instances {
+        let count = per_agent
+            .get(&instance.agent)
+            .and_then(Value::as_u64)
+            .unwrap_or(0);
+        per_agent.insert(instance.agent.clone(), json!(count + 1));
+    }
+    let processes = instances
+        .iter()
+        .map(|instance| {
+            json!({
+                "serverId": instance.server_id,
+                "agent": instance.agent,
+                "ageMs": now.saturating_sub(instance.created_at_ms),
+                "pid": instance.pid,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut sidecar = state.native_sidecar_health().await;
+    if sidecar.get("status").and_then(Value::as_str) != Some("not_configured") {
+        sidecar["circuit"] = state.native_circuit.to_value();
+    }
+    let sidecar_degraded = matches!(
+        sidecar.get("status").and_then(Value::as_str),
+        Some("unreachable" | "restarting" | "failed")
+    ) || state.native_circuit.is_open();
+
+    let status = if !sqlite_ok {
+        "unhealthy"
+    } else if sqlite_slow || sidecar_degraded {
+        "degraded"
+    } else {
+        "healthy"
+    };
+    let code = if status == "unhealthy" {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+
+    (
+        code,
+        Json(json!({
+            "healthy": status != "unhealthy",
+            "status": status,
+            "version": env!("CARGO_PKG_VERSION"),
+            "checks": {
+                "sqlite": sqlite,
+                "eventLog": {
+                    "persistedEvents": sqlite.get("events").cloned().unwrap_or(Value::Null),
+                    "bufferedEvents": buffered_events,
+                    "sessions": sessions,
+                    "archivedSessions": archived_sessions,
+                },
+                "agents": {
+                    "counts": per_agent,
+                    "processes": processes,
+                },
+                "pending": {
+                    "permissions": permissions,
+                    "questions": questions,
+                    "agentRequests": agent_requests,
+                },
+                "sse": {
+                    "subscribers": state.event_broadcaster.receiver_count(),
+                },
+                "nativeSidecar": sidecar,
+            },
+        })),
+    )
+        .into_response()
+}
+
+async fn oc_global_config_get(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(response) = proxy_native_opencode(
+        &state,
+        reqwest::Method::GET,
+        "/global/config",
+        &headers,
+        None,
+    )
+    .await
+    {
+        return response;
+    }
+    oc_config_get(State(state), headers).await
+}
+
+async fn oc_global_config_patch(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    if let Some(response) = proxy_native_opencode(
+        &state,
+        reqwest::Method::PATCH,
+        "/global/config",
+        &headers,
+        Some(body.clone()),
+    )
+    .await
+    {
+        return response;
+    }
+    oc_config_patch(State(state), headers, Json(body)).await
+}
+
+async fn oc_global_dispose() -> Response {
+    bool_ok(true).into_response()
+}
+
+async fn oc_instance_dispose() -> Response {
+    bool_ok(true).into_response()
+}
+
+async fn oc_path(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    Query(query): Query<DirectoryQuery>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+
+    let directory = resolve_directory(&headers, query.directory.as_ref());
+    (
+        StatusCode::OK,
+        Json(json!({
+            "home": paths::home_dir(),
+            "state": std::env::var("OPENCODE_COMPAT_STATE").unwrap_or_else(|_| paths::temp_dir()),
+            "config": std::env::var("OPENCODE_COMPAT_CONFIG").unwrap_or_else(|_| paths::temp_dir()),
+            "worktree": directory,
+            "directory": resolve_directory(&headers, query.directory.as_ref()),
+        })),
+    )
+        .into_response()
+}
+
+async fn oc_vcs(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    (StatusCode::OK, Json(json!({"branch":"main"}))).into_response()
+}
+
+async fn oc_mcp_status(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    (StatusCode::OK, Json(json!({}))).into_response()
+}
+
+async fn oc_lsp_status(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    (StatusCode::OK, Json(json!([]))).into_response()
+}
+
+async fn oc_formatter_status(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    (StatusCode::OK, Json(json!([]))).into_response()
+}
+
+async fn oc_experimental_resource(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    (StatusCode::OK, Json(json!([]))).into_response()
+}
+
+async fn oc_skill_list() -> Response {
+    (StatusCode::OK, Json(json!([]))).into_response()
+}
+
+async fn oc_tui_next(State(state): State<Arc<AdapterState>>, headers: HeaderMap) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Some(response) = proxy_native_opencode(
+        &state,
+        reqwest::Method::GET,
+        "/tui/control/next",
+        &headers,
+        None,
+    )
+    .await
+    {
+        return response;
+    }
+    (StatusCode::OK, Json(json!({"path": "", "body": {}}))).into_response()
+}
+
+async fn oc_tui_response(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    body: Option<Json<Value>>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Some(response) = proxy_native_opencode(
+        &state,
+        reqwest::Method::POST,
+        "/tui/control/response",
+        &headers,
+        body.map(|json| json.0),
+    )
+    .await
+    {
+        return response;
+    }
+    bool_ok(true).into_response()
+}
+
+async fn oc_tui_append_prompt(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    body: Option<Json<Value>>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Some(response) = proxy_native_opencode(
+        &state,
+        reqwest::Method::POST,
+        "/tui/append-prompt",
+        &headers,
+        body.map(|json| json.0),
+    )
+    .await
+    {
+        return response;
+    }
+    bool_ok(true).into_response()
+}
+
+async fn oc_tui_open_help(State(state): State<Arc<AdapterState>>, headers: HeaderMap) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Some(response) = proxy_native_opencode(
+        &state,
+        reqwest::Method::POST,
+        "/tui/open-help",
+        &headers,
+        None,
+    )
+    .await
+    {
+        return response;
+    }
+    bool_ok(true).into_response()
+}
+
+async fn oc_tui_open_sessions() -> Response {
+    bool_ok(true).into_response()
+}
+
+async fn oc_tui_open_themes(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Some(response) = proxy_native_opencode(
+        &state,
+        reqwest::Method::POST,
+        "/tui/open-themes",
+        &headers,
+        None,
+    )
+    .await
+    {
+        return response;
+    }
+    bool_ok(true).into_response()
+}
+
+async fn oc_tui_open_models(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Some(response) = proxy_native_opencode(
+        &state,
+        reqwest::Method::POST,
+        "/tui/open-models",
+        &headers,
+        None,
+    )
+    .await
+    {
+        return response;
+    }
+    bool_ok(true).into_response()
+}
+
+async fn oc_tui_submit_prompt(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    body: Option<Json<Value>>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Some(response) = proxy_native_opencode(
+        &state,
+        reqwest::Method::POST,
+        "/tui/submit-prompt",
+        &headers,
+        body.map(|json| json.0),
+    )
+    .await
+    {
+        return response;
+    }
+    bool_ok(true).into_response()
+}
+
+async fn oc_tui_clear_prompt(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Some(response) = proxy_native_opencode(
+        &state,
+        reqwest::Method::POST,
+        "/tui/clear-prompt",
+        &headers,
+        None,
+    )
+    .await
+    {
+        return response;
+    }
+    bool_ok(true).into_response()
+}
+
+async fn oc_tui_execute_command(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    body: Option<Json<Value>>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Some(response) = proxy_native_opencode(
+        &state,
+        reqwest::Method::POST,
+        "/tui/execute-command",
+        &headers,
+        body.map(|json| json.0),
+    )
+    .await
+    {
+        return response;
+    }
+    bool_ok(true).into_response()
+}
+
+async fn oc_tui_show_toast(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    body: Option<Json<Value>>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Some(response) = proxy_native_opencode(
+        &state,
+        reqwest::Method::POST,
+        "/tui/show-toast",
+        &headers,
+        body.map(|json| json.0),
+    )
+    .await
+    {
+        return response;
+    }
+    bool_ok(true).into_response()
+}
+
+async fn oc_tui_publish(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    body: Option<Json<Value>>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Some(response) = proxy_native_opencode(
+        &state,
+        reqwest::Method::POST,
+        "/tui/publish",
+        &headers,
+        body.map(|json| json.0),
+    )
+    .await
+    {
+        return response;
+    }
+    bool_ok(true).into_response()
+}
+
+async fn oc_project_list(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    Query(query): Query<DirectoryQuery>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let directory = resolve_directory(&headers, query.directory.as_ref());
+    let now = state.now_ms();
+    (
+        StatusCode::OK,
+        Json(json!([{
+            "id": state.project_id,
+            "worktree": directory,
+            "vcs": "git",
+            "name": "sandbox-agent",
+            "time": {"created": now, "updated": now},
+        }])),
+    )
+        .into_response()
+}
+
+async fn oc_project_current(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    Query(query): Query<DirectoryQuery>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let directory = resolve_directory(&headers, query.directory.as_ref());
+    let now = state.now_ms();
+    (
+        StatusCode::OK,
+        Json(json!({
+            "id": state.project_id,
+            "worktree": directory,
+            "vcs": "git",
+            "name": "sandbox-agent",
+            "time": {"created": now, "updated": now},
+        })),
+    )
+        .into_response()
+}
+
+/// Check the session quota before a session is created. The guard, held
+/// until the session is recorded, keeps concurrent creations from taking the
+/// same slot; `None` without a quota.
+async fn admit_session(
+    state: &AdapterState,
+) -> Result<Option<tokio::sync::MutexGuard<'_, ()>>, Response> {
+    let Some(config) = state.config.session_quota.as_ref() else {
+        return Ok(None);
+    };
+    let guard = state.quota_lock.lock().await;
+    let (usage, oldest_recent) = state.quota_usage().await.map_err(internal_error)?;
+    if let Err(exceeded) = config.check(&usage, oldest_recent, state.now_ms()) {
+        warn!(limit = ?exceeded.limit, max = exceeded.max, "session quota exceeded");
+        state.emit_event(json!({
+            "type": "quota.exceeded",
+            "properties": exceeded,
+        }));
+        return Err(quota_exceeded(&exceeded));
+    }
+    Ok(Some(guard))
+}
+
+async fn oc_session_create(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    Query(query): Query<DirectoryQuery>,
+    OptionalStrictJson(body): OptionalStrictJson<SessionCreateBody>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+
+    let body = body.unwrap_or(SessionCreateBody {
+        title: None,
+        parent_id: None,
+        permission: None,
+        permission_mode: None,
+        initial_history: None,
+        env: None,
+        dry_run: None,
+        backends: None,
+        priority: None,
+        kind: None,
+        workspace_id: None,
+    });
+    let initial_history = body.initial_history.unwrap_or_default();
+    if let Some(item) = initial_history
+        .iter()
+        .find(|item| !matches!(item.role.as_str(), "user" | "assistant" | "system" | "tool"))
+    {
+        return bad_request(&format!(
+            "initialHistory role must be user, assistant, system, or tool (got '{}')",
+            item.role
+        ));
+    }
+
+    let env = match session_env::parse_session_env(body.env.unwrap_or_default()) {
+        Ok(env) => env,
+        Err(err) => return bad_request(&err),
+    };
+    let backends =
+        match composer::parse_backends(body.backends.unwrap_or_default(), default_for_agent) {
+            Ok(backends) => backends,
+            Err(err) => return bad_request(&err),
+        };
+
+    let directory = match body.workspace_id.as_deref() {
+        Some(workspace_id) => match state.workspaces.lock().await.get(workspace_id) {
+            Some(workspace) if workspace.status == WorkspaceStatus::Ready => {
+                workspace.directory.clone()
+            }
+            Some(workspace) => {
+                return conflict(&format!(
+                    "workspace {workspace_id} is {}",
+                    json!(workspace.status).as_str().unwrap_or_default()
+                ));
+            }
+            None => return bad_request(&format!("workspace {workspace_id} not found")),
+        },
+        None => resolve_directory(&headers, query.directory.as_ref()),
+    };
+    if let Err(err) = validate_session_directory(&directory) {
+        return bad_request(&err.to_string());
+    }
+    let _admitted = match admit_session(&state).await {
+        Ok(guard) => guard,
+        Err(response) => return response,
+    };
+    let id = state.next_id("ses_");
+    let now = state.now_ms();
+
+    let default_agent = "mock";
+    let connection_id = state.current_connection_for_agent(default_agent).await;
+    let meta = SessionMeta {
+        id: id.clone(),
+        slug: format!("session-{id}"),
+        project_id: state.project_id.clone(),
+        directory: directory.clone(),
+        parent_id: body.parent_id,
+        title: body.title.unwrap_or_else(|| format!("Session {id}")),
+        version: "0".to_string(),
+        created_at: now,
+        updated_at: now,
+        share_url: None,
+        share_id: None,
+        permission_mode: body.permission_mode,
+        agent: default_agent.to_string(),
+        provider_id: default_agent.to_string(),
+        model_id: default_model_for_provider(default_agent)
+            .unwrap_or("default")
+            .to_string(),
+        agent_session_id: format!("acp_{}", state.next_id("ses_")),
+        last_connection_id: connection_id,
+        session_init_json: Some(session_init_json(&directory)),
+        destroyed_at: None,
+        context_files: Vec::new(),
+        env,
+        dry_run: body.dry_run.unwrap_or(false),
+        backends,
+        priority: body.priority.unwrap_or_default(),
+        kind: body.kind.unwrap_or_default(),
+        dormant_at: None,
+        migrated_to: None,
+    };
+
+    if let Err(err) = state.persist_session(&meta).await {
+        return internal_error(err);
+    }
+    state.track_session_secrets(&meta);
+    state.record_session_created(&meta);
+
+    {
+        let mut projection = state.projection.lock().await;
+        projection.sessions.insert(
+            id,
+            SessionState {
+                meta: meta.clone(),
+                messages: Vec::new(),
+                lifecycle: SessionLifecycle::Created,
+                always_rules: Vec::new(),
+                pending_context: Vec::new(),
+                archive_key: None,
+            },
+        );
+    }
+
+    let value = session_to_value(&meta);
+    state.emit_event(json!({
+        "type": "session.created",
+        "properties": { "info": value, "backend": backend_mapping(&meta) }
+    }));
+
+    if !initial_history.is_empty() {
+        if let Err(err) = import_initial_history(&state, &meta, &initial_history).await {
+            return internal_error(err);
+        }
+    }
+
+    (StatusCode::OK, Json(value)).into_response()
+}
+
+/// Persist an imported transcript as ordinary messages, which the session's
+/// first prompt replays to the agent.
+async fn import_initial_history(
+    state: &Arc<AdapterState>,
+    meta: &SessionMeta,
+    history: &[HistoryItem],
+) -> Result<(), String> {
+    let now = state.now_ms();
+    // callID -> (message index, part index) so results fold into their call.
+    let mut tool_parts: HashMap<String, (usize, usize)> = HashMap::new();
+    let mut messages: Vec<(Value, Vec<Value>)> = Vec::new();
+    let mut parent_id = String::new();
+
+    for item in history {
+        let message_id = state.next_id("msg_");
+        let mut info = if matches!(item.role.as_str(), "user" | "system") {
+            build_user_message(
+                &meta.id,
+                &message_id,
+                now,
+                &meta.agent,
+                &meta.provider_id,
+                &meta.model_id,
+                None,
+            )
+        } else {
+            build_completed_assistant_message(
+                &meta.id,
+                &message_id,
+                &parent_id,
+                now,
+                &meta.directory,
+                &meta.agent,
+                &meta.provider_id,
+                &meta.model_id,
+            )
+        };
+        info["imported"] = json!(true);
+        if info["role"] == "user" {
+            parent_id = message_id.clone();
+        }
+
+        let mut parts: Vec<Value> = Vec::new();
+        for content in &item.content {
+            let field = |key: &str| {
+                content
+                    .get(key)
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let mut part = match content.get("type").and_then(Value::as_str) {
+                Some("text") => json!({"type": "text", "text": field("text")}),
+                Some("reasoning") => json!({
+                    "type": "reasoning",
+                    "text": field("text"),
+                    "time": {"start": now, "end": now},
+                }),
+                Some("tool_call") => {
+                    let arguments = field("arguments");
+                    tool_parts.insert(field("call_id"), (messages.len(), parts.len()));
+                    json!({
+                        "type": "tool",
+                        "callID": field("call_id"),
+                        "tool": field("name"),
+                        "state": {
+                            "status": "completed",
+                            "input": serde_json::from_str::<Value>(&arguments)
+                                .unwrap_or_else(|_| json!({"arguments": arguments})),
+                            "output": "",
+                            "title": field("name"),
+                            "metadata": {},
+                            "time": {"start": now, "end": now},
+                        },
+                    })
+                }
+                Some("tool_result") => {
+                    let target = match tool_parts.get(&field("call_id")) {
+                        Some(&(message_index, part_index)) if message_index == messages.len() => {
+                            parts.get_mut(part_index)
+                        }
+                        Some(&(message_index, part_index)) => messages
+                            .get_mut(message_index)
+                            .and_then(|(_, parts)| parts.get_mut(part_index)),
+                        None => None,
+                    };
+                    if let Some(tool_part) = target {
+                        tool_part["state"]["output"] = json!(field("output"));
+                        continue;
+                    }
+                    json!({"type": "text", "text": field("output")})
+                }
+                Some("file_ref") | Some("image") => {
+                    let path = field("path");
+                    json!({
+                        "type": "file",
+                        "mime": content
+                            .get("mime")
+                            .and_then(Value::as_str)
+                            .unwrap_or("text/plain"),
+                        "filename": path.rsplit('/').next().unwrap_or_default(),
+                        "url": format!("file://{path}"),
+                    })
+                }
+                Some("retry") => build_retry_part(
+                    content.get("attempt").and_then(Value::as_u64).unwrap_or(1),
+                    &field("error"),
+                    now,
+                ),
+                Some("status") => continue,
+                _ => json!({"type": "text", "text": content.to_string()}),
+            };
+            part["id"] = json!(part_id(&message_id, parts.len() as u64));
+            part["sessionID"] = json!(meta.id);
+            part["messageID"] = json!(message_id);
+            if item.role == "system" {
+                part["synthetic"] = json!(true);
+            }
+            parts.push(part);
+        }
+        messages.push((info, parts));
+    }
+
+    for (info, parts) in messages {
+        let envelope = json!({
+            "jsonrpc": "2.0",
+            "method": "_sandboxagent/opencode/message",
+            "params": {"message": {"info": info, "parts": parts}},
+        });
+        state.persist_event(&meta.id, "client", &envelope).await?;
+        state.emit_event(message_event("message.updated", &info));
+        for part in parts {
+            state.emit_event(json!({
+                "type": "message.part.updated",
+                "properties": {
+                    "sessionID": meta.id,
+                    "messageID": info["id"],
+                    "part": part,
+                }
+            }));
+        }
+    }
+
+    Ok(())
+}
+
+/// `?kind=` on `GET /session`: `user` (the default), `system` or `all`.
+#[derive(Debug, Default, Deserialize)]
+struct SessionListQuery {
+    kind: Option<String>,
+}
+
+impl SessionListQuery {
+    /// The kind of sessions listed, or `None` for every kind.
+    fn kind(&self) -> Result<Option<SessionKind>, String> {
+        match self.kind.as_deref() {
+            None | Some("user") => Ok(Some(SessionKind::User)),
+            Some("system") => Ok(Some(SessionKind::System)),
+            Some("all") => Ok(None),
+            Some(other) => Err(format!(
+                "unknown session kind `{other}`; expected user, system or all"
+            )),
+        }
+    }
+}
+
+/// Clone a repository into the sandbox in the background. Returns the
+/// workspace at once with status `cloning`; progress follows as
+/// `workspace.clone_progress` events, then `workspace.cloned` or
+/// `workspace.clone_failed`.
+async fn oc_workspace_clone(
+    State(state): State<Arc<AdapterState>>,
+    headers: HeaderMap,
+    StrictJson(body): StrictJson<CloneRequest>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let default_root = resolve_directory(&headers, None);
+    let plan = match ClonePlan::new(body, &default_root) {
+        Ok(plan) => plan,
+        Err(err) => return bad_request(&err),
+    };
+    let directory = plan.directory.display().to_string();
+    let workspace = {
+        let mut workspaces = state.workspaces.lock().await;
+        if workspaces.values().any(|workspace| {
+            workspace.directory == directory && workspace.status == WorkspaceStatus::Cloning
+        }) {
+            return conflict(&format!("already cloning into {directory}"));
+        }
+        let workspace = Workspace {
+            id: state.next_id("wks_"),
+            url: plan.display_url(),
+            reference: plan.reference().map(str::to_string),
+            directory,
+            status: WorkspaceStatus::Cloning,
+            commit: None,
+            error: None,
+            created_at: state.now_ms(),
+            finished_at: None,
+        };
+        workspaces.insert(workspace.id.clone(), workspace.clone());
+        workspace
+    };
+    if let Err(err) = state.persist_workspace(&workspace).await {
+        state.workspaces.lock().await.remove(&workspace.id);
+        return internal_error(err);
+    }
+
+    let task_state = state.clone();
+    let workspace_id = workspace.id.clone();
+    tokio::spawn(async move {
+        let mut last: Option<(String, Option<u8>)> = None;
+        let result = plan
+            .run(|progress| {
+                // Report each phase, then every 10% of it.
+                let step = progress.percent.map(|percent| percent / 10);
+                if last.as_ref().is_some_and(|(phase, last_step)| {
+                    *phase == progress.phase && *last_step == step
+                }) {
+                    return;
+                }
+                last = Some((progress.phase.clone(), step));
+                task_state.emit_event(json!({
+                    "type": "workspace.clone_progress",
+                    "properties": {
+                        "workspaceID": workspace_id,
+                        "phase": progress.phase,
+                        "percent": progress.percent,
+                    }
+                }));
+            })
+            .await;
+        let mut workspaces = task_state.workspaces.lock().await;
+        let Some(workspace) = workspaces.get_mut(&workspace_id) else {
+            return;
+        };
+        workspace.finished_at = Some(task_state.now_ms());
+        match &result {
+            Ok(commit) => {
+                workspace.status = WorkspaceStatus::Ready;
+                workspace.commit = Some(commit.clone());
+            }
+            Err(err) => {
+                workspace.status = WorkspaceStatus::Failed;
+                workspace.error = Some(err.clone());
+            }
+        }
+        // Persisted before it is reported, so a restart keeps what was seen.
+        if let Err(err) = task_state.persist_workspace(workspace).await {
+            warn!(workspace_id, error = %err, "failed to persist workspace");
+        }
+        match result {
+            Ok(_) => {
+                task_state.emit_event(json!({
+                    "type": "workspace.cloned",
+                    "properties": {"workspaceID": workspace_id, "workspace": workspace}
+                }));
+            }
+            Err(err) => {
+                warn!(workspace_id, error = %err, "workspace clone failed");
+                task_state.emit_event(json!({
+                    "type": "workspace.clone_failed",
+                    "properties": {"workspaceID": workspace_id, "error": err}
+                }));
+            }
+        }
+    });
+
+    (StatusCode::ACCEPTED, Json(workspace)).into_response()
+}
+
+async fn oc_workspace_list(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let mut workspaces = state
+        .workspaces
+        .lock()
+        .await
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    workspaces.sort_by(|a, b| a.id.cmp(&b.id));
+    (StatusCode::OK, Json(workspaces)).into_response()
+}
+
+async fn oc_workspace_get(
+    State(state): State<Arc<AdapterState>>,
+    Path(workspace_id): Path<String>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    match state.workspaces.lock().await.get(&workspace_id) {
+        Some(workspace) => (StatusCode::OK, Json(workspace.clone())).into_response(),
+        None => not_found("Workspace not found"),
+    }
+}
+
+async fn oc_session_list(
+    State(state): State<Arc<AdapterState>>,
+    OriginalUri(uri): OriginalUri,
+    Query(page): Query<PageQuery>,
+    Query(list): Query<SessionListQuery>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let kind = match list.kind() {
+        Ok(kind) => kind,
+        Err(err) => return bad_request(&err),
+    };
+    if page.is_paged() {
+        return session_list_page(&state, &uri, &page, kind).await;
+    }
+
+    let projection = state.projection.lock().await;
+    let mut values = projection
+        .sessions
+        .values()
+        .filter(|session| kind.is_none_or(|kind| session.meta.kind == kind))
+        .map(|session| session_to_value(&session.meta))
+        .collect::<Vec<_>>();
+    values.sort_by(|a, b| {
+        let a_id = a.get("id").and_then(Value::as_str).unwrap_or_default();
+        let b_id = b.get("id").and_then(Value::as_str).unwrap_or_default();
+        a_id.cmp(b_id)
+    });
+
+    (StatusCode::OK, Json(values)).into_response()
+}
+
+/// One page of `GET /session`, ordered by creation time. The page is read
+/// from SQLite so only its sessions are looked up in the projection.
+async fn session_list_page(
+    state: &AdapterState,
+    uri: &Uri,
+    page: &PageQuery,
+    kind: Option<SessionKind>,
+) -> Response {
+    let after = match page.after() {
+        Ok(after) => after,
+        Err(err) => return bad_request(&err),
+    };
+    let limit = page.limit();
+    let pool = match state.pool().await {
+        Ok(pool) => pool,
+        Err(err) => return internal_error(err),
+    };
+    let kind = kind.map(SessionKind::as_str);
+    let total: i64 = match sqlx::query_scalar(
+        r#"SELECT COUNT(*) FROM sessions s
+           JOIN opencode_session_metadata m ON m.session_id = s.id
+           WHERE ?1 IS NULL
+              OR COALESCE(json_extract(m.metadata_json, '$.kind'), 'user') = ?1"#,
+    )
+    .bind(kind)
+    .fetch_one(pool)
+    .await
+    {
+        Ok(total) => total,
+        Err(err) => return internal_error(err.to_string()),
+    };
+    let after = after.unwrap_or(PageCursor {
+        created_at: i64::MIN,
+        id: String::new(),
+    });
+    let rows = match sqlx::query(
+        r#"SELECT s.id, s.created_at FROM sessions s
+           JOIN opencode_session_metadata m ON m.session_id = s.id
+           WHERE (s.created_at > ?1 OR (s.created_at = ?1 AND s.id > ?2))
+             AND (?4 IS NULL
+                  OR COALESCE(json_extract(m.metadata_json, '$.kind'), 'user') = ?4)
+           ORDER BY s.created_at ASC, s.id ASC
+           LIMIT ?3"#,
+    )
+    .bind(after.created_at)
+    .bind(&after.id)
+    .bind((limit + 1) as i64)
+    .bind(kind)
+    .fetch_all(pool)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(err) => return internal_error(err.to_string()),
+    };
+    let mut cursors = Vec::with_capacity(rows.len());
+    for row in rows {
+        match (row.try_get("created_at"), row.try_get("id")) {
+            (Ok(created_at), Ok(id)) => cursors.push(PageCursor { created_at, id }),
+            (Err(err), _) | (_, Err(err)) => return internal_error(err.to_string()),
+        }
+    }
+    let next = (cursors.len() > limit).then(|| cursors[limit - 1].clone());
+    cursors.truncate(limit);
+
+    let projection = state.projection.lock().await;
+    let values = cursors
+        .iter()
+        .filter_map(|cursor| projection.sessions.get(&cursor.id))
+        .map(|session| session_to_value(&session.meta))
+        .collect::<Vec<_>>();
+    drop(projection);
+    paged_response(values, total.max(0) as usize, uri, next.as_ref())
+}
+
+/// A listing page with `X-Total-Count` and, when more items follow, a
+/// `Link: <...>; rel="next"` header.
+fn paged_response(
+    values: Vec<Value>,
+    total: usize,
+    uri: &Uri,
+    next: Option<&PageCursor>,
+) -> Response {
+    let mut response = (StatusCode::OK, Json(values)).into_response();
+    let headers = response.headers_mut();
+    headers.insert(
+        HeaderName::from_static("x-total-count"),
+        HeaderValue::from(total),
+    );
+    if let Some(next) = next {
+        if let Ok(link) = HeaderValue::from_str(&page::next_link(uri.path(), uri.query(), next)) {
+            headers.insert(header::LINK, link);
+        }
+    }
+    response
+}
+
+async fn oc_session_get(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+
+    let projection = state.projection.lock().await;
+    let Some(session) = projection.sessions.get(&session_id) else {
+        return not_found("Session not found");
+    };
+
+    (StatusCode::OK, Json(session_to_value(&session.meta))).into_response()
+}
+
+async fn oc_session_update(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    StrictJson(body): StrictJson<SessionUpdateBody>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+
+    if body.model.is_some() || body.provider_id.is_some() || body.model_id.is_some() {
+        return bad_request(MODEL_CHANGE_ERROR);
+    }
+    if let Some(directory) = body.directory.as_deref() {
+        if let Err(err) = validate_session_directory(directory) {
+            return bad_request(&err.to_string());
+        }
+    }
+
+    let (meta, released_server_ids) = {
+        let mut projection = state.projection.lock().await;
+        let Some(session) = projection.sessions.get_mut(&session_id) else {
+            return not_found("Session not found");
+        };
+
+        let mut released_server_ids = Vec::new();
+        if let Some(directory) = body
+            .directory
+            .filter(|directory| *directory != session.meta.directory)
+        {
+            if session.lifecycle.status_type() == "busy" {
+                return conflict("Session is busy; wait for the turn to finish or abort it");
+            }
+            // The agent's cwd is fixed when its session starts, so the move
+            // takes fresh agent sessions, seeded with the transcript.
+            released_server_ids = session.meta.acp_server_ids();
+            session.meta.agent_session_id = format!("acp_{}", state.next_id("ses_"));
+            session.meta.session_init_json = Some(match session.meta.session_init_json.take() {
+                Some(mut init) if init.is_object() => {
+                    init["cwd"] = json!(directory);
+                    init
+                }
+                _ => session_init_json(&directory),
+            });
+            session.meta.directory = directory;
+            session.meta.updated_at = state.now_ms();
+        }
+
+        if let Some(title) = body.title {
+            session.meta.title = title;
+            session.meta.updated_at = state.now_ms();
+        }
+        if let Some(dry_run) = body.dry_run {
+            session.meta.dry_run = dry_run;
+            session.meta.updated_at = state.now_ms();
+        }
+
+        (session.meta.clone(), released_server_ids)
+    };
+
+    if let Err(err) = state.persist_session(&meta).await {
+        return internal_error(err);
+    }
+    if !released_server_ids.is_empty() {
+        for server_id in &released_server_ids {
+            state.release_acp_instance(server_id).await;
+        }
+        match state.replay_text(&session_id).await {
+            Ok(Some(text)) => {
+                state
+                    .pending_replay
+                    .lock()
+                    .await
+                    .insert(session_id.clone(), text);
+            }
+            Ok(None) => {}
+            Err(err) => warn!(%err, "failed to collect replay after directory change"),
+        }
+    }
+
+    let value = session_to_value(&meta);
+    state.emit_event(json!({"type":"session.updated","properties":{"info":value}}));
+    (StatusCode::OK, Json(value)).into_response()
+}
+
+/// Share a session. With an explicit native proxy the request passes through
+/// and the returned `share.url` is mirrored locally; otherwise the adapter
+/// issues its own link backed by `GET /share/:shareID`. Sharing an already
+/// shared session returns the existing link.
+async fn oc_session_share(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if state.config.native_proxy_base_url.is_some() {
+        let path = format!("/session/{session_id}/share");
+        if let Some(result) =
+            proxy_native_opencode_json(&state, reqwest::Method::POST, &path, &headers, None).await
+        {
+            return match result {
+                Ok((status, body)) => {
+                    if status.is_success() {
+                        let share_url = body
+                            .pointer("/share/url")
+                            .and_then(Value::as_str)
+                            .map(ToOwned::to_owned);
+                        if let Err(err) =
+                            set_session_share(&state, &session_id, share_url, None).await
+                        {
+                            warn!(%err, "failed to mirror native share URL");
+                        }
+                    }
+                    (status, Json(body)).into_response()
+                }
+                Err(response) => response,
+            };
+        }
+    }
+
+    let existing = {
+        let projection = state.projection.lock().await;
+        let Some(session) = projection.sessions.get(&session_id) else {
+            return not_found("Session not found");
+        };
+        session.meta.share_url.clone()
+    };
+    if existing.is_some() {
+        return oc_session_get(State(state), Path(session_id)).await;
+    }
+
+    let share_id = match random_token() {
+        Ok(share_id) => share_id,
+        Err(err) => return internal_error(err),
+    };
+    let base_url = state
+        .config
+        .share_base_url
+        .clone()
+        .unwrap_or_else(|| request_base_url(&headers));
+    let share_url = format!("{base_url}/share/{share_id}");
+    match set_session_share(&state, &session_id, Some(share_url), Some(share_id)).await {
+        Ok(Some(value)) => (StatusCode::OK, Json(value)).into_response(),
+        Ok(None) => not_found("Session not found"),
+        Err(err) => internal_error(err),
+    }
+}
+
+async fn oc_session_unshare(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if state.config.native_proxy_base_url.is_some() {
+        let path = format!("/session/{session_id}/share");
+        if let Some(result) =
+            proxy_native_opencode_json(&state, reqwest::Method::DELETE, &path, &headers, None).await
+        {
+            return match result {
+                Ok((status, body)) => {
+                    if status.is_success() {
+                        if let Err(err) = set_session_share(&state, &session_id, None, None).await {
+                            warn!(%err, "failed to clear mirrored share URL");
+                        }
+                    }
+                    (status, Json(body)).into_response()
+                }
+                Err(response) => response,
+            };
+        }
+    }
+
+    match set_session_share(&state, &session_id, None, None).await {
+        Ok(Some(value)) => (StatusCode::OK, Json(value)).into_response(),
+        Ok(None) => not_found("Session not found"),
+        Err(err) => internal_error(err),
+    }
+}
+
+/// Read-only view of a locally shared session: its info and messages.
+async fn oc_share_get(
+    State(state): State<Arc<AdapterState>>,
+    Path(share_id): Path<String>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let session_id = {
+        let projection = state.projection.lock().await;
+        projection
+            .sessions
+            .values()
+            .find(|session| session.meta.share_id.as_deref() == Some(share_id.as_str()))
+            .map(|session| session.meta.id.clone())
+    };
+    let Some(session_id) = session_id else {
+        return not_found("Share not found");
+    };
+    if let Err(err) = state.ensure_hydrated(&session_id).await {
+        return internal_error(err);
+    }
+
+    let projection = state.projection.lock().await;
+    let Some(session) = projection.sessions.get(&session_id) else {
+        return not_found("Share not found");
+    };
+    let messages = session
+        .messages
+        .iter()
+        .map(|record| json!({"info": record.info, "parts": record.parts}))
+        .collect::<Vec<_>>();
+    (
+        StatusCode::OK,
+        Json(json!({"info": session_to_value(&session.meta), "messages": messages})),
+    )
+        .into_response()
+}
+
+/// Update a session's share link, persist it, and emit `session.updated`.
+/// Returns `None` when the session does not exist.
+async fn set_session_share(
+    state: &Arc<AdapterState>,
+    session_id: &str,
+    share_url: Option<String>,
+    share_id: Option<String>,
+) -> Result<Option<Value>, String> {
+    let meta = {
+        let mut projection = state.projection.lock().await;
+        let Some(session) = projection.sessions.get_mut(session_id) else {
+            return Ok(None);
+        };
+        session.meta.share_url = share_url;
+        session.meta.share_id = share_id;
+        session.meta.updated_at = state.now_ms();
+        session.meta.clone()
+    };
+    state.persist_session(&meta).await?;
+
+    let value = session_to_value(&meta);
+    state.emit_event(json!({"type":"session.updated","properties":{"info":value}}));
+    Ok(Some(value))
+}
+
+/// 128 random bits, hex encoded.
+fn random_token() -> Result<String, String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(|err| err.to_string())?;
+    Ok(hex::encode(bytes))
+}
+
+/// The adapter's base URL as seen by the client, from `Host` and
+/// `X-Forwarded-Proto`. The adapter is mounted at `/opencode`.
+fn request_base_url(headers: &HeaderMap) -> String {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("localhost");
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("http");
+    format!("{scheme}://{host}/opencode")
+}
+
+async fn oc_session_delete(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+
+    let removed = {
+        let mut projection = state.projection.lock().await;
+        projection.permissions.retain(|_, value| {
+            value
+                .get("sessionID")
+                .and_then(Value::as_str)
+                .map(|id| id != session_id)
+                .unwrap_or(true)
+        });
+        projection.questions.retain(|_, value| {
+            value
+                .get("sessionID")
+                .and_then(Value::as_str)
+                .map(|id| id != session_id)
+                .unwrap_or(true)
+        });
+        projection.sessions.remove(&session_id)
+    };
+
+    let Some(session) = removed else {
+        return not_found("Session not found");
+    };
+
+    if let Err(err) = state.delete_session(&session_id).await {
+        return internal_error(err);
+    }
+    logs::session_logs().remove(&session_id);
+    if let Ok(mut tracked) = state.session_secrets.lock() {
+        tracked.remove(&session_id);
+    }
+    if let Ok(mut system) = state.system_sessions.lock() {
+        system.remove(&session_id);
+    }
+    if let Ok(mut counters) = state.part_seq.lock() {
+        counters.remove(&session_id);
+    }
+
+    // Clean up the ACP server instances created for this session.
+    for server_id in session.meta.acp_server_ids() {
+        state.release_acp_instance(&server_id).await;
+    }
+
+    // Clean up any pending ACP requests for this session.
+    state
+        .acp_request_ids
+        .lock()
+        .await
+        .retain(|_, req| req.opencode_session_id != session_id);
+
+    // The session row and its event log are gone, so the terminal transition
+    // is only announced, not persisted.
+    state.emit_event(json!({
+        "type":"session.lifecycle",
+        "properties": {
+            "sessionID": session_id,
+            "from": session.lifecycle,
+            "to": SessionLifecycle::Ended,
+            "reason": "deleted",
+        }
+    }));
+
+    let value = session_to_value(&session.meta);
+    state.emit_event(json!({"type":"session.deleted","properties":{"info":value}}));
+
+    (StatusCode::OK, Json(json!(true))).into_response()
+}
+
+/// `POST /session/:id/migrate`: move the session to another instance. The
+/// session is frozen like a running turn while it is exported, staged on the
+/// target and committed there; once the target holds it, the session ends
+/// here with `migratedTo` set and its agent stopped.
+async fn oc_session_migrate(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    StrictJson(body): StrictJson<MigrateBody>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let target = match migration::target_url(&body.target) {
+        Ok(target) => target,
+        Err(err) => return bad_request(&err),
+    };
+    let migrated_to = {
+        let projection = state.projection.lock().await;
+        match projection.sessions.get(&session_id) {
+            Some(session) => session.meta.migrated_to.clone(),
+            None => return not_found("Session not found"),
+        }
+    };
+    if let Some(migrated_to) = migrated_to {
+        return session_migrated(&session_id, &migrated_to);
+    }
+    if let Err(err) = state.ensure_hydrated(&session_id).await {
+        return internal_error(err);
+    }
+
+    let migration_id = state.next_id("migration_");
+    // Prompts queued behind the migration find the session migrated.
+    let _lock = match state.turn_locks.claim(&session_id, &migration_id, false) {
+        Ok(lock) => lock,
+        Err(err) => return TurnStartError::from(err).into_response(),
+    };
+    migrate_session(&state, &session_id, &target, body.token.as_deref()).await
+}
+
+async fn migrate_session(
+    state: &Arc<AdapterState>,
+    session_id: &str,
+    target: &str,
+    token: Option<&str>,
+) -> Response {
+    let migration = match state.session_migration(session_id).await {
+        Ok(migration) => migration,
+        Err(err) => return internal_error(err),
+    };
+    let client = MigrationClient::new(&state.proxy_http_client, target, token);
+    let import_id = match client.prepare(&migration).await {
+        Ok(import_id) => import_id,
+        Err(err) => return migration_failed(session_id, target, &err),
+    };
+    if let Err(err) = client.commit(&import_id).await {
+        match client.abort(&import_id).await {
+            Ok(AbortOutcome::Committed) => {}
+            Ok(AbortOutcome::Aborted) => return migration_failed(session_id, target, &err),
+            Err(abort_err) => {
+                warn!(%session_id, %import_id, %abort_err, "failed to abort a session import");
+                return migration_failed(session_id, target, &format!("{err}; {abort_err}"));
+            }
+        }
+    }
+
+    let meta = match state.hand_over_session(session_id, target).await {
+        Ok(meta) => meta,
+        Err(err) => return internal_error(err),
+    };
+    if let Err(err) =
+        transition_session(state, session_id, SessionLifecycle::Ended, "migrated").await
+    {
+        warn!(?err, "failed to persist the end of a migrated session");
+    }
+    tracing::info!(%session_id, %target, %import_id, "migrated session");
+    state.emit_event(json!({
+        "type": "session.updated",
+        "properties": { "info": session_to_value(&meta) }
+    }));
+    state.emit_event(json!({
+        "type": "session.migrated",
+        "properties": { "sessionID": session_id, "target": target, "importID": import_id }
+    }));
+    (
+        StatusCode::OK,
+        Json(json!({"sessionID": session_id, "target": target, "importID": import_id})),
+    )
+        .into_response()
+}
+
+/// `POST /session/import`: stage a session migrating in from another
+/// instance. It is served once `POST /session/import/:importID/commit`
+/// arrives.
+async fn oc_session_import_prepare(
+    State(state): State<Arc<AdapterState>>,
+    StrictJson(migration): StrictJson<SessionMigration>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if migration.version != migration::MIGRATION_VERSION {
+        return bad_request(&format!(
+            "unsupported session migration version {}",
+            migration.version
+        ));
+    }
+    let meta = match serde_json::from_value::<SessionMeta>(migration.session.clone()) {
+        Ok(meta) => meta,
+        Err(err) => return bad_request(&format!("invalid session: {err}")),
+    };
+    if state
+        .projection
+        .lock()
+        .await
+        .sessions
+        .contains_key(&meta.id)
+    {
+        return conflict(&format!("Session {} already exists", meta.id));
+    }
+    let _admitted = match admit_session(&state).await {
+        Ok(admitted) => admitted,
+        Err(response) => return response,
+    };
+    let import_id = state.next_id("import_");
+    let staged = match state.session_imports.lock() {
+        Ok(mut imports) => imports.stage(&import_id, &meta.id, migration, state.now_ms()),
+        Err(_) => Err("session imports are unavailable".to_string()),
+    };
+    if let Err(err) = staged {
+        return conflict(&err);
+    }
+    (
+        StatusCode::OK,
+        Json(json!({"importID": import_id, "sessionID": meta.id})),
+    )
+        .into_response()
+}
+
+/// `POST /session/import/:importID/commit`: make a staged session live.
+/// Committing an import again returns the session it committed.
+async fn oc_session_import_commit(
+    State(state): State<Arc<AdapterState>>,
+    Path(import_id): Path<String>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let (staged, committed) = match state.session_imports.lock() {
+        Ok(mut imports) => (
+            imports.take(&import_id),
+            imports.committed_session(&import_id).map(str::to_string),
+        ),
+        Err(_) => return internal_error("session imports are unavailable".to_string()),
+    };
+    let Some((session_id, migration)) = staged else {
+        let meta = match committed {
+            Some(session_id) => state
+                .projection
+                .lock()
+                .await
+                .sessions
+                .get(&session_id)
+                .map(|session| session.meta.clone()),
+            None => None,
+        };
+        return match meta {
+            Some(meta) => (StatusCode::OK, Json(session_to_value(&meta))).into_response(),
+            None => not_found("Import not found"),
+        };
+    };
+    if state
+        .projection
+        .lock()
+        .await
+        .sessions
+        .contains_key(&session_id)
+    {
+        return conflict(&format!("Session {session_id} already exists"));
+    }
+    let meta = match state.import_session(migration).await {
+        Ok(meta) => meta,
+        Err(err) => return internal_error(err),
+    };
+    if let Ok(mut imports) = state.session_imports.lock() {
+        imports.committed(&import_id, &session_id);
+    }
+    let value = session_to_value(&meta);
+    state.emit_event(json!({
+        "type": "session.created",
+        "properties": { "info": value, "backend": backend_mapping(&meta) }
+    }));
+    (StatusCode::OK, Json(value)).into_response()
+}
+
+/// `DELETE /session/import/:importID`: drop a staged import. 409 when it was
+/// already committed.
+async fn oc_session_import_abort(
+    State(state): State<Arc<AdapterState>>,
+    Path(import_id): Path<String>,
+) -> Response {
+    let Ok(mut imports) = state.session_imports.lock() else {
+        return internal_error("session imports are unavailable".to_string());
+    };
+    if imports.take(&import_id).is_some() {
+        return (StatusCode::OK, Json(json!(true))).into_response();
+    }
+    match imports.committed_session(&import_id) {
+        Some(session_id) => conflict(&format!(
+            "Import {import_id} was already committed as session {session_id}"
+        )),
+        None => not_found("Import not found"),
+    }
+}
+
+async fn oc_session_status(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let projection = state.projection.lock().await;
+    let mut map = serde_json::Map::new();
+    for (id, session) in &projection.sessions {
+        map.insert(
+            id.clone(),
+            json!({
+                "type": session.lifecycle.status_type(),
+                "state": session.lifecycle,
+            }),
+        );
+    }
+    (StatusCode::OK, Json(Value::Object(map))).into_response()
+}
+
+async fn oc_session_abort(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+
+    let in_flight = {
+        let mut projection = state.projection.lock().await;
+        let Some(session) = projection.sessions.get(&session_id) else {
+            return not_found("Session not found");
+        };
+        let in_flight = session.lifecycle.status_type() == "busy";
+        projection.permissions.retain(|_, value| {
+            value.get("sessionID").and_then(Value::as_str) != Some(session_id.as_str())
+        });
+        projection.questions.retain(|_, value| {
+            value.get("sessionID").and_then(Value::as_str) != Some(session_id.as_str())
+        });
+        in_flight
+    };
+
+    if in_flight {
+        if let Err(err) =
+            transition_session(&state, &session_id, SessionLifecycle::Cancelling, "abort").await
+        {
+            warn!(?err, "failed to record abort lifecycle transition");
+        }
+    }
+
+    // Send session/cancel to the ACP agents if dispatch is available: the
+    // session's own and any composer backend that may be running the turn.
+    if let Some(dispatch) = state.config.acp_dispatch.as_ref() {
+        let server_ids = {
+            let projection = state.projection.lock().await;
+            projection
+                .sessions
+                .get(&session_id)
+                .map(|s| s.meta.acp_server_ids())
+                .unwrap_or_default()
+        };
+        for server_id in server_ids {
+            let acp_session_id = state.acp_initialized.lock().await.get(&server_id).cloned();
+            if let Some(acp_sid) = acp_session_id {
+                let cancel = AcpCall::SessionCancel(SessionCancelParams {
+                    session_id: acp_sid,
+                    meta: None,
+                    extra: Default::default(),
+                });
+                if let Err(err) = dispatch
+                    .call(&server_id, None, state.next_id("oc_rpc_"), cancel)
+                    .await
+                {
+                    warn!(?err, "failed to send session/cancel to ACP agent");
+                }
+            }
+        }
+    }
+
+    if in_flight {
+        if let Err(err) =
+            transition_session(&state, &session_id, SessionLifecycle::Idle, "cancelled").await
+        {
+            warn!(?err, "failed to record abort lifecycle transition");
+        }
+    }
+
+    (StatusCode::OK, Json(json!(true))).into_response()
+}
+
+/// Sessions forked from, or started as sub-agents of, a session.
+async fn oc_session_children(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let projection = state.projection.lock().await;
+    let mut children = projection
+        .sessions
+        .values()
+        .filter(|session| session.meta.parent_id.as_deref() == Some(session_id.as_str()))
+        .map(|session| &session.meta)
+        .collect::<Vec<_>>();
+    children.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+    let values = children
+        .into_iter()
+        .map(session_to_value)
+        .collect::<Vec<_>>();
+    (StatusCode::OK, Json(values)).into_response()
+}
+
+async fn oc_session_init(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+    Query(query): Query<DirectoryQuery>,
+    OptionalStrictJson(body): OptionalStrictJson<SessionInitBody>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Err(err) = state.ensure_hydrated(&session_id).await {
+        return internal_error(err);
+    }
+
+    let directory = resolve_directory(&headers, query.directory.as_ref());
+    if let Err(err) = state.ensure_session(&session_id, directory).await {
+        return internal_error(err);
+    }
+
+    let body = body.unwrap_or(SessionInitBody {
+        provider_id: None,
+        model_id: None,
+        message_id: None,
+    });
+
+    if body.provider_id.is_none() && body.model_id.is_none() {
+        return (StatusCode::OK, Json(json!(true))).into_response();
+    }
+
+    if body.provider_id.is_none() || body.model_id.is_none() {
+        return bad_request("providerID and modelID are required when selecting a model");
+    }
+
+    let provider_id = body.provider_id.unwrap_or_else(|| "mock".to_string());
+    let model_id = body.model_id.unwrap_or_else(|| "mock".to_string());
+
+    let meta = {
+        let mut projection = state.projection.lock().await;
+        let Some(session) = projection.sessions.get_mut(&session_id) else {
+            return not_found("Session not found");
+        };
+        let has_messages = session.has_turns();
+        let selection_changed =
+            session.meta.provider_id != provider_id || session.meta.model_id != model_id;
+        if has_messages && selection_changed {
+            return bad_request(MODEL_CHANGE_ERROR);
+        }
+        session.meta.provider_id = provider_id.clone();
+        session.meta.model_id = model_id.clone();
+        session.meta.agent = provider_to_agent(&provider_id);
+        session.meta.updated_at = state.now_ms();
+        session.meta.clone()
+    };
+
+    if let Err(err) = state.persist_session(&meta).await {
+        return internal_error(err);
+    }
+
+    (StatusCode::OK, Json(json!(true))).into_response()
+}
+
+async fn oc_session_fork(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+    Query(query): Query<DirectoryQuery>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Err(err) = state.ensure_hydrated(&session_id).await {
+        return internal_error(err);
+    }
+
+    let parent = {
+        let projection = state.projection.lock().await;
+        projection.sessions.get(&session_id).cloned()
+    };
+    let Some(parent) = parent else {
+        return not_found("Session not found");
+    };
+
+    // A fork works in its parent's directory unless the caller names another.
+    let directory = match query.directory.as_ref() {
+        Some(_) => resolve_directory(&headers, query.directory.as_ref()),
+        None => parent.meta.directory.clone(),
+    };
+    if let Err(err) = validate_session_directory(&directory) {
+        return bad_request(&err.to_string());
+    }
+    let _admitted = match admit_session(&state).await {
+        Ok(guard) => guard,
+        Err(response) => return response,
+    };
+    let id = state.next_id("ses_");
+    let now = state.now_ms();
+    let connection_id = state.current_connection_for_agent(&parent.meta.agent).await;
+
+    let meta = SessionMeta {
+        id: id.clone(),
+        slug: format!("session-{id}"),
+        project_id: state.project_id.clone(),
+        directory: directory.clone(),
+        parent_id: Some(session_id),
+        title: format!("Fork of {}", parent.meta.title),
+        version: "0".to_string(),
+        created_at: now,
+        updated_at: now,
+        share_url: None,
+        share_id: None,
+        permission_mode: parent.meta.permission_mode.clone(),
+        agent: parent.meta.agent.clone(),
+        provider_id: parent.meta.provider_id.clone(),
+        model_id: parent.meta.model_id.clone(),
+        agent_session_id: format!("acp_{}", state.next_id("ses_")),
+        last_connection_id: connection_id,
+        session_init_json: Some(match parent.meta.session_init_json.clone() {
+            Some(mut init) if init.is_object() => {
+                init["cwd"] = json!(directory);
+                init
+            }
+            _ => session_init_json(&directory),
+        }),
+        destroyed_at: None,
+        context_files: parent.meta.context_files.clone(),
+        env: parent.meta.env.clone(),
+        dry_run: parent.meta.dry_run,
+        backends: parent.meta.backends.clone(),
+        priority: parent.meta.priority,
+        kind: parent.meta.kind,
+        dormant_at: None,
+        migrated_to: None,
+    };
+
+    if let Err(err) = state.persist_session(&meta).await {
+        return internal_error(err);
+    }
+    state.track_session_secrets(&meta);
+    state.record_session_created(&meta);
+
+    {
+        let mut projection = state.projection.lock().await;
+        projection.sessions.insert(
+            id.clone(),
+            SessionState {
+                meta: meta.clone(),
+                messages: Vec::new(),
+                lifecycle: SessionLifecycle::Created,
+                always_rules: Vec::new(),
+                pending_context: Vec::new(),
+                archive_key: None,
+            },
+        );
+    }
+
+    let value = session_to_value(&meta);
+    state.emit_event(json!({
+        "type": "session.created",
+        "properties": { "info": value, "backend": backend_mapping(&meta) }
+    }));
+
+    (StatusCode::OK, Json(value)).into_response()
+}
+
+async fn oc_session_diff() -> Response {
+    (StatusCode::OK, Json(json!([]))).into_response()
+}
+
+async fn oc_session_todo(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+) -> Response {
+    let todos = state.session_todos.lock().await.get(&session_id).cloned();
+    (StatusCode::OK, Json(todos.unwrap_or_else(|| json!([])))).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionBackendQuery {
+    target: Option<String>,
+}
+
+/// The session expiry policy and every session's expiry timer.
+async fn oc_session_expiry(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let policy = state
+        .config
+        .session_expiry
+        .as_ref()
+        .map(SessionExpiryConfig::policy);
+    let sessions = state.session_timers(None).await;
+    (
+        StatusCode::OK,
+        Json(json!({"policy": policy, "sessions": sessions})),
+    )
+        .into_response()
+}
+
+async fn oc_session_expiry_get(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let Some(timer) = state.session_timers(Some(&session_id)).await.pop() else {
+        return not_found("Session not found");
+    };
+    let mut value = json!(timer);
+    value["policy"] = json!(state
+        .config
+        .session_expiry
+        .as_ref()
+        .map(SessionExpiryConfig::policy));
+    (StatusCode::OK, Json(value)).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionReplayQuery {
+    max_events: Option<usize>,
+    max_chars: Option<usize>,
+}
+
+/// How the history replayed to a fresh agent process would be selected for a
+/// session, for tuning the replay budgets. `?maxEvents=` and `?maxChars=`
+/// try other budgets than the configured ones.
+async fn oc_session_replay(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    Query(query): Query<SessionReplayQuery>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if !state
+        .projection
+        .lock()
+        .await
+        .sessions
+        .contains_key(&session_id)
+    {
+        return not_found("Session not found");
+    }
+    let selection = state
+        .replay_selection(
+            &session_id,
+            query.max_events.unwrap_or(state.config.replay_max_events),
+            query.max_chars.unwrap_or(state.config.replay_max_chars),
+        )
+        .await;
+    match selection {
+        Ok(selection) => (StatusCode::OK, Json(json!(selection.stats))).into_response(),
+        Err(err) => internal_error(err),
+    }
+}
+
+/// The agent process and ACP session behind a session, or behind one of its
+/// composer backends with `?target=`, for debugging continuity issues.
+async fn oc_session_backend(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    Query(query): Query<SessionBackendQuery>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let meta = {
+        let projection = state.projection.lock().await;
+        let Some(session) = projection.sessions.get(&session_id) else {
+            return not_found("Session not found");
+        };
+        match query.target.as_deref() {
+            Some(target) => match session.meta.for_backend(target) {
+                Some(meta) => meta,
+                None => return not_found("Backend not found"),
+            },
+            None => session.meta.clone(),
+        }
+    };
+    let server_id = meta.agent_session_id.clone();
+    let acp_session_id = state.acp_initialized.lock().await.get(&server_id).cloned();
+    let backend = state
+        .acp_backends
+        .lock()
+        .await
+        .get(&server_id)
+        .cloned()
+        .unwrap_or_default();
+    let instance = match state.config.acp_dispatch.as_ref() {
+        Some(dispatch) => dispatch
+            .instances()
+            .await
+            .into_iter()
+            .find(|instance| instance.server_id == server_id),
+        None => None,
+    };
+    let agent_version = backend
+        .agent_info
+        .as_ref()
+        .and_then(|info| info.get("version"))
+        .cloned();
+
+    let mut value = backend_mapping(&meta);
+    value["sessionID"] = json!(session_id);
+    value["acpSessionID"] = json!(acp_session_id.filter(|id| !id.is_empty()));
+    value["generation"] = json!(backend.generation);
+    value["bootstrappedAt"] = json!((backend.generation > 0).then_some(backend.bootstrapped_at));
+    value["running"] = json!(instance.is_some());
+    value["pid"] = json!(instance.as_ref().and_then(|instance| instance.pid));
+    value["startedAt"] = json!(instance.as_ref().map(|instance| instance.created_at_ms));
+    value["agentVersion"] = agent_version.unwrap_or(Value::Null);
+    value["agentInfo"] = backend.agent_info.unwrap_or(Value::Null);
+    value["authMethods"] = json!(backend.auth_methods);
+    value["authenticated"] = json!(backend.authenticated);
+    if let Some(target) = query.target {
+        value["target"] = json!(target);
+    }
+    (StatusCode::OK, Json(value)).into_response()
+}
+
+async fn oc_session_summarize(Json(body): Json<Value>) -> Response {
+    if body.get("providerID").is_none() || body.get("modelID").is_none() {
+        return bad_request("providerID and modelID are required");
+    }
+    (StatusCode::OK, Json(json!(true))).into_response()
+}
+
+async fn oc_session_context_list(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+
+    let projection = state.projection.lock().await;
+    let Some(session) = projection.sessions.get(&session_id) else {
+        return not_found("Session not found");
+    };
+
+    (StatusCode::OK, Json(json!(session.pending_context))).into_response()
+}
+
+async fn oc_session_context_add(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    StrictJson(body): StrictJson<SessionContextBody>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+
+    let Some(text) = body.text.filter(|text| !text.trim().is_empty()) else {
+        return bad_request("text is required");
+    };
+
+    {
+        let projection = state.projection.lock().await;
+        if !projection.sessions.contains_key(&session_id) {
+            return not_found("Session not found");
+        }
+    }
+
+    let mut item = json!({
+        "id": state.next_id("ctx_"),
+        "sessionID": session_id,
+        "text": text,
+        "time": {"created": state.now_ms()},
+    });
+    if let Some(obj) = item.as_object_mut() {
+        if let Some(label) = body.label {
+            obj.insert("label".to_string(), json!(label));
+        }
+        if let Some(source) = body.source {
+            obj.insert("source".to_string(), json!(source));
+        }
+    }
+
+    let envelope = json!({
+        "jsonrpc":"2.0",
+        "method":"_sandboxagent/opencode/context_added",
+        "params":{"item": item}
+    });
+    if let Err(err) = state.persist_event(&session_id, "client", &envelope).await {
+        return internal_error(err);
+    }
+
+    state.emit_event(json!({
+        "type":"session.context.added",
+        "properties": {
+            "sessionID": session_id,
+            "item": item,
+        }
+    }));
+
+    (StatusCode::OK, Json(item)).into_response()
+}
+
+async fn oc_session_rpc(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    StrictJson(body): StrictJson<SessionRpcBody>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+
+    let Some(method) = body.method.filter(|method| !method.trim().is_empty()) else {
+        return bad_request("method is required");
+    };
+    if !rpc_method_allowed(&state.config.rpc_method_allowlist, &method) {
+        return forbidden(&format!("method '{method}' is not in the RPC allowlist"));
+    }
+
+    let server_id = {
+        let projection = state.projection.lock().await;
+        let Some(session) = projection.sessions.get(&session_id) else {
+            return not_found("Session not found");
+        };
+        session.meta.agent_session_id.clone()
+    };
+    let Some(dispatch) = state.config.acp_dispatch.as_ref() else {
+        return bad_request("session agent does not accept RPC calls");
+    };
+    // The agent process is initialized by the first prompt; extension
+    // methods are not valid before `initialize` + `session/new`.
+    let Some(acp_session_id) = state.acp_initialized.lock().await.get(&server_id).cloned() else {
+        return conflict("session agent has not been started; send a prompt first");
+    };
+
+    let mut params = body.params.unwrap_or_else(|| json!({}));
+    if let Some(obj) = params.as_object_mut() {
+        obj.entry("sessionId")
+            .or_insert_with(|| json!(acp_session_id));
+    }
+    let payload = json!({
+        "jsonrpc": "2.0",
+        "id": state.next_id("oc_rpc_"),
+        "method": method,
+        "params": params,
+    });
+
+    match dispatch.post(&server_id, None, payload).await {
+        Ok(AcpDispatchResult::Response(response)) => {
+            let mut out = serde_json::Map::new();
+            if let Some(error) = response.get("error") {
+                out.insert("error".to_string(), error.clone());
+            } else {
+                out.insert(
+                    "result".to_string(),
+                    response.get("result").cloned().unwrap_or(Value::Null),
+                );
+            }
+            (StatusCode::OK, Json(Value::Object(out))).into_response()
+        }
+        Ok(AcpDispatchResult::Accepted) => {
+            (StatusCode::ACCEPTED, Json(json!({"accepted": true}))).into_response()
+        }
+        Err(err) => internal_error(format!("ACP {method} failed: {err}")),
+    }
+}
+
+async fn oc_session_messages(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    OriginalUri(uri): OriginalUri,
+    Query(page): Query<PageQuery>,
+) -> Response {
+    let after = match page.after() {
+        Ok(after) => after,
+        Err(err) => return bad_request(&err),
+    };
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Err(err) = state.ensure_hydrated(&session_id).await {
+        return internal_error(err);
+    }
+
+    let projection = state.projection.lock().await;
+    let Some(session) = projection.sessions.get(&session_id) else {
+        return not_found("Session not found");
+    };
+
+    if !page.is_paged() {
+        let values = session
+            .messages
+            .iter()
+            .map(|record| json!({"info": record.info, "parts": record.parts}))
+            .collect::<Vec<_>>();
+        return (StatusCode::OK, Json(values)).into_response();
+    }
+
+    // Messages live in the projection rather than in their own table: the
+    // page is cut from the hydrated transcript, unlike session pages, which
+    // are read from SQLite. Only the page's messages are serialized.
+    let mut ordered = session
+        .messages
+        .iter()
+        .map(|record| (message_cursor(record), record))
+        .filter(|(cursor, _)| after.as_ref().is_none_or(|after| cursor > after))
+        .collect::<Vec<_>>();
+    ordered.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let limit = page.limit();
+    let next = (ordered.len() > limit).then(|| ordered[limit - 1].0.clone());
+    let values = ordered
+        .into_iter()
+        .take(limit)
+        .map(|(_, record)| json!({"info": record.info, "parts": record.parts}))
+        .collect::<Vec<_>>();
+    let total = session.messages.len();
+    drop(projection);
+    paged_response(values, total, &uri, next.as_ref())
+}
+
+fn message_cursor(record: &MessageRecord) -> PageCursor {
+    PageCursor {
+        created_at: record
+            .info
+            .pointer("/time/created")
+            .and_then(Value::as_i64)
+            .unwrap_or_default(),
+        id: record
+            .info
+            .get("id")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CompareQuery {
+    #[serde(rename = "sessionA")]
+    session_a: Option<String>,
+    #[serde(rename = "sessionB")]
+    session_b: Option<String>,
+}
+
+/// Compare two sessions turn by turn, e.g. the same prompts run against two
+/// models.
+async fn oc_compare(
+    State(state): State<Arc<AdapterState>>,
+    Query(query): Query<CompareQuery>,
+) -> Response {
+    let (Some(session_a), Some(session_b)) = (query.session_a, query.session_b) else {
+        return bad_request("sessionA and sessionB are required");
+    };
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    for session_id in [&session_a, &session_b] {
+        if let Err(err) = state.ensure_hydrated(session_id).await {
+            return internal_error(err);
+        }
+    }
+
+    let projection = state.projection.lock().await;
+    let (Some(a), Some(b)) = (
+        projection.sessions.get(&session_a),
+        projection.sessions.get(&session_b),
+    ) else {
+        return not_found("Session not found");
+    };
+    let comparison = compare::compare_sessions(&a.meta, &a.messages, &b.meta, &b.messages);
+    (StatusCode::OK, Json(comparison)).into_response()
+}
+
+/// Run a prompt turn to completion. Rejections before the prompt reaches the
+/// agent keep their own status; [`oc_session_prompt`] only commits to a 200
+/// once [`mark_turn_dispatched`] fires.
+async fn run_session_prompt(
+    state: State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+    query: Query<DirectoryQuery>,
+    body: Json<PromptBody>,
+) -> Response {
+    // `correlation_id` is recorded once the turn's user message ID is known.
+    let span = tracing::info_span!(
+        "prompt",
+        session_id = %session_id,
+        correlation_id = tracing::field::Empty
+    );
+    let started = Instant::now();
+    let message_id = body.message_id.clone();
+    let target = body.target.clone();
+    let mut response = EVENT_BACKEND
+        .scope(
+            target,
+            session_prompt(state.clone(), session_id.clone(), headers, query, body),
+        )
+        .instrument(span)
+        .await;
+    if let Some(message_id) = message_id
+        .filter(|_| response.status().is_success() && !state.config.prompt_interceptors.is_empty())
+    {
+        response = intercept_prompt_response(&state, &session_id, &message_id, response).await;
+    }
+    if let Some(metrics) = state.config.metrics.as_ref() {
+        let agent = state
+            .projection
+            .lock()
+            .await
+            .sessions
+            .get(&session_id)
+            .map(|session| session.meta.agent.clone())
+            .unwrap_or_default();
+        metrics.prompt_completed(&agent, started.elapsed());
+    }
+    response
+}
+
+fn prompt_context(meta: &SessionMeta, message_id: &str) -> PromptContext {
+    PromptContext {
+        session_id: meta.id.clone(),
+        message_id: message_id.to_string(),
+        agent: meta.agent.clone(),
+        provider_id: meta.provider_id.clone(),
+        model_id: meta.model_id.clone(),
+        directory: meta.directory.clone(),
+    }
+}
+
+/// Pass a successful prompt response through the `after_prompt` hooks.
+async fn intercept_prompt_response(
+    state: &Arc<AdapterState>,
+    session_id: &str,
+    message_id: &str,
+    response: Response,
+) -> Response {
+    let meta = {
+        let projection = state.projection.lock().await;
+        projection
+            .sessions
+            .get(session_id)
+            .map(|session| session.meta.clone())
+    };
+    let Some(meta) = meta else {
+        return response;
+    };
+    let status = response.status();
+    let Ok(bytes) = axum::body::to_bytes(response.into_body(), usize::MAX).await else {
+        return internal_error("failed to read prompt response".to_string());
+    };
+    let Ok(mut output) = serde_json::from_slice::<Value>(&bytes) else {
+        return (status, bytes).into_response();
+    };
+    let context = prompt_context(&meta, message_id);
+    interceptor::intercept_response(&state.config.prompt_interceptors, &context, &mut output).await;
+    (status, Json(output)).into_response()
+}
+
+async fn session_prompt(
+    State(state): State<Arc<AdapterState>>,
+    session_id: String,
+    headers: HeaderMap,
+    Query(query): Query<DirectoryQuery>,
+    Json(body): Json<PromptBody>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Err(err) = state.ensure_hydrated(&session_id).await {
+        return internal_error(err);
+    }
+
+    let directory = resolve_directory(&headers, query.directory.as_ref());
+    let mut meta = match state.ensure_session(&session_id, directory).await {
+        Ok(meta) => meta,
+        Err(err) => return internal_error(err),
+    };
+    if let Some(target) = meta.migrated_to.as_deref() {
+        return session_migrated(&session_id, target);
+    }
+    // The session keeps the directory it was created with; per-request
+    // directory hints only apply when the session is first created.
+    let directory = meta.directory.clone();
+
+    if let Some(target) = body.target.as_deref() {
+        if !meta.backends.contains_key(target) {
+            return bad_request(&format!("Session has no backend named '{target}'"));
+        }
+        if body.agent.is_some() || prompt_has_explicit_model_selection(&body) {
+            return bad_request("A prompt with target runs on that backend's agent and model");
+        }
+    }
+
+    let reasoning = match ReasoningBudget::new(body.variant.as_deref(), body.reasoning_budget) {
+        Ok(reasoning) => reasoning,
+        Err(err) => return bad_request(&err),
+    };
+
+    let explicit_model_selection = prompt_has_explicit_model_selection(&body);
+    let requested_selection = resolve_selection_from_prompt(&body);
+    if explicit_model_selection && requested_selection.is_none() {
+        return bad_request("providerID and modelID are required when selecting a model");
+    }
+
+    let (has_messages, awaits_history_replay) = {
+        let projection = state.projection.lock().await;
+        projection
+            .sessions
+            .get(&session_id)
+            .map(|session| (session.has_turns(), session.awaits_history_replay()))
+            .unwrap_or_default()
+    };
+
+    if let Some(selection) = requested_selection.as_ref() {
+        let selection_changed =
+            meta.provider_id != selection.provider_id || meta.model_id != selection.model_id;
+        if has_messages && selection_changed {
+            return bad_request(MODEL_CHANGE_ERROR);
+        }
+        meta.provider_id = selection.provider_id.clone();
+        meta.model_id = selection.model_id.clone();
+        meta.agent = selection.agent.clone();
+    } else if let Some(agent) = body.agent.as_ref() {
+        if has_messages && meta.agent != *agent {
+            return bad_request(MODEL_CHANGE_ERROR);
+        }
+        meta.agent = agent.clone();
+    }
+
+    let mut parts_input = body.parts.unwrap_or_default();
+    if parts_input.is_empty() {
+        return bad_request("parts are required");
+    }
+    // The directory may have been removed since the session was created.
+    if let Err(err) = validate_session_directory(&directory) {
+        return bad_request(&err.to_string());
+    }
+
+    if let Some(session_mode) = {
+        let projection = state.projection.lock().await;
+        projection
+            .sessions
+            .get(&session_id)
+            .and_then(|session| session.meta.permission_mode.clone())
+    } {
+        meta.permission_mode = Some(session_mode);
+    }
+
+    // Context files are discovered once, for the agent that takes the first
+    // turn, and recorded on the session.
+    let discovered_context = (!has_messages && state.config.context_files)
+        .then(|| context_files::discover(&directory, &meta.agent));
+
+    let mut was_dormant = false;
+    {
+        let mut projection = state.projection.lock().await;
+        if let Some(session) = projection.sessions.get_mut(&session_id) {
+            session.meta.agent = meta.agent.clone();
+            session.meta.provider_id = meta.provider_id.clone();
+            session.meta.model_id = meta.model_id.clone();
+            session.meta.updated_at = state.now_ms();
+            was_dormant = session.meta.dormant_at.take().is_some();
+            if let Some(discovered) = discovered_context.as_ref() {
+                session.meta.context_files = discovered.files.clone();
+            }
+            meta = session.meta.clone();
+        }
+    }
+
+    if let Err(err) = state.persist_session(&meta).await {
+        return internal_error(err);
+    }
+    // A dormant session's agent process was stopped; the fresh one it
+    // bootstraps is told what happened so far.
+    if was_dormant {
+        match state.replay_text(&session_id).await {
+            Ok(Some(text)) => {
+                state
+                    .pending_replay
+                    .lock()
+                    .await
+                    .insert(session_id.clone(), text);
+            }
+            Ok(None) => {}
+            Err(err) => warn!(%err, "failed to collect replay for a dormant session"),
+        }
+        state.emit_event(json!({
+            "type": "session.updated",
+            "properties": { "info": session_to_value(&meta) }
+        }));
+    }
+    if discovered_context
+        .as_ref()
+        .is_some_and(|discovered| !discovered.files.is_empty())
+    {
+        state.emit_event(json!({
+            "type": "session.updated",
+            "properties": { "info": session_to_value(&meta) }
+        }));
+    }
+
+    if let Err(err) = state.maybe_restore_session(&session_id).await {
+        return internal_error(err);
+    }
+
+    // Re-read meta after maybe_restore_session, which may have generated a new
+    // agent_session_id (e.g. when the agent changed from "mock" to a real agent
+    // and the connection_id differs).
+    {
+        let projection = state.projection.lock().await;
+        if let Some(session) = projection.sessions.get(&session_id) {
+            meta = session.meta.clone();
+        }
+    }
+    // From here on a targeted prompt runs as its composer backend; the
+    // session itself keeps its own agent.
+    if let Some(target) = body.target.as_deref() {
+        match meta.for_backend(target) {
+            Some(backend) => meta = backend,
+            None => return bad_request(&format!("Session has no backend named '{target}'")),
+        }
+    }
+    if meta.agent != "mock" {
+        state
+            .forget_lost_instance(&session_id, &meta.agent_session_id)
+            .await;
+    }
+
+    let user_message_id = body
+        .message_id
+        .clone()
+        .unwrap_or_else(|| state.next_id("msg_"));
+    let correlation_id = logs::correlation_id(&session_id, &user_message_id);
+    tracing::Span::current().record("correlation_id", correlation_id.as_str());
+
+    if !state.config.prompt_interceptors.is_empty() {
+        let context = prompt_context(&meta, &user_message_id);
+        parts_input = match interceptor::intercept_prompt(
+            &state.config.prompt_interceptors,
+            &context,
+            parts_input,
+        )
+        .await
+        {
+            Ok(parts) if parts.is_empty() => return bad_request("parts are required"),
+            Ok(parts) => parts,
+            Err(reason) => return forbidden(&reason),
+        };
+    }
+    let preflight = preflight::check(
+        &parts_input,
+        preflight::PreflightProfile {
+            agent: &meta.agent,
+            context_tokens: state
+                .model_catalog
+                .as_ref()
+                .and_then(|catalog| catalog.context_limit(&meta.provider_id, &meta.model_id)),
+        },
+    );
+    if !preflight.is_empty() {
+        return preflight_failure(&meta.agent, preflight);
+    }
+    parts_input = match attachments::prepare_parts(
+        parts_input,
+        &meta.agent,
+        state.agent_prompt_capabilities(&meta.agent),
+        &state.config.attachment_transcoders,
+    )
+    .await
+    {
+        Ok(parts) => parts,
+        Err(err) => return attachment_error(err),
+    };
+    let now = state.now_ms();
+
+    let user_info = build_user_message(
+        &session_id,
+        &user_message_id,
+        now,
+        &meta.agent,
+        &meta.provider_id,
+        &meta.model_id,
+        body.system.as_deref(),
+    );
+    let mut user_parts = normalize_parts(&session_id, &user_message_id, &parts_input);
+
+    // Pending context items are consumed by this prompt; the `session/prompt`
+    // envelope below clears them from the projection.
+    let context_text = {
+        let projection = state.projection.lock().await;
+        projection
+            .sessions
+            .get(&session_id)
+            .and_then(|session| build_context_text(&session.pending_context))
+    };
+    if let Some(text) = context_text.as_ref() {
+        user_parts.insert(
+            0,
+            json!({
+                "id": format!("part_{user_message_id}_context"),
+                "sessionID": session_id,
+                "messageID": user_message_id,
+                "type": "text",
+                "text": text,
+                "synthetic": true,
+            }),
+        );
+    }
+
+    let mut replay_injected = state.pending_replay.lock().await.remove(&session_id);
+    // The first turn after `initialHistory` replays it, read from the log so
+    // the import survives a restart before that turn.
+    if replay_injected.is_none() && awaits_history_replay {
+        replay_injected = match state.replay_text(&session_id).await {
+            Ok(text) => text,
+            Err(err) => return internal_error(err),
+        };
+    }
+    let mut outbound_prompt_parts = Vec::new();
+    if let Some(text) = discovered_context
+        .as_ref()
+        .and_then(context_files::DiscoveredContext::preamble)
+    {
+        outbound_prompt_parts.push(json!({"type":"text", "text": text}));
+    }
+    if let Some(replay_text) = replay_injected {
+        outbound_prompt_parts.push(json!({"type":"text", "text": replay_text}));
+    }
+    if let Some(text) = context_text {
+        outbound_prompt_parts.push(json!({"type":"text", "text": text}));
+    }
+    outbound_prompt_parts.extend(agent_parts::translate(parts_input.clone(), &meta.agent));
+
+    let prompt_envelope = json!({
+        "jsonrpc": "2.0",
+        "id": state.next_id("oc_req_"),
+        "method": "session/prompt",
+        "params": {
+            "sessionId": meta.agent_session_id,
+            "prompt": outbound_prompt_parts,
+            "sessionID": session_id,
+            "message": {
+                "info": user_info,
+                "parts": user_parts,
+            }
+        }
+    });
+    // Track the user message ID so the SSE translation task can set
+    // parentID on assistant messages, including after a restart.
+    if let Err(err) = state
+        .persist_turn_start(&session_id, &user_message_id, &prompt_envelope)
+        .await
+    {
+        return internal_error(err);
+    }
+
+    state.emit_event(message_event("message.updated", &user_info));
+    for part in &user_parts {
+        state.emit_event(json!({
+            "type":"message.part.updated",
+            "properties":{
+                "sessionID": session_id,
+                "messageID": user_message_id,
+                "part": part
+            }
+        }));
+    }
+
+    let needs_bootstrap = state.config.acp_dispatch.is_some()
+        && meta.agent != "mock"
+        && !state
+            .acp_initialized
+            .lock()
+            .await
+            .contains_key(&meta.agent_session_id);
+    let (initial_lifecycle, initial_reason) = if needs_bootstrap {
+        (SessionLifecycle::Bootstrapping, "bootstrap")
+    } else {
+        (SessionLifecycle::Prompting, "prompt")
+    };
+    if let Err(err) =
+        transition_session(&state, &session_id, initial_lifecycle, initial_reason).await
+    {
+        return internal_error(err);
+    }
+
+    // -----------------------------------------------------------------------
+    // ACP dispatch path — route to real agent processes when acp_dispatch is
+    // configured and the resolved agent is not "mock".
+    // -----------------------------------------------------------------------
+    tracing::info!(
+        session_id = %session_id,
+        agent = %meta.agent,
+        provider_id = %meta.provider_id,
+        model_id = %meta.model_id,
+        has_acp_dispatch = state.config.acp_dispatch.is_some(),
+        "prompt dispatch decision"
+    );
+    if let Some(dispatch) = state.config.acp_dispatch.as_ref() {
+        if meta.agent != "mock" {
+            let server_id = meta.agent_session_id.clone();
+
+            tracing::info!(server_id = %server_id, agent = %meta.agent, "entering ACP dispatch path");
+
+            // Bootstrap the ACP server instance if this is the first prompt.
+            let needs_init = !state.acp_initialized.lock().await.contains_key(&server_id);
+            // A pre-warmed process is already running and takes no new launch
+            // arguments.
+            let launching = needs_init && !dispatch.has_instance(&server_id).await;
+            let system_override = body
+                .system
+                .as_deref()
+                .and_then(|text| SystemPromptOverride::new(text, &meta.agent, launching));
+            if let Some(system_override) = system_override.as_ref() {
+                if let Some(text) = system_override.preamble() {
+                    outbound_prompt_parts.insert(0, json!({"type":"text", "text": text}));
+                }
+                state
+                    .record_turn_system_prompt(&session_id, system_override.mechanism)
+                    .await;
+            }
+            if needs_init {
+                tracing::info!(server_id = %server_id, "bootstrapping ACP session (initialize + session/new)");
+                // A fresh agent process needs the non-native context files
+                // again, even when the session already has turns.
+                let bootstrap_context = if state.config.context_files {
+                    discovered_context
+                        .clone()
+                        .unwrap_or_else(|| context_files::discover(&directory, &meta.agent))
+                } else {
+                    Default::default()
+                };
+                let mut launch_args = bootstrap_context.launch_args();
+                if let Some(system_override) = system_override.as_ref() {
+                    launch_args.extend(system_override.launch_args());
+                }
+                if !launch_args.is_empty() {
+                    dispatch.set_launch_args(&server_id, launch_args).await;
+                }
+                if !meta.env.is_empty() {
+                    let env = meta
+                        .env
+                        .iter()
+                        .map(|(name, var)| (name.clone(), var.value.clone()))
+                        .collect();
+                    dispatch.set_launch_env(&server_id, env).await;
+                }
+                if !meta.priority.is_normal() {
+                    dispatch
+                        .set_launch_priority(&server_id, meta.priority)
+                        .await;
+                }
+                // 1) initialize
+                let initialize = initialize_call(&meta.agent);
+                let (agent_info, auth_methods) = match dispatch
+                    .call(
+                        &server_id,
+                        Some(&meta.agent),
+                        state.next_id("oc_rpc_"),
+                        initialize,
+                    )
+                    .await
+                {
+                    Ok(AcpCallOutcome::Result(result)) => {
+                        tracing::info!(server_id = %server_id, "ACP initialize succeeded");
+                        match result {
+                            AcpResult::Initialize(result) => {
+                                if let Some(capabilities) = result.agent_capabilities.as_ref() {
+                                    state.record_prompt_capabilities(&meta.agent, capabilities);
+                                }
+                                (result.extra.get("agentInfo").cloned(), result.auth_methods)
+                            }
+                            _ => (None, Vec::new()),
+                        }
+                    }
+                    Ok(AcpCallOutcome::Accepted) => {
+                        tracing::info!(server_id = %server_id, "ACP initialize accepted");
+                        (None, Vec::new())
+                    }
+                    Err(err @ AcpCallError::Rpc { .. }) => {
+                        tracing::error!(server_id = %server_id, error = %err, "ACP initialize returned JSON-RPC error");
+                        if let Some(response) = bootstrap_auth_failure(
+                            &state,
+                            dispatch,
+                            &server_id,
+                            &session_id,
+                            &meta.provider_id,
+                        )
+                        .await
+                        {
+                            return response;
+                        }
+                        let _ = transition_session(
+                            &state,
+                            &session_id,
+                            SessionLifecycle::Errored,
+                            "acp_initialize_error",
+                        )
+                        .await;
+                        return internal_error(format!("ACP initialize error: {err}"));
+                    }
+                    Err(err) => {
+                        if let Some(response) = bootstrap_auth_failure(
+                            &state,
+                            dispatch,
+                            &server_id,
+                            &session_id,
+                            &meta.provider_id,
+                        )
+                        .await
+                        {
+                            return response;
+                        }
+                        let _ = transition_session(
+                            &state,
+                            &session_id,
+                            SessionLifecycle::Errored,
+                            "acp_initialize_failed",
+                        )
+                        .await;
+                        return internal_error(format!("ACP initialize failed: {err}"));
+                    }
+                };
+
+                // 2) authenticate, when credentials were given for the agent
+                let credentials = state
+                    .agent_credentials
+                    .lock()
+                    .await
+                    .get(&meta.agent)
+                    .cloned();
+                let authenticated = match credentials {
+                    Some(credentials) => {
+                        let method_id = credentials.method_id.clone();
+                        let authenticate = AcpCall::Authenticate(credentials);
+                        if let Err(err) = dispatch
+                            .call(&server_id, None, state.next_id("oc_rpc_"), authenticate)
+                            .await
+                        {
+                            return agent_auth_failure(
+                                &state,
+                                AgentAuthError::Failed,
+                                &session_id,
+                                &meta,
+                                &server_id,
+                                &auth_methods,
+                                &err.to_string(),
+                            )
+                            .await;
+                        }
+                        Some(method_id)
+                    }
+                    None => None,
+                };
+
+                // 3) session/new
+                let session_new = AcpCall::SessionNew(SessionNewParams {
+                    cwd: directory.clone(),
+                    mcp_servers: Vec::new(),
+                    meta: Some({
+                        let mut session_meta = json!({
+                            "sandboxagent.dev": {
+                                "model": meta.model_id.clone()
+                            }
+                        });
+                        if let (Some(target), Some(Value::Object(extra))) = (
+                            session_meta.as_object_mut(),
+                            bootstrap_context.session_new_meta(),
+                        ) {
+                            target.extend(extra);
+                        }
+                        session_meta
+                    }),
+                    extra: Default::default(),
+                });
+                let acp_session_id = match dispatch
+                    .call(&server_id, None, state.next_id("oc_rpc_"), session_new)
+                    .await
+                {
+                    Ok(AcpCallOutcome::Result(result)) => {
+                        let sid = match result {
+                            AcpResult::SessionNew(result) => result.session_id,
+                            _ => String::new(),
+                        };
+                        tracing::info!(server_id = %server_id, acp_session_id = %sid, "ACP session/new succeeded");
+                        sid
+                    }
+                    Ok(AcpCallOutcome::Accepted) => {
+                        tracing::info!(server_id = %server_id, "ACP session/new accepted");
+                        String::new()
+                    }
+                    Err(err) if err.is_auth_required() => {
+                        return agent_auth_failure(
+                            &state,
+                            AgentAuthError::Required,
+                            &session_id,
+                            &meta,
+                            &server_id,
+                            &auth_methods,
+                            &err.to_string(),
+                        )
+                        .await;
+                    }
+                    Err(err @ AcpCallError::Rpc { .. }) => {
+                        tracing::error!(server_id = %server_id, error = %err, "ACP session/new returned JSON-RPC error");
+                        if let Some(response) = bootstrap_auth_failure(
+                            &state,
+                            dispatch,
+                            &server_id,
+                            &session_id,
+                            &meta.provider_id,
+                        )
+                        .await
+                        {
+                            return response;
+                        }
+                        let _ = transition_session(
+                            &state,
+                            &session_id,
+                            SessionLifecycle::Errored,
+                            "acp_session_new_error",
+                        )
+                        .await;
+                        return internal_error(format!("ACP session/new error: {err}"));
+                    }
+                    Err(err) => {
+                        if let Some(response) = bootstrap_auth_failure(
+                            &state,
+                            dispatch,
+                            &server_id,
+                            &session_id,
+                            &meta.provider_id,
+                        )
+                        .await
+                        {
+                            return response;
+                        }
+                        let _ = transition_session(
+                            &state,
+                            &session_id,
+                            SessionLifecycle::Errored,
+                            "acp_session_new_failed",
+                        )
+                        .await;
+                        return internal_error(format!("ACP session/new failed: {err}"));
+                    }
+                };
+
+                // 4) Start SSE translation task.
+                match dispatch
+                    .sequenced_notification_stream(&server_id, None)
+                    .await
+                {
+                    Ok(stream) => spawn_acp_translation(
+                        &state,
+                        stream,
+                        &meta,
+                        &directory,
+                        body.target.as_deref(),
+                    ),
+                    Err(err) => {
+                        warn!(
+                            ?err,
+                            "failed to open ACP SSE stream; events will not be translated"
+                        );
+                    }
+                }
+
+                {
+                    let mut backends = state.acp_backends.lock().await;
+                    let backend = backends.entry(server_id.clone()).or_default();
+                    backend.generation += 1;
+                    backend.bootstrapped_at = state.now_ms();
+                    backend.agent_info = agent_info;
+                    backend.auth_methods = auth_methods;
+                    backend.authenticated = authenticated;
+                }
+                if let Err(err) = state
+                    .save_acp_binding(&server_id, &session_id, &meta.agent, &acp_session_id)
+                    .await
+                {
+                    warn!(?err, server_id, "failed to persist ACP session binding");
+                }
+                state
+                    .acp_initialized
+                    .lock()
+                    .await
+                    .insert(server_id.clone(), acp_session_id);
+
+                if let Err(err) = transition_session(
+                    &state,
+                    &session_id,
+                    SessionLifecycle::Prompting,
+                    "bootstrapped",
+                )
+                .await
+                {
+                    return internal_error(err);
+                }
+            }
+
+            // 5) Send session/prompt
+            let acp_session_id = state
+                .acp_initialized
+                .lock()
+                .await
+                .get(&server_id)
+                .cloned()
+                .unwrap_or_default();
+            dispatch
+                .set_correlation_id(&server_id, Some(correlation_id.clone()))
+                .await;
+            if let Err(err) = state.record_turn_dispatched(&session_id).await {
+                warn!(?err, session_id, "failed to journal the dispatched turn");
+            }
+            mark_turn_dispatched();
+            if let Ok(mut dispatched) = state.prompt_dispatched_at.lock() {
+                dispatched.insert(session_id.clone(), state.now_ms());
+            }
+            state.turn_model_fallbacks.lock().await.remove(&session_id);
+            state
+                .pending_subagents
+                .lock()
+                .await
+                .insert(session_id.clone(), agent_parts::requested(&parts_input));
+            let mut fallback_models = state.fallback_models(&meta.provider_id, &meta.model_id);
+            let mut prompt_meta = system_override
+                .as_ref()
+                .and_then(SystemPromptOverride::prompt_meta);
+            if let Some(reasoning) = reasoning.as_ref() {
+                reasoning::merge_meta(&mut prompt_meta, reasoning.prompt_meta(&meta.agent));
+                state
+                    .record_turn_reasoning(&session_id, reasoning.clone())
+                    .await;
+            }
+            // dispatch.call() blocks until the agent returns the session/prompt
+            // response.  The response is also broadcast to the notification stream
+            // so the SSE translation task sees it in-order after all session/update
+            // notifications and can emit session.idle at the right time.
+            let stop_reason = loop {
+                let session_prompt = AcpCall::SessionPrompt(SessionPromptParams {
+                    session_id: acp_session_id.clone(),
+                    prompt: outbound_prompt_parts.clone(),
+                    meta: prompt_meta.clone(),
+                    extra: Default::default(),
+                });
+                break match dispatch
+                    .call(&server_id, None, state.next_id("oc_rpc_"), session_prompt)
+                    .await
+                {
+                    Ok(AcpCallOutcome::Result(result)) => {
+                        tracing::info!(server_id = %server_id, "ACP session/prompt response received (turn completion delegated to SSE task)");
+                        match result {
+                            AcpResult::SessionPrompt(result) => result.stop_reason,
+                            _ => None,
+                        }
+                    }
+                    Ok(AcpCallOutcome::Accepted) => {
+                        tracing::info!(server_id = %server_id, "ACP session/prompt accepted (streaming)");
+                        None
+                    }
+                    Err(err) if err.is_model_unavailable() && !fallback_models.is_empty() => {
+                        let model = fallback_models.pop_front().unwrap_or_default();
+                        tracing::warn!(
+                            server_id = %server_id,
+                            error = %err,
+                            model = %model,
+                            "agent rejected the model; retrying the turn on a fallback model"
+                        );
+                        state.turn_model_fallbacks.lock().await.insert(
+                            session_id.clone(),
+                            ModelFallback {
+                                requested: meta.model_id.clone(),
+                                model: model.clone(),
+                                error: err.to_string(),
+                            },
+                        );
+                        prompt_meta.get_or_insert_with(|| json!({}))["sandboxagent.dev"]["model"] =
+                            json!(model);
+                        continue;
+                    }
+                    Err(err) if err.is_auth_required() => {
+                        let auth_methods = state
+                            .acp_backends
+                            .lock()
+                            .await
+                            .get(&server_id)
+                            .map(|backend| backend.auth_methods.clone())
+                            .unwrap_or_default();
+                        return agent_auth_failure(
+                            &state,
+                            AgentAuthError::Required,
+                            &session_id,
+                            &meta,
+                            &server_id,
+                            &auth_methods,
+                            &err.to_string(),
+                        )
+                        .await;
+                    }
+                    Err(err @ AcpCallError::Rpc { .. }) => {
+                        tracing::error!(server_id = %server_id, error = %err, "ACP session/prompt returned JSON-RPC error");
+                        let _ = transition_session(
+                            &state,
+                            &session_id,
+                            SessionLifecycle::Errored,
+                            "acp_session_prompt_error",
+                        )
+                        .await;
+                        return internal_error(format!("ACP session/prompt error: {err}"));
+                    }
+                    Err(err) => {
+                        let _ = transition_session(
+                            &state,
+                            &session_id,
+                            SessionLifecycle::Errored,
+                            "acp_session_prompt_failed",
+                        )
+                        .await;
+                        return internal_error(format!("ACP session/prompt failed: {err}"));
+                    }
+                };
+            };
+
+            // The SSE translation task handles session.idle and streamed
+            // content, but the HTTP response needs the pending assistant
+            // message envelope so the client can correlate future events.
+            let mut assistant_message = build_assistant_message(
+                &session_id,
+                &format!("{user_message_id}_pending"),
+                &user_message_id,
+                now,
+                &directory,
+                &meta.agent,
+                &meta.provider_id,
+                &meta.model_id,
+            );
+            if let Some(stop_reason) = stop_reason {
+                set_stop_reason(&mut assistant_message, stop_reason);
+            }
+            state
+                .annotate_model_fallback(&session_id, &mut assistant_message)
+                .await;
+            return (
+                StatusCode::OK,
+                Json(json!({
+                    "info": assistant_message,
+                    "parts": [],
+                })),
+            )
+                .into_response();
+        }
+    }
+
+    let prompt_text = parts_input
+        .iter()
+        .find_map(|part| part.get("text").and_then(Value::as_str))
+        .unwrap_or("")
+        .to_string();
+
+    if prompt_text.to_ascii_lowercase().contains("permission") {
+        let request_id = state.next_id("perm_");
+        let mut permission_request = json!({
+            "id": request_id,
+            "sessionID": session_id,
+            "permission": "execute",
+            "patterns": ["*"],
+            "metadata": {},
+            "always": [],
+        });
+        let tool_call = json!({
+            "toolCallId": state.next_id("call_"),
+            "title": "bash",
+            "kind": "execute",
+            "rawInput": {"command": "echo permission"},
+        });
+        attach_permission_tool_context(&state, &session_id, &tool_call, &mut permission_request)
+            .await;
+        permission_rules::scope_request(&mut permission_request);
+        if let Some(auto) = auto_reply_for(&state, &session_id, &permission_request).await {
+            if let Err(err) =
+                auto_reply_permission(&state, &session_id, &permission_request, None, auto).await
+            {
+                return internal_error(err);
+            }
+            let next = lifecycle_after_reply(&state, false);
+            if let Err(err) =
+                transition_session(&state, &session_id, next, "permission_replied").await
+            {
+                return internal_error(err);
+            }
+            let assistant_info = build_assistant_message(
+                &session_id,
+                &format!("{user_message_id}_pending"),
+                &user_message_id,
+                now,
+                &directory,
+                &meta.agent,
+                &meta.provider_id,
+                &meta.model_id,
+            );
+            return (
+                StatusCode::OK,
+                Json(json!({"info": assistant_info, "parts": []})),
+            )
+                .into_response();
+        }
+        let asked = json!({
+            "jsonrpc":"2.0",
+            "method":"_sandboxagent/opencode/permission_asked",
+            "params":{"request": permission_request}
+        });
+        if let Err(err) = state.persist_event(&session_id, "agent", &asked).await {
+            return internal_error(err);
+        }
+        state.emit_event(json!({"type":"permission.asked","properties":permission_request}));
+        if let Err(err) = transition_session(
+            &state,
+            &session_id,
+            SessionLifecycle::WaitingPermission,
+            "permission_asked",
+        )
+        .await
+        {
+            return internal_error(err);
+        }
+
+        let assistant_info = build_assistant_message(
+            &session_id,
+            &format!("{user_message_id}_pending"),
+            &user_message_id,
+            now,
+            &directory,
+            &meta.agent,
+            &meta.provider_id,
+            &meta.model_id,
+        );
+
+        return (
+            StatusCode::OK,
+            Json(json!({"info": assistant_info, "parts": []})),
+        )
+            .into_response();
+    }
+
+    if prompt_text.to_ascii_lowercase().contains("question") {
+        let request_id = state.next_id("q_");
+        let question_request = json!({
+            "id": request_id,
+            "sessionID": session_id,
+            "questions": [{
+                "question": "Choose one option",
+                "header": "Question",
+                "options": [
+                    {"label":"Yes","description":"Accept"},
+                    {"label":"No","description":"Reject"}
+                ],
+                "multiple": false,
+                "custom": true
+            }]
+        });
+        let asked = json!({
+            "jsonrpc":"2.0",
+            "method":"_sandboxagent/opencode/question_asked",
+            "params":{"request": question_request}
+        });
+        if let Err(err) = state.persist_event(&session_id, "agent", &asked).await {
+            return internal_error(err);
+        }
+        state.emit_event(json!({"type":"question.asked","properties":question_request}));
+        if let Err(err) = transition_session(
+            &state,
+            &session_id,
+            SessionLifecycle::WaitingQuestion,
+            "question_asked",
+        )
+        .await
+        {
+            return internal_error(err);
+        }
+
+        let assistant_info = build_assistant_message(
+            &session_id,
+            &format!("{user_message_id}_pending"),
+            &user_message_id,
+            now,
+            &directory,
+            &meta.agent,
+            &meta.provider_id,
+            &meta.model_id,
+        );
 
-    if body.model.is_some() || body.provider_id.is_some() || body.model_id.is_some() {
-        return bad_request(MODEL_CHANGE_ERROR);
+        return (
+            StatusCode::OK,
+            Json(json!({"info": assistant_info, "parts": []})),
+        )
+            .into_response();
     }
 
-    let meta = {
-        let mut projection = state.projection.lock().await;
-        let Some(session) = projection.sessions.get_mut(&session_id) else {
-            return not_found("Session not found");
-        };
+    tokio::time::sleep(Duration::from_millis(120)).await;
 
-        if let Some(title) = body.title {
-            session.meta.title = title;
-            session.meta.updated_at = now_ms();
+    if prompt_text.to_ascii_lowercase().contains("error") {
+        state.emit_event(json!({
+            "type":"session.error",
+            "properties":{
+                "sessionID": session_id,
+                "error": {"name":"UnknownError","data":{"message":"mock process crashed"}}
+            }
+        }));
+        let err_env = json!({
+            "jsonrpc":"2.0",
+            "method":"_sandboxagent/opencode/error",
+            "params":{"message":"mock process crashed"}
+        });
+        if let Err(err) = state.persist_event(&session_id, "agent", &err_env).await {
+            return internal_error(err);
+        }
+        if let Err(err) = transition_session(
+            &state,
+            &session_id,
+            SessionLifecycle::Errored,
+            "agent_crashed",
+        )
+        .await
+        {
+            return internal_error(err);
         }
 
-        session.meta.clone()
-    };
+        let assistant_info = build_assistant_message(
+            &session_id,
+            &format!("{user_message_id}_error"),
+            &user_message_id,
+            now,
+            &directory,
+            &meta.agent,
+            &meta.provider_id,
+            &meta.model_id,
+        );
 
-    if let Err(err) = state.persist_session(&meta).await {
-        return internal_error(err);
+        return (
+            StatusCode::OK,
+            Json(json!({"info": assistant_info, "parts": []})),
+        )
+            .into_response();
     }
 
-    let value = session_to_value(&meta);
-    state.emit_event(json!({"type":"session.updated","properties":{"info":value}}));
-    (StatusCode::OK, Json(value)).into_response()
-}
-
-async fn oc_session_delete(
-    State(state): State<Arc<AdapterState>>,
-    Path(session_id): Path<String>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
+    let assistant_message_id = format!("{user_message_id}_assistant");
+    if let Err(err) = state
+        .record_turn_assistant(&session_id, &user_message_id, &assistant_message_id)
+        .await
+    {
         return internal_error(err);
     }
+    let assistant_info = build_completed_assistant_message(
+        &session_id,
+        &assistant_message_id,
+        &user_message_id,
+        now,
+        &directory,
+        &meta.agent,
+        &meta.provider_id,
+        &meta.model_id,
+    );
 
-    let removed = {
-        let mut projection = state.projection.lock().await;
-        projection.permissions.retain(|_, value| {
-            value
-                .get("sessionID")
-                .and_then(Value::as_str)
-                .map(|id| id != session_id)
-                .unwrap_or(true)
+    let mut assistant_parts = Vec::<Value>::new();
+
+    if prompt_text.to_ascii_lowercase().contains("tool") {
+        let call_id = state.next_id("call_");
+        let tool_part = json!({
+            "id": tool_part_id(&assistant_message_id, &call_id),
+            "sessionID": session_id,
+            "messageID": assistant_message_id,
+            "type": "tool",
+            "callID": call_id,
+            "tool": "bash",
+            "state": {
+                "status": "completed",
+                "input": {"command": "echo tool"},
+                "output": "ok",
+                "title": "bash",
+                "metadata": {},
+                "time": {"start": now, "end": now}
+            }
         });
-        projection.questions.retain(|_, value| {
-            value
-                .get("sessionID")
-                .and_then(Value::as_str)
-                .map(|id| id != session_id)
-                .unwrap_or(true)
+        let file_part = json!({
+            "id": part_id(&assistant_message_id, 1),
+            "sessionID": session_id,
+            "messageID": assistant_message_id,
+            "type": "file",
+            "mime": "text/plain",
+            "filename": "README.md",
+            "url": "file:///README.md",
         });
-        projection.sessions.remove(&session_id)
-    };
-
-    let Some(session) = removed else {
-        return not_found("Session not found");
-    };
 
-    if let Err(err) = state.delete_session(&session_id).await {
-        return internal_error(err);
-    }
+        assistant_parts.push(tool_part.clone());
+        assistant_parts.push(file_part.clone());
 
-    // Clean up the ACP server instance if one was created for this session.
-    let server_id = session.meta.agent_session_id.clone();
-    if state
-        .acp_initialized
-        .lock()
-        .await
-        .remove(&server_id)
-        .is_some()
-    {
-        if let Some(dispatch) = state.config.acp_dispatch.as_ref() {
-            if let Err(err) = dispatch.delete(&server_id).await {
-                warn!(
-                    ?err,
-                    "failed to delete ACP server instance on session delete"
-                );
+        let mut started = tool_event(
+            "tool.started",
+            &session_id,
+            Some(&assistant_message_id),
+            &call_id,
+            "bash",
+        );
+        started["properties"]["input"] = tool_part["state"]["input"].clone();
+        started["properties"]["time"] = json!({"start": now});
+        state.emit_event(started);
+        let mut completed = tool_event(
+            "tool.completed",
+            &session_id,
+            Some(&assistant_message_id),
+            &call_id,
+            "bash",
+        );
+        completed["properties"]["status"] = json!("completed");
+        completed["properties"]["output"] = tool_part["state"]["output"].clone();
+        completed["properties"]["durationMs"] = json!(0);
+        completed["properties"]["time"] = json!({"start": now, "end": now});
+        state.emit_event(completed);
+        state
+            .project_parts(&session_id, &assistant_info, &assistant_parts)
+            .await;
+        state.emit_event(json!({
+            "type":"message.part.updated",
+            "properties":{
+                "sessionID": session_id,
+                "messageID": assistant_message_id,
+                "part": tool_part
             }
-        }
+        }));
+        state.emit_event(json!({
+            "type":"message.part.updated",
+            "properties":{
+                "sessionID": session_id,
+                "messageID": assistant_message_id,
+                "part": file_part
+            }
+        }));
+        state.emit_event(
+            json!({"type":"file.edited","properties":{"sessionID":session_id, "path":"README.md"}}),
+        );
+    } else {
+        let response_text = if prompt_text.trim().is_empty() {
+            "OK".to_string()
+        } else {
+            prompt_text.clone()
+        };
+        let text_part = json!({
+            "id": part_id(&assistant_message_id, 0),
+            "sessionID": session_id,
+            "messageID": assistant_message_id,
+            "type": "text",
+            "text": response_text,
+        });
+        assistant_parts.push(text_part.clone());
+        state
+            .project_parts(&session_id, &assistant_info, &assistant_parts)
+            .await;
+        state.emit_event(json!({
+            "type":"message.part.updated",
+            "properties":{
+                "sessionID": session_id,
+                "messageID": assistant_message_id,
+                "part": text_part
+            }
+        }));
     }
 
-    // Clean up any pending ACP requests for this session.
-    state
-        .acp_request_ids
-        .lock()
-        .await
-        .retain(|_, req| req.opencode_session_id != session_id);
-
-    let value = session_to_value(&session.meta);
-    state.emit_event(json!({"type":"session.deleted","properties":{"info":value}}));
-
-    (StatusCode::OK, Json(json!(true))).into_response()
-}
-
-async fn oc_session_status(State(state): State<Arc<AdapterState>>) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
+    if let Err(err) = transition_session_with(
+        &state,
+        &session_id,
+        SessionLifecycle::Idle,
+        "turn_completed",
+        Some((&assistant_info, &assistant_parts)),
+    )
+    .await
+    {
         return internal_error(err);
     }
+
     let projection = state.projection.lock().await;
-    let mut map = serde_json::Map::new();
-    for (id, session) in &projection.sessions {
-        map.insert(id.clone(), json!({"type": session.status}));
-    }
-    (StatusCode::OK, Json(Value::Object(map))).into_response()
+    let parts = projection
+        .sessions
+        .get(&session_id)
+        .and_then(|session| {
+            session
+                .messages
+                .iter()
+                .find(|message| {
+                    message.info.get("id").and_then(Value::as_str)
+                        == Some(assistant_message_id.as_str())
+                })
+                .map(|message| message.parts.clone())
+        })
+        .unwrap_or_default();
+
+    (
+        StatusCode::OK,
+        Json(json!({"info": assistant_info, "parts": parts})),
+    )
+        .into_response()
 }
 
-async fn oc_session_abort(
+async fn oc_session_message_get(
     State(state): State<Arc<AdapterState>>,
-    Path(session_id): Path<String>,
+    Path((session_id, message_id)): Path<(String, String)>,
 ) -> Response {
     if let Err(err) = state.ensure_initialized().await {
         return internal_error(err);
     }
-
-    let mut should_emit_idle = false;
-    {
-        let mut projection = state.projection.lock().await;
-        let Some(session) = projection.sessions.get_mut(&session_id) else {
-            return not_found("Session not found");
-        };
-        if session.status != "idle" {
-            session.status = "idle".to_string();
-            should_emit_idle = true;
-        }
-        projection.permissions.retain(|_, value| {
-            value.get("sessionID").and_then(Value::as_str) != Some(session_id.as_str())
-        });
-        projection.questions.retain(|_, value| {
-            value.get("sessionID").and_then(Value::as_str) != Some(session_id.as_str())
-        });
-    }
-
-    if should_emit_idle {
-        let payload = json!({"jsonrpc":"2.0","method":"_sandboxagent/opencode/status","params":{"status":"idle"}});
-        if let Err(err) = state.persist_event(&session_id, "agent", &payload).await {
-            warn!(?err, "failed to persist abort idle status envelope");
-        }
-        state.emit_event(json!({"type":"session.idle","properties":{"sessionID":session_id}}));
+    if let Err(err) = state.ensure_hydrated(&session_id).await {
+        return internal_error(err);
     }
 
-    // Send session/cancel to the ACP agent if dispatch is available.
-    if let Some(dispatch) = state.config.acp_dispatch.as_ref() {
-        let agent_session_id = {
-            let projection = state.projection.lock().await;
-            projection
-                .sessions
-                .get(&session_id)
-                .map(|s| s.meta.agent_session_id.clone())
-        };
-        if let Some(server_id) = agent_session_id {
-            let acp_session_id = state.acp_initialized.lock().await.get(&server_id).cloned();
-            if let Some(acp_sid) = acp_session_id {
-                let cancel_id = state.next_id("oc_rpc_");
-                let cancel_payload = json!({
-                    "jsonrpc": "2.0",
-                    "id": cancel_id,
-                    "method": "session/cancel",
-                    "params": {
-                        "sessionId": acp_sid,
-                    }
-                });
-                if let Err(err) = dispatch.post(&server_id, None, cancel_payload).await {
-                    warn!(?err, "failed to send session/cancel to ACP agent");
-                }
-            }
-        }
-    }
+    let projection = state.projection.lock().await;
+    let Some(session) = projection.sessions.get(&session_id) else {
+        return not_found("Session not found");
+    };
 
-    (StatusCode::OK, Json(json!(true))).into_response()
-}
+    let Some(record) = session.messages.iter().find(|message| {
+        message.info.get("id").and_then(Value::as_str) == Some(message_id.as_str())
+    }) else {
+        return not_found("Message not found");
+    };
 
-async fn oc_session_children() -> Response {
-    (StatusCode::OK, Json(json!([]))).into_response()
+    (
+        StatusCode::OK,
+        Json(json!({
+            "id": message_id,
+            "info": record.info,
+            "parts": record.parts,
+        })),
+    )
+        .into_response()
 }
 
-async fn oc_session_init(
+/// Redact a message for good (e.g. a GDPR erasure request): it leaves the
+/// session, exports, event replays, the history replayed to agents and the
+/// session archive.
+async fn oc_session_message_delete(
     State(state): State<Arc<AdapterState>>,
-    Path(session_id): Path<String>,
-    headers: HeaderMap,
-    Query(query): Query<DirectoryQuery>,
-    body: Option<Json<SessionInitBody>>,
+    Path((session_id, message_id)): Path<(String, String)>,
 ) -> Response {
     if let Err(err) = state.ensure_initialized().await {
         return internal_error(err);
     }
-
-    let directory = resolve_directory(&headers, query.directory.as_ref());
-    if let Err(err) = state.ensure_session(&session_id, directory).await {
+    if let Err(err) = state.ensure_hydrated(&session_id).await {
         return internal_error(err);
     }
 
-    let body = body.map(|json| json.0).unwrap_or(SessionInitBody {
-        provider_id: None,
-        model_id: None,
-        message_id: None,
-    });
-
-    if body.provider_id.is_none() && body.model_id.is_none() {
-        return (StatusCode::OK, Json(json!(true))).into_response();
-    }
-
-    if body.provider_id.is_none() || body.model_id.is_none() {
-        return bad_request("providerID and modelID are required when selecting a model");
-    }
-
-    let provider_id = body.provider_id.unwrap_or_else(|| "mock".to_string());
-    let model_id = body.model_id.unwrap_or_else(|| "mock".to_string());
-
-    let meta = {
-        let mut projection = state.projection.lock().await;
-        let Some(session) = projection.sessions.get_mut(&session_id) else {
+    let part_ids = {
+        let projection = state.projection.lock().await;
+        let Some(session) = projection.sessions.get(&session_id) else {
             return not_found("Session not found");
         };
-        let has_messages = !session.messages.is_empty();
-        let selection_changed =
-            session.meta.provider_id != provider_id || session.meta.model_id != model_id;
-        if has_messages && selection_changed {
-            return bad_request(MODEL_CHANGE_ERROR);
+        let Some(record) = session.messages.iter().find(|message| {
+            message.info.get("id").and_then(Value::as_str) == Some(message_id.as_str())
+        }) else {
+            return not_found("Message not found");
+        };
+        if session.lifecycle.status_type() == "busy" {
+            return conflict("Session is busy; abort the running turn before redacting messages");
         }
-        session.meta.provider_id = provider_id.clone();
-        session.meta.model_id = model_id.clone();
-        session.meta.agent = provider_to_agent(&provider_id);
-        session.meta.updated_at = now_ms();
-        session.meta.clone()
+        record
+            .parts
+            .iter()
+            .filter_map(|part| part.get("id").and_then(Value::as_str))
+            .map(str::to_string)
+            .collect::<Vec<_>>()
     };
 
-    if let Err(err) = state.persist_session(&meta).await {
+    if let Err(err) = state
+        .redact_message(&session_id, &message_id, &part_ids)
+        .await
+    {
         return internal_error(err);
     }
+    state.emit_event(json!({
+        "type": "message.removed",
+        "properties": {"sessionID": session_id, "messageID": message_id}
+    }));
 
     (StatusCode::OK, Json(json!(true))).into_response()
 }
 
-async fn oc_session_fork(
+async fn oc_part_update(
     State(state): State<Arc<AdapterState>>,
-    Path(session_id): Path<String>,
-    headers: HeaderMap,
-    Query(query): Query<DirectoryQuery>,
+    Path((session_id, message_id, part_id)): Path<(String, String, String)>,
+    Json(mut part): Json<Value>,
 ) -> Response {
     if let Err(err) = state.ensure_initialized().await {
         return internal_error(err);
     }
-
-    let parent = {
-        let projection = state.projection.lock().await;
-        projection.sessions.get(&session_id).cloned()
-    };
-    let Some(parent) = parent else {
-        return not_found("Session not found");
-    };
-
-    let id = state.next_id("ses_");
-    let now = now_ms();
-    let directory = resolve_directory(&headers, query.directory.as_ref());
-    let connection_id = state.current_connection_for_agent(&parent.meta.agent).await;
-
-    let meta = SessionMeta {
-        id: id.clone(),
-        slug: format!("session-{id}"),
-        project_id: state.project_id.clone(),
-        directory,
-        parent_id: Some(session_id),
-        title: format!("Fork of {}", parent.meta.title),
-        version: "0".to_string(),
-        created_at: now,
-        updated_at: now,
-        share_url: None,
-        permission_mode: parent.meta.permission_mode.clone(),
-        agent: parent.meta.agent.clone(),
-        provider_id: parent.meta.provider_id.clone(),
-        model_id: parent.meta.model_id.clone(),
-        agent_session_id: format!("acp_{}", state.next_id("ses_")),
-        last_connection_id: connection_id,
-        session_init_json: parent.meta.session_init_json.clone(),
-        destroyed_at: None,
-    };
-
-    if let Err(err) = state.persist_session(&meta).await {
+    if let Err(err) = state.ensure_hydrated(&session_id).await {
         return internal_error(err);
     }
 
-    {
-        let mut projection = state.projection.lock().await;
-        projection.sessions.insert(
-            id.clone(),
-            SessionState {
-                meta: meta.clone(),
-                messages: Vec::new(),
-                status: "idle".to_string(),
-                always_permissions: HashSet::new(),
-            },
-        );
-    }
-
-    let value = session_to_value(&meta);
-    state.emit_event(json!({"type":"session.created","properties":{"info":value}}));
-
-    (StatusCode::OK, Json(value)).into_response()
-}
-
-async fn oc_session_diff() -> Response {
-    (StatusCode::OK, Json(json!([]))).into_response()
-}
-
-async fn oc_session_todo() -> Response {
-    (StatusCode::OK, Json(json!([]))).into_response()
-}
-
-async fn oc_session_summarize(Json(body): Json<Value>) -> Response {
-    if body.get("providerID").is_none() || body.get("modelID").is_none() {
-        return bad_request("providerID and modelID are required");
+    if let Some(obj) = part.as_object_mut() {
+        obj.insert("id".to_string(), json!(part_id.clone()));
+        obj.insert("sessionID".to_string(), json!(session_id.clone()));
+        obj.insert("messageID".to_string(), json!(message_id.clone()));
     }
-    (StatusCode::OK, Json(json!(true))).into_response()
-}
+    state.cap_part_output(&session_id, &mut part).await;
 
-async fn oc_session_messages(
-    State(state): State<Arc<AdapterState>>,
-    Path(session_id): Path<String>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+    {
+        let mut projection = state.projection.lock().await;
+        if let Some(session) = projection.sessions.get_mut(&session_id) {
+            if let Some(message) = session.messages.iter_mut().find(|record| {
+                record.info.get("id").and_then(Value::as_str) == Some(message_id.as_str())
+            }) {
+                if let Some(existing) = message.parts.iter_mut().find(|candidate| {
+                    candidate.get("id").and_then(Value::as_str) == Some(part_id.as_str())
+                }) {
+                    *existing = part.clone();
+                } else {
+                    message.parts.push(part.clone());
+                }
+            }
+        }
     }
 
-    let projection = state.projection.lock().await;
-    let Some(session) = projection.sessions.get(&session_id) else {
-        return not_found("Session not found");
-    };
-
-    let values = session
-        .messages
-        .iter()
-        .map(|record| json!({"info": record.info, "parts": record.parts}))
-        .collect::<Vec<_>>();
+    state.emit_event(json!({
+        "type":"message.part.updated",
+        "properties":{
+            "sessionID": session_id,
+            "messageID": message_id,
+            "part": part.clone()
+        }
+    }));
 
-    (StatusCode::OK, Json(values)).into_response()
+    (StatusCode::OK, Json(part)).into_response()
 }
 
-async fn oc_session_prompt(
+/// The untruncated output of a tool part whose output was capped.
+async fn oc_part_full(
     State(state): State<Arc<AdapterState>>,
-    Path(session_id): Path<String>,
-    headers: HeaderMap,
-    Query(query): Query<DirectoryQuery>,
-    Json(body): Json<PromptBody>,
+    Path(part_id): Path<String>,
 ) -> Response {
     if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-
-    let directory = resolve_directory(&headers, query.directory.as_ref());
-    let mut meta = match state.ensure_session(&session_id, directory.clone()).await {
-        Ok(meta) => meta,
-        Err(err) => return internal_error(err),
-    };
-
-    let explicit_model_selection = prompt_has_explicit_model_selection(&body);
-    let requested_selection = resolve_selection_from_prompt(&body);
-    if explicit_model_selection && requested_selection.is_none() {
-        return bad_request("providerID and modelID are required when selecting a model");
-    }
-
-    let has_messages = {
-        let projection = state.projection.lock().await;
-        projection
-            .sessions
-            .get(&session_id)
-            .map(|session| !session.messages.is_empty())
-            .unwrap_or(false)
-    };
-
-    if let Some(selection) = requested_selection.as_ref() {
-        let selection_changed =
-            meta.provider_id != selection.provider_id || meta.model_id != selection.model_id;
-        if has_messages && selection_changed {
-            return bad_request(MODEL_CHANGE_ERROR);
-        }
-        meta.provider_id = selection.provider_id.clone();
-        meta.model_id = selection.model_id.clone();
-        meta.agent = selection.agent.clone();
-    } else if let Some(agent) = body.agent.as_ref() {
-        if has_messages && meta.agent != *agent {
-            return bad_request(MODEL_CHANGE_ERROR);
-        }
-        meta.agent = agent.clone();
+        return internal_error(err);
     }
-
-    let parts_input = body.parts.unwrap_or_default();
-    if parts_input.is_empty() {
-        return bad_request("parts are required");
+    match state.load_part_blob(&part_id).await {
+        Ok(Some(content)) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            content,
+        )
+            .into_response(),
+        Ok(None) => not_found(&format!("no full output stored for part '{part_id}'")),
+        Err(err) => internal_error(err),
     }
+}
 
-    if let Some(session_mode) = {
-        let projection = state.projection.lock().await;
-        projection
-            .sessions
-            .get(&session_id)
-            .and_then(|session| session.meta.permission_mode.clone())
-    } {
-        meta.permission_mode = Some(session_mode);
+async fn oc_part_delete(
+    State(state): State<Arc<AdapterState>>,
+    Path((session_id, message_id, part_id)): Path<(String, String, String)>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if let Err(err) = state.ensure_hydrated(&session_id).await {
+        return internal_error(err);
     }
 
     {
         let mut projection = state.projection.lock().await;
         if let Some(session) = projection.sessions.get_mut(&session_id) {
-            session.meta.agent = meta.agent.clone();
-            session.meta.provider_id = meta.provider_id.clone();
-            session.meta.model_id = meta.model_id.clone();
-            session.meta.updated_at = now_ms();
-            meta = session.meta.clone();
+            if let Some(message) = session.messages.iter_mut().find(|record| {
+                record.info.get("id").and_then(Value::as_str) == Some(message_id.as_str())
+            }) {
+                message.parts.retain(|part| {
+                    part.get("id").and_then(Value::as_str) != Some(part_id.as_str())
+                });
+            }
         }
     }
 
-    if let Err(err) = state.persist_session(&meta).await {
-        return internal_error(err);
-    }
+    state.emit_event(json!({
+        "type":"message.part.removed",
+        "properties": {"sessionID": session_id, "messageID": message_id, "partID": part_id}
+    }));
 
-    if let Err(err) = state.maybe_restore_session(&session_id).await {
-        return internal_error(err);
-    }
+    (StatusCode::OK, Json(json!(true))).into_response()
+}
 
-    // Re-read meta after maybe_restore_session, which may have generated a new
-    // agent_session_id (e.g. when the agent changed from "mock" to a real agent
-    // and the connection_id differs).
-    {
-        let projection = state.projection.lock().await;
-        if let Some(session) = projection.sessions.get(&session_id) {
-            meta = session.meta.clone();
+tokio::task_local! {
+    /// Signalled by the prompt path once the prompt has been handed to the agent.
+    static TURN_DISPATCHED: StdMutex<Option<oneshot::Sender<()>>>;
+    /// The agent payload the ACP translation task is handling; events emitted
+    /// while it is set carry it for `/event?include=native`.
+    static NATIVE_PAYLOAD: StdMutex<Option<Arc<Value>>>;
+    /// The composer backend whose agent is being handled; events emitted
+    /// while it is set carry it as `properties.backend`.
+    static EVENT_BACKEND: Option<String>;
+}
+
+/// Tell the client waiting on `POST /session/:id/message` that the prompt
+/// reached the agent, so it can receive its turn token. A no-op outside a turn.
+fn mark_turn_dispatched() {
+    let _ = TURN_DISPATCHED.try_with(|slot| {
+        if let Some(sender) = slot.lock().ok().and_then(|mut slot| slot.take()) {
+            let _ = sender.send(());
         }
-    }
+    });
+}
 
-    let user_message_id = body
+/// A turn that was started in the background.
+struct StartedTurn {
+    id: String,
+    token: String,
+    /// The turn's initial `turn.started` payload.
+    started: Value,
+    /// Resolves once the prompt reaches the agent; dropped unsent if the turn
+    /// finishes first.
+    dispatched: oneshot::Receiver<()>,
+}
+
+/// Record a turn and run the prompt in a task of its own, so it finishes
+/// even when the client that started it disconnects.
+async fn start_turn(
+    state: &Arc<AdapterState>,
+    session_id: String,
+    headers: HeaderMap,
+    query: Query<DirectoryQuery>,
+    mut body: PromptBody,
+    queue: bool,
+) -> Result<StartedTurn, TurnStartError> {
+    let turn_id = state.next_id("turn_");
+    let token = random_token().map_err(TurnStartError::Internal)?;
+    let turn_lock = state.turn_locks.claim(&session_id, &turn_id, queue)?;
+    // A turn queued behind its own session takes a slot once it is next.
+    let priority = state.session_priority(&session_id).await;
+    let run_slot = (!turn_lock.is_queued()).then(|| state.turn_slots.claim(priority));
+    let message_id = body
         .message_id
-        .clone()
-        .unwrap_or_else(|| state.next_id("msg_"));
-    let now = now_ms();
+        .get_or_insert_with(|| state.next_id("msg_"))
+        .clone();
+    let record = TurnRecord {
+        session_id: session_id.clone(),
+        message_id,
+        token: token.clone(),
+        status: if turn_lock.is_queued() || run_slot.as_ref().is_some_and(SlotClaim::is_queued) {
+            TurnStatus::Queued
+        } else {
+            TurnStatus::Running
+        },
+        created_at: state.now_ms(),
+        completed_at: None,
+        http_status: None,
+        output: None,
+        timeline: ToolTimeline::default(),
+        snapshot: None,
+        system_prompt: None,
+        reasoning: None,
+        baseline: None,
+        tool_paths: BTreeMap::new(),
+        artifacts: Vec::new(),
+        stream_stats: None,
+        resources: None,
+    };
+    let started = record.to_value(&turn_id);
+    {
+        let mut turns = state.turns.lock().await;
+        turns.push((turn_id.clone(), record));
+        prune_turns(&mut turns);
+    }
+    state.emit_event(json!({"type":"turn.started","properties": started}));
+
+    let (dispatched_tx, dispatched) = oneshot::channel();
+    let task_state = state.clone();
+    let task_turn_id = turn_id.clone();
+    let task_session_id = session_id.clone();
+    let directory_hint = resolve_directory(&headers, query.directory.as_ref());
+    let run = run_session_prompt(
+        State(task_state.clone()),
+        Path(session_id),
+        headers,
+        query,
+        Json(body),
+    );
+    tokio::spawn(
+        TURN_DISPATCHED.scope(StdMutex::new(Some(dispatched_tx)), async move {
+            let queued =
+                turn_lock.is_queued() || run_slot.as_ref().is_some_and(SlotClaim::is_queued);
+            // Both held until the turn ends, or dropped should it panic.
+            let turn_lock = turn_lock.held().await;
+            let run_slot = run_slot
+                .unwrap_or_else(|| task_state.turn_slots.claim(priority))
+                .taken()
+                .await;
+            if queued {
+                let mut turns = task_state.turns.lock().await;
+                if let Some((_, record)) = turns.iter_mut().find(|(id, _)| *id == task_turn_id) {
+                    record.status = TurnStatus::Running;
+                }
+            }
+            if task_state.config.turn_artifacts {
+                task_state
+                    .scan_turn_baseline(&task_session_id, &task_turn_id, directory_hint.clone())
+                    .await;
+            }
+            if task_state.config.workspace_snapshots {
+                task_state
+                    .snapshot_workspace(&task_session_id, &task_turn_id, directory_hint)
+                    .await;
+            }
+            let sampler = (!task_state.config.turn_resource_interval.is_zero()).then(|| {
+                let (stop, stopped) = oneshot::channel();
+                let sampling = tokio::spawn(task_state.clone().sample_turn_resources(
+                    task_session_id.clone(),
+                    task_turn_id.clone(),
+                    stopped,
+                ));
+                (stop, sampling)
+            });
+            let response = run.await;
+            let resources = match sampler {
+                Some((stop, sampling)) => {
+                    let _ = stop.send(());
+                    sampling.await.ok().and_then(|resources| resources.usage())
+                }
+                None => None,
+            };
+            drop(run_slot);
+            drop(turn_lock);
+            let http_status = response.status();
+            let status = if http_status.is_success() {
+                TurnStatus::Completed
+            } else {
+                TurnStatus::Error
+            };
+            let output = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .ok()
+                .and_then(|bytes| serde_json::from_slice::<Value>(&bytes).ok());
+            let artifacts = task_state.collect_turn_artifacts(&task_turn_id).await;
 
-    let user_info = build_user_message(
-        &session_id,
-        &user_message_id,
-        now,
-        &meta.agent,
-        &meta.provider_id,
-        &meta.model_id,
-        body.system.as_deref(),
+            let (completed, timeline, resources) = {
+                let mut turns = task_state.turns.lock().await;
+                let Some((_, record)) = turns.iter_mut().find(|(id, _)| *id == task_turn_id) else {
+                    return;
+                };
+                let now = task_state.now_ms();
+                record.status = status;
+                record.completed_at = Some(now);
+                record.http_status = Some(http_status);
+                record.output = output;
+                record.artifacts = artifacts;
+                record.resources = resources.map(|mut resources| {
+                    if record.baseline.is_some() {
+                        resources.workspace_write_bytes =
+                            Some(record.artifacts.iter().map(|artifact| artifact.size).sum());
+                    }
+                    resources
+                });
+                for span in record.timeline.finish(now) {
+                    task_state.emit_event(tool_end_event(&record.session_id, None, &span, None));
+                }
+                (
+                    record.to_value(&task_turn_id),
+                    record.timeline_value(&task_turn_id),
+                    record.resources.clone(),
+                )
+            };
+            if let (Some(metrics), Some(resources)) =
+                (task_state.config.metrics.as_ref(), resources.as_ref())
+            {
+                let agent = task_state
+                    .projection
+                    .lock()
+                    .await
+                    .sessions
+                    .get(&task_session_id)
+                    .map(|session| session.meta.agent.clone())
+                    .unwrap_or_default();
+                metrics.turn_resources(
+                    &agent,
+                    Duration::from_secs_f64(resources.cpu_seconds),
+                    resources.peak_rss_bytes,
+                    resources.disk_write_bytes,
+                );
+            }
+            if let Err(err) = task_state
+                .persist_turn_timeline(&task_turn_id, &timeline)
+                .await
+            {
+                warn!(?err, turn_id = %task_turn_id, "failed to persist turn timeline");
+            }
+            task_state.register_turn_artifacts(&task_turn_id).await;
+            task_state.turn_finished.notify_waiters();
+            task_state.emit_event(json!({"type":"turn.completed","properties": completed}));
+        }),
     );
-    let user_parts = normalize_parts(&session_id, &user_message_id, &parts_input);
 
-    let replay_injected = state.pending_replay.lock().await.remove(&session_id);
-    let outbound_prompt_parts = if let Some(replay_text) = replay_injected {
-        let mut prompt = vec![json!({"type":"text", "text": replay_text})];
-        prompt.extend(parts_input.clone());
-        prompt
-    } else {
-        parts_input.clone()
-    };
+    Ok(StartedTurn {
+        id: turn_id,
+        token,
+        started,
+        dispatched,
+    })
+}
 
-    let prompt_envelope = json!({
-        "jsonrpc": "2.0",
-        "id": state.next_id("oc_req_"),
-        "method": "session/prompt",
-        "params": {
-            "sessionId": meta.agent_session_id,
-            "prompt": outbound_prompt_parts,
-            "sessionID": session_id,
-            "message": {
-                "info": user_info,
-                "parts": user_parts,
+impl AdapterState {
+    /// Sample the agent processes behind `session_id` every
+    /// `turn_resource_interval` until `stopped` fires, publishing the usage
+    /// to the turn as it grows. Takes a last sample as the turn finishes.
+    async fn sample_turn_resources(
+        self: Arc<Self>,
+        session_id: String,
+        turn_id: String,
+        mut stopped: oneshot::Receiver<()>,
+    ) -> TurnResources {
+        let mut resources = TurnResources::default();
+        let mut ticks = tokio::time::interval(self.config.turn_resource_interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            let finished = tokio::select! {
+                _ = ticks.tick() => false,
+                _ = &mut stopped => true,
+            };
+            let roots = self.agent_pids(&session_id).await;
+            let processes =
+                tokio::task::spawn_blocking(move || turn_resources::sample_tree(&roots))
+                    .await
+                    .unwrap_or_default();
+            resources.record(&processes);
+            if finished {
+                return resources;
+            }
+            if let Some(usage) = resources.usage() {
+                let mut turns = self.turns.lock().await;
+                if let Some((_, record)) = turns.iter_mut().find(|(id, _)| *id == turn_id) {
+                    record.resources = Some(usage);
+                }
             }
         }
-    });
-    if let Err(err) = state
-        .persist_event(&session_id, "client", &prompt_envelope)
-        .await
-    {
-        return internal_error(err);
     }
 
-    state.emit_event(message_event("message.updated", &user_info));
-    for part in &user_parts {
-        state.emit_event(json!({
-            "type":"message.part.updated",
-            "properties":{
-                "sessionID": session_id,
-                "messageID": user_message_id,
-                "part": part
-            }
-        }));
+    /// OS process IDs of the agents running `session_id` and its composer
+    /// backends, as far as the dispatch backend knows them.
+    async fn agent_pids(&self, session_id: &str) -> Vec<u32> {
+        let Some(dispatch) = self.config.acp_dispatch.as_ref() else {
+            return Vec::new();
+        };
+        let server_ids = match self.projection.lock().await.sessions.get(session_id) {
+            Some(session) => session.meta.acp_server_ids(),
+            None => return Vec::new(),
+        };
+        dispatch
+            .instances()
+            .await
+            .into_iter()
+            .filter(|instance| server_ids.contains(&instance.server_id))
+            .filter_map(|instance| instance.pid)
+            .collect()
     }
 
-    // Track the user message ID so the SSE translation task can set
-    // parentID on assistant messages.
-    state
-        .last_user_message_id
-        .lock()
-        .await
-        .insert(session_id.clone(), user_message_id.clone());
-
-    if let Err(err) = set_session_status(&state, &session_id, "busy").await {
-        return internal_error(err);
+    /// The session's scheduling priority; `Normal` for sessions that do not
+    /// exist yet.
+    async fn session_priority(&self, session_id: &str) -> SessionPriority {
+        if self.ensure_hydrated(session_id).await.is_err() {
+            return SessionPriority::Normal;
+        }
+        self.projection
+            .lock()
+            .await
+            .sessions
+            .get(session_id)
+            .map(|session| session.meta.priority)
+            .unwrap_or_default()
     }
 
-    // -----------------------------------------------------------------------
-    // ACP dispatch path — route to real agent processes when acp_dispatch is
-    // configured and the resolved agent is not "mock".
-    // -----------------------------------------------------------------------
-    tracing::info!(
-        session_id = %session_id,
-        agent = %meta.agent,
-        provider_id = %meta.provider_id,
-        model_id = %meta.model_id,
-        has_acp_dispatch = state.config.acp_dispatch.is_some(),
-        "prompt dispatch decision"
-    );
-    if let Some(dispatch) = state.config.acp_dispatch.as_ref() {
-        if meta.agent != "mock" {
-            let server_id = meta.agent_session_id.clone();
+    /// The shadow repository holding a session's turn snapshots, next to the
+    /// database.
+    fn shadow_repo(&self, session_id: &str) -> ShadowRepo {
+        ShadowRepo::new(
+            std::path::Path::new(&self.sqlite_path)
+                .with_file_name("opencode-snapshots")
+                .join(session_id),
+        )
+    }
 
-            tracing::info!(server_id = %server_id, agent = %meta.agent, "entering ACP dispatch path");
+    /// Stamp the session directory's files for a starting turn, so the ones
+    /// it changes can be registered as artifacts when it finishes.
+    async fn scan_turn_baseline(&self, session_id: &str, turn_id: &str, directory: String) {
+        let meta = match self.ensure_hydrated(session_id).await {
+            Ok(()) => self.ensure_session(session_id, directory).await,
+            Err(err) => Err(err),
+        };
+        let root = match meta {
+            Ok(meta) => PathBuf::from(meta.directory),
+            Err(err) => {
+                warn!(?err, %session_id, %turn_id, "failed to scan workspace for artifacts");
+                return;
+            }
+        };
+        let Ok(baseline) =
+            tokio::task::spawn_blocking(move || artifacts::Baseline::scan(&root)).await
+        else {
+            return;
+        };
+        let mut turns = self.turns.lock().await;
+        if let Some((_, record)) = turns.iter_mut().find(|(id, _)| id == turn_id) {
+            record.baseline = Some(Arc::new(baseline));
+        }
+    }
 
-            // Bootstrap the ACP server instance if this is the first prompt.
-            let needs_init = !state.acp_initialized.lock().await.contains_key(&server_id);
-            if needs_init {
-                tracing::info!(server_id = %server_id, "bootstrapping ACP session (initialize + session/new)");
-                // 1) initialize
-                let init_id = state.next_id("oc_rpc_");
-                let init_payload = json!({
-                    "jsonrpc": "2.0",
-                    "id": init_id,
-                    "method": "initialize",
-                    "params": {
-                        "protocolVersion": 1,
-                        "capabilities": {},
-                        "clientInfo": {
-                            "name": "sandbox-agent-opencode-adapter",
-                            "version": "0.1.0"
-                        },
-                        "_meta": {
-                            "sandboxagent.dev": {
-                                "agent": meta.agent.clone()
-                            }
-                        }
-                    }
-                });
-                match dispatch
-                    .post(&server_id, Some(&meta.agent), init_payload)
-                    .await
-                {
-                    Ok(AcpDispatchResult::Response(ref resp)) => {
-                        if let Some(err) = resp.get("error") {
-                            tracing::error!(server_id = %server_id, error = %err, "ACP initialize returned JSON-RPC error");
-                            let _ = set_session_status(&state, &session_id, "idle").await;
-                            return internal_error(format!("ACP initialize error: {err}"));
-                        }
-                        tracing::info!(server_id = %server_id, "ACP initialize succeeded");
-                    }
-                    Ok(AcpDispatchResult::Accepted) => {
-                        tracing::info!(server_id = %server_id, "ACP initialize accepted");
-                    }
-                    Err(err) => {
-                        let _ = set_session_status(&state, &session_id, "idle").await;
-                        return internal_error(format!("ACP initialize failed: {err}"));
-                    }
-                }
+    /// The files a finishing turn created or modified; none if the turn has
+    /// no baseline.
+    async fn collect_turn_artifacts(&self, turn_id: &str) -> Vec<artifacts::Artifact> {
+        let (baseline, tool_paths) = {
+            let turns = self.turns.lock().await;
+            let Some((baseline, tool_paths)) =
+                turns
+                    .iter()
+                    .find(|(id, _)| id == turn_id)
+                    .and_then(|(_, record)| {
+                        Some((record.baseline.clone()?, record.tool_paths.clone()))
+                    })
+            else {
+                return Vec::new();
+            };
+            (baseline, tool_paths)
+        };
+        let ids = self.config.id_generator.clone();
+        tokio::task::spawn_blocking(move || {
+            artifacts::collect(&baseline, &tool_paths, || ids.next_id("art_"))
+        })
+        .await
+        .unwrap_or_default()
+    }
 
-                // 2) session/new
-                let new_id = state.next_id("oc_rpc_");
-                let new_payload = json!({
-                    "jsonrpc": "2.0",
-                    "id": new_id,
-                    "method": "session/new",
-                    "params": {
-                        "cwd": directory,
-                        "mcpServers": [],
-                        "_meta": {
-                            "sandboxagent.dev": {
-                                "model": meta.model_id.clone()
-                            }
-                        }
-                    }
-                });
-                let acp_session_id = match dispatch.post(&server_id, None, new_payload).await {
-                    Ok(AcpDispatchResult::Response(ref resp)) => {
-                        if let Some(err) = resp.get("error") {
-                            tracing::error!(server_id = %server_id, error = %err, "ACP session/new returned JSON-RPC error");
-                            let _ = set_session_status(&state, &session_id, "idle").await;
-                            return internal_error(format!("ACP session/new error: {err}"));
-                        }
-                        let sid = resp
-                            .pointer("/result/sessionId")
-                            .and_then(Value::as_str)
-                            .unwrap_or("")
-                            .to_string();
-                        tracing::info!(server_id = %server_id, acp_session_id = %sid, "ACP session/new succeeded");
-                        sid
-                    }
-                    Ok(AcpDispatchResult::Accepted) => {
-                        tracing::info!(server_id = %server_id, "ACP session/new accepted");
-                        String::new()
-                    }
-                    Err(err) => {
-                        let _ = set_session_status(&state, &session_id, "idle").await;
-                        return internal_error(format!("ACP session/new failed: {err}"));
-                    }
-                };
+    /// Keep a finished turn's artifacts once the turn is no longer tracked,
+    /// and announce them with `turn.artifacts`.
+    async fn register_turn_artifacts(&self, turn_id: &str) {
+        let listing = {
+            let turns = self.turns.lock().await;
+            match turns.iter().find(|(id, _)| id == turn_id) {
+                Some((_, record)) if record.baseline.is_some() => record.artifacts_value(turn_id),
+                _ => return,
+            }
+        };
+        if let Err(err) = self.persist_turn_artifacts(turn_id, &listing).await {
+            warn!(?err, %turn_id, "failed to persist turn artifacts");
+        }
+        if listing["artifacts"]
+            .as_array()
+            .is_some_and(|artifacts| !artifacts.is_empty())
+        {
+            self.emit_event(json!({"type":"turn.artifacts","properties": listing}));
+        }
+    }
 
-                // 3) Start SSE translation task.
-                match dispatch.notification_stream(&server_id, None).await {
-                    Ok(stream) => {
-                        let state_for_task = state.clone();
-                        let session_id_for_task = session_id.clone();
-                        let directory_for_task = directory.clone();
-                        let agent_for_task = meta.agent.clone();
-                        let provider_for_task = meta.provider_id.clone();
-                        let model_for_task = meta.model_id.clone();
-                        tokio::spawn(acp_sse_translation_task(
-                            state_for_task,
-                            stream,
-                            session_id_for_task,
-                            directory_for_task,
-                            agent_for_task,
-                            provider_for_task,
-                            model_for_task,
-                        ));
-                    }
-                    Err(err) => {
-                        warn!(
-                            ?err,
-                            "failed to open ACP SSE stream; events will not be translated"
-                        );
-                    }
-                }
+    /// A turn's artifact listing, from memory while the turn is tracked and
+    /// from the database after.
+    async fn turn_artifacts(
+        &self,
+        session_id: &str,
+        turn_id: &str,
+    ) -> Result<Option<Value>, String> {
+        let tracked = {
+            let turns = self.turns.lock().await;
+            turns
+                .iter()
+                .find(|(id, record)| *id == turn_id && record.session_id == session_id)
+                .map(|(id, record)| record.artifacts_value(id))
+        };
+        match tracked {
+            Some(listing) => Ok(Some(listing)),
+            None => self.load_turn_artifacts(session_id, turn_id).await,
+        }
+    }
 
-                state
-                    .acp_initialized
-                    .lock()
+    /// Snapshot the session directory for a starting turn. A failed snapshot
+    /// is logged and the turn runs without one.
+    async fn snapshot_workspace(&self, session_id: &str, turn_id: &str, directory: String) {
+        if !workspace_snapshot::valid_id(session_id) || !workspace_snapshot::valid_id(turn_id) {
+            return;
+        }
+        let meta = match self.ensure_hydrated(session_id).await {
+            Ok(()) => self.ensure_session(session_id, directory).await,
+            Err(err) => Err(err),
+        };
+        let snapshot = match meta {
+            Ok(meta) => {
+                self.shadow_repo(session_id)
+                    .snapshot(std::path::Path::new(&meta.directory), turn_id)
                     .await
-                    .insert(server_id.clone(), acp_session_id);
             }
-
-            // 4) Send session/prompt
-            let acp_session_id = state
-                .acp_initialized
-                .lock()
-                .await
-                .get(&server_id)
-                .cloned()
-                .unwrap_or_default();
-            let prompt_id = state.next_id("oc_rpc_");
-            let prompt_payload = json!({
-                "jsonrpc": "2.0",
-                "id": prompt_id,
-                "method": "session/prompt",
-                "params": {
-                    "sessionId": acp_session_id,
-                    "prompt": outbound_prompt_parts,
-                }
-            });
-            // dispatch.post() blocks until the agent returns the session/prompt
-            // response.  The response is also broadcast to the notification stream
-            // so the SSE translation task sees it in-order after all session/update
-            // notifications and can emit session.idle at the right time.
-            match dispatch.post(&server_id, None, prompt_payload).await {
-                Ok(AcpDispatchResult::Response(ref resp)) => {
-                    if let Some(err) = resp.get("error") {
-                        tracing::error!(server_id = %server_id, error = %err, "ACP session/prompt returned JSON-RPC error");
-                        let _ = set_session_status(&state, &session_id, "idle").await;
-                        return internal_error(format!("ACP session/prompt error: {err}"));
-                    }
-                    tracing::info!(server_id = %server_id, "ACP session/prompt response received (turn completion delegated to SSE task)");
-                }
-                Ok(AcpDispatchResult::Accepted) => {
-                    tracing::info!(server_id = %server_id, "ACP session/prompt accepted (streaming)");
-                }
-                Err(err) => {
-                    let _ = set_session_status(&state, &session_id, "idle").await;
-                    return internal_error(format!("ACP session/prompt failed: {err}"));
+            Err(err) => Err(err),
+        };
+        match snapshot {
+            Ok(commit) => {
+                let mut turns = self.turns.lock().await;
+                if let Some((_, record)) = turns.iter_mut().find(|(id, _)| id == turn_id) {
+                    record.snapshot = Some(commit);
                 }
-            };
+            }
+            Err(err) => warn!(?err, %session_id, %turn_id, "failed to snapshot workspace"),
+        }
+    }
+}
 
-            // The SSE translation task handles session.idle and streamed
-            // content, but the HTTP response needs the pending assistant
-            // message envelope so the client can correlate future events.
-            let assistant_message = build_assistant_message(
-                &session_id,
-                &format!("{user_message_id}_pending"),
-                &user_message_id,
-                now,
-                &directory,
-                &meta.agent,
-                &meta.provider_id,
-                &meta.model_id,
-            );
-            return (
-                StatusCode::OK,
-                Json(json!({
-                    "info": assistant_message,
-                    "parts": [],
-                })),
-            )
-                .into_response();
+/// Wait for a turn to finish. `None` if it is not tracked (any more).
+async fn wait_for_turn(state: Arc<AdapterState>, turn_id: String) -> Option<TurnRecord> {
+    loop {
+        let finished = state.turn_finished.notified();
+        tokio::pin!(finished);
+        finished.as_mut().enable();
+        {
+            let turns = state.turns.lock().await;
+            let (_, record) = turns.iter().find(|(id, _)| *id == turn_id)?;
+            if record.status.is_finished() {
+                return Some(record.clone());
+            }
         }
+        finished.await;
+    }
+}
+
+/// The prompt response a finished turn produced.
+fn turn_response(record: &TurnRecord) -> Response {
+    let status = record.http_status.unwrap_or(StatusCode::OK);
+    match record.output.as_ref() {
+        Some(output) => (status, Json(output.clone())).into_response(),
+        None => status.into_response(),
     }
+}
 
-    let prompt_text = parts_input
+fn with_turn_token(mut response: Response, token: &str) -> Response {
+    if let Ok(value) = HeaderValue::from_str(token) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(TURN_TOKEN_HEADER), value);
+    }
+    response
+}
+
+/// Run a prompt and return its response. The turn runs in the background, so
+/// a client that loses the connection can pick the result up again through
+/// `GET /session/:id/turn/by-token/:token`. Once the prompt reaches the agent
+/// the 200 status and the token header are sent right away, and the body
+/// follows when the turn finishes.
+async fn oc_session_prompt(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+    query: Query<DirectoryQuery>,
+    Query(turn_query): Query<TurnQuery>,
+    StrictJson(body): StrictJson<PromptBody>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let turn = match start_turn(&state, session_id, headers, query, body, turn_query.queue).await {
+        Ok(turn) => turn,
+        Err(err) => return err.into_response(),
+    };
+
+    let mut finished = Box::pin(wait_for_turn(state.clone(), turn.id));
+    let response = tokio::select! {
+        biased;
+        record = &mut finished => match record {
+            Some(record) => turn_response(&record),
+            None => not_found("Turn not found"),
+        },
+        Ok(()) = turn.dispatched => {
+            let body = stream::once(async move {
+                let output = finished.await.and_then(|record| record.output).unwrap_or(Value::Null);
+                Ok::<_, Infallible>(serde_json::to_vec(&output).unwrap_or_default())
+            });
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from_stream(body))
+                .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    };
+    with_turn_token(response, &turn.token)
+}
+
+/// Start a prompt in the background and return its turn ID immediately. The
+/// turn runs the same path as `POST /session/:id/message`; its outcome is
+/// available from `GET /session/:id/turn/:turnID` and `turn.completed`.
+async fn oc_session_prompt_async(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+    query: Query<DirectoryQuery>,
+    Query(turn_query): Query<TurnQuery>,
+    StrictJson(body): StrictJson<PromptBody>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if body.parts.as_ref().is_none_or(Vec::is_empty) {
+        return bad_request("parts are required");
+    }
+    if let Err(err) = ReasoningBudget::new(body.variant.as_deref(), body.reasoning_budget) {
+        return bad_request(&err);
+    }
+
+    match start_turn(&state, session_id, headers, query, body, turn_query.queue).await {
+        Ok(turn) => with_turn_token(
+            (StatusCode::ACCEPTED, Json(turn.started)).into_response(),
+            &turn.token,
+        ),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Drop the oldest finished turns once more than [`MAX_TRACKED_TURNS`] are held.
+fn prune_turns(turns: &mut Vec<(String, TurnRecord)>) {
+    let mut excess = turns.len().saturating_sub(MAX_TRACKED_TURNS);
+    turns.retain(|(_, record)| {
+        if excess > 0 && record.status.is_finished() {
+            excess -= 1;
+            return false;
+        }
+        true
+    });
+}
+
+async fn oc_session_turn_get(
+    State(state): State<Arc<AdapterState>>,
+    Path((session_id, turn_id)): Path<(String, String)>,
+) -> Response {
+    let turns = state.turns.lock().await;
+    match turns
         .iter()
-        .find_map(|part| part.get("text").and_then(Value::as_str))
-        .unwrap_or("")
-        .to_string();
+        .find(|(id, record)| *id == turn_id && record.session_id == session_id)
+    {
+        Some((id, record)) => (StatusCode::OK, Json(record.to_value(id))).into_response(),
+        None => not_found("Turn not found"),
+    }
+}
 
-    let auto_allow = {
-        let projection = state.projection.lock().await;
-        projection
-            .sessions
-            .get(&session_id)
-            .map(|session| session.always_permissions.contains("execute"))
-            .unwrap_or(false)
+/// Put the session directory back the way it was when the turn started,
+/// undoing the file changes of that turn and every turn after it.
+async fn oc_session_turn_rollback(
+    State(state): State<Arc<AdapterState>>,
+    Path((session_id, turn_id)): Path<(String, String)>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if !workspace_snapshot::valid_id(&session_id) || !workspace_snapshot::valid_id(&turn_id) {
+        return not_found("Turn not found");
+    }
+    // Hold the session like a turn so no prompt edits files mid-restore.
+    let rollback_id = state.next_id("rollback_");
+    let lock = match state.turn_locks.claim(&session_id, &rollback_id, false) {
+        Ok(lock) => lock,
+        Err(err) => return TurnStartError::Locked(err).into_response(),
+    };
+    let repo = state.shadow_repo(&session_id);
+    let restored = match repo.find(&turn_id).await {
+        Ok(Some((commit, directory))) => repo
+            .restore(&directory, &commit)
+            .await
+            .map(|restored| Some((commit, directory, restored))),
+        Ok(None) => Ok(None),
+        Err(err) => Err(err),
+    };
+    drop(lock);
+    let (commit, directory, restored) = match restored {
+        Ok(Some(restored)) => restored,
+        Ok(None) => return not_found("Turn has no workspace snapshot"),
+        Err(err) => return internal_error(err),
     };
+    let properties = json!({
+        "sessionID": session_id,
+        "turnID": turn_id,
+        "snapshot": commit,
+        "directory": directory.display().to_string(),
+        "restored": restored.restored,
+        "removed": restored.removed,
+    });
+    state.emit_event(json!({"type":"workspace.rolled_back","properties": properties}));
+    (StatusCode::OK, Json(properties)).into_response()
+}
 
-    if prompt_text.to_ascii_lowercase().contains("permission") {
-        let request_id = state.next_id("perm_");
-        let permission_request = json!({
-            "id": request_id,
-            "sessionID": session_id,
-            "permission": "execute",
-            "patterns": ["*"],
-            "metadata": {},
-            "always": [],
-        });
-        let asked = json!({
-            "jsonrpc":"2.0",
-            "method":"_sandboxagent/opencode/permission_asked",
-            "params":{"request": permission_request}
-        });
-        if let Err(err) = state.persist_event(&session_id, "agent", &asked).await {
-            return internal_error(err);
+/// Tool calls of a turn with their timing, status and output size. Live while
+/// the turn runs; read from SQLite once it is no longer tracked.
+async fn oc_session_turn_timeline(
+    State(state): State<Arc<AdapterState>>,
+    Path((session_id, turn_id)): Path<(String, String)>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let tracked = {
+        let turns = state.turns.lock().await;
+        turns
+            .iter()
+            .find(|(id, record)| *id == turn_id && record.session_id == session_id)
+            .map(|(id, record)| record.timeline_value(id))
+    };
+    let timeline = match tracked {
+        Some(timeline) => Some(timeline),
+        None => match state.load_turn_timeline(&session_id, &turn_id).await {
+            Ok(timeline) => timeline,
+            Err(err) => return internal_error(err),
+        },
+    };
+    match timeline {
+        Some(timeline) => (StatusCode::OK, Json(timeline)).into_response(),
+        None => not_found("Turn not found"),
+    }
+}
+
+/// The files a turn created or modified, with hashes, MIME types and links to
+/// download them.
+async fn oc_session_turn_artifacts(
+    State(state): State<Arc<AdapterState>>,
+    Path((session_id, turn_id)): Path<(String, String)>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    match state.turn_artifacts(&session_id, &turn_id).await {
+        Ok(Some(listing)) => (StatusCode::OK, Json(listing)).into_response(),
+        Ok(None) => not_found("Turn not found"),
+        Err(err) => internal_error(err),
+    }
+}
+
+/// Download an artifact's content. Refused with 409 once the file no longer
+/// has the content the turn left it with.
+async fn oc_session_turn_artifact_download(
+    State(state): State<Arc<AdapterState>>,
+    Path((session_id, turn_id, artifact_id)): Path<(String, String, String)>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    let listing = match state.turn_artifacts(&session_id, &turn_id).await {
+        Ok(Some(listing)) => listing,
+        Ok(None) => return not_found("Turn not found"),
+        Err(err) => return internal_error(err),
+    };
+    let Some(artifact) = listing["artifacts"]
+        .as_array()
+        .and_then(|artifacts| {
+            artifacts
+                .iter()
+                .find(|artifact| artifact["id"] == artifact_id)
+        })
+        .cloned()
+    else {
+        return not_found("Artifact not found");
+    };
+    let absolute = PathBuf::from(artifact["absolute"].as_str().unwrap_or_default());
+    let read = tokio::task::spawn_blocking(move || artifacts::read(&absolute));
+    let bytes = match read.await {
+        Ok(Ok(bytes)) => bytes,
+        Ok(Err(err @ artifacts::ReadError::NotFound)) => return not_found(&err.to_string()),
+        Ok(Err(err @ artifacts::ReadError::TooLarge { .. })) => {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(json!({"errors":[{"message": err.to_string()}]})),
+            )
+                .into_response()
         }
-        state.emit_event(json!({"type":"permission.asked","properties":permission_request}));
+        Ok(Err(err)) => return internal_error(err.to_string()),
+        Err(err) => return internal_error(err.to_string()),
+    };
+    let sha256 = artifact["sha256"].as_str().unwrap_or_default();
+    if hex::encode(Sha256::digest(&bytes)) != sha256 {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({"errors":[{
+                "message": "Artifact changed after the turn that produced it",
+                "name": "ArtifactChangedError",
+                "data": {"artifactID": artifact_id, "path": artifact["path"]},
+            }]})),
+        )
+            .into_response();
+    }
+    let filename = std::path::Path::new(artifact["path"].as_str().unwrap_or_default())
+        .file_name()
+        .map(|name| name.to_string_lossy().replace(['"', '\\'], "_"))
+        .unwrap_or_default();
+    let mut response = (StatusCode::OK, bytes).into_response();
+    let headers = response.headers_mut();
+    for (name, value) in [
+        (
+            header::CONTENT_TYPE,
+            artifact["mimeType"]
+                .as_str()
+                .unwrap_or("application/octet-stream")
+                .to_string(),
+        ),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{filename}\""),
+        ),
+        (header::ETAG, format!("\"{sha256}\"")),
+    ] {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+    response
+}
 
-        if auto_allow {
-            if let Err(err) =
-                resolve_permission_inner(&state, &session_id, &request_id, "always").await
-            {
-                return internal_error(err);
-            }
+/// Re-attach to a turn with the token from its prompt response: waits for the
+/// turn to finish and returns the response the prompt request would have.
+async fn oc_session_turn_attach(
+    State(state): State<Arc<AdapterState>>,
+    Path((session_id, token)): Path<(String, String)>,
+) -> Response {
+    let turn_id = {
+        let turns = state.turns.lock().await;
+        turns
+            .iter()
+            .find(|(_, record)| record.session_id == session_id && record.token == token)
+            .map(|(id, _)| id.clone())
+    };
+    let Some(turn_id) = turn_id else {
+        return not_found("Turn not found");
+    };
+    match wait_for_turn(state, turn_id).await {
+        Some(record) => with_turn_token(turn_response(&record), &token),
+        None => not_found("Turn not found"),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionLogsQuery {
+    source: Option<String>,
+    #[serde(rename = "correlationID")]
+    correlation_id: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ClientLogBody {
+    service: Option<String>,
+    level: Option<String>,
+    message: String,
+    #[serde(default)]
+    extra: serde_json::Map<String, Value>,
+}
+
+/// A log entry from an OpenCode client. It is written to the server log
+/// under the `opencode_client` target and, when `extra` names a session,
+/// added to that session's logs with source `client`.
+async fn oc_log(
+    State(state): State<Arc<AdapterState>>,
+    StrictJson(body): StrictJson<ClientLogBody>,
+) -> Response {
+    let level = body.level.as_deref().unwrap_or("info");
+    let service = body.service.as_deref().unwrap_or("client");
+    let session_id = ["sessionID", "sessionId", "session_id"]
+        .iter()
+        .find_map(|key| body.extra.get(*key).and_then(Value::as_str))
+        .map(ToOwned::to_owned);
+    let extra = Value::Object(body.extra.clone());
+    // Not `session_id`: the entry is added to the session's logs below, and
+    // the session log layer would record it a second time.
+    match level {
+        "debug" => {
+            tracing::debug!(target: logs::CLIENT_LOG_TARGET, service, client_session_id = ?session_id, %extra, "{}", body.message)
+        }
+        "info" => {
+            tracing::info!(target: logs::CLIENT_LOG_TARGET, service, client_session_id = ?session_id, %extra, "{}", body.message)
+        }
+        "warn" => {
+            tracing::warn!(target: logs::CLIENT_LOG_TARGET, service, client_session_id = ?session_id, %extra, "{}", body.message)
+        }
+        "error" => {
+            tracing::error!(target: logs::CLIENT_LOG_TARGET, service, client_session_id = ?session_id, %extra, "{}", body.message)
         }
+        other => return bad_request(&format!("unknown log level: {other}")),
+    }
 
-        let assistant_info = build_assistant_message(
+    if let Some(session_id) = session_id {
+        let correlation_id = ["messageID", "messageId", "message_id"]
+            .iter()
+            .find_map(|key| body.extra.get(*key).and_then(Value::as_str))
+            .map(|message_id| logs::correlation_id(&session_id, message_id));
+        let mut fields = body.extra;
+        fields.insert("service".to_string(), json!(service));
+        logs::session_logs().push(
             &session_id,
-            &format!("{user_message_id}_pending"),
-            &user_message_id,
-            now,
-            &directory,
-            &meta.agent,
-            &meta.provider_id,
-            &meta.model_id,
+            logs::SessionLogEntry {
+                time: state.now_ms(),
+                level: level.to_string(),
+                source: "client",
+                target: logs::CLIENT_LOG_TARGET.to_string(),
+                message: body.message,
+                correlation_id,
+                fields,
+            },
         );
+    }
+    (StatusCode::OK, Json(json!(true))).into_response()
+}
 
-        return (
-            StatusCode::OK,
-            Json(json!({"info": assistant_info, "parts": []})),
+/// Server, agent and client log lines recorded for a session, oldest first. Lines
+/// are buffered in memory, so only logs since the server started are kept.
+async fn oc_session_logs(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    Query(query): Query<SessionLogsQuery>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    if !state
+        .projection
+        .lock()
+        .await
+        .sessions
+        .contains_key(&session_id)
+    {
+        return not_found("Session not found");
+    }
+
+    let mut entries = logs::session_logs().entries(&session_id);
+    entries.retain(|entry| {
+        query
+            .source
+            .as_deref()
+            .is_none_or(|source| entry.source == source)
+            && query
+                .correlation_id
+                .as_deref()
+                .is_none_or(|id| entry.correlation_id.as_deref() == Some(id))
+    });
+    if let Some(limit) = query.limit {
+        let skip = entries.len().saturating_sub(limit);
+        entries.drain(..skip);
+    }
+    (StatusCode::OK, Json(json!(entries))).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct TranscriptQuery {
+    /// Keep the response open and stream events as they are persisted.
+    #[serde(default)]
+    follow: bool,
+    /// Skip the first `offset` events, to resume an earlier read.
+    #[serde(default)]
+    offset: u64,
+}
+
+/// How far a transcript has read its session's event log.
+struct TranscriptReader {
+    session_id: String,
+    events: transcript::EnvelopeEvents,
+    translator: Box<dyn TranscriptTranslator>,
+    /// `rowid` of the last envelope read.
+    cursor: i64,
+    rendered: u64,
+    offset: u64,
+}
+
+impl TranscriptReader {
+    /// NDJSON lines of the envelopes persisted since the last read.
+    async fn read(&mut self, state: &AdapterState) -> Result<Vec<u8>, String> {
+        let pool = state.pool().await?;
+        let rows = sqlx::query(
+            r#"SELECT rowid, created_at, payload_json
+               FROM events
+               WHERE session_id = ?1 AND rowid > ?2
+               ORDER BY rowid ASC"#,
         )
-            .into_response();
+        .bind(&self.session_id)
+        .bind(self.cursor)
+        .fetch_all(pool)
+        .await
+        .map_err(|err| err.to_string())?;
+
+        let mut lines = Vec::new();
+        for row in rows {
+            self.cursor = row.try_get("rowid").map_err(|err| err.to_string())?;
+            let created_at: i64 = row.try_get("created_at").map_err(|err| err.to_string())?;
+            let payload_json: String =
+                row.try_get("payload_json").map_err(|err| err.to_string())?;
+            let mut payload: Value =
+                serde_json::from_str(&payload_json).map_err(|err| err.to_string())?;
+            state.mask_session_secrets(&mut payload);
+            for event in self.events.events(&self.session_id, &payload) {
+                for line in self.translator.translate(&event, created_at) {
+                    self.rendered += 1;
+                    if self.rendered > self.offset {
+                        serde_json::to_writer(&mut lines, &line).map_err(|err| err.to_string())?;
+                        lines.push(b'\n');
+                    }
+                }
+            }
+        }
+        Ok(lines)
     }
+}
 
-    if prompt_text.to_ascii_lowercase().contains("question") {
-        let request_id = state.next_id("q_");
-        let question_request = json!({
-            "id": request_id,
-            "sessionID": session_id,
-            "questions": [{
-                "question": "Choose one option",
-                "header": "Question",
-                "options": [
-                    {"label":"Yes","description":"Accept"},
-                    {"label":"No","description":"Reject"}
-                ],
-                "multiple": false,
-                "custom": true
-            }]
-        });
-        let asked = json!({
-            "jsonrpc":"2.0",
-            "method":"_sandboxagent/opencode/question_asked",
-            "params":{"request": question_request}
-        });
-        if let Err(err) = state.persist_event(&session_id, "agent", &asked).await {
-            return internal_error(err);
+/// The session's transcript as newline-delimited JSON in the configured
+/// format, rebuilt from its persisted event log, so it survives restarts
+/// and archiving. With `follow`, events are streamed as they are persisted
+/// until the session is deleted.
+async fn oc_session_transcript(
+    State(state): State<Arc<AdapterState>>,
+    Path(session_id): Path<String>,
+    Query(query): Query<TranscriptQuery>,
+) -> Response {
+    let Some(format) = state.config.transcript_format.clone() else {
+        return not_found("Transcripts are not enabled");
+    };
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    // Subscribe before the first read so nothing persisted meanwhile is
+    // missed.
+    let receiver = query.follow.then(|| state.subscribe());
+    if !state
+        .projection
+        .lock()
+        .await
+        .sessions
+        .contains_key(&session_id)
+    {
+        return not_found("Session not found");
+    }
+
+    let mut reader = TranscriptReader {
+        events: transcript::EnvelopeEvents::default(),
+        translator: format.translator(&session_id),
+        session_id,
+        cursor: 0,
+        rendered: 0,
+        offset: query.offset,
+    };
+    let lines = match reader.read(&state).await {
+        Ok(lines) => lines,
+        Err(err) => return internal_error(err),
+    };
+    let body = match receiver {
+        None => Body::from(lines),
+        Some(receiver) => {
+            let first = stream::iter((!lines.is_empty()).then_some(Ok::<_, Infallible>(lines)));
+            let rest = stream::unfold(
+                (state, receiver, reader),
+                |(state, mut receiver, mut reader)| async move {
+                    loop {
+                        match receiver.recv().await {
+                            Ok(event) => {
+                                let event_type = event.payload["type"].as_str().unwrap_or_default();
+                                let session = event.payload["properties"]
+                                    .as_object()
+                                    .and_then(|properties| event_session(event_type, properties));
+                                if session.as_deref() != Some(reader.session_id.as_str()) {
+                                    continue;
+                                }
+                                if event_type == "session.deleted" {
+                                    return None;
+                                }
+                            }
+                            Err(broadcast::error::RecvError::Lagged(_)) => {}
+                            Err(broadcast::error::RecvError::Closed) => return None,
+                        }
+                        match reader.read(&state).await {
+                            Ok(lines) if lines.is_empty() => {}
+                            Ok(lines) => return Some((Ok(lines), (state, receiver, reader))),
+                            Err(err) => {
+                                warn!(?err, session_id = %reader.session_id, "failed to read transcript");
+                                return None;
+                            }
+                        }
+                    }
+                },
+            );
+            Body::from_stream(first.chain(rest))
         }
-        state.emit_event(json!({"type":"question.asked","properties":question_request}));
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
 
-        let assistant_info = build_assistant_message(
-            &session_id,
-            &format!("{user_message_id}_pending"),
-            &user_message_id,
-            now,
-            &directory,
-            &meta.agent,
-            &meta.provider_id,
-            &meta.model_id,
-        );
+async fn oc_permission_respond(
+    State(state): State<Arc<AdapterState>>,
+    Path((session_id, permission_id)): Path<(String, String)>,
+    StrictJson(body): StrictJson<PermissionRespondBody>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
 
-        return (
-            StatusCode::OK,
-            Json(json!({"info": assistant_info, "parts": []})),
-        )
-            .into_response();
+    let reply = match body.response.as_deref() {
+        Some("allow") => "once",
+        Some("deny") => "reject",
+        Some("always") => "always",
+        _ => "once",
+    };
+
+    if let Err(err) = resolve_permission_inner(&state, &session_id, &permission_id, reply).await {
+        return internal_error(err);
     }
 
-    tokio::time::sleep(Duration::from_millis(120)).await;
+    (StatusCode::OK, Json(json!(true))).into_response()
+}
 
-    if prompt_text.to_ascii_lowercase().contains("error") {
-        state.emit_event(json!({
-            "type":"session.error",
-            "properties":{
-                "sessionID": session_id,
-                "error": {"name":"UnknownError","data":{"message":"mock process crashed"}}
-            }
-        }));
-        let err_env = json!({
-            "jsonrpc":"2.0",
-            "method":"_sandboxagent/opencode/error",
-            "params":{"message":"mock process crashed"}
-        });
-        if let Err(err) = state.persist_event(&session_id, "agent", &err_env).await {
-            return internal_error(err);
-        }
-        if let Err(err) = set_session_status(&state, &session_id, "idle").await {
-            return internal_error(err);
-        }
+async fn oc_permission_reply(
+    State(state): State<Arc<AdapterState>>,
+    Path(request_id): Path<String>,
+    StrictJson(body): StrictJson<PermissionReplyBody>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
 
-        let assistant_info = build_assistant_message(
-            &session_id,
-            &format!("{user_message_id}_error"),
-            &user_message_id,
-            now,
-            &directory,
-            &meta.agent,
-            &meta.provider_id,
-            &meta.model_id,
-        );
+    let reply = body.reply.unwrap_or_else(|| "once".to_string());
+    let session_id = {
+        let projection = state.projection.lock().await;
+        projection
+            .permissions
+            .get(&request_id)
+            .and_then(|value| value.get("sessionID"))
+            .and_then(Value::as_str)
+            .map(ToOwned::to_owned)
+    };
 
-        return (
-            StatusCode::OK,
-            Json(json!({"info": assistant_info, "parts": []})),
-        )
-            .into_response();
+    let Some(session_id) = session_id else {
+        return not_found("Permission request not found");
+    };
+
+    if let Err(err) = resolve_permission_inner(&state, &session_id, &request_id, &reply).await {
+        return internal_error(err);
     }
 
-    let assistant_message_id = format!("{user_message_id}_assistant");
-    let assistant_info = build_completed_assistant_message(
-        &session_id,
-        &assistant_message_id,
-        &user_message_id,
-        now,
-        &directory,
-        &meta.agent,
-        &meta.provider_id,
-        &meta.model_id,
-    );
+    (StatusCode::OK, Json(json!(true))).into_response()
+}
 
-    let mut assistant_parts = Vec::<Value>::new();
+async fn oc_permission_list(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
 
-    if prompt_text.to_ascii_lowercase().contains("tool") {
-        let tool_part = json!({
-            "id": state.next_id("part_"),
-            "sessionID": session_id,
-            "messageID": assistant_message_id,
-            "type": "tool",
-            "callID": state.next_id("call_"),
-            "tool": "bash",
-            "state": {
-                "status": "completed",
-                "input": {"command": "echo tool"},
-                "output": "ok",
-                "title": "bash",
-                "metadata": {},
-                "time": {"start": now, "end": now}
-            }
-        });
-        let file_part = json!({
-            "id": state.next_id("part_"),
-            "sessionID": session_id,
-            "messageID": assistant_message_id,
-            "type": "file",
-            "mime": "text/plain",
-            "filename": "README.md",
-            "url": "file:///README.md",
-        });
+    // Batched requests are listed through their aggregated request.
+    let projection = state.projection.lock().await;
+    let mut values = projection
+        .permissions
+        .values()
+        .filter(|request| request.get("batchID").is_none())
+        .cloned()
+        .collect::<Vec<_>>();
+    values.sort_by(|a, b| {
+        let a_id = a.get("id").and_then(Value::as_str).unwrap_or_default();
+        let b_id = b.get("id").and_then(Value::as_str).unwrap_or_default();
+        a_id.cmp(b_id)
+    });
+    (StatusCode::OK, Json(values)).into_response()
+}
 
-        assistant_parts.push(tool_part.clone());
-        assistant_parts.push(file_part.clone());
+async fn oc_question_list(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
 
-        state.emit_event(json!({
-            "type":"message.part.updated",
-            "properties":{
-                "sessionID": session_id,
-                "messageID": assistant_message_id,
-                "part": tool_part
-            }
-        }));
-        state.emit_event(json!({
-            "type":"message.part.updated",
-            "properties":{
-                "sessionID": session_id,
-                "messageID": assistant_message_id,
-                "part": file_part
-            }
-        }));
-        state.emit_event(
-            json!({"type":"file.edited","properties":{"sessionID":session_id, "path":"README.md"}}),
-        );
-    } else {
-        let response_text = if prompt_text.trim().is_empty() {
-            "OK".to_string()
-        } else {
-            prompt_text.clone()
-        };
-        let text_part = json!({
-            "id": state.next_id("part_"),
-            "sessionID": session_id,
-            "messageID": assistant_message_id,
-            "type": "text",
-            "text": response_text,
-        });
-        assistant_parts.push(text_part.clone());
-        state.emit_event(json!({
-            "type":"message.part.updated",
-            "properties":{
-                "sessionID": session_id,
-                "messageID": assistant_message_id,
-                "part": text_part
-            }
-        }));
+    let projection = state.projection.lock().await;
+    let mut values = projection.questions.values().cloned().collect::<Vec<_>>();
+    values.sort_by(|a, b| {
+        let a_id = a.get("id").and_then(Value::as_str).unwrap_or_default();
+        let b_id = b.get("id").and_then(Value::as_str).unwrap_or_default();
+        a_id.cmp(b_id)
+    });
+    (StatusCode::OK, Json(values)).into_response()
+}
+
+async fn oc_question_reply(
+    State(state): State<Arc<AdapterState>>,
+    Path(request_id): Path<String>,
+    StrictJson(body): StrictJson<QuestionReplyBody>,
+) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
     }
 
-    let assistant_env = json!({
-        "jsonrpc": "2.0",
-        "method": "_sandboxagent/opencode/message",
-        "params": {
-            "message": {
-                "info": assistant_info,
-                "parts": assistant_parts,
+    let session_id = {
+        let projection = state.projection.lock().await;
+        projection
+            .questions
+            .get(&request_id)
+            .and_then(|value| value.get("sessionID"))
+            .and_then(Value::as_str)
+            .map(ToOwned::to_owned)
+    };
+
+    let Some(session_id) = session_id else {
+        return not_found("Question request not found");
+    };
+
+    let answers = body.answers.unwrap_or_default();
+
+    // Forward the answer to the ACP agent if there's a pending request.
+    let pending = state.acp_request_ids.lock().await.remove(&request_id);
+
+    if let Some(pending) = &pending {
+        if let Some(dispatch) = state.config.acp_dispatch.as_ref() {
+            let response = json!({
+                "jsonrpc": "2.0",
+                "id": pending.jsonrpc_id,
+                "result": {
+                    "outcome": "selected",
+                    "_meta": {
+                        "sandboxagent.dev": {
+                            "answers": answers
+                        }
+                    }
+                }
+            });
+            if let Err(err) = dispatch.post(&pending.server_id, None, response).await {
+                warn!(?err, "failed to forward question response to ACP agent");
             }
         }
+    }
+
+    let envelope = json!({
+        "jsonrpc":"2.0",
+        "method":"_sandboxagent/opencode/question_replied",
+        "params":{"requestID": request_id, "answers": answers}
     });
-    if let Err(err) = state
-        .persist_event(&session_id, "agent", &assistant_env)
-        .await
-    {
+    if let Err(err) = state.persist_event(&session_id, "agent", &envelope).await {
         return internal_error(err);
     }
 
-    state.emit_event(message_event("message.updated", &assistant_info));
+    state.emit_event(json!({
+        "type":"question.replied",
+        "properties": {
+            "sessionID": session_id,
+            "requestID": request_id,
+            "answers": answers,
+        }
+    }));
 
-    if let Err(err) = set_session_status(&state, &session_id, "idle").await {
+    let next = lifecycle_after_reply(&state, pending.is_some());
+    if let Err(err) = transition_session(&state, &session_id, next, "question_replied").await {
         return internal_error(err);
     }
 
-    let projection = state.projection.lock().await;
-    let parts = projection
-        .sessions
-        .get(&session_id)
-        .and_then(|session| {
-            session
-                .messages
-                .iter()
-                .find(|message| {
-                    message.info.get("id").and_then(Value::as_str)
-                        == Some(assistant_message_id.as_str())
-                })
-                .map(|message| message.parts.clone())
-        })
-        .unwrap_or_default();
-
-    (
-        StatusCode::OK,
-        Json(json!({"info": assistant_info, "parts": parts})),
-    )
-        .into_response()
+    (StatusCode::OK, Json(json!(true))).into_response()
 }
 
-async fn oc_session_message_get(
+async fn oc_question_reject(
     State(state): State<Arc<AdapterState>>,
-    Path((session_id, message_id)): Path<(String, String)>,
+    Path(request_id): Path<String>,
 ) -> Response {
     if let Err(err) = state.ensure_initialized().await {
         return internal_error(err);
     }
 
-    let projection = state.projection.lock().await;
-    let Some(session) = projection.sessions.get(&session_id) else {
-        return not_found("Session not found");
+    let session_id = {
+        let projection = state.projection.lock().await;
+        projection
+            .questions
+            .get(&request_id)
+            .and_then(|value| value.get("sessionID"))
+            .and_then(Value::as_str)
+            .map(ToOwned::to_owned)
     };
 
-    let Some(record) = session.messages.iter().find(|message| {
-        message.info.get("id").and_then(Value::as_str) == Some(message_id.as_str())
-    }) else {
-        return not_found("Message not found");
+    let Some(session_id) = session_id else {
+        return not_found("Question request not found");
     };
 
+    // Forward rejection to the ACP agent if there's a pending request.
+    let pending = state.acp_request_ids.lock().await.remove(&request_id);
+
+    if let Some(pending) = &pending {
+        if let Some(dispatch) = state.config.acp_dispatch.as_ref() {
+            let response = json!({
+                "jsonrpc": "2.0",
+                "id": pending.jsonrpc_id,
+                "result": {
+                    "outcome": "rejected"
+                }
+            });
+            if let Err(err) = dispatch.post(&pending.server_id, None, response).await {
+                warn!(?err, "failed to forward question rejection to ACP agent");
+            }
+        }
+    }
+
+    let envelope = json!({
+        "jsonrpc":"2.0",
+        "method":"_sandboxagent/opencode/question_rejected",
+        "params":{"requestID": request_id}
+    });
+    if let Err(err) = state.persist_event(&session_id, "agent", &envelope).await {
+        return internal_error(err);
+    }
+
+    state.emit_event(json!({
+        "type":"question.rejected",
+        "properties": {
+            "sessionID": session_id,
+            "requestID": request_id,
+        }
+    }));
+
+    let next = lifecycle_after_reply(&state, pending.is_some());
+    if let Err(err) = transition_session(&state, &session_id, next, "question_rejected").await {
+        return internal_error(err);
+    }
+
+    (StatusCode::OK, Json(json!(true))).into_response()
+}
+
+async fn oc_provider_list(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    (StatusCode::OK, Json(provider_payload(&state))).into_response()
+}
+
+async fn oc_provider_auth(State(state): State<Arc<AdapterState>>) -> Response {
+    if let Err(err) = state.ensure_initialized().await {
+        return internal_error(err);
+    }
+    (StatusCode::OK, Json(json!({"mock": [], "amp": []}))).into_response()
+}
+
+async fn oc_provider_oauth_authorize(Path(provider_id): Path<String>) -> Response {
     (
         StatusCode::OK,
         Json(json!({
-            "id": message_id,
-            "info": record.info,
-            "parts": record.parts,
+            "url": format!("https://auth.local/{provider_id}/authorize"),
+            "method": "auto",
+            "instructions": "stub",
         })),
     )
         .into_response()
 }
 
-async fn oc_part_update(
-    State(state): State<Arc<AdapterState>>,
-    Path((session_id, message_id, part_id)): Path<(String, String, String)>,
-    Json(mut part): Json<Value>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
+async fn oc_provider_oauth_callback() -> Response {
+    (StatusCode::OK, Json(json!(true))).into_response()
+}
 
-    if let Some(obj) = part.as_object_mut() {
-        obj.insert("id".to_string(), json!(part_id.clone()));
-        obj.insert("sessionID".to_string(), json!(session_id.clone()));
-        obj.insert("messageID".to_string(), json!(message_id.clone()));
+async fn resolve_permission_inner(
+    state: &Arc<AdapterState>,
+    session_id: &str,
+    permission_id: &str,
+    reply: &str,
+) -> Result<(), String> {
+    // Replying to an aggregated request answers each of its members. The
+    // pattern-level rule an "always" adds is kept by the batch itself.
+    let members = state.permission_batches.lock().await.take(permission_id);
+    let mut forwarded = false;
+    if let Some(members) = members {
+        let member_reply = if reply == "always" { "once" } else { reply };
+        for member in members {
+            forwarded |= answer_permission(state, session_id, &member, member_reply).await?;
+        }
+    } else {
+        release_batched_permission(state, session_id, permission_id).await?;
     }
+    forwarded |= answer_permission(state, session_id, permission_id, reply).await?;
 
-    {
-        let mut projection = state.projection.lock().await;
-        if let Some(session) = projection.sessions.get_mut(&session_id) {
-            if let Some(message) = session.messages.iter_mut().find(|record| {
-                record.info.get("id").and_then(Value::as_str) == Some(message_id.as_str())
-            }) {
-                if let Some(existing) = message.parts.iter_mut().find(|candidate| {
-                    candidate.get("id").and_then(Value::as_str) == Some(part_id.as_str())
-                }) {
-                    *existing = part.clone();
-                } else {
-                    message.parts.push(part.clone());
+    let next = lifecycle_after_reply(state, forwarded);
+    transition_session(state, session_id, next, "permission_replied").await
+}
+
+/// Forward a reply to the agent, if it is waiting on one, and record it.
+/// Returns whether the agent got the reply.
+async fn answer_permission(
+    state: &Arc<AdapterState>,
+    session_id: &str,
+    permission_id: &str,
+    reply: &str,
+) -> Result<bool, String> {
+    // If there's a pending ACP request for this permission, forward the
+    // response to the agent process.
+    let pending = state.acp_request_ids.lock().await.remove(permission_id);
+
+    if let Some(pending) = &pending {
+        if let Some(dispatch) = state.config.acp_dispatch.as_ref() {
+            let option_kind = permission_option_kind(reply);
+            let response = json!({
+                "jsonrpc": "2.0",
+                "id": pending.jsonrpc_id,
+                "result": {
+                    "outcome": "selected",
+                    "selectedOption": {
+                        "kind": option_kind
+                    }
                 }
+            });
+            if let Err(err) = dispatch.post(&pending.server_id, None, response).await {
+                warn!(?err, "failed to forward permission response to ACP agent");
             }
         }
     }
 
+    let envelope = json!({
+        "jsonrpc":"2.0",
+        "method":"_sandboxagent/opencode/permission_replied",
+        "params": {
+            "requestID": permission_id,
+            "reply": reply,
+        }
+    });
+    state.persist_event(session_id, "agent", &envelope).await?;
+
     state.emit_event(json!({
-        "type":"message.part.updated",
-        "properties":{
+        "type":"permission.replied",
+        "properties": {
             "sessionID": session_id,
-            "messageID": message_id,
-            "part": part.clone()
+            "requestID": permission_id,
+            "reply": reply,
         }
     }));
-
-    (StatusCode::OK, Json(part)).into_response()
+    Ok(pending.is_some())
 }
 
-async fn oc_part_delete(
-    State(state): State<Arc<AdapterState>>,
-    Path((session_id, message_id, part_id)): Path<(String, String, String)>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
+/// Surface a permission request that was just asked: on its own, or folded
+/// into an aggregated request when similar requests came in within
+/// `permission_batch_window`. Members of a batch stay pending and can still
+/// be answered one by one; clients are told they were batched.
+async fn ask_permission(state: &Arc<AdapterState>, request: &Value) -> Result<(), String> {
+    let window = state.config.permission_batch_window;
+    let admission = match BatchKey::of(request).filter(|_| !window.is_zero()) {
+        Some(key) => {
+            let request_id = request["id"].as_str().unwrap_or_default();
+            let admission = state.permission_batches.lock().await.admit(
+                key.clone(),
+                request_id,
+                state.now_ms(),
+                window.as_millis() as i64,
+                || state.next_id("perm_batch_"),
+            );
+            admission.into_batch().map(|batch| (key, batch))
+        }
+        None => None,
+    };
+    let Some((key, (batch_id, members, formed))) = admission else {
+        state.emit_event(json!({"type":"permission.asked","properties":request}));
+        return Ok(());
+    };
 
-    {
-        let mut projection = state.projection.lock().await;
-        if let Some(session) = projection.sessions.get_mut(&session_id) {
-            if let Some(message) = session.messages.iter_mut().find(|record| {
-                record.info.get("id").and_then(Value::as_str) == Some(message_id.as_str())
-            }) {
-                message.parts.retain(|part| {
-                    part.get("id").and_then(Value::as_str) != Some(part_id.as_str())
-                });
+    let session_id = key.session_id.as_str();
+    // The request that started the batch was already asked on its own.
+    let joined = if formed {
+        &members[..]
+    } else {
+        &members[members.len() - 1..]
+    };
+    for member in joined {
+        let batched = json!({
+            "jsonrpc":"2.0",
+            "method":"_sandboxagent/opencode/permission_batched",
+            "params":{"requestID": member, "batchID": batch_id}
+        });
+        state.persist_event(session_id, "agent", &batched).await?;
+        state.emit_event(json!({
+            "type":"permission.batched",
+            "properties": {
+                "sessionID": session_id,
+                "requestID": member,
+                "batchID": batch_id,
             }
-        }
+        }));
+    }
+
+    let pattern = key.pattern();
+    // Members' tool titles often name their file, so only the tool kind is
+    // carried over and "always" covers the pattern for any title.
+    let mut metadata = json!({
+        "batch": {"requestIDs": members, "count": members.len()},
+    });
+    if let Some(kind) = request.pointer("/metadata/toolKind") {
+        metadata["toolKind"] = kind.clone();
     }
+    let aggregated = json!({
+        "id": batch_id,
+        "sessionID": session_id,
+        "permission": key.permission,
+        "patterns": [pattern],
+        "metadata": metadata,
+        "always": [pattern],
+    });
+    let asked = json!({
+        "jsonrpc":"2.0",
+        "method":"_sandboxagent/opencode/permission_asked",
+        "params":{"request": aggregated}
+    });
+    state.persist_event(session_id, "agent", &asked).await?;
+    state.emit_event(json!({"type":"permission.asked","properties":aggregated}));
+    Ok(())
+}
 
+/// Drop a permission request answered or withdrawn on its own from its
+/// batch, and cancel the batch once none of its members are left.
+async fn release_batched_permission(
+    state: &Arc<AdapterState>,
+    session_id: &str,
+    request_id: &str,
+) -> Result<(), String> {
+    let Some(batch_id) = state.permission_batches.lock().await.release(request_id) else {
+        return Ok(());
+    };
+    let reason = "every request in the batch was answered";
+    let envelope = json!({
+        "jsonrpc":"2.0",
+        "method":"_sandboxagent/opencode/permission_cancelled",
+        "params":{"requestID": batch_id, "reason": reason}
+    });
+    state.persist_event(session_id, "agent", &envelope).await?;
     state.emit_event(json!({
-        "type":"message.part.removed",
-        "properties": {"sessionID": session_id, "messageID": message_id, "partID": part_id}
+        "type":"permission.cancelled",
+        "properties": {
+            "sessionID": session_id,
+            "requestID": batch_id,
+            "reason": reason,
+        }
     }));
-
-    (StatusCode::OK, Json(json!(true))).into_response()
+    Ok(())
 }
 
-async fn oc_session_prompt_async(
-    State(state): State<Arc<AdapterState>>,
-    Path(session_id): Path<String>,
-    headers: HeaderMap,
-    query: Query<DirectoryQuery>,
-    Json(body): Json<PromptBody>,
-) -> Response {
-    let _ = oc_session_prompt(State(state), Path(session_id), headers, query, Json(body)).await;
-
-    StatusCode::NO_CONTENT.into_response()
+/// Why a permission request is answered without asking the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AutoReply {
+    /// An earlier "always" reply in the session covers it.
+    Always,
+    /// The session is in dry-run mode and the tool has side effects.
+    DryRun,
+    /// The configured [`Authorizer`] allowed or denied it.
+    Authorizer { allow: bool, reason: Option<String> },
 }
 
-async fn oc_permission_respond(
-    State(state): State<Arc<AdapterState>>,
-    Path((session_id, permission_id)): Path<(String, String)>,
-    Json(body): Json<PermissionRespondBody>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
-    }
-
-    let reply = match body.response.as_deref() {
-        Some("allow") => "once",
-        Some("deny") => "reject",
-        Some("always") => "always",
-        _ => "once",
-    };
+const DRY_RUN_DENIAL: &str =
+    "Denied: this session is in dry-run mode, so tools that change the sandbox are not run. Describe the change instead.";
 
-    if let Err(err) = resolve_permission_inner(&state, &session_id, &permission_id, reply).await {
-        return internal_error(err);
+impl AutoReply {
+    fn reply(&self) -> &'static str {
+        match self {
+            Self::Always => "always",
+            Self::DryRun | Self::Authorizer { allow: false, .. } => "reject",
+            Self::Authorizer { allow: true, .. } => "once",
+        }
     }
 
-    (StatusCode::OK, Json(json!(true))).into_response()
-}
-
-async fn oc_permission_reply(
-    State(state): State<Arc<AdapterState>>,
-    Path(request_id): Path<String>,
-    Json(body): Json<PermissionReplyBody>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+    /// The explanation passed to the agent with the reply.
+    fn message(&self) -> Option<&str> {
+        match self {
+            Self::Always => None,
+            Self::DryRun => Some(DRY_RUN_DENIAL),
+            Self::Authorizer { reason, .. } => reason.as_deref(),
+        }
     }
+}
 
-    let reply = body.reply.unwrap_or_else(|| "once".to_string());
-    let session_id = {
+/// How the session answers `request` on its own, if it does: dry-run
+/// denials first, then the authorizer, then the session's "always" rules.
+async fn auto_reply_for(
+    state: &Arc<AdapterState>,
+    session_id: &str,
+    request: &Value,
+) -> Option<AutoReply> {
+    let (context, always) = {
         let projection = state.projection.lock().await;
-        projection
-            .permissions
-            .get(&request_id)
-            .and_then(|value| value.get("sessionID"))
-            .and_then(Value::as_str)
-            .map(ToOwned::to_owned)
-    };
-
-    let Some(session_id) = session_id else {
-        return not_found("Permission request not found");
+        let session = projection.sessions.get(session_id)?;
+        if session.meta.dry_run && permission_rules::is_side_effecting(request) {
+            return Some(AutoReply::DryRun);
+        }
+        let context = state.config.authorizer.is_some().then(|| {
+            PermissionContext::from_request(&session.meta.agent, &session.meta.directory, request)
+        });
+        (
+            context,
+            permission_rules::allows(&session.always_rules, request),
+        )
     };
-
-    if let Err(err) = resolve_permission_inner(&state, &session_id, &request_id, &reply).await {
-        return internal_error(err);
+    if let (Some(authorizer), Some(context)) = (state.config.authorizer.as_ref(), context) {
+        let decision = authorizer::consult(authorizer.as_ref(), &context).await;
+        if let Err(err) = record_authorizer_decision(state, session_id, request, &decision).await {
+            warn!(?err, "failed to persist authorizer decision");
+        }
+        match decision {
+            AuthorizerDecision::Allow { reason } => {
+                return Some(AutoReply::Authorizer {
+                    allow: true,
+                    reason,
+                })
+            }
+            AuthorizerDecision::Deny { reason } => {
+                return Some(AutoReply::Authorizer {
+                    allow: false,
+                    reason,
+                })
+            }
+            AuthorizerDecision::Defer => {}
+        }
     }
-
-    (StatusCode::OK, Json(json!(true))).into_response()
+    always.then_some(AutoReply::Always)
 }
 
-async fn oc_permission_list(State(state): State<Arc<AdapterState>>) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+/// Log an authorizer's decision on a permission request as
+/// `permission.authorized`, and persist it with the request for audits.
+async fn record_authorizer_decision(
+    state: &Arc<AdapterState>,
+    session_id: &str,
+    request: &Value,
+    decision: &AuthorizerDecision,
+) -> Result<(), String> {
+    let mut properties = json!({
+        "sessionID": session_id,
+        "requestID": request["id"],
+        "decision": decision.as_str(),
+    });
+    if let Some(reason) = decision.reason() {
+        properties["reason"] = json!(reason);
     }
-
-    let projection = state.projection.lock().await;
-    let mut values = projection.permissions.values().cloned().collect::<Vec<_>>();
-    values.sort_by(|a, b| {
-        let a_id = a.get("id").and_then(Value::as_str).unwrap_or_default();
-        let b_id = b.get("id").and_then(Value::as_str).unwrap_or_default();
-        a_id.cmp(b_id)
+    let mut params = properties.clone();
+    params["request"] = request.clone();
+    let envelope = json!({
+        "jsonrpc":"2.0",
+        "method":"_sandboxagent/opencode/permission_authorized",
+        "params": params,
     });
-    (StatusCode::OK, Json(values)).into_response()
+    state.persist_event(session_id, "agent", &envelope).await?;
+    state.emit_event(json!({"type":"permission.authorized", "properties": properties}));
+    Ok(())
 }
 
-async fn oc_question_list(State(state): State<Arc<AdapterState>>) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+/// Answer a permission request without asking the user: the agent gets the
+/// reply and clients only see `permission.replied`. The reply is persisted
+/// with the request so the transcript records what was decided.
+/// `agent_request` is the ACP server instance that asked and its JSON-RPC id.
+async fn auto_reply_permission(
+    state: &Arc<AdapterState>,
+    session_id: &str,
+    request: &Value,
+    agent_request: Option<(&str, Value)>,
+    auto: AutoReply,
+) -> Result<(), String> {
+    let request_id = request["id"].as_str().unwrap_or_default();
+    let message = auto.message();
+    if let (Some((server_id, jsonrpc_id)), Some(dispatch)) =
+        (agent_request, state.config.acp_dispatch.as_ref())
+    {
+        let mut result = json!({
+            "outcome": "selected",
+            "selectedOption": {"kind": permission_option_kind(auto.reply())}
+        });
+        if let Some(message) = message {
+            result["_meta"] = json!({"sandboxagent.dev": {"message": message}});
+        }
+        let response = json!({"jsonrpc": "2.0", "id": jsonrpc_id, "result": result});
+        if let Err(err) = dispatch.post(server_id, None, response).await {
+            warn!(?err, "failed to auto-reply to ACP permission request");
+        }
     }
 
-    let projection = state.projection.lock().await;
-    let mut values = projection.questions.values().cloned().collect::<Vec<_>>();
-    values.sort_by(|a, b| {
-        let a_id = a.get("id").and_then(Value::as_str).unwrap_or_default();
-        let b_id = b.get("id").and_then(Value::as_str).unwrap_or_default();
-        a_id.cmp(b_id)
+    let mut properties = json!({
+        "sessionID": session_id,
+        "requestID": request_id,
+        "reply": auto.reply(),
+        "auto": true,
     });
-    (StatusCode::OK, Json(values)).into_response()
+    match auto {
+        AutoReply::DryRun => properties["dryRun"] = json!(true),
+        AutoReply::Authorizer { .. } => properties["authorizer"] = json!(true),
+        AutoReply::Always => {}
+    }
+    if let Some(message) = message {
+        properties["message"] = json!(message);
+    }
+    let mut params = properties.clone();
+    params["request"] = request.clone();
+    let envelope = json!({
+        "jsonrpc":"2.0",
+        "method":"_sandboxagent/opencode/permission_replied",
+        "params": params,
+    });
+    state.persist_event(session_id, "agent", &envelope).await?;
+    state.emit_event(json!({"type":"permission.replied", "properties": properties}));
+    Ok(())
 }
 
-async fn oc_question_reply(
-    State(state): State<Arc<AdapterState>>,
-    Path(request_id): Path<String>,
-    Json(body): Json<QuestionReplyBody>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+/// The ACP permission option kind for an OpenCode reply.
+fn permission_option_kind(reply: &str) -> &'static str {
+    match reply {
+        "always" => "allow_always",
+        "reject" | "deny" => "reject_once",
+        _ => "allow_once",
     }
+}
 
-    let session_id = {
-        let projection = state.projection.lock().await;
-        projection
-            .questions
-            .get(&request_id)
-            .and_then(|value| value.get("sessionID"))
-            .and_then(Value::as_str)
-            .map(ToOwned::to_owned)
+/// Drop the pending permission or question request an agent withdrew, and
+/// emit `permission.cancelled` or `question.cancelled`. `params.id` is the
+/// JSON-RPC `id` of the agent's request; unknown IDs are ignored, e.g. when
+/// the user answered first.
+async fn cancel_agent_request(state: &Arc<AdapterState>, server_id: &str, params: &Value) {
+    let Some(jsonrpc_id) = params.get("id") else {
+        return;
     };
-
-    let Some(session_id) = session_id else {
-        return not_found("Question request not found");
+    let cancelled = {
+        let mut pending = state.acp_request_ids.lock().await;
+        let request_id = pending
+            .iter()
+            .find(|(_, request)| {
+                request.server_id == server_id && request.jsonrpc_id == *jsonrpc_id
+            })
+            .map(|(request_id, _)| request_id.clone());
+        request_id.and_then(|request_id| {
+            pending
+                .remove(&request_id)
+                .map(|request| (request_id, request))
+        })
     };
-
-    let answers = body.answers.unwrap_or_default();
-
-    // Forward the answer to the ACP agent if there's a pending request.
-    let pending = state.acp_request_ids.lock().await.remove(&request_id);
-
-    if let Some(pending) = &pending {
-        if let Some(dispatch) = state.config.acp_dispatch.as_ref() {
-            let agent_session_id = {
-                let projection = state.projection.lock().await;
-                projection
-                    .sessions
-                    .get(&session_id)
-                    .map(|s| s.meta.agent_session_id.clone())
-            };
-            if let Some(server_id) = agent_session_id {
-                let response = json!({
-                    "jsonrpc": "2.0",
-                    "id": pending.jsonrpc_id,
-                    "result": {
-                        "outcome": "selected",
-                        "_meta": {
-                            "sandboxagent.dev": {
-                                "answers": answers
-                            }
-                        }
-                    }
-                });
-                if let Err(err) = dispatch.post(&server_id, None, response).await {
-                    warn!(?err, "failed to forward question response to ACP agent");
-                }
-            }
+    let Some((request_id, request)) = cancelled else {
+        return;
+    };
+    let session_id = request.opencode_session_id;
+    let kind = match request.kind {
+        AcpPendingKind::Permission => "permission",
+        AcpPendingKind::Question => "question",
+    };
+    if matches!(request.kind, AcpPendingKind::Permission) {
+        if let Err(err) = release_batched_permission(state, &session_id, &request_id).await {
+            warn!(?err, "failed to cancel emptied permission batch");
         }
     }
-
+    let reason = params.get("reason").and_then(Value::as_str);
     let envelope = json!({
         "jsonrpc":"2.0",
-        "method":"_sandboxagent/opencode/question_replied",
-        "params":{"requestID": request_id, "answers": answers}
+        "method": format!("_sandboxagent/opencode/{kind}_cancelled"),
+        "params":{"requestID": request_id, "reason": reason}
     });
     if let Err(err) = state.persist_event(&session_id, "agent", &envelope).await {
-        return internal_error(err);
+        warn!(?err, "failed to persist {kind} cancellation");
     }
-
     state.emit_event(json!({
-        "type":"question.replied",
+        "type": format!("{kind}.cancelled"),
         "properties": {
             "sessionID": session_id,
             "requestID": request_id,
-            "answers": answers,
+            "reason": reason,
         }
     }));
 
-    if let Err(err) = set_session_status(&state, &session_id, "idle").await {
-        return internal_error(err);
+    // The agent carries on with its turn unless it is still waiting on
+    // another request.
+    let waiting = {
+        let projection = state.projection.lock().await;
+        let lifecycle = projection
+            .sessions
+            .get(&session_id)
+            .map(|session| session.lifecycle);
+        let still_asking = projection
+            .permissions
+            .values()
+            .chain(projection.questions.values())
+            .any(|request| request.get("sessionID").and_then(Value::as_str) == Some(&session_id));
+        matches!(
+            lifecycle,
+            Some(SessionLifecycle::WaitingPermission | SessionLifecycle::WaitingQuestion)
+        ) && !still_asking
+    };
+    if waiting {
+        let next = lifecycle_after_reply(state, true);
+        if let Err(err) =
+            transition_session(state, &session_id, next, &format!("{kind}_cancelled")).await
+        {
+            warn!(?err, "failed to resume session after {kind} cancellation");
+        }
     }
-
-    (StatusCode::OK, Json(json!(true))).into_response()
 }
 
-async fn oc_question_reject(
-    State(state): State<Arc<AdapterState>>,
-    Path(request_id): Path<String>,
-) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+/// A reply that was forwarded to a live agent resumes its turn; otherwise
+/// nothing else will complete the turn, so the session settles to idle.
+fn lifecycle_after_reply(state: &AdapterState, forwarded: bool) -> SessionLifecycle {
+    if forwarded && state.config.acp_dispatch.is_some() {
+        SessionLifecycle::Prompting
+    } else {
+        SessionLifecycle::Idle
     }
+}
 
-    let session_id = {
-        let projection = state.projection.lock().await;
-        projection
-            .questions
-            .get(&request_id)
-            .and_then(|value| value.get("sessionID"))
-            .and_then(Value::as_str)
-            .map(ToOwned::to_owned)
+/// Periodically export settled sessions to the configured archive.
+async fn archive_loop(state: Arc<AdapterState>) {
+    let Some(period) = state
+        .archive
+        .as_ref()
+        .map(|archive| archive.config().interval)
+    else {
+        return;
     };
+    let mut ticker = interval(period);
+    loop {
+        ticker.tick().await;
+        if let Err(err) = state.ensure_initialized().await {
+            warn!(?err, "session archival skipped: adapter not initialized");
+            continue;
+        }
+        let candidates = {
+            let projection = state.projection.lock().await;
+            projection
+                .sessions
+                .keys()
+                .filter(|id| state.is_archivable(&projection, id))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        for session_id in candidates {
+            match state.archive_session(&session_id).await {
+                Ok(true) => tracing::info!(session_id = %session_id, "archived session"),
+                Ok(false) => {}
+                Err(err) => warn!(session_id = %session_id, ?err, "failed to archive session"),
+            }
+        }
+    }
+}
 
-    let Some(session_id) = session_id else {
-        return not_found("Question request not found");
+/// Periodically return orphaned busy sessions to idle, e.g. when the ACP
+/// translation task died before it emitted `session.idle`.
+async fn model_catalog_loop(state: Arc<AdapterState>) {
+    let Some(catalog) = state.model_catalog.as_ref() else {
+        return;
     };
-
-    // Forward rejection to the ACP agent if there's a pending request.
-    let pending = state.acp_request_ids.lock().await.remove(&request_id);
-
-    if let Some(pending) = &pending {
-        if let Some(dispatch) = state.config.acp_dispatch.as_ref() {
-            let agent_session_id = {
-                let projection = state.projection.lock().await;
-                projection
-                    .sessions
-                    .get(&session_id)
-                    .map(|s| s.meta.agent_session_id.clone())
-            };
-            if let Some(server_id) = agent_session_id {
-                let response = json!({
-                    "jsonrpc": "2.0",
-                    "id": pending.jsonrpc_id,
-                    "result": {
-                        "outcome": "rejected"
-                    }
-                });
-                if let Err(err) = dispatch.post(&server_id, None, response).await {
-                    warn!(?err, "failed to forward question rejection to ACP agent");
-                }
-            }
+    let Some(refresh_interval) = catalog.refresh_interval() else {
+        return;
+    };
+    // A cached catalog younger than the interval is used as is at first.
+    sleep(catalog.next_refresh_in()).await;
+    let mut ticker = interval(refresh_interval);
+    loop {
+        ticker.tick().await;
+        if let Err(err) = catalog.refresh().await {
+            warn!(%err, "failed to refresh the models catalog");
         }
     }
+}
 
-    let envelope = json!({
-        "jsonrpc":"2.0",
-        "method":"_sandboxagent/opencode/question_rejected",
-        "params":{"requestID": request_id}
-    });
-    if let Err(err) = state.persist_event(&session_id, "agent", &envelope).await {
-        return internal_error(err);
+/// Stop using the database once another instance takes it over:
+/// [`AdapterState::pool`] refuses further queries, and the file lock is
+/// released only after the pool has closed.
+async fn database_fence_loop(state: Arc<AdapterState>) {
+    let Some(lock) = state.config.database_lock.clone() else {
+        return;
+    };
+    let mut ticker = interval(DATABASE_FENCE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let fence_lock = lock.clone();
+        let fenced = tokio::task::spawn_blocking(move || fence_lock.check_fence())
+            .await
+            .unwrap_or(false);
+        if fenced {
+            tracing::error!(
+                db = %state.sqlite_path,
+                "opencode database taken over by another instance; refusing further writes"
+            );
+            if let Some(pool) = state.pool.get() {
+                pool.close().await;
+            }
+            lock.release();
+            return;
+        }
     }
+}
 
-    state.emit_event(json!({
-        "type":"question.rejected",
-        "properties": {
-            "sessionID": session_id,
-            "requestID": request_id,
+/// Periodically stop the agent processes of sessions idle for longer than
+/// the expiry TTL, leaving the sessions dormant.
+async fn expiry_loop(state: Arc<AdapterState>) {
+    let Some(config) = state.config.session_expiry.clone() else {
+        return;
+    };
+    let mut ticker = interval(config.interval);
+    loop {
+        ticker.tick().await;
+        if let Err(err) = state.ensure_initialized().await {
+            warn!(?err, "session expiry skipped: adapter not initialized");
+            continue;
         }
-    }));
-
-    if let Err(err) = set_session_status(&state, &session_id, "idle").await {
-        return internal_error(err);
+        expire_idle_sessions(&state).await;
     }
-
-    (StatusCode::OK, Json(json!(true))).into_response()
 }
 
-async fn oc_provider_list(State(state): State<Arc<AdapterState>>) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+async fn expire_idle_sessions(state: &Arc<AdapterState>) {
+    let now = state.now_ms();
+    let expired = state
+        .session_timers(None)
+        .await
+        .into_iter()
+        .filter(|timer| timer.expires_at.is_some_and(|at| at <= now));
+    for timer in expired {
+        let meta = {
+            let mut projection = state.projection.lock().await;
+            let Some(session) = projection.sessions.get_mut(&timer.session_id) else {
+                continue;
+            };
+            // The session may have been prompted since the timers were read.
+            if session.lifecycle.status_type() == "busy" {
+                continue;
+            }
+            session.meta.dormant_at = Some(now);
+            session.meta.clone()
+        };
+        for server_id in meta.acp_server_ids() {
+            state.release_acp_instance(&server_id).await;
+        }
+        if let Err(err) = state.persist_session(&meta).await {
+            warn!(session_id = %meta.id, ?err, "failed to persist dormant session");
+        }
+        let idle_ms = now.saturating_sub(timer.last_activity);
+        tracing::info!(session_id = %meta.id, idle_ms, "stopped the agent of an idle session");
+        state.emit_event(json!({
+            "type": "session.updated",
+            "properties": { "info": session_to_value(&meta) }
+        }));
+        state.emit_event(json!({
+            "type": "session.dormant",
+            "properties": { "sessionID": meta.id, "idleMs": idle_ms }
+        }));
     }
-    (StatusCode::OK, Json(provider_payload(&state))).into_response()
 }
 
-async fn oc_provider_auth(State(state): State<Arc<AdapterState>>) -> Response {
-    if let Err(err) = state.ensure_initialized().await {
-        return internal_error(err);
+async fn watchdog_loop(state: Arc<AdapterState>) {
+    let Some(config) = state.config.session_watchdog.clone() else {
+        return;
+    };
+    let mut ticker = interval(config.interval);
+    loop {
+        ticker.tick().await;
+        if let Err(err) = state.ensure_initialized().await {
+            warn!(?err, "session watchdog skipped: adapter not initialized");
+            continue;
+        }
+        reconcile_busy_sessions(&state, config.stale_after).await;
     }
-    (StatusCode::OK, Json(json!({"mock": [], "amp": []}))).into_response()
 }
 
-async fn oc_provider_oauth_authorize(Path(provider_id): Path<String>) -> Response {
-    (
-        StatusCode::OK,
-        Json(json!({
-            "url": format!("https://auth.local/{provider_id}/authorize"),
-            "method": "auto",
-            "instructions": "stub",
-        })),
-    )
-        .into_response()
+async fn reconcile_busy_sessions(state: &Arc<AdapterState>, stale_after: Duration) {
+    let busy = {
+        let projection = state.projection.lock().await;
+        projection
+            .sessions
+            .values()
+            .filter(|session| session.lifecycle.status_type() == "busy")
+            .map(|session| (session.meta.clone(), session.lifecycle))
+            .collect::<Vec<_>>()
+    };
+    if busy.is_empty() {
+        return;
+    }
+    // An empty list is indistinguishable from a backend that does not track
+    // instances, so only a non-empty one is trusted for liveness.
+    let running_servers: Option<HashSet<String>> = match state.config.acp_dispatch.as_ref() {
+        Some(dispatch) => {
+            let instances = dispatch.instances().await;
+            (!instances.is_empty()).then(|| {
+                instances
+                    .into_iter()
+                    .map(|instance| instance.server_id)
+                    .collect()
+            })
+        }
+        None => None,
+    };
+    let initialized: HashSet<String> = state.acp_initialized.lock().await.keys().cloned().collect();
+    let running_turns: HashSet<String> = state
+        .turns
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, record)| !record.status.is_finished())
+        .map(|(_, record)| record.session_id.clone())
+        .collect();
+    let now = state.now_ms();
+
+    for (meta, lifecycle) in busy {
+        let last_active = state
+            .session_activity
+            .lock()
+            .ok()
+            .and_then(|activity| activity.get(&meta.id).copied())
+            .unwrap_or(meta.updated_at)
+            .max(meta.updated_at);
+        // Only sessions whose agent finished bootstrapping have a process
+        // that can have exited.
+        let agent_running = match &running_servers {
+            Some(servers) if initialized.contains(&meta.agent_session_id) => {
+                Some(servers.contains(&meta.agent_session_id))
+            }
+            _ => None,
+        };
+        let observed = watchdog::BusySession {
+            idle_for: Duration::from_millis(now.saturating_sub(last_active).max(0) as u64),
+            turn_running: running_turns.contains(&meta.id),
+            waiting_on_user: matches!(
+                lifecycle,
+                SessionLifecycle::WaitingPermission | SessionLifecycle::WaitingQuestion
+            ),
+            agent_running,
+        };
+        let Some(reason) = watchdog::orphan_reason(observed, stale_after) else {
+            continue;
+        };
+        // The session may have moved on while the checks above ran.
+        let current = {
+            let projection = state.projection.lock().await;
+            projection
+                .sessions
+                .get(&meta.id)
+                .map(|session| session.lifecycle)
+        };
+        if current != Some(lifecycle) {
+            continue;
+        }
+        let idle_ms = observed.idle_for.as_millis() as u64;
+        warn!(
+            session_id = %meta.id,
+            state = lifecycle.as_str(),
+            reason,
+            idle_ms,
+            turn_running = observed.turn_running,
+            agent_running = ?observed.agent_running,
+            "session was busy without an active turn; reconciling to idle"
+        );
+        if agent_running == Some(false) {
+            // The next prompt bootstraps a fresh agent process.
+            state
+                .acp_initialized
+                .lock()
+                .await
+                .remove(&meta.agent_session_id);
+            if let Err(err) = state.delete_acp_binding(&meta.agent_session_id).await {
+                warn!(?err, session_id = %meta.id, "failed to delete ACP session binding");
+            }
+        }
+        if let Err(err) =
+            transition_session(state, &meta.id, SessionLifecycle::Idle, "reconciled").await
+        {
+            warn!(session_id = %meta.id, ?err, "failed to reconcile session");
+            continue;
+        }
+        state.emit_event(json!({
+            "type": "session.reconciled",
+            "properties": {
+                "sessionID": meta.id,
+                "from": lifecycle,
+                "reason": reason,
+                "idleMs": idle_ms,
+                "turnRunning": observed.turn_running,
+                "agentRunning": observed.agent_running,
+            }
+        }));
+    }
 }
 
-async fn oc_provider_oauth_callback() -> Response {
-    (StatusCode::OK, Json(json!(true))).into_response()
+/// Emit `server.sidecar` whenever the native OpenCode sidecar changes state.
+async fn sidecar_status_loop(
+    state: Arc<AdapterState>,
+    mut status: tokio::sync::watch::Receiver<SidecarStatus>,
+) {
+    let mut last_state = status.borrow_and_update().state;
+    while status.changed().await.is_ok() {
+        let current = status.borrow_and_update().clone();
+        // Repeated probe failures and backoff steps only change the details.
+        if current.state == last_state
+            && !matches!(
+                current.state,
+                SidecarState::Restarting | SidecarState::Unhealthy
+            )
+        {
+            continue;
+        }
+        last_state = current.state;
+        state.emit_event(json!({
+            "type": "server.sidecar",
+            "properties": sidecar_status_value(&current),
+        }));
+    }
 }
 
-async fn resolve_permission_inner(
-    state: &Arc<AdapterState>,
-    session_id: &str,
-    permission_id: &str,
-    reply: &str,
-) -> Result<(), String> {
-    // If there's a pending ACP request for this permission, forward the
-    // response to the agent process.
-    let pending = state.acp_request_ids.lock().await.remove(permission_id);
+fn sidecar_status_value(status: &SidecarStatus) -> Value {
+    json!({
+        "state": status.state.as_str(),
+        "url": status.base_url,
+        "restarts": status.auto_restarts,
+        "restartAttempt": status.restart_attempt,
+        "retryInMs": status.retry_in_ms,
+        "lastError": status.last_error,
+    })
+}
 
-    if let Some(pending) = &pending {
-        if let Some(dispatch) = state.config.acp_dispatch.as_ref() {
-            let agent_session_id = {
-                let projection = state.projection.lock().await;
-                projection
-                    .sessions
-                    .get(session_id)
-                    .map(|s| s.meta.agent_session_id.clone())
-            };
-            if let Some(server_id) = agent_session_id {
-                let option_kind = match reply {
-                    "always" => "allow_always",
-                    "reject" | "deny" => "reject_once",
-                    _ => "allow_once",
-                };
-                let response = json!({
-                    "jsonrpc": "2.0",
-                    "id": pending.jsonrpc_id,
-                    "result": {
-                        "outcome": "selected",
-                        "selectedOption": {
-                            "kind": option_kind
-                        }
-                    }
-                });
-                if let Err(err) = dispatch.post(&server_id, None, response).await {
-                    warn!(?err, "failed to forward permission response to ACP agent");
+/// Forward `permission.asked` and `question.asked` to every webhook target.
+async fn webhook_loop(state: Arc<AdapterState>, mut rx: broadcast::Receiver<OpenCodeStreamEvent>) {
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!(
+                    skipped,
+                    "webhook delivery lagged; events were not delivered"
+                );
+                if let Some(metrics) = state.config.metrics.as_ref() {
+                    metrics.events_dropped("webhook", skipped);
                 }
+                continue;
             }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let event_type = event
+            .payload
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let reply_path = match event_type {
+            "permission.asked" => "permission",
+            "question.asked" => "question",
+            _ => continue,
+        };
+        let properties = event
+            .payload
+            .get("properties")
+            .cloned()
+            .unwrap_or(json!({}));
+        let Some(request_id) = properties.get("id").and_then(Value::as_str) else {
+            continue;
+        };
+        let delivery_id = state.next_id("whd_");
+        let body = json!({
+            "id": delivery_id,
+            "type": event_type,
+            "timestamp": state.now_ms(),
+            "properties": properties,
+            "replyPath": format!("/{reply_path}/{request_id}/reply"),
+        });
+        let Ok(body) = serde_json::to_vec(&body) else {
+            continue;
+        };
+        let target_count = state
+            .webhooks
+            .as_ref()
+            .map_or(0, |hooks| hooks.targets().len());
+        for index in 0..target_count {
+            tokio::spawn(deliver_webhook(
+                state.clone(),
+                index,
+                event_type.to_string(),
+                request_id.to_string(),
+                delivery_id.clone(),
+                body.clone(),
+            ));
         }
     }
+}
 
-    let envelope = json!({
-        "jsonrpc":"2.0",
-        "method":"_sandboxagent/opencode/permission_replied",
-        "params": {
-            "requestID": permission_id,
-            "reply": reply,
+/// Deliver one event to one target and apply any reply it returns inline.
+/// Replies go through the same handlers as `POST /permission/:id/reply` and
+/// `POST /question/:id/reply`, so the first answer from any channel wins.
+async fn deliver_webhook(
+    state: Arc<AdapterState>,
+    target_index: usize,
+    event_type: String,
+    request_id: String,
+    delivery_id: String,
+    body: Vec<u8>,
+) {
+    let Some(hooks) = state.webhooks.as_ref() else {
+        return;
+    };
+    let target = &hooks.targets()[target_index];
+    let reply = match hooks
+        .deliver(target, &event_type, &delivery_id, &body)
+        .await
+    {
+        Ok(Some(reply)) => reply,
+        Ok(None) => return,
+        Err(err) => {
+            warn!(url = %target.url, event = %event_type, request_id = %request_id, %err, "webhook delivery failed");
+            return;
         }
-    });
-    state.persist_event(session_id, "agent", &envelope).await?;
+    };
 
-    state.emit_event(json!({
-        "type":"permission.replied",
-        "properties": {
-            "sessionID": session_id,
-            "requestID": permission_id,
-            "reply": reply,
+    let response = match event_type.as_str() {
+        "permission.asked" if reply.get("reply").is_some() => {
+            let Ok(body) = serde_json::from_value::<PermissionReplyBody>(reply) else {
+                warn!(url = %target.url, request_id = %request_id, "ignoring malformed webhook permission reply");
+                return;
+            };
+            oc_permission_reply(
+                State(state.clone()),
+                Path(request_id.clone()),
+                StrictJson(body),
+            )
+            .await
         }
-    }));
-
-    if reply == "always" {
-        let mut projection = state.projection.lock().await;
-        if let Some(session) = projection.sessions.get_mut(session_id) {
-            session.always_permissions.insert("execute".to_string());
+        "question.asked" if reply.get("reject").and_then(Value::as_bool) == Some(true) => {
+            oc_question_reject(State(state.clone()), Path(request_id.clone())).await
+        }
+        "question.asked" if reply.get("answers").is_some() => {
+            let Ok(body) = serde_json::from_value::<QuestionReplyBody>(reply) else {
+                warn!(url = %target.url, request_id = %request_id, "ignoring malformed webhook question reply");
+                return;
+            };
+            oc_question_reply(
+                State(state.clone()),
+                Path(request_id.clone()),
+                StrictJson(body),
+            )
+            .await
         }
+        _ => return,
+    };
+    if !response.status().is_success() {
+        tracing::debug!(request_id = %request_id, status = %response.status(), "webhook reply not applied");
     }
+}
 
-    set_session_status(state, session_id, "idle").await
+async fn transition_session(
+    state: &Arc<AdapterState>,
+    session_id: &str,
+    next: SessionLifecycle,
+    reason: &str,
+) -> Result<(), String> {
+    transition_session_with(state, session_id, next, reason, None).await
 }
 
-async fn set_session_status(
+/// Transition `session_id`, persisting the finished assistant message
+/// `assistant` (its info and parts) in the same transaction, so a turn's
+/// final message and the status that ends the turn are written together or
+/// not at all.
+async fn transition_session_with(
     state: &Arc<AdapterState>,
     session_id: &str,
-    status: &str,
+    next: SessionLifecycle,
+    reason: &str,
+    assistant: Option<(&Value, &[Value])>,
 ) -> Result<(), String> {
-    let updated_meta = {
+    let (previous, updated_meta) = {
         let mut projection = state.projection.lock().await;
         let Some(session) = projection.sessions.get_mut(session_id) else {
             return Err(format!("session '{session_id}' not found"));
         };
-        session.status = status.to_string();
-        session.meta.updated_at = now_ms();
-        session.meta.clone()
+        let previous = session.lifecycle;
+        if previous != next && !previous.can_transition_to(next) {
+            warn!(
+                session_id,
+                from = previous.as_str(),
+                to = next.as_str(),
+                reason,
+                "unexpected session lifecycle transition"
+            );
+        }
+        session.lifecycle = next;
+        session.meta.updated_at = state.now_ms();
+        (previous, session.meta.clone())
     };
-    state.persist_session(&updated_meta).await?;
-
-    let env = json!({
+    let mut envelopes = Vec::new();
+    if let Some((info, parts)) = assistant {
+        envelopes.push(json!({
+            "jsonrpc":"2.0",
+            "method":"_sandboxagent/opencode/message",
+            "params":{"message":{"info": info, "parts": parts}}
+        }));
+    }
+    envelopes.push(json!({
         "jsonrpc":"2.0",
         "method":"_sandboxagent/opencode/status",
-        "params":{"status": status}
-    });
-    state.persist_event(session_id, "agent", &env).await?;
-
-    state.emit_event(json!({
-        "type":"session.status",
-        "properties": {
-            "sessionID": session_id,
-            "status": {"type": status},
-        }
+        "params":{"status": next.status_type(), "state": next, "reason": reason}
     }));
+    state
+        .persist_transition(&updated_meta, &envelopes, next.ends_turn())
+        .await?;
+    if let Some((info, _)) = assistant {
+        state.emit_event(message_event("message.updated", info));
+    }
+
+    if previous != next {
+        state.emit_event(json!({
+            "type":"session.lifecycle",
+            "properties": {
+                "sessionID": session_id,
+                "from": previous,
+                "to": next,
+                "reason": reason,
+            }
+        }));
+    }
 
-    if status == "idle" {
+    if previous.status_type() != next.status_type() {
         state.emit_event(json!({
-            "type":"session.idle",
-            "properties": {"sessionID": session_id}
+            "type":"session.status",
+            "properties": {
+                "sessionID": session_id,
+                "status": {"type": next.status_type()},
+            }
         }));
+
+        if next.status_type() == "idle" {
+            state.emit_event(json!({
+                "type":"session.idle",
+                "properties": {"sessionID": session_id}
+            }));
+        }
     }
 
     Ok(())
 }
 
+/// An envelope ready to be written to the event log.
+struct EventRow {
+    id: String,
+    session_id: String,
+    created_at: i64,
+    connection_id: String,
+    /// The user message of the turn the envelope belongs to, for dedupe.
+    turn: String,
+    sender: String,
+    payload: Value,
+}
+
+/// Insert `row` into the event log. Returns `false`, writing nothing, when
+/// the same envelope was already persisted in its turn.
+async fn insert_event(conn: &mut sqlx::SqliteConnection, row: &EventRow) -> Result<bool, String> {
+    if let Some(key) = envelope_dedupe_key(&row.turn, &row.sender, &row.payload) {
+        let inserted = sqlx::query(
+            r#"INSERT OR IGNORE INTO event_dedupe (session_id, dedupe_key, event_id)
+               VALUES (?1, ?2, ?3)"#,
+        )
+        .bind(&row.session_id)
+        .bind(&key)
+        .bind(&row.id)
+        .execute(&mut *conn)
+        .await
+        .map_err(|err| err.to_string())?
+        .rows_affected();
+        if inserted == 0 {
+            tracing::debug!(session_id = %row.session_id, key, "skipping duplicate envelope");
+            return Ok(false);
+        }
+    }
+    sqlx::query(
+        r#"INSERT INTO events (id, session_id, created_at, connection_id, sender, payload_json)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6)"#,
+    )
+    .bind(&row.id)
+    .bind(&row.session_id)
+    .bind(row.created_at)
+    .bind(&row.connection_id)
+    .bind(&row.sender)
+    .bind(serde_json::to_string(&row.payload).map_err(|err| err.to_string())?)
+    .execute(&mut *conn)
+    .await
+    .map_err(|err| err.to_string())?;
+    Ok(true)
+}
+
+/// Insert a session's event log as exported by
+/// [`AdapterState::session_event_log`], skipping events already present.
+/// An event whose ID is taken by a different event fails the insert rather
+/// than being dropped.
+async fn insert_event_log(
+    conn: &mut sqlx::SqliteConnection,
+    session_id: &str,
+    events: &[Value],
+) -> Result<(), String> {
+    for event in events {
+        let id = event.get("id").and_then(Value::as_str).unwrap_or_default();
+        let payload = event.get("payload").unwrap_or(&Value::Null);
+        let inserted = sqlx::query(
+            r#"INSERT OR IGNORE INTO events (id, session_id, created_at, connection_id, sender, payload_json)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6)"#,
+        )
+        .bind(id)
+        .bind(session_id)
+        .bind(event.get("createdAt").and_then(Value::as_i64).unwrap_or_default())
+        .bind(
+            event
+                .get("connectionId")
+                .and_then(Value::as_str)
+                .unwrap_or("conn_unknown"),
+        )
+        .bind(event.get("sender").and_then(Value::as_str).unwrap_or("agent"))
+        .bind(serde_json::to_string(payload).map_err(|err| err.to_string())?)
+        .execute(&mut *conn)
+        .await
+        .map_err(|err| err.to_string())?
+        .rows_affected();
+        if inserted > 0 {
+            continue;
+        }
+        let existing = sqlx::query("SELECT session_id, payload_json FROM events WHERE id = ?1")
+            .bind(id)
+            .fetch_optional(&mut *conn)
+            .await
+            .map_err(|err| err.to_string())?;
+        let same = existing.is_some_and(|row| {
+            row.try_get::<String, _>("session_id").ok().as_deref() == Some(session_id)
+                && row
+                    .try_get::<String, _>("payload_json")
+                    .ok()
+                    .and_then(|json| serde_json::from_str::<Value>(&json).ok())
+                    .as_ref()
+                    == Some(payload)
+        });
+        if !same {
+            return Err(format!("event '{id}' collides with an existing event"));
+        }
+    }
+    Ok(())
+}
+
+/// Project an exported event log onto `session_id`.
+fn apply_event_log(projection: &mut Projection, session_id: &str, events: &[Value]) {
+    let mut dedupe = ReplayDedupe::default();
+    for event in events {
+        let sender = event
+            .get("sender")
+            .and_then(Value::as_str)
+            .unwrap_or("agent");
+        if let Some(payload) = event.get("payload") {
+            if dedupe.admit(session_id, sender, payload) {
+                apply_envelope(projection, session_id, sender, payload);
+            }
+        }
+    }
+}
+
+/// Upsert a session's row and its OpenCode metadata.
+async fn write_session(
+    conn: &mut sqlx::SqliteConnection,
+    meta: &SessionMeta,
+) -> Result<(), String> {
+    let session_init_json = meta
+        .session_init_json
+        .as_ref()
+        .map(|value| serde_json::to_string(value).unwrap_or_else(|_| "{}".to_string()));
+    sqlx::query(
+        r#"INSERT INTO sessions (
+            id, agent, agent_session_id, last_connection_id, created_at, destroyed_at, session_init_json
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+        ON CONFLICT(id) DO UPDATE SET
+            agent = excluded.agent,
+            agent_session_id = excluded.agent_session_id,
+            last_connection_id = excluded.last_connection_id,
+            created_at = excluded.created_at,
+            destroyed_at = excluded.destroyed_at,
+            session_init_json = excluded.session_init_json"#,
+    )
+    .bind(&meta.id)
+    .bind(&meta.agent)
+    .bind(&meta.agent_session_id)
+    .bind(&meta.last_connection_id)
+    .bind(meta.created_at)
+    .bind(meta.destroyed_at)
+    .bind(session_init_json)
+    .execute(&mut *conn)
+    .await
+    .map_err(|err| err.to_string())?;
+
+    let metadata_json = serde_json::to_string(meta).map_err(|err| err.to_string())?;
+    sqlx::query(
+        r#"INSERT INTO opencode_session_metadata (session_id, metadata_json)
+           VALUES (?1, ?2)
+           ON CONFLICT(session_id) DO UPDATE SET
+             metadata_json = excluded.metadata_json"#,
+    )
+    .bind(&meta.id)
+    .bind(metadata_json)
+    .execute(&mut *conn)
+    .await
+    .map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+/// Identity of an envelope within the turn it was persisted in: a hash of
+/// the sender and the payload without its JSON-RPC `id`, which a retried
+/// request regenerates. Status envelopes are ordered state changes that
+/// legitimately repeat, so they have none.
+fn envelope_dedupe_key(turn: &str, sender: &str, payload: &Value) -> Option<String> {
+    let method = payload.get("method").and_then(Value::as_str);
+    if method == Some("_sandboxagent/opencode/status") {
+        return None;
+    }
+    let mut content = payload.clone();
+    if let Some(object) = content.as_object_mut() {
+        object.remove("id");
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(turn.as_bytes());
+    hasher.update([0]);
+    hasher.update(sender.as_bytes());
+    hasher.update([0]);
+    hasher.update(content.to_string().as_bytes());
+    Some(hex::encode(hasher.finalize()))
+}
+
+/// The replay side of the dedupe in `persist_event`, for event logs that were
+/// written before it existed. A `session/prompt` starts a new turn after its
+/// own key is taken, as `persist_turn_start` does at runtime.
+#[derive(Default)]
+struct ReplayDedupe {
+    turns: HashMap<String, String>,
+    seen: HashSet<(String, String)>,
+}
+
+impl ReplayDedupe {
+    fn admit(&mut self, session_id: &str, sender: &str, payload: &Value) -> bool {
+        let turn = self.turns.get(session_id).map(String::as_str).unwrap_or("");
+        let admitted = match envelope_dedupe_key(turn, sender, payload) {
+            Some(key) => self.seen.insert((session_id.to_string(), key)),
+            None => true,
+        };
+        if payload.get("method").and_then(Value::as_str) == Some("session/prompt") {
+            if let Some(message_id) = payload
+                .pointer("/params/message/info/id")
+                .and_then(Value::as_str)
+            {
+                self.turns
+                    .insert(session_id.to_string(), message_id.to_string());
+            }
+        }
+        admitted
+    }
+}
+
 fn apply_envelope(projection: &mut Projection, session_id: &str, _sender: &str, payload: &Value) {
     let Some(method) = payload.get("method").and_then(Value::as_str) else {
         return;
@@ -3057,7 +11662,18 @@ fn apply_envelope(projection: &mut Projection, session_id: &str, _sender: &str,
                     .unwrap_or_default();
                 if let Some(session) = projection.sessions.get_mut(session_id) {
                     upsert_message(session, info, parts);
-                    session.status = "busy".to_string();
+                    session.pending_context.clear();
+                }
+            }
+        }
+        "_sandboxagent/opencode/context_added" => {
+            if let Some(item) = payload
+                .get("params")
+                .and_then(|params| params.get("item"))
+                .cloned()
+            {
+                if let Some(session) = projection.sessions.get_mut(session_id) {
+                    session.pending_context.push(item);
                 }
             }
         }
@@ -3078,15 +11694,21 @@ fn apply_envelope(projection: &mut Projection, session_id: &str, _sender: &str,
                 }
             }
         }
+        "_sandboxagent/opencode/message_redacted" => {
+            if let (Some(message_id), Some(session)) = (
+                redacted_message_id(payload),
+                projection.sessions.get_mut(session_id),
+            ) {
+                session.messages.retain(|message| {
+                    message.info.get("id").and_then(Value::as_str) != Some(message_id)
+                });
+            }
+        }
         "_sandboxagent/opencode/status" => {
-            let status = payload
-                .get("params")
-                .and_then(|params| params.get("status"))
-                .and_then(Value::as_str)
-                .unwrap_or("idle")
-                .to_string();
+            let lifecycle =
+                SessionLifecycle::from_status_params(payload.get("params").unwrap_or(&Value::Null));
             if let Some(session) = projection.sessions.get_mut(session_id) {
-                session.status = status;
+                session.lifecycle = lifecycle;
             }
         }
         "_sandboxagent/opencode/permission_asked" => {
@@ -3098,9 +11720,6 @@ fn apply_envelope(projection: &mut Projection, session_id: &str, _sender: &str,
                 if let Some(id) = request.get("id").and_then(Value::as_str) {
                     projection.permissions.insert(id.to_string(), request);
                 }
-                if let Some(session) = projection.sessions.get_mut(session_id) {
-                    session.status = "busy".to_string();
-                }
             }
         }
         "_sandboxagent/opencode/permission_replied" => {
@@ -3114,10 +11733,17 @@ fn apply_envelope(projection: &mut Projection, session_id: &str, _sender: &str,
                     .and_then(|params| params.get("reply"))
                     .and_then(Value::as_str)
                     .unwrap_or("once");
-                projection.permissions.remove(request_id);
-                if reply == "always" {
+                let asked = projection.permissions.remove(request_id);
+                // Auto-approved requests were never asked, so they carry
+                // their own request.
+                let request = payload.pointer("/params/request").cloned().or(asked);
+                if let (Some(request), "always") = (request, reply) {
                     if let Some(session) = projection.sessions.get_mut(session_id) {
-                        session.always_permissions.insert("execute".to_string());
+                        for rule in permission_rules::rules_for(&request) {
+                            if !session.always_rules.contains(&rule) {
+                                session.always_rules.push(rule);
+                            }
+                        }
                     }
                 }
             }
@@ -3131,9 +11757,6 @@ fn apply_envelope(projection: &mut Projection, session_id: &str, _sender: &str,
                 if let Some(id) = request.get("id").and_then(Value::as_str) {
                     projection.questions.insert(id.to_string(), request);
                 }
-                if let Some(session) = projection.sessions.get_mut(session_id) {
-                    session.status = "busy".to_string();
-                }
             }
         }
         "_sandboxagent/opencode/question_replied" => {
@@ -3154,6 +11777,36 @@ fn apply_envelope(projection: &mut Projection, session_id: &str, _sender: &str,
                 projection.questions.remove(request_id);
             }
         }
+        "_sandboxagent/opencode/permission_batched" => {
+            let params = payload.get("params");
+            let request_id = params
+                .and_then(|params| params.get("requestID"))
+                .and_then(Value::as_str);
+            let batch_id = params.and_then(|params| params.get("batchID")).cloned();
+            if let (Some(request_id), Some(batch_id)) = (request_id, batch_id) {
+                if let Some(request) = projection.permissions.get_mut(request_id) {
+                    request["batchID"] = batch_id;
+                }
+            }
+        }
+        "_sandboxagent/opencode/permission_cancelled" => {
+            if let Some(request_id) = payload
+                .get("params")
+                .and_then(|params| params.get("requestID"))
+                .and_then(Value::as_str)
+            {
+                projection.permissions.remove(request_id);
+            }
+        }
+        "_sandboxagent/opencode/question_cancelled" => {
+            if let Some(request_id) = payload
+                .get("params")
+                .and_then(|params| params.get("requestID"))
+                .and_then(Value::as_str)
+            {
+                projection.questions.remove(request_id);
+            }
+        }
         _ => {}
     }
 }
@@ -3196,13 +11849,21 @@ fn upsert_message(session: &mut SessionState, info: Value, parts: Vec<Value>) {
 }
 
 fn provider_payload(state: &Arc<AdapterState>) -> Value {
+    let mut payload = base_provider_payload(state);
+    if let Some(catalog) = state.model_catalog.as_ref() {
+        catalog.enrich(&mut payload);
+    }
+    payload
+}
+
+fn base_provider_payload(state: &Arc<AdapterState>) -> Value {
     // Use pre-built provider data from config when available (built from
     // real agent config options in router.rs).
     if let Some(payload) = state.config.provider_payload.as_ref() {
         return payload.clone();
     }
 
-    // Fallback: hardcoded mock/amp/claude/codex list for standalone testing.
+    // Fallback: hardcoded mock/amp/claude/codex/cursor list for standalone testing.
     let mock_model = model_entry("mock", "Mock", "Mock", true, true, true, true, 8192, 4096);
     let amp_model = model_entry(
         "smart", "Smart", "Amp", false, false, true, true, 8192, 4096,
@@ -3224,6 +11885,9 @@ fn provider_payload(state: &Arc<AdapterState>) -> Value {
     let codex_default = model_entry(
         "gpt-5", "GPT-5", "Codex", true, true, true, true, 200_000, 16_384,
     );
+    let cursor_auto = model_entry(
+        "auto", "Auto", "Cursor", true, false, true, true, 200_000, 8_192,
+    );
 
     json!({
         "all": [
@@ -3254,6 +11918,12 @@ fn provider_payload(state: &Arc<AdapterState>) -> Value {
                 "name": "Codex",
                 "env": [],
                 "models": { "gpt-5": codex_default },
+            },
+            {
+                "id": "cursor",
+                "name": "Cursor Agent",
+                "env": [],
+                "models": { "auto": cursor_auto },
             }
         ],
         "default": {
@@ -3261,8 +11931,9 @@ fn provider_payload(state: &Arc<AdapterState>) -> Value {
             "amp": "smart",
             "claude": "default",
             "codex": "gpt-5",
+            "cursor": "auto",
         },
-        "connected": ["mock", "amp", "claude", "codex"],
+        "connected": ["mock", "amp", "claude", "codex", "cursor"],
     })
 }
 
@@ -3359,6 +12030,30 @@ fn build_assistant_message(
     })
 }
 
+/// Record why the agent ended the turn on an assistant message: ACP's
+/// `stopReason` as sent, and the OpenCode `finish` it maps to. Refusals and
+/// token limits get their own `finish` so clients can tell them from a
+/// normal stop.
+fn set_stop_reason(info: &mut Value, stop_reason: StopReason) {
+    info["stopReason"] = json!(stop_reason.as_str());
+    info["finish"] = json!(match stop_reason {
+        StopReason::EndTurn => "stop",
+        StopReason::MaxTokens | StopReason::MaxTurnRequests => "length",
+        StopReason::Refusal => "content-filter",
+        StopReason::Cancelled | StopReason::Unknown => "other",
+    });
+}
+
+/// An OpenCode `RetryPart` without its IDs: the agent retried a failed step.
+fn build_retry_part(attempt: u64, error: &str, now: i64) -> Value {
+    json!({
+        "type": "retry",
+        "attempt": attempt,
+        "error": {"name": "APIError", "data": {"message": error, "isRetryable": true}},
+        "time": {"created": now},
+    })
+}
+
 /// Build a finalized assistant message with `time.completed` set.
 fn build_completed_assistant_message(
     session_id: &str,
@@ -3423,7 +12118,7 @@ fn normalize_parts(session_id: &str, message_id: &str, input: &[Value]) -> Vec<V
                 .get("id")
                 .and_then(Value::as_str)
                 .map(ToOwned::to_owned)
-                .unwrap_or_else(|| format!("part_{}_{}", message_id, index));
+                .unwrap_or_else(|| part_id(message_id, index as u64));
 
             if let Some(text) = part.get("text").and_then(Value::as_str) {
                 json!({
@@ -3433,6 +12128,11 @@ fn normalize_parts(session_id: &str, message_id: &str, input: &[Value]) -> Vec<V
                     "type": "text",
                     "text": text,
                 })
+            } else if let Some(mut agent) = agent_parts::normalize(part) {
+                agent["id"] = json!(id);
+                agent["sessionID"] = json!(session_id);
+                agent["messageID"] = json!(message_id);
+                agent
             } else {
                 let mut cloned = part.clone();
                 if let Some(obj) = cloned.as_object_mut() {
@@ -3446,6 +12146,17 @@ fn normalize_parts(session_id: &str, message_id: &str, input: &[Value]) -> Vec<V
         .collect()
 }
 
+/// Which ACP server instance backs a session. `serverID` is the dispatch
+/// key of the agent process; `connectionID` changes when the agent
+/// connection the session was bound to is replaced.
+fn backend_mapping(meta: &SessionMeta) -> Value {
+    json!({
+        "agent": meta.agent,
+        "serverID": meta.agent_session_id,
+        "connectionID": meta.last_connection_id,
+    })
+}
+
 fn session_to_value(meta: &SessionMeta) -> Value {
     let mut value = json!({
         "id": meta.id,
@@ -3482,6 +12193,68 @@ fn session_to_value(meta: &SessionMeta) -> Value {
         }
     }
 
+    if !meta.context_files.is_empty() {
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert("contextFiles".to_string(), json!(meta.context_files));
+        }
+    }
+
+    if !meta.env.is_empty() {
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert("env".to_string(), session_env::env_summary(&meta.env));
+        }
+    }
+
+    if meta.dry_run {
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert("dryRun".to_string(), json!(true));
+        }
+    }
+
+    if let Some(dormant_at) = meta.dormant_at {
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert("dormantAt".to_string(), json!(dormant_at));
+        }
+    }
+
+    if let Some(migrated_to) = &meta.migrated_to {
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert("migratedTo".to_string(), json!(migrated_to));
+        }
+    }
+
+    if !meta.priority.is_normal() {
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert("priority".to_string(), json!(meta.priority));
+        }
+    }
+
+    if !meta.kind.is_user() {
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert("kind".to_string(), json!(meta.kind));
+        }
+    }
+
+    if !meta.backends.is_empty() {
+        if let Some(obj) = value.as_object_mut() {
+            let backends = meta
+                .backends
+                .iter()
+                .map(|(target, backend)| {
+                    (
+                        target.clone(),
+                        json!({
+                            "agent": backend.agent,
+                            "providerID": backend.provider_id,
+                            "modelID": backend.model_id,
+                        }),
+                    )
+                })
+                .collect::<serde_json::Map<_, _>>();
+            obj.insert("backends".to_string(), Value::Object(backends));
+        }
+    }
+
     value
 }
 
@@ -3491,6 +12264,7 @@ fn provider_to_agent(provider_id: &str) -> String {
         "codex" => "codex".to_string(),
         "claude" => "claude".to_string(),
         "opencode" => "opencode".to_string(),
+        "cursor" => "cursor".to_string(),
         _ => "mock".to_string(),
     }
 }
@@ -3555,6 +12329,7 @@ fn default_model_for_provider(provider_id: &str) -> Option<&'static str> {
         "amp" => Some("smart"),
         "claude" => Some("default"),
         "codex" => Some("gpt-5"),
+        "cursor" => Some("auto"),
         _ => None,
     }
 }
@@ -3567,6 +12342,7 @@ fn provider_for_model(model_id: &str) -> Option<&'static str> {
         "default" | "sonnet" | "haiku" | "opus" => Some("claude"),
         _ if model_id.starts_with("claude-") => Some("claude"),
         _ if model_id.starts_with("gpt-") => Some("codex"),
+        _ if model_id.starts_with("composer-") => Some("cursor"),
         _ if model_id.contains('/') => Some("opencode"),
         _ if model_id.starts_with("opencode/") => Some("opencode"),
         _ => None,
@@ -3579,31 +12355,263 @@ fn default_for_agent(agent: &str) -> Option<(&'static str, &'static str)> {
         "amp" => Some(("amp", "smart")),
         "claude" => Some(("claude", "default")),
         "codex" => Some(("codex", "gpt-5")),
+        "cursor" => Some(("cursor", "auto")),
         _ => None,
     }
 }
 
-fn build_replay_text(events: &[Value], max_chars: usize) -> Option<String> {
-    if events.is_empty() {
+/// The message a `message_redacted` envelope tombstones.
+fn redacted_message_id(payload: &Value) -> Option<&str> {
+    if payload.get("method").and_then(Value::as_str)
+        != Some("_sandboxagent/opencode/message_redacted")
+    {
         return None;
     }
+    payload.pointer("/params/messageID").and_then(Value::as_str)
+}
 
-    let prefix = "Previous session history is replayed below as JSON-RPC envelopes. Use it as context before responding to the latest user prompt.\n";
-    let mut text = prefix.to_string();
+/// Drop the prompt text parts of a `session/prompt` envelope that quote
+/// `message_id`, i.e. replay text. Returns whether anything was removed.
+fn strip_quoted_message(payload: &mut Value, message_id: &str) -> bool {
+    let Some(prompt) = payload
+        .pointer_mut("/params/prompt")
+        .and_then(Value::as_array_mut)
+    else {
+        return false;
+    };
+    let before = prompt.len();
+    prompt.retain(|part| {
+        !part
+            .get("text")
+            .and_then(Value::as_str)
+            .is_some_and(|text| text.contains(message_id))
+    });
+    prompt.len() != before
+}
 
-    for event in events {
-        let line = serde_json::to_string(event).unwrap_or_else(|_| "{}".to_string());
-        if text.len() + line.len() + 1 > max_chars {
-            text.push_str("\n[history truncated]");
-            break;
+/// Whether an OpenCode event is about `message_id` or one of its parts.
+fn event_mentions_message(event: &Value, message_id: &str) -> bool {
+    let properties = &event["properties"];
+    [
+        properties.get("messageID"),
+        properties.pointer("/info/id"),
+        properties.pointer("/part/messageID"),
+    ]
+    .into_iter()
+    .flatten()
+    .any(|id| id.as_str() == Some(message_id))
+}
+
+fn rpc_method_allowed(allowlist: &[String], method: &str) -> bool {
+    allowlist.iter().any(|entry| match entry.strip_suffix('*') {
+        Some(prefix) => method.starts_with(prefix),
+        None => entry == method,
+    })
+}
+
+fn build_context_text(items: &[Value]) -> Option<String> {
+    if items.is_empty() {
+        return None;
+    }
+
+    let mut text = "Additional context attached by the user for this prompt:\n".to_string();
+    for item in items {
+        let body = item.get("text").and_then(Value::as_str).unwrap_or_default();
+        match item.get("label").and_then(Value::as_str) {
+            Some(label) => text.push_str(&format!("\n[{label}]\n{body}\n")),
+            None => text.push_str(&format!("\n{body}\n")),
         }
-        text.push_str(&line);
-        text.push('\n');
     }
 
     Some(text)
 }
 
+/// Session of a `message.part.updated` event.
+/// The session an event is about: its or its part's `sessionID`, then
+/// `info.sessionID` for message events and `info.id` for session events.
+fn event_session(event_type: &str, properties: &serde_json::Map<String, Value>) -> Option<String> {
+    part_event_session(properties).or_else(|| {
+        let info = properties.get("info")?;
+        let key = if event_type.starts_with("session.") {
+            "id"
+        } else {
+            "sessionID"
+        };
+        info.get(key).and_then(Value::as_str).map(str::to_string)
+    })
+}
+
+fn part_event_session(properties: &serde_json::Map<String, Value>) -> Option<String> {
+    properties
+        .get("sessionID")
+        .or_else(|| {
+            properties
+                .get("part")
+                .and_then(|part| part.get("sessionID"))
+        })
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+/// Events one `/event` subscriber is known to have missed, either because its
+/// broadcast receiver lagged or because `Last-Event-ID` points before the
+/// replay buffer. Each drop is announced once with a `server.gap` event, and
+/// the next `message.part.updated` of every session afterwards carries
+/// `gap: true` so the client knows to refetch that session's messages.
+#[derive(Default)]
+struct StreamGaps {
+    unannounced: u64,
+    dropped: bool,
+    flagged: HashSet<String>,
+    metrics: Option<Arc<dyn AdapterMetrics>>,
+}
+
+impl StreamGaps {
+    /// The subscriber's broadcast receiver fell `missed` events behind.
+    fn lagged(&mut self, missed: u64) {
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.events_dropped("event_stream", missed);
+        }
+        self.mark(missed);
+    }
+
+    fn mark(&mut self, missed: u64) {
+        if missed == 0 {
+            return;
+        }
+        self.unannounced += missed;
+        self.dropped = true;
+        self.flagged.clear();
+    }
+
+    fn take_notice(&mut self) -> Option<Value> {
+        if self.unannounced == 0 {
+            return None;
+        }
+        let missed = std::mem::take(&mut self.unannounced);
+        Some(json!({"type":"server.gap","properties":{"missed": missed}}))
+    }
+
+    fn annotate(&mut self, payload: &mut Value) {
+        if !self.dropped
+            || payload.get("type").and_then(Value::as_str) != Some("message.part.updated")
+        {
+            return;
+        }
+        let Some(properties) = payload.get_mut("properties").and_then(Value::as_object_mut) else {
+            return;
+        };
+        if let Some(session_id) = part_event_session(properties) {
+            if self.flagged.insert(session_id) {
+                properties.insert("gap".to_string(), json!(true));
+            }
+        }
+    }
+}
+
+/// A `heartbeatMs` or `keepAliveMs` value, clamped to the allowed range.
+fn stream_interval(ms: u64) -> Duration {
+    Duration::from_millis(ms.clamp(MIN_STREAM_INTERVAL_MS, MAX_STREAM_INTERVAL_MS))
+}
+
+/// An interval set in milliseconds by an environment variable, clamped like
+/// [`stream_interval`]; `default` when unset or not a number.
+fn interval_override(value: Option<String>, default: Duration) -> Duration {
+    value
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map_or(default, stream_interval)
+}
+
+/// Counts an `/event` subscriber for as long as its stream is alive, and
+/// measures how late its heartbeats are delivered.
+struct EventSubscriber {
+    metrics: Option<Arc<dyn AdapterMetrics>>,
+    clock: Arc<dyn Clock>,
+    /// When the last heartbeat handed to the stream was due, until the
+    /// stream is polled for the next frame.
+    heartbeat_due: Option<tokio::time::Instant>,
+    /// Held while the subscriber gets agent payloads.
+    _native: Option<NativeSubscription>,
+}
+
+impl EventSubscriber {
+    fn new(
+        metrics: Option<Arc<dyn AdapterMetrics>>,
+        clock: Arc<dyn Clock>,
+        native: Option<NativeSubscription>,
+    ) -> Self {
+        if let Some(metrics) = metrics.as_ref() {
+            metrics.event_subscribers_changed(1);
+        }
+        Self {
+            metrics,
+            clock,
+            heartbeat_due: None,
+            _native: native,
+        }
+    }
+
+    /// A `server.heartbeat` event for a tick that was due at `due`.
+    /// `sentAt` lets clients measure the delay on their side.
+    fn heartbeat(&mut self, due: tokio::time::Instant) -> Value {
+        self.heartbeat_due = Some(due);
+        json!({"type": "server.heartbeat", "properties": {"sentAt": self.clock.now_ms()}})
+    }
+
+    /// The stream was polled again, so the previous frame was handed to the
+    /// connection.
+    fn heartbeat_polled(&mut self) {
+        if let (Some(due), Some(metrics)) = (self.heartbeat_due.take(), self.metrics.as_ref()) {
+            metrics.heartbeat_delivered(due.elapsed());
+        }
+    }
+}
+
+/// A subscriber to agent payloads, counted in
+/// `AdapterState::native_subscribers` until dropped.
+struct NativeSubscription(Arc<AtomicUsize>);
+
+impl Drop for NativeSubscription {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for EventSubscriber {
+    fn drop(&mut self) {
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.event_subscribers_changed(-1);
+        }
+    }
+}
+
+fn batch_frame(
+    batch: Vec<OpenCodeStreamEvent>,
+    include_native: bool,
+    select: Option<&EventSelect>,
+) -> Event {
+    let last_id = batch.last().map(|event| event.id);
+    let payloads = batch
+        .into_iter()
+        .map(|event| select_event(select, event.into_payload(include_native)))
+        .collect::<Vec<_>>();
+    let evt = Event::default()
+        .json_data(payloads)
+        .unwrap_or_else(|_| Event::default().data("[]"));
+    match last_id {
+        Some(id) => evt.id(id.to_string()),
+        None => evt,
+    }
+}
+
+/// Apply a subscriber's `?select=` projection, if it has one.
+fn select_event(select: Option<&EventSelect>, payload: Value) -> Value {
+    match select {
+        Some(select) => select.apply(&payload),
+        None => payload,
+    }
+}
+
 fn parse_last_event_id(headers: &HeaderMap) -> Option<u64> {
     headers
         .get("last-event-id")
@@ -3613,69 +12621,339 @@ fn parse_last_event_id(headers: &HeaderMap) -> Option<u64> {
 
 fn resolve_directory(headers: &HeaderMap, query_directory: Option<&String>) -> String {
     if let Some(value) = query_directory {
-        return value.clone();
+        return paths::normalize_directory(value);
     }
 
     if let Ok(value) = std::env::var("OPENCODE_COMPAT_DIRECTORY") {
         if !value.trim().is_empty() {
-            return value;
+            return paths::normalize_directory(&value);
+        }
+    }
+
+    if let Some(value) = headers
+        .get("x-opencode-directory")
+        .and_then(|v| v.to_str().ok())
+    {
+        if !value.trim().is_empty() {
+            return paths::normalize_directory(value);
+        }
+    }
+
+    std::env::current_dir()
+        .ok()
+        .and_then(|path| path.to_str().map(paths::normalize_directory))
+        .unwrap_or_else(paths::home_dir)
+}
+
+// ---------------------------------------------------------------------------
+// ACP SSE event translation — reads the raw ACP SSE stream from the agent
+// process and emits translated OpenCode-compatible events.
+// ---------------------------------------------------------------------------
+
+/// Translate the agent behind `meta` on a background task. Events from a
+/// composer backend are attributed to its `target`.
+fn spawn_acp_translation(
+    state: &Arc<AdapterState>,
+    stream: AcpSequencedStream,
+    meta: &SessionMeta,
+    directory: &str,
+    target: Option<&str>,
+) {
+    let span = tracing::info_span!(
+        "acp_translation",
+        session_id = %meta.id,
+        correlation_id = tracing::field::Empty
+    );
+    tokio::spawn(
+        EVENT_BACKEND
+            .scope(
+                target.map(str::to_string),
+                NATIVE_PAYLOAD.scope(
+                    StdMutex::new(None),
+                    acp_sse_translation_task(
+                        state.clone(),
+                        stream,
+                        meta.clone(),
+                        directory.to_string(),
+                    ),
+                ),
+            )
+            .instrument(span),
+    );
+}
+
+/// Re-attach translation to agent processes that outlived the previous
+/// adapter, resuming each stream after the last payload it translated so
+/// in-flight turns keep streaming. Bindings whose agent is gone are dropped;
+/// the next prompt to those sessions bootstraps a fresh agent.
+async fn resume_acp_translations(state: Arc<AdapterState>) {
+    let Some(dispatch) = state.config.acp_dispatch.as_ref() else {
+        return;
+    };
+    if let Err(err) = state.ensure_initialized().await {
+        warn!(?err, "failed to initialize before resuming ACP sessions");
+        return;
+    }
+    let bindings = match state.load_acp_bindings().await {
+        Ok(bindings) if !bindings.is_empty() => bindings,
+        Ok(_) => return,
+        Err(err) => {
+            warn!(?err, "failed to load ACP session bindings");
+            return;
+        }
+    };
+    let live: HashSet<String> = dispatch
+        .instances()
+        .await
+        .into_iter()
+        .map(|instance| instance.server_id)
+        .collect();
+    for binding in bindings {
+        // The binding is for the session's own agent or one of its composer
+        // backends.
+        let (meta, target) = {
+            let projection = state.projection.lock().await;
+            let session = projection.sessions.get(&binding.session_id);
+            match session.map(|session| &session.meta) {
+                Some(meta) if meta.agent_session_id == binding.server_id => {
+                    (Some(meta.clone()), None)
+                }
+                Some(meta) => {
+                    let target = composer::target_for_server(
+                        &meta.backends,
+                        &meta.agent_session_id,
+                        &binding.server_id,
+                    )
+                    .map(str::to_string);
+                    let meta = target
+                        .as_deref()
+                        .and_then(|target| meta.for_backend(target));
+                    (meta, target)
+                }
+                None => (None, None),
+            }
+        };
+        let (Some(meta), true) = (meta, live.contains(&binding.server_id)) else {
+            if let Err(err) = state.delete_acp_binding(&binding.server_id).await {
+                warn!(?err, server_id = %binding.server_id, "failed to drop stale ACP binding");
+            }
+            continue;
+        };
+        if state
+            .acp_initialized
+            .lock()
+            .await
+            .contains_key(&binding.server_id)
+        {
+            continue;
+        }
+        let stream = match dispatch
+            .sequenced_notification_stream(&binding.server_id, binding.last_event_id)
+            .await
+        {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!(?err, server_id = %binding.server_id, "failed to reopen ACP stream");
+                continue;
+            }
+        };
+        spawn_acp_translation(&state, stream, &meta, &meta.directory, target.as_deref());
+        state
+            .acp_backends
+            .lock()
+            .await
+            .entry(binding.server_id.clone())
+            .or_default()
+            .bootstrapped_at = state.now_ms();
+        state
+            .acp_initialized
+            .lock()
+            .await
+            .insert(binding.server_id.clone(), binding.acp_session_id);
+        tracing::info!(
+            session_id = %meta.id,
+            server_id = %binding.server_id,
+            last_event_id = ?binding.last_event_id,
+            "resumed ACP translation after restart"
+        );
+    }
+}
+
+/// The `initialize` call that opens a connection to an `agent` process.
+fn initialize_call(agent: &str) -> AcpCall {
+    AcpCall::Initialize(InitializeParams {
+        protocol_version: 1,
+        client_capabilities: None,
+        client_info: Some(ClientInfo {
+            name: "sandbox-agent-opencode-adapter".to_string(),
+            version: "0.1.0".to_string(),
+        }),
+        meta: Some(json!({
+            "sandboxagent.dev": {
+                "agent": agent
+            }
+        })),
+        extra: [("capabilities".to_string(), json!({}))]
+            .into_iter()
+            .collect(),
+    })
+}
+
+/// Re-bind recently active sessions that [`resume_acp_translations`] could
+/// not resume to this server's agent connection, and start their agent
+/// processes with the launch arguments and environment their next bootstrap
+/// would use. The first prompt then only opens a new agent session on the
+/// running process.
+async fn prewarm_sessions(state: Arc<AdapterState>) {
+    let (Some(dispatch), Some(config)) = (
+        state.config.acp_dispatch.as_ref(),
+        state.config.session_prewarm.as_ref(),
+    ) else {
+        return;
+    };
+    let candidates = {
+        let initialized = state.acp_initialized.lock().await;
+        let projection = state.projection.lock().await;
+        projection
+            .sessions
+            .values()
+            .map(|session| prewarm::PrewarmCandidate {
+                session_id: session.meta.id.clone(),
+                updated_at: session.meta.updated_at,
+                eligible: session.meta.agent != "mock"
+                    && session.meta.dormant_at.is_none()
+                    && !initialized.contains_key(&session.meta.agent_session_id),
+            })
+            .collect::<Vec<_>>()
+    };
+    for session_id in prewarm::select(config, candidates, state.now_ms()) {
+        // The session moves to a new agent session on this server's
+        // connection now, rather than on its first prompt.
+        if let Err(err) = state.maybe_restore_session(&session_id).await {
+            warn!(%err, session_id = %session_id, "failed to re-bind session for pre-warming");
+            continue;
+        }
+        let Some(meta) = state
+            .projection
+            .lock()
+            .await
+            .sessions
+            .get(&session_id)
+            .map(|session| session.meta.clone())
+        else {
+            continue;
+        };
+        let server_id = meta.agent_session_id.clone();
+        if dispatch.has_instance(&server_id).await {
+            continue;
+        }
+        if state.config.context_files {
+            let launch_args = context_files::discover(&meta.directory, &meta.agent).launch_args();
+            if !launch_args.is_empty() {
+                dispatch.set_launch_args(&server_id, launch_args).await;
+            }
+        }
+        if !meta.env.is_empty() {
+            let env = meta
+                .env
+                .iter()
+                .map(|(name, var)| (name.clone(), var.value.clone()))
+                .collect();
+            dispatch.set_launch_env(&server_id, env).await;
         }
-    }
-
-    if let Some(value) = headers
-        .get("x-opencode-directory")
-        .and_then(|v| v.to_str().ok())
-    {
-        if !value.trim().is_empty() {
-            return value.to_string();
+        if !meta.priority.is_normal() {
+            dispatch
+                .set_launch_priority(&server_id, meta.priority)
+                .await;
+        }
+        match dispatch
+            .call(
+                &server_id,
+                Some(&meta.agent),
+                state.next_id("oc_rpc_"),
+                initialize_call(&meta.agent),
+            )
+            .await
+        {
+            Ok(outcome) => {
+                if let AcpCallOutcome::Result(AcpResult::Initialize(result)) = outcome {
+                    if let Some(capabilities) = result.agent_capabilities.as_ref() {
+                        state.record_prompt_capabilities(&meta.agent, capabilities);
+                    }
+                }
+                tracing::info!(
+                    session_id = %session_id,
+                    server_id = %server_id,
+                    agent = %meta.agent,
+                    "pre-warmed agent process after restart"
+                );
+            }
+            Err(err) => {
+                warn!(%err, session_id = %session_id, server_id = %server_id, "failed to pre-warm agent process");
+            }
         }
     }
-
-    std::env::current_dir()
-        .ok()
-        .and_then(|path| path.to_str().map(ToOwned::to_owned))
-        .unwrap_or_else(|| "/".to_string())
-}
-
-fn now_ms() -> i64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|duration| duration.as_millis() as i64)
-        .unwrap_or(0)
-}
-
-fn runtime_unique_seed() -> u64 {
-    let nanos = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|duration| duration.as_nanos() as u64)
-        .unwrap_or(0);
-    nanos ^ ((std::process::id() as u64) << 32)
 }
 
-// ---------------------------------------------------------------------------
-// ACP SSE event translation — reads the raw ACP SSE stream from the agent
-// process and emits translated OpenCode-compatible events.
-// ---------------------------------------------------------------------------
-
 async fn acp_sse_translation_task(
     state: Arc<AdapterState>,
-    mut stream: AcpPayloadStream,
-    session_id: String,
+    mut stream: AcpSequencedStream,
+    meta: SessionMeta,
     directory: String,
-    agent: String,
-    provider_id: String,
-    model_id: String,
 ) {
+    let SessionMeta {
+        id: session_id,
+        agent,
+        provider_id,
+        model_id,
+        agent_session_id: server_id,
+        ..
+    } = meta;
     tracing::info!(session_id = %session_id, agent = %agent, "ACP SSE translation task started");
 
     // Running assistant message ID (set on first update, used to group parts).
     let mut assistant_message_id: Option<String> = None;
     let mut part_counter: u64 = 0;
-    // Accumulated text for the current streaming text part.
-    let mut text_accum = String::new();
-    let mut text_part_id: Option<String> = None;
+    // The current streaming text and reasoning parts.
+    let mut text_part = StreamingPart::new("text");
+    let mut reasoning_part = StreamingPart::new("reasoning");
+    // Streaming speed of the running assistant message.
+    let mut stream_stats = StreamStats::default();
+    let mut flushed_seq = 0;
+    let mut handled_seq = 0;
+
+    loop {
+        let window = state.config.chunk_coalesce_window;
+        let flush_at = match (
+            text_part.flush_deadline(window),
+            reasoning_part.flush_deadline(window),
+        ) {
+            (Some(text), Some(reasoning)) => Some(text.min(reasoning)),
+            (text, reasoning) => text.or(reasoning),
+        };
+        let next = match flush_at {
+            Some(flush_at) => tokio::select! {
+                next = stream.next() => next,
+                _ = tokio::time::sleep_until(flush_at) => {
+                    let msg_id = assistant_message_id.as_deref().unwrap_or("");
+                    reasoning_part.flush(&state, &session_id, msg_id).await;
+                    text_part.flush(&state, &session_id, msg_id).await;
+                    continue;
+                }
+            },
+            None => stream.next().await,
+        };
+        let Some((seq, payload)) = next else {
+            break;
+        };
+        handled_seq = seq;
+        let native = state.native_payload(&payload);
+        let _ = NATIVE_PAYLOAD.try_with(|slot| {
+            if let Ok(mut slot) = slot.lock() {
+                *slot = native;
+            }
+        });
 
-    while let Some(payload) = stream.next().await {
         // Determine whether this is a notification (no `id`) or a response.
         let method = payload.get("method").and_then(Value::as_str);
         let has_result = payload.get("result").is_some();
@@ -3690,9 +12968,23 @@ async fn acp_sse_translation_task(
             "ACP SSE event received"
         );
 
+        // Coalesced chunks go out before anything that follows them.
+        let is_chunk = matches!(
+            payload
+                .pointer("/params/update/sessionUpdate")
+                .and_then(Value::as_str),
+            Some("agent_message_chunk" | "agent_thought_chunk")
+        );
+        if !is_chunk {
+            let msg_id = assistant_message_id.as_deref().unwrap_or("");
+            reasoning_part.flush(&state, &session_id, msg_id).await;
+            text_part.flush(&state, &session_id, msg_id).await;
+        }
+
         match method {
             // --- Text / tool streaming updates ---
-            Some("session/update") => {
+            // Retries are translated like an update of kind `retry`.
+            Some("session/update") | Some(ACP_SESSION_RETRY_METHOD) => {
                 // Lazily assign an assistant_message_id for grouping parts.
                 // Only set it here (not for every event) so that response
                 // events for initialize/session/new don't accidentally set
@@ -3705,19 +12997,47 @@ async fn acp_sse_translation_task(
                         .lock()
                         .await
                         .get(&*session_id)
-                        .cloned()
-                        .unwrap_or_else(|| state.next_id("msg_"));
-                    assistant_message_id = Some(format!("{user_id}_assistant"));
+                        .cloned();
+                    let assistant_id = match user_id {
+                        Some(user_id) => {
+                            tracing::Span::current().record(
+                                "correlation_id",
+                                logs::correlation_id(&session_id, &user_id).as_str(),
+                            );
+                            let assistant_id = format!("{user_id}_assistant");
+                            if let Err(err) = state
+                                .record_turn_assistant(&session_id, &user_id, &assistant_id)
+                                .await
+                            {
+                                warn!(?err, "failed to record turn assistant message");
+                            }
+                            assistant_id
+                        }
+                        None => format!("{}_assistant", state.next_id("msg_")),
+                    };
+                    assistant_message_id = Some(assistant_id);
+                    let dispatched_at = state
+                        .prompt_dispatched_at
+                        .lock()
+                        .ok()
+                        .and_then(|mut dispatched| dispatched.remove(&*session_id));
+                    stream_stats = StreamStats::new(dispatched_at);
                 }
                 let msg_id = assistant_message_id.as_deref().unwrap();
-                let params = payload.get("params").cloned().unwrap_or(json!({}));
+                let mut params = payload.get("params").cloned().unwrap_or(json!({}));
+                if method == Some(ACP_SESSION_RETRY_METHOD) {
+                    params["sessionUpdate"] = json!("retry");
+                    params = json!({ "update": params });
+                }
                 translate_session_update(
                     &state,
                     &session_id,
                     msg_id,
+                    seq,
                     &mut part_counter,
-                    &mut text_accum,
-                    &mut text_part_id,
+                    &mut text_part,
+                    &mut reasoning_part,
+                    &mut stream_stats,
                     &directory,
                     &agent,
                     &provider_id,
@@ -3729,9 +13049,9 @@ async fn acp_sse_translation_task(
 
             // --- Permission request from agent ---
             Some("session/request_permission") => {
-                let request_id = state.next_id("perm_");
+                let request_id = state.agent_request_id("perm_", &server_id, jsonrpc_id.as_ref());
                 let params = payload.get("params").cloned().unwrap_or(json!({}));
-                let permission_request = json!({
+                let mut permission_request = json!({
                     "id": request_id,
                     "sessionID": session_id,
                     "permission": params.get("permission").and_then(Value::as_str).unwrap_or("execute"),
@@ -3739,34 +13059,77 @@ async fn acp_sse_translation_task(
                     "metadata": params.get("metadata").cloned().unwrap_or(json!({})),
                     "always": [],
                 });
-
-                // Save the mapping so we can respond to the agent when the user replies.
-                if let Some(jrpc_id) = jsonrpc_id {
-                    state.acp_request_ids.lock().await.insert(
-                        request_id.clone(),
-                        AcpPendingRequest {
-                            opencode_session_id: session_id.clone(),
-                            jsonrpc_id: jrpc_id,
-                            kind: AcpPendingKind::Permission,
-                        },
-                    );
+                if let Some(tool_call) = params.get("toolCall") {
+                    attach_permission_tool_context(
+                        &state,
+                        &session_id,
+                        tool_call,
+                        &mut permission_request,
+                    )
+                    .await;
                 }
+                permission_rules::scope_request(&mut permission_request);
+                if let Some(auto) = auto_reply_for(&state, &session_id, &permission_request).await {
+                    if let Err(err) = auto_reply_permission(
+                        &state,
+                        &session_id,
+                        &permission_request,
+                        jsonrpc_id.map(|id| (server_id.as_str(), id)),
+                        auto,
+                    )
+                    .await
+                    {
+                        warn!(?err, "failed to persist automatic permission reply");
+                    }
+                } else {
+                    // Save the mapping so we can respond to the agent when the user replies.
+                    if let Some(jrpc_id) = jsonrpc_id {
+                        state.acp_request_ids.lock().await.insert(
+                            request_id.clone(),
+                            AcpPendingRequest {
+                                opencode_session_id: session_id.clone(),
+                                server_id: server_id.clone(),
+                                jsonrpc_id: jrpc_id,
+                                kind: AcpPendingKind::Permission,
+                            },
+                        );
+                    }
 
-                let asked = json!({
-                    "jsonrpc":"2.0",
-                    "method":"_sandboxagent/opencode/permission_asked",
-                    "params":{"request": permission_request}
-                });
-                if let Err(err) = state.persist_event(&session_id, "agent", &asked).await {
-                    warn!(?err, "failed to persist permission_asked event");
+                    let asked = json!({
+                        "jsonrpc":"2.0",
+                        "method":"_sandboxagent/opencode/permission_asked",
+                        "params":{"request": permission_request}
+                    });
+                    let fresh = state
+                        .persist_new_event(&session_id, "agent", &asked)
+                        .await
+                        .unwrap_or_else(|err| {
+                            warn!(?err, "failed to persist permission_asked event");
+                            true
+                        });
+                    // A request replayed to a restarted adapter was asked
+                    // already, and stays routed only while it is pending.
+                    if !fresh {
+                        forget_answered_request(&state, &request_id).await;
+                    }
+                    if fresh {
+                        if let Err(err) = ask_permission(&state, &permission_request).await {
+                            warn!(?err, "failed to persist aggregated permission request");
+                        }
+                        let _ = transition_session(
+                            &state,
+                            &session_id,
+                            SessionLifecycle::WaitingPermission,
+                            "permission_asked",
+                        )
+                        .await;
+                    }
                 }
-                state
-                    .emit_event(json!({"type":"permission.asked","properties":permission_request}));
             }
 
             // --- Question request from agent ---
             Some("_sandboxagent/session/request_question") => {
-                let request_id = state.next_id("q_");
+                let request_id = state.agent_request_id("q_", &server_id, jsonrpc_id.as_ref());
                 let params = payload.get("params").cloned().unwrap_or(json!({}));
                 let question_request = json!({
                     "id": request_id,
@@ -3779,6 +13142,7 @@ async fn acp_sse_translation_task(
                         request_id.clone(),
                         AcpPendingRequest {
                             opencode_session_id: session_id.clone(),
+                            server_id: server_id.clone(),
                             jsonrpc_id: jrpc_id,
                             kind: AcpPendingKind::Question,
                         },
@@ -3790,10 +13154,70 @@ async fn acp_sse_translation_task(
                     "method":"_sandboxagent/opencode/question_asked",
                     "params":{"request": question_request}
                 });
-                if let Err(err) = state.persist_event(&session_id, "agent", &asked).await {
-                    warn!(?err, "failed to persist question_asked event");
+                let fresh = state
+                    .persist_new_event(&session_id, "agent", &asked)
+                    .await
+                    .unwrap_or_else(|err| {
+                        warn!(?err, "failed to persist question_asked event");
+                        true
+                    });
+                if !fresh {
+                    forget_answered_request(&state, &request_id).await;
+                }
+                if fresh {
+                    state
+                        .emit_event(json!({"type":"question.asked","properties":question_request}));
+                    let _ = transition_session(
+                        &state,
+                        &session_id,
+                        SessionLifecycle::WaitingQuestion,
+                        "question_asked",
+                    )
+                    .await;
                 }
-                state.emit_event(json!({"type":"question.asked","properties":question_request}));
+            }
+
+            // --- Agent withdrew a permission or question request ---
+            Some(ACP_CANCEL_REQUEST_METHOD) | Some(ACP_REQUEST_CANCELLED_METHOD) => {
+                let params = payload.get("params").cloned().unwrap_or(json!({}));
+                cancel_agent_request(&state, &server_id, &params).await;
+            }
+
+            // --- Provider authentication failure ---
+            Some(ACP_AUTH_REQUIRED_METHOD) => {
+                let params = payload.get("params").cloned().unwrap_or(json!({}));
+                emit_auth_required(&state, &session_id, &provider_id, &params);
+                let _ = transition_session(
+                    &state,
+                    &session_id,
+                    SessionLifecycle::Errored,
+                    "auth_required",
+                )
+                .await;
+            }
+
+            // --- Resource limit breach ---
+            Some(ACP_LIMIT_EXCEEDED_METHOD) => {
+                let params = payload.get("params").cloned().unwrap_or(json!({}));
+                let message = params
+                    .pointer("/error/message")
+                    .and_then(Value::as_str)
+                    .unwrap_or("agent exceeded a resource limit");
+                state.emit_event(json!({
+                    "type": "session.error",
+                    "properties": {
+                        "sessionID": session_id,
+                        "error": {
+                            "name": "ResourceLimitError",
+                            "data": {
+                                "message": message,
+                                "limit": params.get("limit"),
+                                "killed": params.get("killed"),
+                                "error": params.get("error"),
+                            }
+                        }
+                    }
+                }));
             }
 
             // --- Session ended notification ---
@@ -3815,7 +13239,8 @@ async fn acp_sse_translation_task(
                         "error": {"name":"AgentError","data":{"message": error_message}}
                     }
                 }));
-                let _ = set_session_status(&state, &session_id, "idle").await;
+                let _ = transition_session(&state, &session_id, SessionLifecycle::Errored, reason)
+                    .await;
                 break;
             }
 
@@ -3840,28 +13265,13 @@ async fn acp_sse_translation_task(
                     }));
                 }
 
-                // Persist any remaining accumulated text part.
-                if let Some(tid) = text_part_id.take() {
-                    let msg_id = assistant_message_id.as_deref().unwrap_or("");
-                    let part = json!({
-                        "id": tid,
-                        "sessionID": session_id,
-                        "messageID": msg_id,
-                        "type": "text",
-                        "text": text_accum,
-                    });
-                    let env = json!({
-                        "jsonrpc":"2.0",
-                        "method":"_sandboxagent/opencode/message",
-                        "params":{"message":{"info":{"id": msg_id},"parts":[part]}}
-                    });
-                    if let Err(err) = state.persist_event(&session_id, "agent", &env).await {
-                        warn!(?err, "failed to persist ACP text part at turn end");
-                    }
-                    text_accum.clear();
-                }
+                // Persist any remaining streamed parts.
+                let msg_id = assistant_message_id.as_deref().unwrap_or("");
+                reasoning_part.close(&state, &session_id, msg_id).await;
+                text_part.close(&state, &session_id, msg_id).await;
 
-                // Finalize the assistant message.
+                // Finalize the assistant message with the turn's end.
+                let mut completed = None;
                 if let Some(msg_id) = assistant_message_id.as_ref() {
                     let parent_id = state
                         .last_user_message_id
@@ -3870,8 +13280,8 @@ async fn acp_sse_translation_task(
                         .get(&*session_id)
                         .cloned()
                         .unwrap_or_default();
-                    let now = now_ms();
-                    let info = build_completed_assistant_message(
+                    let now = state.now_ms();
+                    let mut info = build_completed_assistant_message(
                         &session_id,
                         msg_id,
                         &parent_id,
@@ -3881,14 +13291,43 @@ async fn acp_sse_translation_task(
                         &provider_id,
                         &model_id,
                     );
-                    state.emit_event(message_event("message.updated", &info));
+                    let stop_reason = payload
+                        .pointer("/result/stopReason")
+                        .and_then(|reason| StopReason::deserialize(reason).ok());
+                    if let Some(stop_reason) = stop_reason {
+                        set_stop_reason(&mut info, stop_reason);
+                    }
+                    state.annotate_model_fallback(&session_id, &mut info).await;
+                    let stats = stream_stats.snapshot(now);
+                    if let Some(usage) = payload
+                        .get("result")
+                        .and_then(TokenUsage::from_prompt_result)
+                    {
+                        usage.apply(&mut info, stats.reasoning_tokens);
+                    }
+                    if !stream_stats.is_empty() {
+                        info["stats"] = json!(stats);
+                        state.record_turn_stream_stats(&session_id, stats).await;
+                    }
+                    completed = Some(info);
                 }
 
-                let _ = set_session_status(&state, &session_id, "idle").await;
+                let (next, reason) = if has_error {
+                    (SessionLifecycle::Errored, "turn_failed")
+                } else {
+                    (SessionLifecycle::Idle, "turn_completed")
+                };
+                let assistant = completed.as_ref().map(|info| (info, &[][..]));
+                if let Err(err) =
+                    transition_session_with(&state, &session_id, next, reason, assistant).await
+                {
+                    warn!(?err, "failed to persist the end of the turn");
+                }
 
                 // Reset for next turn (if the SSE stream stays open).
                 assistant_message_id = None;
                 part_counter = 0;
+                stream_stats = StreamStats::default();
             }
 
             _ => {
@@ -3899,6 +13338,311 @@ async fn acp_sse_translation_task(
                 );
             }
         }
+
+        // A restarted adapter resumes after the last recorded payload. Payloads
+        // handled since then are translated again, and the envelope dedupe in
+        // `persist_event` keeps them out of the log a second time.
+        if has_result || has_error || seq >= flushed_seq + ACP_CURSOR_FLUSH_INTERVAL {
+            match state.record_acp_cursor(&session_id, seq).await {
+                Ok(()) => flushed_seq = seq,
+                Err(err) => warn!(?err, "failed to record ACP stream cursor"),
+            }
+        }
+    }
+
+    // The stream may end inside a coalescing window; send what is pending
+    // and record how far the stream was handled.
+    let msg_id = assistant_message_id.as_deref().unwrap_or("");
+    reasoning_part.flush(&state, &session_id, msg_id).await;
+    text_part.flush(&state, &session_id, msg_id).await;
+    if handled_seq > flushed_seq {
+        if let Err(err) = state.record_acp_cursor(&session_id, handled_seq).await {
+            warn!(?err, "failed to record ACP stream cursor");
+        }
+    }
+}
+
+/// Drop the route back to the agent of a request replayed to a restarted
+/// adapter once it is no longer pending.
+async fn forget_answered_request(state: &AdapterState, request_id: &str) {
+    let pending = {
+        let projection = state.projection.lock().await;
+        projection.permissions.contains_key(request_id)
+            || projection.questions.contains_key(request_id)
+    };
+    if !pending {
+        state.acp_request_ids.lock().await.remove(request_id);
+    }
+}
+
+/// Check the agent's buffered notifications after a failed bootstrap call. When
+/// the agent reported an authentication failure, surface it and return the
+/// response for the prompt request.
+async fn bootstrap_auth_failure(
+    state: &Arc<AdapterState>,
+    dispatch: &Arc<dyn AcpDispatch>,
+    server_id: &str,
+    session_id: &str,
+    provider_id: &str,
+) -> Option<Response> {
+    let mut stream = dispatch.notification_stream(server_id, None).await.ok()?;
+    let params = loop {
+        let payload = tokio::time::timeout(AUTH_FAILURE_SCAN_IDLE, stream.next())
+            .await
+            .ok()??;
+        if payload.get("method").and_then(Value::as_str) == Some(ACP_AUTH_REQUIRED_METHOD) {
+            break payload.get("params").cloned().unwrap_or(json!({}));
+        }
+    };
+
+    emit_auth_required(state, session_id, provider_id, &params);
+    let _ = transition_session(
+        state,
+        session_id,
+        SessionLifecycle::Errored,
+        "auth_required",
+    )
+    .await;
+    Some(
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "errors": [{
+                    "message": params.pointer("/error/message").and_then(Value::as_str).unwrap_or("agent authentication failed"),
+                    "hint": params.get("hint"),
+                }],
+                "error": params.get("error"),
+            })),
+        )
+            .into_response(),
+    )
+}
+
+/// Emit `provider.auth_required` with the runtime's remediation hint, plus
+/// the `session.error` OpenCode clients already render.
+fn emit_auth_required(state: &AdapterState, session_id: &str, provider_id: &str, params: &Value) {
+    let message = params
+        .pointer("/error/message")
+        .and_then(Value::as_str)
+        .unwrap_or("agent authentication failed");
+    state.emit_event(json!({
+        "type": "provider.auth_required",
+        "properties": {
+            "sessionID": session_id,
+            "providerID": provider_id,
+            "agent": params.get("agent"),
+            "signature": params.get("signature"),
+            "message": message,
+            "hint": params.get("hint"),
+            "error": params.get("error"),
+        }
+    }));
+    state.emit_event(json!({
+        "type": "session.error",
+        "properties": {
+            "sessionID": session_id,
+            "error": {
+                "name": "ProviderAuthError",
+                "data": {"providerID": provider_id, "message": message}
+            }
+        }
+    }));
+}
+
+/// Why a prompt could not run against an agent's ACP instance.
+#[derive(Debug, Clone, Copy)]
+enum AgentAuthError {
+    /// The agent needs `POST /agents/:agent/authenticate` first.
+    Required,
+    /// The agent rejected the credentials it was given.
+    Failed,
+}
+
+impl AgentAuthError {
+    fn name(self) -> &'static str {
+        match self {
+            AgentAuthError::Required => "AgentAuthRequiredError",
+            AgentAuthError::Failed => "AgentAuthFailedError",
+        }
+    }
+}
+
+/// Fail a prompt the agent would not run unauthenticated: emit
+/// `provider.auth_required`, mark the session errored and answer 401 with
+/// the methods the agent accepts.
+async fn agent_auth_failure(
+    state: &Arc<AdapterState>,
+    kind: AgentAuthError,
+    session_id: &str,
+    meta: &SessionMeta,
+    server_id: &str,
+    auth_methods: &[Value],
+    message: &str,
+) -> Response {
+    warn!(%server_id, agent = %meta.agent, %message, "ACP agent is not authenticated");
+    let params = json!({
+        "agent": meta.agent,
+        "error": {"message": message},
+        "hint": format!("POST /agents/{}/authenticate with one of authMethods", meta.agent),
+    });
+    emit_auth_required(state, session_id, &meta.provider_id, &params);
+    let _ = transition_session(
+        state,
+        session_id,
+        SessionLifecycle::Errored,
+        "auth_required",
+    )
+    .await;
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(json!({"errors":[{
+            "message": message,
+            "name": kind.name(),
+            "data": {
+                "agent": meta.agent,
+                "serverID": server_id,
+                "authMethods": auth_methods,
+            },
+        }]})),
+    )
+        .into_response()
+}
+
+/// A text or reasoning part that grows chunk by chunk during a turn. It keeps
+/// one part ID so UIs update it in place, is projected as it streams, and is
+/// persisted when it closes.
+#[derive(Debug)]
+struct StreamingPart {
+    part_type: &'static str,
+    id: Option<String>,
+    text: String,
+    started_at: i64,
+    /// Text streamed since the last `message.part.updated`, and when the
+    /// first of it arrived.
+    pending: String,
+    pending_since: Option<tokio::time::Instant>,
+}
+
+impl StreamingPart {
+    fn new(part_type: &'static str) -> Self {
+        Self {
+            part_type,
+            id: None,
+            text: String::new(),
+            started_at: 0,
+            pending: String::new(),
+            pending_since: None,
+        }
+    }
+
+    /// Append a chunk, allocating the part ID from the stream sequence number
+    /// of the first one.
+    fn push(&mut self, chunk: &str, message_id: &str, seq: u64, part_counter: &mut u64, now: i64) {
+        if self.id.is_none() {
+            self.id = Some(part_id(message_id, seq));
+            self.started_at = now;
+            *part_counter += 1;
+        }
+        self.text.push_str(chunk);
+        self.pending.push_str(chunk);
+        self.pending_since
+            .get_or_insert_with(tokio::time::Instant::now);
+    }
+
+    /// When the pending chunks must be emitted, if there are any.
+    fn flush_deadline(&self, window: Duration) -> Option<tokio::time::Instant> {
+        self.pending_since.map(|since| since + window)
+    }
+
+    /// Whether the pending chunks are due: the window passed or they reached
+    /// the size limit.
+    fn flush_due(&self, config: &OpenCodeAdapterConfig) -> bool {
+        self.pending.len() >= config.chunk_coalesce_max_chars
+            || self
+                .flush_deadline(config.chunk_coalesce_window)
+                .is_some_and(|deadline| deadline <= tokio::time::Instant::now())
+    }
+
+    /// Emit the pending chunks as one `message.part.updated` delta, after
+    /// projecting the part so far.
+    async fn flush(&mut self, state: &AdapterState, session_id: &str, message_id: &str) {
+        self.pending_since = None;
+        if self.pending.is_empty() {
+            return;
+        }
+        let delta = std::mem::take(&mut self.pending);
+        let part = self.to_part(session_id, message_id, None);
+        state
+            .project_parts(
+                session_id,
+                &json!({"id": message_id}),
+                std::slice::from_ref(&part),
+            )
+            .await;
+        state.emit_event(json!({
+            "type":"message.part.updated",
+            "properties":{
+                "sessionID": session_id,
+                "messageID": message_id,
+                "part": part,
+                "delta": delta
+            }
+        }));
+    }
+
+    fn to_part(&self, session_id: &str, message_id: &str, ended_at: Option<i64>) -> Value {
+        let mut part = json!({
+            "id": self.id,
+            "sessionID": session_id,
+            "messageID": message_id,
+            "type": self.part_type,
+            "text": self.text,
+        });
+        // OpenCode's ReasoningPart carries its own timing.
+        if self.part_type == "reasoning" {
+            part["time"] = match ended_at {
+                Some(end) => json!({"start": self.started_at, "end": end}),
+                None => json!({"start": self.started_at}),
+            };
+        }
+        part
+    }
+
+    /// Persist the part and reset for the next one. No-op if nothing was
+    /// streamed since the last close.
+    async fn close(&mut self, state: &Arc<AdapterState>, session_id: &str, message_id: &str) {
+        self.flush(state, session_id, message_id).await;
+        if self.id.is_none() {
+            return;
+        }
+        let part = self.to_part(session_id, message_id, Some(state.now_ms()));
+        if let Err(err) = state
+            .persist_parts(
+                session_id,
+                &json!({"id": message_id}),
+                std::slice::from_ref(&part),
+            )
+            .await
+        {
+            warn!(
+                ?err,
+                part_type = self.part_type,
+                "failed to persist ACP streamed part"
+            );
+        }
+        if self.part_type == "reasoning" {
+            // Publish the end time so UIs can collapse the finished part.
+            state.emit_event(json!({
+                "type":"message.part.updated",
+                "properties":{
+                    "sessionID": session_id,
+                    "messageID": message_id,
+                    "part": part,
+                }
+            }));
+        }
+        self.id = None;
+        self.text.clear();
     }
 }
 
@@ -3909,13 +13653,19 @@ async fn acp_sse_translation_task(
 ///   - `agent_message_chunk` / `agent_thought_chunk`:  `{ content: ContentBlock }`
 ///   - `tool_call`:  ToolCall fields at top level (`toolCallId`, `title`, …)
 ///   - `tool_call_update`:  ToolCallUpdate fields at top level
+///
+/// `seq` is the update's position in the agent's event stream. Parts it opens
+/// are named after it, so translating the stream again after a restart
+/// yields the same part IDs.
 async fn translate_session_update(
     state: &Arc<AdapterState>,
     session_id: &str,
     message_id: &str,
+    seq: u64,
     part_counter: &mut u64,
-    text_accum: &mut String,
-    text_part_id: &mut Option<String>,
+    text_part: &mut StreamingPart,
+    reasoning_part: &mut StreamingPart,
+    stream_stats: &mut StreamStats,
     directory: &str,
     agent: &str,
     provider_id: &str,
@@ -3923,17 +13673,23 @@ async fn translate_session_update(
     params: &Value,
 ) {
     // ACP session/update params: { sessionId, update: { sessionUpdate, content, ... } }
-    let update = params.get("update").unwrap_or(params);
-    let kind = update
-        .get("sessionUpdate")
-        .and_then(Value::as_str)
-        .unwrap_or("");
+    let Some(update) = AcpUpdate::from_acp(params) else {
+        tracing::debug!(
+            session_id = %session_id,
+            kind = ?params.pointer("/update/sessionUpdate"),
+            "translate_session_update: unhandled sessionUpdate kind"
+        );
+        return;
+    };
 
     // Emit AND persist the assistant message info on the first content update.
     if *part_counter == 0
         && matches!(
-            kind,
-            "agent_message_chunk" | "agent_thought_chunk" | "tool_call"
+            update,
+            AcpUpdate::AgentMessageChunk { .. }
+                | AcpUpdate::AgentThoughtChunk { .. }
+                | AcpUpdate::ToolCall { .. }
+                | AcpUpdate::Retry { .. }
         )
     {
         let parent_id = state
@@ -3943,8 +13699,8 @@ async fn translate_session_update(
             .get(session_id)
             .cloned()
             .unwrap_or_default();
-        let now = now_ms();
-        let info = build_assistant_message(
+        let now = state.now_ms();
+        let mut info = build_assistant_message(
             session_id,
             message_id,
             &parent_id,
@@ -3954,111 +13710,174 @@ async fn translate_session_update(
             provider_id,
             model_id,
         );
-        state.emit_event(message_event("message.updated", &info));
+        state.annotate_model_fallback(session_id, &mut info).await;
         // Persist so the projection has the correct info (role, parentID, etc.)
         // for this assistant message when the session is replayed.
-        let env = json!({
-            "jsonrpc":"2.0",
-            "method":"_sandboxagent/opencode/message",
-            "params":{"message":{"info": info, "parts":[]}}
-        });
-        if let Err(err) = state.persist_event(session_id, "agent", &env).await {
+        if let Err(err) = state.persist_parts(session_id, &info, &[]).await {
             warn!(?err, "failed to persist assistant message info");
         }
+        state.emit_event(message_event("message.updated", &info));
     }
 
-    match kind {
+    match &update {
         // ── Text / thought chunk ───────────────────────────────────────
-        "agent_message_chunk" | "agent_thought_chunk" => {
-            // ContentChunk.content is a ContentBlock; for text it has { type: "text", text: "…" }
-            let chunk = update
-                .pointer("/content/text")
-                .and_then(Value::as_str)
-                .unwrap_or("");
+        AcpUpdate::AgentMessageChunk { text: chunk }
+        | AcpUpdate::AgentThoughtChunk { text: chunk } => {
             if chunk.is_empty() {
                 return;
             }
-
-            // Accumulate into a single part — reuse the same part ID so the
-            // UI updates in-place instead of creating a new line per chunk.
-            text_accum.push_str(chunk);
-            let part_id = text_part_id.get_or_insert_with(|| {
-                let id = format!("part_{message_id}_{part_counter}");
-                *part_counter += 1;
-                id
-            });
+
+            // Thoughts stream into a reasoning part that closes once regular
+            // output resumes, so UIs can collapse it.
+            let thought = matches!(update, AcpUpdate::AgentThoughtChunk { .. });
+            let target = if thought {
+                reasoning_part
+            } else {
+                reasoning_part.close(state, session_id, message_id).await;
+                text_part
+            };
+            let now = state.now_ms();
+            target.push(chunk, message_id, seq, part_counter, now);
+            stream_stats.record(chunk, thought, now);
+            if stream_stats.report_due(now) {
+                state
+                    .record_turn_stream_stats(session_id, stream_stats.snapshot(now))
+                    .await;
+            }
+            // Fast token streams are merged into fewer, larger deltas; the
+            // translation task emits whatever is left when the window ends.
+            if target.flush_due(&state.config) {
+                target.flush(state, session_id, message_id).await;
+            }
+        }
+
+        // ── Tool call initiation ───────────────────────────────────────
+        AcpUpdate::ToolCall {
+            call_id,
+            title: tool_title,
+            kind,
+            input,
+            status,
+        } => {
+            // Finalize any streamed parts before switching to tool.
+            reasoning_part.close(state, session_id, message_id).await;
+            text_part.close(state, session_id, message_id).await;
+            // Updates to the call replace this part.
+            let part_id = tool_part_id(message_id, call_id);
+            *part_counter += 1;
+            let now = state.now_ms();
+            let announced = state
+                .update_turn_timeline(session_id, |timeline| {
+                    timeline.tool_started(call_id, tool_title, kind.as_deref(), now)
+                })
+                .await;
+            let paths = artifacts::tool_paths(input);
+            if !paths.is_empty() && kind.as_deref() != Some("read") {
+                state
+                    .record_turn_tool_paths(session_id, call_id, paths)
+                    .await;
+            }
+            if announced != Some(false) {
+                let mut event = tool_event(
+                    "tool.started",
+                    session_id,
+                    Some(message_id),
+                    call_id,
+                    tool_title,
+                );
+                event["properties"]["kind"] = json!(kind);
+                event["properties"]["input"] = input.clone();
+                event["properties"]["time"] = json!({"start": now});
+                state.emit_event(event);
+            }
+            // A call starting a sub-agent the prompt delegated to links the
+            // child session recording its work.
+            let metadata = match state
+                .start_subagent_session(session_id, call_id, input)
+                .await
+            {
+                Some(child_id) => json!({
+                    "sessionId": child_id,
+                    "subagent": agent_parts::started_by(input),
+                }),
+                None => json!({}),
+            };
+            let state_value = if status.as_deref() == Some("pending") {
+                json!({"status": "pending", "input": input, "raw": ""})
+            } else {
+                json!({
+                    "status": "running",
+                    "input": input,
+                    "title": tool_title,
+                    "metadata": metadata,
+                    "time": {"start": now}
+                })
+            };
             let part = json!({
-                "id": *part_id,
+                "id": part_id,
                 "sessionID": session_id,
                 "messageID": message_id,
-                "type": "text",
-                "text": *text_accum,
+                "type": "tool",
+                "callID": call_id,
+                "tool": tool_title,
+                "state": state_value,
             });
+            if let Err(err) = state
+                .persist_parts(
+                    session_id,
+                    &json!({"id": message_id}),
+                    std::slice::from_ref(&part),
+                )
+                .await
+            {
+                warn!(?err, "failed to persist ACP tool call event");
+            }
             state.emit_event(json!({
                 "type":"message.part.updated",
                 "properties":{
                     "sessionID": session_id,
                     "messageID": message_id,
-                    "part": part,
-                    "delta": chunk
+                    "part": part
                 }
             }));
+            // Codex announces some calls, such as web searches, already
+            // finished.
+            if matches!(status.as_deref(), Some("completed" | "failed")) {
+                update_tool_call(
+                    state,
+                    session_id,
+                    message_id,
+                    call_id,
+                    status.as_deref(),
+                    None,
+                    ToolCallChanges::default(),
+                )
+                .await;
+            }
         }
 
-        // ── Tool call initiation ───────────────────────────────────────
-        "tool_call" => {
-            // Finalize any accumulated text part before switching to tool.
-            if let Some(tid) = text_part_id.take() {
-                let part = json!({
-                    "id": tid,
-                    "sessionID": session_id,
-                    "messageID": message_id,
-                    "type": "text",
-                    "text": *text_accum,
-                });
-                let env = json!({
-                    "jsonrpc":"2.0",
-                    "method":"_sandboxagent/opencode/message",
-                    "params":{"message":{"info":{"id": message_id},"parts":[part]}}
-                });
-                if let Err(err) = state.persist_event(session_id, "agent", &env).await {
-                    warn!(?err, "failed to persist ACP text part");
-                }
-                text_accum.clear();
-            }
-            let call_id = update
-                .get("toolCallId")
-                .and_then(Value::as_str)
-                .unwrap_or("unknown");
-            let tool_title = update
-                .get("title")
-                .and_then(Value::as_str)
-                .unwrap_or("unknown");
-            let part_id = format!("part_{message_id}_{part_counter}");
+        // ── Retry of a failed step ─────────────────────────────────────
+        AcpUpdate::Retry {
+            attempt,
+            error,
+            next,
+        } => {
+            reasoning_part.close(state, session_id, message_id).await;
+            text_part.close(state, session_id, message_id).await;
+            let mut part = build_retry_part(*attempt, error, state.now_ms());
+            part["id"] = json!(part_id(message_id, seq));
+            part["sessionID"] = json!(session_id);
+            part["messageID"] = json!(message_id);
             *part_counter += 1;
-            let now = now_ms();
-            let part = json!({
-                "id": part_id,
-                "sessionID": session_id,
-                "messageID": message_id,
-                "type": "tool",
-                "callID": call_id,
-                "tool": tool_title,
-                "state": {
-                    "status": "running",
-                    "input": update.get("rawInput").cloned().unwrap_or(json!({})),
-                    "title": tool_title,
-                    "metadata": {},
-                    "time": {"start": now}
-                }
-            });
-            let env = json!({
-                "jsonrpc":"2.0",
-                "method":"_sandboxagent/opencode/message",
-                "params":{"message":{"info":{"id": message_id},"parts":[part.clone()]}}
-            });
-            if let Err(err) = state.persist_event(session_id, "agent", &env).await {
-                warn!(?err, "failed to persist ACP tool call event");
+            if let Err(err) = state
+                .persist_parts(
+                    session_id,
+                    &json!({"id": message_id}),
+                    std::slice::from_ref(&part),
+                )
+                .await
+            {
+                warn!(?err, "failed to persist ACP retry event");
             }
             state.emit_event(json!({
                 "type":"message.part.updated",
@@ -4068,58 +13887,400 @@ async fn translate_session_update(
                     "part": part
                 }
             }));
+            state.emit_event(json!({
+                "type":"message.retry",
+                "properties":{
+                    "sessionID": session_id,
+                    "messageID": message_id,
+                    "attempt": attempt,
+                    "error": part["error"],
+                    "next": next,
+                }
+            }));
         }
 
         // ── Tool call status update ────────────────────────────────────
-        "tool_call_update" => {
-            let call_id = update
-                .get("toolCallId")
-                .and_then(Value::as_str)
-                .unwrap_or("unknown");
-            let status = update
-                .get("status")
-                .and_then(Value::as_str)
-                .unwrap_or("completed");
-            let output = update
-                .get("content")
-                .and_then(|v| v.as_array())
-                .and_then(|arr| {
-                    arr.iter()
-                        .filter_map(|c| c.get("text").and_then(Value::as_str))
-                        .next()
+        AcpUpdate::ToolCallUpdate {
+            call_id,
+            status,
+            output,
+            title,
+            input,
+        } => {
+            let changes = ToolCallChanges {
+                title: title.as_deref(),
+                input: input.as_ref(),
+            };
+            update_tool_call(
+                state,
+                session_id,
+                message_id,
+                call_id,
+                status.as_deref(),
+                output.as_deref(),
+                changes,
+            )
+            .await;
+        }
+
+        // ── Plan ───────────────────────────────────────────────────────
+        AcpUpdate::Plan { entries } => {
+            let todos: Vec<Value> = entries
+                .iter()
+                .enumerate()
+                .map(|(index, entry)| {
+                    json!({
+                        "id": index.to_string(),
+                        "content": entry.content,
+                        "status": entry.status,
+                        "priority": entry.priority,
+                    })
                 })
-                .unwrap_or("");
-            let now = now_ms();
-            let part = json!({
-                "id": format!("part_tc_{call_id}"),
-                "sessionID": session_id,
-                "messageID": message_id,
-                "type": "tool",
-                "callID": call_id,
-                "state": {
-                    "status": status,
-                    "output": output,
-                    "time": {"end": now}
-                }
-            });
+                .collect();
+            state
+                .session_todos
+                .lock()
+                .await
+                .insert(session_id.to_string(), json!(todos));
             state.emit_event(json!({
-                "type":"message.part.updated",
+                "type":"todo.updated",
                 "properties":{
                     "sessionID": session_id,
-                    "messageID": message_id,
-                    "part": part
+                    "todos": todos
                 }
             }));
         }
+    }
+}
+
+/// Fields a `tool_call_update` changes besides its status and output.
+#[derive(Debug, Default, Clone, Copy)]
+struct ToolCallChanges<'a> {
+    title: Option<&'a str>,
+    input: Option<&'a Value>,
+}
 
-        _ => {
-            tracing::debug!(
-                session_id = %session_id,
-                kind = %kind,
-                "translate_session_update: unhandled sessionUpdate kind"
+/// Apply an ACP tool call status or output update to the call's tool part.
+/// The part keeps the input, title and start time it was created with, and
+/// its state follows the ACP status: `pending`, `running` (`in_progress`),
+/// `completed`, or `error` (`failed`). An update without a status leaves it
+/// unchanged. Status changes are persisted; output-only updates are
+/// projected, and persisted with the next status change.
+async fn update_tool_call(
+    state: &Arc<AdapterState>,
+    session_id: &str,
+    message_id: &str,
+    call_id: &str,
+    status: Option<&str>,
+    output: Option<&str>,
+    changes: ToolCallChanges<'_>,
+) {
+    let now = state.now_ms();
+    let tracked = state
+        .update_turn_timeline(session_id, |timeline| {
+            let ended = timeline.tool_updated(call_id, status, output.map(str::len), now);
+            (
+                ended,
+                timeline
+                    .span(call_id)
+                    .map(|span| (span.tool.clone(), span.start)),
+            )
+        })
+        .await;
+    let turn_running = tracked.is_some();
+    let (ended, span) = tracked.unwrap_or_default();
+    let previous = state
+        .recorded_tool_part(session_id, message_id, call_id)
+        .await
+        .unwrap_or(Value::Null);
+    let previous_state = &previous["state"];
+    let tool = changes
+        .title
+        .or_else(|| previous["tool"].as_str())
+        .or(span.as_ref().map(|(tool, _)| tool.as_str()))
+        .unwrap_or_default()
+        .to_string();
+    let input = changes
+        .input
+        .or_else(|| previous_state.get("input"))
+        .cloned()
+        .unwrap_or_else(|| json!({}));
+    let start = previous_state
+        .pointer("/time/start")
+        .and_then(Value::as_i64)
+        .or(span.as_ref().map(|(_, start)| *start))
+        .unwrap_or(now);
+    let part_status = status
+        .and_then(convert_acp::tool_state_status)
+        .or_else(|| previous_state["status"].as_str())
+        .unwrap_or("running")
+        .to_string();
+    let mut tool_state = match part_status.as_str() {
+        "pending" => json!({"status": "pending", "input": input, "raw": ""}),
+        status => {
+            let mut tool_state = json!({
+                "status": status,
+                "input": input,
+                "title": changes.title.or_else(|| previous_state["title"].as_str()).unwrap_or(&tool),
+                "metadata": previous_state.get("metadata").cloned().unwrap_or_else(|| json!({})),
+                "time": {"start": start},
+            });
+            if status != "running" {
+                tool_state["time"]["end"] = json!(now);
+            }
+            tool_state
+        }
+    };
+    if let Some(output) = output.or_else(|| previous_state["output"].as_str()) {
+        tool_state["output"] = json!(output);
+    }
+    let mut part = json!({
+        // Parts recorded under an earlier ID scheme keep theirs.
+        "id": previous
+            .get("id")
+            .cloned()
+            .unwrap_or_else(|| json!(tool_part_id(message_id, call_id))),
+        "sessionID": session_id,
+        "messageID": message_id,
+        "type": "tool",
+        "callID": call_id,
+        "tool": tool,
+        "state": tool_state,
+    });
+    state.cap_part_output(session_id, &mut part).await;
+    if let Some(child_id) = state.finish_subagent_session(call_id, status, output).await {
+        part["state"]["metadata"]["sessionId"] = json!(child_id);
+    }
+    if part_status == "error" {
+        part["state"]["error"] = part["state"]
+            .get("output")
+            .filter(|output| output.as_str().is_some_and(|output| !output.is_empty()))
+            .cloned()
+            .unwrap_or_else(|| json!("Tool call failed"));
+    }
+    let capped_output = output
+        .and_then(|_| part.pointer("/state/output").and_then(Value::as_str))
+        .map(str::to_owned);
+    let output = capped_output.as_deref();
+    match (status, ended) {
+        (_, Some(span)) => {
+            state.emit_event(tool_end_event(session_id, Some(message_id), &span, output))
+        }
+        // Outside a turn there is no timeline to time the call against.
+        (Some(status @ ("completed" | "failed")), None) if !turn_running => {
+            let mut event = tool_event(
+                &format!("tool.{status}"),
+                session_id,
+                Some(message_id),
+                call_id,
+                &tool,
             );
+            event["properties"]["status"] = json!(status);
+            event["properties"]["output"] = json!(output);
+            event["properties"]["time"] = json!({"end": now});
+            state.emit_event(event);
+        }
+        (status, None) if output.is_some_and(|output| !output.is_empty()) => {
+            let mut event = tool_event("tool.output", session_id, Some(message_id), call_id, &tool);
+            event["properties"]["status"] = json!(status.unwrap_or("running"));
+            event["properties"]["output"] = json!(output);
+            state.emit_event(event);
+        }
+        _ => {}
+    }
+    let info = json!({"id": message_id});
+    if status.is_none() {
+        state
+            .project_parts(session_id, &info, std::slice::from_ref(&part))
+            .await;
+    } else if let Err(err) = state
+        .persist_parts(session_id, &info, std::slice::from_ref(&part))
+        .await
+    {
+        warn!(?err, "failed to persist ACP tool call update");
+    }
+    state.emit_event(json!({
+        "type":"message.part.updated",
+        "properties":{
+            "sessionID": session_id,
+            "messageID": message_id,
+            "part": part
+        }
+    }));
+}
+
+const PERMISSION_DIFF_PREVIEW_MAX_CHARS: usize = 4_000;
+
+/// Describe the tool behind a permission request so a human can judge it.
+/// `tool_call` is the ACP `ToolCallUpdate` carried by the request; fields it
+/// omits are filled from the matching tool part already in the transcript.
+/// Adds `tool: {messageID, callID}` (OpenCode's shape) when the part is known,
+/// and `tool`, `input`, `diff` and `filepath` to `metadata`.
+async fn attach_permission_tool_context(
+    state: &Arc<AdapterState>,
+    session_id: &str,
+    tool_call: &Value,
+    request: &mut Value,
+) {
+    let call_id = tool_call.get("toolCallId").and_then(Value::as_str);
+    let pending_part = match call_id {
+        Some(call_id) => {
+            let projection = state.projection.lock().await;
+            projection.sessions.get(session_id).and_then(|session| {
+                session.messages.iter().rev().find_map(|message| {
+                    message
+                        .parts
+                        .iter()
+                        .find(|part| {
+                            part.get("type").and_then(Value::as_str) == Some("tool")
+                                && part.get("callID").and_then(Value::as_str) == Some(call_id)
+                        })
+                        .cloned()
+                })
+            })
+        }
+        None => None,
+    };
+
+    let tool_name = tool_call
+        .get("title")
+        .and_then(Value::as_str)
+        .or_else(|| pending_part.as_ref()?.get("tool")?.as_str())
+        .map(ToOwned::to_owned);
+    let input = tool_call
+        .get("rawInput")
+        .filter(|input| !is_empty_json(input))
+        .or_else(|| {
+            pending_part
+                .as_ref()?
+                .pointer("/state/input")
+                .filter(|input| !is_empty_json(input))
+        })
+        .cloned();
+    let diff = tool_call
+        .get("content")
+        .and_then(Value::as_array)
+        .and_then(|content| {
+            content
+                .iter()
+                .find(|item| item.get("type").and_then(Value::as_str) == Some("diff"))
+        });
+    let filepath = diff
+        .and_then(|diff| diff.get("path"))
+        .or_else(|| tool_call.pointer("/locations/0/path"))
+        .and_then(Value::as_str);
+
+    if let (Some(part), Some(call_id)) = (pending_part.as_ref(), call_id) {
+        if let Some(message_id) = part.get("messageID").and_then(Value::as_str) {
+            request["tool"] = json!({"messageID": message_id, "callID": call_id});
+        }
+    }
+    if !request.get("metadata").is_some_and(Value::is_object) {
+        request["metadata"] = json!({});
+    }
+    let metadata = &mut request["metadata"];
+    if let Some(tool_name) = tool_name {
+        metadata["tool"] = json!(tool_name);
+    }
+    if let Some(kind) = tool_call.get("kind").and_then(Value::as_str) {
+        metadata["toolKind"] = json!(kind);
+    }
+    if let Some(rendered) = render_tool_input(input.as_ref(), filepath) {
+        metadata["input"] = json!(rendered);
+    }
+    if let Some(input) = input {
+        metadata["rawInput"] = input;
+    }
+    if let Some(filepath) = filepath {
+        metadata["filepath"] = json!(filepath);
+    }
+    if let Some(diff) = diff {
+        metadata["diff"] = json!(render_diff_preview(
+            filepath.unwrap_or_default(),
+            diff.get("oldText")
+                .and_then(Value::as_str)
+                .unwrap_or_default(),
+            diff.get("newText")
+                .and_then(Value::as_str)
+                .unwrap_or_default(),
+        ));
+    }
+}
+
+fn is_empty_json(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Object(map) => map.is_empty(),
+        _ => false,
+    }
+}
+
+/// One-line summary of a tool's input: the shell command, else the file it
+/// touches, else the compact JSON input.
+fn render_tool_input(input: Option<&Value>, filepath: Option<&str>) -> Option<String> {
+    if let Some(command) = input.and_then(|input| input.get("command")) {
+        match command {
+            Value::String(command) => return Some(command.clone()),
+            Value::Array(args) => {
+                return Some(
+                    args.iter()
+                        .filter_map(Value::as_str)
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                )
+            }
+            _ => {}
+        }
+    }
+    let path = ["file_path", "filePath", "path", "notebook_path"]
+        .iter()
+        .find_map(|key| input?.get(*key)?.as_str())
+        .or(filepath);
+    if let Some(path) = path {
+        return Some(path.to_string());
+    }
+    input.map(Value::to_string)
+}
+
+/// Unified-style preview of an ACP diff: shared leading and trailing lines
+/// are dropped and the changed block is shown as `-`/`+` lines.
+fn render_diff_preview(path: &str, old_text: &str, new_text: &str) -> String {
+    let old_lines: Vec<&str> = old_text.lines().collect();
+    let new_lines: Vec<&str> = new_text.lines().collect();
+    let prefix = old_lines
+        .iter()
+        .zip(&new_lines)
+        .take_while(|(old, new)| old == new)
+        .count();
+    let suffix = old_lines[prefix..]
+        .iter()
+        .rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|(old, new)| old == new)
+        .count();
+
+    let mut preview = format!(
+        "--- {path}\n+++ {path}\n@@ -{},{} +{},{} @@\n",
+        prefix + 1,
+        old_lines.len() - prefix - suffix,
+        prefix + 1,
+        new_lines.len() - prefix - suffix,
+    );
+    for line in &old_lines[prefix..old_lines.len() - suffix] {
+        preview.push_str(&format!("-{line}\n"));
+    }
+    for line in &new_lines[prefix..new_lines.len() - suffix] {
+        preview.push_str(&format!("+{line}\n"));
+    }
+    if preview.len() > PERMISSION_DIFF_PREVIEW_MAX_CHARS {
+        let mut end = PERMISSION_DIFF_PREVIEW_MAX_CHARS;
+        while !preview.is_char_boundary(end) {
+            end -= 1;
         }
+        preview.truncate(end);
+        preview.push_str("\n... (truncated)\n");
     }
+    preview
 }
 
 fn normalize_proxy_base_url(value: String) -> Option<String> {
@@ -4141,7 +14302,7 @@ async fn resolve_proxy_base_url(state: &Arc<AdapterState>, path: &str) -> Option
     }
 
     let manager = state.config.native_proxy_manager.as_ref()?;
-    match manager.ensure_server().await {
+    match manager.ensure_server_within(SIDECAR_RESTART_WAIT).await {
         Ok(base_url) => Some(base_url),
         Err(err) => {
             warn!(path, error = ?err, "failed to lazily start native OpenCode sidecar");
@@ -4150,39 +14311,118 @@ async fn resolve_proxy_base_url(state: &Arc<AdapterState>, path: &str) -> Option
     }
 }
 
-async fn proxy_native_opencode(
+/// Send a request to the native sidecar. A request that cannot connect to a
+/// managed sidecar, e.g. because it just crashed, is retried once after the
+/// sidecar is back. GETs are also retried with jittered backoff on transport
+/// errors and 502/503/504 responses. The outcome feeds the circuit breaker.
+async fn send_native_request(
     state: &Arc<AdapterState>,
     method: reqwest::Method,
     path: &str,
     headers: &HeaderMap,
     body: Option<Value>,
-) -> Option<Response> {
-    let base_url = resolve_proxy_base_url(state, path).await?;
+) -> Option<Result<reqwest::Response, reqwest::Error>> {
+    let Some(mut base_url) = resolve_proxy_base_url(state, path).await else {
+        if state.config.native_proxy_manager.is_some() {
+            state.native_circuit.record_failure();
+            native_proxy::mark_fallback();
+        }
+        return None;
+    };
+    let retry_attempts = if method == reqwest::Method::GET {
+        native_proxy::GET_ATTEMPTS
+    } else {
+        1
+    };
+    let mut restarted = false;
+    let mut attempt = 1;
+    loop {
+        let mut request = state
+            .proxy_http_client
+            .request(method.clone(), format!("{base_url}{path}"));
+
+        for header_name in [
+            header::AUTHORIZATION,
+            header::ACCEPT,
+            HeaderName::from_static("x-opencode-directory"),
+        ] {
+            if let Some(value) = headers.get(&header_name) {
+                request = request.header(header_name.as_str(), value.as_bytes());
+            }
+        }
 
-    let mut request = state
-        .proxy_http_client
-        .request(method, format!("{base_url}{path}"));
+        if let Some(body) = body.as_ref() {
+            request = request.json(body);
+        }
 
-    for header_name in [
-        header::AUTHORIZATION,
-        header::ACCEPT,
-        HeaderName::from_static("x-opencode-directory"),
-    ] {
-        if let Some(value) = headers.get(&header_name) {
-            request = request.header(header_name.as_str(), value.as_bytes());
+        match request.send().await {
+            Err(err)
+                if err.is_connect()
+                    && !restarted
+                    && state.config.native_proxy_base_url.is_none()
+                    && state.config.native_proxy_manager.is_some() =>
+            {
+                warn!(path, error = ?err, "native OpenCode sidecar unreachable; retrying after restart");
+                restarted = true;
+                sleep(SIDECAR_EXIT_GRACE).await;
+                base_url = resolve_proxy_base_url(state, path).await?;
+                continue;
+            }
+            Err(err) if attempt < retry_attempts => {
+                warn!(path, attempt, error = ?err, "native OpenCode request failed; retrying");
+            }
+            Ok(response)
+                if attempt < retry_attempts && transient_native_status(response.status()) =>
+            {
+                warn!(path, attempt, status = %response.status(), "native OpenCode request failed; retrying");
+            }
+            result => {
+                match &result {
+                    Ok(response) if !transient_native_status(response.status()) => {
+                        state.native_circuit.record_success()
+                    }
+                    _ => state.native_circuit.record_failure(),
+                }
+                return Some(result);
+            }
         }
+        sleep(native_proxy::retry_delay(attempt)).await;
+        attempt += 1;
     }
+}
 
-    if let Some(body) = body {
-        request = request.json(&body);
-    }
+/// Statuses a briefly unavailable sidecar or a proxy in front of it answers
+/// with.
+fn transient_native_status(status: reqwest::StatusCode) -> bool {
+    matches!(status.as_u16(), 502..=504)
+}
 
-    let response = match request.send().await {
+async fn proxy_native_opencode(
+    state: &Arc<AdapterState>,
+    method: reqwest::Method,
+    path: &str,
+    headers: &HeaderMap,
+    body: Option<Value>,
+) -> Option<Response> {
+    let native_configured =
+        state.config.native_proxy_base_url.is_some() || state.config.native_proxy_manager.is_some();
+    if native_configured && state.native_circuit.is_open() {
+        native_proxy::mark_fallback();
+        return None;
+    }
+    let idempotent = method == reqwest::Method::GET;
+    let response = match send_native_request(state, method, path, headers, body).await? {
+        Ok(response) if idempotent && transient_native_status(response.status()) => {
+            warn!(path, status = %response.status(), "native OpenCode kept failing; falling back to adapter response");
+            native_proxy::mark_fallback();
+            return None;
+        }
         Ok(response) => response,
         Err(err) => {
             warn!(path, error = ?err, "failed proxy request to native OpenCode; falling back to adapter response");
             // Return None so the caller can use its own fallback response
             // instead of showing a BAD_GATEWAY error to the client.
+            native_proxy::mark_fallback();
             return None;
         }
     };
@@ -4232,27 +14472,7 @@ async fn proxy_native_opencode_json(
     headers: &HeaderMap,
     body: Option<Value>,
 ) -> Option<Result<(StatusCode, Value), Response>> {
-    let base_url = resolve_proxy_base_url(state, path).await?;
-
-    let mut request = state
-        .proxy_http_client
-        .request(method, format!("{base_url}{path}"));
-
-    for header_name in [
-        header::AUTHORIZATION,
-        header::ACCEPT,
-        HeaderName::from_static("x-opencode-directory"),
-    ] {
-        if let Some(value) = headers.get(&header_name) {
-            request = request.header(header_name.as_str(), value.as_bytes());
-        }
-    }
-
-    if let Some(body) = body {
-        request = request.json(&body);
-    }
-
-    let response = match request.send().await {
+    let response = match send_native_request(state, method, path, headers, body).await? {
         Ok(response) => response,
         Err(err) => {
             warn!(path, error = ?err, "failed proxy request to native OpenCode");
@@ -4309,6 +14529,7 @@ async fn proxy_native_opencode_json(
             path,
             "native OpenCode prompt proxy returned an empty success body; falling back to local compat"
         );
+        native_proxy::mark_fallback();
         return None;
     }
 
@@ -4353,6 +14574,71 @@ fn not_found(message: &str) -> Response {
         .into_response()
 }
 
+fn forbidden(message: &str) -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({"errors":[{"message": message}]})),
+    )
+        .into_response()
+}
+
+fn conflict(message: &str) -> Response {
+    (
+        StatusCode::CONFLICT,
+        Json(json!({"errors":[{"message": message}]})),
+    )
+        .into_response()
+}
+
+/// 410 for a session that now lives on another instance.
+fn session_migrated(session_id: &str, target: &str) -> Response {
+    (
+        StatusCode::GONE,
+        Json(json!({"errors":[{
+            "message": format!("Session {session_id} was migrated to {target}"),
+            "name": "SessionMigratedError",
+            "data": {"sessionID": session_id, "target": target},
+        }]})),
+    )
+        .into_response()
+}
+
+/// 502 for a migration the target refused or never acknowledged; the
+/// session stays on this instance.
+fn migration_failed(session_id: &str, target: &str, message: &str) -> Response {
+    warn!(%session_id, %target, %message, "session migration failed");
+    (
+        StatusCode::BAD_GATEWAY,
+        Json(json!({"errors":[{
+            "message": message,
+            "name": "SessionMigrationError",
+            "data": {"sessionID": session_id, "target": target},
+        }]})),
+    )
+        .into_response()
+}
+
+/// 429 with `Retry-After` for the hourly session limit, 409 for the others.
+fn quota_exceeded(exceeded: &QuotaExceeded) -> Response {
+    let body = Json(json!({"errors":[{
+        "message": exceeded.message(),
+        "name": "SessionQuotaExceededError",
+        "data": exceeded,
+    }]}));
+    match (exceeded.limit, exceeded.retry_after_ms) {
+        (QuotaLimit::SessionsPerHour, Some(retry_after_ms)) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(
+                header::RETRY_AFTER,
+                retry_after_ms.div_ceil(1000).to_string(),
+            )],
+            body,
+        )
+            .into_response(),
+        _ => (StatusCode::CONFLICT, body).into_response(),
+    }
+}
+
 fn internal_error(message: String) -> Response {
     warn!(?message, "opencode adapter internal error");
     (
@@ -4361,3 +14647,72 @@ fn internal_error(message: String) -> Response {
     )
         .into_response()
 }
+
+/// A 400 problem response listing why a prompt cannot be sent to `agent`.
+fn preflight_failure(agent: &str, problems: Vec<preflight::PreflightProblem>) -> Response {
+    let mut problem = ProblemDetails::new(
+        ErrorType::InvalidRequest,
+        Some(format!("prompt cannot be sent to {agent}")),
+    );
+    problem
+        .extensions
+        .insert("errors".to_string(), json!(problems));
+    (
+        StatusCode::BAD_REQUEST,
+        [(header::CONTENT_TYPE, "application/problem+json")],
+        Json(problem),
+    )
+        .into_response()
+}
+
+fn attachment_error(err: AttachmentError) -> Response {
+    match &err {
+        AttachmentError::UnsupportedMediaType {
+            index,
+            agent,
+            mime,
+            filename,
+            accepted,
+        } => (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Json(json!({"errors":[{
+                "message": err.to_string(),
+                "name": "UnsupportedMediaTypeError",
+                "data": {
+                    "partIndex": index,
+                    "agent": agent,
+                    "mime": mime,
+                    "filename": filename,
+                    "accepted": accepted,
+                },
+            }]})),
+        )
+            .into_response(),
+        AttachmentError::InvalidDataUrl(_) | AttachmentError::Transcode { .. } => {
+            bad_request(&err.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_intervals_default_and_clamp_their_overrides() {
+        let config = OpenCodeAdapterConfig::default();
+        assert_eq!(config.heartbeat_interval, Duration::from_secs(30));
+        assert_eq!(config.keep_alive_interval, Duration::from_secs(15));
+
+        let default = config.heartbeat_interval;
+        let parsed = |value: &str| interval_override(Some(value.to_string()), default);
+        assert_eq!(parsed(" 5000 "), Duration::from_secs(5));
+        assert_eq!(parsed("10"), Duration::from_secs(1));
+        assert_eq!(parsed("86400000"), Duration::from_secs(300));
+        assert_eq!(parsed("5s"), default);
+        assert_eq!(parsed("-1"), default);
+        assert_eq!(interval_override(None, default), default);
+        assert_eq!(stream_interval(0), Duration::from_secs(1));
+        assert_eq!(stream_interval(2_500), Duration::from_millis(2_500));
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::clock::{Clock, SystemClock};
+
+/// Log entries kept per session.
+const MAX_ENTRIES_PER_SESSION: usize = 2_000;
+/// Sessions with buffered logs; the least recently logged one is dropped first.
+const MAX_SESSIONS: usize = 256;
+/// Target and messages of the adapter's log lines for agent output: stderr
+/// lines, and stdout lines that are not JSON-RPC (carried in `raw`).
+const AGENT_OUTPUT_TARGET: &str = "acp_http_adapter::process";
+const AGENT_STDERR_PREFIX: &str = "agent stderr: ";
+const AGENT_STDOUT_MESSAGE: &str = "agent stdout: invalid JSON";
+/// Target of the server log lines for entries clients post to `/log`.
+pub(crate) const CLIENT_LOG_TARGET: &str = "opencode_client";
+
+static SESSION_LOGS: OnceLock<Arc<SessionLogStore>> = OnceLock::new();
+
+/// Correlation ID tying log lines to one turn: `{sessionID}/{userMessageID}`.
+pub(crate) fn correlation_id(session_id: &str, turn_id: &str) -> String {
+    format!("{session_id}/{turn_id}")
+}
+
+fn session_from_correlation(correlation_id: &str) -> &str {
+    correlation_id
+        .split_once('/')
+        .map_or(correlation_id, |(session_id, _)| session_id)
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SessionLogEntry {
+    pub time: i64,
+    pub level: String,
+    /// `sandbox-agent` for server logs, `agent` for the agent process output,
+    /// `client` for entries posted to `/log`.
+    pub source: &'static str,
+    pub target: String,
+    pub message: String,
+    #[serde(rename = "correlationID", skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub fields: Map<String, Value>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SessionLogStore {
+    sessions: Mutex<HashMap<String, (i64, VecDeque<SessionLogEntry>)>>,
+}
+
+impl SessionLogStore {
+    pub(crate) fn push(&self, session_id: &str, entry: SessionLogEntry) {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return;
+        };
+        if !sessions.contains_key(session_id) && sessions.len() >= MAX_SESSIONS {
+            if let Some(oldest) = sessions
+                .iter()
+                .min_by_key(|(_, (updated, _))| *updated)
+                .map(|(id, _)| id.clone())
+            {
+                sessions.remove(&oldest);
+            }
+        }
+        let (updated, entries) = sessions.entry(session_id.to_string()).or_default();
+        *updated = entry.time;
+        entries.push_back(entry);
+        while entries.len() > MAX_ENTRIES_PER_SESSION {
+            entries.pop_front();
+        }
+    }
+
+    /// Buffered entries for a session in time order.
+    pub(crate) fn entries(&self, session_id: &str) -> Vec<SessionLogEntry> {
+        let Ok(sessions) = self.sessions.lock() else {
+            return Vec::new();
+        };
+        let mut entries: Vec<_> = sessions
+            .get(session_id)
+            .map(|(_, entries)| entries.iter().cloned().collect())
+            .unwrap_or_default();
+        entries.sort_by_key(|entry| entry.time);
+        entries
+    }
+
+    pub(crate) fn remove(&self, session_id: &str) {
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.remove(session_id);
+        }
+    }
+}
+
+/// The process-wide store behind [`session_log_layer`].
+pub(crate) fn session_logs() -> &'static Arc<SessionLogStore> {
+    SESSION_LOGS.get_or_init(Default::default)
+}
+
+/// Tracing layer that buffers every event carrying a `session_id` or
+/// `correlation_id`, on the event itself or on an enclosing span, for
+/// `GET /session/:sessionID/logs`. Install it next to the regular log output.
+pub fn session_log_layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    SessionLogLayer {
+        store: session_logs().clone(),
+    }
+}
+
+struct SessionLogLayer {
+    store: Arc<SessionLogStore>,
+}
+
+/// Session context recorded on a span.
+#[derive(Debug, Default, Clone)]
+struct SpanContext {
+    session_id: Option<String>,
+    correlation_id: Option<String>,
+}
+
+impl<S> Layer<S> for SessionLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        if visitor.session_id.is_none() && visitor.correlation_id.is_none() {
+            return;
+        }
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanContext {
+                session_id: visitor.session_id,
+                correlation_id: visitor.correlation_id,
+            });
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        values.record(&mut visitor);
+        if visitor.session_id.is_none() && visitor.correlation_id.is_none() {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        match extensions.get_mut::<SpanContext>() {
+            Some(context) => {
+                if visitor.session_id.is_some() {
+                    context.session_id = visitor.session_id;
+                }
+                if visitor.correlation_id.is_some() {
+                    context.correlation_id = visitor.correlation_id;
+                }
+            }
+            None => extensions.insert(SpanContext {
+                session_id: visitor.session_id,
+                correlation_id: visitor.correlation_id,
+            }),
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+
+        // The innermost span with session context wins over outer ones; the
+        // event's own fields win over every span.
+        let mut inherited = SpanContext::default();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope {
+                if let Some(context) = span.extensions().get::<SpanContext>() {
+                    if inherited.session_id.is_none() {
+                        inherited.session_id = context.session_id.clone();
+                    }
+                    if inherited.correlation_id.is_none() {
+                        inherited.correlation_id = context.correlation_id.clone();
+                    }
+                }
+            }
+        }
+        let correlation_id = visitor.correlation_id.or(inherited.correlation_id);
+        let Some(session_id) = visitor.session_id.or(inherited.session_id).or_else(|| {
+            correlation_id
+                .as_deref()
+                .map(|id| session_from_correlation(id).to_string())
+        }) else {
+            return;
+        };
+
+        let metadata = event.metadata();
+        let mut message = visitor.message.unwrap_or_default();
+        let mut source = "sandbox-agent";
+        if metadata.target() == AGENT_OUTPUT_TARGET {
+            if let Some(line) = message.strip_prefix(AGENT_STDERR_PREFIX) {
+                message = line.to_string();
+                source = "agent";
+            } else if message == AGENT_STDOUT_MESSAGE {
+                if let Some(Value::String(raw)) = visitor.fields.get("raw") {
+                    message = raw.clone();
+                    source = "agent";
+                }
+            }
+        }
+
+        self.store.push(
+            &session_id,
+            SessionLogEntry {
+                time: SystemClock.now_ms(),
+                level: metadata.level().as_str().to_ascii_lowercase(),
+                source,
+                target: metadata.target().to_string(),
+                message,
+                correlation_id,
+                fields: visitor.fields,
+            },
+        );
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    message: Option<String>,
+    session_id: Option<String>,
+    correlation_id: Option<String>,
+    fields: Map<String, Value>,
+}
+
+impl FieldVisitor {
+    fn record_value(&mut self, field: &Field, value: Value) {
+        match field.name() {
+            "message" => {
+                self.message = Some(match value {
+                    Value::String(text) => text,
+                    other => other.to_string(),
+                })
+            }
+            // `?field` values arrive Debug-quoted.
+            "session_id" => {
+                self.session_id = value.as_str().map(|v| v.trim_matches('"').to_string())
+            }
+            "correlation_id" => {
+                self.correlation_id = value.as_str().map(|v| v.trim_matches('"').to_string())
+            }
+            name => {
+                self.fields.insert(name.to_string(), value);
+            }
+        }
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_value(field, Value::String(value.to_string()));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record_value(field, Value::Bool(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record_value(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record_value(field, Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_value(field, Value::String(format!("{value:?}")));
+    }
+}
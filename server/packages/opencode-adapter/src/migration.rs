@@ -0,0 +1,254 @@
+//! Live migration of a session to another sandbox-agent instance. The source
+//! freezes the session and pushes it to the target in two steps: `prepare`
+//! stages the export on the target, `commit` makes it live there. Only after
+//! a commit does the source hand the session over; a failed commit is
+//! aborted on the target and the session stays where it was.
+
+use std::collections::HashMap;
+
+use reqwest::{StatusCode, Url};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Version of [`SessionMigration`] this build exports and imports.
+pub(crate) const MIGRATION_VERSION: u32 = 1;
+
+/// Largest export `POST /session/import` accepts.
+pub(crate) const MAX_IMPORT_BYTES: usize = 256 * 1024 * 1024;
+
+/// How long a prepared import waits for its commit before it is dropped.
+const STAGED_IMPORT_TTL_MS: i64 = 10 * 60 * 1000;
+
+/// `POST /session/:id/migrate`: where to move the session.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MigrateBody {
+    /// Base URL of the target's OpenCode API, e.g.
+    /// `http://10.0.0.7:2468/opencode`.
+    pub target: String,
+    /// Bearer token for the target, when it requires one.
+    pub token: Option<String>,
+}
+
+/// A session as exported by the source and imported by the target.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SessionMigration {
+    pub version: u32,
+    /// The session's metadata, as persisted.
+    pub session: Value,
+    /// The session's event log, oldest first.
+    pub events: Vec<Value>,
+    /// Full outputs of capped tool parts: `{ partID, content, createdAt }`.
+    #[serde(default)]
+    pub part_blobs: Vec<Value>,
+    #[serde(default)]
+    pub todos: Value,
+    /// The ACP sessions the source ran the session on: `{ serverID,
+    /// acpSessionID, lastEventID }`. The target starts its own agent on the
+    /// next prompt and replays the history to it.
+    #[serde(default)]
+    pub acp: Vec<Value>,
+    /// Permission requests still waiting on the user, as listed by
+    /// `GET /permission`; the target lists them until they are answered.
+    #[serde(default)]
+    pub permissions: Vec<Value>,
+    /// Questions still waiting on the user, as listed by `GET /question`.
+    #[serde(default)]
+    pub questions: Vec<Value>,
+}
+
+/// Base URL of a migration target, without a trailing slash.
+pub(crate) fn target_url(target: &str) -> Result<String, String> {
+    let url = Url::parse(target).map_err(|err| format!("invalid migration target: {err}"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("migration target must be an http(s) URL".to_string());
+    }
+    Ok(target.trim_end_matches('/').to_string())
+}
+
+/// How `DELETE /session/import/:importID` left an import.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AbortOutcome {
+    Aborted,
+    /// The commit landed; its response was lost on the way back.
+    Committed,
+}
+
+/// The source's side of the handshake with a target.
+pub(crate) struct MigrationClient<'a> {
+    http: &'a reqwest::Client,
+    target: &'a str,
+    token: Option<&'a str>,
+}
+
+impl<'a> MigrationClient<'a> {
+    pub(crate) fn new(http: &'a reqwest::Client, target: &'a str, token: Option<&'a str>) -> Self {
+        Self {
+            http,
+            target,
+            token,
+        }
+    }
+
+    /// Stage the export on the target. Returns the import ID to commit.
+    pub(crate) async fn prepare(&self, migration: &SessionMigration) -> Result<String, String> {
+        let response = self
+            .request(reqwest::Method::POST, "/session/import")
+            .json(migration)
+            .send()
+            .await
+            .map_err(|err| format!("prepare failed: {err}"))?;
+        let body = success_body(response, "prepare").await?;
+        body.get("importID")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| "prepare response has no importID".to_string())
+    }
+
+    pub(crate) async fn commit(&self, import_id: &str) -> Result<(), String> {
+        let response = self
+            .request(
+                reqwest::Method::POST,
+                &format!("/session/import/{import_id}/commit"),
+            )
+            .send()
+            .await
+            .map_err(|err| format!("commit failed: {err}"))?;
+        success_body(response, "commit").await.map(|_| ())
+    }
+
+    pub(crate) async fn abort(&self, import_id: &str) -> Result<AbortOutcome, String> {
+        let response = self
+            .request(
+                reqwest::Method::DELETE,
+                &format!("/session/import/{import_id}"),
+            )
+            .send()
+            .await
+            .map_err(|err| format!("abort failed: {err}"))?;
+        if response.status() == StatusCode::CONFLICT {
+            return Ok(AbortOutcome::Committed);
+        }
+        success_body(response, "abort")
+            .await
+            .map(|_| AbortOutcome::Aborted)
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self.http.request(method, format!("{}{path}", self.target));
+        match self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+async fn success_body(response: reqwest::Response, step: &str) -> Result<Value, String> {
+    let status = response.status();
+    let body: Value = response.json().await.unwrap_or(Value::Null);
+    if status.is_success() {
+        return Ok(body);
+    }
+    let message = body
+        .pointer("/errors/0/message")
+        .and_then(Value::as_str)
+        .unwrap_or("no error message");
+    Err(format!("{step} failed with {status}: {message}"))
+}
+
+/// Imports prepared on this instance, by import ID, until they are
+/// committed or aborted.
+#[derive(Debug, Default)]
+pub(crate) struct SessionImports {
+    staged: HashMap<String, (i64, String, SessionMigration)>,
+    /// Committed imports, by import ID, so a retried commit or a late abort
+    /// learns the outcome.
+    committed: HashMap<String, String>,
+}
+
+impl SessionImports {
+    /// Stage `migration` of `session_id`, dropping imports whose commit never
+    /// came. Fails when the session is already staged.
+    pub(crate) fn stage(
+        &mut self,
+        import_id: &str,
+        session_id: &str,
+        migration: SessionMigration,
+        now: i64,
+    ) -> Result<(), String> {
+        self.staged
+            .retain(|_, (staged_at, _, _)| now - *staged_at < STAGED_IMPORT_TTL_MS);
+        if self
+            .staged
+            .values()
+            .any(|(_, staged, _)| staged == session_id)
+        {
+            return Err(format!("session {session_id} is already being imported"));
+        }
+        self.staged.insert(
+            import_id.to_string(),
+            (now, session_id.to_string(), migration),
+        );
+        Ok(())
+    }
+
+    /// Take a staged import to commit it.
+    pub(crate) fn take(&mut self, import_id: &str) -> Option<(String, SessionMigration)> {
+        self.staged
+            .remove(import_id)
+            .map(|(_, session_id, migration)| (session_id, migration))
+    }
+
+    pub(crate) fn committed(&mut self, import_id: &str, session_id: &str) {
+        self.committed
+            .insert(import_id.to_string(), session_id.to_string());
+    }
+
+    /// The session an import committed, if it did.
+    pub(crate) fn committed_session(&self, import_id: &str) -> Option<&str> {
+        self.committed.get(import_id).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn migration() -> SessionMigration {
+        SessionMigration {
+            version: MIGRATION_VERSION,
+            session: json!({"id": "ses_1"}),
+            events: Vec::new(),
+            part_blobs: Vec::new(),
+            todos: Value::Null,
+            acp: Vec::new(),
+            permissions: Vec::new(),
+            questions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn staged_imports_expire_and_remember_commits() {
+        let mut imports = SessionImports::default();
+        imports.stage("imp_1", "ses_1", migration(), 0).unwrap();
+        assert!(imports.stage("imp_2", "ses_1", migration(), 1).is_err());
+        // An import whose commit never came no longer holds the session.
+        imports
+            .stage("imp_3", "ses_1", migration(), STAGED_IMPORT_TTL_MS)
+            .unwrap();
+        assert!(imports.take("imp_1").is_none());
+        let (session_id, _) = imports.take("imp_3").expect("staged");
+        imports.committed("imp_3", &session_id);
+        assert_eq!(imports.committed_session("imp_3"), Some("ses_1"));
+        assert_eq!(imports.committed_session("imp_1"), None);
+
+        assert_eq!(
+            target_url("http://10.0.0.7:2468/opencode/").unwrap(),
+            "http://10.0.0.7:2468/opencode"
+        );
+        assert!(target_url("file:///tmp").is_err());
+    }
+}
@@ -0,0 +1,470 @@
+//! An in-memory [`AcpDispatch`] for testing adapter integrations and SDKs
+//! without spawning agent processes. Enabled by the `test-utils` feature.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{stream, StreamExt};
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+
+use crate::{
+    AcpDispatch, AcpDispatchResult, AcpInstanceSummary, AcpPayloadStream, AcpSequencedStream,
+    SessionPriority,
+};
+
+/// Notifications kept per instance for streams opened with a `last_event_id`.
+const MOCK_RING_SIZE: usize = 1024;
+
+/// A payload passed to [`AcpDispatch::post`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostedPayload {
+    pub server_id: String,
+    pub bootstrap_agent: Option<String>,
+    pub payload: Value,
+}
+
+impl PostedPayload {
+    /// The JSON-RPC method, or `None` for responses sent to the agent.
+    pub fn method(&self) -> Option<&str> {
+        self.payload.get("method").and_then(Value::as_str)
+    }
+}
+
+/// What the mock answers a request with.
+#[derive(Debug, Clone)]
+enum Scripted {
+    Result(Value),
+    Delayed(Duration, Value),
+    Error { code: i64, message: String },
+    Transport(String),
+}
+
+struct MockInstance {
+    agent: String,
+    created_at_ms: i64,
+    sequence: u64,
+    ring: VecDeque<(u64, Value)>,
+    sender: broadcast::Sender<(u64, Value)>,
+}
+
+#[derive(Default)]
+struct MockState {
+    instances: HashMap<String, MockInstance>,
+    /// One-shot answers by method, used in order before `defaults`.
+    queued: HashMap<String, VecDeque<Scripted>>,
+    defaults: HashMap<String, Scripted>,
+    posted: Vec<PostedPayload>,
+    deleted: Vec<String>,
+    launch_priorities: HashMap<String, SessionPriority>,
+    /// Reported as the OS process of every instance.
+    pid: Option<u32>,
+}
+
+/// An [`AcpDispatch`] that answers requests from a script and streams the
+/// notifications the test pushes.
+///
+/// Unscripted requests succeed with a minimal result: `initialize` reports
+/// protocol version 1, `session/new` a session ID derived from the server ID,
+/// `session/prompt` an `end_turn` stop reason and anything else `{}`. Like the
+/// real runtime, each response is also published on the instance's
+/// notification stream, after the notifications pushed before it.
+///
+/// ```
+/// # use sandbox_agent_opencode_adapter::MockAcpDispatch;
+/// # use serde_json::json;
+/// let dispatch = MockAcpDispatch::new();
+/// dispatch.respond("session/new", json!({"sessionId": "acp-1"}));
+/// dispatch.respond_error("session/prompt", -32000, "rate limited");
+/// ```
+#[derive(Clone, Default)]
+pub struct MockAcpDispatch {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockAcpDispatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Answer the next `method` request with `result`.
+    pub fn respond(&self, method: &str, result: Value) {
+        self.queue(method, Scripted::Result(result));
+    }
+
+    /// Answer the next `method` request with `result` after `delay`, like a
+    /// slow agent; e.g. to push notifications while a prompt is running.
+    pub fn respond_after(&self, method: &str, delay: Duration, result: Value) {
+        self.queue(method, Scripted::Delayed(delay, result));
+    }
+
+    /// Answer the next `method` request with a JSON-RPC error.
+    pub fn respond_error(&self, method: &str, code: i64, message: &str) {
+        self.queue(
+            method,
+            Scripted::Error {
+                code,
+                message: message.to_string(),
+            },
+        );
+    }
+
+    /// Fail the next `method` request as if the agent process were
+    /// unreachable.
+    pub fn fail(&self, method: &str, error: &str) {
+        self.queue(method, Scripted::Transport(error.to_string()));
+    }
+
+    /// Answer every `method` request without a queued answer with `result`.
+    pub fn respond_always(&self, method: &str, result: Value) {
+        self.lock()
+            .defaults
+            .insert(method.to_string(), Scripted::Result(result));
+    }
+
+    fn queue(&self, method: &str, scripted: Scripted) {
+        self.lock()
+            .queued
+            .entry(method.to_string())
+            .or_default()
+            .push_back(scripted);
+    }
+
+    /// Start an instance for `agent`, as if a previous adapter had bootstrapped
+    /// it. Instances are otherwise created by the first post for them.
+    pub fn spawn_instance(&self, server_id: &str, agent: &str) {
+        let mut state = self.lock();
+        ensure_instance(&mut state, server_id, Some(agent));
+    }
+
+    /// Report `pid` as the agent process of every instance, e.g. the test's
+    /// own process to exercise per-turn resource sampling.
+    pub fn set_pid(&self, pid: u32) {
+        self.lock().pid = Some(pid);
+    }
+
+    /// Drop the instance behind `server_id` as if its agent process had died,
+    /// ending its notification stream. Unlike a delete, it is not recorded.
+    pub fn stop_instance(&self, server_id: &str) {
+        self.lock().instances.remove(server_id);
+    }
+
+    /// Publish `payload` on the notification stream of `server_id`, starting
+    /// the instance if needed. Returns its sequence number.
+    pub fn notify(&self, server_id: &str, payload: Value) -> u64 {
+        let mut state = self.lock();
+        publish(ensure_instance(&mut state, server_id, None), payload)
+    }
+
+    /// Publish an ACP `session/update` notification carrying `update`.
+    pub fn session_update(&self, server_id: &str, acp_session_id: &str, update: Value) -> u64 {
+        self.notify(
+            server_id,
+            json!({
+                "jsonrpc": "2.0",
+                "method": "session/update",
+                "params": {"sessionId": acp_session_id, "update": update}
+            }),
+        )
+    }
+
+    /// Every payload posted so far, in order.
+    pub fn posted(&self) -> Vec<PostedPayload> {
+        self.lock().posted.clone()
+    }
+
+    /// Methods of the requests and notifications posted to `server_id`.
+    pub fn posted_methods(&self, server_id: &str) -> Vec<String> {
+        self.lock()
+            .posted
+            .iter()
+            .filter(|posted| posted.server_id == server_id)
+            .filter_map(|posted| posted.method().map(str::to_string))
+            .collect()
+    }
+
+    /// Server IDs of the instances deleted so far, in order.
+    pub fn deleted(&self) -> Vec<String> {
+        self.lock().deleted.clone()
+    }
+
+    /// The launch priority last set for `server_id`, if any.
+    pub fn launch_priority(&self, server_id: &str) -> Option<SessionPriority> {
+        self.lock().launch_priorities.get(server_id).copied()
+    }
+}
+
+fn ensure_instance<'a>(
+    state: &'a mut MockState,
+    server_id: &str,
+    agent: Option<&str>,
+) -> &'a mut MockInstance {
+    state
+        .instances
+        .entry(server_id.to_string())
+        .or_insert_with(|| MockInstance {
+            agent: agent.unwrap_or("mock").to_string(),
+            created_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as i64)
+                .unwrap_or_default(),
+            sequence: 0,
+            ring: VecDeque::new(),
+            sender: broadcast::channel(MOCK_RING_SIZE).0,
+        })
+}
+
+fn publish(instance: &mut MockInstance, payload: Value) -> u64 {
+    instance.sequence += 1;
+    let seq = instance.sequence;
+    instance.ring.push_back((seq, payload.clone()));
+    while instance.ring.len() > MOCK_RING_SIZE {
+        instance.ring.pop_front();
+    }
+    let _ = instance.sender.send((seq, payload));
+    seq
+}
+
+fn default_result(server_id: &str, method: &str) -> Value {
+    match method {
+        "initialize" => json!({"protocolVersion": 1, "agentCapabilities": {}}),
+        "session/new" => json!({"sessionId": format!("{server_id}-session")}),
+        "session/prompt" => json!({"stopReason": "end_turn"}),
+        _ => json!({}),
+    }
+}
+
+impl AcpDispatch for MockAcpDispatch {
+    fn post(
+        &self,
+        server_id: &str,
+        bootstrap_agent: Option<&str>,
+        payload: Value,
+    ) -> Pin<Box<dyn Future<Output = Result<AcpDispatchResult, String>> + Send + '_>> {
+        let mut state = self.lock();
+        state.posted.push(PostedPayload {
+            server_id: server_id.to_string(),
+            bootstrap_agent: bootstrap_agent.map(str::to_string),
+            payload: payload.clone(),
+        });
+        let method = payload.get("method").and_then(Value::as_str);
+        let result = match (method, payload.get("id")) {
+            (Some(method), Some(id)) => {
+                let scripted = state
+                    .queued
+                    .get_mut(method)
+                    .and_then(VecDeque::pop_front)
+                    .or_else(|| state.defaults.get(method).cloned())
+                    .unwrap_or_else(|| Scripted::Result(default_result(server_id, method)));
+                let response = match scripted {
+                    Scripted::Result(result) => {
+                        json!({"jsonrpc": "2.0", "id": id, "result": result})
+                    }
+                    Scripted::Error { code, message } => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": {"code": code, "message": message}
+                    }),
+                    Scripted::Transport(error) => return Box::pin(async move { Err(error) }),
+                    Scripted::Delayed(delay, result) => {
+                        let response = json!({"jsonrpc": "2.0", "id": id, "result": result});
+                        ensure_instance(&mut state, server_id, bootstrap_agent);
+                        drop(state);
+                        let server_id = server_id.to_string();
+                        return Box::pin(async move {
+                            tokio::time::sleep(delay).await;
+                            publish(
+                                ensure_instance(&mut self.lock(), &server_id, None),
+                                response.clone(),
+                            );
+                            Ok(AcpDispatchResult::Response(response))
+                        });
+                    }
+                };
+                publish(
+                    ensure_instance(&mut state, server_id, bootstrap_agent),
+                    response.clone(),
+                );
+                Ok(AcpDispatchResult::Response(response))
+            }
+            // Notifications, and responses to the agent's own requests.
+            _ => {
+                ensure_instance(&mut state, server_id, bootstrap_agent);
+                Ok(AcpDispatchResult::Accepted)
+            }
+        };
+        Box::pin(async move { result })
+    }
+
+    fn notification_stream(
+        &self,
+        server_id: &str,
+        last_event_id: Option<u64>,
+    ) -> Pin<Box<dyn Future<Output = Result<AcpPayloadStream, String>> + Send + '_>> {
+        let stream = self.sequenced_notification_stream(server_id, last_event_id);
+        Box::pin(async move {
+            let stream = stream.await?;
+            Ok(Box::pin(stream.map(|(_, payload)| payload)) as AcpPayloadStream)
+        })
+    }
+
+    fn sequenced_notification_stream(
+        &self,
+        server_id: &str,
+        last_event_id: Option<u64>,
+    ) -> Pin<Box<dyn Future<Output = Result<AcpSequencedStream, String>> + Send + '_>> {
+        let opened = match self.lock().instances.get(server_id) {
+            Some(instance) => {
+                let after = last_event_id.unwrap_or(0);
+                let replay = instance
+                    .ring
+                    .iter()
+                    .filter(|(seq, _)| *seq > after)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let live = stream::unfold(instance.sender.subscribe(), |mut receiver| async {
+                    loop {
+                        match receiver.recv().await {
+                            Ok(item) => return Some((item, receiver)),
+                            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                            Err(broadcast::error::RecvError::Closed) => return None,
+                        }
+                    }
+                });
+                Ok(Box::pin(stream::iter(replay).chain(live)) as AcpSequencedStream)
+            }
+            None => Err(format!("unknown ACP server instance {server_id}")),
+        };
+        Box::pin(async move { opened })
+    }
+
+    fn delete(
+        &self,
+        server_id: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + '_>> {
+        let mut state = self.lock();
+        state.instances.remove(server_id);
+        state.deleted.push(server_id.to_string());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn set_launch_priority(
+        &self,
+        server_id: &str,
+        priority: SessionPriority,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        self.lock()
+            .launch_priorities
+            .insert(server_id.to_string(), priority);
+        Box::pin(async {})
+    }
+
+    fn has_instance(&self, server_id: &str) -> Pin<Box<dyn Future<Output = bool> + Send + '_>> {
+        let exists = self.lock().instances.contains_key(server_id);
+        Box::pin(async move { exists })
+    }
+
+    fn instances(&self) -> Pin<Box<dyn Future<Output = Vec<AcpInstanceSummary>> + Send + '_>> {
+        let state = self.lock();
+        let instances = state
+            .instances
+            .iter()
+            .map(|(server_id, instance)| AcpInstanceSummary {
+                server_id: server_id.clone(),
+                agent: instance.agent.clone(),
+                created_at_ms: instance.created_at_ms,
+                pid: state.pid,
+            })
+            .collect();
+        drop(state);
+        Box::pin(async move { instances })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AcpCall, AcpCallError, AcpCallOutcome, AcpResult, SessionNewParams};
+
+    #[tokio::test]
+    async fn answers_from_the_script_then_the_defaults() {
+        let dispatch = MockAcpDispatch::new();
+        dispatch.respond("session/new", json!({"sessionId": "acp-1"}));
+        dispatch.respond_error("session/new", -32000, "no capacity");
+
+        let session_new = || {
+            AcpCall::SessionNew(SessionNewParams {
+                cwd: "/".to_string(),
+                mcp_servers: Vec::new(),
+                meta: None,
+                extra: Default::default(),
+            })
+        };
+        let first = dispatch
+            .call("srv", Some("claude"), "1".to_string(), session_new())
+            .await;
+        assert!(matches!(
+            first,
+            Ok(AcpCallOutcome::Result(AcpResult::SessionNew(result))) if result.session_id == "acp-1"
+        ));
+        let second = dispatch
+            .call("srv", None, "2".to_string(), session_new())
+            .await;
+        assert!(matches!(
+            second,
+            Err(AcpCallError::Rpc { code: -32000, .. })
+        ));
+        let third = dispatch
+            .call("srv", None, "3".to_string(), session_new())
+            .await;
+        assert!(matches!(
+            third,
+            Ok(AcpCallOutcome::Result(AcpResult::SessionNew(result))) if result.session_id == "srv-session"
+        ));
+
+        let posted = dispatch.posted();
+        assert_eq!(posted.len(), 3);
+        assert_eq!(posted[0].bootstrap_agent.as_deref(), Some("claude"));
+        assert_eq!(dispatch.posted_methods("srv"), vec!["session/new"; 3]);
+        let instances = dispatch.instances().await;
+        assert_eq!(instances[0].agent, "claude");
+
+        dispatch.delete("srv").await.unwrap();
+        assert_eq!(dispatch.deleted(), vec!["srv"]);
+        assert!(dispatch.instances().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn streams_replay_after_the_cursor_then_follow_live() {
+        let dispatch = MockAcpDispatch::new();
+        dispatch.spawn_instance("srv", "codex");
+        dispatch.notify("srv", json!({"n": 1}));
+        dispatch.notify("srv", json!({"n": 2}));
+
+        let mut stream = dispatch
+            .sequenced_notification_stream("srv", Some(1))
+            .await
+            .unwrap();
+        assert_eq!(stream.next().await, Some((2, json!({"n": 2}))));
+        dispatch
+            .post(
+                "srv",
+                None,
+                json!({"jsonrpc": "2.0", "id": 7, "method": "session/prompt"}),
+            )
+            .await
+            .unwrap();
+        let (seq, response) = stream.next().await.unwrap();
+        assert_eq!(seq, 3);
+        assert_eq!(response["result"]["stopReason"], "end_turn");
+
+        assert!(dispatch.notification_stream("missing", None).await.is_err());
+    }
+}
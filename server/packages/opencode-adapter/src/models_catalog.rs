@@ -0,0 +1,326 @@
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+use serde_json::Value;
+
+use crate::archive::env_nonempty;
+
+const DEFAULT_MODELS_URL: &str = "https://models.dev/api.json";
+const DEFAULT_REFRESH_SECS: u64 = 60 * 60;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// A models.dev snapshot covering the Claude and Codex models, used until
+/// the first fetch succeeds and whenever the catalog cannot be fetched.
+const BUNDLED_CATALOG: &str = include_str!("../models/catalog.json");
+/// Model fields taken from the catalog; `id`, `name` and `family` stay as
+/// the agent reports them.
+const MERGED_FIELDS: &[&str] = &[
+    "attachment",
+    "reasoning",
+    "temperature",
+    "tool_call",
+    "knowledge",
+    "release_date",
+    "last_updated",
+    "modalities",
+    "open_weights",
+    "cost",
+    "limit",
+];
+/// Catalog providers searched after the agent's own vendor.
+const FIRST_PARTY_PROVIDERS: &[&str] = &["anthropic", "openai", "google"];
+
+/// Where the models.dev catalog is fetched from, how often, and where the
+/// last fetched copy is kept across restarts.
+#[derive(Debug, Clone)]
+pub struct ModelCatalogConfig {
+    pub url: String,
+    /// `None` never fetches; the cached or bundled catalog is used as is.
+    pub refresh_interval: Option<Duration>,
+    /// Defaults to `opencode-models.json` next to the adapter database.
+    pub cache_path: Option<PathBuf>,
+}
+
+impl Default for ModelCatalogConfig {
+    fn default() -> Self {
+        Self {
+            url: DEFAULT_MODELS_URL.to_string(),
+            refresh_interval: Some(Duration::from_secs(DEFAULT_REFRESH_SECS)),
+            cache_path: None,
+        }
+    }
+}
+
+impl ModelCatalogConfig {
+    /// Build from `OPENCODE_COMPAT_MODELS_URL`,
+    /// `OPENCODE_COMPAT_MODELS_REFRESH_SECS` (`0` keeps the catalog offline)
+    /// and `OPENCODE_COMPAT_MODELS_CACHE_PATH`.
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let refresh_secs = env_nonempty("OPENCODE_COMPAT_MODELS_REFRESH_SECS")
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_REFRESH_SECS);
+        Self {
+            url: env_nonempty("OPENCODE_COMPAT_MODELS_URL").unwrap_or(defaults.url),
+            refresh_interval: (refresh_secs > 0).then(|| Duration::from_secs(refresh_secs)),
+            cache_path: env_nonempty("OPENCODE_COMPAT_MODELS_CACHE_PATH").map(PathBuf::from),
+        }
+    }
+}
+
+/// The models.dev catalog (`{ providerID: { models: { modelID: Model } } }`)
+/// used to fill in pricing, modalities and limits on `/provider`.
+pub(crate) struct ModelCatalog {
+    config: ModelCatalogConfig,
+    catalog: RwLock<Value>,
+    /// When the catalog in use was fetched; `None` for the bundled one.
+    fetched_at: RwLock<Option<SystemTime>>,
+    client: reqwest::Client,
+}
+
+impl ModelCatalog {
+    /// Start from the cached catalog if there is a readable one, otherwise
+    /// from the bundled snapshot.
+    pub(crate) fn new(config: ModelCatalogConfig) -> Self {
+        let cached = config.cache_path.as_deref().and_then(read_cache);
+        let (catalog, fetched_at) = match cached {
+            Some((catalog, modified)) => (catalog, Some(modified)),
+            None => (
+                serde_json::from_str(BUNDLED_CATALOG).unwrap_or(Value::Null),
+                None,
+            ),
+        };
+        Self {
+            config,
+            catalog: RwLock::new(catalog),
+            fetched_at: RwLock::new(fetched_at),
+            client: reqwest::Client::builder()
+                .timeout(FETCH_TIMEOUT)
+                .build()
+                .unwrap_or_else(|_| reqwest::Client::new()),
+        }
+    }
+
+    pub(crate) fn refresh_interval(&self) -> Option<Duration> {
+        self.config.refresh_interval
+    }
+
+    /// Time until the catalog in use is due for a refresh.
+    pub(crate) fn next_refresh_in(&self) -> Duration {
+        let Some(interval) = self.config.refresh_interval else {
+            return Duration::MAX;
+        };
+        let fetched_at = self.fetched_at.read().ok().and_then(|guard| *guard);
+        fetched_at
+            .and_then(|fetched_at| fetched_at.elapsed().ok())
+            .map_or(Duration::ZERO, |age| interval.saturating_sub(age))
+    }
+
+    /// Fetch the catalog and, if it parses, use it and write it to the cache.
+    pub(crate) async fn refresh(&self) -> Result<(), String> {
+        let response = self
+            .client
+            .get(&self.config.url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("failed to fetch {}: {err}", self.config.url))?;
+        let body = response.text().await.map_err(|err| err.to_string())?;
+        let catalog: Value = serde_json::from_str(&body)
+            .ok()
+            .filter(is_catalog)
+            .ok_or_else(|| format!("{} did not return a models catalog", self.config.url))?;
+        if let Ok(mut guard) = self.catalog.write() {
+            *guard = catalog;
+        }
+        if let Ok(mut guard) = self.fetched_at.write() {
+            *guard = Some(SystemTime::now());
+        }
+        if let Some(path) = self.config.cache_path.as_deref() {
+            write_cache(path, &body)
+                .map_err(|err| format!("failed to write {}: {err}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Merge catalog metadata into the models of a `/provider` payload.
+    pub(crate) fn enrich(&self, payload: &mut Value) {
+        let Ok(catalog) = self.catalog.read() else {
+            return;
+        };
+        let Some(providers) = payload.get_mut("all").and_then(Value::as_array_mut) else {
+            return;
+        };
+        for provider in providers {
+            let provider_id = provider
+                .get("id")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string();
+            let Some(models) = provider.get_mut("models").and_then(Value::as_object_mut) else {
+                continue;
+            };
+            for (model_id, model) in models.iter_mut() {
+                if let Some(metadata) = model_metadata(&catalog, &provider_id, model_id) {
+                    merge_model(model, metadata);
+                }
+            }
+        }
+    }
+
+    /// The context window of `model_id` as listed by the agent
+    /// `provider_id`, in tokens, when the catalog knows the model.
+    pub(crate) fn context_limit(&self, provider_id: &str, model_id: &str) -> Option<u64> {
+        let catalog = self.catalog.read().ok()?;
+        model_metadata(&catalog, provider_id, model_id)?
+            .pointer("/limit/context")?
+            .as_u64()
+    }
+}
+
+fn is_catalog(catalog: &Value) -> bool {
+    catalog.as_object().is_some_and(|providers| {
+        !providers.is_empty()
+            && providers
+                .values()
+                .all(|provider| provider["models"].is_object())
+    })
+}
+
+fn read_cache(path: &Path) -> Option<(Value, SystemTime)> {
+    let modified = std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()?;
+    let catalog = serde_json::from_slice(&std::fs::read(path).ok()?)
+        .ok()
+        .filter(is_catalog)?;
+    Some((catalog, modified))
+}
+
+fn write_cache(path: &Path, body: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let partial = path.with_extension("json.partial");
+    std::fs::write(&partial, body)?;
+    std::fs::rename(&partial, path)
+}
+
+/// The catalog provider an agent's models come from.
+fn vendor(provider_id: &str) -> Option<&'static str> {
+    match provider_id {
+        "claude" => Some("anthropic"),
+        "codex" => Some("openai"),
+        _ => None,
+    }
+}
+
+/// Catalog metadata for `model_id` as listed by the agent `provider_id`:
+/// a `provider/model` ID is looked up directly, then an exact model ID is
+/// searched for (the agent's vendor first), then an alias such as `sonnet`
+/// resolves to the vendor's newest model with that word in its ID.
+fn model_metadata<'a>(catalog: &'a Value, provider_id: &str, model_id: &str) -> Option<&'a Value> {
+    if let Some((catalog_provider, catalog_model)) = model_id.split_once('/') {
+        return catalog
+            .get(catalog_provider)?
+            .get("models")?
+            .get(catalog_model);
+    }
+    let vendor = vendor(provider_id);
+    let preferred = vendor
+        .into_iter()
+        .chain(FIRST_PARTY_PROVIDERS.iter().copied());
+    let others = catalog
+        .as_object()
+        .into_iter()
+        .flat_map(|providers| providers.keys().map(String::as_str));
+    if let Some(metadata) = preferred
+        .chain(others)
+        .find_map(|provider| catalog.get(provider)?.get("models")?.get(model_id))
+    {
+        return Some(metadata);
+    }
+    catalog
+        .get(vendor?)?
+        .get("models")?
+        .as_object()?
+        .iter()
+        .filter(|(id, _)| id.split('-').any(|segment| segment == model_id))
+        .max_by(|(_, a), (_, b)| {
+            a["release_date"]
+                .as_str()
+                .unwrap_or_default()
+                .cmp(b["release_date"].as_str().unwrap_or_default())
+        })
+        .map(|(_, metadata)| metadata)
+}
+
+fn merge_model(model: &mut Value, metadata: &Value) {
+    let Some(model) = model.as_object_mut() else {
+        return;
+    };
+    for field in MERGED_FIELDS {
+        if let Some(value) = metadata.get(*field) {
+            model.insert((*field).to_string(), value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn bundled() -> Value {
+        serde_json::from_str(BUNDLED_CATALOG).expect("bundled catalog parses")
+    }
+
+    #[test]
+    fn resolves_exact_ids_prefixed_ids_and_aliases() {
+        let catalog = bundled();
+        assert!(is_catalog(&catalog));
+        let find = |provider: &str, model: &str| {
+            model_metadata(&catalog, provider, model)
+                .and_then(|metadata| metadata["id"].as_str())
+                .map(str::to_string)
+        };
+        assert_eq!(find("codex", "gpt-5.2").as_deref(), Some("gpt-5.2"));
+        assert_eq!(
+            find("opencode", "anthropic/claude-sonnet-4-20250514").as_deref(),
+            Some("claude-sonnet-4-20250514")
+        );
+        assert_eq!(
+            find("claude", "sonnet").as_deref(),
+            Some("claude-sonnet-4-5-20250929")
+        );
+        assert_eq!(find("claude", "default"), None);
+        assert_eq!(find("amp", "smart"), None);
+    }
+
+    #[test]
+    fn enrich_keeps_agent_names() {
+        let catalog = ModelCatalog::new(ModelCatalogConfig {
+            refresh_interval: None,
+            ..ModelCatalogConfig::default()
+        });
+        let mut payload = json!({
+            "all": [{
+                "id": "claude",
+                "models": {
+                    "opus": {"id": "opus", "name": "Opus", "limit": {"context": 1, "output": 1}},
+                    "default": {"id": "default", "name": "Default"}
+                }
+            }]
+        });
+        catalog.enrich(&mut payload);
+        let opus = &payload["all"][0]["models"]["opus"];
+        assert_eq!(opus["name"], "Opus");
+        assert_eq!(opus["cost"]["input"], 15);
+        assert_eq!(opus["limit"]["context"], 200_000);
+        assert_eq!(
+            payload["all"][0]["models"]["default"],
+            json!({"id": "default", "name": "Default"})
+        );
+        assert_eq!(catalog.next_refresh_in(), Duration::MAX);
+    }
+}
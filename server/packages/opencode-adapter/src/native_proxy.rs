@@ -0,0 +1,161 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+/// Header set on responses answered locally because the native sidecar
+/// failed, so clients can tell them from native data.
+pub(crate) const FALLBACK_HEADER: &str = "x-sandboxagent-fallback";
+
+/// Attempts for an idempotent GET before falling back.
+pub(crate) const GET_ATTEMPTS: u32 = 3;
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(50);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// Consecutive failed requests that open the circuit.
+const CIRCUIT_FAILURE_THRESHOLD: u32 = 5;
+/// How long an open circuit skips the sidecar before letting a probe through.
+const CIRCUIT_OPEN_FOR: Duration = Duration::from_secs(30);
+
+tokio::task_local! {
+    /// Set while a request is handled; raised when its response is a local
+    /// fallback for a failed sidecar request.
+    pub(crate) static NATIVE_FALLBACK: Arc<AtomicBool>;
+}
+
+/// Record that the current request fell back to a local response.
+pub(crate) fn mark_fallback() {
+    let _ = NATIVE_FALLBACK.try_with(|fallback| fallback.store(true, Ordering::Relaxed));
+}
+
+/// The delay before retry `attempt` (1-based): exponential from 50ms, capped
+/// at 1s, with the upper half jittered so retries from concurrent requests
+/// spread out.
+pub(crate) fn retry_delay(attempt: u32) -> Duration {
+    let full = RETRY_BASE_DELAY
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(RETRY_MAX_DELAY);
+    let mut bytes = [0u8; 2];
+    let jitter = match getrandom::getrandom(&mut bytes) {
+        Ok(()) => f64::from(u16::from_le_bytes(bytes)) / f64::from(u16::MAX),
+        Err(_) => 0.5,
+    };
+    full.mul_f64(0.5 + jitter / 2.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CircuitState {
+    /// Requests go to the sidecar.
+    Closed,
+    /// The sidecar failed repeatedly; requests fall back without trying it.
+    Open,
+    /// The open period is over; the next request probes the sidecar.
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Open => "open",
+            Self::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Circuit breaker for the native sidecar. It opens after consecutive failed
+/// requests and stays open for a while; a success closes it again.
+#[derive(Default)]
+pub(crate) struct NativeCircuit {
+    breaker: Mutex<Breaker>,
+}
+
+impl NativeCircuit {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Breaker> {
+        self.breaker.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub(crate) fn state(&self) -> CircuitState {
+        self.state_at(Instant::now())
+    }
+
+    fn state_at(&self, now: Instant) -> CircuitState {
+        match self.lock().open_until {
+            None => CircuitState::Closed,
+            Some(until) if now < until => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Whether requests should skip the sidecar.
+    pub(crate) fn is_open(&self) -> bool {
+        self.state() == CircuitState::Open
+    }
+
+    pub(crate) fn record_success(&self) {
+        *self.lock() = Breaker::default();
+    }
+
+    pub(crate) fn record_failure(&self) {
+        self.record_failure_at(Instant::now());
+    }
+
+    fn record_failure_at(&self, now: Instant) {
+        let mut breaker = self.lock();
+        breaker.failures = breaker.failures.saturating_add(1);
+        // A failed probe reopens a half-open circuit right away.
+        if breaker.failures >= CIRCUIT_FAILURE_THRESHOLD || breaker.open_until.is_some() {
+            breaker.open_until = Some(now + CIRCUIT_OPEN_FOR);
+        }
+    }
+
+    /// The breaker for `/global/health`: `{ state, failures }`.
+    pub(crate) fn to_value(&self) -> Value {
+        let state = self.state();
+        json!({"state": state.as_str(), "failures": self.lock().failures})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circuit_opens_after_repeated_failures_and_closes_on_success() {
+        let circuit = NativeCircuit::default();
+        let now = Instant::now();
+        for _ in 0..CIRCUIT_FAILURE_THRESHOLD - 1 {
+            circuit.record_failure_at(now);
+        }
+        assert_eq!(circuit.state_at(now), CircuitState::Closed);
+        circuit.record_failure_at(now);
+        assert_eq!(circuit.state_at(now), CircuitState::Open);
+
+        let later = now + CIRCUIT_OPEN_FOR;
+        assert_eq!(circuit.state_at(later), CircuitState::HalfOpen);
+        circuit.record_failure_at(later);
+        assert_eq!(circuit.state_at(later), CircuitState::Open);
+
+        circuit.record_success();
+        assert_eq!(circuit.state(), CircuitState::Closed);
+        assert_eq!(circuit.to_value()["failures"], 0);
+    }
+
+    #[test]
+    fn retry_delays_grow_with_jitter_and_are_capped() {
+        for attempt in 1..=8 {
+            let full = RETRY_BASE_DELAY
+                .saturating_mul(1 << (attempt - 1))
+                .min(RETRY_MAX_DELAY);
+            let delay = retry_delay(attempt);
+            assert!(delay >= full / 2 && delay <= full, "{attempt}: {delay:?}");
+        }
+    }
+}
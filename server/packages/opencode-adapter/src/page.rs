@@ -0,0 +1,112 @@
+use base64::Engine;
+use serde::Deserialize;
+
+pub(crate) const DEFAULT_PAGE_LIMIT: usize = 100;
+pub(crate) const MAX_PAGE_LIMIT: usize = 1000;
+
+/// `?limit=` and `?cursor=` on a listing. A listing without either returns
+/// every item, as OpenCode clients expect.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct PageQuery {
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+impl PageQuery {
+    pub(crate) fn is_paged(&self) -> bool {
+        self.limit.is_some() || self.cursor.is_some()
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT)
+            .clamp(1, MAX_PAGE_LIMIT)
+    }
+
+    /// The position to list after, decoded from `cursor`.
+    pub(crate) fn after(&self) -> Result<Option<PageCursor>, String> {
+        self.cursor
+            .as_deref()
+            .map(|cursor| PageCursor::decode(cursor).ok_or_else(|| "invalid cursor".to_string()))
+            .transpose()
+    }
+}
+
+/// A position in a listing ordered by `created_at`, then `id`. Clients treat
+/// the encoded form as opaque.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct PageCursor {
+    pub created_at: i64,
+    pub id: String,
+}
+
+impl PageCursor {
+    pub(crate) fn encode(&self) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(format!("{}:{}", self.created_at, self.id))
+    }
+
+    pub(crate) fn decode(cursor: &str) -> Option<Self> {
+        let decoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (created_at, id) = decoded.split_once(':')?;
+        Some(Self {
+            created_at: created_at.parse().ok()?,
+            id: id.to_string(),
+        })
+    }
+}
+
+/// `Link` header value pointing at the page after `next`, keeping the
+/// request's other query parameters.
+pub(crate) fn next_link(path: &str, raw_query: Option<&str>, next: &PageCursor) -> String {
+    let mut params: Vec<&str> = raw_query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| !param.is_empty() && !param.starts_with("cursor="))
+        .collect();
+    let cursor = format!("cursor={}", next.encode());
+    params.push(&cursor);
+    format!("<{path}?{}>; rel=\"next\"", params.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = PageCursor {
+            created_at: 1_700_000_000_000,
+            id: "ses_1:fork".to_string(),
+        };
+        assert_eq!(PageCursor::decode(&cursor.encode()), Some(cursor));
+        assert_eq!(PageCursor::decode("not a cursor"), None);
+        let query = PageQuery {
+            limit: None,
+            cursor: Some("bm9wZQ".to_string()),
+        };
+        assert!(query.after().is_err());
+    }
+
+    #[test]
+    fn next_link_replaces_the_cursor() {
+        let next = PageCursor {
+            created_at: 5,
+            id: "ses_2".to_string(),
+        };
+        assert_eq!(
+            next_link(
+                "/opencode/session",
+                Some("limit=2&cursor=old&directory=/repo"),
+                &next
+            ),
+            format!(
+                "</opencode/session?limit=2&directory=/repo&cursor={}>; rel=\"next\"",
+                next.encode()
+            )
+        );
+    }
+}
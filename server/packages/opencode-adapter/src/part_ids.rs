@@ -0,0 +1,17 @@
+//! Part IDs derived from the message a part belongs to, so the IDs emitted
+//! in `message.part.updated`, persisted in envelopes and served by
+//! `GET /session/:id/message` agree, and translating the same ACP updates
+//! again (e.g. after a restart) updates parts instead of adding new ones.
+
+/// Part `index` of `message_id`. Parts built whole are numbered by position;
+/// streamed text and reasoning parts and retries by the ACP stream sequence
+/// number of the update that opened them.
+pub(crate) fn part_id(message_id: &str, index: u64) -> String {
+    format!("part_{message_id}_{index}")
+}
+
+/// The tool part of ACP tool call `call_id` in `message_id`, shared by its
+/// `tool_call` and every `tool_call_update`.
+pub(crate) fn tool_part_id(message_id: &str, call_id: &str) -> String {
+    format!("part_{message_id}_tc_{call_id}")
+}
@@ -0,0 +1,83 @@
+use serde_json::{json, Value};
+
+/// A tool output cut down to fit a part's size cap.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct TruncatedOutput {
+    /// The head of the output followed by the truncation marker; never
+    /// longer than the cap unless the marker alone is.
+    pub output: String,
+    /// Size of the untruncated output.
+    pub bytes: usize,
+}
+
+impl TruncatedOutput {
+    /// `state.metadata.truncated` on the capped part.
+    pub(crate) fn metadata(&self, part_id: &str, limit: usize) -> Value {
+        json!({
+            "bytes": self.bytes,
+            "limit": limit,
+            "full": format!("/part/{part_id}/full"),
+        })
+    }
+}
+
+/// Cut `output` to at most `max_bytes`, marker included, on a character
+/// boundary. `None` when it already fits or the cap is `0` (disabled).
+pub(crate) fn truncate_output(
+    output: &str,
+    part_id: &str,
+    max_bytes: usize,
+) -> Option<TruncatedOutput> {
+    if max_bytes == 0 || output.len() <= max_bytes {
+        return None;
+    }
+    let marker = |omitted: usize| {
+        format!(
+            "\n… [truncated {omitted} of {} bytes; full output at /part/{part_id}/full]",
+            output.len()
+        )
+    };
+    // The omitted count is part of the marker, so size it for the worst case.
+    let mut keep = max_bytes.saturating_sub(marker(output.len()).len());
+    while !output.is_char_boundary(keep) {
+        keep -= 1;
+    }
+    Some(TruncatedOutput {
+        output: format!("{}{}", &output[..keep], marker(output.len() - keep)),
+        bytes: output.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outputs_within_the_cap_are_kept() {
+        assert_eq!(truncate_output("short", "part_1", 5), None);
+        assert_eq!(truncate_output(&"x".repeat(1_000), "part_1", 0), None);
+    }
+
+    #[test]
+    fn long_outputs_fit_the_cap_with_a_marker() {
+        let output = "x".repeat(10_000);
+        let truncated = truncate_output(&output, "part_1", 200).expect("truncated");
+        assert!(truncated.output.len() <= 200);
+        assert!(truncated.output.starts_with("xxx"));
+        assert!(truncated
+            .output
+            .ends_with("of 10000 bytes; full output at /part/part_1/full]"));
+        assert_eq!(truncated.bytes, 10_000);
+        // Capping again is a no-op.
+        assert_eq!(truncate_output(&truncated.output, "part_1", 200), None);
+    }
+
+    #[test]
+    fn truncation_keeps_whole_characters() {
+        let output = "é".repeat(1_000);
+        let truncated = truncate_output(&output, "part_1", 151).expect("truncated");
+        let head = truncated.output.split('\n').next().unwrap();
+        assert!(head.chars().all(|c| c == 'é'));
+        assert!(truncated.output.len() <= 151);
+    }
+}
@@ -0,0 +1,166 @@
+use std::path::PathBuf;
+
+/// A session directory in the host's native form, so the same directory
+/// sent by differently configured clients maps to one session `directory`.
+/// On Windows, `C:/work`, `c:\work\` and MSYS-style `/c/work` all become
+/// `C:\work`.
+pub(crate) fn normalize_directory(directory: &str) -> String {
+    normalize_for(directory, cfg!(windows))
+}
+
+/// The user's home directory: `HOME`, else `USERPROFILE` (Windows), else
+/// the filesystem root.
+pub(crate) fn home_dir() -> String {
+    home_dir_for(|key| std::env::var(key).ok(), cfg!(windows))
+}
+
+/// The system temporary directory (`/tmp`, or `%TEMP%` on Windows).
+pub(crate) fn temp_dir() -> String {
+    std::env::temp_dir().to_string_lossy().into_owned()
+}
+
+/// `file_name` inside `directory`, joined with the host's separator.
+pub(crate) fn join(directory: &str, file_name: &str) -> String {
+    PathBuf::from(directory)
+        .join(file_name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn home_dir_for(var: impl Fn(&str) -> Option<String>, windows: bool) -> String {
+    let var = |key: &str| var(key).filter(|value| !value.is_empty());
+    if let Some(home) = var("HOME").or_else(|| var("USERPROFILE")) {
+        return home;
+    }
+    if windows {
+        format!("{}\\", var("SystemDrive").as_deref().unwrap_or("C:"))
+    } else {
+        "/".to_string()
+    }
+}
+
+fn normalize_for(directory: &str, windows: bool) -> String {
+    let directory = directory.trim();
+    if directory.is_empty() {
+        return String::new();
+    }
+    if !windows {
+        return trim_separators(directory, '/', 0);
+    }
+
+    // Paths from `canonicalize` carry the verbatim prefix.
+    let directory = match directory.strip_prefix(r"\\?\") {
+        Some(rest) => match rest.strip_prefix(r"UNC\") {
+            Some(share) => format!(r"\\{share}"),
+            None => rest.to_string(),
+        },
+        None => directory.to_string(),
+    };
+    let mut path = directory.replace('/', "\\");
+    // MSYS and Git Bash spell `C:\work` as `/c/work`.
+    let bytes = path.as_bytes();
+    if bytes.len() >= 2
+        && bytes[0] == b'\\'
+        && bytes[1].is_ascii_alphabetic()
+        && (bytes.len() == 2 || bytes[2] == b'\\')
+    {
+        path = format!("{}:{}", &path[1..2], &path[2..]);
+    }
+    let bytes = path.as_bytes();
+    if bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+        path = format!("{}{}", path[..1].to_ascii_uppercase(), &path[1..]);
+        if path.len() == 2 {
+            path.push('\\');
+        }
+        // Keep the separator of a drive root such as `C:\`.
+        return trim_separators(&path, '\\', 3);
+    }
+    // Keep the leading `\\` of a UNC path.
+    let prefix = if path.starts_with(r"\\") { 2 } else { 0 };
+    format!(
+        "{}{}",
+        &path[..prefix],
+        trim_separators(&path[prefix..], '\\', 0)
+    )
+}
+
+/// Collapse repeated separators and drop a trailing one, keeping the first
+/// `keep` bytes and a lone root separator as they are.
+fn trim_separators(path: &str, separator: char, keep: usize) -> String {
+    let keep = keep.min(path.len());
+    let mut normalized = path[..keep].to_string();
+    for ch in path[keep..].chars() {
+        if ch == separator && normalized.ends_with(separator) {
+            continue;
+        }
+        normalized.push(ch);
+    }
+    while normalized.len() > keep.max(1) && normalized.ends_with(separator) {
+        normalized.pop();
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn posix_directories_lose_repeated_and_trailing_slashes() {
+        assert_eq!(normalize_for("/work//repo/", false), "/work/repo");
+        assert_eq!(normalize_for("/", false), "/");
+        assert_eq!(normalize_for("C:/work", false), "C:/work");
+    }
+
+    #[test]
+    fn home_falls_back_to_the_windows_profile_and_root() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |key: &str| {
+                vars.iter()
+                    .find(|(name, _)| *name == key)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert_eq!(
+            home_dir_for(
+                env(&[("HOME", "/home/dev"), ("USERPROFILE", r"C:\Users\dev")]),
+                true
+            ),
+            "/home/dev"
+        );
+        assert_eq!(
+            home_dir_for(env(&[("HOME", ""), ("USERPROFILE", r"C:\Users\dev")]), true),
+            r"C:\Users\dev"
+        );
+        assert_eq!(home_dir_for(env(&[("SystemDrive", "D:")]), true), r"D:\");
+        assert_eq!(home_dir_for(env(&[]), true), r"C:\");
+        assert_eq!(home_dir_for(env(&[]), false), "/");
+    }
+
+    #[test]
+    fn windows_directories_take_the_native_form() {
+        for directory in [
+            "C:/work/repo",
+            r"c:\work\repo\",
+            "/c/work/repo",
+            r"\\?\C:\work\repo",
+            r"C:\work\\repo",
+        ] {
+            assert_eq!(
+                normalize_for(directory, true),
+                r"C:\work\repo",
+                "{directory}"
+            );
+        }
+        assert_eq!(normalize_for("c:", true), r"C:\");
+        assert_eq!(normalize_for("C:/", true), r"C:\");
+        assert_eq!(
+            normalize_for(r"\\?\UNC\server\share\repo\", true),
+            r"\\server\share\repo"
+        );
+        assert_eq!(
+            normalize_for("//server/share/repo", true),
+            r"\\server\share\repo"
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+/// What makes permission requests similar enough to answer together: the
+/// same session, permission and tool, about the same directory or command.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct BatchKey {
+    pub session_id: String,
+    pub permission: String,
+    pub tool: Option<String>,
+    pub scope: BatchScope,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum BatchScope {
+    /// Files in this directory.
+    Directory(String),
+    /// Commands matching this `always` pattern (`git status *`).
+    Command(String),
+}
+
+impl BatchKey {
+    /// The key of a scoped permission request, or `None` when it is not
+    /// about a file or a command.
+    pub(crate) fn of(request: &Value) -> Option<Self> {
+        let scope = if let Some(path) = request
+            .pointer("/metadata/filepath")
+            .and_then(Value::as_str)
+        {
+            let (directory, _) = path.rsplit_once('/')?;
+            BatchScope::Directory(directory.to_string())
+        } else if request.pointer("/metadata/rawInput/command").is_some() {
+            let always = request["always"].as_array()?;
+            let [pattern] = always.as_slice() else {
+                return None;
+            };
+            BatchScope::Command(pattern.as_str()?.to_string())
+        } else {
+            return None;
+        };
+        Some(Self {
+            session_id: request["sessionID"].as_str()?.to_string(),
+            permission: request["permission"].as_str()?.to_string(),
+            // Tool titles often name the file, so the ACP tool kind is
+            // preferred.
+            tool: request
+                .pointer("/metadata/toolKind")
+                .or_else(|| request.pointer("/metadata/tool"))
+                .and_then(Value::as_str)
+                .map(ToOwned::to_owned),
+            scope,
+        })
+    }
+
+    /// The pattern an aggregated request asks for, and what "always"
+    /// approves: every file in the directory, or the command prefix.
+    pub(crate) fn pattern(&self) -> String {
+        match &self.scope {
+            BatchScope::Directory(directory) => format!("{directory}/*"),
+            BatchScope::Command(pattern) => pattern.clone(),
+        }
+    }
+}
+
+/// How a new permission request was admitted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Admission {
+    /// Nothing similar was asked recently; ask it on its own.
+    Alone,
+    /// It joined the aggregated request `batch_id`, which now stands for
+    /// `members`. `formed` is set when this request started the batch.
+    Batched {
+        batch_id: String,
+        members: Vec<String>,
+        formed: bool,
+    },
+}
+
+impl Admission {
+    /// The batch's ID, its members and whether this request formed it.
+    pub(crate) fn into_batch(self) -> Option<(String, Vec<String>, bool)> {
+        match self {
+            Self::Alone => None,
+            Self::Batched {
+                batch_id,
+                members,
+                formed,
+            } => Some((batch_id, members, formed)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Recent {
+    request_id: String,
+    batch_id: Option<String>,
+    at: i64,
+}
+
+/// Bursts of similar permission requests, folded into aggregated requests
+/// that answer all of their members at once.
+#[derive(Debug, Default)]
+pub(crate) struct PermissionBatches {
+    /// The latest request, or the open batch, for each key.
+    recent: HashMap<BatchKey, Recent>,
+    /// Unanswered members of each batch, in the order they were asked.
+    members: HashMap<String, Vec<String>>,
+}
+
+impl PermissionBatches {
+    /// Admit `request_id` at `now` (epoch milliseconds). It joins a batch
+    /// when a similar request is still unanswered and the last one came in
+    /// within `window_ms`; `new_id` names a batch when one is formed.
+    pub(crate) fn admit(
+        &mut self,
+        key: BatchKey,
+        request_id: &str,
+        now: i64,
+        window_ms: i64,
+        new_id: impl FnOnce() -> String,
+    ) -> Admission {
+        let members = &self.members;
+        self.recent.retain(|_, recent| {
+            now - recent.at <= window_ms
+                || recent
+                    .batch_id
+                    .as_ref()
+                    .is_some_and(|batch_id| members.contains_key(batch_id))
+        });
+        let joinable = self.recent.get_mut(&key).filter(|recent| {
+            now - recent.at <= window_ms
+                && recent
+                    .batch_id
+                    .as_ref()
+                    .is_none_or(|batch_id| self.members.contains_key(batch_id))
+        });
+        let Some(recent) = joinable else {
+            self.recent.insert(
+                key,
+                Recent {
+                    request_id: request_id.to_string(),
+                    batch_id: None,
+                    at: now,
+                },
+            );
+            return Admission::Alone;
+        };
+        recent.at = now;
+        let (batch_id, formed) = match &recent.batch_id {
+            Some(batch_id) => (batch_id.clone(), false),
+            None => {
+                let batch_id = new_id();
+                recent.batch_id = Some(batch_id.clone());
+                self.members
+                    .insert(batch_id.clone(), vec![recent.request_id.clone()]);
+                (batch_id, true)
+            }
+        };
+        let members = self.members.entry(batch_id.clone()).or_default();
+        members.push(request_id.to_string());
+        Admission::Batched {
+            batch_id,
+            members: members.clone(),
+            formed,
+        }
+    }
+
+    /// Close batch `batch_id` and return its unanswered members, or `None`
+    /// when it is not an open batch.
+    pub(crate) fn take(&mut self, batch_id: &str) -> Option<Vec<String>> {
+        let members = self.members.remove(batch_id)?;
+        self.recent
+            .retain(|_, recent| recent.batch_id.as_deref() != Some(batch_id));
+        Some(members)
+    }
+
+    /// Forget `request_id` once it is answered or withdrawn on its own.
+    /// Returns the batch it belonged to when that batch has no unanswered
+    /// members left.
+    pub(crate) fn release(&mut self, request_id: &str) -> Option<String> {
+        self.recent
+            .retain(|_, recent| recent.batch_id.is_some() || recent.request_id != request_id);
+        let batch_id = self
+            .members
+            .iter()
+            .find(|(_, members)| members.iter().any(|member| member == request_id))
+            .map(|(batch_id, _)| batch_id.clone())?;
+        let members = self.members.get_mut(&batch_id)?;
+        members.retain(|member| member != request_id);
+        if !members.is_empty() {
+            return None;
+        }
+        self.take(&batch_id);
+        Some(batch_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const WINDOW: i64 = 2_000;
+
+    fn edit(path: &str) -> BatchKey {
+        BatchKey::of(&json!({
+            "sessionID": "ses_1",
+            "permission": "edit",
+            "metadata": {"tool": "write", "filepath": path},
+            "always": [path],
+        }))
+        .unwrap()
+    }
+
+    fn batched(admission: Admission) -> (String, Vec<String>, bool) {
+        admission.into_batch().expect("expected a batch")
+    }
+
+    #[test]
+    fn keys_group_files_by_directory_and_commands_by_prefix() {
+        assert_eq!(edit("/work/src/a.rs"), edit("/work/src/b.rs"));
+        assert_ne!(edit("/work/src/a.rs"), edit("/work/tests/a.rs"));
+        assert_eq!(edit("/work/src/a.rs").pattern(), "/work/src/*");
+
+        let command = BatchKey::of(&json!({
+            "sessionID": "ses_1",
+            "permission": "execute",
+            "metadata": {"rawInput": {"command": "git status -s"}},
+            "always": ["git status *"],
+        }))
+        .unwrap();
+        assert_eq!(command.pattern(), "git status *");
+        assert!(BatchKey::of(&json!({
+            "sessionID": "ses_1",
+            "permission": "execute",
+            "metadata": {},
+        }))
+        .is_none());
+    }
+
+    #[test]
+    fn a_burst_forms_one_batch() {
+        let mut batches = PermissionBatches::default();
+        let new_id = || "perm_batch_1".to_string();
+        assert_eq!(
+            batches.admit(edit("/w/a.rs"), "perm_1", 0, WINDOW, new_id),
+            Admission::Alone
+        );
+        let (batch_id, members, formed) =
+            batched(batches.admit(edit("/w/b.rs"), "perm_2", 500, WINDOW, new_id));
+        assert_eq!(batch_id, "perm_batch_1");
+        assert_eq!(members, vec!["perm_1", "perm_2"]);
+        assert!(formed);
+        let (_, members, formed) =
+            batched(batches.admit(edit("/w/c.rs"), "perm_3", 2_400, WINDOW, || unreachable!()));
+        assert_eq!(members, vec!["perm_1", "perm_2", "perm_3"]);
+        assert!(!formed);
+
+        assert_eq!(
+            batches.take("perm_batch_1"),
+            Some(vec![
+                "perm_1".to_string(),
+                "perm_2".to_string(),
+                "perm_3".to_string()
+            ])
+        );
+        assert_eq!(
+            batches.admit(edit("/w/d.rs"), "perm_4", 2_500, WINDOW, || unreachable!()),
+            Admission::Alone
+        );
+    }
+
+    #[test]
+    fn requests_outside_the_window_or_already_answered_are_not_batched() {
+        let mut batches = PermissionBatches::default();
+        batches.admit(edit("/w/a.rs"), "perm_1", 0, WINDOW, || unreachable!());
+        assert_eq!(
+            batches.admit(edit("/w/b.rs"), "perm_2", 5_000, WINDOW, || unreachable!()),
+            Admission::Alone
+        );
+        assert_eq!(batches.release("perm_2"), None);
+        assert_eq!(
+            batches.admit(edit("/w/c.rs"), "perm_3", 5_100, WINDOW, || unreachable!()),
+            Admission::Alone
+        );
+    }
+
+    #[test]
+    fn answering_every_member_closes_the_batch() {
+        let mut batches = PermissionBatches::default();
+        batches.admit(edit("/w/a.rs"), "perm_1", 0, WINDOW, || unreachable!());
+        batches.admit(edit("/w/b.rs"), "perm_2", 10, WINDOW, || {
+            "perm_batch_1".to_string()
+        });
+        assert_eq!(batches.release("perm_1"), None);
+        assert_eq!(batches.release("perm_2"), Some("perm_batch_1".to_string()));
+        assert_eq!(batches.take("perm_batch_1"), None);
+    }
+}
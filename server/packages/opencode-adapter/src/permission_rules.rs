@@ -0,0 +1,248 @@
+use serde_json::{json, Value};
+
+/// What an "always" reply approves for the rest of a session: requests for
+/// the same permission and tool whose patterns all match `pattern`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PermissionRule {
+    pub permission: String,
+    /// `None` for requests that did not name a tool.
+    pub tool: Option<String>,
+    /// Glob where `*` matches any run of characters.
+    pub pattern: String,
+}
+
+/// Fill in what a permission request is about and what "always" would
+/// approve. Requests without explicit patterns get the command or file path
+/// from their tool input; `always` widens a command to its subcommand
+/// (`git status -s` becomes `git status *`).
+pub(crate) fn scope_request(request: &mut Value) {
+    let subject = request
+        .pointer("/metadata/rawInput/command")
+        .and_then(Value::as_str)
+        .map(|command| (command.trim().to_string(), command_prefix(command)))
+        .or_else(|| {
+            let path = request
+                .pointer("/metadata/filepath")
+                .and_then(Value::as_str)?;
+            Some((path.to_string(), path.to_string()))
+        });
+    let explicit = patterns(request)
+        .into_iter()
+        .filter(|pattern| pattern != "*")
+        .collect::<Vec<_>>();
+    let (patterns, always) = match (explicit.is_empty(), subject) {
+        (false, _) => (explicit.clone(), explicit),
+        (true, Some((subject, always))) if !subject.is_empty() => (vec![subject], vec![always]),
+        _ => (vec!["*".to_string()], vec!["*".to_string()]),
+    };
+    request["patterns"] = json!(patterns);
+    if request["always"]
+        .as_array()
+        .is_none_or(|always| always.is_empty())
+    {
+        request["always"] = json!(always);
+    }
+}
+
+/// The rules an "always" reply to `request` adds.
+pub(crate) fn rules_for(request: &Value) -> Vec<PermissionRule> {
+    let permission = permission(request);
+    let tool = tool(request);
+    let mut always = string_list(&request["always"]);
+    if always.is_empty() {
+        always = patterns(request);
+    }
+    always
+        .into_iter()
+        .map(|pattern| PermissionRule {
+            permission: permission.clone(),
+            tool: tool.clone(),
+            pattern,
+        })
+        .collect()
+}
+
+/// Whether `rules` already approve every pattern of `request`.
+pub(crate) fn allows(rules: &[PermissionRule], request: &Value) -> bool {
+    let permission = permission(request);
+    let tool = tool(request);
+    let applicable = rules
+        .iter()
+        .filter(|rule| rule.permission == permission && (rule.tool.is_none() || rule.tool == tool))
+        .collect::<Vec<_>>();
+    !applicable.is_empty()
+        && patterns(request).iter().all(|pattern| {
+            applicable
+                .iter()
+                .any(|rule| glob_match(&rule.pattern, pattern))
+        })
+}
+
+/// Whether `request` may change the sandbox. Only tools known to be
+/// read-only (by ACP tool kind, or by permission when the kind is unknown)
+/// are not; anything else counts as a write, edit or command.
+pub(crate) fn is_side_effecting(request: &Value) -> bool {
+    let kind = request
+        .pointer("/metadata/toolKind")
+        .and_then(Value::as_str);
+    match kind {
+        Some("read" | "search" | "think" | "fetch") => false,
+        Some("edit" | "delete" | "move" | "execute") => true,
+        _ => !matches!(
+            permission(request).as_str(),
+            "read" | "list" | "glob" | "grep" | "webfetch" | "websearch"
+        ),
+    }
+}
+
+fn permission(request: &Value) -> String {
+    request["permission"]
+        .as_str()
+        .unwrap_or("execute")
+        .to_string()
+}
+
+fn tool(request: &Value) -> Option<String> {
+    request
+        .pointer("/metadata/tool")
+        .and_then(Value::as_str)
+        .map(str::to_ascii_lowercase)
+}
+
+fn patterns(request: &Value) -> Vec<String> {
+    let patterns = string_list(&request["patterns"]);
+    if patterns.is_empty() {
+        vec!["*".to_string()]
+    } else {
+        patterns
+    }
+}
+
+fn string_list(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(str::to_string)
+        .collect()
+}
+
+/// `git status -s` → `git status *`, `ls -la` → `ls *`: the program and, when
+/// it looks like one, its subcommand.
+fn command_prefix(command: &str) -> String {
+    let mut words = command.split_whitespace();
+    let Some(program) = words.next() else {
+        return "*".to_string();
+    };
+    match words.next() {
+        Some(sub)
+            if sub
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_')
+                && !sub.starts_with('-') =>
+        {
+            format!("{program} {sub} *")
+        }
+        _ => format!("{program} *"),
+    }
+}
+
+/// Match `text` against `pattern`, where `*` matches any run of characters
+/// and a trailing ` *` also matches nothing (`git status *` matches
+/// `git status`).
+fn glob_match(pattern: &str, text: &str) -> bool {
+    if let Some(prefix) = pattern.strip_suffix(" *") {
+        if text == prefix {
+            return true;
+        }
+    }
+    let mut segments = pattern.split('*');
+    let first = segments.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let segments = segments.collect::<Vec<_>>();
+    let Some((last, middle)) = segments.split_last() else {
+        return rest.is_empty();
+    };
+    for segment in middle {
+        match rest.find(segment) {
+            Some(index) => rest = &rest[index + segment.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bash(command: &str) -> Value {
+        let mut request = json!({
+            "permission": "execute",
+            "patterns": ["*"],
+            "metadata": {"tool": "Bash", "rawInput": {"command": command}},
+            "always": [],
+        });
+        scope_request(&mut request);
+        request
+    }
+
+    #[test]
+    fn scopes_commands_and_paths() {
+        let request = bash("git status -s");
+        assert_eq!(request["patterns"], json!(["git status -s"]));
+        assert_eq!(request["always"], json!(["git status *"]));
+        assert_eq!(bash("ls -la")["always"], json!(["ls *"]));
+
+        let mut edit = json!({
+            "permission": "edit",
+            "metadata": {"tool": "Edit", "filepath": "/repo/src/main.rs"},
+        });
+        scope_request(&mut edit);
+        assert_eq!(edit["patterns"], json!(["/repo/src/main.rs"]));
+        assert_eq!(edit["always"], json!(["/repo/src/main.rs"]));
+
+        let mut bare = json!({"permission": "execute"});
+        scope_request(&mut bare);
+        assert_eq!(bare["always"], json!(["*"]));
+    }
+
+    #[test]
+    fn always_rules_match_the_same_tool_and_pattern() {
+        let rules = rules_for(&bash("git status -s"));
+        assert!(allows(&rules, &bash("git status")));
+        assert!(allows(&rules, &bash("git status --porcelain")));
+        assert!(!allows(&rules, &bash("git push --force")));
+        assert!(!allows(&rules, &bash("rm -rf /")));
+
+        let mut read = bash("git status");
+        read["metadata"]["tool"] = json!("Read");
+        assert!(!allows(&rules, &read));
+        let mut edit = bash("git status");
+        edit["permission"] = json!("edit");
+        assert!(!allows(&rules, &edit));
+        assert!(!allows(&[], &bash("git status")));
+    }
+
+    #[test]
+    fn only_known_read_only_tools_are_free_of_side_effects() {
+        assert!(is_side_effecting(&bash("ls")));
+        let read = json!({"permission": "execute", "metadata": {"toolKind": "read"}});
+        assert!(!is_side_effecting(&read));
+        assert!(!is_side_effecting(&json!({"permission": "webfetch"})));
+        assert!(is_side_effecting(&json!({"permission": "edit"})));
+        assert!(is_side_effecting(&json!({"permission": "mcp_tool"})));
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("/repo/*.rs", "/repo/src/main.rs"));
+        assert!(!glob_match("/repo/*.rs", "/repo/README.md"));
+        assert!(glob_match("a*b*c", "a-b-c"));
+        assert!(!glob_match("abc", "abcd"));
+    }
+}
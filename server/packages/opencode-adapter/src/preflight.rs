@@ -0,0 +1,253 @@
+use base64::Engine;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Rough characters per token, used to estimate a prompt's size without the
+/// model's tokenizer.
+const CHARS_PER_TOKEN: u64 = 4;
+/// Part types the adapter can hand to an ACP agent.
+const PART_TYPES: &[&str] = &["text", "file", "agent"];
+
+/// What preflight knows about the agent and model a prompt is sent to.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PreflightProfile<'a> {
+    pub agent: &'a str,
+    /// The model's context window in tokens; `None` skips the size check.
+    pub context_tokens: Option<u64>,
+}
+
+/// Why a prompt cannot be sent to the agent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PreflightProblem {
+    /// JSON pointer into the request body.
+    pub pointer: String,
+    /// Index of the offending part; `None` for the prompt as a whole.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub part_index: Option<usize>,
+    pub reason: &'static str,
+    pub message: String,
+}
+
+impl PreflightProblem {
+    fn part(index: usize, field: &str, reason: &'static str, message: String) -> Self {
+        let pointer = if field.is_empty() {
+            format!("/parts/{index}")
+        } else {
+            format!("/parts/{index}/{field}")
+        };
+        Self {
+            pointer,
+            part_index: Some(index),
+            reason,
+            message,
+        }
+    }
+}
+
+/// Check a prompt's parts against what the agent and model can take: part
+/// types the agent has no content block for, parts with nothing in them,
+/// malformed attachments, and an estimated size over the model's context
+/// window. Attachment types are checked against the agent's capabilities
+/// when the parts are prepared.
+pub(crate) fn check(parts: &[Value], profile: PreflightProfile<'_>) -> Vec<PreflightProblem> {
+    let mut problems = Vec::new();
+    let mut chars = 0u64;
+    for (index, part) in parts.iter().enumerate() {
+        // Parts are read as text when they carry a `text` field, whatever
+        // their type.
+        if let Some(text) = part.get("text").and_then(Value::as_str) {
+            if text.trim().is_empty() {
+                problems.push(PreflightProblem::part(
+                    index,
+                    "text",
+                    "empty_text",
+                    "text part is empty".to_string(),
+                ));
+            }
+            chars += text.chars().count() as u64;
+            continue;
+        }
+        let part_type = part.get("type").and_then(Value::as_str).unwrap_or_default();
+        match part_type {
+            "file" => chars += check_file(index, part, &mut problems),
+            "agent" => {
+                let named = part
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .is_some_and(|name| !name.trim().is_empty());
+                if !named {
+                    problems.push(PreflightProblem::part(
+                        index,
+                        "name",
+                        "missing_agent_name",
+                        "agent part does not name a sub-agent".to_string(),
+                    ));
+                }
+            }
+            "" => problems.push(PreflightProblem::part(
+                index,
+                "type",
+                "missing_type",
+                "part has no type and no text".to_string(),
+            )),
+            other => problems.push(PreflightProblem::part(
+                index,
+                "type",
+                "unsupported_part_type",
+                format!(
+                    "{} does not accept {other} parts; it accepts {}",
+                    profile.agent,
+                    PART_TYPES.join(", ")
+                ),
+            )),
+        }
+    }
+    if let Some(context_tokens) = profile.context_tokens {
+        let estimated = chars.div_ceil(CHARS_PER_TOKEN);
+        if estimated > context_tokens {
+            problems.push(PreflightProblem {
+                pointer: "/parts".to_string(),
+                part_index: None,
+                reason: "context_exceeded",
+                message: format!(
+                    "prompt is an estimated {estimated} tokens, over the model's \
+                     {context_tokens}-token context window"
+                ),
+            });
+        }
+    }
+    problems
+}
+
+/// Check a `file` part and return the characters of text it adds to the
+/// prompt.
+fn check_file(index: usize, part: &Value, problems: &mut Vec<PreflightProblem>) -> u64 {
+    let url = part.get("url").and_then(Value::as_str).unwrap_or_default();
+    if url.is_empty() {
+        problems.push(PreflightProblem::part(
+            index,
+            "url",
+            "missing_url",
+            "file part has no url".to_string(),
+        ));
+        return 0;
+    }
+    let Some(data_url) = url.strip_prefix("data:") else {
+        // Linked files are read by the agent itself.
+        return 0;
+    };
+    let Some((header, payload)) = data_url.split_once(',') else {
+        problems.push(PreflightProblem::part(
+            index,
+            "url",
+            "invalid_data_url",
+            "file part url is not a valid data URL".to_string(),
+        ));
+        return 0;
+    };
+    let Some(mime) = header.strip_suffix(";base64") else {
+        // A percent-encoded data URL holds text.
+        return payload.len() as u64;
+    };
+    let Ok(data) = base64::engine::general_purpose::STANDARD.decode(payload) else {
+        problems.push(PreflightProblem::part(
+            index,
+            "url",
+            "invalid_data_url",
+            "file part url is not valid base64".to_string(),
+        ));
+        return 0;
+    };
+    if data.is_empty() {
+        problems.push(PreflightProblem::part(
+            index,
+            "url",
+            "empty_file",
+            "file part is empty".to_string(),
+        ));
+        return 0;
+    }
+    let mime = part.get("mime").and_then(Value::as_str).unwrap_or(mime);
+    // Embedded text is inlined into the prompt.
+    if mime.starts_with("text/") {
+        return String::from_utf8_lossy(&data).chars().count() as u64;
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn profile(context_tokens: Option<u64>) -> PreflightProfile<'static> {
+        PreflightProfile {
+            agent: "claude",
+            context_tokens,
+        }
+    }
+
+    fn reasons(problems: &[PreflightProblem]) -> Vec<(Option<usize>, &str)> {
+        problems
+            .iter()
+            .map(|problem| (problem.part_index, problem.reason))
+            .collect()
+    }
+
+    #[test]
+    fn accepts_well_formed_prompts() {
+        let parts = vec![
+            json!({"type": "text", "text": "look at this"}),
+            json!({"type": "file", "url": "file:///workspace/main.rs", "mime": "text/x-rust"}),
+            json!({"type": "agent", "name": "explore"}),
+        ];
+        assert!(check(&parts, profile(Some(1000))).is_empty());
+    }
+
+    #[test]
+    fn reports_each_failing_part_by_index() {
+        let parts = vec![
+            json!({"type": "text", "text": "fine"}),
+            json!({"type": "text", "text": "   "}),
+            json!({"type": "subtask", "prompt": "do it"}),
+            json!({"type": "file", "mime": "image/png"}),
+            json!({"type": "agent", "name": ""}),
+            json!({"type": "file", "url": "data:image/png;base64,!!"}),
+        ];
+        let problems = check(&parts, profile(None));
+        assert_eq!(
+            reasons(&problems),
+            vec![
+                (Some(1), "empty_text"),
+                (Some(2), "unsupported_part_type"),
+                (Some(3), "missing_url"),
+                (Some(4), "missing_agent_name"),
+                (Some(5), "invalid_data_url"),
+            ]
+        );
+        assert_eq!(problems[0].pointer, "/parts/1/text");
+        assert_eq!(
+            problems[1].message,
+            "claude does not accept subtask parts; it accepts text, file, agent"
+        );
+    }
+
+    #[test]
+    fn estimates_the_prompt_against_the_context_window() {
+        let text = "x".repeat(400);
+        let embedded = format!(
+            "data:text/plain;base64,{}",
+            base64::engine::general_purpose::STANDARD.encode("y".repeat(400))
+        );
+        let parts = vec![
+            json!({"type": "text", "text": text}),
+            json!({"type": "file", "url": embedded, "mime": "text/plain"}),
+        ];
+        assert!(check(&parts, profile(Some(200))).is_empty());
+        let problems = check(&parts, profile(Some(199)));
+        assert_eq!(reasons(&problems), vec![(None, "context_exceeded")]);
+        assert_eq!(problems[0].pointer, "/parts");
+    }
+}
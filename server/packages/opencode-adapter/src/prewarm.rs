@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use crate::archive::env_nonempty;
+
+const DEFAULT_PREWARM_WINDOW_MINS: u64 = 30;
+const DEFAULT_PREWARM_MAX_SESSIONS: usize = 4;
+
+/// Which sessions get their agent process started again when the adapter
+/// starts, so the first prompt after a restart does not pay for the launch.
+#[derive(Debug, Clone)]
+pub struct SessionPrewarmConfig {
+    /// Only sessions updated within this long before startup are pre-warmed.
+    pub window: Duration,
+    /// At most this many sessions are pre-warmed, most recent first.
+    pub max_sessions: usize,
+}
+
+impl SessionPrewarmConfig {
+    /// Build from `SANDBOX_AGENT_SESSION_PREWARM_MINS` and
+    /// `SANDBOX_AGENT_SESSION_PREWARM_MAX`. Pre-warming is on by default and
+    /// off when either is set to `0`.
+    pub fn from_env() -> Option<Self> {
+        let env_u64 = |key: &str| env_nonempty(key).and_then(|value| value.parse::<u64>().ok());
+        let window_mins =
+            env_u64("SANDBOX_AGENT_SESSION_PREWARM_MINS").unwrap_or(DEFAULT_PREWARM_WINDOW_MINS);
+        let max_sessions = env_u64("SANDBOX_AGENT_SESSION_PREWARM_MAX")
+            .map(|max| max as usize)
+            .unwrap_or(DEFAULT_PREWARM_MAX_SESSIONS);
+        (window_mins > 0 && max_sessions > 0).then(|| Self {
+            window: Duration::from_secs(window_mins * 60),
+            max_sessions,
+        })
+    }
+}
+
+/// What the startup pass knows about a session.
+#[derive(Debug, Clone)]
+pub(crate) struct PrewarmCandidate {
+    pub session_id: String,
+    /// When the session was last updated, in epoch milliseconds.
+    pub updated_at: i64,
+    /// Whether the session runs on a real agent whose process is not already
+    /// bound, and was not put to sleep by the expiry sweep.
+    pub eligible: bool,
+}
+
+/// The sessions to pre-warm at `now`: eligible, updated within the window,
+/// most recent first, capped at `max_sessions`.
+pub(crate) fn select(
+    config: &SessionPrewarmConfig,
+    mut candidates: Vec<PrewarmCandidate>,
+    now: i64,
+) -> Vec<String> {
+    let since = now.saturating_sub(config.window.as_millis() as i64);
+    candidates.retain(|candidate| candidate.eligible && candidate.updated_at >= since);
+    candidates.sort_by(|a, b| {
+        b.updated_at
+            .cmp(&a.updated_at)
+            .then_with(|| a.session_id.cmp(&b.session_id))
+    });
+    candidates
+        .into_iter()
+        .take(config.max_sessions)
+        .map(|candidate| candidate.session_id)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 10 * 60 * 60 * 1000;
+
+    fn config(max_sessions: usize) -> SessionPrewarmConfig {
+        SessionPrewarmConfig {
+            window: Duration::from_secs(30 * 60),
+            max_sessions,
+        }
+    }
+
+    fn candidate(session_id: &str, minutes_ago: i64, eligible: bool) -> PrewarmCandidate {
+        PrewarmCandidate {
+            session_id: session_id.to_string(),
+            updated_at: NOW - minutes_ago * 60 * 1000,
+            eligible,
+        }
+    }
+
+    #[test]
+    fn picks_recent_eligible_sessions_most_recent_first() {
+        let selected = select(
+            &config(4),
+            vec![
+                candidate("ses_old", 45, true),
+                candidate("ses_a", 20, true),
+                candidate("ses_b", 5, true),
+                candidate("ses_mock", 1, false),
+            ],
+            NOW,
+        );
+        assert_eq!(selected, vec!["ses_b", "ses_a"]);
+    }
+
+    #[test]
+    fn caps_the_number_of_sessions() {
+        let selected = select(
+            &config(2),
+            vec![
+                candidate("ses_a", 3, true),
+                candidate("ses_b", 2, true),
+                candidate("ses_c", 1, true),
+            ],
+            NOW,
+        );
+        assert_eq!(selected, vec!["ses_c", "ses_b"]);
+    }
+}
@@ -0,0 +1,253 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+/// How a session's agent process and turns are scheduled against other
+/// sessions': batch `background` sessions yield to `interactive` ones.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionPriority {
+    Background,
+    #[default]
+    Normal,
+    Interactive,
+}
+
+impl SessionPriority {
+    pub fn is_normal(&self) -> bool {
+        *self == Self::Normal
+    }
+
+    /// Niceness for the agent process; `None` keeps the server's own. A
+    /// negative value needs `CAP_SYS_NICE` and is skipped without it.
+    pub fn niceness(self) -> Option<i32> {
+        match self {
+            Self::Background => Some(10),
+            Self::Normal => None,
+            Self::Interactive => Some(-5),
+        }
+    }
+
+    /// Scale a cgroup `cpu.weight` (default 100) for this priority.
+    pub fn cpu_weight(self, base: u64) -> u64 {
+        match self {
+            Self::Background => (base / 4).max(1),
+            Self::Normal => base,
+            Self::Interactive => (base * 4).min(10_000),
+        }
+    }
+}
+
+/// A turn waiting for a slot, with the sender that hands it the slot.
+struct Waiter {
+    priority: SessionPriority,
+    seq: u64,
+    start: oneshot::Sender<TurnSlot>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    /// Higher priority first, then earlier submission.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Default)]
+struct Slots {
+    running: usize,
+    waiting: BinaryHeap<Waiter>,
+    next_seq: u64,
+}
+
+/// Caps how many prompt turns run at once across all sessions. Turns over
+/// the cap wait and start by session priority, then in submission order.
+pub(crate) struct TurnSlots {
+    /// `0` runs every turn right away.
+    limit: usize,
+    slots: Arc<Mutex<Slots>>,
+}
+
+/// A running turn's slot, handed to the next waiter when dropped, so a turn
+/// that panics gives it up too.
+pub(crate) struct TurnSlot {
+    /// `None` when turns are not capped.
+    slots: Option<Arc<Mutex<Slots>>>,
+}
+
+/// What [`TurnSlots::claim`] got a turn.
+pub(crate) enum SlotClaim {
+    Taken(TurnSlot),
+    /// All slots are taken; the receiver gets one once the turn may start.
+    Queued(oneshot::Receiver<TurnSlot>),
+}
+
+impl TurnSlots {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            slots: Arc::new(Mutex::new(Slots::default())),
+        }
+    }
+
+    /// Take a slot for a turn, or a place in line when all are taken.
+    pub(crate) fn claim(&self, priority: SessionPriority) -> SlotClaim {
+        if self.limit == 0 {
+            return SlotClaim::Taken(TurnSlot { slots: None });
+        }
+        let mut slots = self.slots.lock().unwrap_or_else(|err| err.into_inner());
+        if slots.running < self.limit {
+            slots.running += 1;
+            return SlotClaim::Taken(TurnSlot {
+                slots: Some(self.slots.clone()),
+            });
+        }
+        let (start, started) = oneshot::channel();
+        let seq = slots.next_seq;
+        slots.next_seq += 1;
+        slots.waiting.push(Waiter {
+            priority,
+            seq,
+            start,
+        });
+        SlotClaim::Queued(started)
+    }
+}
+
+impl SlotClaim {
+    pub(crate) fn is_queued(&self) -> bool {
+        matches!(self, Self::Queued(_))
+    }
+
+    /// Wait for the slot.
+    pub(crate) async fn taken(self) -> TurnSlot {
+        match self {
+            Self::Taken(slot) => slot,
+            // The slots went away with the adapter; nothing is left to cap.
+            Self::Queued(started) => started.await.unwrap_or(TurnSlot { slots: None }),
+        }
+    }
+}
+
+impl Drop for TurnSlot {
+    /// Hand the slot to the next waiter, or free it.
+    fn drop(&mut self) {
+        let Some(shared) = self.slots.take() else {
+            return;
+        };
+        let mut slots = shared.lock().unwrap_or_else(|err| err.into_inner());
+        // A waiter whose task is gone cannot start; skip it. One that goes
+        // away after being handed the slot drops it, which frees it again.
+        while let Some(waiter) = slots.waiting.pop() {
+            let slot = TurnSlot {
+                slots: Some(shared.clone()),
+            };
+            match waiter.start.send(slot) {
+                Ok(()) => return,
+                Err(mut slot) => slot.slots = None,
+            }
+        }
+        slots.running = slots.running.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(claim: SlotClaim) -> oneshot::Receiver<TurnSlot> {
+        match claim {
+            SlotClaim::Queued(started) => started,
+            SlotClaim::Taken(_) => panic!("expected to wait for a slot"),
+        }
+    }
+
+    #[test]
+    fn waiting_turns_start_by_priority_then_in_order() {
+        let slots = TurnSlots::new(1);
+        let SlotClaim::Taken(running) = slots.claim(SessionPriority::Normal) else {
+            panic!("expected a free slot");
+        };
+        let mut first_batch = queued(slots.claim(SessionPriority::Background));
+        let mut second_batch = queued(slots.claim(SessionPriority::Background));
+        let mut interactive = queued(slots.claim(SessionPriority::Interactive));
+
+        drop(running);
+        let running = interactive.try_recv().expect("interactive starts first");
+        assert!(first_batch.try_recv().is_err());
+
+        drop(running);
+        let running = first_batch.try_recv().expect("then the earlier batch turn");
+        assert!(second_batch.try_recv().is_err());
+
+        drop(second_batch);
+        drop(running);
+        assert!(!slots.claim(SessionPriority::Background).is_queued());
+    }
+
+    #[tokio::test]
+    async fn a_turn_that_panics_gives_up_its_slot() {
+        let slots = TurnSlots::new(1);
+        let running = slots.claim(SessionPriority::Normal);
+        let waiting = slots.claim(SessionPriority::Normal);
+        assert!(waiting.is_queued());
+
+        let panicked = tokio::spawn(async move {
+            let _slot = running.taken().await;
+            panic!("turn failed");
+        })
+        .await;
+        assert!(panicked.is_err());
+        let _slot = tokio::time::timeout(std::time::Duration::from_secs(1), waiting.taken())
+            .await
+            .expect("the waiting turn starts");
+    }
+
+    #[test]
+    fn a_slot_handed_to_a_turn_that_is_gone_is_freed() {
+        let slots = TurnSlots::new(1);
+        let running = slots.claim(SessionPriority::Normal);
+        let waiting = queued(slots.claim(SessionPriority::Normal));
+        drop(running);
+        drop(waiting);
+        assert!(!slots.claim(SessionPriority::Normal).is_queued());
+    }
+
+    #[test]
+    fn no_limit_never_waits() {
+        let slots = TurnSlots::new(0);
+        for _ in 0..8 {
+            assert!(!slots.claim(SessionPriority::Background).is_queued());
+        }
+    }
+
+    #[test]
+    fn priorities_map_to_niceness_and_cpu_weight() {
+        assert_eq!(SessionPriority::Normal.niceness(), None);
+        assert_eq!(SessionPriority::Background.niceness(), Some(10));
+        assert_eq!(SessionPriority::Background.cpu_weight(100), 25);
+        assert_eq!(SessionPriority::Interactive.cpu_weight(100), 400);
+        assert_eq!(SessionPriority::Interactive.cpu_weight(5_000), 10_000);
+    }
+}
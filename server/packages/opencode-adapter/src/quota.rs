@@ -0,0 +1,241 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::archive::env_nonempty;
+
+/// The rolling window of `max_sessions_per_hour`.
+pub(crate) const HOUR_MS: i64 = 60 * 60 * 1000;
+
+/// Caps on the sessions one adapter serves, checked when a session is
+/// created or forked. A limit of `0` is off.
+#[derive(Debug, Clone, Default)]
+pub struct SessionQuotaConfig {
+    /// Sessions that have not ended, idle and dormant ones included.
+    pub max_live_sessions: usize,
+    /// Sessions created within any rolling hour, deleted ones included.
+    pub max_sessions_per_hour: usize,
+    /// Events persisted across all sessions.
+    pub max_total_events: u64,
+}
+
+impl SessionQuotaConfig {
+    /// Build from `SANDBOX_AGENT_MAX_LIVE_SESSIONS`,
+    /// `SANDBOX_AGENT_MAX_SESSIONS_PER_HOUR` and
+    /// `SANDBOX_AGENT_MAX_TOTAL_EVENTS`. `None` unless one of them is set to
+    /// a non-zero number.
+    pub fn from_env() -> Option<Self> {
+        let env_u64 = |key: &str| env_nonempty(key).and_then(|value| value.parse::<u64>().ok());
+        let config = Self {
+            max_live_sessions: env_u64("SANDBOX_AGENT_MAX_LIVE_SESSIONS").unwrap_or(0) as usize,
+            max_sessions_per_hour: env_u64("SANDBOX_AGENT_MAX_SESSIONS_PER_HOUR").unwrap_or(0)
+                as usize,
+            max_total_events: env_u64("SANDBOX_AGENT_MAX_TOTAL_EVENTS").unwrap_or(0),
+        };
+        (config.max_live_sessions > 0
+            || config.max_sessions_per_hour > 0
+            || config.max_total_events > 0)
+            .then_some(config)
+    }
+
+    /// The limits as reported by `GET /admin/quota`; `null` when off.
+    pub(crate) fn limits(&self) -> Value {
+        let limit = |max: u64| (max > 0).then_some(max);
+        json!({
+            "liveSessions": limit(self.max_live_sessions as u64),
+            "sessionsPerHour": limit(self.max_sessions_per_hour as u64),
+            "totalEvents": limit(self.max_total_events),
+        })
+    }
+
+    /// The first limit one more session would exceed. `oldest_recent` is
+    /// when the oldest session of the last hour was created, for the time
+    /// until the hourly limit lets another one through.
+    pub(crate) fn check(
+        &self,
+        usage: &QuotaUsage,
+        oldest_recent: Option<i64>,
+        now: i64,
+    ) -> Result<(), QuotaExceeded> {
+        let over = |max: u64, current: u64| max > 0 && current >= max;
+        if over(self.max_live_sessions as u64, usage.live_sessions as u64) {
+            return Err(QuotaExceeded {
+                limit: QuotaLimit::LiveSessions,
+                max: self.max_live_sessions as u64,
+                current: usage.live_sessions as u64,
+                retry_after_ms: None,
+            });
+        }
+        if over(
+            self.max_sessions_per_hour as u64,
+            usage.sessions_last_hour as u64,
+        ) {
+            return Err(QuotaExceeded {
+                limit: QuotaLimit::SessionsPerHour,
+                max: self.max_sessions_per_hour as u64,
+                current: usage.sessions_last_hour as u64,
+                retry_after_ms: Some(
+                    oldest_recent
+                        .map_or(HOUR_MS, |oldest| oldest + HOUR_MS - now)
+                        .max(1) as u64,
+                ),
+            });
+        }
+        if over(self.max_total_events, usage.total_events) {
+            return Err(QuotaExceeded {
+                limit: QuotaLimit::TotalEvents,
+                max: self.max_total_events,
+                current: usage.total_events,
+                retry_after_ms: None,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Usage of the quota's resources, as reported by `GET /admin/quota`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct QuotaUsage {
+    pub live_sessions: usize,
+    pub sessions_last_hour: usize,
+    pub total_events: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum QuotaLimit {
+    LiveSessions,
+    SessionsPerHour,
+    TotalEvents,
+}
+
+/// A limit a new session would go over.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct QuotaExceeded {
+    pub limit: QuotaLimit,
+    pub max: u64,
+    pub current: u64,
+    /// Until the hourly limit admits another session.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+}
+
+impl QuotaExceeded {
+    pub(crate) fn message(&self) -> String {
+        let what = match self.limit {
+            QuotaLimit::LiveSessions => "live sessions",
+            QuotaLimit::SessionsPerHour => "sessions created in the last hour",
+            QuotaLimit::TotalEvents => "persisted events",
+        };
+        format!(
+            "session quota exceeded: {} of {} {what}",
+            self.current, self.max
+        )
+    }
+}
+
+/// Sessions created within the last hour, by ID, so deleting a session does
+/// not free up the hourly limit.
+#[derive(Debug, Default)]
+pub(crate) struct RecentCreations {
+    created: VecDeque<(i64, String)>,
+}
+
+impl RecentCreations {
+    pub(crate) fn record(&mut self, session_id: &str, created_at: i64) {
+        self.created.push_back((created_at, session_id.to_string()));
+    }
+
+    /// Creation times of the sessions created since `since`: those recorded
+    /// here, merged with `known` (e.g. sessions loaded from the database).
+    pub(crate) fn since(
+        &mut self,
+        since: i64,
+        known: impl IntoIterator<Item = (i64, String)>,
+    ) -> Vec<i64> {
+        while self
+            .created
+            .front()
+            .is_some_and(|(created_at, _)| *created_at < since)
+        {
+            self.created.pop_front();
+        }
+        let mut recent: HashMap<String, i64> = known
+            .into_iter()
+            .filter(|(created_at, _)| *created_at >= since)
+            .map(|(created_at, id)| (id, created_at))
+            .collect();
+        for (created_at, id) in &self.created {
+            recent.insert(id.clone(), *created_at);
+        }
+        recent.into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 10 * HOUR_MS;
+
+    #[test]
+    fn reports_the_first_limit_a_new_session_would_exceed() {
+        let config = SessionQuotaConfig {
+            max_live_sessions: 3,
+            max_sessions_per_hour: 5,
+            max_total_events: 0,
+        };
+        let usage = QuotaUsage {
+            live_sessions: 2,
+            sessions_last_hour: 5,
+            total_events: 1_000_000,
+        };
+        let exceeded = config
+            .check(&usage, Some(NOW - HOUR_MS + 90_000), NOW)
+            .unwrap_err();
+        assert_eq!(exceeded.limit, QuotaLimit::SessionsPerHour);
+        assert_eq!(exceeded.retry_after_ms, Some(90_000));
+        assert_eq!(
+            exceeded.message(),
+            "session quota exceeded: 5 of 5 sessions created in the last hour"
+        );
+
+        let usage = QuotaUsage {
+            live_sessions: 3,
+            ..usage
+        };
+        assert_eq!(
+            config.check(&usage, None, NOW).unwrap_err().limit,
+            QuotaLimit::LiveSessions
+        );
+        let usage = QuotaUsage {
+            live_sessions: 0,
+            sessions_last_hour: 0,
+            ..usage
+        };
+        assert_eq!(config.check(&usage, None, NOW), Ok(()));
+        assert_eq!(
+            config.limits(),
+            json!({"liveSessions": 3, "sessionsPerHour": 5, "totalEvents": null})
+        );
+    }
+
+    #[test]
+    fn counts_deleted_sessions_within_the_hour() {
+        let mut recent = RecentCreations::default();
+        recent.record("ses_old", NOW - HOUR_MS - 1);
+        recent.record("ses_deleted", NOW - 60_000);
+        recent.record("ses_kept", NOW - 30_000);
+        let known = vec![
+            (NOW - 30_000, "ses_kept".to_string()),
+            (NOW - 10_000, "ses_loaded".to_string()),
+            (NOW - 2 * HOUR_MS, "ses_ancient".to_string()),
+        ];
+        let mut times = recent.since(NOW - HOUR_MS, known);
+        times.sort();
+        assert_eq!(times, vec![NOW - 60_000, NOW - 30_000, NOW - 10_000]);
+    }
+}
@@ -0,0 +1,199 @@
+use serde_json::{json, Value};
+
+/// Thinking budgets, in tokens, of the prompt `variant`s, in increasing
+/// order. `max` is the largest budget Claude accepts below its output limit.
+const VARIANTS: &[(&str, u32)] = &[
+    ("none", 0),
+    ("low", 4_000),
+    ("medium", 10_000),
+    ("high", 16_000),
+    ("max", 31_999),
+];
+
+/// Most thinking tokens a prompt may ask for.
+const MAX_BUDGET_TOKENS: u32 = 128_000;
+
+/// A prompt's reasoning effort: its `variant`, or an explicit
+/// `reasoningBudget` in tokens, which wins over the variant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ReasoningBudget {
+    variant: Option<String>,
+    pub budget_tokens: u32,
+}
+
+impl ReasoningBudget {
+    /// `None` when the prompt sets neither.
+    pub(crate) fn new(
+        variant: Option<&str>,
+        budget_tokens: Option<u32>,
+    ) -> Result<Option<Self>, String> {
+        let variant = variant.map(str::trim).filter(|variant| !variant.is_empty());
+        let variant_budget = variant
+            .map(|variant| {
+                VARIANTS
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(variant))
+                    .map(|(_, budget)| *budget)
+                    .ok_or_else(|| {
+                        let names = VARIANTS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+                        format!(
+                            "unknown variant {variant:?}; expected one of {}",
+                            names.join(", ")
+                        )
+                    })
+            })
+            .transpose()?;
+        if let Some(budget) = budget_tokens.filter(|budget| *budget > MAX_BUDGET_TOKENS) {
+            return Err(format!(
+                "reasoningBudget {budget} is over the {MAX_BUDGET_TOKENS}-token limit"
+            ));
+        }
+        Ok(budget_tokens.or(variant_budget).map(|budget_tokens| Self {
+            variant: variant.map(str::to_ascii_lowercase),
+            budget_tokens,
+        }))
+    }
+
+    /// The `session/prompt` `_meta` carrying the budget: `maxThinkingTokens`,
+    /// which Claude's ACP adapter hands to the CLI as
+    /// `--max-thinking-tokens`, and `_meta["sandboxagent.dev"].reasoning`
+    /// for other agents.
+    pub(crate) fn prompt_meta(&self, agent: &str) -> Value {
+        match agent {
+            "claude" => json!({"maxThinkingTokens": self.budget_tokens}),
+            _ => json!({"sandboxagent.dev": {"reasoning": {
+                "variant": self.variant,
+                "budgetTokens": self.budget_tokens,
+            }}}),
+        }
+    }
+
+    /// As recorded on the turn.
+    pub(crate) fn to_value(&self) -> Value {
+        json!({"variant": self.variant, "budgetTokens": self.budget_tokens})
+    }
+}
+
+/// Merge `extra` into a prompt `_meta`, one level into shared objects.
+pub(crate) fn merge_meta(meta: &mut Option<Value>, extra: Value) {
+    let Value::Object(extra) = extra else {
+        return;
+    };
+    let target = meta.get_or_insert_with(|| json!({}));
+    for (key, value) in extra {
+        match (target.get_mut(&key), value) {
+            (Some(Value::Object(existing)), Value::Object(value)) => existing.extend(value),
+            (_, value) => target[key] = value,
+        }
+    }
+}
+
+/// Token usage an agent reported for a turn, from the `session/prompt`
+/// result's `usage`, or `_meta.usage` in Anthropic's field names.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TokenUsage {
+    input: u64,
+    output: u64,
+    /// Reported separately by some agents; Claude counts thinking as output.
+    reasoning: Option<u64>,
+    cache_read: u64,
+    cache_write: u64,
+}
+
+impl TokenUsage {
+    pub(crate) fn from_prompt_result(result: &Value) -> Option<Self> {
+        let usage = result
+            .get("usage")
+            .or_else(|| result.pointer("/_meta/usage"))
+            .filter(|usage| usage.is_object())?;
+        let count = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|key| usage.get(*key).and_then(Value::as_u64))
+        };
+        Some(Self {
+            input: count(&["inputTokens", "input_tokens"]).unwrap_or(0),
+            output: count(&["outputTokens", "output_tokens"]).unwrap_or(0),
+            reasoning: count(&[
+                "thoughtTokens",
+                "reasoningTokens",
+                "reasoning_output_tokens",
+            ]),
+            cache_read: count(&["cachedReadTokens", "cache_read_input_tokens"]).unwrap_or(0),
+            cache_write: count(&["cachedWriteTokens", "cache_creation_input_tokens"]).unwrap_or(0),
+        })
+    }
+
+    /// Fill an assistant message's `tokens`. Without a reported reasoning
+    /// count, `streamed_reasoning` (estimated from the streamed thinking) is
+    /// taken out of the output count, which then includes it.
+    pub(crate) fn apply(&self, info: &mut Value, streamed_reasoning: u64) {
+        let (output, reasoning) = match self.reasoning {
+            Some(reasoning) => (self.output, reasoning),
+            None => {
+                let reasoning = streamed_reasoning.min(self.output);
+                (self.output - reasoning, reasoning)
+            }
+        };
+        info["tokens"] = json!({
+            "input": self.input,
+            "output": output,
+            "reasoning": reasoning,
+            "cache": {"read": self.cache_read, "write": self.cache_write},
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_variants_and_budgets_to_prompt_meta() {
+        let high = ReasoningBudget::new(Some("High"), None).unwrap().unwrap();
+        assert_eq!(
+            high.prompt_meta("claude"),
+            json!({"maxThinkingTokens": 16_000})
+        );
+        let explicit = ReasoningBudget::new(Some("low"), Some(2_048))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            explicit.prompt_meta("codex"),
+            json!({"sandboxagent.dev": {"reasoning": {"variant": "low", "budgetTokens": 2_048}}})
+        );
+        assert_eq!(ReasoningBudget::new(None, None), Ok(None));
+        assert!(ReasoningBudget::new(Some("extreme"), None).is_err());
+        assert!(ReasoningBudget::new(None, Some(MAX_BUDGET_TOKENS + 1)).is_err());
+
+        let mut meta = Some(json!({"sandboxagent.dev": {"model": "opus"}}));
+        merge_meta(&mut meta, explicit.prompt_meta("codex"));
+        assert_eq!(meta.unwrap()["sandboxagent.dev"]["model"], "opus");
+    }
+
+    #[test]
+    fn separates_reasoning_from_reported_output() {
+        let mut info = json!({});
+        let claude = TokenUsage::from_prompt_result(&json!({
+            "stopReason": "end_turn",
+            "_meta": {"usage": {"input_tokens": 120, "output_tokens": 90, "cache_read_input_tokens": 30}},
+        }))
+        .unwrap();
+        claude.apply(&mut info, 40);
+        assert_eq!(
+            info["tokens"],
+            json!({"input": 120, "output": 50, "reasoning": 40, "cache": {"read": 30, "write": 0}})
+        );
+
+        let reported = TokenUsage::from_prompt_result(&json!({
+            "usage": {"inputTokens": 5, "outputTokens": 7, "thoughtTokens": 3},
+        }))
+        .unwrap();
+        reported.apply(&mut info, 40);
+        assert_eq!(info["tokens"]["output"], 7);
+        assert_eq!(info["tokens"]["reasoning"], 3);
+        assert_eq!(
+            TokenUsage::from_prompt_result(&json!({"stopReason": "end_turn"})),
+            None
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_json::Value;
+
+const REPLAY_PREFIX: &str = "Previous session history is replayed below as JSON-RPC envelopes. Use it as context before responding to the latest user prompt.\n";
+const TRUNCATION_MARKER: &str = "[history truncated]";
+
+/// What a persisted envelope contributes to a replayed history, in order of
+/// how much an agent resuming the session needs it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReplayKind {
+    /// A prompt the user sent.
+    UserPrompt,
+    /// A finished assistant text part.
+    AssistantText,
+    /// A tool result that failed or that the agent flagged with
+    /// `metadata.important`.
+    ImportantToolResult,
+    /// Any other finished tool result.
+    ToolResult,
+    /// A permission or question and its answer.
+    Decision,
+    /// Reasoning, running tools, retries and other progress.
+    Progress,
+    /// Protocol bookkeeping: session setup, statuses, message metadata.
+    Other,
+}
+
+impl ReplayKind {
+    pub(crate) fn weight(self) -> u32 {
+        match self {
+            Self::UserPrompt => 100,
+            Self::AssistantText => 80,
+            Self::ImportantToolResult => 70,
+            Self::ToolResult => 40,
+            Self::Decision => 30,
+            Self::Progress => 10,
+            Self::Other => 5,
+        }
+    }
+
+    /// The kind of a replay event (`{createdAt, sender, payload}`).
+    pub(crate) fn of(event: &Value) -> Self {
+        let payload = &event["payload"];
+        match payload["method"].as_str().unwrap_or_default() {
+            "session/prompt" => Self::UserPrompt,
+            "_sandboxagent/opencode/message" => Self::of_message(&payload["params"]["message"]),
+            method
+                if method.starts_with("_sandboxagent/opencode/permission_")
+                    || method.starts_with("_sandboxagent/opencode/question_") =>
+            {
+                Self::Decision
+            }
+            _ => Self::Other,
+        }
+    }
+
+    fn of_message(message: &Value) -> Self {
+        if message.pointer("/info/role").and_then(Value::as_str) == Some("user") {
+            return Self::UserPrompt;
+        }
+        let parts = message["parts"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or(&[]);
+        parts
+            .iter()
+            .map(|part| match part["type"].as_str().unwrap_or_default() {
+                "text"
+                    if part["text"]
+                        .as_str()
+                        .is_some_and(|text| !text.trim().is_empty()) =>
+                {
+                    Self::AssistantText
+                }
+                "tool" => match part.pointer("/state/status").and_then(Value::as_str) {
+                    Some("error") => Self::ImportantToolResult,
+                    Some("completed")
+                        if part.pointer("/state/metadata/important")
+                            == Some(&Value::Bool(true)) =>
+                    {
+                        Self::ImportantToolResult
+                    }
+                    Some("completed") => Self::ToolResult,
+                    _ => Self::Progress,
+                },
+                _ => Self::Progress,
+            })
+            .min()
+            .unwrap_or(Self::Other)
+    }
+}
+
+/// How one kind of event fared in a selection.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ReplayKindStats {
+    pub weight: u32,
+    pub events: usize,
+    pub chars: usize,
+    pub selected_events: usize,
+    pub selected_chars: usize,
+}
+
+/// What a selection kept out of a session's history, for tuning the
+/// weights and budgets.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ReplayStats {
+    pub max_events: usize,
+    pub max_chars: usize,
+    pub total_events: usize,
+    pub total_chars: usize,
+    pub selected_events: usize,
+    /// Characters of the replay text, including its framing.
+    pub selected_chars: usize,
+    pub kinds: BTreeMap<ReplayKind, ReplayKindStats>,
+}
+
+/// The events replayed to an agent, in the order they happened.
+#[derive(Debug, Clone)]
+pub(crate) struct ReplaySelection {
+    pub events: Vec<Value>,
+    pub truncated: bool,
+    pub stats: ReplayStats,
+}
+
+impl ReplaySelection {
+    /// The replay text handed to the agent, or `None` for an empty history.
+    pub(crate) fn text(&self) -> Option<String> {
+        if self.stats.total_events == 0 {
+            return None;
+        }
+        let mut text = REPLAY_PREFIX.to_string();
+        for event in &self.events {
+            text.push_str(&line(event));
+        }
+        if self.truncated {
+            text.push_str(TRUNCATION_MARKER);
+        }
+        Some(text)
+    }
+}
+
+fn line(event: &Value) -> String {
+    let mut line = serde_json::to_string(event).unwrap_or_else(|_| "{}".to_string());
+    line.push('\n');
+    line
+}
+
+/// Pick the events to replay from a session's history (oldest first): the
+/// heaviest kinds first and, within a kind, the most recent, until
+/// `max_events` are picked or the next candidate would push the replay text
+/// past `max_chars`. Candidates that do not fit are skipped, so smaller
+/// events further down can still fill the budget.
+pub(crate) fn select(events: Vec<Value>, max_events: usize, max_chars: usize) -> ReplaySelection {
+    let lines = events.iter().map(line).collect::<Vec<_>>();
+    let kinds = events.iter().map(ReplayKind::of).collect::<Vec<_>>();
+    let mut order = (0..events.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| {
+        kinds[b]
+            .weight()
+            .cmp(&kinds[a].weight())
+            .then_with(|| b.cmp(&a))
+    });
+
+    let budget = max_chars.saturating_sub(REPLAY_PREFIX.len() + TRUNCATION_MARKER.len());
+    let mut used = 0;
+    let mut picked = vec![false; events.len()];
+    let mut picked_count = 0;
+    for index in order {
+        if picked_count == max_events {
+            break;
+        }
+        if used + lines[index].len() <= budget {
+            used += lines[index].len();
+            picked[index] = true;
+            picked_count += 1;
+        }
+    }
+
+    let mut stats = ReplayStats {
+        max_events,
+        max_chars,
+        total_events: events.len(),
+        ..ReplayStats::default()
+    };
+    for (index, kind) in kinds.iter().enumerate() {
+        let chars = lines[index].len();
+        let entry = stats.kinds.entry(*kind).or_insert_with(|| ReplayKindStats {
+            weight: kind.weight(),
+            ..ReplayKindStats::default()
+        });
+        entry.events += 1;
+        entry.chars += chars;
+        stats.total_chars += chars;
+        if picked[index] {
+            entry.selected_events += 1;
+            entry.selected_chars += chars;
+        }
+    }
+    stats.selected_events = picked_count;
+    let truncated = picked_count < events.len();
+    let selected = events
+        .into_iter()
+        .zip(picked)
+        .filter_map(|(event, picked)| picked.then_some(event))
+        .collect();
+    let mut selection = ReplaySelection {
+        events: selected,
+        truncated,
+        stats,
+    };
+    selection.stats.selected_chars = selection.text().map_or(0, |text| text.len());
+    selection
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn prompt(text: &str) -> Value {
+        json!({"sender": "client", "payload": {
+            "method": "session/prompt",
+            "params": {"prompt": [{"type": "text", "text": text}]}
+        }})
+    }
+
+    fn part(part: Value) -> Value {
+        json!({"sender": "agent", "payload": {
+            "method": "_sandboxagent/opencode/message",
+            "params": {"message": {"info": {"id": "msg_1"}, "parts": [part]}}
+        }})
+    }
+
+    fn tool(status: &str, important: bool) -> Value {
+        part(json!({"type": "tool", "state": {
+            "status": status,
+            "metadata": {"important": important}
+        }}))
+    }
+
+    #[test]
+    fn classifies_envelopes_by_information_value() {
+        assert_eq!(ReplayKind::of(&prompt("hi")), ReplayKind::UserPrompt);
+        assert_eq!(
+            ReplayKind::of(&part(json!({"type": "text", "text": "done"}))),
+            ReplayKind::AssistantText
+        );
+        assert_eq!(
+            ReplayKind::of(&tool("error", false)),
+            ReplayKind::ImportantToolResult
+        );
+        assert_eq!(
+            ReplayKind::of(&tool("completed", true)),
+            ReplayKind::ImportantToolResult
+        );
+        assert_eq!(
+            ReplayKind::of(&tool("completed", false)),
+            ReplayKind::ToolResult
+        );
+        assert_eq!(
+            ReplayKind::of(&tool("running", false)),
+            ReplayKind::Progress
+        );
+        assert_eq!(
+            ReplayKind::of(&json!({"payload": {
+                "method": "_sandboxagent/opencode/permission_replied"
+            }})),
+            ReplayKind::Decision
+        );
+        assert_eq!(
+            ReplayKind::of(&json!({"payload": {"method": "session/new"}})),
+            ReplayKind::Other
+        );
+    }
+
+    #[test]
+    fn keeps_the_heaviest_events_in_order_under_the_budget() {
+        let events = vec![
+            prompt("first"),
+            tool("running", false),
+            tool("completed", false),
+            part(json!({"type": "text", "text": "answer"})),
+            prompt("second"),
+        ];
+        let budget = REPLAY_PREFIX.len()
+            + TRUNCATION_MARKER.len()
+            + line(&events[0]).len()
+            + line(&events[3]).len()
+            + line(&events[4]).len();
+
+        let selection = select(events.clone(), 50, budget);
+        assert_eq!(
+            selection.events,
+            vec![events[0].clone(), events[3].clone(), events[4].clone()]
+        );
+        assert!(selection.truncated);
+        let text = selection.text().expect("replay text");
+        assert!(text.len() <= budget);
+        assert!(text.ends_with(TRUNCATION_MARKER));
+
+        let stats = &selection.stats;
+        assert_eq!(stats.total_events, 5);
+        assert_eq!(stats.selected_events, 3);
+        assert_eq!(stats.selected_chars, text.len());
+        assert_eq!(stats.kinds[&ReplayKind::UserPrompt].selected_events, 2);
+        assert_eq!(stats.kinds[&ReplayKind::ToolResult].selected_events, 0);
+    }
+
+    #[test]
+    fn prefers_recent_events_of_a_kind_and_caps_the_count() {
+        let events = vec![prompt("one"), prompt("two"), prompt("three")];
+        let selection = select(events.clone(), 2, 100_000);
+        assert_eq!(selection.events, vec![events[1].clone(), events[2].clone()]);
+
+        let everything = select(events.clone(), 50, 100_000);
+        assert_eq!(everything.events, events);
+        assert!(!everything.truncated);
+        assert!(select(Vec::new(), 50, 100_000).text().is_none());
+    }
+}
@@ -0,0 +1,294 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Where a request body departs from its schema.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Problem {
+    /// JSON pointer to the offending value; empty for the body itself.
+    pub pointer: String,
+    pub message: String,
+}
+
+/// The JSON schema generated from a request body type.
+pub(crate) fn schema_of<T: JsonSchema>() -> Value {
+    serde_json::to_value(schemars::schema_for!(T)).unwrap_or(Value::Bool(true))
+}
+
+/// Check `body` against `schema` as generated by [`schema_of`]. Objects with
+/// declared `properties` are closed: fields they do not declare are
+/// reported, since serde would drop them silently.
+pub(crate) fn validate(schema: &Value, body: &Value) -> Vec<Problem> {
+    let definitions = schema
+        .get("definitions")
+        .and_then(Value::as_object)
+        .cloned()
+        .unwrap_or_default();
+    let mut problems = Vec::new();
+    check(schema, body, "", &definitions, &mut problems);
+    problems
+}
+
+fn check(
+    schema: &Value,
+    value: &Value,
+    pointer: &str,
+    definitions: &Map<String, Value>,
+    problems: &mut Vec<Problem>,
+) {
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => {
+            problems.push(problem(pointer, "no value is allowed here".to_string()));
+            return;
+        }
+        Value::Object(schema) => schema,
+        _ => return,
+    };
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        let name = reference.trim_start_matches("#/definitions/");
+        if let Some(definition) = definitions.get(name) {
+            check(definition, value, pointer, definitions, problems);
+        }
+        return;
+    }
+    for key in ["anyOf", "oneOf"] {
+        if let Some(branches) = schema.get(key).and_then(Value::as_array) {
+            // Report the branch the value came closest to matching: one of
+            // its type, with the fewest problems.
+            let mut closest: Option<(usize, Vec<Problem>)> = None;
+            for branch in branches {
+                let mut branch_problems = Vec::new();
+                check(branch, value, pointer, definitions, &mut branch_problems);
+                if branch_problems.is_empty() {
+                    return;
+                }
+                let wrong_type = branch_problems.iter().any(|problem| {
+                    problem.pointer == pointer && problem.message.starts_with("expected")
+                });
+                let distance = if wrong_type {
+                    usize::MAX
+                } else {
+                    branch_problems.len()
+                };
+                if closest.as_ref().is_none_or(|(best, _)| distance < *best) {
+                    closest = Some((distance, branch_problems));
+                }
+            }
+            problems.extend(closest.map(|(_, problems)| problems).unwrap_or_default());
+            return;
+        }
+    }
+    if let Some(types) = schema.get("type") {
+        let types: Vec<&str> = match types {
+            Value::String(single) => vec![single.as_str()],
+            Value::Array(many) => many.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.iter().any(|expected| is_type(value, expected)) {
+            problems.push(problem(
+                pointer,
+                format!(
+                    "expected {}, found {}",
+                    types.join(" or "),
+                    type_name(value)
+                ),
+            ));
+            return;
+        }
+    }
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            problems.push(problem(
+                pointer,
+                format!(
+                    "expected one of {}",
+                    allowed
+                        .iter()
+                        .map(Value::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            ));
+        }
+    }
+    if let (Some(minimum), Some(number)) = (
+        schema.get("minimum").and_then(Value::as_f64),
+        value.as_f64(),
+    ) {
+        if number < minimum {
+            problems.push(problem(pointer, format!("must be at least {minimum}")));
+        }
+    }
+    match value {
+        Value::Object(object) => check_object(schema, object, pointer, definitions, problems),
+        Value::Array(items) => {
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    let pointer = format!("{pointer}/{index}");
+                    check(item_schema, item, &pointer, definitions, problems);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn check_object(
+    schema: &Map<String, Value>,
+    object: &Map<String, Value>,
+    pointer: &str,
+    definitions: &Map<String, Value>,
+    problems: &mut Vec<Problem>,
+) {
+    let properties = schema.get("properties").and_then(Value::as_object);
+    for required in schema
+        .get("required")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+    {
+        if !object.contains_key(required) {
+            problems.push(problem(
+                &child(pointer, required),
+                "required field is missing".to_string(),
+            ));
+        }
+    }
+    for (key, value) in object {
+        let pointer = child(pointer, key);
+        match (
+            properties.and_then(|properties| properties.get(key)),
+            schema.get("additionalProperties"),
+        ) {
+            (Some(property), _) => check(property, value, &pointer, definitions, problems),
+            (None, Some(additional)) => check(additional, value, &pointer, definitions, problems),
+            (None, None) if properties.is_some() => {
+                problems.push(problem(&pointer, "unknown field".to_string()))
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+fn child(pointer: &str, key: &str) -> String {
+    format!("{pointer}/{}", key.replace('~', "~0").replace('/', "~1"))
+}
+
+fn problem(pointer: &str, message: String) -> Problem {
+    Problem {
+        pointer: pointer.to_string(),
+        message,
+    }
+}
+
+fn is_type(value: &Value, expected: &str) -> bool {
+    match expected {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, JsonSchema)]
+    #[serde(rename_all = "camelCase")]
+    struct Body {
+        title: Option<String>,
+        parts: Option<Vec<Value>>,
+        model: Option<Model>,
+        limit: Option<u32>,
+        env: Option<HashMap<String, Env>>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, JsonSchema)]
+    struct Model {
+        #[serde(rename = "modelID")]
+        model_id: String,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Deserialize, JsonSchema)]
+    #[serde(untagged)]
+    enum Env {
+        Plain(String),
+        Detailed { value: String, secret: Option<bool> },
+    }
+
+    fn pointers(problems: &[Problem]) -> Vec<&str> {
+        problems
+            .iter()
+            .map(|problem| problem.pointer.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn valid_bodies_have_no_problems() {
+        let schema = schema_of::<Body>();
+        let body = json!({
+            "title": "t",
+            "parts": [{"type": "text", "anything": true}],
+            "model": {"modelID": "m"},
+            "limit": 3,
+            "env": {"A": "1", "B": {"value": "2", "secret": true}},
+        });
+        assert_eq!(validate(&schema, &body), Vec::new());
+        assert_eq!(validate(&schema, &json!({"title": null})), Vec::new());
+    }
+
+    #[test]
+    fn unknown_fields_and_wrong_types_are_reported() {
+        let schema = schema_of::<Body>();
+        let body = json!({
+            "titel": "typo",
+            "limit": -1,
+            "parts": "text",
+            "model": {"modelId": "m"},
+            "env": {"A": {"value": 1}},
+        });
+        let problems = validate(&schema, &body);
+        assert_eq!(
+            pointers(&problems),
+            vec![
+                "/env/A/value",
+                "/limit",
+                "/model/modelID",
+                "/model/modelId",
+                "/parts",
+                "/titel"
+            ]
+        );
+        assert_eq!(problems[0].message, "expected string, found integer");
+        assert_eq!(problems[2].message, "required field is missing");
+        assert_eq!(problems[3].message, "unknown field");
+        assert_eq!(problems[4].message, "expected array or null, found string");
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::{BTreeMap, HashMap};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+const MAX_ENV_VARS: usize = 64;
+/// Combined size of all names and values.
+const MAX_ENV_BYTES: usize = 32 * 1024;
+const MASK: &str = "********";
+/// Prefixes a session may not set: loader hooks and the server's own settings.
+const RESERVED_PREFIXES: &[&str] = &["LD_", "DYLD_", "SANDBOX_AGENT_", "OPENCODE_COMPAT_"];
+/// Name fragments that mark a plain string value as a secret.
+const SECRET_MARKERS: &[&str] = &[
+    "SECRET",
+    "TOKEN",
+    "PASSWORD",
+    "PASSWD",
+    "API_KEY",
+    "PRIVATE_KEY",
+    "CREDENTIAL",
+];
+
+/// A `SessionCreateBody.env` entry: a plain value, or a value with an
+/// explicit `secret` flag.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub(crate) enum SessionEnvInput {
+    Plain(String),
+    Detailed { value: String, secret: Option<bool> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SessionEnvVar {
+    pub value: String,
+    #[serde(default)]
+    pub secret: bool,
+}
+
+/// Validate `SessionCreateBody.env`. Plain values are secret when their name
+/// looks like one (`*_TOKEN`, `*_API_KEY`, ...).
+pub(crate) fn parse_session_env(
+    input: HashMap<String, SessionEnvInput>,
+) -> Result<BTreeMap<String, SessionEnvVar>, String> {
+    if input.len() > MAX_ENV_VARS {
+        return Err(format!("env may set at most {MAX_ENV_VARS} variables"));
+    }
+    let mut env = BTreeMap::new();
+    let mut total = 0;
+    for (name, value) in input {
+        if !valid_name(&name) {
+            return Err(format!(
+                "env name '{name}' must match [A-Za-z_][A-Za-z0-9_]*"
+            ));
+        }
+        if let Some(prefix) = RESERVED_PREFIXES
+            .iter()
+            .find(|prefix| name.to_ascii_uppercase().starts_with(*prefix))
+        {
+            return Err(format!(
+                "env name '{name}' uses the reserved prefix {prefix}"
+            ));
+        }
+        let (value, secret) = match value {
+            SessionEnvInput::Plain(value) => {
+                let secret = looks_secret(&name);
+                (value, secret)
+            }
+            SessionEnvInput::Detailed { value, secret } => {
+                let secret = secret.unwrap_or_else(|| looks_secret(&name));
+                (value, secret)
+            }
+        };
+        if value.contains('\0') {
+            return Err(format!("env value for '{name}' contains a NUL byte"));
+        }
+        total += name.len() + value.len();
+        env.insert(name, SessionEnvVar { value, secret });
+    }
+    if total > MAX_ENV_BYTES {
+        return Err(format!("env exceeds {MAX_ENV_BYTES} bytes"));
+    }
+    Ok(env)
+}
+
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn looks_secret(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    SECRET_MARKERS.iter().any(|marker| name.contains(marker))
+}
+
+/// Values of the secret variables, for [`mask_secrets`].
+pub(crate) fn secret_values(env: &BTreeMap<String, SessionEnvVar>) -> Vec<String> {
+    env.values()
+        .filter(|var| var.secret && !var.value.is_empty())
+        .map(|var| var.value.clone())
+        .collect()
+}
+
+/// The variables as reported on the session, with secret values masked.
+pub(crate) fn env_summary(env: &BTreeMap<String, SessionEnvVar>) -> Value {
+    Value::Object(
+        env.iter()
+            .map(|(name, var)| {
+                let value = if var.secret { MASK } else { &var.value };
+                (name.clone(), Value::String(value.to_string()))
+            })
+            .collect::<Map<_, _>>(),
+    )
+}
+
+/// Replace every occurrence of `secrets` in the strings of `value`.
+pub(crate) fn mask_secrets(value: &mut Value, secrets: &[String]) {
+    match value {
+        Value::String(text) if secrets.iter().any(|secret| text.contains(secret.as_str())) => {
+            for secret in secrets {
+                *text = text.replace(secret.as_str(), MASK);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                mask_secrets(item, secrets);
+            }
+        }
+        Value::Object(map) => {
+            for item in map.values_mut() {
+                mask_secrets(item, secrets);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(value: Value) -> Result<BTreeMap<String, SessionEnvVar>, String> {
+        parse_session_env(serde_json::from_value(value).expect("env input"))
+    }
+
+    #[test]
+    fn marks_secrets_by_name_or_flag() {
+        let env = parse(json!({
+            "API_BASE": "https://api.example.com",
+            "STRIPE_API_KEY": "sk_live_123",
+            "FEATURE_X": { "value": "on", "secret": true },
+            "GITHUB_TOKEN": { "value": "ghp_abc", "secret": false },
+        }))
+        .expect("valid env");
+        let secret: Vec<_> = env
+            .iter()
+            .filter(|(_, var)| var.secret)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(secret, ["FEATURE_X", "STRIPE_API_KEY"]);
+        assert_eq!(env_summary(&env)["STRIPE_API_KEY"], json!("********"));
+        assert_eq!(
+            env_summary(&env)["API_BASE"],
+            json!("https://api.example.com")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_names_and_reserved_prefixes() {
+        assert!(parse(json!({ "1BAD": "x" })).is_err());
+        assert!(parse(json!({ "BAD-NAME": "x" })).is_err());
+        assert!(parse(json!({ "LD_PRELOAD": "/tmp/x.so" })).is_err());
+        assert!(parse(json!({ "sandbox_agent_token": "x" })).is_err());
+        let oversized = "x".repeat(MAX_ENV_BYTES);
+        assert!(parse(json!({ "BIG": oversized })).is_err());
+    }
+
+    #[test]
+    fn masks_nested_strings() {
+        let mut event = json!({
+            "properties": { "part": { "state": { "output": "token=ghp_abc done" } } }
+        });
+        mask_secrets(&mut event, &["ghp_abc".to_string()]);
+        assert_eq!(
+            event["properties"]["part"]["state"]["output"],
+            "token=******** done"
+        );
+    }
+}
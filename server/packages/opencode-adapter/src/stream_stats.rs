@@ -0,0 +1,169 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+/// Characters per token when estimating token counts from streamed text.
+const CHARS_PER_TOKEN: usize = 4;
+/// Span of the rolling tokens/sec rate.
+const ROLLING_WINDOW_MS: i64 = 5_000;
+/// How often the live rate is published to the running turn.
+const REPORT_INTERVAL_MS: i64 = 500;
+
+/// Streaming speed of one assistant message: time to first token and
+/// tokens per second, overall and over the last few seconds. Tokens are
+/// estimated from the streamed text, as ACP agents do not report them per
+/// chunk.
+#[derive(Debug, Default)]
+pub(crate) struct StreamStats {
+    /// When the prompt went to the agent.
+    dispatched_at: Option<i64>,
+    first_token_at: Option<i64>,
+    last_token_at: Option<i64>,
+    output_tokens: u64,
+    reasoning_tokens: u64,
+    /// Recent chunks as `(time, tokens)`, oldest first.
+    window: VecDeque<(i64, u64)>,
+    reported_at: Option<i64>,
+}
+
+/// [`StreamStats`] as reported on messages and turns.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StreamSnapshot {
+    /// From the prompt reaching the agent to the first streamed token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttft_ms: Option<i64>,
+    pub output_tokens: u64,
+    pub reasoning_tokens: u64,
+    /// From the first streamed token to the latest.
+    pub streaming_ms: i64,
+    /// Over `streaming_ms`; absent until tokens span some time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens_per_second: Option<f64>,
+    /// Over the last five seconds of streaming.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rolling_tokens_per_second: Option<f64>,
+    /// Token counts are estimated from the text, not reported by the agent.
+    pub estimated: bool,
+}
+
+impl StreamStats {
+    pub(crate) fn new(dispatched_at: Option<i64>) -> Self {
+        Self {
+            dispatched_at,
+            ..Self::default()
+        }
+    }
+
+    /// Record a streamed chunk of text or reasoning received at `now`.
+    pub(crate) fn record(&mut self, text: &str, reasoning: bool, now: i64) {
+        let tokens = estimate_tokens(text);
+        if tokens == 0 {
+            return;
+        }
+        self.first_token_at.get_or_insert(now);
+        self.last_token_at = Some(now);
+        if reasoning {
+            self.reasoning_tokens += tokens;
+        } else {
+            self.output_tokens += tokens;
+        }
+        self.window.push_back((now, tokens));
+        while self
+            .window
+            .front()
+            .is_some_and(|(at, _)| *at < now - ROLLING_WINDOW_MS)
+        {
+            self.window.pop_front();
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.first_token_at.is_none()
+    }
+
+    /// Whether the live rate is due to be published again; marks it
+    /// published if so.
+    pub(crate) fn report_due(&mut self, now: i64) -> bool {
+        if self
+            .reported_at
+            .is_some_and(|reported| now - reported < REPORT_INTERVAL_MS)
+        {
+            return false;
+        }
+        self.reported_at = Some(now);
+        true
+    }
+
+    pub(crate) fn snapshot(&self, now: i64) -> StreamSnapshot {
+        let tokens = self.output_tokens + self.reasoning_tokens;
+        let streaming_ms = match (self.first_token_at, self.last_token_at) {
+            (Some(first), Some(last)) => last - first,
+            _ => 0,
+        };
+        let window_start = self
+            .first_token_at
+            .map_or(now, |first| first.max(now - ROLLING_WINDOW_MS));
+        let recent: u64 = self
+            .window
+            .iter()
+            .filter(|(at, _)| *at >= now - ROLLING_WINDOW_MS)
+            .map(|(_, tokens)| tokens)
+            .sum();
+        StreamSnapshot {
+            ttft_ms: self
+                .dispatched_at
+                .zip(self.first_token_at)
+                .map(|(dispatched, first)| (first - dispatched).max(0)),
+            output_tokens: self.output_tokens,
+            reasoning_tokens: self.reasoning_tokens,
+            streaming_ms,
+            tokens_per_second: rate(tokens, streaming_ms),
+            rolling_tokens_per_second: rate(recent, now - window_start),
+            estimated: true,
+        }
+    }
+}
+
+fn estimate_tokens(text: &str) -> u64 {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN) as u64
+}
+
+/// Tokens per second, rounded to a tenth; `None` over an empty span.
+fn rate(tokens: u64, span_ms: i64) -> Option<f64> {
+    (span_ms > 0).then(|| (tokens as f64 * 10_000.0 / span_ms as f64).round() / 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_time_to_first_token_and_rates() {
+        let mut stats = StreamStats::new(Some(1_000));
+        assert!(stats.is_empty());
+        stats.record("", false, 1_100);
+        assert!(stats.is_empty());
+        stats.record("thinking", true, 1_400);
+        stats.record(&"x".repeat(40), false, 2_400);
+        stats.record(&"x".repeat(40), false, 8_400);
+
+        let snapshot = stats.snapshot(8_400);
+        assert_eq!(snapshot.ttft_ms, Some(400));
+        assert_eq!(snapshot.output_tokens, 20);
+        assert_eq!(snapshot.reasoning_tokens, 2);
+        assert_eq!(snapshot.streaming_ms, 7_000);
+        assert_eq!(snapshot.tokens_per_second, Some(3.1));
+        // Only the last chunk is within five seconds.
+        assert_eq!(snapshot.rolling_tokens_per_second, Some(2.0));
+    }
+
+    #[test]
+    fn reports_at_most_every_interval() {
+        let mut stats = StreamStats::new(None);
+        assert!(stats.report_due(0));
+        assert!(!stats.report_due(REPORT_INTERVAL_MS - 1));
+        assert!(stats.report_due(REPORT_INTERVAL_MS));
+        assert_eq!(stats.snapshot(0).ttft_ms, None);
+    }
+}
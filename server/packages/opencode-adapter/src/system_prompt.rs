@@ -0,0 +1,121 @@
+use serde_json::{json, Value};
+
+/// How a prompt's `system` override reaches the agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SystemPromptMechanism {
+    /// `systemPrompt` in the `session/prompt` `_meta`, which Claude's ACP
+    /// adapter hands to the CLI as `--system-prompt`.
+    ClaudeSystemPrompt,
+    /// `-c base_instructions=…` on the launch of a new codex process.
+    CodexConfig,
+    /// A text part ahead of the prompt, for a codex process that is already
+    /// running and cannot take new config.
+    Preamble,
+    /// `_meta["sandboxagent.dev"].systemPrompt` on `session/prompt`.
+    AcpMeta,
+}
+
+impl SystemPromptMechanism {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            SystemPromptMechanism::ClaudeSystemPrompt => "claudeSystemPrompt",
+            SystemPromptMechanism::CodexConfig => "codexConfig",
+            SystemPromptMechanism::Preamble => "preamble",
+            SystemPromptMechanism::AcpMeta => "acpMeta",
+        }
+    }
+}
+
+/// A prompt's `system` override and the mechanism that forwards it.
+#[derive(Debug, Clone)]
+pub(crate) struct SystemPromptOverride {
+    text: String,
+    pub mechanism: SystemPromptMechanism,
+}
+
+impl SystemPromptOverride {
+    /// The override for a turn of `agent`; `launching` is whether the turn
+    /// starts a new agent process. `None` for a blank override.
+    pub(crate) fn new(text: &str, agent: &str, launching: bool) -> Option<Self> {
+        if text.trim().is_empty() {
+            return None;
+        }
+        let mechanism = match agent {
+            "claude" => SystemPromptMechanism::ClaudeSystemPrompt,
+            "codex" if launching => SystemPromptMechanism::CodexConfig,
+            "codex" => SystemPromptMechanism::Preamble,
+            _ => SystemPromptMechanism::AcpMeta,
+        };
+        Some(Self {
+            text: text.to_string(),
+            mechanism,
+        })
+    }
+
+    /// Extra arguments for the agent process launch.
+    pub(crate) fn launch_args(&self) -> Vec<String> {
+        match self.mechanism {
+            SystemPromptMechanism::CodexConfig => vec![
+                "-c".to_string(),
+                format!("base_instructions={}", Value::String(self.text.clone())),
+            ],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The `session/prompt` `_meta` carrying the override.
+    pub(crate) fn prompt_meta(&self) -> Option<Value> {
+        match self.mechanism {
+            SystemPromptMechanism::ClaudeSystemPrompt => Some(json!({"systemPrompt": self.text})),
+            SystemPromptMechanism::AcpMeta => {
+                Some(json!({"sandboxagent.dev": {"systemPrompt": self.text}}))
+            }
+            _ => None,
+        }
+    }
+
+    /// Text part prepended to the prompt.
+    pub(crate) fn preamble(&self) -> Option<String> {
+        (self.mechanism == SystemPromptMechanism::Preamble).then(|| {
+            format!(
+                "System instructions for this turn:\n\n<system>\n{}\n</system>",
+                self.text.trim_end()
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mechanism_depends_on_agent_and_launch() {
+        let claude = SystemPromptOverride::new("Be terse.", "claude", false).expect("override");
+        assert_eq!(
+            claude.prompt_meta(),
+            Some(json!({"systemPrompt": "Be terse."}))
+        );
+        assert!(claude.launch_args().is_empty());
+
+        let codex = SystemPromptOverride::new("Be terse.", "codex", true).expect("override");
+        assert_eq!(
+            codex.launch_args(),
+            vec!["-c", "base_instructions=\"Be terse.\""]
+        );
+        assert_eq!(codex.prompt_meta(), None);
+
+        let running = SystemPromptOverride::new("Be terse.", "codex", false).expect("override");
+        assert_eq!(running.mechanism, SystemPromptMechanism::Preamble);
+        assert!(running
+            .preamble()
+            .is_some_and(|text| text.contains("Be terse.")));
+
+        let amp = SystemPromptOverride::new("Be terse.", "amp", true).expect("override");
+        assert_eq!(
+            amp.prompt_meta(),
+            Some(json!({"sandboxagent.dev": {"systemPrompt": "Be terse."}}))
+        );
+        assert!(SystemPromptOverride::new("  ", "amp", true).is_none());
+    }
+}
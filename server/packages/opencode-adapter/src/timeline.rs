@@ -0,0 +1,175 @@
+use serde::Serialize;
+
+/// One tool call within a turn, from the agent's `tool_call` to the
+/// `tool_call_update` that completed or failed it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ToolSpan {
+    #[serde(rename = "callID")]
+    pub call_id: String,
+    pub tool: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    /// `running`, then the agent's final status (`completed`, `failed`), or
+    /// `interrupted` if the turn ended first.
+    pub status: String,
+    pub start: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<i64>,
+    /// Size of the latest output the agent reported, in bytes.
+    pub output_bytes: usize,
+    /// `tool_call_update` notifications received for the call.
+    pub updates: u32,
+}
+
+/// The tool calls of a turn in start order, for
+/// `GET /session/:id/turn/:turnID/timeline`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub(crate) struct ToolTimeline {
+    pub tools: Vec<ToolSpan>,
+}
+
+impl ToolTimeline {
+    /// Record a `tool_call`; `false` if the call was already announced.
+    pub(crate) fn tool_started(
+        &mut self,
+        call_id: &str,
+        tool: &str,
+        kind: Option<&str>,
+        now: i64,
+    ) -> bool {
+        // Some agents resend `tool_call` for a call they already announced.
+        if self.tools.iter().any(|span| span.call_id == call_id) {
+            return false;
+        }
+        self.tools.push(ToolSpan {
+            call_id: call_id.to_string(),
+            tool: tool.to_string(),
+            kind: kind.map(str::to_string),
+            status: "running".to_string(),
+            start: now,
+            end: None,
+            duration_ms: None,
+            output_bytes: 0,
+            updates: 0,
+        });
+        true
+    }
+
+    pub(crate) fn span(&self, call_id: &str) -> Option<&ToolSpan> {
+        self.tools.iter().find(|span| span.call_id == call_id)
+    }
+
+    /// Record a `tool_call_update`. A call the timeline has not seen start
+    /// (e.g. one announced before the turn) starts at `now`. Returns the span
+    /// if this update completed or failed it.
+    pub(crate) fn tool_updated(
+        &mut self,
+        call_id: &str,
+        status: Option<&str>,
+        output_bytes: Option<usize>,
+        now: i64,
+    ) -> Option<ToolSpan> {
+        if !self.tools.iter().any(|span| span.call_id == call_id) {
+            self.tool_started(call_id, "", None, now);
+        }
+        let span = self.tools.iter_mut().find(|span| span.call_id == call_id)?;
+        span.updates += 1;
+        if let Some(bytes) = output_bytes {
+            span.output_bytes = bytes;
+        }
+        match status {
+            Some(status @ ("completed" | "failed")) if span.end.is_none() => {
+                span.status = status.to_string();
+                span.end = Some(now);
+                span.duration_ms = Some(now - span.start);
+                return Some(span.clone());
+            }
+            Some(status) if span.end.is_none() => span.status = status.to_string(),
+            _ => {}
+        }
+        None
+    }
+
+    /// Close the calls still open when the turn ends, returning them.
+    pub(crate) fn finish(&mut self, now: i64) -> Vec<ToolSpan> {
+        let mut interrupted = Vec::new();
+        for span in self.tools.iter_mut().filter(|span| span.end.is_none()) {
+            span.status = "interrupted".to_string();
+            span.end = Some(now);
+            span.duration_ms = Some(now - span.start);
+            interrupted.push(span.clone());
+        }
+        interrupted
+    }
+
+    /// Total time spent in tool calls, counting overlapping calls once.
+    pub(crate) fn busy_ms(&self) -> i64 {
+        let mut spans: Vec<(i64, i64)> = self
+            .tools
+            .iter()
+            .filter_map(|span| Some((span.start, span.end?)))
+            .collect();
+        spans.sort_unstable();
+        let mut busy = 0;
+        let mut covered_until = i64::MIN;
+        for (start, end) in spans {
+            let start = start.max(covered_until);
+            if end > start {
+                busy += end - start;
+            }
+            covered_until = covered_until.max(end);
+        }
+        busy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_run_from_call_to_final_update() {
+        let mut timeline = ToolTimeline::default();
+        assert!(timeline.tool_started("call_1", "Read", Some("read"), 100));
+        assert!(!timeline.tool_started("call_1", "Read", Some("read"), 105));
+        assert!(timeline
+            .tool_updated("call_1", Some("in_progress"), None, 110)
+            .is_none());
+        let ended = timeline.tool_updated("call_1", Some("completed"), Some(2048), 160);
+        assert_eq!(ended.and_then(|span| span.duration_ms), Some(60));
+        assert!(timeline
+            .tool_updated("call_1", Some("completed"), None, 170)
+            .is_none());
+        timeline.tool_updated("call_2", Some("failed"), Some(12), 170);
+
+        let read = &timeline.tools[0];
+        assert_eq!(read.status, "completed");
+        assert_eq!(
+            (read.start, read.end, read.duration_ms),
+            (100, Some(160), Some(60))
+        );
+        assert_eq!((read.output_bytes, read.updates), (2048, 3));
+        assert_eq!(timeline.tools[1].status, "failed");
+        assert_eq!(timeline.tools[1].duration_ms, Some(0));
+    }
+
+    #[test]
+    fn finish_interrupts_open_calls_and_busy_time_merges_overlaps() {
+        let mut timeline = ToolTimeline::default();
+        timeline.tool_started("a", "Bash", None, 0);
+        timeline.tool_started("b", "Grep", None, 50);
+        timeline.tool_updated("b", Some("completed"), None, 80);
+        timeline.tool_started("c", "Read", None, 200);
+        timeline.tool_updated("c", Some("completed"), None, 210);
+        let interrupted = timeline.finish(100);
+
+        assert_eq!(interrupted.len(), 1);
+        assert_eq!(interrupted[0].call_id, "a");
+        assert_eq!(timeline.tools[0].status, "interrupted");
+        assert_eq!(timeline.tools[0].duration_ms, Some(100));
+        assert_eq!(timeline.busy_ms(), 110);
+    }
+}
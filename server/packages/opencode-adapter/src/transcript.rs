@@ -0,0 +1,171 @@
+//! `GET /session/:id/transcript.ndjson`: a session's history rebuilt from
+//! its persisted event log and rendered by a [`TranscriptFormat`], e.g. the
+//! universal event schema.
+
+use serde_json::{json, Value};
+
+use crate::SessionLifecycle;
+
+/// Renders one session's transcript. Fed the OpenCode events rebuilt from
+/// the session's event log, in order from the first, each with the time
+/// (ms) its envelope was persisted.
+pub trait TranscriptTranslator: Send {
+    fn translate(&mut self, event: &Value, created_at: i64) -> Vec<Value>;
+}
+
+/// The format of `/session/:id/transcript.ndjson`; each value a translator
+/// returns becomes one line. Without one the route answers 404.
+pub trait TranscriptFormat: Send + Sync + 'static {
+    fn translator(&self, session_id: &str) -> Box<dyn TranscriptTranslator>;
+}
+
+/// Rebuilds the OpenCode events a session's persisted envelopes stand for,
+/// fed in log order. The log keeps whole messages rather than their
+/// streamed deltas, so a transcript has none. A turn is named after the
+/// user message that started it.
+#[derive(Debug, Default)]
+pub(crate) struct EnvelopeEvents {
+    turn: Option<Value>,
+}
+
+impl EnvelopeEvents {
+    pub(crate) fn events(&mut self, session_id: &str, payload: &Value) -> Vec<Value> {
+        let params = &payload["params"];
+        let event = |event_type: &str, properties: Value| {
+            let mut properties = properties;
+            if let Some(properties) = properties.as_object_mut() {
+                properties
+                    .entry("sessionID")
+                    .or_insert_with(|| json!(session_id));
+            }
+            json!({"type": event_type, "properties": properties})
+        };
+        let method = payload["method"].as_str().unwrap_or_default();
+        match method {
+            "session/prompt" | "_sandboxagent/opencode/message" => {
+                let message = &params["message"];
+                if !message.is_object() {
+                    return Vec::new();
+                }
+                let mut events = Vec::new();
+                if method == "session/prompt" && self.turn.is_none() {
+                    self.turn = Some(message["info"]["id"].clone());
+                    events.push(event("turn.started", json!({"id": self.turn})));
+                }
+                events.push(event("message.updated", json!({"info": message["info"]})));
+                events.extend(
+                    message["parts"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .map(|part| event("message.part.updated", json!({"part": part}))),
+                );
+                events
+            }
+            "_sandboxagent/opencode/status" => {
+                let state = SessionLifecycle::from_status_params(params);
+                match self.turn.take_if(|_| state.ends_turn()) {
+                    Some(turn) => vec![event(
+                        "turn.completed",
+                        json!({
+                            "id": turn,
+                            "status": if state == SessionLifecycle::Errored { "error" } else { "completed" },
+                        }),
+                    )],
+                    None => Vec::new(),
+                }
+            }
+            "_sandboxagent/opencode/permission_asked" => {
+                vec![event("permission.asked", params["request"].clone())]
+            }
+            "_sandboxagent/opencode/permission_replied" => vec![event(
+                "permission.replied",
+                json!({"requestID": params["requestID"], "reply": params["reply"]}),
+            )],
+            "_sandboxagent/opencode/question_asked" => {
+                vec![event("question.asked", params["request"].clone())]
+            }
+            "_sandboxagent/opencode/question_replied" => vec![event(
+                "question.replied",
+                json!({"requestID": params["requestID"], "answers": params["answers"]}),
+            )],
+            "_sandboxagent/opencode/question_rejected" => vec![event(
+                "question.rejected",
+                json!({"requestID": params["requestID"]}),
+            )],
+            "_sandboxagent/opencode/error" => vec![event(
+                "session.error",
+                json!({
+                    "error": {
+                        "name": "UnknownError",
+                        "data": {"message": params["message"]},
+                    }
+                }),
+            )],
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebuilds_events_from_envelopes() {
+        let message = json!({
+            "jsonrpc": "2.0",
+            "method": "_sandboxagent/opencode/message",
+            "params": {"message": {
+                "info": {"id": "msg_1", "sessionID": "ses_1", "role": "assistant"},
+                "parts": [{"id": "prt_1", "sessionID": "ses_1", "type": "text", "text": "hi"}],
+            }},
+        });
+        let mut rebuilt = EnvelopeEvents::default();
+        let events = rebuilt.events("ses_1", &message);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["type"], "message.updated");
+        assert_eq!(events[0]["properties"]["info"]["id"], "msg_1");
+        assert_eq!(events[1]["type"], "message.part.updated");
+        assert_eq!(events[1]["properties"]["part"]["id"], "prt_1");
+
+        let replied = json!({
+            "jsonrpc": "2.0",
+            "method": "_sandboxagent/opencode/permission_replied",
+            "params": {"requestID": "per_1", "reply": "once"},
+        });
+        assert_eq!(
+            rebuilt.events("ses_1", &replied),
+            vec![json!({
+                "type": "permission.replied",
+                "properties": {"requestID": "per_1", "reply": "once", "sessionID": "ses_1"},
+            })]
+        );
+
+        let context = json!({
+            "jsonrpc": "2.0",
+            "method": "_sandboxagent/opencode/context_added",
+            "params": {"item": {}},
+        });
+        assert!(rebuilt.events("ses_1", &context).is_empty());
+
+        // A prompt starts a turn and the next idle status ends it.
+        let prompt = json!({
+            "jsonrpc": "2.0",
+            "method": "session/prompt",
+            "params": {"message": {"info": {"id": "msg_2", "role": "user"}, "parts": []}},
+        });
+        let idle = json!({
+            "jsonrpc": "2.0",
+            "method": "_sandboxagent/opencode/status",
+            "params": {"status": "idle", "state": "idle"},
+        });
+        let events = rebuilt.events("ses_1", &prompt);
+        assert_eq!(events[0]["type"], "turn.started");
+        assert_eq!(events[0]["properties"]["id"], "msg_2");
+        let events = rebuilt.events("ses_1", &idle);
+        assert_eq!(events[0]["type"], "turn.completed");
+        assert_eq!(events[0]["properties"]["status"], "completed");
+        assert!(rebuilt.events("ses_1", &idle).is_empty());
+    }
+}
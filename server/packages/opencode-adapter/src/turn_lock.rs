@@ -0,0 +1,203 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+/// Why a prompt could not take its session.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum TurnLockError {
+    #[error("session {session_id} is busy with turn {turn_id}; wait for it to finish or pass ?queue=true")]
+    Busy { session_id: String, turn_id: String },
+}
+
+/// The turn holding a session and the turns queued behind it, each with the
+/// sender that hands it the session.
+struct SessionTurns {
+    running: String,
+    queued: VecDeque<(String, oneshot::Sender<TurnLock>)>,
+}
+
+type Sessions = Arc<Mutex<HashMap<String, SessionTurns>>>;
+
+/// Per-session turn locks: a session runs one prompt turn at a time, and
+/// queued turns start in the order they were submitted.
+#[derive(Default)]
+pub(crate) struct TurnLocks {
+    sessions: Sessions,
+}
+
+/// A session held by a turn, handed to the next queued turn when dropped, so
+/// a turn that panics or a handler whose request went away gives it up too.
+pub(crate) struct TurnLock {
+    sessions: Option<Sessions>,
+    session_id: String,
+}
+
+/// What [`TurnLocks::claim`] got a turn.
+pub(crate) enum LockClaim {
+    Held(TurnLock),
+    /// Another turn holds the session; the receiver gets it once this turn
+    /// is next.
+    Queued(oneshot::Receiver<TurnLock>),
+}
+
+impl TurnLocks {
+    /// Make `turn_id` the session's running turn. If another turn holds the
+    /// session, fail, or with `queue` line the turn up behind it.
+    pub(crate) fn claim(
+        &self,
+        session_id: &str,
+        turn_id: &str,
+        queue: bool,
+    ) -> Result<LockClaim, TurnLockError> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|err| err.into_inner());
+        let Some(turns) = sessions.get_mut(session_id) else {
+            sessions.insert(
+                session_id.to_string(),
+                SessionTurns {
+                    running: turn_id.to_string(),
+                    queued: VecDeque::new(),
+                },
+            );
+            return Ok(LockClaim::Held(TurnLock {
+                sessions: Some(self.sessions.clone()),
+                session_id: session_id.to_string(),
+            }));
+        };
+        if !queue {
+            return Err(TurnLockError::Busy {
+                session_id: session_id.to_string(),
+                turn_id: turns.running.clone(),
+            });
+        }
+        let (start, started) = oneshot::channel();
+        turns.queued.push_back((turn_id.to_string(), start));
+        Ok(LockClaim::Queued(started))
+    }
+}
+
+impl LockClaim {
+    pub(crate) fn is_queued(&self) -> bool {
+        matches!(self, Self::Queued(_))
+    }
+
+    /// Wait for the session; `None` if the locks went away with the adapter.
+    pub(crate) async fn held(self) -> Option<TurnLock> {
+        match self {
+            Self::Held(lock) => Some(lock),
+            Self::Queued(started) => started.await.ok(),
+        }
+    }
+}
+
+impl Drop for TurnLock {
+    /// Start the session's next queued turn, or free the session.
+    fn drop(&mut self) {
+        let Some(shared) = self.sessions.take() else {
+            return;
+        };
+        let mut sessions = shared.lock().unwrap_or_else(|err| err.into_inner());
+        let Some(turns) = sessions.get_mut(&self.session_id) else {
+            return;
+        };
+        // A queued turn whose task is gone cannot start; skip it. One that
+        // goes away after being handed the session drops it, which frees it
+        // again.
+        while let Some((turn_id, start)) = turns.queued.pop_front() {
+            let lock = TurnLock {
+                sessions: Some(shared.clone()),
+                session_id: self.session_id.clone(),
+            };
+            match start.send(lock) {
+                Ok(()) => {
+                    turns.running = turn_id;
+                    return;
+                }
+                Err(mut lock) => lock.sessions = None,
+            }
+        }
+        sessions.remove(&self.session_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(claim: LockClaim) -> TurnLock {
+        match claim {
+            LockClaim::Held(lock) => lock,
+            LockClaim::Queued(_) => panic!("expected to hold the session"),
+        }
+    }
+
+    fn queued(claim: LockClaim) -> oneshot::Receiver<TurnLock> {
+        match claim {
+            LockClaim::Queued(started) => started,
+            LockClaim::Held(_) => panic!("expected to wait for the session"),
+        }
+    }
+
+    #[test]
+    fn a_second_turn_conflicts_without_queue() {
+        let locks = TurnLocks::default();
+        let first = held(locks.claim("ses_1", "turn_1", false).unwrap());
+        assert_eq!(
+            locks.claim("ses_1", "turn_2", false).err(),
+            Some(TurnLockError::Busy {
+                session_id: "ses_1".to_string(),
+                turn_id: "turn_1".to_string(),
+            })
+        );
+        held(locks.claim("ses_2", "turn_3", false).unwrap());
+        drop(first);
+        held(locks.claim("ses_1", "turn_4", false).unwrap());
+    }
+
+    #[test]
+    fn queued_turns_start_in_order() {
+        let locks = TurnLocks::default();
+        let first = held(locks.claim("ses_1", "turn_1", true).unwrap());
+        let mut second = queued(locks.claim("ses_1", "turn_2", true).unwrap());
+        let third = queued(locks.claim("ses_1", "turn_3", true).unwrap());
+        assert!(second.try_recv().is_err());
+
+        drop(first);
+        let second = second.try_recv().expect("turn_2 starts");
+        let busy = locks.claim("ses_1", "turn_4", false).err();
+        assert!(matches!(busy, Some(TurnLockError::Busy { turn_id, .. }) if turn_id == "turn_2"));
+
+        // turn_3 was dropped before it could start, so the session frees up.
+        drop(third);
+        drop(second);
+        held(locks.claim("ses_1", "turn_5", false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_holder_that_panics_hands_the_session_on() {
+        let locks = TurnLocks::default();
+        let first = locks.claim("ses_1", "turn_1", true).unwrap();
+        let second = locks.claim("ses_1", "turn_2", true).unwrap();
+        assert!(second.is_queued());
+
+        let panicked = tokio::spawn(async move {
+            let _lock = first.held().await;
+            panic!("turn failed");
+        })
+        .await;
+        assert!(panicked.is_err());
+        let second = tokio::time::timeout(std::time::Duration::from_secs(1), second.held())
+            .await
+            .expect("the queued turn starts")
+            .expect("handed the session");
+
+        // A handler dropped mid-request frees the session as well.
+        drop(second);
+        let handler = async {
+            let _lock = held(locks.claim("ses_1", "rollback_1", false).unwrap());
+            std::future::pending::<()>().await;
+        };
+        drop(tokio::time::timeout(std::time::Duration::from_millis(10), handler).await);
+        held(locks.claim("ses_1", "turn_3", false).unwrap());
+    }
+}
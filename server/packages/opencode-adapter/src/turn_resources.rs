@@ -0,0 +1,317 @@
+//! Resource usage of the agent processes behind a session while a turn runs,
+//! sampled from `/proc`: CPU time and disk writes of the process tree, and
+//! its peak resident memory. The tree is walked from the agents down through
+//! `/proc/<pid>/task/<tid>/children`, so a sample reads only its own
+//! processes. Elsewhere than Linux nothing is sampled.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+/// Clock ticks per second of the CPU times in `/proc/<pid>/stat`. Linux
+/// reports them in `USER_HZ`, which is 100 on every architecture it exports
+/// to userspace.
+const USER_HZ: u64 = 100;
+
+/// A process as `(pid, start time)`, which stays unique when the kernel
+/// hands a finished process's pid to a new one.
+type ProcessKey = (u32, u64);
+
+/// One process of a sampled tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ProcessSample {
+    pub pid: u32,
+    pub ppid: u32,
+    /// When the process started, in ticks since boot.
+    pub start_time: u64,
+    /// CPU time of the process and of the children it reaped, in ticks.
+    pub cpu_ticks: u64,
+    pub rss_bytes: u64,
+    /// Bytes the process and the children it reaped caused to be written to
+    /// storage.
+    pub write_bytes: u64,
+}
+
+/// The processes descending from `roots`, roots included.
+pub(crate) fn sample_tree(roots: &[u32]) -> Vec<ProcessSample> {
+    let read = |pid: u32, file: &str| std::fs::read_to_string(format!("/proc/{pid}/{file}")).ok();
+    let mut seen = HashSet::new();
+    let mut pending = roots.to_vec();
+    let mut processes = Vec::new();
+    while let Some(pid) = pending.pop() {
+        if !seen.insert(pid) {
+            continue;
+        }
+        let Some((ppid, cpu_ticks, start_time)) =
+            read(pid, "stat").and_then(|stat| parse_stat(&stat))
+        else {
+            continue;
+        };
+        pending.extend(children(pid));
+        processes.push(ProcessSample {
+            pid,
+            ppid,
+            start_time,
+            cpu_ticks,
+            rss_bytes: read(pid, "status")
+                .and_then(|status| parse_status_rss_bytes(&status))
+                .unwrap_or(0),
+            write_bytes: read(pid, "io")
+                .and_then(|io| parse_io_write_bytes(&io))
+                .unwrap_or(0),
+        });
+    }
+    processes
+}
+
+/// The children of every thread of `pid`.
+fn children(pid: u32) -> Vec<u32> {
+    let Ok(tasks) = std::fs::read_dir(format!("/proc/{pid}/task")) else {
+        return Vec::new();
+    };
+    tasks
+        .flatten()
+        .filter_map(|task| std::fs::read_to_string(task.path().join("children")).ok())
+        .flat_map(|children| parse_children(&children))
+        .collect()
+}
+
+fn parse_children(children: &str) -> Vec<u32> {
+    children
+        .split_whitespace()
+        .filter_map(|pid| pid.parse().ok())
+        .collect()
+}
+
+/// `(ppid, utime + stime + cutime + cstime, starttime)` from
+/// `/proc/<pid>/stat`.
+fn parse_stat(stat: &str) -> Option<(u32, u64, u64)> {
+    // The command name is parenthesized and may itself contain spaces and
+    // parentheses, so fields are counted from the last `)`.
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let field = |index: usize| fields.get(index)?.parse::<u64>().ok();
+    let ppid = field(1)? as u32;
+    let cpu_ticks = field(11)? + field(12)? + field(13)? + field(14)?;
+    Some((ppid, cpu_ticks, field(19)?))
+}
+
+fn parse_io_write_bytes(io: &str) -> Option<u64> {
+    io.lines()
+        .find_map(|line| line.strip_prefix("write_bytes:"))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// `VmRSS` from `/proc/<pid>/status`, in bytes; absent for zombies.
+fn parse_status_rss_bytes(status: &str) -> Option<u64> {
+    let kb = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .split_whitespace()
+        .next()?
+        .parse::<u64>()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+/// Usage of one turn, accumulated over the samples taken while it ran.
+#[derive(Debug, Default)]
+pub(crate) struct TurnResources {
+    /// `(cpu_ticks, write_bytes)` of the processes running as the turn
+    /// started, which count from there.
+    baseline: Option<HashMap<ProcessKey, (u64, u64)>>,
+    /// `(ppid, cpu_ticks, write_bytes)` each process of the latest sample
+    /// used during the turn.
+    live: HashMap<ProcessKey, (u32, u64, u64)>,
+    /// Usage of processes that left the tree without a parent in it to reap
+    /// them, which would otherwise count it. One that shows up again, say
+    /// reparented to a subreaper in the tree, counts as live instead.
+    retired: HashMap<ProcessKey, (u64, u64)>,
+    peak_rss_bytes: u64,
+    peak_processes: usize,
+    samples: u32,
+}
+
+/// [`TurnResources`] as reported on turns.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TurnResourceUsage {
+    pub cpu_seconds: f64,
+    pub peak_rss_bytes: u64,
+    /// Bytes the agent's processes caused to be written to storage.
+    pub disk_write_bytes: u64,
+    /// Size of the files the turn created or modified in the session
+    /// directory; absent unless turn artifacts are tracked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workspace_write_bytes: Option<u64>,
+    pub peak_processes: usize,
+    pub samples: u32,
+}
+
+impl TurnResources {
+    /// Record a sample of the agent's process tree; the first one is the
+    /// baseline the turn's usage counts from.
+    pub(crate) fn record(&mut self, processes: &[ProcessSample]) {
+        self.samples += 1;
+        let baseline = self.baseline.get_or_insert_with(|| {
+            processes
+                .iter()
+                .map(|process| (key(process), (process.cpu_ticks, process.write_bytes)))
+                .collect()
+        });
+        let current: HashMap<ProcessKey, (u32, u64, u64)> = processes
+            .iter()
+            .map(|process| {
+                let (cpu_base, write_base) = baseline.get(&key(process)).copied().unwrap_or((0, 0));
+                (
+                    key(process),
+                    (
+                        process.ppid,
+                        process.cpu_ticks.saturating_sub(cpu_base),
+                        process.write_bytes.saturating_sub(write_base),
+                    ),
+                )
+            })
+            .collect();
+        // A process that is gone was reaped; its usage moved to its parent's
+        // totals when the parent is still sampled.
+        let parent_sampled = |ppid: u32| processes.iter().any(|process| process.pid == ppid);
+        for (process, (ppid, cpu, write)) in &self.live {
+            if !current.contains_key(process) && !parent_sampled(*ppid) {
+                self.retired.insert(*process, (*cpu, *write));
+            }
+        }
+        self.retired
+            .retain(|process, _| !current.contains_key(process));
+        self.live = current;
+        self.peak_rss_bytes = self
+            .peak_rss_bytes
+            .max(processes.iter().map(|process| process.rss_bytes).sum());
+        self.peak_processes = self.peak_processes.max(processes.len());
+    }
+
+    /// The usage so far; `None` until a sample found an agent process.
+    pub(crate) fn usage(&self) -> Option<TurnResourceUsage> {
+        if self.peak_processes == 0 {
+            return None;
+        }
+        let (cpu_ticks, write_bytes) = self
+            .live
+            .values()
+            .map(|(_, cpu, write)| (*cpu, *write))
+            .chain(self.retired.values().copied())
+            .fold((0, 0), |(cpu, write), (process_cpu, process_write)| {
+                (cpu + process_cpu, write + process_write)
+            });
+        Some(TurnResourceUsage {
+            cpu_seconds: cpu_ticks as f64 / USER_HZ as f64,
+            peak_rss_bytes: self.peak_rss_bytes,
+            disk_write_bytes: write_bytes,
+            workspace_write_bytes: None,
+            peak_processes: self.peak_processes,
+            samples: self.samples,
+        })
+    }
+}
+
+fn key(process: &ProcessSample) -> ProcessKey {
+    (process.pid, process.start_time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, ppid: u32, cpu_ticks: u64, rss_bytes: u64) -> ProcessSample {
+        ProcessSample {
+            pid,
+            ppid,
+            start_time: u64::from(pid),
+            cpu_ticks,
+            rss_bytes,
+            write_bytes: cpu_ticks * 1000,
+        }
+    }
+
+    #[test]
+    fn parses_proc_files() {
+        let stat = "4242 (node (agent) x) S 4100 4242 4242 0 -1 4194560 2046 0 0 0 \
+                    150 30 7 3 20 0 11 0 123456 1073741824 5120 18446744073709551615";
+        assert_eq!(parse_stat(stat), Some((4100, 190, 123456)));
+        assert_eq!(parse_children("4243 4250 "), vec![4243, 4250]);
+        let status = "Name:\tnode\nVmPeak:\t  900 kB\nVmRSS:\t  2048 kB\n";
+        assert_eq!(parse_status_rss_bytes(status), Some(2048 * 1024));
+        let io = "rchar: 10\nwchar: 20\nread_bytes: 4096\nwrite_bytes: 8192\n";
+        assert_eq!(parse_io_write_bytes(io), Some(8192));
+    }
+
+    #[test]
+    fn counts_usage_from_the_turn_start_through_reaped_children() {
+        let mut usage = TurnResources::default();
+        assert_eq!(usage.usage(), None);
+
+        usage.record(&[process(10, 1, 500, 100)]);
+        usage.record(&[
+            process(10, 1, 520, 150),
+            process(11, 10, 40, 300),
+            process(12, 11, 10, 50),
+        ]);
+        // 11 exits and 10 reaps it; 12 is orphaned and exits unreaped.
+        usage.record(&[process(10, 1, 570, 120)]);
+
+        let usage = usage.usage().expect("sampled");
+        // 70 ticks of 10 (reaped 11 included) plus 10 of the orphan.
+        assert_eq!(usage.cpu_seconds, 0.8);
+        assert_eq!(usage.disk_write_bytes, 80_000);
+        assert_eq!(usage.peak_rss_bytes, 500);
+        assert_eq!(usage.peak_processes, 3);
+        assert_eq!(usage.samples, 3);
+    }
+
+    #[test]
+    fn counts_reparented_and_reused_pids_once() {
+        let mut usage = TurnResources::default();
+        usage.record(&[process(10, 1, 0, 100), process(11, 10, 0, 100)]);
+        usage.record(&[process(10, 1, 5, 100), process(11, 10, 20, 100)]);
+        // 10 exits without reaping 11, which is adopted by a subreaper
+        // outside the tree, then by 20 in it.
+        usage.record(&[process(20, 1, 0, 100)]);
+        usage.record(&[process(20, 1, 0, 100), process(11, 20, 30, 100)]);
+        let reparented = usage.usage().expect("sampled");
+        assert_eq!(reparented.cpu_seconds, 0.35);
+
+        // 20 reaps 11, and the kernel hands its pid to a new process, which
+        // counts from 0.
+        let reused = ProcessSample {
+            start_time: 500,
+            ..process(11, 20, 4, 100)
+        };
+        usage.record(&[process(20, 1, 30, 100), reused]);
+        let usage = usage.usage().expect("sampled");
+        assert_eq!(usage.cpu_seconds, 0.39);
+    }
+
+    #[test]
+    fn samples_the_current_process_tree() {
+        if !std::path::Path::new("/proc/self/stat").exists() {
+            return;
+        }
+        let mut child = std::process::Command::new("sleep")
+            .arg("5")
+            .spawn()
+            .expect("spawn sleep");
+        let processes = sample_tree(&[std::process::id()]);
+        let _ = child.kill();
+        let _ = child.wait();
+        let own = processes
+            .iter()
+            .find(|process| process.pid == std::process::id())
+            .expect("own process sampled");
+        assert!(own.rss_bytes > 0);
+        let sleep = processes
+            .iter()
+            .find(|process| process.pid == child.id())
+            .expect("child sampled");
+        assert_eq!(sleep.ppid, std::process::id());
+        assert!(sleep.start_time >= own.start_time);
+    }
+}
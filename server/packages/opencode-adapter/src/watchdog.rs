@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use crate::archive::env_nonempty;
+
+const DEFAULT_WATCHDOG_INTERVAL_SECS: u64 = 30;
+const DEFAULT_WATCHDOG_STALE_SECS: u64 = 120;
+
+/// When the session watchdog runs and how long a busy session may go without
+/// activity before it is checked.
+#[derive(Debug, Clone)]
+pub struct SessionWatchdogConfig {
+    pub interval: Duration,
+    pub stale_after: Duration,
+}
+
+impl Default for SessionWatchdogConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(DEFAULT_WATCHDOG_INTERVAL_SECS),
+            stale_after: Duration::from_secs(DEFAULT_WATCHDOG_STALE_SECS),
+        }
+    }
+}
+
+impl SessionWatchdogConfig {
+    /// Build from `SANDBOX_AGENT_WATCHDOG_INTERVAL_SECS` and
+    /// `SANDBOX_AGENT_WATCHDOG_STALE_SECS`. An interval of `0` disables the
+    /// watchdog.
+    pub fn from_env() -> Option<Self> {
+        let env_secs = |key: &str| env_nonempty(key).and_then(|value| value.parse::<u64>().ok());
+        let interval = env_secs("SANDBOX_AGENT_WATCHDOG_INTERVAL_SECS")
+            .unwrap_or(DEFAULT_WATCHDOG_INTERVAL_SECS);
+        if interval == 0 {
+            return None;
+        }
+        Some(Self {
+            interval: Duration::from_secs(interval),
+            stale_after: Duration::from_secs(
+                env_secs("SANDBOX_AGENT_WATCHDOG_STALE_SECS")
+                    .unwrap_or(DEFAULT_WATCHDOG_STALE_SECS),
+            ),
+        })
+    }
+}
+
+/// What the watchdog knows about a busy session.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BusySession {
+    /// Time since the session last persisted an event.
+    pub idle_for: Duration,
+    /// Whether a prompt request for the session is still running.
+    pub turn_running: bool,
+    /// Whether the session is waiting on a permission or question reply.
+    pub waiting_on_user: bool,
+    /// Whether the session's agent process is running; `None` when the
+    /// liveness is unknown (e.g. the mock agent, or a session still
+    /// bootstrapping).
+    pub agent_running: Option<bool>,
+}
+
+/// Why a busy session is orphaned, or `None` if it may still finish.
+pub(crate) fn orphan_reason(session: BusySession, stale_after: Duration) -> Option<&'static str> {
+    if session.idle_for < stale_after {
+        return None;
+    }
+    if session.agent_running == Some(false) {
+        return Some("agent_exited");
+    }
+    if !session.turn_running && !session.waiting_on_user {
+        return Some("no_active_turn");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STALE: Duration = Duration::from_secs(120);
+
+    fn busy(idle_secs: u64) -> BusySession {
+        BusySession {
+            idle_for: Duration::from_secs(idle_secs),
+            turn_running: false,
+            waiting_on_user: false,
+            agent_running: Some(true),
+        }
+    }
+
+    #[test]
+    fn recent_activity_is_never_orphaned() {
+        let session = BusySession {
+            agent_running: Some(false),
+            ..busy(5)
+        };
+        assert_eq!(orphan_reason(session, STALE), None);
+    }
+
+    #[test]
+    fn stale_sessions_need_a_running_turn_or_a_pending_reply() {
+        assert_eq!(orphan_reason(busy(300), STALE), Some("no_active_turn"));
+        let running = BusySession {
+            turn_running: true,
+            ..busy(300)
+        };
+        assert_eq!(orphan_reason(running, STALE), None);
+        let waiting = BusySession {
+            waiting_on_user: true,
+            ..busy(300)
+        };
+        assert_eq!(orphan_reason(waiting, STALE), None);
+    }
+
+    #[test]
+    fn exited_agents_orphan_even_a_waiting_session() {
+        let session = BusySession {
+            turn_running: true,
+            waiting_on_user: true,
+            agent_running: Some(false),
+            ..busy(300)
+        };
+        assert_eq!(orphan_reason(session, STALE), Some("agent_exited"));
+    }
+}
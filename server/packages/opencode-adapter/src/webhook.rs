@@ -0,0 +1,218 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::archive::env_nonempty;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_INITIAL_BACKOFF_MS: u64 = 500;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) const SIGNATURE_HEADER: &str = "x-sandbox-agent-signature";
+pub(crate) const TIMESTAMP_HEADER: &str = "x-sandbox-agent-timestamp";
+pub(crate) const EVENT_HEADER: &str = "x-sandbox-agent-event";
+pub(crate) const DELIVERY_HEADER: &str = "x-sandbox-agent-delivery";
+
+/// An HTTP endpoint that receives `permission.asked` and `question.asked`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookTarget {
+    pub url: String,
+    /// Shared secret used to sign every delivery; see [`sign_payload`].
+    pub secret: String,
+}
+
+/// Webhook targets and delivery policy.
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub targets: Vec<WebhookTarget>,
+    /// Total delivery attempts per target, including the first.
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubled after each failed attempt.
+    pub initial_backoff: Duration,
+}
+
+impl WebhookConfig {
+    /// Build from `SANDBOX_AGENT_WEBHOOK_URL`/`SANDBOX_AGENT_WEBHOOK_SECRET`
+    /// and/or `SANDBOX_AGENT_WEBHOOKS` (a JSON array of `{url, secret}`).
+    /// Returns `None` when no target is configured.
+    pub fn from_env() -> Option<Self> {
+        let mut targets = Vec::new();
+        if let Some(raw) = env_nonempty("SANDBOX_AGENT_WEBHOOKS") {
+            match serde_json::from_str::<Vec<WebhookTarget>>(&raw) {
+                Ok(parsed) => targets.extend(parsed),
+                Err(err) => tracing::warn!(?err, "ignoring invalid SANDBOX_AGENT_WEBHOOKS"),
+            }
+        }
+        if let Some(url) = env_nonempty("SANDBOX_AGENT_WEBHOOK_URL") {
+            match env_nonempty("SANDBOX_AGENT_WEBHOOK_SECRET") {
+                Some(secret) => targets.push(WebhookTarget { url, secret }),
+                None => tracing::warn!(
+                    "SANDBOX_AGENT_WEBHOOK_URL is set without SANDBOX_AGENT_WEBHOOK_SECRET; ignoring"
+                ),
+            }
+        }
+        if targets.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            targets,
+            max_attempts: env_nonempty("SANDBOX_AGENT_WEBHOOK_MAX_ATTEMPTS")
+                .and_then(|value| value.parse().ok())
+                .filter(|attempts| *attempts > 0)
+                .unwrap_or(DEFAULT_MAX_ATTEMPTS),
+            initial_backoff: Duration::from_millis(DEFAULT_INITIAL_BACKOFF_MS),
+        })
+    }
+}
+
+/// Signs and posts webhook deliveries, retrying transient failures.
+pub(crate) struct WebhookClient {
+    config: WebhookConfig,
+    http: reqwest::Client,
+}
+
+impl WebhookClient {
+    pub(crate) fn new(config: WebhookConfig) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_else(|_| reqwest::Client::new()),
+        }
+    }
+
+    pub(crate) fn targets(&self) -> &[WebhookTarget] {
+        &self.config.targets
+    }
+
+    /// POST `body` to `target` until it is accepted or attempts run out.
+    /// Connection errors, 408, 429 and 5xx are retried with exponential
+    /// backoff; any other non-2xx status is final. Returns the JSON body of
+    /// the accepted response, if it had one.
+    pub(crate) async fn deliver(
+        &self,
+        target: &WebhookTarget,
+        event_type: &str,
+        delivery_id: &str,
+        body: &[u8],
+    ) -> Result<Option<Value>, String> {
+        let mut backoff = self.config.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+                .to_string();
+            let result = self
+                .http
+                .post(&target.url)
+                .header("content-type", "application/json")
+                .header(EVENT_HEADER, event_type)
+                .header(DELIVERY_HEADER, delivery_id)
+                .header(TIMESTAMP_HEADER, &timestamp)
+                .header(
+                    SIGNATURE_HEADER,
+                    sign_payload(&target.secret, &timestamp, body),
+                )
+                .body(body.to_vec())
+                .send()
+                .await;
+
+            let error = match result {
+                Ok(response) if response.status().is_success() => {
+                    let bytes = response.bytes().await.unwrap_or_default();
+                    return Ok(serde_json::from_slice(&bytes).ok());
+                }
+                Ok(response) if !is_retryable(response.status()) => {
+                    return Err(format!("webhook rejected with {}", response.status()));
+                }
+                Ok(response) => format!("webhook returned {}", response.status()),
+                Err(err) => err.to_string(),
+            };
+            if attempt >= self.config.max_attempts {
+                return Err(format!("{error} after {attempt} attempts"));
+            }
+            tracing::debug!(url = %target.url, attempt, %error, "retrying webhook delivery");
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            attempt += 1;
+        }
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// `sha256=<hex>` HMAC of `"{timestamp}.{body}"` keyed by the target secret.
+/// Receivers recompute it over the raw request body and the
+/// `X-Sandbox-Agent-Timestamp` header.
+pub fn sign_payload(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let digest = signature_mac(secret, timestamp, body)
+        .finalize()
+        .into_bytes();
+    format!("sha256={}", hex::encode(digest))
+}
+
+/// Check an `X-Sandbox-Agent-Signature` header in constant time.
+pub fn verify_signature(secret: &str, timestamp: &str, body: &[u8], signature: &str) -> bool {
+    let Some(expected) = signature
+        .strip_prefix("sha256=")
+        .and_then(|digest| hex::decode(digest).ok())
+    else {
+        return false;
+    };
+    signature_mac(secret, timestamp, body)
+        .verify_slice(&expected)
+        .is_ok()
+}
+
+fn signature_mac(secret: &str, timestamp: &str, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_round_trips() {
+        let body = br#"{"type":"permission.asked"}"#;
+        let signature = sign_payload("secret", "1700000000", body);
+        assert!(signature.starts_with("sha256="));
+        assert!(verify_signature("secret", "1700000000", body, &signature));
+    }
+
+    #[test]
+    fn signature_rejects_tampering() {
+        let body = br#"{"type":"permission.asked"}"#;
+        let signature = sign_payload("secret", "1700000000", body);
+        assert!(!verify_signature("other", "1700000000", body, &signature));
+        assert!(!verify_signature("secret", "1700000001", body, &signature));
+        assert!(!verify_signature("secret", "1700000000", b"{}", &signature));
+        assert!(!verify_signature("secret", "1700000000", body, "sha256=zz"));
+    }
+
+    #[test]
+    fn retries_only_transient_statuses() {
+        assert!(is_retryable(StatusCode::BAD_GATEWAY));
+        assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_retryable(StatusCode::UNAUTHORIZED));
+        assert!(!is_retryable(StatusCode::NOT_FOUND));
+    }
+}
@@ -0,0 +1,288 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use tokio::process::Command;
+
+/// Name and email on shadow commits.
+const SNAPSHOT_IDENTITY: &str = "sandbox-agent";
+const SNAPSHOT_EMAIL: &str = "snapshots@sandbox-agent.invalid";
+/// Commit message line naming the directory a snapshot was taken of.
+const DIRECTORY_PREFIX: &str = "directory: ";
+
+/// A session's shadow repository: a git directory kept outside the session
+/// directory, so snapshots never touch the user's own `.git`. Each turn's
+/// snapshot is a commit under `refs/turns/<turnID>`.
+#[derive(Debug, Clone)]
+pub(crate) struct ShadowRepo {
+    git_dir: PathBuf,
+}
+
+/// What a rollback changed, as paths relative to the session directory.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Restored {
+    /// Files written back to their snapshot content.
+    pub restored: Vec<String>,
+    /// Files created after the snapshot, deleted.
+    pub removed: Vec<String>,
+}
+
+impl ShadowRepo {
+    pub(crate) fn new(git_dir: PathBuf) -> Self {
+        Self { git_dir }
+    }
+
+    /// Record the files under `directory` as the snapshot for `turn_id`.
+    /// Files the directory's `.gitignore` excludes are not recorded. Returns
+    /// the snapshot commit.
+    pub(crate) async fn snapshot(&self, directory: &Path, turn_id: &str) -> Result<String, String> {
+        self.init().await?;
+        let tree = self.stage(directory).await?;
+        let message = format!(
+            "turn {turn_id}\n\n{DIRECTORY_PREFIX}{}",
+            directory.display()
+        );
+        let commit = self
+            .git(None, &["commit-tree", &tree, "-m", &message])
+            .await?;
+        self.git(
+            None,
+            &["update-ref", &format!("refs/turns/{turn_id}"), &commit],
+        )
+        .await?;
+        Ok(commit)
+    }
+
+    /// The snapshot commit for `turn_id` and the directory it was taken of,
+    /// or `None` when the turn has none.
+    pub(crate) async fn find(&self, turn_id: &str) -> Result<Option<(String, PathBuf)>, String> {
+        if !self.git_dir.join("HEAD").is_file() {
+            return Ok(None);
+        }
+        let reference = format!("refs/turns/{turn_id}^{{commit}}");
+        let Ok(commit) = self
+            .git(None, &["rev-parse", "--verify", "--quiet", &reference])
+            .await
+        else {
+            return Ok(None);
+        };
+        let message = self
+            .git(None, &["log", "-1", "--format=%B", &commit])
+            .await?;
+        let directory = message
+            .lines()
+            .find_map(|line| line.strip_prefix(DIRECTORY_PREFIX))
+            .ok_or_else(|| format!("snapshot {commit} does not name its directory"))?;
+        Ok(Some((commit, PathBuf::from(directory))))
+    }
+
+    /// Put the files under `directory` back the way `commit` recorded them:
+    /// changed and deleted files are rewritten and files created since are
+    /// removed. Ignored files are left alone.
+    pub(crate) async fn restore(&self, directory: &Path, commit: &str) -> Result<Restored, String> {
+        let current = self.stage(directory).await?;
+        let diff = self
+            .git(
+                Some(directory),
+                &[
+                    "diff-tree",
+                    "-r",
+                    "-z",
+                    "--no-renames",
+                    "--name-status",
+                    commit,
+                    &current,
+                ],
+            )
+            .await?;
+        let mut restored = Restored::default();
+        let mut fields = diff.split('\0').filter(|field| !field.is_empty());
+        while let (Some(status), Some(path)) = (fields.next(), fields.next()) {
+            if status == "A" {
+                restored.removed.push(path.to_string());
+            } else {
+                restored.restored.push(path.to_string());
+            }
+        }
+
+        self.git(Some(directory), &["read-tree", commit]).await?;
+        self.git(Some(directory), &["checkout-index", "--all", "--force"])
+            .await?;
+        for path in &restored.removed {
+            let file = directory.join(path);
+            match std::fs::remove_file(&file) {
+                Ok(()) => remove_empty_parents(directory, &file),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(format!("failed to remove {path}: {err}")),
+            }
+        }
+        Ok(restored)
+    }
+
+    async fn init(&self) -> Result<(), String> {
+        if self.git_dir.join("HEAD").is_file() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.git_dir).map_err(|err| {
+            format!(
+                "failed to create snapshot repository {}: {err}",
+                self.git_dir.display()
+            )
+        })?;
+        self.git(None, &["init", "--quiet", "--bare"]).await?;
+        // Not bare, but with no work tree of its own: every command that
+        // needs one passes the session directory.
+        self.git(None, &["config", "core.bare", "false"])
+            .await
+            .map(|_| ())
+    }
+
+    /// Sync the shadow index with `directory` and return its tree.
+    async fn stage(&self, directory: &Path) -> Result<String, String> {
+        // Keep the shadow repository out of its own snapshots when it lives
+        // under the session directory.
+        let exclude = self
+            .git_dir
+            .strip_prefix(directory)
+            .ok()
+            .map(|inside| format!(":(exclude){}", inside.display()));
+        let mut args = vec!["add", "--all", "."];
+        args.extend(exclude.as_deref());
+        self.git(Some(directory), &args).await?;
+        self.git(Some(directory), &["write-tree"]).await
+    }
+
+    /// Run git against the shadow repository, with `work_tree` as its work
+    /// tree when given, returning trimmed stdout.
+    async fn git(&self, work_tree: Option<&Path>, args: &[&str]) -> Result<String, String> {
+        let mut command = Command::new("git");
+        command
+            .args(["-c", "commit.gpgsign=false", "-c", "core.autocrlf=false"])
+            .args(args)
+            .env("GIT_DIR", &self.git_dir)
+            .env_remove("GIT_WORK_TREE")
+            .env_remove("GIT_INDEX_FILE");
+        match work_tree {
+            Some(work_tree) => command
+                .current_dir(work_tree)
+                .env("GIT_WORK_TREE", work_tree),
+            None => command.current_dir(&self.git_dir),
+        };
+        let output = command
+            .env("GIT_AUTHOR_NAME", SNAPSHOT_IDENTITY)
+            .env("GIT_AUTHOR_EMAIL", SNAPSHOT_EMAIL)
+            .env("GIT_COMMITTER_NAME", SNAPSHOT_IDENTITY)
+            .env("GIT_COMMITTER_EMAIL", SNAPSHOT_EMAIL)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|err| format!("failed to run git: {err}"))?;
+        if !output.status.success() {
+            return Err(format!(
+                "git {} failed: {}",
+                args.first().copied().unwrap_or_default(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+/// Whether `id` is safe to use in a ref name and a path: session and turn
+/// IDs are ASCII letters, digits, `_` and `-`.
+pub(crate) fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Remove the directories between `file` and `root` that are left empty.
+fn remove_empty_parents(root: &Path, file: &Path) {
+    let mut parent = file.parent();
+    while let Some(dir) = parent {
+        if dir == root || !dir.starts_with(root) || std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+        parent = dir.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "sandbox-agent-snapshot-{name}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("create temp dir");
+        dir
+    }
+
+    #[tokio::test]
+    async fn restores_changed_deleted_and_new_files() {
+        let root = temp_dir("restore");
+        let work = root.join("work");
+        std::fs::create_dir_all(work.join("src")).expect("create work tree");
+        std::fs::write(work.join("keep.txt"), "keep").expect("write");
+        std::fs::write(work.join("edit.txt"), "before").expect("write");
+        std::fs::write(work.join("src/gone.txt"), "gone").expect("write");
+        std::fs::write(work.join(".gitignore"), "ignored.txt\n").expect("write");
+        let repo = ShadowRepo::new(root.join("shadow"));
+        let commit = repo.snapshot(&work, "turn_1").await.expect("snapshot");
+
+        std::fs::write(work.join("edit.txt"), "after").expect("write");
+        std::fs::remove_file(work.join("src/gone.txt")).expect("remove");
+        std::fs::create_dir_all(work.join("new/dir")).expect("create");
+        std::fs::write(work.join("new/dir/file.txt"), "new").expect("write");
+        std::fs::write(work.join("ignored.txt"), "ignored").expect("write");
+
+        let (found, directory) = repo
+            .find("turn_1")
+            .await
+            .expect("find")
+            .expect("snapshot exists");
+        assert_eq!(found, commit);
+        assert_eq!(directory, work);
+        let restored = repo.restore(&work, &commit).await.expect("restore");
+        assert_eq!(restored.restored, vec!["edit.txt", "src/gone.txt"]);
+        assert_eq!(restored.removed, vec!["new/dir/file.txt"]);
+        assert_eq!(
+            std::fs::read_to_string(work.join("edit.txt")).unwrap(),
+            "before"
+        );
+        assert_eq!(
+            std::fs::read_to_string(work.join("src/gone.txt")).unwrap(),
+            "gone"
+        );
+        assert!(!work.join("new").exists());
+        assert!(work.join("ignored.txt").exists());
+        assert!(!work.join(".git").exists());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn unknown_turns_have_no_snapshot_and_the_repo_skips_itself() {
+        let root = temp_dir("missing");
+        let repo = ShadowRepo::new(root.join("shadow"));
+        assert_eq!(repo.find("turn_1").await.expect("find"), None);
+        std::fs::write(root.join("file.txt"), "file").expect("write");
+        let commit = repo.snapshot(&root, "turn_1").await.expect("snapshot");
+        std::fs::write(root.join("file.txt"), "changed").expect("write");
+        let restored = repo.restore(&root, &commit).await.expect("restore");
+        assert_eq!(restored.restored, vec!["file.txt"]);
+        assert!(restored.removed.is_empty());
+        assert_eq!(repo.find("turn_2").await.expect("find"), None);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn ids_must_be_plain() {
+        assert!(valid_id("turn_01-a"));
+        assert!(!valid_id(""));
+        assert!(!valid_id("../turn"));
+        assert!(!valid_id("turn^{commit}"));
+    }
+}
@@ -3,23 +3,36 @@ use std::net::TcpListener;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, ExitStatus, Stdio};
 use std::sync::{Arc, Mutex as StdMutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use reqwest::Client;
 use sandbox_agent_agent_management::agents::{AgentId, AgentManager};
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
 use tokio::time::sleep;
 use tracing::warn;
 
 const HEALTH_ENDPOINTS: [&str; 4] = ["health", "healthz", "app/agents", "agents"];
 const HEALTH_ATTEMPTS: usize = 20;
 const HEALTH_DELAY_MS: u64 = 150;
+const HEALTH_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
 const MONITOR_DELAY_MS: u64 = 500;
 
 #[derive(Debug, Clone)]
 pub struct OpenCodeServerManagerConfig {
     pub log_dir: PathBuf,
     pub auto_restart: bool,
+    /// How often a running sidecar is probed over HTTP.
+    pub health_interval: Duration,
+    /// Consecutive failed probes after which a running sidecar is killed and
+    /// restarted.
+    pub health_failure_threshold: u32,
+    /// Delay before the first restart attempt; doubled after each failure up
+    /// to `restart_backoff_max`.
+    pub restart_backoff_initial: Duration,
+    pub restart_backoff_max: Duration,
+    /// Restart attempts before the sidecar is left `failed`. A later request
+    /// still starts it on demand.
+    pub max_restart_attempts: u32,
 }
 
 impl Default for OpenCodeServerManagerConfig {
@@ -27,6 +40,65 @@ impl Default for OpenCodeServerManagerConfig {
         Self {
             log_dir: default_log_dir(),
             auto_restart: true,
+            health_interval: Duration::from_secs(5),
+            health_failure_threshold: 3,
+            restart_backoff_initial: Duration::from_millis(MONITOR_DELAY_MS),
+            restart_backoff_max: Duration::from_secs(30),
+            max_restart_attempts: 10,
+        }
+    }
+}
+
+/// Lifecycle of the sidecar as reported by [`OpenCodeServerManager::status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SidecarState {
+    /// Not started yet, or shut down.
+    Stopped,
+    Starting,
+    Running,
+    /// Running but failing health probes.
+    Unhealthy,
+    /// Exited unexpectedly; a restart is scheduled.
+    Restarting,
+    /// Could not be started, or gave up restarting.
+    Failed,
+}
+
+impl SidecarState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Stopped => "stopped",
+            Self::Starting => "starting",
+            Self::Running => "running",
+            Self::Unhealthy => "unhealthy",
+            Self::Restarting => "restarting",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SidecarStatus {
+    pub state: SidecarState,
+    pub base_url: Option<String>,
+    /// Times the sidecar was restarted after exiting unexpectedly.
+    pub auto_restarts: u64,
+    /// Restart attempt in progress, counting from 1.
+    pub restart_attempt: u32,
+    /// Delay before the scheduled restart attempt.
+    pub retry_in_ms: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl Default for SidecarStatus {
+    fn default() -> Self {
+        Self {
+            state: SidecarState::Stopped,
+            base_url: None,
+            auto_restarts: 0,
+            restart_attempt: 0,
+            retry_in_ms: None,
+            last_error: None,
         }
     }
 }
@@ -43,13 +115,18 @@ struct Inner {
     config: OpenCodeServerManagerConfig,
     ensure_lock: Mutex<()>,
     state: Mutex<ManagerState>,
+    status: watch::Sender<SidecarStatus>,
 }
 
 #[derive(Debug, Default)]
 struct ManagerState {
     server: Option<RunningServer>,
     restart_count: u64,
+    /// Restarts triggered by the sidecar exiting on its own.
+    auto_restarts: u64,
     shutdown_requested: bool,
+    /// Set while the supervisor waits to restart a crashed sidecar.
+    restarting: bool,
     last_error: Option<String>,
 }
 
@@ -69,10 +146,41 @@ impl OpenCodeServerManager {
                 config,
                 ensure_lock: Mutex::new(()),
                 state: Mutex::new(ManagerState::default()),
+                status: watch::channel(SidecarStatus::default()).0,
             }),
         }
     }
 
+    /// Base URL of the sidecar, starting it if needed. While a crashed sidecar
+    /// is waiting to be restarted, waits up to `wait` for the restart instead
+    /// of racing it.
+    pub async fn ensure_server_within(&self, wait: Duration) -> Result<String, String> {
+        if self.inner.state.lock().await.restarting {
+            let mut status = self.inner.status.subscribe();
+            let deadline = Instant::now() + wait;
+            loop {
+                let current = status.borrow_and_update().clone();
+                match current.state {
+                    SidecarState::Running => {
+                        if let Some(base_url) = current.base_url {
+                            return Ok(base_url);
+                        }
+                    }
+                    SidecarState::Restarting | SidecarState::Starting | SidecarState::Unhealthy => {
+                    }
+                    _ => break,
+                }
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match tokio::time::timeout(remaining, status.changed()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(_)) => break,
+                    Err(_) => return Err("OpenCode server is restarting".to_string()),
+                }
+            }
+        }
+        self.ensure_server().await
+    }
+
     pub async fn ensure_server(&self) -> Result<String, String> {
         let _guard = self.inner.ensure_lock.lock().await;
 
@@ -80,7 +188,35 @@ impl OpenCodeServerManager {
             return Ok(base_url);
         }
 
-        let (base_url, child) = self.spawn_http_server().await?;
+        self.publish(|status| {
+            status.state = SidecarState::Starting;
+            status.base_url = None;
+        });
+        let started = self.start_server().await;
+        match &started {
+            Ok(base_url) => self.publish(|status| {
+                status.state = SidecarState::Running;
+                status.base_url = Some(base_url.clone());
+                status.restart_attempt = 0;
+                status.retry_in_ms = None;
+                status.last_error = None;
+            }),
+            Err(err) => self.publish(|status| {
+                status.state = SidecarState::Failed;
+                status.last_error = Some(err.clone());
+            }),
+        }
+        started
+    }
+
+    async fn start_server(&self) -> Result<String, String> {
+        let (base_url, child) = match self.spawn_http_server().await {
+            Ok(spawned) => spawned,
+            Err(err) => {
+                self.inner.state.lock().await.last_error = Some(err.clone());
+                return Err(err);
+            }
+        };
 
         if let Err(err) = self.wait_for_http_server(&base_url).await {
             kill_child(&child);
@@ -103,7 +239,7 @@ impl OpenCodeServerManager {
             instance_id
         };
 
-        self.spawn_monitor_task(instance_id, child);
+        self.spawn_monitor_task(instance_id, base_url.clone(), child);
 
         Ok(base_url)
     }
@@ -114,15 +250,45 @@ impl OpenCodeServerManager {
         let child = {
             let mut state = self.inner.state.lock().await;
             state.shutdown_requested = true;
+            state.restarting = false;
             state.server.take().map(|server| server.child)
         };
 
         if let Some(child) = child {
             kill_child(&child);
         }
+        self.publish(|status| {
+            status.state = SidecarState::Stopped;
+            status.base_url = None;
+            status.retry_in_ms = None;
+        });
+    }
+
+    /// Current lifecycle state of the sidecar.
+    pub fn status(&self) -> SidecarStatus {
+        self.inner.status.borrow().clone()
+    }
+
+    /// Receive every change of [`status`](Self::status).
+    pub fn subscribe_status(&self) -> watch::Receiver<SidecarStatus> {
+        self.inner.status.subscribe()
+    }
+
+    fn publish(&self, update: impl FnOnce(&mut SidecarStatus)) {
+        self.inner.status.send_if_modified(|status| {
+            let before = status.clone();
+            update(status);
+            *status != before
+        });
     }
 
-    async fn running_base_url(&self) -> Option<String> {
+    /// Times the sidecar was restarted after exiting unexpectedly.
+    pub async fn auto_restarts(&self) -> u64 {
+        self.inner.state.lock().await.auto_restarts
+    }
+
+    /// Base URL of the sidecar if it is currently running. Never starts it.
+    pub async fn running_base_url(&self) -> Option<String> {
         let running = {
             let state = self.inner.state.lock().await;
             state.server.clone()
@@ -187,9 +353,34 @@ impl OpenCodeServerManager {
         Ok((base_url, Arc::new(StdMutex::new(Some(child)))))
     }
 
-    fn spawn_monitor_task(&self, instance_id: u64, child: Arc<StdMutex<Option<Child>>>) {
+    /// Whether one of the health endpoints answers within the probe timeout.
+    async fn probe(&self, base_url: &str) -> bool {
+        for endpoint in HEALTH_ENDPOINTS {
+            let request = self
+                .inner
+                .http_client
+                .get(format!("{base_url}/{endpoint}"))
+                .timeout(HEALTH_PROBE_TIMEOUT);
+            if matches!(request.send().await, Ok(response) if response.status().is_success()) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Watch for the sidecar exiting, and probe it every `health_interval`. A
+    /// sidecar failing `health_failure_threshold` probes in a row is killed,
+    /// which the next poll treats as a crash.
+    fn spawn_monitor_task(
+        &self,
+        instance_id: u64,
+        base_url: String,
+        child: Arc<StdMutex<Option<Child>>>,
+    ) {
         let manager = self.clone();
         tokio::spawn(async move {
+            let mut next_probe = Instant::now() + manager.inner.config.health_interval;
+            let mut failed_probes = 0;
             loop {
                 let status = {
                     let mut guard = match child.lock() {
@@ -210,6 +401,32 @@ impl OpenCodeServerManager {
                     return;
                 }
 
+                if Instant::now() >= next_probe {
+                    if manager.probe(&base_url).await {
+                        if failed_probes > 0 {
+                            manager.publish(|status| status.state = SidecarState::Running);
+                        }
+                        failed_probes = 0;
+                    } else {
+                        failed_probes += 1;
+                        let threshold = manager.inner.config.health_failure_threshold;
+                        warn!(
+                            failed_probes,
+                            threshold, "OpenCode compat sidecar failed a health probe"
+                        );
+                        manager.publish(|status| {
+                            status.state = SidecarState::Unhealthy;
+                            status.last_error = Some(format!(
+                                "health probe failed {failed_probes} time(s) in a row"
+                            ));
+                        });
+                        if failed_probes >= threshold {
+                            kill_process(&child);
+                        }
+                    }
+                    next_probe = Instant::now() + manager.inner.config.health_interval;
+                }
+
                 sleep(Duration::from_millis(MONITOR_DELAY_MS)).await;
             }
         });
@@ -232,26 +449,83 @@ impl OpenCodeServerManager {
             }
             state.server = None;
 
-            (
-                !shutdown_requested && self.inner.config.auto_restart,
-                message,
-            )
+            let should_restart = !shutdown_requested && self.inner.config.auto_restart;
+            if should_restart {
+                state.auto_restarts += 1;
+                state.restarting = true;
+            }
+            (should_restart, message)
         };
 
         if !should_restart {
+            self.publish(|status| {
+                status.state = SidecarState::Stopped;
+                status.base_url = None;
+            });
             return;
         }
 
         let manager = self.clone();
         tokio::spawn(async move {
-            sleep(Duration::from_millis(MONITOR_DELAY_MS)).await;
-            if let Err(err) = manager.ensure_server().await {
-                warn!(
-                    error = ?err,
-                    prior_exit = %error_message,
-                    "failed to restart OpenCode compat sidecar"
-                );
+            manager.restart_with_backoff(error_message).await;
+        });
+    }
+
+    /// Restart a crashed sidecar, doubling the delay after every failed
+    /// attempt, until it is up, shut down, or out of attempts.
+    async fn restart_with_backoff(&self, prior_exit: String) {
+        let config = &self.inner.config;
+        let auto_restarts = self.inner.state.lock().await.auto_restarts;
+        let mut delay = config.restart_backoff_initial;
+        let mut last_error = prior_exit;
+        for attempt in 1..=config.max_restart_attempts.max(1) {
+            self.publish(|status| {
+                status.state = SidecarState::Restarting;
+                status.base_url = None;
+                status.auto_restarts = auto_restarts;
+                status.restart_attempt = attempt;
+                status.retry_in_ms = Some(delay.as_millis() as u64);
+                status.last_error = Some(last_error.clone());
+            });
+            sleep(delay).await;
+
+            let _guard = self.inner.ensure_lock.lock().await;
+            if self.inner.state.lock().await.shutdown_requested {
+                return;
             }
+            if let Some(base_url) = self.running_base_url().await {
+                self.finish_restart(&base_url).await;
+                return;
+            }
+            match self.start_server().await {
+                Ok(base_url) => {
+                    self.finish_restart(&base_url).await;
+                    return;
+                }
+                Err(err) => {
+                    warn!(attempt, error = %err, "failed to restart OpenCode compat sidecar");
+                    last_error = err;
+                }
+            }
+            delay = (delay * 2).min(config.restart_backoff_max);
+        }
+
+        self.inner.state.lock().await.restarting = false;
+        self.publish(|status| {
+            status.state = SidecarState::Failed;
+            status.retry_in_ms = None;
+            status.last_error = Some(last_error);
+        });
+    }
+
+    async fn finish_restart(&self, base_url: &str) {
+        self.inner.state.lock().await.restarting = false;
+        self.publish(|status| {
+            status.state = SidecarState::Running;
+            status.base_url = Some(base_url.to_string());
+            status.restart_attempt = 0;
+            status.retry_in_ms = None;
+            status.last_error = None;
         });
     }
 }
@@ -300,6 +574,15 @@ fn child_is_alive(child: &Arc<StdMutex<Option<Child>>>) -> bool {
     }
 }
 
+/// Kill the process but keep it for the monitor task to reap.
+fn kill_process(child: &Arc<StdMutex<Option<Child>>>) {
+    if let Ok(mut guard) = child.lock() {
+        if let Some(child) = guard.as_mut() {
+            let _ = child.kill();
+        }
+    }
+}
+
 fn kill_child(child: &Arc<StdMutex<Option<Child>>>) {
     if let Ok(mut guard) = child.lock() {
         if let Some(child) = guard.as_mut() {
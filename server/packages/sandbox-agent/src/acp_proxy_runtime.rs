@@ -4,16 +4,23 @@ use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 
+use acp_http_adapter::limits::ResourceLimits;
 use acp_http_adapter::process::{AdapterError, AdapterRuntime, PostOutcome};
 use acp_http_adapter::registry::LaunchSpec;
 use axum::response::sse::Event;
-use futures::Stream;
+use futures::{Stream, StreamExt};
 use sandbox_agent_agent_management::agents::{AgentId, AgentManager, InstallOptions};
 use sandbox_agent_error::SandboxError;
-use sandbox_agent_opencode_adapter::{AcpDispatch, AcpDispatchResult, AcpPayloadStream};
-use serde_json::Value;
+use sandbox_agent_opencode_adapter::{
+    AcpDispatch, AcpDispatchResult, AcpInstanceSummary, AcpPayloadStream, AcpSequencedStream,
+    SessionPriority,
+};
+use serde_json::{json, Value};
 use tokio::sync::{Mutex, RwLock};
 
+use crate::fetch_proxy::{FetchPolicy, FetchProxy};
+use crate::telemetry::metrics::metrics;
+
 const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 120_000;
 
 #[derive(Debug, Clone)]
@@ -26,9 +33,21 @@ struct AcpProxyRuntimeInner {
     agent_manager: Arc<AgentManager>,
     require_preinstall: bool,
     request_timeout: Duration,
+    limits: AgentLimits,
     instances: RwLock<HashMap<String, Arc<ProxyInstance>>>,
     instance_locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
     install_locks: Mutex<HashMap<AgentId, Arc<Mutex<()>>>>,
+    /// Extra arguments, env and priority for instances that have not been
+    /// launched yet.
+    launch_overrides: Mutex<HashMap<String, LaunchOverrides>>,
+    fetch_proxy: Arc<FetchProxy>,
+}
+
+#[derive(Debug, Default)]
+struct LaunchOverrides {
+    args: Vec<String>,
+    env: HashMap<String, String>,
+    priority: SessionPriority,
 }
 
 #[derive(Debug)]
@@ -50,6 +69,7 @@ pub struct AcpServerInstanceInfo {
     pub server_id: String,
     pub agent: AgentId,
     pub created_at_ms: i64,
+    pub pid: Option<u32>,
 }
 
 pub type PinBoxSseStream =
@@ -76,9 +96,12 @@ impl AcpProxyRuntime {
                 agent_manager,
                 require_preinstall,
                 request_timeout,
+                limits: AgentLimits::from_env(),
                 instances: RwLock::new(HashMap::new()),
                 instance_locks: Mutex::new(HashMap::new()),
                 install_locks: Mutex::new(HashMap::new()),
+                launch_overrides: Mutex::new(HashMap::new()),
+                fetch_proxy: Arc::new(FetchProxy::new(FetchPolicy::from_env())),
             }),
         }
     }
@@ -94,6 +117,7 @@ impl AcpProxyRuntime {
                 server_id: instance.server_id.clone(),
                 agent: instance.agent,
                 created_at_ms: instance.created_at_ms,
+                pid: Some(instance.runtime.pid()).filter(|pid| *pid != 0),
             })
             .collect::<Vec<_>>();
         infos.sort_by(|left, right| left.server_id.cmp(&right.server_id));
@@ -177,10 +201,15 @@ impl AcpProxyRuntime {
     ) -> Result<PinBoxSseStream, SandboxError> {
         let instance = self.get_instance(server_id).await?;
         let stream = instance.runtime.clone().sse_stream(last_event_id).await;
-        Ok(Box::pin(stream))
+        let subscriber = metrics().sse_subscribers.get("acp").track();
+        Ok(Box::pin(stream.map(move |event| {
+            let _subscriber = &subscriber;
+            event
+        })))
     }
 
     pub async fn delete(&self, server_id: &str) -> Result<(), SandboxError> {
+        self.inner.launch_overrides.lock().await.remove(server_id);
         let removed = self.inner.instances.write().await.remove(server_id);
         if let Some(instance) = removed {
             instance.runtime.shutdown().await;
@@ -294,7 +323,7 @@ impl AcpProxyRuntime {
         );
 
         let manager = self.inner.agent_manager.clone();
-        let launch = tokio::task::spawn_blocking(move || manager.resolve_agent_process(agent))
+        let mut launch = tokio::task::spawn_blocking(move || manager.resolve_agent_process(agent))
             .await
             .map_err(|err| SandboxError::StreamError {
                 message: format!("failed to resolve ACP agent process launch spec: {err}"),
@@ -302,6 +331,12 @@ impl AcpProxyRuntime {
             .map_err(|err| SandboxError::StreamError {
                 message: err.to_string(),
             })?;
+        let mut limits = self.inner.limits.for_agent(agent);
+        if let Some(overrides) = self.inner.launch_overrides.lock().await.remove(server_id) {
+            launch.args.extend(overrides.args);
+            launch.env.extend(overrides.env);
+            limits = prioritized(limits, overrides.priority);
+        }
 
         tracing::info!(
             server_id = server_id,
@@ -317,12 +352,24 @@ impl AcpProxyRuntime {
                 program: launch.program,
                 args: launch.args,
                 env: launch.env,
+                limits,
             },
             self.inner.request_timeout,
         )
         .await
         .map_err(map_adapter_error)?;
-
+        let runtime = Arc::new(runtime);
+        self.inner
+            .fetch_proxy
+            .clone()
+            .attach(server_id.to_string(), runtime.clone());
+        attach_auth_failure_watch(server_id.to_string(), agent, runtime.clone());
+        attach_limit_watch(server_id.to_string(), agent, runtime.clone());
+
+        metrics()
+            .acp_bootstrap_duration
+            .get(agent.as_str())
+            .observe(start.elapsed());
         let total_ms = start.elapsed().as_millis() as u64;
         tracing::info!(
             server_id = server_id,
@@ -334,7 +381,7 @@ impl AcpProxyRuntime {
         Ok(Arc::new(ProxyInstance {
             server_id: server_id.to_string(),
             agent,
-            runtime: Arc::new(runtime),
+            runtime,
             created_at_ms: now_ms(),
         }))
     }
@@ -423,6 +470,26 @@ impl AcpDispatch for AcpProxyRuntime {
         })
     }
 
+    fn sequenced_notification_stream(
+        &self,
+        server_id: &str,
+        last_event_id: Option<u64>,
+    ) -> Pin<Box<dyn Future<Output = Result<AcpSequencedStream, String>> + Send + '_>> {
+        let server_id = server_id.to_string();
+        Box::pin(async move {
+            let instance = self
+                .get_instance(&server_id)
+                .await
+                .map_err(|e| e.to_string())?;
+            let stream = instance
+                .runtime
+                .clone()
+                .sequenced_value_stream(last_event_id)
+                .await;
+            Ok(Box::pin(stream) as AcpSequencedStream)
+        })
+    }
+
     fn delete(
         &self,
         server_id: &str,
@@ -430,6 +497,90 @@ impl AcpDispatch for AcpProxyRuntime {
         let server_id = server_id.to_string();
         Box::pin(async move { self.delete(&server_id).await.map_err(|err| err.to_string()) })
     }
+
+    fn set_correlation_id(
+        &self,
+        server_id: &str,
+        correlation_id: Option<String>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        let server_id = server_id.to_string();
+        Box::pin(async move {
+            if let Ok(instance) = self.get_instance(&server_id).await {
+                instance.runtime.set_correlation_id(correlation_id);
+            }
+        })
+    }
+
+    fn set_launch_args(
+        &self,
+        server_id: &str,
+        args: Vec<String>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        let server_id = server_id.to_string();
+        Box::pin(async move {
+            self.inner
+                .launch_overrides
+                .lock()
+                .await
+                .entry(server_id)
+                .or_default()
+                .args = args;
+        })
+    }
+
+    fn set_launch_env(
+        &self,
+        server_id: &str,
+        env: HashMap<String, String>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        let server_id = server_id.to_string();
+        Box::pin(async move {
+            self.inner
+                .launch_overrides
+                .lock()
+                .await
+                .entry(server_id)
+                .or_default()
+                .env = env;
+        })
+    }
+
+    fn set_launch_priority(
+        &self,
+        server_id: &str,
+        priority: SessionPriority,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        let server_id = server_id.to_string();
+        Box::pin(async move {
+            self.inner
+                .launch_overrides
+                .lock()
+                .await
+                .entry(server_id)
+                .or_default()
+                .priority = priority;
+        })
+    }
+
+    fn has_instance(&self, server_id: &str) -> Pin<Box<dyn Future<Output = bool> + Send + '_>> {
+        let server_id = server_id.to_string();
+        Box::pin(async move { self.inner.instances.read().await.contains_key(&server_id) })
+    }
+
+    fn instances(&self) -> Pin<Box<dyn Future<Output = Vec<AcpInstanceSummary>> + Send + '_>> {
+        Box::pin(async move {
+            self.list_instances()
+                .await
+                .into_iter()
+                .map(|info| AcpInstanceSummary {
+                    server_id: info.server_id,
+                    agent: info.agent.as_str().to_string(),
+                    created_at_ms: info.created_at_ms,
+                    pid: info.pid,
+                })
+                .collect()
+        })
+    }
 }
 
 fn map_adapter_error(err: AdapterError) -> SandboxError {
@@ -449,6 +600,9 @@ fn map_adapter_error(err: AdapterError) -> SandboxError {
         AdapterError::Spawn(error) => SandboxError::StreamError {
             message: format!("failed to start agent process: {error}"),
         },
+        AdapterError::Limits(error) => SandboxError::StreamError {
+            message: format!("failed to prepare agent resource limits: {error}"),
+        },
         AdapterError::MissingStdin | AdapterError::MissingStdout | AdapterError::MissingStderr => {
             SandboxError::StreamError {
                 message: "agent subprocess pipes were not available".to_string(),
@@ -488,6 +642,187 @@ fn annotate_agent_error(agent: AgentId, mut value: Value) -> Value {
     value
 }
 
+/// Notification published when an agent process reports that its provider
+/// credentials are missing, invalid, or expired.
+pub(crate) const AUTH_REQUIRED_METHOD: &str = "_sandboxagent/agent/auth_required";
+
+/// Turn the adapter's agent-agnostic `_adapter/auth_failed` signal into a
+/// `TokenInvalid` agent error with agent-specific remediation.
+fn attach_auth_failure_watch(server_id: String, agent: AgentId, runtime: Arc<AdapterRuntime>) {
+    tokio::spawn(async move {
+        let mut stream = Box::pin(runtime.clone().value_stream(None).await);
+        while let Some(payload) = stream.next().await {
+            if payload.get("method").and_then(Value::as_str) != Some("_adapter/auth_failed") {
+                continue;
+            }
+            let params = payload.get("params").cloned().unwrap_or(Value::Null);
+            let line = params
+                .get("line")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let mut error = SandboxError::TokenInvalid {
+                message: Some(format!("{} could not authenticate: {line}", agent.as_str())),
+            }
+            .to_agent_error();
+            error.agent = Some(agent.as_str().to_string());
+
+            tracing::warn!(
+                server_id = %server_id,
+                agent = agent.as_str(),
+                signature = ?params.get("signature"),
+                "agent reported an authentication failure"
+            );
+            runtime
+                .publish(json!({
+                    "jsonrpc": "2.0",
+                    "method": AUTH_REQUIRED_METHOD,
+                    "params": {
+                        "agent": agent.as_str(),
+                        "signature": params.get("signature"),
+                        "source": params.get("source"),
+                        "error": error,
+                        "hint": auth_remediation_hint(agent),
+                    }
+                }))
+                .await;
+            break;
+        }
+    });
+}
+
+/// Notification published when an agent process breaches one of its
+/// configured resource limits.
+pub(crate) const LIMIT_EXCEEDED_METHOD: &str = "_sandboxagent/agent/limit_exceeded";
+
+/// Turn the adapter's `_adapter/limit_exceeded` signal into a typed
+/// `ResourceLimitExceeded` agent error.
+fn attach_limit_watch(server_id: String, agent: AgentId, runtime: Arc<AdapterRuntime>) {
+    tokio::spawn(async move {
+        let mut stream = Box::pin(runtime.clone().value_stream(None).await);
+        while let Some(payload) = stream.next().await {
+            if payload.get("method").and_then(Value::as_str) != Some("_adapter/limit_exceeded") {
+                continue;
+            }
+            let params = payload.get("params").cloned().unwrap_or(Value::Null);
+            let limit = params
+                .get("limit")
+                .and_then(Value::as_str)
+                .unwrap_or("unknown");
+            let detail = params
+                .get("detail")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let error = SandboxError::ResourceLimitExceeded {
+                agent: agent.as_str().to_string(),
+                limit: limit.to_string(),
+                message: detail.to_string(),
+            }
+            .to_agent_error();
+
+            tracing::warn!(
+                server_id = %server_id,
+                agent = agent.as_str(),
+                limit,
+                "agent exceeded a resource limit"
+            );
+            runtime
+                .publish(json!({
+                    "jsonrpc": "2.0",
+                    "method": LIMIT_EXCEEDED_METHOD,
+                    "params": {
+                        "agent": agent.as_str(),
+                        "limit": limit,
+                        "configured": params.get("configured"),
+                        "killed": params.get("killed"),
+                        "error": error,
+                    }
+                }))
+                .await;
+        }
+    });
+}
+
+/// Resource limits per agent, read from `SANDBOX_AGENT_LIMITS`: a JSON object
+/// keyed by agent ID, where a `default` entry applies to every agent and the
+/// agent's own entry overrides it field by field.
+#[derive(Debug, Default)]
+struct AgentLimits {
+    default: ResourceLimits,
+    agents: HashMap<AgentId, ResourceLimits>,
+}
+
+impl AgentLimits {
+    fn from_env() -> Self {
+        let Some(raw) = std::env::var("SANDBOX_AGENT_LIMITS")
+            .ok()
+            .filter(|value| !value.trim().is_empty())
+        else {
+            return Self::default();
+        };
+        let mut entries = match serde_json::from_str::<HashMap<String, ResourceLimits>>(&raw) {
+            Ok(entries) => entries,
+            Err(err) => {
+                tracing::error!(error = %err, "ignoring invalid SANDBOX_AGENT_LIMITS");
+                return Self::default();
+            }
+        };
+        let default = entries.remove("default").unwrap_or_default();
+        let mut agents = HashMap::new();
+        for (key, limits) in entries {
+            match AgentId::parse(&key) {
+                Some(agent) => {
+                    agents.insert(agent, limits);
+                }
+                None => tracing::warn!(agent = %key, "SANDBOX_AGENT_LIMITS: unknown agent"),
+            }
+        }
+        Self { default, agents }
+    }
+
+    fn for_agent(&self, agent: AgentId) -> ResourceLimits {
+        match self.agents.get(&agent) {
+            Some(limits) => self.default.merged(limits),
+            None => self.default.clone(),
+        }
+    }
+}
+
+/// Apply a session's priority on top of the agent's limits: its niceness
+/// unless one is configured, and a scaled CPU weight when the process gets a
+/// cgroup.
+fn prioritized(mut limits: ResourceLimits, priority: SessionPriority) -> ResourceLimits {
+    if priority.is_normal() {
+        return limits;
+    }
+    limits.nice = limits.nice.or(priority.niceness());
+    if limits.cgroup_parent.is_some() {
+        limits.cpu_shares = Some(priority.cpu_weight(limits.cpu_shares.unwrap_or(100)));
+    }
+    limits
+}
+
+fn auth_remediation_hint(agent: AgentId) -> &'static str {
+    match agent {
+        AgentId::Claude => {
+            "Claude credentials are missing or expired. Set ANTHROPIC_API_KEY (or \
+             CLAUDE_CODE_OAUTH_TOKEN) for the sandbox, or run `claude /login` inside it."
+        }
+        AgentId::Codex => {
+            "Codex credentials are missing or expired. Set OPENAI_API_KEY (or \
+             CODEX_API_KEY) for the sandbox, or run `codex login` inside it."
+        }
+        AgentId::Amp => "Amp credentials are missing or expired. Set AMP_API_KEY for the sandbox.",
+        AgentId::Cursor => {
+            "Cursor credentials are missing or expired. Set CURSOR_API_KEY for the sandbox, \
+             or run `cursor-agent login` inside it."
+        }
+        _ => {
+            "The agent's provider credentials are missing or expired. Set the provider API \
+             key for the sandbox (e.g. ANTHROPIC_API_KEY or OPENAI_API_KEY) and retry."
+        }
+    }
+}
+
 fn duration_from_env_ms(key: &str, default: Duration) -> Duration {
     match std::env::var(key) {
         Ok(raw) => raw
@@ -0,0 +1,627 @@
+//! `sandbox-agent attach`: an interactive terminal on an OpenCode compat
+//! session, without the OpenCode client.
+//!
+//! The session's events are rendered as they stream from `/opencode/event`.
+//! Lines typed at the terminal are sent as prompts with `prompt_async`,
+//! except while a permission or question request is pending, when they
+//! answer it.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use clap::Args;
+use reqwest::Method;
+use serde_json::{json, Value};
+
+use crate::cli::{CliConfig, CliError, ClientArgs, ClientContext};
+
+const OPENCODE_PREFIX: &str = "/opencode";
+/// Lines of a tool's output shown once it completes.
+const TOOL_OUTPUT_PREVIEW_LINES: usize = 5;
+
+const BOLD: &str = "1";
+const DIM: &str = "2";
+const RED: &str = "31";
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const MAGENTA: &str = "35";
+const CYAN: &str = "36";
+
+#[derive(Args, Debug)]
+pub struct AttachArgs {
+    /// OpenCode session to attach to.
+    session: String,
+
+    /// Print without ANSI colors. Also disabled by `NO_COLOR` or when stdout
+    /// is not a terminal.
+    #[arg(long = "no-color")]
+    no_color: bool,
+
+    /// Seconds the event stream stays open.
+    #[arg(long, default_value_t = 86_400)]
+    timeout: u64,
+
+    #[command(flatten)]
+    client: ClientArgs,
+}
+
+pub fn run(args: &AttachArgs, cli: &CliConfig) -> Result<(), CliError> {
+    let ctx = ClientContext::new(cli, &args.client)?;
+    let events = ctx
+        .request(Method::GET, &format!("{OPENCODE_PREFIX}/event"))
+        .header("accept", "text/event-stream")
+        .timeout(Duration::from_secs(args.timeout))
+        .send()?;
+    if !events.status().is_success() {
+        return Err(CliError::HttpStatus(events.status()));
+    }
+
+    let color =
+        !args.no_color && std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal();
+    let pending = Arc::new(Mutex::new(VecDeque::<PendingRequest>::new()));
+    let closed = Arc::new(AtomicBool::new(false));
+    std::thread::spawn({
+        let session = args.session.clone();
+        let pending = pending.clone();
+        let closed = closed.clone();
+        move || {
+            let mut renderer = Renderer::new(&session, color);
+            for line in BufReader::new(events).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                let Some(data) = line.strip_prefix("data:") else {
+                    continue;
+                };
+                let Ok(event) = serde_json::from_str::<Value>(data.trim()) else {
+                    continue;
+                };
+                if let Ok(mut pending) = pending.lock() {
+                    renderer.track(&event, &mut pending);
+                }
+                if let Some(text) = renderer.render(&event) {
+                    let mut out = std::io::stdout();
+                    let _ = out.write_all(text.as_bytes());
+                    let _ = out.flush();
+                }
+            }
+            closed.store(true, Ordering::SeqCst);
+            let _ = writeln!(
+                std::io::stderr(),
+                "\nevent stream closed; press Enter to exit"
+            );
+        }
+    });
+
+    let hint = Renderer::new(&args.session, color);
+    println!(
+        "{}",
+        hint.paint(
+            DIM,
+            &format!(
+                "attached to {}; type a prompt, /abort to stop the turn, /quit to leave",
+                args.session
+            )
+        )
+    );
+    let stdin = std::io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        if closed.load(Ordering::SeqCst) {
+            break;
+        }
+        let front = pending
+            .lock()
+            .ok()
+            .and_then(|pending| pending.front().cloned());
+        let (path, body) = match parse_input(&line, front.as_ref()) {
+            Input::Empty => continue,
+            Input::Quit => break,
+            Input::Invalid(message) => {
+                eprintln!("{}", hint.paint(YELLOW, &message));
+                continue;
+            }
+            Input::Prompt(text) => (
+                format!("/session/{}/prompt_async", args.session),
+                json!({"parts": [{"type": "text", "text": text}]}),
+            ),
+            Input::Abort => (format!("/session/{}/abort", args.session), json!({})),
+            Input::Permission { id, reply } => {
+                (format!("/permission/{id}/reply"), json!({"reply": reply}))
+            }
+            Input::Answer { id, answer } => (
+                format!("/question/{id}/reply"),
+                json!({"answers": [[answer]]}),
+            ),
+            Input::RejectQuestion { id } => (format!("/question/{id}/reject"), json!({})),
+        };
+        let response = ctx
+            .request(Method::POST, &format!("{OPENCODE_PREFIX}{path}"))
+            .json(&body)
+            .send()?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().unwrap_or_default();
+            eprintln!(
+                "{}",
+                hint.paint(RED, &format!("request failed ({status}): {body}"))
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A request from the agent waiting for the user, oldest first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PendingRequest {
+    Permission { id: String },
+    Question { id: String, options: Vec<String> },
+}
+
+/// What a line typed at the terminal does.
+#[derive(Debug, PartialEq, Eq)]
+enum Input {
+    Empty,
+    Quit,
+    Abort,
+    Prompt(String),
+    Permission { id: String, reply: &'static str },
+    Answer { id: String, answer: String },
+    RejectQuestion { id: String },
+    Invalid(String),
+}
+
+/// Interpret a typed line. While a request is pending the line answers it:
+/// `y`, `a` or `n` for a permission, an option number or free text for a
+/// question.
+fn parse_input(line: &str, pending: Option<&PendingRequest>) -> Input {
+    let line = line.trim();
+    match line {
+        "" => return Input::Empty,
+        "/quit" | "/exit" => return Input::Quit,
+        "/abort" => return Input::Abort,
+        _ => {}
+    }
+    match pending {
+        None => Input::Prompt(line.to_string()),
+        Some(PendingRequest::Permission { id }) => {
+            let reply = match line.to_ascii_lowercase().as_str() {
+                "y" | "yes" | "once" => "once",
+                "a" | "always" => "always",
+                "n" | "no" | "reject" => "reject",
+                _ => return Input::Invalid(
+                    "a permission request is pending: answer y (once), a (always) or n (reject)"
+                        .to_string(),
+                ),
+            };
+            Input::Permission {
+                id: id.clone(),
+                reply,
+            }
+        }
+        Some(PendingRequest::Question { id, options }) => {
+            if line == "/skip" {
+                return Input::RejectQuestion { id: id.clone() };
+            }
+            let answer = line
+                .parse::<usize>()
+                .ok()
+                .and_then(|number| options.get(number.checked_sub(1)?))
+                .cloned()
+                .unwrap_or_else(|| line.to_string());
+            Input::Answer {
+                id: id.clone(),
+                answer,
+            }
+        }
+    }
+}
+
+/// Turns one session's events into terminal output.
+struct Renderer {
+    session_id: String,
+    color: bool,
+    /// Whether the last output ended a line.
+    at_line_start: bool,
+    /// Role of each message seen in `message.updated`.
+    roles: HashMap<String, String>,
+    /// Bytes of each text or reasoning part already printed.
+    printed: HashMap<String, usize>,
+    /// Tool name per call ID; later updates of a call may omit it.
+    tools: HashMap<String, String>,
+    finished_tools: HashSet<String>,
+}
+
+impl Renderer {
+    fn new(session_id: &str, color: bool) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            color,
+            at_line_start: true,
+            roles: HashMap::new(),
+            printed: HashMap::new(),
+            tools: HashMap::new(),
+            finished_tools: HashSet::new(),
+        }
+    }
+
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.color {
+            format!("\x1b[{code}m{text}\x1b[0m")
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn is_ours(&self, event: &Value) -> bool {
+        let properties = &event["properties"];
+        properties
+            .get("sessionID")
+            .or_else(|| properties.pointer("/info/sessionID"))
+            .or_else(|| properties.pointer("/part/sessionID"))
+            .and_then(Value::as_str)
+            == Some(self.session_id.as_str())
+    }
+
+    /// Keep `pending` in step with the session's permission and question
+    /// requests.
+    fn track(&self, event: &Value, pending: &mut VecDeque<PendingRequest>) {
+        if !self.is_ours(event) {
+            return;
+        }
+        let properties = &event["properties"];
+        match event["type"].as_str().unwrap_or_default() {
+            "permission.asked" => {
+                if let Some(id) = properties["id"].as_str() {
+                    pending.push_back(PendingRequest::Permission { id: id.to_string() });
+                }
+            }
+            "question.asked" => {
+                if let Some(id) = properties["id"].as_str() {
+                    let options = properties
+                        .pointer("/questions/0/options")
+                        .and_then(Value::as_array)
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|option| option["label"].as_str().map(str::to_owned))
+                        .collect();
+                    pending.push_back(PendingRequest::Question {
+                        id: id.to_string(),
+                        options,
+                    });
+                }
+            }
+            "permission.replied" | "question.replied" | "question.rejected" => {
+                let answered = properties["requestID"].as_str().unwrap_or_default();
+                pending.retain(|request| match request {
+                    PendingRequest::Permission { id } | PendingRequest::Question { id, .. } => {
+                        id != answered
+                    }
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Output for `event`, or `None` when it shows nothing.
+    fn render(&mut self, event: &Value) -> Option<String> {
+        if !self.is_ours(event) {
+            return None;
+        }
+        let properties = &event["properties"];
+        match event["type"].as_str().unwrap_or_default() {
+            "message.updated" => {
+                if let (Some(id), Some(role)) = (
+                    properties.pointer("/info/id").and_then(Value::as_str),
+                    properties.pointer("/info/role").and_then(Value::as_str),
+                ) {
+                    self.roles.insert(id.to_string(), role.to_string());
+                }
+                None
+            }
+            "message.part.updated" => self.render_part(properties),
+            "permission.asked" => {
+                let mut request = format!(
+                    "permission requested: {}",
+                    properties["permission"].as_str().unwrap_or("unknown")
+                );
+                if let Some(tool) = properties.pointer("/metadata/tool").and_then(Value::as_str) {
+                    request.push_str(&format!(" ({tool})"));
+                }
+                if let Some(input) = properties.pointer("/metadata/input") {
+                    request.push_str(&format!("\n  {input}"));
+                }
+                Some(self.block(&format!(
+                    "{}\n{}",
+                    self.paint(YELLOW, &request),
+                    self.paint(YELLOW, "allow? [y]es once, [a]lways, [n]o")
+                )))
+            }
+            "question.asked" => {
+                let question = &properties["questions"][0];
+                let mut text =
+                    self.paint(MAGENTA, question["question"].as_str().unwrap_or("question"));
+                for (index, option) in question["options"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .enumerate()
+                {
+                    text.push_str(&format!(
+                        "\n  {}. {}",
+                        index + 1,
+                        option["label"].as_str().unwrap_or_default()
+                    ));
+                }
+                text.push_str(&format!(
+                    "\n{}",
+                    self.paint(MAGENTA, "answer with a number or text, /skip to reject")
+                ));
+                Some(self.block(&text))
+            }
+            "session.error" => {
+                let message = properties
+                    .pointer("/error/data/message")
+                    .and_then(Value::as_str)
+                    .unwrap_or("agent error");
+                Some(self.block(&self.paint(RED, &format!("error: {message}"))))
+            }
+            "turn.completed" => {
+                let status = properties["status"].as_str().unwrap_or("completed");
+                Some(self.block(&self.paint(DIM, &format!("-- turn {status}"))))
+            }
+            _ => None,
+        }
+    }
+
+    fn render_part(&mut self, properties: &Value) -> Option<String> {
+        let part = &properties["part"];
+        let from_user = self
+            .roles
+            .get(part["messageID"].as_str().unwrap_or_default())
+            .is_some_and(|role| role == "user");
+        // The user typed these.
+        if from_user {
+            return None;
+        }
+        match part["type"].as_str().unwrap_or_default() {
+            kind @ ("text" | "reasoning") => {
+                let part_id = part["id"].as_str()?;
+                let printed = self.printed.entry(part_id.to_string()).or_default();
+                // Coalesced deltas carry the new text; parts sent whole are
+                // printed past what was already shown.
+                let new = match properties["delta"].as_str() {
+                    Some(delta) => delta,
+                    None => part["text"].as_str()?.get(*printed..)?,
+                };
+                if new.is_empty() {
+                    return None;
+                }
+                *printed += new.len();
+                let text = if kind == "reasoning" {
+                    self.paint(DIM, new)
+                } else {
+                    new.to_string()
+                };
+                self.at_line_start = new.ends_with('\n');
+                Some(text)
+            }
+            "tool" => {
+                let call_id = part["callID"].as_str()?.to_string();
+                if self.finished_tools.contains(&call_id) {
+                    return None;
+                }
+                let status = part
+                    .pointer("/state/status")
+                    .and_then(Value::as_str)
+                    .unwrap_or("running");
+                let mut text = String::new();
+                if !self.tools.contains_key(&call_id) {
+                    let name = part["tool"].as_str().unwrap_or("tool").to_string();
+                    let mut started = self.paint(CYAN, &format!("> {name}"));
+                    if let Some(input) = part
+                        .pointer("/state/input")
+                        .filter(|input| input.as_object().is_some_and(|input| !input.is_empty()))
+                    {
+                        started.push_str(&format!(" {}", self.paint(DIM, &input.to_string())));
+                    }
+                    text.push_str(&self.block(&started));
+                    self.tools.insert(call_id.clone(), name);
+                }
+                let name = self.tools[&call_id].clone();
+                match status {
+                    "completed" => {
+                        self.finished_tools.insert(call_id);
+                        let mut done = self.paint(GREEN, &format!("+ {name}"));
+                        let output = part
+                            .pointer("/state/output")
+                            .and_then(Value::as_str)
+                            .unwrap_or_default();
+                        let lines: Vec<&str> = output.lines().collect();
+                        for line in lines.iter().take(TOOL_OUTPUT_PREVIEW_LINES) {
+                            done.push_str(&format!("\n  {}", self.paint(DIM, line)));
+                        }
+                        if lines.len() > TOOL_OUTPUT_PREVIEW_LINES {
+                            done.push_str(&format!(
+                                "\n  {}",
+                                self.paint(
+                                    DIM,
+                                    &format!(
+                                        "... {} more lines",
+                                        lines.len() - TOOL_OUTPUT_PREVIEW_LINES
+                                    )
+                                )
+                            ));
+                        }
+                        text.push_str(&self.block(&done));
+                    }
+                    "error" | "failed" => {
+                        self.finished_tools.insert(call_id);
+                        let error = part
+                            .pointer("/state/error")
+                            .or_else(|| part.pointer("/state/output"))
+                            .and_then(Value::as_str)
+                            .unwrap_or("failed");
+                        text.push_str(&self.block(&self.paint(RED, &format!("x {name}: {error}"))));
+                    }
+                    _ => {}
+                }
+                (!text.is_empty()).then_some(text)
+            }
+            "file" => {
+                let path = part["url"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .trim_start_matches("file://");
+                Some(self.block(&self.paint(BOLD, &format!("file {path}"))))
+            }
+            _ => None,
+        }
+    }
+
+    /// `text` on lines of its own.
+    fn block(&mut self, text: &str) -> String {
+        let separator = if self.at_line_start { "" } else { "\n" };
+        self.at_line_start = true;
+        format!("{separator}{text}\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part_event(part: Value, delta: Option<&str>) -> Value {
+        let mut event = json!({
+            "type": "message.part.updated",
+            "properties": {"sessionID": "ses_1", "part": part},
+        });
+        if let Some(delta) = delta {
+            event["properties"]["delta"] = json!(delta);
+        }
+        event
+    }
+
+    #[test]
+    fn renders_streamed_text_and_tools() {
+        let mut renderer = Renderer::new("ses_1", false);
+        let other = json!({"type": "turn.completed", "properties": {"sessionID": "ses_2"}});
+        assert_eq!(renderer.render(&other), None);
+
+        let text = json!({"id": "prt_1", "messageID": "msg_1", "sessionID": "ses_1", "type": "text", "text": "Hel"});
+        assert_eq!(
+            renderer.render(&part_event(text, Some("Hel"))).as_deref(),
+            Some("Hel")
+        );
+        let whole = json!({"id": "prt_1", "messageID": "msg_1", "sessionID": "ses_1", "type": "text", "text": "Hello"});
+        assert_eq!(
+            renderer.render(&part_event(whole, None)).as_deref(),
+            Some("lo")
+        );
+
+        let running = json!({
+            "id": "prt_2", "messageID": "msg_1", "sessionID": "ses_1", "type": "tool",
+            "callID": "call_1", "tool": "bash",
+            "state": {"status": "running", "input": {"command": "ls"}},
+        });
+        assert_eq!(
+            renderer.render(&part_event(running, None)).as_deref(),
+            Some("\n> bash {\"command\":\"ls\"}\n")
+        );
+        let completed = json!({
+            "id": "part_msg_1_tc_call_1", "messageID": "msg_1", "sessionID": "ses_1", "type": "tool",
+            "callID": "call_1", "state": {"status": "completed", "output": "a\nb"},
+        });
+        assert_eq!(
+            renderer
+                .render(&part_event(completed.clone(), None))
+                .as_deref(),
+            Some("+ bash\n  a\n  b\n")
+        );
+        assert_eq!(renderer.render(&part_event(completed, None)), None);
+    }
+
+    #[test]
+    fn user_parts_are_not_echoed_and_colors_are_optional() {
+        let mut renderer = Renderer::new("ses_1", true);
+        renderer.render(&json!({
+            "type": "message.updated",
+            "properties": {"info": {"id": "msg_u", "sessionID": "ses_1", "role": "user"}},
+        }));
+        let user = json!({"id": "prt_u", "messageID": "msg_u", "sessionID": "ses_1", "type": "text", "text": "hi"});
+        assert_eq!(renderer.render(&part_event(user, None)), None);
+        let error = renderer.render(&json!({
+            "type": "session.error",
+            "properties": {"sessionID": "ses_1", "error": {"data": {"message": "boom"}}},
+        }));
+        assert_eq!(error.as_deref(), Some("\x1b[31merror: boom\x1b[0m\n"));
+    }
+
+    #[test]
+    fn pending_requests_are_tracked_and_answered_inline() {
+        let renderer = Renderer::new("ses_1", false);
+        let mut pending = VecDeque::new();
+        renderer.track(
+            &json!({"type": "permission.asked", "properties": {"id": "perm_1", "sessionID": "ses_1"}}),
+            &mut pending,
+        );
+        renderer.track(
+            &json!({"type": "question.asked", "properties": {
+                "id": "q_1", "sessionID": "ses_1",
+                "questions": [{"question": "Pick", "options": [{"label": "Yes"}, {"label": "No"}]}],
+            }}),
+            &mut pending,
+        );
+        assert_eq!(
+            parse_input("a", pending.front()),
+            Input::Permission {
+                id: "perm_1".to_string(),
+                reply: "always"
+            }
+        );
+        assert!(matches!(
+            parse_input("sure", pending.front()),
+            Input::Invalid(_)
+        ));
+        assert_eq!(parse_input("/abort", pending.front()), Input::Abort);
+
+        renderer.track(
+            &json!({"type": "permission.replied", "properties": {"sessionID": "ses_1", "requestID": "perm_1"}}),
+            &mut pending,
+        );
+        assert_eq!(
+            parse_input("2", pending.front()),
+            Input::Answer {
+                id: "q_1".to_string(),
+                answer: "No".to_string()
+            }
+        );
+        assert_eq!(
+            parse_input("maybe", pending.front()),
+            Input::Answer {
+                id: "q_1".to_string(),
+                answer: "maybe".to_string()
+            }
+        );
+        assert_eq!(
+            parse_input("/skip", pending.front()),
+            Input::RejectQuestion {
+                id: "q_1".to_string()
+            }
+        );
+
+        renderer.track(
+            &json!({"type": "question.rejected", "properties": {"sessionID": "ses_1", "requestID": "q_1"}}),
+            &mut pending,
+        );
+        assert_eq!(
+            parse_input(" fix the bug ", pending.front()),
+            Input::Prompt("fix the bug".to_string())
+        );
+        assert_eq!(parse_input("  ", None), Input::Empty);
+        assert_eq!(parse_input("/quit", None), Input::Quit);
+    }
+}
@@ -0,0 +1,494 @@
+//! Synthetic load generator for the OpenCode event pipeline.
+//!
+//! `sandbox-agent bench` mounts the OpenCode compat router in-process with a
+//! fake ACP backend that streams timestamped text chunks at a fixed rate, then
+//! drives N sessions through it while subscribed to `/event`. The report covers
+//! end-to-end chunk latency (agent emit to SSE receipt), delivered throughput,
+//! and how long a finished turn takes to become readable from the persisted
+//! transcript.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::Args;
+use futures::StreamExt;
+use sandbox_agent_opencode_adapter::{
+    build_opencode_router, AcpDispatch, AcpDispatchResult, AcpPayloadStream, OpenCodeAdapterConfig,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+
+use crate::cli::CliError;
+
+const BENCH_AGENT: &str = "bench";
+const WRITE_LAG_POLL: Duration = Duration::from_millis(2);
+const WRITE_LAG_TIMEOUT: Duration = Duration::from_secs(30);
+const SSE_DRAIN_IDLE: Duration = Duration::from_millis(500);
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Number of concurrent sessions.
+    #[arg(long, default_value_t = 10)]
+    sessions: usize,
+
+    /// Text chunks each session streams per turn.
+    #[arg(long, default_value_t = 200)]
+    chunks: usize,
+
+    /// Chunks per second per session; 0 streams as fast as possible.
+    #[arg(long = "chunk-rate", default_value_t = 100)]
+    chunk_rate: u32,
+
+    /// Size of each chunk in bytes (padded past the timestamp header).
+    #[arg(long = "chunk-bytes", default_value_t = 64)]
+    chunk_bytes: usize,
+
+    /// Print the report as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BenchReport {
+    sessions: usize,
+    chunks_per_session: usize,
+    chunk_rate: u32,
+    chunk_bytes: usize,
+    expected_events: usize,
+    delivered_events: usize,
+    dropped_events: usize,
+    duration_ms: f64,
+    events_per_sec: f64,
+    bytes_per_sec: f64,
+    latency_ms: Percentiles,
+    write_lag_ms: Percentiles,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Percentiles {
+    p50: f64,
+    p95: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl Percentiles {
+    fn from_micros(mut samples: Vec<u64>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort_unstable();
+        let at = |q: f64| {
+            let index = ((samples.len() - 1) as f64 * q).round() as usize;
+            samples[index] as f64 / 1000.0
+        };
+        Self {
+            p50: at(0.50),
+            p95: at(0.95),
+            p99: at(0.99),
+            max: at(1.0),
+        }
+    }
+}
+
+pub fn run(args: &BenchArgs) -> Result<(), CliError> {
+    if args.sessions == 0 || args.chunks == 0 {
+        return Err(CliError::Bench(
+            "--sessions and --chunks must be greater than zero".to_string(),
+        ));
+    }
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| CliError::Server(err.to_string()))?;
+
+    let sqlite_path = std::env::temp_dir().join(format!(
+        "sandbox-agent-bench-{}-{}.db",
+        std::process::id(),
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0)
+    ));
+    let result = runtime.block_on(run_bench(args, &sqlite_path));
+    remove_sqlite_files(&sqlite_path);
+    let report = result?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report);
+    }
+    Ok(())
+}
+
+async fn run_bench(args: &BenchArgs, sqlite_path: &Path) -> Result<BenchReport, CliError> {
+    let epoch = Instant::now();
+    let dispatch = Arc::new(BenchDispatch::new(args, epoch));
+    let router = build_opencode_router(OpenCodeAdapterConfig {
+        sqlite_path: Some(sqlite_path.to_string_lossy().into_owned()),
+        acp_dispatch: Some(dispatch as Arc<dyn AcpDispatch>),
+        ..Default::default()
+    })
+    .map_err(CliError::Bench)?;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let base_url = format!("http://{}", listener.local_addr()?);
+    let server = tokio::spawn(async move {
+        let _ = axum::serve(listener, router).await;
+    });
+
+    let client = reqwest::Client::new();
+    let collector = Arc::new(Mutex::new(Collector::default()));
+    let events = client.get(format!("{base_url}/event")).send().await?;
+    if !events.status().is_success() {
+        return Err(CliError::HttpStatus(events.status()));
+    }
+    let sse = tokio::spawn(collect_events(events, epoch, collector.clone()));
+
+    let directory = std::env::temp_dir().to_string_lossy().into_owned();
+    let mut session_ids = Vec::with_capacity(args.sessions);
+    for index in 0..args.sessions {
+        let response = client
+            .post(format!("{base_url}/session"))
+            .query(&[("directory", directory.as_str())])
+            .json(&json!({"title": format!("bench {index}")}))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(CliError::HttpStatus(response.status()));
+        }
+        let session: Value = response.json().await?;
+        let id = session
+            .get("id")
+            .and_then(Value::as_str)
+            .ok_or_else(|| CliError::Bench("session create returned no id".to_string()))?;
+        session_ids.push(id.to_string());
+    }
+
+    let started = Instant::now();
+    let turns = session_ids
+        .iter()
+        .map(|session_id| run_turn(&client, &base_url, session_id, args.chunks));
+    let write_lags = futures::future::try_join_all(turns).await?;
+
+    // Turns are done once their transcripts persist; give the SSE stream a
+    // moment to deliver anything still in flight before counting drops.
+    let expected_events = args.sessions * args.chunks;
+    let mut last_seen = collector.lock().unwrap().received;
+    loop {
+        tokio::time::sleep(SSE_DRAIN_IDLE).await;
+        let received = collector.lock().unwrap().received;
+        if received >= expected_events || received == last_seen {
+            break;
+        }
+        last_seen = received;
+    }
+
+    sse.abort();
+    server.abort();
+
+    let collector = std::mem::take(&mut *collector.lock().unwrap());
+    let duration = collector
+        .last_at
+        .map(|last| last.saturating_duration_since(started))
+        .unwrap_or_else(|| started.elapsed());
+    let seconds = duration.as_secs_f64().max(f64::EPSILON);
+    Ok(BenchReport {
+        sessions: args.sessions,
+        chunks_per_session: args.chunks,
+        chunk_rate: args.chunk_rate,
+        chunk_bytes: args.chunk_bytes,
+        expected_events,
+        delivered_events: collector.received,
+        dropped_events: expected_events.saturating_sub(collector.received),
+        duration_ms: duration.as_secs_f64() * 1000.0,
+        events_per_sec: collector.received as f64 / seconds,
+        bytes_per_sec: collector.bytes as f64 / seconds,
+        latency_ms: Percentiles::from_micros(collector.latencies),
+        write_lag_ms: Percentiles::from_micros(write_lags),
+    })
+}
+
+/// Prompt one session and return how long, in microseconds, the finished
+/// turn took to show up in `GET /session/:id/message`. The transcript is only
+/// readable once its envelope has been written to SQLite, so this is the
+/// persistence lag behind the live stream.
+async fn run_turn(
+    client: &reqwest::Client,
+    base_url: &str,
+    session_id: &str,
+    chunks: usize,
+) -> Result<u64, CliError> {
+    let response = client
+        .post(format!("{base_url}/session/{session_id}/message"))
+        .json(&json!({
+            "agent": BENCH_AGENT,
+            "parts": [{"type": "text", "text": "bench"}]
+        }))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(CliError::HttpStatus(response.status()));
+    }
+    let turn_ended = Instant::now();
+
+    loop {
+        let messages: Value = client
+            .get(format!("{base_url}/session/{session_id}/message"))
+            .send()
+            .await?
+            .json()
+            .await?;
+        if persisted_chunks(&messages) >= chunks {
+            return Ok(turn_ended.elapsed().as_micros() as u64);
+        }
+        if turn_ended.elapsed() > WRITE_LAG_TIMEOUT {
+            return Err(CliError::Bench(format!(
+                "session {session_id} transcript was not persisted within {}s",
+                WRITE_LAG_TIMEOUT.as_secs()
+            )));
+        }
+        tokio::time::sleep(WRITE_LAG_POLL).await;
+    }
+}
+
+fn persisted_chunks(messages: &Value) -> usize {
+    messages
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|message| {
+            message.pointer("/info/role").and_then(Value::as_str) == Some("assistant")
+        })
+        .flat_map(|message| {
+            message
+                .get("parts")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+        })
+        .filter_map(|part| part.get("text").and_then(Value::as_str))
+        .map(|text| text.matches(';').count())
+        .sum()
+}
+
+#[derive(Default)]
+struct Collector {
+    received: usize,
+    bytes: usize,
+    latencies: Vec<u64>,
+    last_at: Option<Instant>,
+}
+
+async fn collect_events(
+    response: reqwest::Response,
+    epoch: Instant,
+    collector: Arc<Mutex<Collector>>,
+) {
+    let mut stream = response.bytes_stream();
+    let mut buffer = String::new();
+    while let Some(Ok(bytes)) = stream.next().await {
+        buffer.push_str(&String::from_utf8_lossy(&bytes));
+        while let Some(end) = buffer.find("\n\n") {
+            let frame: String = buffer.drain(..end + 2).collect();
+            for line in frame.lines() {
+                let Some(data) = line.strip_prefix("data:") else {
+                    continue;
+                };
+                let Ok(event) = serde_json::from_str::<Value>(data.trim_start()) else {
+                    continue;
+                };
+                record_event(&event, epoch, &collector);
+            }
+        }
+    }
+}
+
+fn record_event(event: &Value, epoch: Instant, collector: &Mutex<Collector>) {
+    if event.get("type").and_then(Value::as_str) != Some("message.part.updated") {
+        return;
+    }
+    let Some(delta) = event.pointer("/properties/delta").and_then(Value::as_str) else {
+        return;
+    };
+    let Some(sent_micros) = parse_chunk_stamp(delta) else {
+        return;
+    };
+    let now = Instant::now();
+    let latency = (now.duration_since(epoch).as_micros() as u64).saturating_sub(sent_micros);
+    let mut collector = collector.lock().unwrap();
+    collector.received += 1;
+    collector.bytes += delta.len();
+    collector.latencies.push(latency);
+    collector.last_at = Some(now);
+}
+
+/// Chunks are `<seq>:<micros since bench start>;` followed by padding.
+fn format_chunk(seq: usize, sent_micros: u64, chunk_bytes: usize) -> String {
+    let mut text = format!("{seq}:{sent_micros};");
+    if text.len() < chunk_bytes {
+        text.push_str(&".".repeat(chunk_bytes - text.len()));
+    }
+    text
+}
+
+fn parse_chunk_stamp(text: &str) -> Option<u64> {
+    let (header, _) = text.split_once(';')?;
+    let (_, micros) = header.split_once(':')?;
+    micros.parse().ok()
+}
+
+fn print_report(report: &BenchReport) {
+    println!(
+        "sessions: {}  chunks/session: {}  chunk rate: {}/s  chunk bytes: {}",
+        report.sessions, report.chunks_per_session, report.chunk_rate, report.chunk_bytes
+    );
+    println!(
+        "events: {} delivered / {} expected ({} dropped) in {:.1}ms",
+        report.delivered_events, report.expected_events, report.dropped_events, report.duration_ms
+    );
+    println!(
+        "throughput: {:.0} events/s, {:.1} KiB/s",
+        report.events_per_sec,
+        report.bytes_per_sec / 1024.0
+    );
+    let row = |label: &str, p: &Percentiles| {
+        println!(
+            "{label}: p50 {:.2}ms  p95 {:.2}ms  p99 {:.2}ms  max {:.2}ms",
+            p.p50, p.p95, p.p99, p.max
+        );
+    };
+    row("sse latency", &report.latency_ms);
+    row("write lag", &report.write_lag_ms);
+}
+
+fn remove_sqlite_files(path: &Path) {
+    for suffix in ["", "-wal", "-shm", "-journal"] {
+        let mut file = path.as_os_str().to_owned();
+        file.push(suffix);
+        let _ = std::fs::remove_file(PathBuf::from(file));
+    }
+}
+
+/// Fake ACP backend: answers the bootstrap calls and streams timestamped
+/// `agent_message_chunk` updates for every `session/prompt`, followed by the
+/// prompt response on the same stream like a real agent process.
+struct BenchDispatch {
+    epoch: Instant,
+    chunks: usize,
+    chunk_interval: Option<Duration>,
+    chunk_bytes: usize,
+    channels: Mutex<HashMap<String, broadcast::Sender<Value>>>,
+}
+
+impl BenchDispatch {
+    fn new(args: &BenchArgs, epoch: Instant) -> Self {
+        Self {
+            epoch,
+            chunks: args.chunks,
+            chunk_interval: (args.chunk_rate > 0)
+                .then(|| Duration::from_secs_f64(1.0 / f64::from(args.chunk_rate))),
+            chunk_bytes: args.chunk_bytes,
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn sender(&self, server_id: &str) -> broadcast::Sender<Value> {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(server_id.to_string())
+            .or_insert_with(|| broadcast::channel(self.chunks + 64).0)
+            .clone()
+    }
+
+    async fn stream_turn(&self, server_id: &str, session_id: Value) {
+        let sender = self.sender(server_id);
+        let mut ticker = self.chunk_interval.map(tokio::time::interval);
+        for seq in 0..self.chunks {
+            if let Some(ticker) = ticker.as_mut() {
+                ticker.tick().await;
+            }
+            let sent_micros = self.epoch.elapsed().as_micros() as u64;
+            let _ = sender.send(json!({
+                "jsonrpc": "2.0",
+                "method": "session/update",
+                "params": {
+                    "sessionId": session_id,
+                    "update": {
+                        "sessionUpdate": "agent_message_chunk",
+                        "content": {
+                            "type": "text",
+                            "text": format_chunk(seq, sent_micros, self.chunk_bytes)
+                        }
+                    }
+                }
+            }));
+        }
+    }
+}
+
+impl AcpDispatch for BenchDispatch {
+    fn post(
+        &self,
+        server_id: &str,
+        _bootstrap_agent: Option<&str>,
+        payload: Value,
+    ) -> Pin<Box<dyn Future<Output = Result<AcpDispatchResult, String>> + Send + '_>> {
+        let server_id = server_id.to_string();
+        Box::pin(async move {
+            let id = payload.get("id").cloned().unwrap_or(Value::Null);
+            let result = match payload.get("method").and_then(Value::as_str) {
+                Some("initialize") => json!({"protocolVersion": 1}),
+                Some("session/new") => json!({"sessionId": server_id}),
+                Some("session/prompt") => {
+                    let session_id = payload
+                        .pointer("/params/sessionId")
+                        .cloned()
+                        .unwrap_or(Value::Null);
+                    self.stream_turn(&server_id, session_id).await;
+                    let response = json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "result": {"stopReason": "end_turn"}
+                    });
+                    let _ = self.sender(&server_id).send(response.clone());
+                    return Ok(AcpDispatchResult::Response(response));
+                }
+                _ if payload.get("id").is_none() => return Ok(AcpDispatchResult::Accepted),
+                _ => json!({}),
+            };
+            Ok(AcpDispatchResult::Response(
+                json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            ))
+        })
+    }
+
+    fn notification_stream(
+        &self,
+        server_id: &str,
+        _last_event_id: Option<u64>,
+    ) -> Pin<Box<dyn Future<Output = Result<AcpPayloadStream, String>> + Send + '_>> {
+        let receiver = self.sender(server_id).subscribe();
+        Box::pin(async move {
+            let stream = BroadcastStream::new(receiver).filter_map(|item| async move { item.ok() });
+            Ok(Box::pin(stream) as AcpPayloadStream)
+        })
+    }
+
+    fn delete(
+        &self,
+        server_id: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + '_>> {
+        self.channels.lock().unwrap().remove(server_id);
+        Box::pin(async { Ok(()) })
+    }
+}
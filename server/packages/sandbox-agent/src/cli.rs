@@ -11,9 +11,15 @@ mod build_version {
     include!(concat!(env!("OUT_DIR"), "/version.rs"));
 }
 
+use crate::attach::AttachArgs;
+use crate::bench::BenchArgs;
+use crate::conformance::ConformanceArgs;
+use crate::prompt::PromptArgs;
 use crate::router::{
     build_router_with_state, shutdown_servers, AppState, AuthConfig, BrandingMode,
+    CredentialCheckConfig, EventSinkConfig, FederatedSandbox, FederationConfig,
 };
+use crate::schema::SchemaArgs;
 use crate::server_logs::ServerLogs;
 use crate::telemetry;
 use crate::ui;
@@ -24,6 +30,7 @@ use sandbox_agent_agent_credentials::{
     ProviderCredentials,
 };
 use sandbox_agent_agent_management::agents::{AgentId, AgentManager, InstallOptions};
+use sandbox_agent_opencode_adapter::{resolve_sqlite_path, DatabaseLock, DatabaseLockError};
 use serde::Serialize;
 use serde_json::{json, Value};
 use thiserror::Error;
@@ -83,6 +90,21 @@ pub enum Command {
     InstallAgent(InstallAgentArgs),
     /// Inspect locally discovered credentials.
     Credentials(CredentialsArgs),
+    /// Prompt an OpenCode session and stream the turn as universal events
+    /// (NDJSON), optionally into a shell command.
+    Prompt(PromptArgs),
+    /// Attach an interactive terminal to an OpenCode session: stream its
+    /// events, send prompts, and answer permission and question requests.
+    Attach(AttachArgs),
+    /// Run a scripted conformance checklist against an agent through the
+    /// server and print a JSON report.
+    Conformance(ConformanceArgs),
+    /// Export JSON Schemas of universal events, `/opencode/event` payloads
+    /// and problem details for SDK code generation.
+    Schema(SchemaArgs),
+    /// Measure event pipeline latency and throughput with synthetic sessions.
+    #[command(hide = true)]
+    Bench(BenchArgs),
 }
 
 #[derive(Args, Debug)]
@@ -107,6 +129,27 @@ pub struct ServerArgs {
 
     #[arg(long = "no-telemetry")]
     no_telemetry: bool,
+
+    /// Check each agent's provider credentials before accepting connections
+    /// and report the results in `/v1/agents` and `/v1/health`.
+    #[arg(long = "validate-agents")]
+    validate_agents: bool,
+
+    /// Take the OpenCode session database over from another server using
+    /// it, instead of refusing to start.
+    #[arg(long = "force-takeover")]
+    force_takeover: bool,
+
+    /// Act as a controller for another sandbox-agent, served under
+    /// `/v1/federation/sandboxes/NAME/`. Repeat for each sandbox; adds to
+    /// `SANDBOX_AGENT_FEDERATION`.
+    #[arg(long = "federate", value_name = "NAME=URL")]
+    federate: Vec<FederatedSandbox>,
+
+    /// Bearer token for the federated sandboxes. Defaults to
+    /// `SANDBOX_AGENT_FEDERATION_TOKEN`.
+    #[arg(long = "federation-token")]
+    federation_token: Option<String>,
 }
 
 #[derive(Args, Debug)]
@@ -346,6 +389,10 @@ pub enum CliError {
     Server(String),
     #[error("unexpected http status: {0}")]
     HttpStatus(reqwest::StatusCode),
+    #[error("bench error: {0}")]
+    Bench(String),
+    #[error(transparent)]
+    DatabaseLock(#[from] DatabaseLockError),
 }
 
 pub struct CliConfig {
@@ -389,6 +436,7 @@ pub fn init_logging(command: &Command) -> Result<(), CliError> {
                 .layer()
                 .with_writer(std::io::stderr),
         )
+        .with(sandbox_agent_opencode_adapter::session_log_layer())
         .init();
     Ok(())
 }
@@ -401,6 +449,11 @@ pub fn run_command(command: &Command, cli: &CliConfig) -> Result<(), CliError> {
         Command::Daemon(subcommand) => run_daemon(&subcommand.command, cli),
         Command::InstallAgent(args) => install_agent_local(args),
         Command::Credentials(subcommand) => run_credentials(&subcommand.command),
+        Command::Prompt(args) => crate::prompt::run(args, cli),
+        Command::Attach(args) => crate::attach::run(args, cli),
+        Command::Conformance(args) => crate::conformance::run(args, cli),
+        Command::Schema(args) => crate::schema::run(args),
+        Command::Bench(args) => crate::bench::run(args),
     }
 }
 
@@ -417,10 +470,44 @@ fn run_server(cli: &CliConfig, server: &ServerArgs) -> Result<(), CliError> {
         BrandingMode::SandboxAgent
     };
 
+    let db_path = resolve_sqlite_path(None);
+    let database_lock = if server.force_takeover {
+        DatabaseLock::acquire_with_takeover(&db_path, None)?
+    } else {
+        DatabaseLock::acquire(&db_path)?
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| CliError::Server(err.to_string()))?;
+
+    let mut federation = FederationConfig::from_env().map_err(CliError::Server)?;
+    federation.sandboxes.extend(server.federate.iter().cloned());
+    if let Some(token) = server.federation_token.clone() {
+        federation.token = Some(token);
+    }
+    federation.validate().map_err(CliError::Server)?;
+
     let agent_manager = AgentManager::new(default_install_dir())
         .map_err(|err| CliError::Server(err.to_string()))?;
-    let state = Arc::new(AppState::with_branding(auth, agent_manager, branding));
-    let (mut router, state) = build_router_with_state(state);
+    let mut state = AppState::with_branding(auth, agent_manager, branding)
+        .with_opencode_database_lock(database_lock);
+    if !federation.sandboxes.is_empty() {
+        state = state.with_federation(federation);
+    }
+    let event_sinks = EventSinkConfig::from_env().map_err(CliError::Server)?;
+    event_sinks.validate().map_err(CliError::Server)?;
+    if !event_sinks.sinks.is_empty() {
+        state = state.with_event_sinks(event_sinks);
+    }
+    let state = Arc::new(state);
+    // Inside the runtime, the OpenCode adapter starts its background jobs
+    // (including the database fence check) now rather than on first request.
+    let (mut router, state) = {
+        let _runtime = runtime.enter();
+        build_router_with_state(state)
+    };
 
     let cors = build_cors_layer(server)?;
     router = router.layer(cors);
@@ -431,12 +518,9 @@ fn run_server(cli: &CliConfig, server: &ServerArgs) -> Result<(), CliError> {
         other => other,
     };
     let inspector_url = format!("http://{}:{}/ui", display_host, server.port);
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .map_err(|err| CliError::Server(err.to_string()))?;
 
     let telemetry_enabled = telemetry::telemetry_enabled(server.no_telemetry);
+    let validate_agents = server.validate_agents;
 
     runtime.block_on(async move {
         if telemetry_enabled {
@@ -444,6 +528,17 @@ fn run_server(cli: &CliConfig, server: &ServerArgs) -> Result<(), CliError> {
             telemetry::spawn_telemetry_task();
         }
 
+        if validate_agents {
+            let credentials = tokio::task::spawn_blocking(|| {
+                extract_all_credentials(&CredentialExtractionOptions::new())
+            })
+            .await
+            .map_err(|err| CliError::Server(err.to_string()))?;
+            state
+                .validate_agent_credentials(&credentials, &CredentialCheckConfig::from_env())
+                .await;
+        }
+
         let listener = tokio::net::TcpListener::bind(&addr).await?;
         tracing::info!(addr = %addr, "server listening");
         if ui::is_enabled() {
@@ -1042,17 +1137,21 @@ fn build_cors_layer(server: &ServerArgs) -> Result<CorsLayer, CliError> {
         cors = cors.allow_credentials(true);
     }
 
+    cors = cors.expose_headers([axum::http::HeaderName::from_static(
+        crate::router::REQUEST_TIMEOUT_HEADER,
+    )]);
+
     Ok(cors)
 }
 
-struct ClientContext {
+pub(crate) struct ClientContext {
     endpoint: String,
     token: Option<String>,
     client: HttpClient,
 }
 
 impl ClientContext {
-    fn new(cli: &CliConfig, args: &ClientArgs) -> Result<Self, CliError> {
+    pub(crate) fn new(cli: &CliConfig, args: &ClientArgs) -> Result<Self, CliError> {
         let endpoint = args
             .endpoint
             .clone()
@@ -1070,11 +1169,15 @@ impl ClientContext {
         })
     }
 
+    pub(crate) fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
     fn url(&self, path: &str) -> String {
         format!("{}{}", self.endpoint.trim_end_matches('/'), path)
     }
 
-    fn request(&self, method: Method, path: &str) -> reqwest::blocking::RequestBuilder {
+    pub(crate) fn request(&self, method: Method, path: &str) -> reqwest::blocking::RequestBuilder {
         let url = self.url(path);
         let mut builder = self.client.request(method, url);
         if let Some(token) = &self.token {
@@ -1160,7 +1263,7 @@ fn print_empty_response(response: reqwest::blocking::Response) -> Result<(), Cli
     Err(CliError::HttpStatus(status))
 }
 
-fn print_error_body(text: &str) -> Result<(), CliError> {
+pub(crate) fn print_error_body(text: &str) -> Result<(), CliError> {
     if let Ok(json) = serde_json::from_str::<Value>(text) {
         let pretty = serde_json::to_string_pretty(&json)?;
         write_stderr_line(&pretty)
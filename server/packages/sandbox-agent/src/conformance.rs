@@ -0,0 +1,600 @@
+//! `sandbox-agent conformance`: run a scripted checklist against one agent
+//! through a running server's OpenCode compat API, i.e. the same dispatch
+//! path real clients use, and print a machine-readable report.
+//!
+//! The checks run in order on one session: create it, prompt for plain text,
+//! have the agent call a tool (answering any permission request), cancel a
+//! long turn, and prompt again to check the session resumes with its
+//! history. A check whose prerequisite failed is skipped.
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use clap::Args;
+use reqwest::Method;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::cli::{CliConfig, CliError, ClientArgs, ClientContext};
+
+const OPENCODE_PREFIX: &str = "/opencode";
+/// The word the agent is asked to reply with, and later to recall.
+const MARKER: &str = "CONFORMANCE";
+const TOOL_OUTPUT: &str = "sandbox-agent-conformance";
+
+#[derive(Args, Debug)]
+pub struct ConformanceArgs {
+    /// Agent to check, e.g. `claude`.
+    #[arg(long)]
+    agent: String,
+
+    /// Working directory for the session; the tool check runs a shell
+    /// command in it.
+    #[arg(long)]
+    directory: Option<String>,
+
+    /// Write the report to this file instead of stdout.
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Seconds each check may take.
+    #[arg(long, default_value_t = 300)]
+    timeout: u64,
+
+    #[command(flatten)]
+    client: ClientArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum CheckStatus {
+    Pass,
+    Fail,
+    Skip,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CheckResult {
+    name: &'static str,
+    status: CheckStatus,
+    duration_ms: u64,
+    detail: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Report {
+    agent: String,
+    endpoint: String,
+    #[serde(rename = "sessionID")]
+    session_id: Option<String>,
+    conformant: bool,
+    summary: Summary,
+    checks: Vec<CheckResult>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+struct Summary {
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+impl Summary {
+    fn of(checks: &[CheckResult]) -> Self {
+        let count = |status| checks.iter().filter(|check| check.status == status).count();
+        Self {
+            passed: count(CheckStatus::Pass),
+            failed: count(CheckStatus::Fail),
+            skipped: count(CheckStatus::Skip),
+        }
+    }
+}
+
+pub fn run(args: &ConformanceArgs, cli: &CliConfig) -> Result<(), CliError> {
+    let ctx = ClientContext::new(cli, &args.client)?;
+    let mut runner = Runner {
+        ctx: &ctx,
+        agent: args.agent.clone(),
+        timeout: Duration::from_secs(args.timeout),
+        cursor: None,
+        session_id: None,
+        permission: None,
+        checks: Vec::new(),
+    };
+    runner.run_all(args.directory.as_deref());
+
+    let summary = Summary::of(&runner.checks);
+    let report = Report {
+        agent: args.agent.clone(),
+        endpoint: ctx.endpoint().to_string(),
+        session_id: runner.session_id.clone(),
+        conformant: summary.failed == 0,
+        summary,
+        checks: runner.checks,
+    };
+    let rendered = serde_json::to_string_pretty(&report)?;
+    match &args.output {
+        Some(path) => std::fs::write(path, format!("{rendered}\n"))?,
+        None => println!("{rendered}"),
+    }
+    if report.conformant {
+        Ok(())
+    } else {
+        Err(CliError::Server(format!(
+            "{} of {} conformance checks failed",
+            report.summary.failed,
+            report.checks.len()
+        )))
+    }
+}
+
+/// How a prompt turn ended, as seen on the event stream.
+struct TurnOutcome {
+    status: String,
+    events: Vec<Value>,
+}
+
+struct Runner<'a> {
+    ctx: &'a ClientContext,
+    agent: String,
+    timeout: Duration,
+    /// `/event/poll` cursor; `None` until the first poll.
+    cursor: Option<u64>,
+    session_id: Option<String>,
+    /// Result of the permission round trip made during `tool.call`.
+    permission: Option<Result<String, String>>,
+    checks: Vec<CheckResult>,
+}
+
+impl Runner<'_> {
+    fn run_all(&mut self, directory: Option<&str>) {
+        // Start the event cursor before anything happens on the session.
+        let created = match self.poll(Duration::ZERO) {
+            Ok(_) => self.check("session.create", None, |runner| {
+                runner.create_session(directory)
+            }),
+            Err(err) => self.check("session.create", None, |_| Err(err)),
+        };
+        let prompted = self.check(
+            "prompt.text",
+            (!created).then_some("session.create"),
+            Self::check_text_prompt,
+        );
+        let needs_prompt = (!prompted).then_some("prompt.text");
+        self.check("tool.call", needs_prompt, Self::check_tool_call);
+        match self.permission.take() {
+            Some(result) => self.record("permission.round_trip", 0, result),
+            None => self.skip(
+                "permission.round_trip",
+                needs_prompt.map_or("the agent did not ask for permission".to_string(), |name| {
+                    format!("requires {name}")
+                }),
+            ),
+        }
+        self.check("turn.cancel", needs_prompt, Self::check_cancel);
+        self.check("session.resume", needs_prompt, Self::check_resume);
+    }
+
+    /// Run one check, or skip it when `prerequisite` names a check that did
+    /// not pass. Returns whether it passed.
+    fn check(
+        &mut self,
+        name: &'static str,
+        prerequisite: Option<&str>,
+        run: impl FnOnce(&mut Self) -> Result<String, String>,
+    ) -> bool {
+        if let Some(prerequisite) = prerequisite {
+            self.skip(name, format!("requires {prerequisite}"));
+            return false;
+        }
+        let started = Instant::now();
+        let result = run(self);
+        let passed = result.is_ok();
+        self.record(name, started.elapsed().as_millis() as u64, result);
+        passed
+    }
+
+    fn record(&mut self, name: &'static str, duration_ms: u64, result: Result<String, String>) {
+        let (status, detail) = match result {
+            Ok(detail) => (CheckStatus::Pass, detail),
+            Err(detail) => (CheckStatus::Fail, detail),
+        };
+        self.checks.push(CheckResult {
+            name,
+            status,
+            duration_ms,
+            detail,
+        });
+    }
+
+    fn skip(&mut self, name: &'static str, detail: String) {
+        self.checks.push(CheckResult {
+            name,
+            status: CheckStatus::Skip,
+            duration_ms: 0,
+            detail,
+        });
+    }
+
+    fn session_id(&self) -> &str {
+        self.session_id.as_deref().unwrap_or_default()
+    }
+
+    fn create_session(&mut self, directory: Option<&str>) -> Result<String, String> {
+        let mut request = self
+            .ctx
+            .request(Method::POST, &format!("{OPENCODE_PREFIX}/session"))
+            .json(&json!({"title": format!("conformance: {}", self.agent)}));
+        if let Some(directory) = directory {
+            request = request.header("x-opencode-directory", directory);
+        }
+        let session = json_response(request.send().map_err(|err| err.to_string())?)?;
+        let id = session
+            .get("id")
+            .and_then(Value::as_str)
+            .ok_or("session response has no id")?;
+        self.session_id = Some(id.to_string());
+        Ok(format!("created {id}"))
+    }
+
+    fn check_text_prompt(&mut self) -> Result<String, String> {
+        let outcome = self.prompt(
+            &format!("Reply with exactly the word {MARKER} and nothing else."),
+            |_, _| {},
+        )?;
+        if outcome.status != "completed" {
+            return Err(format!("turn ended with status {}", outcome.status));
+        }
+        let reply = self.last_reply()?;
+        if reply.trim().is_empty() {
+            return Err("the agent sent no text".to_string());
+        }
+        Ok(format!("replied {:?}", truncate(&reply, 80)))
+    }
+
+    /// Also records the permission round trip in `self.permission` when
+    /// the agent asked for one.
+    fn check_tool_call(&mut self) -> Result<String, String> {
+        let mut permission: Option<Result<String, String>> = None;
+        let outcome = self.prompt(
+            &format!(
+                "Use your shell tool to run `echo {TOOL_OUTPUT}`, then reply with its output."
+            ),
+            |runner, event| {
+                if event_type(event) != Some("permission.asked") {
+                    return;
+                }
+                let Some(request_id) = event.pointer("/properties/id").and_then(Value::as_str)
+                else {
+                    return;
+                };
+                let reply = runner
+                    .ctx
+                    .request(
+                        Method::POST,
+                        &format!("{OPENCODE_PREFIX}/permission/{request_id}/reply"),
+                    )
+                    .json(&json!({"reply": "once"}))
+                    .send()
+                    .map_err(|err| err.to_string())
+                    .and_then(json_response);
+                permission = Some(
+                    reply.map(|_| format!("answered permission request {request_id} with once")),
+                );
+            },
+        );
+        let outcome = match outcome {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                self.permission = permission;
+                return Err(err);
+            }
+        };
+        let tool_events = outcome
+            .events
+            .iter()
+            .filter(|event| event_type(event).is_some_and(|kind| kind.starts_with("tool.")))
+            .count();
+        if let Some(Ok(detail)) = permission.as_ref() {
+            let replied = outcome
+                .events
+                .iter()
+                .any(|event| event_type(event) == Some("permission.replied"));
+            if !replied {
+                permission = Some(Err(format!("{detail}, but no permission.replied followed")));
+            }
+        }
+        self.permission = permission;
+        if outcome.status != "completed" {
+            return Err(format!("turn ended with status {}", outcome.status));
+        }
+        if tool_events == 0 {
+            return Err("no tool events were emitted".to_string());
+        }
+        let reply = self.last_reply()?;
+        let detail = if reply.contains(TOOL_OUTPUT) {
+            format!("{tool_events} tool events; the reply quotes the output")
+        } else {
+            format!("{tool_events} tool events")
+        };
+        Ok(detail)
+    }
+
+    fn check_cancel(&mut self) -> Result<String, String> {
+        let turn_id =
+            self.start_turn("Count from 1 to 2000, one number per line. Do not use tools.")?;
+        // Abort once the agent is streaming, or after a short wait.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut events = Vec::new();
+        while Instant::now() < deadline {
+            let polled = self.poll(Duration::from_millis(500))?;
+            let streaming = polled
+                .iter()
+                .any(|event| event_type(event) == Some("message.part.updated"));
+            events.extend(polled);
+            if streaming {
+                break;
+            }
+        }
+        let aborted = self
+            .ctx
+            .request(
+                Method::POST,
+                &format!("{OPENCODE_PREFIX}/session/{}/abort", self.session_id()),
+            )
+            .send()
+            .map_err(|err| err.to_string())?;
+        if !aborted.status().is_success() {
+            return Err(format!("abort returned {}", aborted.status()));
+        }
+        let started = Instant::now();
+        let outcome = self.wait_for_turn(&turn_id, events, |_, _| {})?;
+        self.wait_for_idle()?;
+        Ok(format!(
+            "turn ended with status {} {}ms after abort",
+            outcome.status,
+            started.elapsed().as_millis()
+        ))
+    }
+
+    fn check_resume(&mut self) -> Result<String, String> {
+        let outcome = self.prompt(
+            "What exact word did you reply with to my first message? Reply with just that word.",
+            |_, _| {},
+        )?;
+        if outcome.status != "completed" {
+            return Err(format!("turn ended with status {}", outcome.status));
+        }
+        let reply = self.last_reply()?;
+        if !reply.to_ascii_uppercase().contains(MARKER) {
+            return Err(format!(
+                "the agent did not recall the earlier reply: {:?}",
+                truncate(&reply, 80)
+            ));
+        }
+        Ok("the session kept its history".to_string())
+    }
+
+    /// Run one prompt turn to the end, passing each event to `on_event`.
+    fn prompt(
+        &mut self,
+        text: &str,
+        on_event: impl FnMut(&Runner, &Value),
+    ) -> Result<TurnOutcome, String> {
+        let turn_id = self.start_turn(text)?;
+        let outcome = self.wait_for_turn(&turn_id, Vec::new(), on_event)?;
+        self.wait_for_idle()?;
+        Ok(outcome)
+    }
+
+    fn start_turn(&mut self, text: &str) -> Result<String, String> {
+        let response = self
+            .ctx
+            .request(
+                Method::POST,
+                &format!(
+                    "{OPENCODE_PREFIX}/session/{}/prompt_async",
+                    self.session_id()
+                ),
+            )
+            .json(&json!({
+                "agent": self.agent,
+                "parts": [{"type": "text", "text": text}],
+            }))
+            .send()
+            .map_err(|err| err.to_string())?;
+        let turn = json_response(response)?;
+        turn.get("id")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| "prompt response has no turn id".to_string())
+    }
+
+    fn wait_for_turn(
+        &mut self,
+        turn_id: &str,
+        mut events: Vec<Value>,
+        mut on_event: impl FnMut(&Runner, &Value),
+    ) -> Result<TurnOutcome, String> {
+        for event in &events {
+            on_event(self, event);
+        }
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if let Some(completed) = events.iter().find(|event| {
+                event_type(event) == Some("turn.completed")
+                    && event.pointer("/properties/id").and_then(Value::as_str) == Some(turn_id)
+            }) {
+                let status = completed
+                    .pointer("/properties/status")
+                    .and_then(Value::as_str)
+                    .unwrap_or("unknown")
+                    .to_string();
+                return Ok(TurnOutcome { status, events });
+            }
+            if Instant::now() >= deadline {
+                return Err(format!("turn {turn_id} did not finish in time"));
+            }
+            for event in self.poll(Duration::from_secs(1))? {
+                if event_session(&event) == Some(self.session_id()) {
+                    on_event(self, &event);
+                }
+                events.push(event);
+            }
+        }
+    }
+
+    /// Wait until the session reports idle, after its turn's events have
+    /// all been translated.
+    fn wait_for_idle(&mut self) -> Result<(), String> {
+        let deadline = Instant::now() + self.timeout;
+        while Instant::now() < deadline {
+            let statuses = json_response(
+                self.ctx
+                    .request(Method::GET, &format!("{OPENCODE_PREFIX}/session/status"))
+                    .send()
+                    .map_err(|err| err.to_string())?,
+            )?;
+            let busy = statuses
+                .get(self.session_id())
+                .and_then(|status| status.get("type"))
+                .and_then(Value::as_str)
+                == Some("busy");
+            if !busy {
+                return Ok(());
+            }
+            self.poll(Duration::from_millis(500))?;
+        }
+        Err("the session did not return to idle".to_string())
+    }
+
+    fn poll(&mut self, wait: Duration) -> Result<Vec<Value>, String> {
+        let mut path = format!("{OPENCODE_PREFIX}/event/poll?waitMs={}", wait.as_millis());
+        if let Some(cursor) = self.cursor {
+            path.push_str(&format!("&since={cursor}"));
+        }
+        let polled = json_response(
+            self.ctx
+                .request(Method::GET, &path)
+                .send()
+                .map_err(|err| err.to_string())?,
+        )?;
+        if let Some(cursor) = polled.get("cursor").and_then(Value::as_u64) {
+            self.cursor = Some(cursor);
+        }
+        Ok(polled
+            .get("events")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// Text of the session's latest assistant message.
+    fn last_reply(&self) -> Result<String, String> {
+        let messages = json_response(
+            self.ctx
+                .request(
+                    Method::GET,
+                    &format!("{OPENCODE_PREFIX}/session/{}/message", self.session_id()),
+                )
+                .send()
+                .map_err(|err| err.to_string())?,
+        )?;
+        Ok(last_assistant_text(&messages))
+    }
+}
+
+fn json_response(response: reqwest::blocking::Response) -> Result<Value, String> {
+    let status = response.status();
+    let body = response.text().map_err(|err| err.to_string())?;
+    if !status.is_success() {
+        return Err(format!("HTTP {status}: {}", truncate(&body, 200)));
+    }
+    serde_json::from_str(&body).map_err(|err| err.to_string())
+}
+
+fn event_type(event: &Value) -> Option<&str> {
+    event.get("type").and_then(Value::as_str)
+}
+
+fn event_session(event: &Value) -> Option<&str> {
+    let properties = event.get("properties")?;
+    properties
+        .get("sessionID")
+        .or_else(|| properties.pointer("/info/sessionID"))
+        .or_else(|| properties.pointer("/part/sessionID"))
+        .and_then(Value::as_str)
+}
+
+/// The text parts of the last assistant message in a `/message` listing.
+fn last_assistant_text(messages: &Value) -> String {
+    messages
+        .as_array()
+        .into_iter()
+        .flatten()
+        .rev()
+        .find(|message| message.pointer("/info/role").and_then(Value::as_str) == Some("assistant"))
+        .and_then(|message| message.get("parts").and_then(Value::as_array))
+        .into_iter()
+        .flatten()
+        .filter(|part| part.get("type").and_then(Value::as_str) == Some("text"))
+        .filter_map(|part| part.get("text").and_then(Value::as_str))
+        .collect::<Vec<_>>()
+        .join("")
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(status: CheckStatus) -> CheckResult {
+        CheckResult {
+            name: "check",
+            status,
+            duration_ms: 0,
+            detail: String::new(),
+        }
+    }
+
+    #[test]
+    fn summary_counts_each_status() {
+        let checks = [
+            check(CheckStatus::Pass),
+            check(CheckStatus::Pass),
+            check(CheckStatus::Fail),
+            check(CheckStatus::Skip),
+        ];
+        assert_eq!(
+            Summary::of(&checks),
+            Summary {
+                passed: 2,
+                failed: 1,
+                skipped: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn last_assistant_text_joins_the_latest_reply() {
+        let messages = json!([
+            {"info": {"role": "assistant"}, "parts": [{"type": "text", "text": "old"}]},
+            {"info": {"role": "user"}, "parts": [{"type": "text", "text": "question"}]},
+            {"info": {"role": "assistant"}, "parts": [
+                {"type": "reasoning", "text": "thinking"},
+                {"type": "text", "text": "CONFORM"},
+                {"type": "text", "text": "ANCE"},
+            ]},
+        ]);
+        assert_eq!(last_assistant_text(&messages), "CONFORMANCE");
+        assert_eq!(last_assistant_text(&json!([])), "");
+    }
+}
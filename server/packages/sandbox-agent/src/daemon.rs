@@ -519,9 +519,9 @@ pub fn stop(host: &str, port: u16) -> Result<(), CliError> {
 
     eprintln!("stopping daemon (PID {pid})...");
 
-    // Use taskkill on Windows
+    // Use taskkill on Windows; `/T` also stops the agent processes it spawned.
     let _ = ProcessCommand::new("taskkill")
-        .args(["/PID", &pid.to_string(), "/F"])
+        .args(["/PID", &pid.to_string(), "/T", "/F"])
         .status();
 
     std::thread::sleep(Duration::from_millis(500));
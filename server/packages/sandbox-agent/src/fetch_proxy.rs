@@ -0,0 +1,509 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use acp_http_adapter::process::AdapterRuntime;
+use futures::StreamExt;
+use reqwest::Url;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+/// ACP extension method agents call to fetch a URL through the runtime.
+pub(crate) const FETCH_METHOD: &str = "_sandboxagent/fetch";
+/// Notification published to stream subscribers for every fetch attempt.
+const FETCH_AUDIT_METHOD: &str = "_sandboxagent/fetch/audit";
+
+const DEFAULT_MAX_BYTES: usize = 1024 * 1024;
+const DEFAULT_CACHE_TTL_MS: u64 = 60_000;
+const DEFAULT_FETCH_TIMEOUT_MS: u64 = 30_000;
+const MAX_CACHE_ENTRIES: usize = 256;
+/// Redirects followed per fetch; each hop is checked like the first URL.
+const MAX_REDIRECTS: usize = 5;
+
+const JSONRPC_INVALID_PARAMS: i64 = -32602;
+const FETCH_DENIED_CODE: i64 = -32001;
+const FETCH_FAILED_CODE: i64 = -32002;
+
+#[derive(Debug, Clone)]
+pub(crate) struct FetchPolicy {
+    allow: Vec<String>,
+    deny: Vec<String>,
+    max_bytes: usize,
+    cache_ttl: Duration,
+    timeout: Duration,
+}
+
+impl FetchPolicy {
+    pub(crate) fn from_env() -> Self {
+        Self {
+            allow: domain_list_from_env("SANDBOX_AGENT_FETCH_ALLOWLIST"),
+            deny: domain_list_from_env("SANDBOX_AGENT_FETCH_DENYLIST"),
+            max_bytes: std::env::var("SANDBOX_AGENT_FETCH_MAX_BYTES")
+                .ok()
+                .and_then(|raw| raw.trim().parse::<usize>().ok())
+                .filter(|value| *value > 0)
+                .unwrap_or(DEFAULT_MAX_BYTES),
+            cache_ttl: std::env::var("SANDBOX_AGENT_FETCH_CACHE_TTL_MS")
+                .ok()
+                .and_then(|raw| raw.trim().parse::<u64>().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(Duration::from_millis(DEFAULT_CACHE_TTL_MS)),
+            timeout: Duration::from_millis(DEFAULT_FETCH_TIMEOUT_MS),
+        }
+    }
+
+    /// Denylist entries always win. An empty allowlist permits nothing; `*`
+    /// permits every host that is not denied.
+    fn allows_host(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if self.deny.iter().any(|rule| domain_matches(rule, &host)) {
+            return false;
+        }
+        self.allow.iter().any(|rule| domain_matches(rule, &host))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CachedFetch {
+    fetched_at: Instant,
+    result: Value,
+}
+
+#[derive(Debug)]
+pub(crate) struct FetchProxy {
+    policy: FetchPolicy,
+    cache: Mutex<HashMap<String, CachedFetch>>,
+}
+
+#[derive(Debug)]
+struct FetchError {
+    code: i64,
+    reason: &'static str,
+    message: String,
+}
+
+impl FetchProxy {
+    pub(crate) fn new(policy: FetchPolicy) -> Self {
+        Self {
+            policy,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Watch an agent process stream and answer `_sandboxagent/fetch`
+    /// requests on behalf of the client. Runs until the stream closes.
+    pub(crate) fn attach(self: Arc<Self>, server_id: String, runtime: Arc<AdapterRuntime>) {
+        tokio::spawn(async move {
+            let mut stream = Box::pin(runtime.clone().value_stream(None).await);
+            while let Some(payload) = stream.next().await {
+                if payload.get("method").and_then(Value::as_str) != Some(FETCH_METHOD) {
+                    continue;
+                }
+                let Some(id) = payload.get("id").cloned() else {
+                    continue;
+                };
+                let params = payload.get("params").cloned().unwrap_or(Value::Null);
+
+                let proxy = self.clone();
+                let runtime = runtime.clone();
+                let server_id = server_id.clone();
+                tokio::spawn(async move {
+                    let response = proxy.handle(&server_id, &runtime, id, &params).await;
+                    if let Err(err) = runtime.post(response).await {
+                        tracing::warn!(
+                            server_id = %server_id,
+                            error = %err,
+                            "fetch_proxy: failed to deliver fetch response to agent"
+                        );
+                    }
+                });
+            }
+        });
+    }
+
+    async fn handle(
+        &self,
+        server_id: &str,
+        runtime: &AdapterRuntime,
+        id: Value,
+        params: &Value,
+    ) -> Value {
+        let start = Instant::now();
+        let url = params
+            .get("url")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let outcome = self.fetch(url).await;
+
+        let audit = match &outcome {
+            Ok((result, cached)) => json!({
+                "requestId": id,
+                "url": url,
+                "outcome": "allowed",
+                "status": result.get("status"),
+                "bytes": result.get("bytes"),
+                "truncated": result.get("truncated"),
+                "cached": cached,
+                "durationMs": start.elapsed().as_millis() as u64,
+            }),
+            Err(err) => json!({
+                "requestId": id,
+                "url": url,
+                "outcome": err.reason,
+                "message": err.message,
+                "durationMs": start.elapsed().as_millis() as u64,
+            }),
+        };
+        tracing::info!(server_id = %server_id, audit = %audit, "fetch_proxy: audit");
+        runtime
+            .publish(json!({
+                "jsonrpc": "2.0",
+                "method": FETCH_AUDIT_METHOD,
+                "params": audit,
+            }))
+            .await;
+
+        match outcome {
+            Ok((result, _)) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(err) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {
+                    "code": err.code,
+                    "message": err.message,
+                    "data": {"reason": err.reason},
+                }
+            }),
+        }
+    }
+
+    async fn fetch(&self, raw_url: &str) -> Result<(Value, bool), FetchError> {
+        let mut url = Url::parse(raw_url).map_err(|err| FetchError {
+            code: JSONRPC_INVALID_PARAMS,
+            reason: "invalid",
+            message: format!("invalid url '{raw_url}': {err}"),
+        })?;
+        let mut addrs = self.check_url(&url).await?;
+
+        // Cached under the URL asked for, which is what the next lookup has;
+        // after redirects `url` is where the response came from.
+        let requested = url.to_string();
+        if let Some(cached) = self.cached(&requested).await {
+            return Ok((cached, true));
+        }
+
+        let mut redirects = 0;
+        let mut response = loop {
+            let response = self
+                .client(&url, &addrs)?
+                .get(url.clone())
+                .send()
+                .await
+                .map_err(|err| FetchError {
+                    code: FETCH_FAILED_CODE,
+                    reason: "error",
+                    message: format!("fetch failed: {err}"),
+                })?;
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .filter(|_| response.status().is_redirection());
+            let Some(location) = location else {
+                break response;
+            };
+            if redirects == MAX_REDIRECTS {
+                return Err(FetchError {
+                    code: FETCH_FAILED_CODE,
+                    reason: "error",
+                    message: format!("fetch failed: more than {MAX_REDIRECTS} redirects"),
+                });
+            }
+            redirects += 1;
+            url = url.join(location).map_err(|err| FetchError {
+                code: FETCH_FAILED_CODE,
+                reason: "error",
+                message: format!("invalid redirect '{location}': {err}"),
+            })?;
+            addrs = self.check_url(&url).await?;
+        };
+
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(ToOwned::to_owned);
+
+        let mut body = Vec::new();
+        let mut truncated = false;
+        while let Some(chunk) = response.chunk().await.map_err(|err| FetchError {
+            code: FETCH_FAILED_CODE,
+            reason: "error",
+            message: format!("failed reading response body: {err}"),
+        })? {
+            let remaining = self.policy.max_bytes.saturating_sub(body.len());
+            if chunk.len() > remaining {
+                body.extend_from_slice(&chunk[..remaining]);
+                truncated = true;
+                break;
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        let result = json!({
+            "url": url.as_str(),
+            "status": status.as_u16(),
+            "contentType": content_type,
+            "body": String::from_utf8_lossy(&body),
+            "bytes": body.len(),
+            "truncated": truncated,
+        });
+
+        if status.is_success() {
+            self.store(&requested, result.clone()).await;
+        }
+
+        Ok((result, false))
+    }
+
+    /// Refuse URLs that are not http(s), whose host the policy does not
+    /// permit, or that resolve to a loopback, private, link-local or other
+    /// non-public address. Returns the addresses the host resolved to.
+    async fn check_url(&self, url: &Url) -> Result<Vec<SocketAddr>, FetchError> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(FetchError {
+                code: JSONRPC_INVALID_PARAMS,
+                reason: "invalid",
+                message: format!("unsupported url scheme '{}'", url.scheme()),
+            });
+        }
+        let host = url.host_str().unwrap_or_default();
+        let denied = |message: String| FetchError {
+            code: FETCH_DENIED_CODE,
+            reason: "denied",
+            message,
+        };
+        if !self.policy.allows_host(host) {
+            return Err(denied(format!(
+                "fetching '{host}' is not permitted by the sandbox fetch policy"
+            )));
+        }
+        let port = url.port_or_known_default().unwrap_or(80);
+        let literal = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>();
+        let addrs: Vec<SocketAddr> = match literal {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => tokio::net::lookup_host((host, port))
+                .await
+                .map_err(|err| FetchError {
+                    code: FETCH_FAILED_CODE,
+                    reason: "error",
+                    message: format!("failed to resolve '{host}': {err}"),
+                })?
+                .collect(),
+        };
+        if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+            return Err(denied(format!(
+                "fetching '{host}' is not permitted: it resolves to non-public address {}",
+                addr.ip()
+            )));
+        }
+        Ok(addrs)
+    }
+
+    /// A client that connects to `url`'s host only at `addrs`, the addresses
+    /// [`Self::check_url`] vetted, so the host cannot rebind to an internal
+    /// address in between. Redirects are left to [`Self::fetch`], which
+    /// checks every hop.
+    fn client(&self, url: &Url, addrs: &[SocketAddr]) -> Result<reqwest::Client, FetchError> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(self.policy.timeout)
+            .redirect(reqwest::redirect::Policy::none());
+        if let Some(host) = url.host_str() {
+            builder = builder.resolve_to_addrs(host, addrs);
+        }
+        builder.build().map_err(|err| FetchError {
+            code: FETCH_FAILED_CODE,
+            reason: "error",
+            message: format!("fetch failed: {err}"),
+        })
+    }
+
+    async fn cached(&self, key: &str) -> Option<Value> {
+        if self.policy.cache_ttl.is_zero() {
+            return None;
+        }
+        let mut cache = self.cache.lock().await;
+        match cache.get(key) {
+            Some(entry) if entry.fetched_at.elapsed() < self.policy.cache_ttl => {
+                Some(entry.result.clone())
+            }
+            Some(_) => {
+                cache.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    async fn store(&self, key: &str, result: Value) {
+        if self.policy.cache_ttl.is_zero() {
+            return;
+        }
+        let mut cache = self.cache.lock().await;
+        let ttl = self.policy.cache_ttl;
+        cache.retain(|_, entry| entry.fetched_at.elapsed() < ttl);
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            if let Some(oldest) = cache
+                .iter()
+                .min_by_key(|(_, entry)| entry.fetched_at)
+                .map(|(key, _)| key.clone())
+            {
+                cache.remove(&oldest);
+            }
+        }
+        cache.insert(
+            key.to_string(),
+            CachedFetch {
+                fetched_at: Instant::now(),
+                result,
+            },
+        );
+    }
+}
+
+/// Whether `ip` is a globally routable address: not loopback, private,
+/// link-local (e.g. the `169.254.169.254` metadata service), shared,
+/// multicast, documentation or otherwise reserved.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                || a >= 240
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 198 && (18..20).contains(&b))
+                || (a == 192 && b == 0 && ip.octets()[2] == 0))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(mapped));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                || first == 0x2001 && ip.segments()[1] == 0x0db8
+                || first == 0x0064 && ip.segments()[1] == 0xff9b)
+        }
+    }
+}
+
+fn domain_list_from_env(key: &str) -> Vec<String> {
+    std::env::var(key)
+        .ok()
+        .map(|raw| {
+            raw.split(',')
+                .map(|entry| entry.trim().trim_end_matches('.').to_ascii_lowercase())
+                .filter(|entry| !entry.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// `example.com` matches the domain itself and any subdomain; `*` matches
+/// every host.
+fn domain_matches(rule: &str, host: &str) -> bool {
+    let rule = rule.trim_start_matches("*.");
+    rule == "*" || host == rule || host.ends_with(&format!(".{rule}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str], deny: &[&str]) -> FetchPolicy {
+        FetchPolicy {
+            allow: allow.iter().map(|value| value.to_string()).collect(),
+            deny: deny.iter().map(|value| value.to_string()).collect(),
+            max_bytes: DEFAULT_MAX_BYTES,
+            cache_ttl: Duration::from_millis(DEFAULT_CACHE_TTL_MS),
+            timeout: Duration::from_millis(DEFAULT_FETCH_TIMEOUT_MS),
+        }
+    }
+
+    #[test]
+    fn allowlist_matches_subdomains() {
+        let policy = policy(&["example.com"], &[]);
+        assert!(policy.allows_host("example.com"));
+        assert!(policy.allows_host("docs.example.com"));
+        assert!(!policy.allows_host("badexample.com"));
+        assert!(!policy.allows_host("other.org"));
+    }
+
+    #[test]
+    fn empty_allowlist_permits_nothing() {
+        let policy = policy(&[], &[]);
+        assert!(!policy.allows_host("example.com"));
+    }
+
+    #[test]
+    fn internal_addresses_are_not_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["93.184.216.34", "1.1.1.1", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn urls_resolving_to_internal_addresses_are_denied() {
+        let proxy = FetchProxy::new(policy(&["*"], &[]));
+        for url in [
+            "http://169.254.169.254/latest/meta-data/",
+            "http://127.0.0.1:8080/",
+            "http://[::1]/",
+            "http://localhost/",
+        ] {
+            let err = proxy
+                .check_url(&Url::parse(url).unwrap())
+                .await
+                .unwrap_err();
+            assert_eq!(err.reason, "denied", "{url}: {}", err.message);
+        }
+    }
+
+    #[test]
+    fn denylist_wins_over_allowlist() {
+        let policy = policy(&["*"], &["internal.example.com"]);
+        assert!(policy.allows_host("example.com"));
+        assert!(!policy.allows_host("internal.example.com"));
+        assert!(!policy.allows_host("api.internal.example.com"));
+    }
+}
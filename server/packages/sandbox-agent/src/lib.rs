@@ -1,9 +1,16 @@
 //! Sandbox agent core utilities.
 
 mod acp_proxy_runtime;
+mod attach;
+mod bench;
 pub mod cli;
+mod conformance;
 pub mod daemon;
+mod fetch_proxy;
+mod prompt;
 pub mod router;
+mod schema;
 pub mod server_logs;
 pub mod telemetry;
 pub mod ui;
+pub mod universal_events;
@@ -0,0 +1,538 @@
+//! `sandbox-agent prompt`: send a prompt to an OpenCode compat session and
+//! stream the turn as universal events (NDJSON), either to stdout or to the
+//! stdin of a shell command given with `--exec`.
+//!
+//! The command subscribes to `/opencode/event` before starting the turn with
+//! `prompt_async`, converts the session's events into the universal event
+//! schema, and exits once the turn completes.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, Command as ProcessCommand, Stdio};
+use std::time::Duration;
+
+use clap::Args;
+use reqwest::Method;
+use serde_json::{json, Value};
+
+use crate::cli::{CliConfig, CliError, ClientArgs, ClientContext};
+
+const OPENCODE_PREFIX: &str = "/opencode";
+
+#[derive(Args, Debug)]
+pub struct PromptArgs {
+    /// Prompt text.
+    message: String,
+
+    /// OpenCode session to prompt.
+    #[arg(long)]
+    session: String,
+
+    /// Shell command that receives the events as NDJSON on stdin, e.g.
+    /// `jq -r 'select(.type == "item.delta") | .data.delta'`. Events are
+    /// printed to stdout when omitted.
+    #[arg(long)]
+    exec: Option<String>,
+
+    /// Attach the OpenCode event each universal event came from as `raw`.
+    #[arg(long = "include-raw")]
+    include_raw: bool,
+
+    /// Seconds to wait for the turn to finish.
+    #[arg(long, default_value_t = 3600)]
+    timeout: u64,
+
+    #[command(flatten)]
+    client: ClientArgs,
+}
+
+pub fn run(args: &PromptArgs, cli: &CliConfig) -> Result<(), CliError> {
+    let ctx = ClientContext::new(cli, &args.client)?;
+    let timeout = Duration::from_secs(args.timeout);
+
+    // Subscribe first so no event of the turn is missed.
+    let events = ctx
+        .request(Method::GET, &format!("{OPENCODE_PREFIX}/event"))
+        .header("accept", "text/event-stream")
+        .timeout(timeout)
+        .send()?;
+    if !events.status().is_success() {
+        return Err(CliError::HttpStatus(events.status()));
+    }
+
+    let response = ctx
+        .request(
+            Method::POST,
+            &format!("{OPENCODE_PREFIX}/session/{}/prompt_async", args.session),
+        )
+        .json(&json!({"parts": [{"type": "text", "text": args.message}]}))
+        .send()?;
+    let status = response.status();
+    let body = response.text()?;
+    if !status.is_success() {
+        crate::cli::print_error_body(&body)?;
+        return Err(CliError::HttpStatus(status));
+    }
+    let turn: Value = serde_json::from_str(&body)?;
+    let turn_id = turn
+        .get("id")
+        .and_then(Value::as_str)
+        .ok_or_else(|| CliError::Server("prompt response has no turn id".to_string()))?
+        .to_string();
+
+    let mut sink = Sink::open(args.exec.as_deref())?;
+    let mut translator = UniversalTranslator::new(&args.session, args.include_raw);
+    let mut result = Ok(());
+    for line in BufReader::new(events).lines() {
+        let line = line?;
+        let Some(data) = line.strip_prefix("data:") else {
+            continue;
+        };
+        let Ok(event) = serde_json::from_str::<Value>(data.trim()) else {
+            continue;
+        };
+        let finished = event.get("type").and_then(Value::as_str) == Some("turn.completed")
+            && event.pointer("/properties/id").and_then(Value::as_str) == Some(turn_id.as_str());
+        for universal in translator.translate(&event) {
+            result = sink.write(&universal);
+            if result.is_err() {
+                break;
+            }
+        }
+        if finished || result.is_err() {
+            break;
+        }
+    }
+    let exit = sink.close()?;
+    match result {
+        // The command exited before reading everything, e.g. `head`.
+        Err(CliError::Io(err)) if err.kind() == std::io::ErrorKind::BrokenPipe => exit,
+        Err(err) => Err(err),
+        Ok(()) => exit,
+    }
+}
+
+/// Where events are written: stdout, or the stdin of a child process.
+enum Sink {
+    Stdout,
+    Exec { command: String, child: Child },
+}
+
+impl Sink {
+    fn open(command: Option<&str>) -> Result<Self, CliError> {
+        let Some(command) = command else {
+            return Ok(Self::Stdout);
+        };
+        let mut shell = if cfg!(windows) {
+            let mut shell = ProcessCommand::new("cmd");
+            shell.arg("/C");
+            shell
+        } else {
+            let mut shell = ProcessCommand::new("sh");
+            shell.arg("-c");
+            shell
+        };
+        let child = shell.arg(command).stdin(Stdio::piped()).spawn()?;
+        Ok(Self::Exec {
+            command: command.to_string(),
+            child,
+        })
+    }
+
+    fn write(&mut self, event: &Value) -> Result<(), CliError> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        match self {
+            Self::Stdout => {
+                let mut out = std::io::stdout();
+                out.write_all(&line)?;
+                out.flush()?;
+            }
+            Self::Exec { child, .. } => {
+                if let Some(stdin) = child.stdin.as_mut() {
+                    stdin.write_all(&line)?;
+                    stdin.flush()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Close the child's stdin and wait for it. A non-zero exit is an error.
+    fn close(self) -> Result<Result<(), CliError>, CliError> {
+        let Self::Exec { command, mut child } = self else {
+            return Ok(Ok(()));
+        };
+        drop(child.stdin.take());
+        let status = child.wait()?;
+        if status.success() {
+            Ok(Ok(()))
+        } else {
+            Ok(Err(CliError::Server(format!(
+                "`{command}` exited with {status}"
+            ))))
+        }
+    }
+}
+
+/// The session an OpenCode event belongs to, if any.
+pub(crate) fn event_session(event: &Value) -> Option<&str> {
+    let properties = &event["properties"];
+    properties
+        .get("sessionID")
+        .or_else(|| properties.pointer("/info/sessionID"))
+        .or_else(|| properties.pointer("/part/sessionID"))
+        .and_then(Value::as_str)
+}
+
+/// Converts one session's OpenCode events into universal events
+/// (`docs/session-transcript-schema.mdx`).
+#[derive(Debug)]
+pub(crate) struct UniversalTranslator {
+    session_id: String,
+    include_raw: bool,
+    sequence: u64,
+    /// Role of each message seen in `message.updated`.
+    roles: HashMap<String, String>,
+    /// Latest state of each part that has started but not completed.
+    open: HashMap<String, Value>,
+    completed: HashSet<String>,
+}
+
+impl UniversalTranslator {
+    pub(crate) fn new(session_id: &str, include_raw: bool) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            include_raw,
+            sequence: 0,
+            roles: HashMap::new(),
+            open: HashMap::new(),
+            completed: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn translate(&mut self, event: &Value) -> Vec<Value> {
+        if event_session(event) != Some(self.session_id.as_str()) {
+            return Vec::new();
+        }
+        let properties = &event["properties"];
+        let translated: Vec<(&str, Value)> = match event["type"].as_str().unwrap_or_default() {
+            "turn.started" => vec![(
+                "turn.started",
+                json!({"phase": "started", "turn_id": properties["id"]}),
+            )],
+            "turn.completed" => {
+                // Parts the agent never marked final end with the turn.
+                let mut events: Vec<(&str, Value)> = std::mem::take(&mut self.open)
+                    .into_values()
+                    .map(|part| ("item.completed", json!({"item": self.item(&part, true)})))
+                    .collect();
+                events.push((
+                    "turn.ended",
+                    json!({
+                        "phase": "ended",
+                        "turn_id": properties["id"],
+                        "metadata": {"status": properties["status"]},
+                    }),
+                ));
+                events
+            }
+            "message.updated" => {
+                if let (Some(id), Some(role)) = (
+                    properties.pointer("/info/id").and_then(Value::as_str),
+                    properties.pointer("/info/role").and_then(Value::as_str),
+                ) {
+                    self.roles.insert(id.to_string(), role.to_string());
+                }
+                Vec::new()
+            }
+            "message.part.updated" => self.part_events(properties),
+            "permission.asked" => vec![(
+                "permission.requested",
+                json!({
+                    "permission_id": properties["id"],
+                    "action": properties["permission"],
+                    "status": "requested",
+                    "metadata": properties.get("metadata"),
+                }),
+            )],
+            "permission.replied" => vec![(
+                "permission.resolved",
+                json!({
+                    "permission_id": properties["requestID"],
+                    "action": Value::Null,
+                    "status": match properties["reply"].as_str() {
+                        Some("always") => "accept_for_session",
+                        Some("reject") => "reject",
+                        _ => "accept",
+                    },
+                }),
+            )],
+            "question.asked" => {
+                let question = &properties["questions"][0];
+                vec![(
+                    "question.requested",
+                    json!({
+                        "question_id": properties["id"],
+                        "prompt": question["question"].as_str().unwrap_or_default(),
+                        "options": question["options"]
+                            .as_array()
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|option| option["label"].as_str())
+                            .collect::<Vec<_>>(),
+                        "status": "requested",
+                    }),
+                )]
+            }
+            "question.replied" | "question.rejected" => vec![(
+                "question.resolved",
+                json!({
+                    "question_id": properties["requestID"],
+                    "status": if event["type"] == "question.replied" { "answered" } else { "rejected" },
+                    "response": properties.pointer("/answers/0/0"),
+                }),
+            )],
+            "session.error" => vec![(
+                "error",
+                json!({
+                    "message": properties
+                        .pointer("/error/data/message")
+                        .and_then(Value::as_str)
+                        .unwrap_or("agent error"),
+                    "code": properties.pointer("/error/name"),
+                    "details": properties.pointer("/error/data"),
+                }),
+            )],
+            _ => Vec::new(),
+        };
+        translated
+            .into_iter()
+            .map(|(event_type, data)| self.envelope(event_type, data, event))
+            .collect()
+    }
+
+    /// `item.started` the first time a part is seen, `item.delta` for
+    /// streamed text, and `item.completed` once the part is final.
+    fn part_events(&mut self, properties: &Value) -> Vec<(&'static str, Value)> {
+        let part = &properties["part"];
+        let Some(part_id) = part["id"].as_str() else {
+            return Vec::new();
+        };
+        if self.completed.contains(part_id) {
+            return Vec::new();
+        }
+        let mut events = Vec::new();
+        if self
+            .open
+            .insert(part_id.to_string(), part.clone())
+            .is_none()
+        {
+            events.push(("item.started", json!({"item": self.item(part, false)})));
+        }
+        if let Some(delta) = properties["delta"].as_str() {
+            events.push((
+                "item.delta",
+                json!({"item_id": part_id, "native_item_id": null, "delta": delta}),
+            ));
+        }
+        let from_user = self
+            .roles
+            .get(part["messageID"].as_str().unwrap_or_default())
+            .is_some_and(|role| role == "user");
+        let finished = match part["type"].as_str() {
+            // User messages are not streamed.
+            _ if from_user => true,
+            Some("text") | Some("reasoning") => part.pointer("/time/end").is_some(),
+            Some("tool") => matches!(
+                part.pointer("/state/status").and_then(Value::as_str),
+                Some("completed") | Some("error")
+            ),
+            _ => true,
+        };
+        if finished {
+            self.open.remove(part_id);
+            self.completed.insert(part_id.to_string());
+            events.push(("item.completed", json!({"item": self.item(part, true)})));
+        }
+        events
+    }
+
+    fn item(&self, part: &Value, finished: bool) -> Value {
+        let message_id = part["messageID"].as_str().unwrap_or_default();
+        let role = self
+            .roles
+            .get(message_id)
+            .map_or("assistant", String::as_str);
+        let (kind, content) = match part["type"].as_str().unwrap_or_default() {
+            "text" => ("message", json!([{"type": "text", "text": part["text"]}])),
+            "reasoning" => (
+                "message",
+                json!([{"type": "reasoning", "text": part["text"], "visibility": "public"}]),
+            ),
+            "tool" => {
+                let call_id = &part["callID"];
+                let mut content = vec![json!({
+                    "type": "tool_call",
+                    "name": part["tool"],
+                    "arguments": part.pointer("/state/input").map(Value::to_string).unwrap_or_default(),
+                    "call_id": call_id,
+                })];
+                if let Some(output) = part
+                    .pointer("/state/output")
+                    .or_else(|| part.pointer("/state/error"))
+                    .and_then(Value::as_str)
+                {
+                    content
+                        .push(json!({"type": "tool_result", "call_id": call_id, "output": output}));
+                }
+                ("tool_call", Value::Array(content))
+            }
+            "file" => (
+                "message",
+                json!([{
+                    "type": "file_ref",
+                    "path": part["url"].as_str().unwrap_or_default().trim_start_matches("file://"),
+                    "action": "read",
+                    "diff": null,
+                }]),
+            ),
+            _ => ("unknown", json!([{"type": "json", "json": part}])),
+        };
+        let status = if !finished {
+            "in_progress"
+        } else if part.pointer("/state/status").and_then(Value::as_str) == Some("error") {
+            "failed"
+        } else {
+            "completed"
+        };
+        json!({
+            "item_id": part["id"],
+            "native_item_id": null,
+            "parent_id": message_id,
+            "kind": kind,
+            "role": role,
+            "content": content,
+            "status": status,
+        })
+    }
+
+    fn envelope(&mut self, event_type: &str, data: Value, raw: &Value) -> Value {
+        self.sequence += 1;
+        let mut event = json!({
+            "event_id": format!("evt_{}", self.sequence),
+            "sequence": self.sequence,
+            "time": chrono::Utc::now().to_rfc3339(),
+            "session_id": self.session_id,
+            "native_session_id": self.session_id,
+            "source": "agent",
+            "synthetic": false,
+            "type": event_type,
+            "data": data,
+        });
+        if self.include_raw {
+            event["raw"] = raw.clone();
+        }
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn types(events: &[Value]) -> Vec<&str> {
+        events
+            .iter()
+            .map(|event| event["type"].as_str().unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn translates_a_streamed_text_part() {
+        let mut translator = UniversalTranslator::new("ses_1", false);
+        let other =
+            json!({"type": "turn.started", "properties": {"sessionID": "ses_2", "id": "turn_9"}});
+        assert!(translator.translate(&other).is_empty());
+
+        let started = translator.translate(&json!({
+            "type": "turn.started",
+            "properties": {"sessionID": "ses_1", "id": "turn_1"},
+        }));
+        assert_eq!(types(&started), ["turn.started"]);
+        assert_eq!(started[0]["data"]["turn_id"], "turn_1");
+        assert_eq!(started[0]["sequence"], 1);
+
+        let chunk = translator.translate(&json!({
+            "type": "message.part.updated",
+            "properties": {
+                "sessionID": "ses_1",
+                "delta": "Hel",
+                "part": {"id": "prt_1", "messageID": "msg_1", "sessionID": "ses_1", "type": "text", "text": "Hel"},
+            },
+        }));
+        assert_eq!(types(&chunk), ["item.started", "item.delta"]);
+        assert_eq!(chunk[1]["data"]["delta"], "Hel");
+        assert_eq!(chunk[0]["data"]["item"]["status"], "in_progress");
+
+        let done = translator.translate(&json!({
+            "type": "message.part.updated",
+            "properties": {
+                "sessionID": "ses_1",
+                "part": {
+                    "id": "prt_1", "messageID": "msg_1", "sessionID": "ses_1",
+                    "type": "text", "text": "Hello", "time": {"start": 1, "end": 2},
+                },
+            },
+        }));
+        assert_eq!(types(&done), ["item.completed"]);
+        assert_eq!(
+            done[0]["data"]["item"]["content"],
+            json!([{"type": "text", "text": "Hello"}])
+        );
+        assert!(done[0].get("raw").is_none());
+
+        let thinking = translator.translate(&json!({
+            "type": "message.part.updated",
+            "properties": {
+                "sessionID": "ses_1",
+                "part": {"id": "prt_3", "messageID": "msg_1", "sessionID": "ses_1", "type": "reasoning", "text": "hmm"},
+            },
+        }));
+        assert_eq!(types(&thinking), ["item.started"]);
+        let ended = translator.translate(&json!({
+            "type": "turn.completed",
+            "properties": {"sessionID": "ses_1", "id": "turn_1", "status": "completed"},
+        }));
+        assert_eq!(types(&ended), ["item.completed", "turn.ended"]);
+        assert_eq!(ended[0]["data"]["item"]["item_id"], "prt_3");
+    }
+
+    #[test]
+    fn translates_tool_parts_and_permissions() {
+        let mut translator = UniversalTranslator::new("ses_1", true);
+        let tool = translator.translate(&json!({
+            "type": "message.part.updated",
+            "properties": {
+                "sessionID": "ses_1",
+                "part": {
+                    "id": "prt_2", "messageID": "msg_1", "sessionID": "ses_1", "type": "tool",
+                    "callID": "call_1", "tool": "bash",
+                    "state": {"status": "completed", "input": {"command": "ls"}, "output": "README.md"},
+                },
+            },
+        }));
+        assert_eq!(types(&tool), ["item.started", "item.completed"]);
+        let item = &tool[1]["data"]["item"];
+        assert_eq!(item["kind"], "tool_call");
+        assert_eq!(item["content"][1]["output"], "README.md");
+        assert_eq!(tool[1]["raw"]["type"], "message.part.updated");
+
+        let replied = translator.translate(&json!({
+            "type": "permission.replied",
+            "properties": {"sessionID": "ses_1", "requestID": "perm_1", "reply": "always"},
+        }));
+        assert_eq!(types(&replied), ["permission.resolved"]);
+        assert_eq!(replied[0]["data"]["status"], "accept_for_session");
+    }
+}
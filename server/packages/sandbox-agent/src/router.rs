@@ -11,16 +11,18 @@ use axum::http::{header, HeaderMap, Request, StatusCode};
 use axum::middleware::Next;
 use axum::response::sse::KeepAlive;
 use axum::response::{IntoResponse, Response, Sse};
-use axum::routing::{delete, get, post};
+use axum::routing::{any, delete, get, post};
 use axum::{Json, Router};
 use sandbox_agent_agent_management::agents::{
     AgentId, AgentManager, InstallOptions, InstallResult, InstallSource, InstalledArtifactKind,
 };
 use sandbox_agent_agent_management::credentials::{
-    extract_all_credentials, CredentialExtractionOptions,
+    extract_all_credentials, CredentialExtractionOptions, ExtractedCredentials,
 };
 use sandbox_agent_error::{ErrorType, ProblemDetails, SandboxError};
-use sandbox_agent_opencode_adapter::{build_opencode_router, OpenCodeAdapterConfig};
+use sandbox_agent_opencode_adapter::{
+    build_opencode_router, DatabaseLock, EventObserver, OpenCodeAdapterConfig, TokenVerifier,
+};
 use sandbox_agent_opencode_server_manager::{OpenCodeServerManager, OpenCodeServerManagerConfig};
 use schemars::JsonSchema;
 use serde::de::DeserializeOwned;
@@ -32,11 +34,30 @@ use tracing::Span;
 use utoipa::{Modify, OpenApi, ToSchema};
 
 use crate::acp_proxy_runtime::{AcpProxyRuntime, ProxyPostOutcome};
+use crate::schema;
+use crate::telemetry::metrics::{metrics, OpenCodeAdapterMetrics, PROMETHEUS_CONTENT_TYPE};
 use crate::ui;
 
+mod auth_tokens;
+mod chat_completions;
+mod credential_checks;
+mod event_sinks;
+mod federation;
 mod support;
+mod timeouts;
+mod transcripts;
 mod types;
+use self::auth_tokens::*;
+use self::chat_completions::*;
+pub use self::credential_checks::CredentialCheckConfig;
+use self::event_sinks::*;
+pub use self::event_sinks::{EventSinkConfig, EventSinkTarget};
+use self::federation::*;
+pub use self::federation::{FederatedSandbox, FederationConfig};
 use self::support::*;
+pub use self::timeouts::REQUEST_TIMEOUT_HEADER;
+use self::timeouts::*;
+use self::transcripts::*;
 pub use self::types::*;
 
 const APPLICATION_JSON: &str = "application/json";
@@ -73,12 +94,18 @@ pub(crate) struct CachedAgentVersion {
 
 #[derive(Debug)]
 pub struct AppState {
-    auth: AuthConfig,
+    /// Accepted bearer tokens; `None` when auth is disabled.
+    auth: Option<Arc<AuthTokens>>,
     agent_manager: Arc<AgentManager>,
     acp_proxy: Arc<AcpProxyRuntime>,
     opencode_server_manager: Arc<OpenCodeServerManager>,
     pub(crate) branding: BrandingMode,
     version_cache: Mutex<HashMap<AgentId, CachedAgentVersion>>,
+    credential_checks: Mutex<Option<HashMap<AgentId, CredentialCheck>>>,
+    opencode_database_lock: Option<Arc<DatabaseLock>>,
+    federation: Option<Arc<Federation>>,
+    event_sinks: Option<Arc<EventSinks>>,
+    transcripts: Arc<SessionTranscripts>,
 }
 
 impl AppState {
@@ -97,19 +124,46 @@ impl AppState {
             agent_manager.clone(),
             OpenCodeServerManagerConfig {
                 log_dir: default_opencode_server_log_dir(),
-                auto_restart: true,
+                ..Default::default()
             },
         ));
         Self {
-            auth,
+            auth: auth.token.map(|token| Arc::new(AuthTokens::new(token))),
             agent_manager,
             acp_proxy,
             opencode_server_manager,
             branding,
             version_cache: Mutex::new(HashMap::new()),
+            credential_checks: Mutex::new(None),
+            opencode_database_lock: None,
+            federation: None,
+            event_sinks: None,
+            transcripts: Arc::new(SessionTranscripts::new(None)),
         }
     }
 
+    /// Hold `lock` for the lifetime of the OpenCode adapter, which stops
+    /// using its database if another server takes it over.
+    pub fn with_opencode_database_lock(mut self, lock: DatabaseLock) -> Self {
+        self.opencode_database_lock = Some(Arc::new(lock));
+        self
+    }
+
+    /// Serve the sandboxes in `config` under `/v1/federation`, acting as
+    /// their controller.
+    pub fn with_federation(mut self, config: FederationConfig) -> Self {
+        self.federation = Some(Arc::new(Federation::new(config)));
+        self
+    }
+
+    /// Publish the universal event stream to the sinks in `config`.
+    pub fn with_event_sinks(mut self, config: EventSinkConfig) -> Self {
+        let sinks = Arc::new(EventSinks::new(config));
+        self.transcripts = Arc::new(SessionTranscripts::new(Some(sinks.clone())));
+        self.event_sinks = Some(sinks);
+        self
+    }
+
     pub(crate) fn acp_proxy(&self) -> Arc<AcpProxyRuntime> {
         self.acp_proxy.clone()
     }
@@ -125,6 +179,39 @@ impl AppState {
     pub(crate) fn purge_version_cache(&self, agent: AgentId) {
         self.version_cache.lock().unwrap().remove(&agent);
     }
+
+    /// Check every agent's credentials with a minimal authenticated provider
+    /// call and keep the results for `/v1/agents` and `/v1/health`.
+    pub async fn validate_agent_credentials(
+        &self,
+        credentials: &ExtractedCredentials,
+        config: &CredentialCheckConfig,
+    ) {
+        let checks = credential_checks::check_agent_credentials(credentials, config).await;
+        for (agent, check) in &checks {
+            let message = check.message.as_deref().unwrap_or_default();
+            match check.status {
+                CredentialCheckStatus::Valid => {
+                    tracing::info!(agent = agent.as_str(), "agent credentials valid")
+                }
+                CredentialCheckStatus::Missing | CredentialCheckStatus::Unverified => {
+                    tracing::info!(agent = agent.as_str(), status = ?check.status, message, "agent credentials not checked")
+                }
+                CredentialCheckStatus::Invalid | CredentialCheckStatus::Error => {
+                    tracing::warn!(agent = agent.as_str(), status = ?check.status, message, "agent credential check failed")
+                }
+            }
+        }
+        *self.credential_checks.lock().unwrap() = Some(checks);
+    }
+
+    fn credential_check(&self, agent: AgentId) -> Option<CredentialCheck> {
+        self.credential_checks
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|checks| checks.get(&agent).cloned())
+    }
 }
 
 fn default_opencode_server_log_dir() -> PathBuf {
@@ -154,11 +241,12 @@ pub fn build_router(state: AppState) -> Router {
 }
 
 pub fn build_router_with_state(shared: Arc<AppState>) -> (Router, Arc<AppState>) {
-    let mut v1_router = Router::new()
+    let timeouts = RouteTimeouts::from_env();
+
+    let control_routes = Router::new()
         .route("/health", get(get_v1_health))
         .route("/agents", get(get_v1_agents))
         .route("/agents/:agent", get(get_v1_agent))
-        .route("/agents/:agent/install", post(post_v1_agent_install))
         .route("/fs/entries", get(get_v1_fs_entries))
         .route("/fs/file", get(get_v1_fs_file).put(put_v1_fs_file))
         .route("/fs/entry", delete(delete_v1_fs_entry))
@@ -177,42 +265,118 @@ pub fn build_router_with_state(shared: Arc<AppState>) -> (Router, Arc<AppState>)
             get(get_v1_config_skills)
                 .put(put_v1_config_skills)
                 .delete(delete_v1_config_skills),
-        )
-        .route("/acp", get(get_v1_acp_servers))
-        .route(
-            "/acp/:server_id",
-            post(post_v1_acp).get(get_v1_acp).delete(delete_v1_acp),
-        )
-        .with_state(shared.clone());
-
-    if shared.auth.token.is_some() {
-        v1_router = v1_router.layer(axum::middleware::from_fn_with_state(
-            shared.clone(),
-            require_token,
+        );
+    let install_routes = Router::new().route("/agents/:agent/install", post(post_v1_agent_install));
+    let acp_routes = Router::new().route("/acp", get(get_v1_acp_servers)).route(
+        "/acp/:server_id",
+        post(post_v1_acp).get(get_v1_acp).delete(delete_v1_acp),
+    );
+    let mut v1_router = with_timeout(control_routes, timeouts.control)
+        .merge(with_timeout(install_routes, timeouts.install))
+        .merge(with_timeout(acp_routes, timeouts.acp));
+    if shared.federation.is_some() {
+        let federation_routes = Router::new()
+            .route("/federation/sandboxes", get(get_v1_federation_sandboxes))
+            .route("/federation/sessions", get(get_v1_federation_sessions));
+        // Proxied requests may hold a prompt open as long as `/opencode` does.
+        let federation_proxy_routes = Router::new()
+            .route("/federation/event", get(get_v1_federation_events))
+            .route("/federation/sandboxes/:name/*path", any(federation_proxy));
+        v1_router = v1_router
+            .merge(with_timeout(federation_routes, timeouts.control))
+            .merge(with_timeout(federation_proxy_routes, timeouts.opencode));
+    }
+    if shared.event_sinks.is_some() {
+        v1_router = v1_router.merge(with_timeout(
+            Router::new().route(
+                "/event-sinks/dead-letters",
+                get(get_v1_event_sink_dead_letters),
+            ),
+            timeouts.control,
         ));
     }
-
     let opencode_router = build_opencode_router(OpenCodeAdapterConfig {
-        auth_token: shared.auth.token.clone(),
-        sqlite_path: std::env::var("OPENCODE_COMPAT_DB_PATH").ok(),
+        token_verifier: shared
+            .auth
+            .clone()
+            .map(|tokens| tokens as Arc<dyn TokenVerifier>),
+        sqlite_path: shared
+            .opencode_database_lock
+            .as_ref()
+            .map(|lock| lock.db_path().to_string())
+            .or_else(|| std::env::var("OPENCODE_COMPAT_DB_PATH").ok()),
         native_proxy_base_url: std::env::var("OPENCODE_COMPAT_PROXY_URL").ok(),
         native_proxy_manager: Some(shared.opencode_server_manager()),
         acp_dispatch: Some(shared.acp_proxy() as Arc<dyn sandbox_agent_opencode_adapter::AcpDispatch>),
         provider_payload: Some(build_provider_payload_for_opencode(&shared)),
+        model_catalog: Some(sandbox_agent_opencode_adapter::ModelCatalogConfig::from_env()),
+        rpc_method_allowlist: std::env::var("OPENCODE_COMPAT_RPC_ALLOWLIST")
+            .map(|raw| {
+                raw.split(',')
+                    .map(|entry| entry.trim().to_string())
+                    .filter(|entry| !entry.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        archive: sandbox_agent_opencode_adapter::SessionArchiveConfig::from_env(),
+        webhooks: sandbox_agent_opencode_adapter::WebhookConfig::from_env(),
+        session_watchdog: sandbox_agent_opencode_adapter::SessionWatchdogConfig::from_env(),
+        session_expiry: sandbox_agent_opencode_adapter::SessionExpiryConfig::from_env(),
+        session_prewarm: sandbox_agent_opencode_adapter::SessionPrewarmConfig::from_env(),
+        session_quota: sandbox_agent_opencode_adapter::SessionQuotaConfig::from_env(),
+        attachment_transcoders: sandbox_agent_opencode_adapter::CommandTranscoder::from_env()
+            .map(|transcoder| {
+                Arc::new(transcoder) as Arc<dyn sandbox_agent_opencode_adapter::AttachmentTranscoder>
+            })
+            .into_iter()
+            .collect(),
+        metrics: Some(Arc::new(OpenCodeAdapterMetrics)),
+        event_observer: Some(shared.transcripts.clone() as Arc<dyn EventObserver>),
+        transcript_format: Some(Arc::new(UniversalTranscripts)),
+        database_lock: shared.opencode_database_lock.clone(),
         ..OpenCodeAdapterConfig::default()
     })
     .unwrap_or_else(|err| {
         tracing::error!(error = %err, "failed to initialize opencode adapter router; using fallback");
         Router::new().fallback(opencode_unavailable)
     });
+    let opencode_router = with_timeout(opencode_router, timeouts.opencode);
+
+    let mut v1_router = v1_router.with_state(shared.clone()).merge(with_timeout(
+        chat_completion_routes(opencode_router.clone(), shared.transcripts.clone()),
+        timeouts.opencode,
+    ));
+
+    if shared.auth.is_some() {
+        v1_router = v1_router.layer(axum::middleware::from_fn_with_state(
+            shared.clone(),
+            require_token,
+        ));
+    }
+
+    let mut metrics_routes = Router::new().route("/metrics", get(get_metrics));
+    if shared.auth.is_some() {
+        metrics_routes = metrics_routes
+            .route("/admin/tokens", get(get_admin_tokens))
+            .route("/admin/tokens/rotate", post(post_admin_tokens_rotate));
+    }
+    let mut metrics_router =
+        with_timeout(metrics_routes, timeouts.control).with_state(shared.clone());
+    if shared.auth.is_some() {
+        metrics_router = metrics_router.layer(axum::middleware::from_fn_with_state(
+            shared.clone(),
+            require_token,
+        ));
+    }
 
     let mut router = Router::new()
         .route("/", get(get_root))
+        .merge(metrics_router)
         .nest("/v1", v1_router)
         .nest("/opencode", opencode_router)
         .fallback(not_found);
 
-    router = router.merge(ui::router());
+    router = router.merge(ui::router()).merge(schema::router());
 
     let http_logging = match std::env::var("SANDBOX_AGENT_LOG_HTTP") {
         Ok(value) if value == "0" || value.eq_ignore_ascii_case("false") => false,
@@ -309,10 +473,14 @@ pub async fn shutdown_servers(state: &Arc<AppState>) {
     components(
         schemas(
             HealthResponse,
+            EventSinkHealth,
+            EventSinkStatus,
             ServerStatus,
             ServerStatusInfo,
             AgentCapabilities,
             AgentInfo,
+            CredentialCheck,
+            CredentialCheckStatus,
             AgentListResponse,
             AgentInstallRequest,
             AgentInstallArtifact,
@@ -386,6 +554,21 @@ async fn get_root() -> Json<Value> {
     }))
 }
 
+/// Prometheus scrape endpoint. Counters collected elsewhere are refreshed
+/// here before rendering.
+async fn get_metrics(State(state): State<Arc<AppState>>) -> Response {
+    let metrics = metrics();
+    metrics
+        .agent_restarts
+        .get(AgentId::Opencode.as_str())
+        .set(state.opencode_server_manager().auto_restarts().await);
+    (
+        [(header::CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)],
+        metrics.render(),
+    )
+        .into_response()
+}
+
 #[utoipa::path(
     get,
     path = "/v1/health",
@@ -394,9 +577,22 @@ async fn get_root() -> Json<Value> {
         (status = 200, description = "Service health response", body = HealthResponse)
     )
 )]
-async fn get_v1_health() -> Json<HealthResponse> {
+async fn get_v1_health(State(state): State<Arc<AppState>>) -> Json<HealthResponse> {
+    let agent_credentials = state
+        .credential_checks
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|checks| {
+            checks
+                .iter()
+                .map(|(agent, check)| (agent.as_str().to_string(), check.clone()))
+                .collect()
+        });
     Json(HealthResponse {
         status: "ok".to_string(),
+        agent_credentials,
+        event_sinks: state.event_sinks.as_ref().map(|sinks| sinks.health()),
     })
 }
 
@@ -471,6 +667,7 @@ async fn get_v1_agents(
             server_status,
             config_options: None,
             config_error: None,
+            credential_check: state.credential_check(agent_id),
         });
     }
 
@@ -604,6 +801,7 @@ async fn get_v1_agent(
         server_status,
         config_options: None,
         config_error: None,
+        credential_check: state.credential_check(agent_id),
     };
 
     if query.config.unwrap_or(false) {
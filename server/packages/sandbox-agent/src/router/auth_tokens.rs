@@ -0,0 +1,238 @@
+use std::collections::VecDeque;
+
+use super::*;
+
+const DEFAULT_TOKEN_GRACE_SECS: u64 = 300;
+/// Longest grace period a replaced token gets; longer ones are clamped.
+const MAX_TOKEN_GRACE_SECS: u64 = 7 * 24 * 60 * 60;
+/// Rotation audit events kept for `GET /admin/tokens`.
+const MAX_AUDIT_EVENTS: usize = 100;
+
+/// The bearer tokens the server accepts: the current token, and the tokens
+/// it replaced through `POST /admin/tokens/rotate` until their grace
+/// deadlines pass. Connections opened with a replaced token, such as SSE
+/// streams, stay open; only new requests are checked.
+#[derive(Debug)]
+pub(crate) struct AuthTokens {
+    state: Mutex<TokenState>,
+    default_grace: Duration,
+}
+
+#[derive(Debug)]
+struct TokenState {
+    current: String,
+    retiring: Vec<RetiringToken>,
+    audit: VecDeque<Value>,
+}
+
+#[derive(Debug)]
+struct RetiringToken {
+    token: String,
+    /// Epoch milliseconds after which the token is refused.
+    expires_at: i64,
+}
+
+impl AuthTokens {
+    /// Tokens starting from `token`. Replaced tokens stay valid for
+    /// `SANDBOX_AGENT_TOKEN_GRACE_SECS` (default 300) unless a rotation
+    /// names its own grace period.
+    pub(crate) fn new(token: String) -> Self {
+        let grace_secs = std::env::var("SANDBOX_AGENT_TOKEN_GRACE_SECS")
+            .ok()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .unwrap_or(DEFAULT_TOKEN_GRACE_SECS);
+        Self {
+            state: Mutex::new(TokenState {
+                current: token,
+                retiring: Vec::new(),
+                audit: VecDeque::new(),
+            }),
+            default_grace: Duration::from_secs(grace_secs.min(MAX_TOKEN_GRACE_SECS)),
+        }
+    }
+
+    /// Make `next` (or a generated token) current. The current token keeps
+    /// working for `grace`, or the default grace period. Only the current
+    /// token may rotate, so a replaced token cannot mint a successor.
+    fn rotate(
+        &self,
+        next: Option<String>,
+        grace: Option<Duration>,
+        rotated_by: &str,
+    ) -> Result<Value, SandboxError> {
+        let next = match next {
+            Some(token) => token,
+            None => random_token()?,
+        };
+        if next.is_empty() || next.chars().any(char::is_whitespace) {
+            return Err(SandboxError::InvalidRequest {
+                message: "token must be non-empty and contain no whitespace".to_string(),
+            });
+        }
+        let grace = grace
+            .unwrap_or(self.default_grace)
+            .min(Duration::from_secs(MAX_TOKEN_GRACE_SECS));
+        let now = now_ms();
+        let expires_at = i64::try_from(grace.as_millis())
+            .ok()
+            .and_then(|grace_ms| now.checked_add(grace_ms))
+            .ok_or_else(|| SandboxError::InvalidRequest {
+                message: "graceSeconds is out of range".to_string(),
+            })?;
+        let mut state = self.state.lock().unwrap();
+        if rotated_by != state.current {
+            return Err(SandboxError::PermissionDenied {
+                message: Some("only the current token can rotate tokens".to_string()),
+            });
+        }
+        state.prune(now);
+        if next == state.current || state.retiring.iter().any(|retiring| retiring.token == next) {
+            return Err(SandboxError::Conflict {
+                message: "token was already used".to_string(),
+            });
+        }
+        let previous = std::mem::replace(&mut state.current, next.clone());
+        let event = json!({
+            "type": "auth.token.rotated",
+            "time": now,
+            "token": fingerprint(&next),
+            "previous": fingerprint(&previous),
+            "previousExpiresAt": expires_at,
+            "rotatedBy": fingerprint(rotated_by),
+        });
+        state.retiring.push(RetiringToken {
+            token: previous.clone(),
+            expires_at,
+        });
+        state.record(event);
+        Ok(json!({
+            "token": next,
+            "previous": {
+                "fingerprint": fingerprint(&previous),
+                "expiresAt": expires_at,
+            },
+            "graceSeconds": grace.as_secs(),
+        }))
+    }
+
+    /// The accepted tokens, by fingerprint, and the rotation audit log.
+    fn status(&self) -> Value {
+        let mut state = self.state.lock().unwrap();
+        state.prune(now_ms());
+        json!({
+            "current": {"fingerprint": fingerprint(&state.current)},
+            "retiring": state
+                .retiring
+                .iter()
+                .map(|retiring| json!({
+                    "fingerprint": fingerprint(&retiring.token),
+                    "expiresAt": retiring.expires_at,
+                }))
+                .collect::<Vec<_>>(),
+            "audit": state.audit.iter().cloned().collect::<Vec<_>>(),
+        })
+    }
+}
+
+impl TokenVerifier for AuthTokens {
+    fn verify(&self, token: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.current == token {
+            return true;
+        }
+        state.prune(now_ms());
+        state
+            .retiring
+            .iter()
+            .any(|retiring| retiring.token == token)
+    }
+}
+
+impl TokenState {
+    /// Drop replaced tokens past their grace deadline.
+    fn prune(&mut self, now: i64) {
+        let (expired, retiring) = std::mem::take(&mut self.retiring)
+            .into_iter()
+            .partition::<Vec<_>, _>(|retiring| retiring.expires_at <= now);
+        self.retiring = retiring;
+        for token in expired {
+            self.record(json!({
+                "type": "auth.token.expired",
+                "time": now,
+                "token": fingerprint(&token.token),
+                "expiresAt": token.expires_at,
+            }));
+        }
+    }
+
+    fn record(&mut self, event: Value) {
+        tracing::info!(target: "sandbox_agent::audit", event = %event, "auth token audit");
+        if self.audit.len() == MAX_AUDIT_EVENTS {
+            self.audit.pop_front();
+        }
+        self.audit.push_back(event);
+    }
+}
+
+/// Identifies a token in audit records without revealing it.
+fn fingerprint(token: &str) -> String {
+    let tail: String = token
+        .chars()
+        .rev()
+        .take(4)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect();
+    format!("…{tail}")
+}
+
+fn random_token() -> Result<String, SandboxError> {
+    let mut bytes = [0u8; 24];
+    getrandom::getrandom(&mut bytes).map_err(|err| SandboxError::StreamError {
+        message: format!("failed to generate token: {err}"),
+    })?;
+    Ok(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct TokenRotateRequest {
+    /// The new token; generated when omitted.
+    token: Option<String>,
+    /// How long the replaced token keeps working.
+    grace_seconds: Option<u64>,
+}
+
+pub(super) async fn post_admin_tokens_rotate(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Option<Json<TokenRotateRequest>>,
+) -> Result<Json<Value>, ApiError> {
+    let Some(tokens) = state.auth.as_ref() else {
+        return Err(SandboxError::InvalidRequest {
+            message: "token auth is disabled".to_string(),
+        }
+        .into());
+    };
+    let request = body.map(|Json(request)| request).unwrap_or_default();
+    let rotated_by = bearer_token(&headers).unwrap_or_default();
+    let rotated = tokens.rotate(
+        request.token,
+        request.grace_seconds.map(Duration::from_secs),
+        rotated_by,
+    )?;
+    Ok(Json(rotated))
+}
+
+pub(super) async fn get_admin_tokens(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Value>, ApiError> {
+    let Some(tokens) = state.auth.as_ref() else {
+        return Err(SandboxError::InvalidRequest {
+            message: "token auth is disabled".to_string(),
+        }
+        .into());
+    };
+    Ok(Json(tokens.status()))
+}
@@ -0,0 +1,772 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::body::Body;
+use axum::http::{HeaderValue, Method};
+use axum::response::sse::Event;
+use futures::stream::{self, Stream, StreamExt};
+use tower::util::ServiceExt;
+
+use super::*;
+
+/// Names the OpenCode session a completion ran in. Sent back when the
+/// session is kept; a request that carries it continues that session
+/// instead of starting a new one.
+const SESSION_HEADER: &str = "x-sandbox-agent-session-id";
+/// Set to `true` to keep the session a request starts, so later requests
+/// can continue it. Otherwise it is deleted, and its agent process stopped,
+/// once the completion is answered.
+const KEEP_SESSION_HEADER: &str = "x-sandbox-agent-keep-session";
+
+/// `POST /v1/chat/completions` and `POST /v1/messages`: OpenAI- and
+/// Anthropic-compatible ingresses for tools that only speak one of those
+/// APIs. Each request becomes a prompt on an OpenCode session, and the
+/// session's universal events are turned back into a completion or message,
+/// or, with `stream`, its server-sent events.
+pub(super) struct ChatCompletions {
+    opencode: Router,
+    transcripts: Arc<SessionTranscripts>,
+}
+
+pub(super) fn chat_completion_routes(
+    opencode: Router,
+    transcripts: Arc<SessionTranscripts>,
+) -> Router {
+    Router::new()
+        .route("/chat/completions", post(post_v1_chat_completions))
+        .route("/messages", post(post_v1_messages))
+        .with_state(Arc::new(ChatCompletions {
+            opencode,
+            transcripts,
+        }))
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct ChatCompletionRequest {
+    /// `providerID/modelID`, e.g. `anthropic/claude-sonnet-4`, or a bare
+    /// model ID whose provider the session resolves.
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+}
+
+/// An Anthropic Messages API request. `max_tokens` and sampling parameters
+/// are accepted and ignored; the agent decides.
+#[derive(Debug, Deserialize)]
+pub(super) struct MessagesRequest {
+    /// As for [`ChatCompletionRequest::model`].
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    system: Option<ChatContent>,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMessage {
+    role: String,
+    #[serde(default)]
+    content: ChatContent,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(untagged)]
+enum ChatContent {
+    #[default]
+    Empty,
+    Text(String),
+    Parts(Vec<Value>),
+}
+
+impl ChatContent {
+    fn text(&self) -> Result<String, String> {
+        match self {
+            Self::Empty => Ok(String::new()),
+            Self::Text(text) => Ok(text.clone()),
+            Self::Parts(parts) => parts
+                .iter()
+                .map(|part| match part["type"].as_str() {
+                    Some("text") => Ok(part["text"].as_str().unwrap_or_default()),
+                    other => Err(format!(
+                        "content parts of type {} are not supported",
+                        other.unwrap_or("(none)")
+                    )),
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(|texts| texts.join("\n")),
+        }
+    }
+}
+
+/// What a chat completion request asks of the session.
+struct ChatPrompt {
+    system: Option<String>,
+    /// Earlier messages, when they have to be replayed to a new session.
+    history: Option<String>,
+    text: String,
+}
+
+impl ChatPrompt {
+    /// Split `messages` into the system prompt, the conversation so far and
+    /// the trailing user messages to prompt with. A continued session holds
+    /// its own history, so only the trailing user messages are sent.
+    fn parse(messages: &[ChatMessage], continued: bool) -> Result<Self, String> {
+        let mut system = Vec::new();
+        let mut conversation = Vec::new();
+        for message in messages {
+            let text = message.content.text()?;
+            match message.role.as_str() {
+                "system" | "developer" => system.push(text),
+                "user" | "assistant" | "tool" => conversation.push((message.role.as_str(), text)),
+                role => return Err(format!("unsupported message role: {role}")),
+            }
+        }
+        let latest = conversation
+            .iter()
+            .rposition(|(role, _)| *role != "user")
+            .map_or(0, |index| index + 1);
+        if latest == conversation.len() {
+            return Err("the last message must be from the user".to_string());
+        }
+        let text = conversation[latest..]
+            .iter()
+            .map(|(_, text)| text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let history = (!continued && latest > 0).then(|| {
+            let mut history = "Conversation so far:\n".to_string();
+            for (role, text) in &conversation[..latest] {
+                history.push_str(&format!("\n{role}: {text}\n"));
+            }
+            history
+        });
+        Ok(Self {
+            system: (!system.is_empty()).then(|| system.join("\n\n")),
+            history,
+            text,
+        })
+    }
+
+    fn body(&self, model: &str) -> Value {
+        let mut parts = Vec::new();
+        if let Some(history) = self.history.as_ref() {
+            parts.push(json!({"type": "text", "text": history, "synthetic": true}));
+        }
+        parts.push(json!({"type": "text", "text": self.text}));
+        let mut body = json!({"parts": parts});
+        match model.split_once('/') {
+            Some((provider_id, model_id)) => {
+                body["model"] = json!({"providerID": provider_id, "modelID": model_id});
+            }
+            None => body["modelID"] = json!(model),
+        }
+        if let Some(system) = self.system.as_ref() {
+            body["system"] = json!(system);
+        }
+        body
+    }
+}
+
+/// Assistant text of a turn, read off the universal event stream.
+#[derive(Default)]
+struct TurnText {
+    /// Text streamed so far by each assistant text item.
+    items: HashMap<String, String>,
+    /// Whether any text was produced yet, to separate items.
+    any: bool,
+    error: Option<String>,
+}
+
+enum TurnUpdate {
+    Text(String),
+    Ended { failed: bool },
+}
+
+/// What a client is told about a turn: its text as it streams, then how it
+/// ended.
+enum TurnOutput {
+    Text(String),
+    Done,
+    Failed(String),
+}
+
+/// Follow the turn on the session's events until it ends.
+fn turn_output(
+    events: impl Stream<Item = Value> + Send + 'static,
+    turn_id: String,
+) -> impl Stream<Item = TurnOutput> + Send {
+    stream::unfold(
+        Some((Box::pin(events), TurnText::default(), turn_id)),
+        |state| async move {
+            let (mut events, mut turn, turn_id) = state?;
+            loop {
+                let Some(event) = events.next().await else {
+                    let ended = "the session ended before the turn finished".to_string();
+                    return Some((TurnOutput::Failed(ended), None));
+                };
+                match turn.apply(&event, &turn_id) {
+                    Some(TurnUpdate::Text(text)) => {
+                        return Some((TurnOutput::Text(text), Some((events, turn, turn_id))));
+                    }
+                    Some(TurnUpdate::Ended { failed: true }) => {
+                        let error = turn
+                            .error
+                            .take()
+                            .unwrap_or_else(|| "the agent turn failed".to_string());
+                        return Some((TurnOutput::Failed(error), None));
+                    }
+                    Some(TurnUpdate::Ended { failed: false }) => {
+                        return Some((TurnOutput::Done, None));
+                    }
+                    None => {}
+                }
+            }
+        },
+    )
+}
+
+impl TurnText {
+    fn apply(&mut self, event: &Value, turn_id: &str) -> Option<TurnUpdate> {
+        let data = &event["data"];
+        match event["type"].as_str()? {
+            "item.started" => {
+                let item = &data["item"];
+                if item["role"] == "assistant"
+                    && item["kind"] == "message"
+                    && item["content"][0]["type"] == "text"
+                {
+                    self.items
+                        .insert(item["item_id"].as_str()?.to_string(), String::new());
+                }
+                None
+            }
+            "item.delta" => {
+                let streamed = self.items.get_mut(data["item_id"].as_str()?)?;
+                let delta = data["delta"].as_str()?;
+                let first = streamed.is_empty();
+                streamed.push_str(delta);
+                self.text(delta, first)
+            }
+            "item.completed" => {
+                let item = &data["item"];
+                let streamed = self.items.remove(item["item_id"].as_str()?)?;
+                let text = item["content"][0]["text"].as_str().unwrap_or_default();
+                // Text the agent sent whole, or finalized past its deltas.
+                let rest = text.strip_prefix(streamed.as_str()).unwrap_or_default();
+                self.text(rest, streamed.is_empty())
+            }
+            "error" => {
+                self.error = data["message"].as_str().map(ToOwned::to_owned);
+                None
+            }
+            "turn.ended" if data["turn_id"] == turn_id => Some(TurnUpdate::Ended {
+                failed: data["metadata"]["status"] != "completed",
+            }),
+            _ => None,
+        }
+    }
+
+    fn text(&mut self, text: &str, starts_item: bool) -> Option<TurnUpdate> {
+        if text.is_empty() {
+            return None;
+        }
+        let separator = if starts_item && self.any { "\n\n" } else { "" };
+        self.any = true;
+        Some(TurnUpdate::Text(format!("{separator}{text}")))
+    }
+}
+
+/// The API a request came in on, which decides how its errors look.
+#[derive(Debug, Clone, Copy)]
+enum Dialect {
+    OpenAi,
+    Anthropic,
+}
+
+impl Dialect {
+    fn error(self, status: StatusCode, message: impl Into<String>) -> Response {
+        match self {
+            Self::OpenAi => openai_error(status, message),
+            Self::Anthropic => anthropic_error(status, message),
+        }
+    }
+}
+
+fn openai_error(status: StatusCode, message: impl Into<String>) -> Response {
+    let kind = if status.is_client_error() {
+        "invalid_request_error"
+    } else {
+        "server_error"
+    };
+    (
+        status,
+        Json(json!({
+            "error": {"message": message.into(), "type": kind, "param": null, "code": null}
+        })),
+    )
+        .into_response()
+}
+
+fn anthropic_error(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(anthropic_error_body(status, message.into()))).into_response()
+}
+
+fn anthropic_error_body(status: StatusCode, message: String) -> Value {
+    let kind = match status {
+        StatusCode::UNAUTHORIZED => "authentication_error",
+        StatusCode::FORBIDDEN => "permission_error",
+        StatusCode::NOT_FOUND => "not_found_error",
+        status if status.is_client_error() => "invalid_request_error",
+        _ => "api_error",
+    };
+    json!({"type": "error", "error": {"type": kind, "message": message}})
+}
+
+impl ChatCompletions {
+    /// Call the OpenCode adapter on behalf of the client, as that client.
+    async fn opencode(
+        &self,
+        headers: &HeaderMap,
+        dialect: Dialect,
+        method: Method,
+        uri: &str,
+        body: Value,
+    ) -> Result<Value, Response> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, APPLICATION_JSON);
+        if let Some(authorization) = headers.get(header::AUTHORIZATION) {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        let request = request
+            .body(Body::from(body.to_string()))
+            .map_err(|err| dialect.error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        let response = self
+            .opencode
+            .clone()
+            .oneshot(request)
+            .await
+            .unwrap_or_else(|err| match err {});
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .map_err(|err| dialect.error(StatusCode::BAD_GATEWAY, err.to_string()))?;
+        let value = serde_json::from_slice::<Value>(&bytes).unwrap_or(Value::Null);
+        if status.is_success() {
+            return Ok(value);
+        }
+        let message = value
+            .pointer("/errors/0/message")
+            .or_else(|| value.get("detail"))
+            .or_else(|| value.pointer("/data/message"))
+            .and_then(Value::as_str)
+            .map_or_else(
+                || format!("session request failed with {status}"),
+                str::to_string,
+            );
+        Err(dialect.error(status, message))
+    }
+
+    /// Start the turn for a request's messages: on the session named by
+    /// [`SESSION_HEADER`], or on a new one.
+    async fn start(
+        &self,
+        headers: &HeaderMap,
+        dialect: Dialect,
+        model: &str,
+        messages: &[ChatMessage],
+    ) -> Result<StartedTurn, Response> {
+        let continued = headers
+            .get(SESSION_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let prompt = ChatPrompt::parse(messages, continued.is_some())
+            .map_err(|message| dialect.error(StatusCode::BAD_REQUEST, message))?;
+        let keep = continued.is_some()
+            || headers
+                .get(KEEP_SESSION_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.eq_ignore_ascii_case("true"));
+        let session_id = match continued {
+            Some(session_id) => session_id,
+            None => {
+                let session = self
+                    .opencode(headers, dialect, Method::POST, "/session", json!({}))
+                    .await?;
+                let Some(session_id) = session["id"].as_str() else {
+                    return Err(dialect.error(StatusCode::BAD_GATEWAY, "session has no id"));
+                };
+                session_id.to_string()
+            }
+        };
+        let ephemeral = (!keep).then(|| EphemeralSession {
+            opencode: self.opencode.clone(),
+            authorization: headers.get(header::AUTHORIZATION).cloned(),
+            session_id: session_id.clone(),
+        });
+
+        // Tail the session from now on before the turn starts, so none of its
+        // events are missed.
+        let events = tail(self.transcripts.clone(), session_id.clone());
+        let turn = self
+            .opencode(
+                headers,
+                dialect,
+                Method::POST,
+                &format!("/session/{session_id}/prompt_async"),
+                prompt.body(model),
+            )
+            .await?;
+        let Some(turn_id) = turn["id"].as_str().map(str::to_string) else {
+            return Err(dialect.error(StatusCode::BAD_GATEWAY, "turn has no id"));
+        };
+        Ok(StartedTurn {
+            output: Box::pin(turn_output(events, turn_id.clone())),
+            turn_id,
+            session: TurnSession {
+                session_id,
+                keep,
+                ephemeral,
+            },
+        })
+    }
+}
+
+/// A request's turn, started on its session.
+struct StartedTurn {
+    turn_id: String,
+    output: TurnOutputs,
+    session: TurnSession,
+}
+
+type TurnOutputs = Pin<Box<dyn Stream<Item = TurnOutput> + Send>>;
+
+/// The session a request's turn runs on, and whether it outlives the request.
+struct TurnSession {
+    session_id: String,
+    keep: bool,
+    ephemeral: Option<EphemeralSession>,
+}
+
+impl TurnSession {
+    /// Keep the session as long as `output` is streamed, which ends with the
+    /// turn or when the client goes away.
+    fn hold(&mut self, output: TurnOutputs) -> TurnOutputs {
+        let ephemeral = self.ephemeral.take();
+        Box::pin(output.map(move |output| {
+            let _ = &ephemeral;
+            output
+        }))
+    }
+
+    /// Name a kept session in the response's [`SESSION_HEADER`].
+    fn name(self, mut response: Response) -> Response {
+        if self.keep {
+            if let Ok(value) = self.session_id.parse() {
+                response.headers_mut().insert(SESSION_HEADER, value);
+            }
+        }
+        response
+    }
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+pub(super) async fn post_v1_chat_completions(
+    State(chat): State<Arc<ChatCompletions>>,
+    headers: HeaderMap,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Response {
+    let StartedTurn {
+        turn_id,
+        output,
+        mut session,
+    } = match chat
+        .start(&headers, Dialect::OpenAi, &request.model, &request.messages)
+        .await
+    {
+        Ok(turn) => turn,
+        Err(response) => return response,
+    };
+    let completion = Completion {
+        id: format!("chatcmpl-{turn_id}"),
+        created: unix_seconds(),
+        model: request.model,
+    };
+    let response = if request.stream {
+        Sse::new(
+            completion
+                .chunks(session.hold(output))
+                .map(Ok::<_, Infallible>),
+        )
+        .keep_alive(KeepAlive::default())
+        .into_response()
+    } else {
+        completion.collect(collect_text(output).await)
+    };
+    session.name(response)
+}
+
+/// `POST /v1/messages`, the Anthropic Messages API counterpart of
+/// [`post_v1_chat_completions`].
+pub(super) async fn post_v1_messages(
+    State(chat): State<Arc<ChatCompletions>>,
+    headers: HeaderMap,
+    Json(request): Json<MessagesRequest>,
+) -> Response {
+    let mut messages = Vec::with_capacity(request.messages.len() + 1);
+    if let Some(system) = request.system {
+        messages.push(ChatMessage {
+            role: "system".to_string(),
+            content: system,
+        });
+    }
+    messages.extend(request.messages);
+    let StartedTurn {
+        turn_id,
+        output,
+        mut session,
+    } = match chat
+        .start(&headers, Dialect::Anthropic, &request.model, &messages)
+        .await
+    {
+        Ok(turn) => turn,
+        Err(response) => return response,
+    };
+    let message = AnthropicMessage {
+        id: format!("msg_{turn_id}"),
+        model: request.model,
+    };
+    let response = if request.stream {
+        Sse::new(
+            message
+                .events(session.hold(output))
+                .map(Ok::<_, Infallible>),
+        )
+        .keep_alive(KeepAlive::default())
+        .into_response()
+    } else {
+        message.collect(collect_text(output).await)
+    };
+    session.name(response)
+}
+
+/// A session started for a single completion. Dropping it deletes the
+/// session, which stops its agent process.
+struct EphemeralSession {
+    opencode: Router,
+    authorization: Option<HeaderValue>,
+    session_id: String,
+}
+
+impl Drop for EphemeralSession {
+    fn drop(&mut self) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let mut request = Request::builder()
+            .method(Method::DELETE)
+            .uri(format!("/session/{}", self.session_id));
+        if let Some(authorization) = self.authorization.take() {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        let Ok(request) = request.body(Body::empty()) else {
+            return;
+        };
+        let opencode = self.opencode.clone();
+        let session_id = std::mem::take(&mut self.session_id);
+        runtime.spawn(async move {
+            let response = opencode
+                .oneshot(request)
+                .await
+                .unwrap_or_else(|err| match err {});
+            if !response.status().is_success() {
+                tracing::warn!(
+                    session_id,
+                    status = %response.status(),
+                    "failed to delete chat completion session"
+                );
+            }
+        });
+    }
+}
+
+/// Wait for the turn and gather its text.
+async fn collect_text(mut output: TurnOutputs) -> Result<String, String> {
+    let mut content = String::new();
+    while let Some(update) = output.next().await {
+        match update {
+            TurnOutput::Text(text) => content.push_str(&text),
+            TurnOutput::Done => return Ok(content),
+            TurnOutput::Failed(error) => return Err(error),
+        }
+    }
+    Err("the session ended before the turn finished".to_string())
+}
+
+struct Completion {
+    id: String,
+    created: u64,
+    model: String,
+}
+
+impl Completion {
+    /// Answer with the whole completion, or the turn's error.
+    fn collect(&self, text: Result<String, String>) -> Response {
+        match text {
+            Ok(content) => Json(json!({
+                "id": self.id,
+                "object": "chat.completion",
+                "created": self.created,
+                "model": self.model,
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": content},
+                    "finish_reason": "stop",
+                }],
+            }))
+            .into_response(),
+            Err(error) => openai_error(StatusCode::BAD_GATEWAY, error),
+        }
+    }
+
+    fn chunk(&self, delta: Value, finish_reason: Option<&str>) -> Event {
+        Event::default().data(
+            json!({
+                "id": self.id,
+                "object": "chat.completion.chunk",
+                "created": self.created,
+                "model": self.model,
+                "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+            })
+            .to_string(),
+        )
+    }
+
+    /// The turn as completion chunks: the assistant role, its text as it
+    /// streams, a final chunk with the finish reason, then `[DONE]`. A failed
+    /// turn sends an `error` object in place of the final chunk.
+    fn chunks(
+        self,
+        output: impl Stream<Item = TurnOutput> + Send + 'static,
+    ) -> impl Stream<Item = Event> + Send {
+        let role = self.chunk(json!({"role": "assistant", "content": ""}), None);
+        let body = output.flat_map(move |output| {
+            let events = match output {
+                TurnOutput::Text(text) => vec![self.chunk(json!({"content": text}), None)],
+                TurnOutput::Done => vec![
+                    self.chunk(json!({}), Some("stop")),
+                    Event::default().data("[DONE]"),
+                ],
+                TurnOutput::Failed(message) => vec![
+                    Event::default().data(
+                        json!({"error": {"message": message, "type": "server_error"}}).to_string(),
+                    ),
+                    Event::default().data("[DONE]"),
+                ],
+            };
+            stream::iter(events)
+        });
+        stream::once(async move { role }).chain(body)
+    }
+}
+
+/// An Anthropic Messages API answer. Token usage is not tracked per
+/// request, so it is reported as zero.
+struct AnthropicMessage {
+    id: String,
+    model: String,
+}
+
+impl AnthropicMessage {
+    fn message(&self, content: Value, stop_reason: Option<&str>) -> Value {
+        json!({
+            "id": self.id,
+            "type": "message",
+            "role": "assistant",
+            "model": self.model,
+            "content": content,
+            "stop_reason": stop_reason,
+            "stop_sequence": null,
+            "usage": {"input_tokens": 0, "output_tokens": 0},
+        })
+    }
+
+    /// Answer with the whole message, or the turn's error.
+    fn collect(&self, text: Result<String, String>) -> Response {
+        match text {
+            Ok(text) => {
+                Json(self.message(json!([{"type": "text", "text": text}]), Some("end_turn")))
+                    .into_response()
+            }
+            Err(error) => anthropic_error(StatusCode::BAD_GATEWAY, error),
+        }
+    }
+
+    /// The turn as Messages API stream events: `message_start`, one text
+    /// content block whose deltas follow the turn's text, then
+    /// `message_delta` with the stop reason and `message_stop`. A failed turn
+    /// ends with an `error` event instead.
+    fn events(
+        self,
+        output: impl Stream<Item = TurnOutput> + Send + 'static,
+    ) -> impl Stream<Item = Event> + Send {
+        let event = |name: &str, data: Value| Event::default().event(name).data(data.to_string());
+        let start = vec![
+            event(
+                "message_start",
+                json!({"type": "message_start", "message": self.message(json!([]), None)}),
+            ),
+            event(
+                "content_block_start",
+                json!({
+                    "type": "content_block_start",
+                    "index": 0,
+                    "content_block": {"type": "text", "text": ""},
+                }),
+            ),
+        ];
+        let body = output.flat_map(move |output| {
+            let events = match output {
+                TurnOutput::Text(text) => vec![event(
+                    "content_block_delta",
+                    json!({
+                        "type": "content_block_delta",
+                        "index": 0,
+                        "delta": {"type": "text_delta", "text": text},
+                    }),
+                )],
+                TurnOutput::Done => vec![
+                    event(
+                        "content_block_stop",
+                        json!({"type": "content_block_stop", "index": 0}),
+                    ),
+                    event(
+                        "message_delta",
+                        json!({
+                            "type": "message_delta",
+                            "delta": {"stop_reason": "end_turn", "stop_sequence": null},
+                            "usage": {"output_tokens": 0},
+                        }),
+                    ),
+                    event("message_stop", json!({"type": "message_stop"})),
+                ],
+                TurnOutput::Failed(message) => vec![event(
+                    "error",
+                    anthropic_error_body(StatusCode::BAD_GATEWAY, message),
+                )],
+            };
+            stream::iter(events)
+        });
+        stream::iter(start).chain(body)
+    }
+}
@@ -0,0 +1,196 @@
+use sandbox_agent_agent_credentials::{AuthType, ExtractedCredentials, ProviderCredentials};
+
+use super::*;
+
+const DEFAULT_ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com";
+const DEFAULT_OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const ANTHROPIC_OAUTH_BETA: &str = "oauth-2025-04-20";
+const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where `--validate-agents` sends its probe requests.
+#[derive(Debug, Clone)]
+pub struct CredentialCheckConfig {
+    /// Anthropic API root, without `/v1`.
+    pub anthropic_base_url: String,
+    /// OpenAI API root, including `/v1`.
+    pub openai_base_url: String,
+    pub timeout: Duration,
+}
+
+impl Default for CredentialCheckConfig {
+    fn default() -> Self {
+        Self {
+            anthropic_base_url: DEFAULT_ANTHROPIC_BASE_URL.to_string(),
+            openai_base_url: DEFAULT_OPENAI_BASE_URL.to_string(),
+            timeout: DEFAULT_CHECK_TIMEOUT,
+        }
+    }
+}
+
+impl CredentialCheckConfig {
+    /// Probe the endpoints the agents themselves will use: `ANTHROPIC_BASE_URL`
+    /// and `OPENAI_BASE_URL` when set.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Some(url) = non_empty_env("ANTHROPIC_BASE_URL") {
+            config.anthropic_base_url = url;
+        }
+        if let Some(url) = non_empty_env("OPENAI_BASE_URL") {
+            config.openai_base_url = url;
+        }
+        config
+    }
+}
+
+fn non_empty_env(key: &str) -> Option<String> {
+    std::env::var(key)
+        .ok()
+        .map(|value| value.trim().trim_end_matches('/').to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Check each provider once with the cheapest authenticated call it offers
+/// (listing one model) and derive every agent's status from the providers it
+/// runs on. Agents that manage their own login (Pi, Cursor) and the mock agent
+/// are not checked.
+pub(super) async fn check_agent_credentials(
+    credentials: &ExtractedCredentials,
+    config: &CredentialCheckConfig,
+) -> HashMap<AgentId, CredentialCheck> {
+    let client = reqwest::Client::builder()
+        .timeout(config.timeout)
+        .build()
+        .unwrap_or_default();
+    let (anthropic, openai) = tokio::join!(
+        check_anthropic(&client, credentials.anthropic.as_ref(), config),
+        check_openai(&client, credentials.openai.as_ref(), config),
+    );
+
+    let mut checks = HashMap::new();
+    for agent in AgentId::all().iter().copied() {
+        let check = match agent {
+            AgentId::Claude | AgentId::Amp => anthropic.clone(),
+            AgentId::Codex => openai.clone(),
+            AgentId::Opencode => best_of(&anthropic, &openai).clone(),
+            AgentId::Pi | AgentId::Cursor | AgentId::Mock => continue,
+        };
+        checks.insert(agent, check);
+    }
+    checks
+}
+
+async fn check_anthropic(
+    client: &reqwest::Client,
+    credential: Option<&ProviderCredentials>,
+    config: &CredentialCheckConfig,
+) -> CredentialCheck {
+    let Some(credential) = credential else {
+        return check_result("anthropic", CredentialCheckStatus::Missing, None);
+    };
+    let request = client
+        .get(format!("{}/v1/models?limit=1", config.anthropic_base_url))
+        .header("anthropic-version", ANTHROPIC_VERSION);
+    let request = match credential.auth_type {
+        AuthType::ApiKey => request.header("x-api-key", &credential.api_key),
+        AuthType::Oauth => request
+            .bearer_auth(&credential.api_key)
+            .header("anthropic-beta", ANTHROPIC_OAUTH_BETA),
+    };
+    probe("anthropic", request).await
+}
+
+async fn check_openai(
+    client: &reqwest::Client,
+    credential: Option<&ProviderCredentials>,
+    config: &CredentialCheckConfig,
+) -> CredentialCheck {
+    let Some(credential) = credential else {
+        return check_result("openai", CredentialCheckStatus::Missing, None);
+    };
+    // ChatGPT sign-in tokens are only accepted by the Codex backend, which
+    // has no side-effect-free endpoint to probe.
+    if credential.auth_type == AuthType::Oauth {
+        return check_result(
+            "openai",
+            CredentialCheckStatus::Unverified,
+            Some("OAuth tokens are not checked at startup".to_string()),
+        );
+    }
+    let request = client
+        .get(format!("{}/models", config.openai_base_url))
+        .bearer_auth(&credential.api_key);
+    probe("openai", request).await
+}
+
+async fn probe(provider: &str, request: reqwest::RequestBuilder) -> CredentialCheck {
+    match request.send().await {
+        Ok(response) if response.status().is_success() => {
+            check_result(provider, CredentialCheckStatus::Valid, None)
+        }
+        Ok(response)
+            if matches!(
+                response.status(),
+                reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN
+            ) =>
+        {
+            let message = provider_error_message(response).await;
+            check_result(provider, CredentialCheckStatus::Invalid, Some(message))
+        }
+        Ok(response) => {
+            let message = provider_error_message(response).await;
+            check_result(provider, CredentialCheckStatus::Error, Some(message))
+        }
+        Err(err) => check_result(
+            provider,
+            CredentialCheckStatus::Error,
+            Some(format!("request failed: {err}")),
+        ),
+    }
+}
+
+/// `HTTP 401: invalid x-api-key`, using the `error.message` both providers
+/// put in their error bodies.
+async fn provider_error_message(response: reqwest::Response) -> String {
+    let status = response.status();
+    let detail = response.json::<Value>().await.ok().and_then(|body| {
+        body.pointer("/error/message")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+    });
+    match detail {
+        Some(detail) => format!("HTTP {}: {detail}", status.as_u16()),
+        None => format!("HTTP {}", status.as_u16()),
+    }
+}
+
+fn check_result(
+    provider: &str,
+    status: CredentialCheckStatus,
+    message: Option<String>,
+) -> CredentialCheck {
+    CredentialCheck {
+        status,
+        provider: Some(provider.to_string()),
+        message,
+        checked_at_ms: now_ms(),
+    }
+}
+
+/// The more usable of two checks, for agents that can run on either provider.
+fn best_of<'a>(left: &'a CredentialCheck, right: &'a CredentialCheck) -> &'a CredentialCheck {
+    fn rank(status: CredentialCheckStatus) -> u8 {
+        match status {
+            CredentialCheckStatus::Valid => 4,
+            CredentialCheckStatus::Unverified => 3,
+            CredentialCheckStatus::Error => 2,
+            CredentialCheckStatus::Invalid => 1,
+            CredentialCheckStatus::Missing => 0,
+        }
+    }
+    if rank(right.status) > rank(left.status) {
+        right
+    } else {
+        left
+    }
+}
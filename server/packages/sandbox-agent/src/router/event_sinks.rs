@@ -0,0 +1,549 @@
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sandbox_agent_opencode_adapter::sign_payload;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use super::*;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 8;
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const DEFAULT_DEAD_LETTER_CAPACITY: usize = 1000;
+/// Events queued per sink; once a sink falls this far behind, new events go
+/// straight to its dead-letter buffer.
+const SINK_QUEUE_CAPACITY: usize = 10_000;
+/// Budget for one delivery attempt, connection included.
+const SINK_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+const ORDERING_KEY_HEADER: &str = "x-sandbox-agent-ordering-key";
+
+/// An external system the universal event stream is published to.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum EventSinkTarget {
+    /// `PUB {subject}.{sessionId}` on a NATS server
+    /// (`nats://[user:pass@]host[:port]`).
+    Nats { url: String, subject: String },
+    /// `XADD {stream}` on a Redis server
+    /// (`redis://[[user]:password@]host[:port][/db]`), with the session id in
+    /// the entry's `sessionId` field.
+    Redis { url: String, stream: String },
+    /// A `POST` per event, signed with `secret` like adapter webhooks.
+    Webhook {
+        url: String,
+        #[serde(default)]
+        secret: Option<String>,
+    },
+}
+
+impl EventSinkTarget {
+    fn kind(&self) -> &'static str {
+        match self {
+            EventSinkTarget::Nats { .. } => "nats",
+            EventSinkTarget::Redis { .. } => "redis",
+            EventSinkTarget::Webhook { .. } => "webhook",
+        }
+    }
+
+    /// The subject, stream or URL events go to, without credentials.
+    fn destination(&self) -> String {
+        match self {
+            EventSinkTarget::Nats { subject, .. } => subject.clone(),
+            EventSinkTarget::Redis { stream, .. } => stream.clone(),
+            EventSinkTarget::Webhook { url, .. } => reqwest::Url::parse(url)
+                .map(|mut url| {
+                    let _ = url.set_username("");
+                    let _ = url.set_password(None);
+                    url.to_string()
+                })
+                .unwrap_or_else(|_| url.clone()),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let (url, schemes) = match self {
+            EventSinkTarget::Nats { url, subject } => {
+                if subject.is_empty() || subject.contains(char::is_whitespace) {
+                    return Err(format!("NATS subject '{subject}' is not valid"));
+                }
+                (url, &["nats"][..])
+            }
+            EventSinkTarget::Redis { url, stream } => {
+                if stream.is_empty() {
+                    return Err("Redis stream name is empty".to_string());
+                }
+                (url, &["redis"][..])
+            }
+            EventSinkTarget::Webhook { url, .. } => (url, &["http", "https"][..]),
+        };
+        let parsed = reqwest::Url::parse(url).map_err(|err| format!("sink URL '{url}': {err}"))?;
+        if !schemes.contains(&parsed.scheme()) || parsed.host_str().is_none() {
+            return Err(format!(
+                "{} sink needs a {}:// URL, got '{url}'",
+                self.kind(),
+                schemes.join(":// or ")
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Outbound sinks for the universal event stream
+/// (`docs/session-transcript-schema.mdx`). Every event is delivered at least
+/// once, in order per session, and retried with exponential backoff; events
+/// that still fail are kept in a dead-letter buffer.
+#[derive(Debug, Clone)]
+pub struct EventSinkConfig {
+    pub sinks: Vec<EventSinkTarget>,
+    /// Delivery attempts per event before it is dead-lettered.
+    pub max_attempts: u32,
+    /// Wait before the first retry; doubles per attempt up to 30s.
+    pub initial_backoff: Duration,
+    /// Dead-lettered events kept per sink; the oldest are dropped first.
+    pub dead_letter_capacity: usize,
+}
+
+impl Default for EventSinkConfig {
+    fn default() -> Self {
+        Self {
+            sinks: Vec::new(),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            dead_letter_capacity: DEFAULT_DEAD_LETTER_CAPACITY,
+        }
+    }
+}
+
+impl EventSinkConfig {
+    /// Sinks from `SANDBOX_AGENT_EVENT_SINKS`, a JSON array such as
+    /// `[{"type":"nats","url":"nats://127.0.0.1:4222","subject":"sandbox.events"}]`,
+    /// and the attempt budget from `SANDBOX_AGENT_EVENT_SINK_MAX_ATTEMPTS`.
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = Self::default();
+        if let Ok(raw) = std::env::var("SANDBOX_AGENT_EVENT_SINKS") {
+            if !raw.trim().is_empty() {
+                config.sinks = serde_json::from_str(&raw)
+                    .map_err(|err| format!("SANDBOX_AGENT_EVENT_SINKS: {err}"))?;
+            }
+        }
+        if let Some(attempts) = std::env::var("SANDBOX_AGENT_EVENT_SINK_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|value| value.trim().parse::<u32>().ok())
+        {
+            config.max_attempts = attempts.max(1);
+        }
+        Ok(config)
+    }
+
+    /// Reject sinks with an unusable URL, subject or stream.
+    pub fn validate(&self) -> Result<(), String> {
+        self.sinks.iter().try_for_each(EventSinkTarget::validate)
+    }
+}
+
+/// Queues universal events on every sink. Each sink delivers its queue in
+/// order on its own task.
+#[derive(Debug)]
+pub(super) struct EventSinks {
+    sinks: Vec<Arc<Sink>>,
+}
+
+#[derive(Debug)]
+struct Sink {
+    target: EventSinkTarget,
+    config: EventSinkConfig,
+    sender: mpsc::Sender<Value>,
+    /// Taken when the delivery task starts on the first event.
+    receiver: Mutex<Option<mpsc::Receiver<Value>>>,
+    stats: Mutex<SinkStats>,
+}
+
+#[derive(Debug, Default)]
+struct SinkStats {
+    delivered: u64,
+    failed_attempts: u64,
+    /// Failed attempts since the last delivery.
+    consecutive_failures: u64,
+    last_error: Option<String>,
+    last_delivered_at: Option<i64>,
+    dead_letters: VecDeque<Value>,
+}
+
+impl EventSinks {
+    pub(super) fn new(config: EventSinkConfig) -> Self {
+        let sinks = config
+            .sinks
+            .iter()
+            .map(|target| {
+                let (sender, receiver) = mpsc::channel(SINK_QUEUE_CAPACITY);
+                Arc::new(Sink {
+                    target: target.clone(),
+                    config: config.clone(),
+                    sender,
+                    receiver: Mutex::new(Some(receiver)),
+                    stats: Mutex::new(SinkStats::default()),
+                })
+            })
+            .collect();
+        Self { sinks }
+    }
+
+    /// Queue a universal event on every sink.
+    pub(super) fn publish(&self, event: &Value) {
+        for sink in &self.sinks {
+            sink.enqueue(event.clone());
+        }
+    }
+
+    pub(super) fn health(&self) -> Vec<EventSinkHealth> {
+        self.sinks.iter().map(|sink| sink.health()).collect()
+    }
+
+    /// Dead-lettered events per sink, oldest first.
+    pub(super) fn dead_letters(&self) -> Value {
+        let sinks = self
+            .sinks
+            .iter()
+            .map(|sink| {
+                let stats = sink.stats.lock().unwrap();
+                json!({
+                    "type": sink.target.kind(),
+                    "destination": sink.target.destination(),
+                    "events": stats.dead_letters.iter().cloned().collect::<Vec<_>>(),
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "sinks": sinks })
+    }
+}
+
+impl Sink {
+    fn enqueue(self: &Arc<Self>, event: Value) {
+        self.start();
+        if let Err(err) = self.sender.try_send(event) {
+            let event = match err {
+                mpsc::error::TrySendError::Full(event)
+                | mpsc::error::TrySendError::Closed(event) => event,
+            };
+            self.dead_letter(event, "sink queue is full".to_string(), 0);
+        }
+    }
+
+    /// Start the delivery task, once, on the runtime emitting events.
+    fn start(self: &Arc<Self>) {
+        let mut receiver = self.receiver.lock().unwrap();
+        if receiver.is_none() {
+            return;
+        }
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let receiver = receiver.take().expect("receiver checked above");
+        handle.spawn(self.clone().run(receiver));
+    }
+
+    async fn run(self: Arc<Self>, mut receiver: mpsc::Receiver<Value>) {
+        let mut transport = Transport::new(self.target.clone());
+        while let Some(event) = receiver.recv().await {
+            self.deliver(&mut transport, event).await;
+        }
+    }
+
+    async fn deliver(&self, transport: &mut Transport, event: Value) {
+        let mut backoff = self.config.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            let result = tokio::time::timeout(SINK_ATTEMPT_TIMEOUT, transport.publish(&event))
+                .await
+                .unwrap_or_else(|_| Err("delivery timed out".to_string()));
+            let error = match result {
+                Ok(()) => {
+                    let mut stats = self.stats.lock().unwrap();
+                    stats.delivered += 1;
+                    stats.consecutive_failures = 0;
+                    stats.last_delivered_at = Some(now_ms());
+                    return;
+                }
+                Err(error) => error,
+            };
+            {
+                let mut stats = self.stats.lock().unwrap();
+                stats.failed_attempts += 1;
+                stats.consecutive_failures += 1;
+                stats.last_error = Some(error.clone());
+            }
+            transport.reset();
+            tracing::warn!(
+                sink = self.target.kind(),
+                destination = %self.target.destination(),
+                attempt,
+                error = %error,
+                "event sink delivery failed"
+            );
+            if attempt >= self.config.max_attempts {
+                self.dead_letter(event, error, attempt);
+                return;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            attempt += 1;
+        }
+    }
+
+    fn dead_letter(&self, event: Value, error: String, attempts: u32) {
+        let mut stats = self.stats.lock().unwrap();
+        if self.config.dead_letter_capacity == 0 {
+            return;
+        }
+        if stats.dead_letters.len() == self.config.dead_letter_capacity {
+            stats.dead_letters.pop_front();
+        }
+        stats.dead_letters.push_back(json!({
+            "event": event,
+            "error": error,
+            "attempts": attempts,
+            "failedAt": now_ms(),
+        }));
+    }
+
+    fn health(&self) -> EventSinkHealth {
+        let stats = self.stats.lock().unwrap();
+        EventSinkHealth {
+            sink_type: self.target.kind().to_string(),
+            destination: self.target.destination(),
+            status: if stats.consecutive_failures == 0 {
+                EventSinkStatus::Healthy
+            } else {
+                EventSinkStatus::Failing
+            },
+            delivered: stats.delivered,
+            failed_attempts: stats.failed_attempts,
+            pending: SINK_QUEUE_CAPACITY.saturating_sub(self.sender.capacity()),
+            dead_letters: stats.dead_letters.len(),
+            last_error: stats.last_error.clone(),
+            last_delivered_at: stats.last_delivered_at,
+        }
+    }
+}
+
+/// One sink's connection, kept across deliveries and reopened after a
+/// failure.
+struct Transport {
+    target: EventSinkTarget,
+    http: reqwest::Client,
+    connection: Option<BufReader<TcpStream>>,
+}
+
+impl Transport {
+    fn new(target: EventSinkTarget) -> Self {
+        Self {
+            target,
+            http: reqwest::Client::new(),
+            connection: None,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.connection = None;
+    }
+
+    async fn publish(&mut self, event: &Value) -> Result<(), String> {
+        let session_id = event["session_id"].as_str().unwrap_or_default();
+        let body = event.to_string();
+        match self.target.clone() {
+            EventSinkTarget::Nats { url, subject } => {
+                let connection = self.connect_nats(&url).await?;
+                let frame = format!("PUB {subject}.{session_id} {}\r\n{body}\r\n", body.len());
+                connection
+                    .get_mut()
+                    .write_all(frame.as_bytes())
+                    .await
+                    .map_err(|err| err.to_string())?;
+                nats_ack(connection).await
+            }
+            EventSinkTarget::Redis { url, stream } => {
+                let connection = self.connect_redis(&url).await?;
+                redis_command(
+                    connection,
+                    &[
+                        "XADD",
+                        &stream,
+                        "*",
+                        "sessionId",
+                        session_id,
+                        "event",
+                        &body,
+                    ],
+                )
+                .await
+            }
+            EventSinkTarget::Webhook { url, secret } => {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_secs())
+                    .unwrap_or(0)
+                    .to_string();
+                let mut request = self
+                    .http
+                    .post(&url)
+                    .header("content-type", APPLICATION_JSON)
+                    .header(
+                        "x-sandbox-agent-event",
+                        event["type"].as_str().unwrap_or_default(),
+                    )
+                    .header(
+                        "x-sandbox-agent-delivery",
+                        format!("{session_id}-{}", event["sequence"]),
+                    )
+                    .header(ORDERING_KEY_HEADER, session_id)
+                    .header("x-sandbox-agent-timestamp", &timestamp);
+                if let Some(secret) = secret.as_deref() {
+                    request = request.header(
+                        "x-sandbox-agent-signature",
+                        sign_payload(secret, &timestamp, body.as_bytes()),
+                    );
+                }
+                let response = request
+                    .body(body)
+                    .send()
+                    .await
+                    .map_err(|err| err.to_string())?;
+                if response.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("webhook returned {}", response.status()))
+                }
+            }
+        }
+    }
+
+    async fn connect_nats(&mut self, url: &str) -> Result<&mut BufReader<TcpStream>, String> {
+        if self.connection.is_none() {
+            let url = reqwest::Url::parse(url).map_err(|err| err.to_string())?;
+            let mut connection = connect(&url, 4222).await?;
+            let mut options = json!({"verbose": true, "pedantic": false, "name": "sandbox-agent"});
+            if !url.username().is_empty() {
+                options["user"] = json!(url.username());
+                options["pass"] = json!(url.password().unwrap_or_default());
+            }
+            connection
+                .get_mut()
+                .write_all(format!("CONNECT {options}\r\n").as_bytes())
+                .await
+                .map_err(|err| err.to_string())?;
+            nats_ack(&mut connection).await?;
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_mut().expect("connection opened above"))
+    }
+
+    async fn connect_redis(&mut self, url: &str) -> Result<&mut BufReader<TcpStream>, String> {
+        if self.connection.is_none() {
+            let url = reqwest::Url::parse(url).map_err(|err| err.to_string())?;
+            let mut connection = connect(&url, 6379).await?;
+            if let Some(password) = url.password() {
+                let mut auth = vec!["AUTH"];
+                if !url.username().is_empty() {
+                    auth.push(url.username());
+                }
+                auth.push(password);
+                redis_command(&mut connection, &auth).await?;
+            }
+            let db = url.path().trim_start_matches('/');
+            if !db.is_empty() {
+                redis_command(&mut connection, &["SELECT", db]).await?;
+            }
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_mut().expect("connection opened above"))
+    }
+}
+
+async fn connect(url: &reqwest::Url, default_port: u16) -> Result<BufReader<TcpStream>, String> {
+    let host = url.host_str().ok_or("sink URL has no host")?;
+    let port = url.port().unwrap_or(default_port);
+    let stream = TcpStream::connect((host, port))
+        .await
+        .map_err(|err| format!("connect to {host}:{port}: {err}"))?;
+    Ok(BufReader::new(stream))
+}
+
+async fn read_line(connection: &mut BufReader<TcpStream>) -> Result<String, String> {
+    let mut line = String::new();
+    let read = connection
+        .read_line(&mut line)
+        .await
+        .map_err(|err| err.to_string())?;
+    if read == 0 {
+        return Err("connection closed".to_string());
+    }
+    Ok(line.trim_end().to_string())
+}
+
+/// Wait for the `+OK` a verbose NATS connection sends for each command,
+/// answering server pings on the way.
+async fn nats_ack(connection: &mut BufReader<TcpStream>) -> Result<(), String> {
+    loop {
+        let line = read_line(connection).await?;
+        if line == "+OK" {
+            return Ok(());
+        }
+        if let Some(error) = line.strip_prefix("-ERR") {
+            return Err(format!("NATS error:{error}"));
+        }
+        if line == "PING" {
+            connection
+                .get_mut()
+                .write_all(b"PONG\r\n")
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        // `INFO` on connect, and `PONG`, need no answer.
+    }
+}
+
+/// Send a RESP command and read a simple, integer or bulk string reply.
+async fn redis_command(connection: &mut BufReader<TcpStream>, args: &[&str]) -> Result<(), String> {
+    let mut frame = format!("*{}\r\n", args.len());
+    for arg in args {
+        frame.push_str(&format!("${}\r\n{arg}\r\n", arg.len()));
+    }
+    connection
+        .get_mut()
+        .write_all(frame.as_bytes())
+        .await
+        .map_err(|err| err.to_string())?;
+    let line = read_line(connection).await?;
+    match line.as_bytes().first() {
+        Some(b'+') | Some(b':') => Ok(()),
+        Some(b'-') => Err(format!("Redis error: {}", &line[1..])),
+        Some(b'$') => {
+            let length: i64 = line[1..]
+                .parse()
+                .map_err(|_| format!("unexpected Redis reply '{line}'"))?;
+            if length >= 0 {
+                let mut value = vec![0u8; length as usize + 2];
+                connection
+                    .read_exact(&mut value)
+                    .await
+                    .map_err(|err| err.to_string())?;
+            }
+            Ok(())
+        }
+        _ => Err(format!("unexpected Redis reply '{line}'")),
+    }
+}
+
+pub(super) async fn get_v1_event_sink_dead_letters(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Value>, ApiError> {
+    let Some(sinks) = state.event_sinks.as_ref() else {
+        return Err(SandboxError::InvalidRequest {
+            message: "no event sinks are configured".to_string(),
+        }
+        .into());
+    };
+    Ok(Json(sinks.dead_letters()))
+}
@@ -0,0 +1,405 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::str::FromStr;
+
+use axum::body::Body;
+use axum::response::sse::Event;
+use futures::stream::{self, Stream, StreamExt};
+
+use super::*;
+
+const DEFAULT_FEDERATION_TIMEOUT: Duration = Duration::from_secs(10);
+/// Request and response headers that describe one hop, not the message, and
+/// are not passed through the proxy.
+const HOP_HEADERS: [&str; 6] = [
+    "host",
+    "authorization",
+    "connection",
+    "content-length",
+    "transfer-encoding",
+    "keep-alive",
+];
+
+/// A downstream sandbox-agent behind a controller, parsed from `NAME=URL`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FederatedSandbox {
+    /// Path segment the sandbox is served under, e.g. `eu-1`.
+    pub name: String,
+    /// Base URL of the sandbox-agent server, without a trailing slash.
+    pub url: String,
+}
+
+impl FromStr for FederatedSandbox {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let (name, url) = raw
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=URL, got '{raw}'"))?;
+        let name = name.trim();
+        let url = url.trim().trim_end_matches('/');
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "sandbox name '{name}' may only contain letters, digits, '-' and '_'"
+            ));
+        }
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(format!(
+                "sandbox '{name}' needs an http(s) URL, got '{url}'"
+            ));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            url: url.to_string(),
+        })
+    }
+}
+
+/// Controller mode: one server fronting a fleet of sandbox-agents. Each
+/// sandbox's API is served under `/v1/federation/sandboxes/{name}/`, their
+/// sessions are listed together and their OpenCode events are merged into
+/// one stream.
+#[derive(Debug, Clone)]
+pub struct FederationConfig {
+    pub sandboxes: Vec<FederatedSandbox>,
+    /// Bearer token sent to every downstream sandbox.
+    pub token: Option<String>,
+    /// Budget per sandbox for health checks and session listing.
+    pub timeout: Duration,
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        Self {
+            sandboxes: Vec::new(),
+            token: None,
+            timeout: DEFAULT_FEDERATION_TIMEOUT,
+        }
+    }
+}
+
+impl FederationConfig {
+    /// Sandboxes from `SANDBOX_AGENT_FEDERATION` (comma-separated `NAME=URL`)
+    /// and the downstream token from `SANDBOX_AGENT_FEDERATION_TOKEN`.
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = Self::default();
+        if let Ok(raw) = std::env::var("SANDBOX_AGENT_FEDERATION") {
+            for entry in raw
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+            {
+                config.sandboxes.push(entry.parse()?);
+            }
+        }
+        config.token = std::env::var("SANDBOX_AGENT_FEDERATION_TOKEN")
+            .ok()
+            .map(|token| token.trim().to_string())
+            .filter(|token| !token.is_empty());
+        Ok(config)
+    }
+
+    /// Reject two sandboxes with the same name.
+    pub fn validate(&self) -> Result<(), String> {
+        for (index, sandbox) in self.sandboxes.iter().enumerate() {
+            if self.sandboxes[..index]
+                .iter()
+                .any(|other| other.name == sandbox.name)
+            {
+                return Err(format!("sandbox '{}' is listed twice", sandbox.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct Federation {
+    config: FederationConfig,
+    client: reqwest::Client,
+}
+
+impl Federation {
+    pub(super) fn new(config: FederationConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .connect_timeout(config.timeout)
+            .build()
+            .unwrap_or_default();
+        Self { config, client }
+    }
+
+    fn sandbox(&self, name: &str) -> Result<&FederatedSandbox, SandboxError> {
+        self.config
+            .sandboxes
+            .iter()
+            .find(|sandbox| sandbox.name == name)
+            .ok_or_else(|| SandboxError::SessionNotFound {
+                session_id: format!("federation:{name}"),
+            })
+    }
+
+    fn request(&self, method: reqwest::Method, url: String) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, url);
+        match self.config.token.as_deref() {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// `GET {sandbox}{path}` as JSON, within the configured budget.
+    async fn get_json(&self, sandbox: &FederatedSandbox, path: &str) -> Result<Value, String> {
+        let response = self
+            .request(reqwest::Method::GET, format!("{}{path}", sandbox.url))
+            .timeout(self.config.timeout)
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("{path} returned {status}"));
+        }
+        response.json().await.map_err(|err| err.to_string())
+    }
+}
+
+fn federation(state: &AppState) -> Result<&Federation, ApiError> {
+    state.federation.as_deref().ok_or_else(|| {
+        SandboxError::InvalidRequest {
+            message: "federation is not enabled".to_string(),
+        }
+        .into()
+    })
+}
+
+/// Every downstream sandbox with its `/v1/health`, or why it is unreachable.
+pub(super) async fn get_v1_federation_sandboxes(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Value>, ApiError> {
+    let federation = federation(&state)?;
+    let sandboxes = futures::future::join_all(federation.config.sandboxes.iter().map(
+        |sandbox| async move {
+            let mut value = json!({"name": sandbox.name, "url": sandbox.url});
+            match federation.get_json(sandbox, "/v1/health").await {
+                Ok(health) => {
+                    value["status"] = json!("healthy");
+                    value["health"] = health;
+                }
+                Err(err) => {
+                    value["status"] = json!("unreachable");
+                    value["error"] = json!(err);
+                }
+            }
+            value
+        },
+    ))
+    .await;
+    Ok(Json(json!({"sandboxes": sandboxes})))
+}
+
+/// OpenCode sessions of every sandbox, each tagged with its `sandbox` and the
+/// `path` it is served under here. Sandboxes that could not be listed are
+/// reported in `errors`.
+pub(super) async fn get_v1_federation_sessions(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Value>, ApiError> {
+    let federation = federation(&state)?;
+    let listed = futures::future::join_all(federation.config.sandboxes.iter().map(
+        |sandbox| async move {
+            (
+                sandbox,
+                federation.get_json(sandbox, "/opencode/session").await,
+            )
+        },
+    ))
+    .await;
+    let mut sessions = Vec::new();
+    let mut errors = Vec::new();
+    for (sandbox, result) in listed {
+        match result {
+            Ok(Value::Array(listed)) => {
+                sessions.extend(listed.into_iter().map(|mut session| {
+                    if let Some(id) = session.get("id").and_then(Value::as_str) {
+                        session["path"] = json!(format!(
+                            "/v1/federation/sandboxes/{}/opencode/session/{id}",
+                            sandbox.name
+                        ));
+                    }
+                    session["sandbox"] = json!(sandbox.name);
+                    session
+                }));
+            }
+            Ok(_) => errors.push(json!({
+                "sandbox": sandbox.name,
+                "message": "/opencode/session did not return a list",
+            })),
+            Err(err) => errors.push(json!({"sandbox": sandbox.name, "message": err})),
+        }
+    }
+    Ok(Json(json!({"sessions": sessions, "errors": errors})))
+}
+
+/// Forward a request to `/{path}` on the named sandbox and stream the
+/// response back, so SSE subscriptions pass through as they arrive.
+pub(super) async fn federation_proxy(
+    State(state): State<Arc<AppState>>,
+    Path((name, path)): Path<(String, String)>,
+    request: Request<Body>,
+) -> Result<Response, ApiError> {
+    let federation = federation(&state)?;
+    let sandbox = federation.sandbox(&name)?;
+    let (parts, body) = request.into_parts();
+    let mut url = format!("{}/{}", sandbox.url, path.trim_start_matches('/'));
+    if let Some(query) = parts.uri.query() {
+        url.push('?');
+        url.push_str(query);
+    }
+    let method = reqwest::Method::from_bytes(parts.method.as_str().as_bytes()).map_err(|err| {
+        SandboxError::InvalidRequest {
+            message: err.to_string(),
+        }
+    })?;
+    let body = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|err| SandboxError::InvalidRequest {
+            message: format!("failed to read request body: {err}"),
+        })?;
+
+    let mut outbound = federation.request(method, url).body(body.to_vec());
+    for (header_name, value) in &parts.headers {
+        if HOP_HEADERS.contains(&header_name.as_str()) {
+            continue;
+        }
+        outbound = outbound.header(header_name.as_str(), value.as_bytes());
+    }
+    let upstream = outbound
+        .send()
+        .await
+        .map_err(|err| SandboxError::StreamError {
+            message: format!("sandbox '{name}' is unreachable: {err}"),
+        })?;
+
+    let status =
+        StatusCode::from_u16(upstream.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut response = Response::builder().status(status);
+    for (header_name, value) in upstream.headers() {
+        if HOP_HEADERS.contains(&header_name.as_str()) {
+            continue;
+        }
+        response = response.header(header_name.as_str(), value.as_bytes());
+    }
+    response
+        .body(Body::from_stream(upstream.bytes_stream()))
+        .map_err(|err| {
+            SandboxError::StreamError {
+                message: err.to_string(),
+            }
+            .into()
+        })
+}
+
+/// The OpenCode `/event` streams of every sandbox merged into one. Each
+/// event gains a top-level `sandbox`; a sandbox that cannot be subscribed to
+/// or drops its stream is reported with `federation.sandbox_disconnected`.
+pub(super) async fn get_v1_federation_events(
+    State(state): State<Arc<AppState>>,
+) -> Result<Sse<PinBoxSseStream>, ApiError> {
+    let federation = federation(&state)?;
+    let streams = futures::future::join_all(federation.config.sandboxes.iter().map(|sandbox| {
+        let request = federation
+            .request(
+                reqwest::Method::GET,
+                format!("{}/opencode/event", sandbox.url),
+            )
+            .header(reqwest::header::ACCEPT, TEXT_EVENT_STREAM);
+        let name = sandbox.name.clone();
+        async move {
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    sandbox_events(name, response.bytes_stream()).boxed()
+                }
+                Ok(response) => {
+                    let message = format!("/opencode/event returned {}", response.status());
+                    stream::iter([disconnected(&name, &message)]).boxed()
+                }
+                Err(err) => stream::iter([disconnected(&name, &err.to_string())]).boxed(),
+            }
+        }
+    }))
+    .await;
+    let merged = stream::select_all(streams)
+        .map(|payload| Ok::<_, Infallible>(Event::default().data(payload.to_string())));
+    Ok(Sse::new(Box::pin(merged) as PinBoxSseStream).keep_alive(
+        KeepAlive::new()
+            .interval(Duration::from_secs(15))
+            .text("heartbeat"),
+    ))
+}
+
+fn disconnected(sandbox: &str, message: &str) -> Value {
+    json!({
+        "type": "federation.sandbox_disconnected",
+        "sandbox": sandbox,
+        "properties": {"sandbox": sandbox, "message": message},
+    })
+}
+
+/// The JSON `data` of each SSE event in `bytes`, tagged with `sandbox`,
+/// followed by a disconnect notice when the stream ends.
+fn sandbox_events<S>(sandbox: String, bytes: S) -> impl Stream<Item = Value> + Send
+where
+    S: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin + 'static,
+{
+    struct Reader<S> {
+        sandbox: String,
+        bytes: Option<S>,
+        buffer: String,
+        ready: VecDeque<Value>,
+    }
+
+    let reader = Reader {
+        sandbox,
+        bytes: Some(bytes),
+        buffer: String::new(),
+        ready: VecDeque::new(),
+    };
+    stream::unfold(reader, |mut reader| async move {
+        loop {
+            if let Some(event) = reader.ready.pop_front() {
+                return Some((event, reader));
+            }
+            let bytes = reader.bytes.as_mut()?;
+            match bytes.next().await {
+                Some(Ok(chunk)) => {
+                    reader
+                        .buffer
+                        .push_str(&String::from_utf8_lossy(&chunk).replace("\r\n", "\n"));
+                    while let Some(end) = reader.buffer.find("\n\n") {
+                        let block: String = reader.buffer.drain(..end + 2).collect();
+                        let data = block
+                            .lines()
+                            .filter_map(|line| line.strip_prefix("data:"))
+                            .map(|line| line.strip_prefix(' ').unwrap_or(line))
+                            .collect::<Vec<_>>()
+                            .join("\n");
+                        if let Ok(mut payload @ Value::Object(_)) = serde_json::from_str(&data) {
+                            payload["sandbox"] = json!(reader.sandbox);
+                            reader.ready.push_back(payload);
+                        }
+                    }
+                }
+                Some(Err(err)) => {
+                    reader.bytes = None;
+                    return Some((disconnected(&reader.sandbox, &err.to_string()), reader));
+                }
+                None => {
+                    reader.bytes = None;
+                    return Some((disconnected(&reader.sandbox, "event stream ended"), reader));
+                }
+            }
+        }
+    })
+}
@@ -23,17 +23,11 @@ pub(super) async fn require_token(
     request: Request<axum::body::Body>,
     next: Next,
 ) -> Result<Response, ApiError> {
-    let Some(expected) = state.auth.token.as_ref() else {
+    let Some(tokens) = state.auth.as_ref() else {
         return Ok(next.run(request).await);
     };
 
-    let bearer = request
-        .headers()
-        .get(header::AUTHORIZATION)
-        .and_then(|value| value.to_str().ok())
-        .and_then(|value| value.strip_prefix("Bearer "));
-
-    if bearer == Some(expected.as_str()) {
+    if bearer_token(request.headers()).is_some_and(|token| tokens.verify(token)) {
         return Ok(next.run(request).await);
     }
 
@@ -42,6 +36,13 @@ pub(super) async fn require_token(
     }))
 }
 
+pub(super) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
 pub(super) type PinBoxSseStream = crate::acp_proxy_runtime::PinBoxSseStream;
 
 pub(super) fn credentials_available_for(
@@ -0,0 +1,98 @@
+use super::*;
+
+/// Response header carrying the budget of the route that served the request,
+/// in milliseconds, so clients can align their own deadlines.
+pub const REQUEST_TIMEOUT_HEADER: &str = "x-request-timeout";
+
+const DEFAULT_CONTROL_TIMEOUT_MS: u64 = 30_000;
+const DEFAULT_INSTALL_TIMEOUT_MS: u64 = 600_000;
+/// Longer than the ACP runtime's own agent response timeout, so that one
+/// reports first.
+const DEFAULT_ACP_TIMEOUT_MS: u64 = 150_000;
+/// Synchronous OpenCode prompts hold the request open for the whole turn.
+const DEFAULT_OPENCODE_TIMEOUT_MS: u64 = 600_000;
+
+/// Time budget per route group. The budget covers producing the response
+/// head; streamed bodies such as SSE are not cut off. `None` disables the
+/// timeout for a group.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct RouteTimeouts {
+    /// `/v1` health, agents, filesystem and config routes, and `/metrics`.
+    pub control: Option<Duration>,
+    /// `POST /v1/agents/:agent/install`.
+    pub install: Option<Duration>,
+    /// `/v1/acp` routes.
+    pub acp: Option<Duration>,
+    /// Everything under `/opencode`.
+    pub opencode: Option<Duration>,
+}
+
+impl RouteTimeouts {
+    /// Budgets from `SANDBOX_AGENT_TIMEOUT_{CONTROL,INSTALL,ACP,OPENCODE}_MS`;
+    /// `0` disables the timeout.
+    pub fn from_env() -> Self {
+        Self {
+            control: budget_from_env(
+                "SANDBOX_AGENT_TIMEOUT_CONTROL_MS",
+                DEFAULT_CONTROL_TIMEOUT_MS,
+            ),
+            install: budget_from_env(
+                "SANDBOX_AGENT_TIMEOUT_INSTALL_MS",
+                DEFAULT_INSTALL_TIMEOUT_MS,
+            ),
+            acp: budget_from_env("SANDBOX_AGENT_TIMEOUT_ACP_MS", DEFAULT_ACP_TIMEOUT_MS),
+            opencode: budget_from_env(
+                "SANDBOX_AGENT_TIMEOUT_OPENCODE_MS",
+                DEFAULT_OPENCODE_TIMEOUT_MS,
+            ),
+        }
+    }
+}
+
+fn budget_from_env(key: &str, default_ms: u64) -> Option<Duration> {
+    let ms = std::env::var(key)
+        .ok()
+        .and_then(|raw| raw.trim().parse::<u64>().ok())
+        .unwrap_or(default_ms);
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+/// Apply `budget` to every route of `router`.
+pub(super) fn with_timeout<S>(router: Router<S>, budget: Option<Duration>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    match budget {
+        Some(budget) => router.layer(axum::middleware::from_fn_with_state(
+            budget,
+            enforce_timeout,
+        )),
+        None => router,
+    }
+}
+
+async fn enforce_timeout(
+    State(budget): State<Duration>,
+    request: Request<axum::body::Body>,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let mut response = match tokio::time::timeout(budget, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            tracing::warn!(%method, path, budget_ms = budget.as_millis(), "request timed out");
+            ApiError::Sandbox(SandboxError::Timeout {
+                message: Some(format!(
+                    "{method} {path} exceeded its {} ms budget",
+                    budget.as_millis()
+                )),
+            })
+            .into_response()
+        }
+    };
+    if let Ok(value) = header::HeaderValue::from_str(&budget.as_millis().to_string()) {
+        response.headers_mut().insert(REQUEST_TIMEOUT_HEADER, value);
+    }
+    response
+}
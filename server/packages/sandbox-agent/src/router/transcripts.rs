@@ -0,0 +1,122 @@
+use futures::stream::{self, Stream};
+use sandbox_agent_opencode_adapter::{EventObserver, TranscriptFormat, TranscriptTranslator};
+use tokio::sync::broadcast;
+
+use super::*;
+use crate::prompt::{event_session, UniversalTranslator};
+
+/// The universal event stream (`docs/session-transcript-schema.mdx`) of every
+/// session, translated once from the events the OpenCode adapter emits and
+/// passed on to live tails and the event sinks. Nothing is kept: transcripts
+/// are rebuilt from the persisted event log by [`UniversalTranscripts`].
+#[derive(Debug)]
+pub(super) struct SessionTranscripts {
+    sessions: Mutex<HashMap<String, Transcript>>,
+    sinks: Option<Arc<EventSinks>>,
+}
+
+#[derive(Debug)]
+struct Transcript {
+    translator: UniversalTranslator,
+    sender: broadcast::Sender<Value>,
+}
+
+impl Transcript {
+    fn new(session_id: &str) -> Self {
+        Self {
+            translator: UniversalTranslator::new(session_id, false),
+            sender: broadcast::channel(1024).0,
+        }
+    }
+}
+
+impl SessionTranscripts {
+    pub(super) fn new(sinks: Option<Arc<EventSinks>>) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            sinks,
+        }
+    }
+
+    fn subscribe(&self, session_id: &str) -> broadcast::Receiver<Value> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions
+            .entry(session_id.to_string())
+            .or_insert_with(|| Transcript::new(session_id))
+            .sender
+            .subscribe()
+    }
+}
+
+impl EventObserver for SessionTranscripts {
+    fn observe(&self, event: &Value) {
+        // The lock is held while publishing so that tails and sinks see
+        // events in the order they were translated.
+        let mut sessions = self.sessions.lock().unwrap();
+        if event["type"] == "session.deleted" {
+            // Dropping the sender ends the session's tails.
+            if let Some(session_id) = event.pointer("/properties/info/id").and_then(Value::as_str) {
+                sessions.remove(session_id);
+            }
+            return;
+        }
+        let Some(session_id) = event_session(event) else {
+            return;
+        };
+        let transcript = sessions
+            .entry(session_id.to_string())
+            .or_insert_with(|| Transcript::new(session_id));
+        for universal in transcript.translator.translate(event) {
+            let _ = transcript.sender.send(universal.clone());
+            if let Some(sinks) = self.sinks.as_ref() {
+                sinks.publish(&universal);
+            }
+        }
+    }
+}
+
+/// The universal events of `session_id` from now on, until the session is
+/// deleted. A receiver that lags behind skips the events it missed.
+pub(super) fn tail(
+    transcripts: Arc<SessionTranscripts>,
+    session_id: String,
+) -> impl Stream<Item = Value> + Send {
+    let receiver = transcripts.subscribe(&session_id);
+    stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+/// `/opencode/session/:id/transcript.ndjson` in the universal event schema.
+/// Events are stamped with the time their envelope was persisted, so a
+/// transcript reads the same every time.
+pub(super) struct UniversalTranscripts;
+
+struct PersistedTranscript(UniversalTranslator);
+
+impl TranscriptFormat for UniversalTranscripts {
+    fn translator(&self, session_id: &str) -> Box<dyn TranscriptTranslator> {
+        Box::new(PersistedTranscript(UniversalTranslator::new(
+            session_id, false,
+        )))
+    }
+}
+
+impl TranscriptTranslator for PersistedTranscript {
+    fn translate(&mut self, event: &Value, created_at: i64) -> Vec<Value> {
+        let time = chrono::DateTime::from_timestamp_millis(created_at)
+            .map(|time| time.to_rfc3339())
+            .unwrap_or_default();
+        let mut events = self.0.translate(event);
+        for event in &mut events {
+            event["time"] = json!(time);
+        }
+        events
+    }
+}
@@ -3,8 +3,48 @@ use std::collections::BTreeMap;
 use super::*;
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct HealthResponse {
     pub status: String,
+    /// Credential check results by agent id; present when the server was
+    /// started with `--validate-agents`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_credentials: Option<BTreeMap<String, CredentialCheck>>,
+    /// Delivery state of each outbound event sink; present when sinks are
+    /// configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_sinks: Option<Vec<EventSinkHealth>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum EventSinkStatus {
+    /// The last delivery attempt succeeded, or none was made yet.
+    Healthy,
+    /// The last delivery attempt failed; the sink is retrying.
+    Failing,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EventSinkHealth {
+    /// `nats`, `redis` or `webhook`.
+    #[serde(rename = "type")]
+    pub sink_type: String,
+    /// Subject, stream or URL the sink publishes to.
+    pub destination: String,
+    pub status: EventSinkStatus,
+    pub delivered: u64,
+    pub failed_attempts: u64,
+    /// Events queued behind the one being delivered.
+    pub pending: usize,
+    /// Events given up on, kept for `GET /v1/event-sinks/dead-letters`.
+    pub dead_letters: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// Epoch milliseconds of the last successful delivery.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_delivered_at: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, ToSchema)]
@@ -62,6 +102,36 @@ pub struct AgentInfo {
     pub config_options: Option<Vec<Value>>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub config_error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential_check: Option<CredentialCheck>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CredentialCheckStatus {
+    /// The provider accepted the credential.
+    Valid,
+    /// The provider rejected the credential (HTTP 401/403).
+    Invalid,
+    /// No credential was found for the agent's provider.
+    Missing,
+    /// A credential was found but cannot be checked without using it
+    /// (e.g. ChatGPT OAuth tokens).
+    Unverified,
+    /// The provider could not be reached or answered unexpectedly.
+    Error,
+}
+
+/// Result of the startup credential check for one agent.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialCheck {
+    pub status: CredentialCheckStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub checked_at_ms: i64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, ToSchema)]
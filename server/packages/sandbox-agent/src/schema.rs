@@ -0,0 +1,151 @@
+//! JSON Schemas of the payloads clients consume, for SDK code generation:
+//! printed or written by `sandbox-agent schema export` and served at
+//! `/schema/{name}.json`.
+
+use std::fs;
+use std::path::PathBuf;
+
+use axum::extract::Path as AxumPath;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use clap::{Args, Subcommand};
+use sandbox_agent_error::{ErrorType, ProblemDetails};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::cli::CliError;
+use crate::universal_events::UniversalEvent;
+
+/// One `data:` payload of the `/opencode/event` SSE stream.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct OpenCodeEvent {
+    /// Event type, e.g. `message.part.updated` or `session.idle`.
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub properties: Map<String, Value>,
+    /// The agent's own event the payload was converted from, with
+    /// `include=native`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub native: Option<Value>,
+}
+
+/// Exported schemas by name: `universal-event` for the NDJSON of
+/// `sandbox-agent prompt`, `opencode-event` for `/opencode/event`, and
+/// `problem-details` for error bodies.
+pub fn schemas() -> Vec<(&'static str, Value)> {
+    vec![
+        ("universal-event", schema_of::<UniversalEvent>()),
+        ("opencode-event", schema_of::<OpenCodeEvent>()),
+        ("problem-details", schema_of::<ProblemDetails>()),
+    ]
+}
+
+fn schema_of<T: JsonSchema>() -> Value {
+    serde_json::to_value(schemars::schema_for!(T)).unwrap_or(Value::Bool(true))
+}
+
+#[derive(Args, Debug)]
+pub struct SchemaArgs {
+    #[command(subcommand)]
+    command: SchemaCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SchemaCommand {
+    /// Print the JSON Schemas as one object keyed by name, or write them
+    /// to `<name>.json` files with `--out`.
+    Export(SchemaExportArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct SchemaExportArgs {
+    /// Directory to write the schema files into; created if missing.
+    #[arg(long)]
+    out: Option<PathBuf>,
+}
+
+pub fn run(args: &SchemaArgs) -> Result<(), CliError> {
+    let SchemaCommand::Export(args) = &args.command;
+    let schemas = schemas();
+    let Some(out) = &args.out else {
+        let schemas: Map<String, Value> = schemas
+            .into_iter()
+            .map(|(name, schema)| (name.to_string(), schema))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&schemas)?);
+        return Ok(());
+    };
+    fs::create_dir_all(out)?;
+    for (name, schema) in schemas {
+        let mut text = serde_json::to_string_pretty(&schema)?;
+        text.push('\n');
+        fs::write(out.join(format!("{name}.json")), text)?;
+    }
+    Ok(())
+}
+
+pub fn router() -> Router {
+    Router::new().route("/schema/:file", get(get_schema))
+}
+
+async fn get_schema(AxumPath(file): AxumPath<String>) -> Response {
+    let schema = file.strip_suffix(".json").and_then(|name| {
+        schemas()
+            .into_iter()
+            .find_map(|(candidate, schema)| (candidate == name).then_some(schema))
+    });
+    match schema {
+        Some(schema) => Json(schema).into_response(),
+        None => {
+            let mut problem = ProblemDetails::new(
+                ErrorType::InvalidRequest,
+                Some(format!("unknown schema {file:?}")),
+            );
+            problem.title = "Not Found".to_string();
+            problem.status = 404;
+            (
+                StatusCode::NOT_FOUND,
+                [(header::CONTENT_TYPE, "application/problem+json")],
+                Json(problem),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prompt::UniversalTranslator;
+    use serde_json::json;
+
+    #[test]
+    fn prompt_output_matches_the_universal_event_schema() {
+        let mut translator = UniversalTranslator::new("ses_1", true);
+        let events = translator.translate(&json!({
+            "type": "message.part.updated",
+            "properties": {
+                "sessionID": "ses_1",
+                "part": {
+                    "id": "prt_1", "messageID": "msg_1", "sessionID": "ses_1",
+                    "type": "text", "text": "Hi", "time": {"start": 1, "end": 2},
+                },
+            },
+        }));
+        assert_eq!(events.len(), 2);
+        for event in events {
+            serde_json::from_value::<UniversalEvent>(event.clone())
+                .unwrap_or_else(|err| panic!("{err}: {event}"));
+            serde_json::from_value::<OpenCodeEvent>(event["raw"].clone()).unwrap();
+        }
+
+        let names: Vec<_> = schemas().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(
+            names,
+            ["universal-event", "opencode-event", "problem-details"]
+        );
+    }
+}
@@ -11,6 +11,8 @@ use serde::Serialize;
 use time::OffsetDateTime;
 use tokio::time::Instant;
 
+pub mod metrics;
+
 static TELEMETRY_ENABLED: AtomicBool = AtomicBool::new(false);
 
 const TELEMETRY_URL: &str = "https://tc.rivet.dev";
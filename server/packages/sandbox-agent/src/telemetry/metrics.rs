@@ -0,0 +1,374 @@
+//! Process-wide counters and histograms served at `GET /metrics` in the
+//! Prometheus text exposition format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use sandbox_agent_opencode_adapter::AdapterMetrics;
+
+/// Content type of [`Metrics::render`].
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+const PROMPT_BUCKETS: &[f64] = &[
+    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0,
+];
+const BOOTSTRAP_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+const SQLITE_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+const HEARTBEAT_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0];
+const TURN_CPU_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// The process-wide registry.
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+pub struct Metrics {
+    /// Prompt turn duration, by agent.
+    pub prompt_duration: Family<Histogram>,
+    /// Time to install, spawn and attach an ACP agent process, by agent.
+    pub acp_bootstrap_duration: Family<Histogram>,
+    /// Open SSE subscriptions, by stream (`acp`, `opencode`).
+    pub sse_subscribers: Family<Gauge>,
+    /// SQLite write duration in the OpenCode compat store.
+    pub sqlite_write_duration: Histogram,
+    /// Events dropped because a broadcast consumer fell behind, by consumer.
+    pub broadcast_lagged_events: Family<Counter>,
+    /// Agent processes restarted after exiting unexpectedly, by agent.
+    pub agent_restarts: Family<Counter>,
+    /// Delay between an OpenCode `/event` heartbeat falling due and the
+    /// subscriber taking it.
+    pub sse_heartbeat_lag: Histogram,
+    /// OpenCode request bodies that did not match their schema, by body type.
+    pub opencode_schema_drift: Family<Counter>,
+    /// CPU time the agent processes used per prompt turn, by agent.
+    pub turn_cpu: Family<Histogram>,
+    /// Bytes the agent processes wrote to storage during prompt turns, by
+    /// agent.
+    pub turn_disk_write_bytes: Family<Counter>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            prompt_duration: Family::new(|| Histogram::new(PROMPT_BUCKETS)),
+            acp_bootstrap_duration: Family::new(|| Histogram::new(BOOTSTRAP_BUCKETS)),
+            sse_subscribers: Family::new(Gauge::default),
+            sqlite_write_duration: Histogram::new(SQLITE_BUCKETS),
+            broadcast_lagged_events: Family::new(Counter::default),
+            agent_restarts: Family::new(Counter::default),
+            sse_heartbeat_lag: Histogram::new(HEARTBEAT_BUCKETS),
+            opencode_schema_drift: Family::new(Counter::default),
+            turn_cpu: Family::new(|| Histogram::new(TURN_CPU_BUCKETS)),
+            turn_disk_write_bytes: Family::new(Counter::default),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.prompt_duration.render_histograms(
+            &mut out,
+            "sandbox_agent_prompt_duration_seconds",
+            "Prompt turn duration.",
+            "agent",
+        );
+        self.acp_bootstrap_duration.render_histograms(
+            &mut out,
+            "sandbox_agent_acp_bootstrap_duration_seconds",
+            "Time to install, spawn and attach an ACP agent process.",
+            "agent",
+        );
+        self.sse_subscribers.render_values(
+            &mut out,
+            "sandbox_agent_sse_subscribers",
+            "Open SSE subscriptions.",
+            "gauge",
+            "stream",
+            Gauge::value,
+        );
+        write_header(
+            &mut out,
+            "sandbox_agent_sqlite_write_duration_seconds",
+            "SQLite write duration in the OpenCode compat store.",
+            "histogram",
+        );
+        self.sqlite_write_duration.render(
+            &mut out,
+            "sandbox_agent_sqlite_write_duration_seconds",
+            "",
+        );
+        self.broadcast_lagged_events.render_values(
+            &mut out,
+            "sandbox_agent_broadcast_lagged_events_total",
+            "Events dropped because a broadcast consumer fell behind.",
+            "counter",
+            "consumer",
+            Counter::value,
+        );
+        self.agent_restarts.render_values(
+            &mut out,
+            "sandbox_agent_agent_restarts_total",
+            "Agent processes restarted after exiting unexpectedly.",
+            "counter",
+            "agent",
+            Counter::value,
+        );
+        write_header(
+            &mut out,
+            "sandbox_agent_sse_heartbeat_lag_seconds",
+            "Delay between an OpenCode event stream heartbeat falling due and the subscriber taking it.",
+            "histogram",
+        );
+        self.sse_heartbeat_lag
+            .render(&mut out, "sandbox_agent_sse_heartbeat_lag_seconds", "");
+        self.opencode_schema_drift.render_values(
+            &mut out,
+            "sandbox_agent_opencode_schema_drift_total",
+            "OpenCode request bodies that did not match their schema.",
+            "counter",
+            "body",
+            Counter::value,
+        );
+        self.turn_cpu.render_histograms(
+            &mut out,
+            "sandbox_agent_turn_cpu_seconds",
+            "CPU time the agent processes used per prompt turn.",
+            "agent",
+        );
+        self.turn_disk_write_bytes.render_values(
+            &mut out,
+            "sandbox_agent_turn_disk_write_bytes_total",
+            "Bytes the agent processes wrote to storage during prompt turns.",
+            "counter",
+            "agent",
+            Counter::value,
+        );
+        out
+    }
+}
+
+/// Forwards OpenCode compat adapter measurements into [`metrics`].
+pub struct OpenCodeAdapterMetrics;
+
+impl AdapterMetrics for OpenCodeAdapterMetrics {
+    fn prompt_completed(&self, agent: &str, elapsed: Duration) {
+        metrics().prompt_duration.get(agent).observe(elapsed);
+    }
+
+    fn sqlite_write(&self, elapsed: Duration) {
+        metrics().sqlite_write_duration.observe(elapsed);
+    }
+
+    fn event_subscribers_changed(&self, delta: i64) {
+        metrics().sse_subscribers.get("opencode").add(delta);
+    }
+
+    fn events_dropped(&self, consumer: &str, count: u64) {
+        metrics()
+            .broadcast_lagged_events
+            .get(&format!("opencode_{consumer}"))
+            .inc_by(count);
+    }
+
+    fn heartbeat_delivered(&self, lag: Duration) {
+        metrics().sse_heartbeat_lag.observe(lag);
+    }
+
+    fn schema_drift(&self, body: &str, _problems: usize) {
+        metrics().opencode_schema_drift.get(body).inc_by(1);
+    }
+
+    fn turn_resources(
+        &self,
+        agent: &str,
+        cpu: Duration,
+        _peak_rss_bytes: u64,
+        disk_write_bytes: u64,
+    ) {
+        metrics().turn_cpu.get(agent).observe(cpu);
+        metrics()
+            .turn_disk_write_bytes
+            .get(agent)
+            .inc_by(disk_write_bytes);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc_by(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Overwrite with a total tracked elsewhere, for counters collected at
+    /// scrape time.
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    fn value(&self) -> f64 {
+        self.0.load(Ordering::Relaxed) as f64
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn add(&self, delta: i64) {
+        self.0.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    /// Increment now and decrement when the returned guard is dropped.
+    pub fn track(self: Arc<Self>) -> GaugeGuard {
+        self.add(1);
+        GaugeGuard(self)
+    }
+
+    fn value(&self) -> f64 {
+        self.0.load(Ordering::Relaxed) as f64
+    }
+}
+
+pub struct GaugeGuard(Arc<Gauge>);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.add(-1);
+    }
+}
+
+/// Cumulative histogram over fixed upper bounds, in seconds.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Per-bucket counts; the last slot is `+Inf`.
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let index = self
+            .bounds
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = self
+                .bounds
+                .get(index)
+                .map_or_else(|| "+Inf".to_string(), |bound| bound.to_string());
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{separator}le=\"{le}\"}} {cumulative}"
+            );
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {sum}");
+        let _ = writeln!(out, "{name}_count{labels} {cumulative}");
+    }
+}
+
+/// Metrics of one kind keyed by the value of a single label.
+pub struct Family<T> {
+    members: Mutex<BTreeMap<String, Arc<T>>>,
+    make: fn() -> T,
+}
+
+impl<T> Family<T> {
+    fn new(make: fn() -> T) -> Self {
+        Self {
+            members: Mutex::new(BTreeMap::new()),
+            make,
+        }
+    }
+
+    pub fn get(&self, label: &str) -> Arc<T> {
+        let mut members = self.members.lock().unwrap_or_else(|err| err.into_inner());
+        members
+            .entry(label.to_string())
+            .or_insert_with(|| Arc::new((self.make)()))
+            .clone()
+    }
+
+    fn snapshot(&self) -> Vec<(String, Arc<T>)> {
+        let members = self.members.lock().unwrap_or_else(|err| err.into_inner());
+        members
+            .iter()
+            .map(|(label, member)| (label.clone(), member.clone()))
+            .collect()
+    }
+
+    /// Render a counter or gauge family; `sample` reads one member.
+    fn render_values(
+        &self,
+        out: &mut String,
+        name: &str,
+        help: &str,
+        kind: &str,
+        label: &str,
+        sample: fn(&T) -> f64,
+    ) {
+        write_header(out, name, help, kind);
+        for (value, member) in self.snapshot() {
+            let _ = writeln!(
+                out,
+                "{name}{{{label}=\"{}\"}} {}",
+                escape(&value),
+                sample(&member)
+            );
+        }
+    }
+}
+
+impl Family<Histogram> {
+    fn render_histograms(&self, out: &mut String, name: &str, help: &str, label: &str) {
+        write_header(out, name, help, "histogram");
+        for (value, histogram) in self.snapshot() {
+            histogram.render(out, name, &format!("{label}=\"{}\"", escape(&value)));
+        }
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape(label_value: &str) -> String {
+    label_value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
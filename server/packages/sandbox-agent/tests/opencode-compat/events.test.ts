@@ -7,6 +7,7 @@
  * Expected endpoints:
  * - GET /event - Subscribe to all events (SSE)
  * - GET /global/event - Subscribe to global events (SSE)
+ * - GET /event?batchMs=N - Batched array frames (Sandbox Agent extension)
  */
 
 import { describe, it, expect, beforeAll, beforeEach, afterEach } from "vitest";
@@ -146,6 +147,99 @@ describe("OpenCode-compatible Event Streaming", () => {
     });
   });
 
+  describe("event batching", () => {
+    it("should coalesce events into array frames when batchMs is set", async () => {
+      const response = await fetch(`${handle.baseUrl}/opencode/event?batchMs=50`, {
+        headers: { Authorization: `Bearer ${handle.token}` },
+      });
+      expect(response.ok).toBe(true);
+
+      const reader = response.body!.getReader();
+      const decoder = new TextDecoder();
+      let buffer = "";
+      let frame: any[] | undefined;
+      const deadline = Date.now() + 5000;
+      while (!frame && Date.now() < deadline) {
+        const { value, done } = await reader.read();
+        if (done) break;
+        buffer += decoder.decode(value, { stream: true });
+        const dataLine = buffer.split("\n").find((line) => line.startsWith("data:"));
+        if (dataLine) {
+          frame = JSON.parse(dataLine.slice("data:".length));
+        }
+      }
+      await reader.cancel();
+
+      expect(Array.isArray(frame)).toBe(true);
+      const types = frame!.map((event) => event.type);
+      expect(types).toContain("server.connected");
+      expect(types).toContain("worktree.ready");
+    });
+  });
+
+  describe("part sequence numbers", () => {
+    it("should number message.part.updated events per session without gaps", async () => {
+      const session = await client.session.create();
+      const sessionId = session.data?.id!;
+      const parts: any[] = [];
+
+      const eventStream = await client.event.subscribe();
+      const collectEvents = new Promise<void>((resolve) => {
+        const timeout = setTimeout(resolve, 10000);
+        (async () => {
+          try {
+            for await (const event of (eventStream as any).stream) {
+              if (event.type === "message.part.updated" && event.properties?.sessionID === sessionId) {
+                parts.push(event.properties);
+              }
+              if (event.type === "session.idle" && event.properties?.sessionID === sessionId) {
+                clearTimeout(timeout);
+                resolve();
+                break;
+              }
+            }
+          } catch {
+            // Stream ended
+          }
+        })();
+      });
+
+      await client.session.prompt({
+        path: { id: sessionId },
+        body: {
+          model: { providerID: "mock", modelID: "mock" },
+          parts: [{ type: "text", text: "Say hello" }],
+        },
+      });
+      await collectEvents;
+
+      expect(parts.length).toBeGreaterThan(0);
+      const seqs = parts.map((properties) => properties.seq);
+      for (let i = 1; i < seqs.length; i++) {
+        expect(seqs[i]).toBe(seqs[i - 1] + 1);
+      }
+      expect(parts.some((properties) => properties.gap)).toBe(false);
+    });
+  });
+
+  describe("global.health", () => {
+    it("should return a structured health document", async () => {
+      const response = await fetch(`${handle.baseUrl}/opencode/global/health`, {
+        headers: { Authorization: `Bearer ${handle.token}` },
+      });
+      expect(response.ok).toBe(true);
+      const health = await response.json();
+
+      expect(health.healthy).toBe(true);
+      expect(["healthy", "degraded"]).toContain(health.status);
+      expect(health.checks.sqlite.status).toBe("ok");
+      expect(typeof health.checks.sqlite.latencyMs).toBe("number");
+      expect(typeof health.checks.sse.subscribers).toBe("number");
+      expect(health.checks.pending).toMatchObject({ permissions: 0, questions: 0 });
+      expect(Array.isArray(health.checks.agents.processes)).toBe(true);
+    });
+  });
+
   describe("global.event", () => {
     it("should connect to global SSE endpoint", async () => {
       const response = await client.global.event();
@@ -215,6 +309,59 @@ describe("OpenCode-compatible Event Streaming", () => {
       expect(finalStatus.data?.[sessionId]?.type).toBe("idle");
     });
 
+    it("should report lifecycle state and emit session.lifecycle transitions", async () => {
+      const sessionId = uniqueSessionId("status-lifecycle");
+      await initSessionViaHttp(sessionId, { providerID: "mock", modelID: "mock" });
+
+      const initial = await client.session.status();
+      expect((initial.data as any)?.[sessionId]?.state).toBe("created");
+
+      const eventStream = await client.event.subscribe();
+      const transitions: Array<{ from: string; to: string; reason: string }> = [];
+
+      const collectIdle = new Promise<void>((resolve, reject) => {
+        const timeout = setTimeout(
+          () => reject(new Error("Timed out waiting for session.idle")),
+          15_000
+        );
+        (async () => {
+          try {
+            for await (const event of (eventStream as any).stream) {
+              if (event?.properties?.sessionID !== sessionId) continue;
+              if (event.type === "session.lifecycle") {
+                transitions.push(event.properties);
+              }
+              if (event.type === "session.idle") {
+                clearTimeout(timeout);
+                resolve();
+                break;
+              }
+            }
+          } catch {
+            // Stream ended
+          }
+        })();
+      });
+
+      await client.session.prompt({
+        path: { id: sessionId },
+        body: {
+          model: { providerID: "mock", modelID: "mock" },
+          parts: [{ type: "text", text: "Say hello" }],
+        },
+      });
+
+      await collectIdle;
+
+      expect(transitions.map((t) => `${t.from}->${t.to}`)).toEqual([
+        "created->prompting",
+        "prompting->idle",
+      ]);
+      expect(transitions[1].reason).toBe("turn_completed");
+      const finalStatus = await client.session.status();
+      expect((finalStatus.data as any)?.[sessionId]?.state).toBe("idle");
+    });
+
     it("should report busy via /session/status while turn is in flight", async () => {
       const sessionId = uniqueSessionId("status-busy-inflight");
       await initSessionViaHttp(sessionId, { providerID: "mock", modelID: "mock" });
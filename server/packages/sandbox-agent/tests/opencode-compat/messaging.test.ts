@@ -8,6 +8,9 @@
  * - POST /session/{id}/message - Send a prompt to the session
  * - GET /session/{id}/message - List messages in a session
  * - GET /session/{id}/message/{messageID} - Get a specific message
+ * - POST /session/{id}/context - Attach context to the next prompt
+ * - GET /session/{id}/logs - Server and agent logs for the session
+ * - GET /compare - Turn-by-turn comparison of two sessions
  */
 
 import { describe, it, expect, beforeAll, beforeEach, afterEach } from "vitest";
@@ -65,6 +68,37 @@ describe("OpenCode-compatible Messaging API", () => {
 
       expect(response.error).toBeUndefined();
     });
+
+    it("should return a turn token that re-attaches to the turn", async () => {
+      const response = await fetch(`${handle.baseUrl}/opencode/session/${sessionId}/message`, {
+        method: "POST",
+        headers: {
+          Authorization: `Bearer ${handle.token}`,
+          "Content-Type": "application/json",
+        },
+        body: JSON.stringify({
+          model: { providerID: "mock", modelID: "mock" },
+          parts: [{ type: "text", text: "Say hello" }],
+        }),
+      });
+      expect(response.status).toBe(200);
+      const token = response.headers.get("x-sandbox-agent-turn-token");
+      expect(token).toMatch(/^[0-9a-f]{32}$/);
+      const result = await response.json();
+
+      const attached = await fetch(
+        `${handle.baseUrl}/opencode/session/${sessionId}/turn/by-token/${token}`,
+        { headers: { Authorization: `Bearer ${handle.token}` } },
+      );
+      expect(attached.status).toBe(200);
+      expect(await attached.json()).toEqual(result);
+
+      const unknown = await fetch(
+        `${handle.baseUrl}/opencode/session/${sessionId}/turn/by-token/not-a-token`,
+        { headers: { Authorization: `Bearer ${handle.token}` } },
+      );
+      expect(unknown.status).toBe(404);
+    });
   });
 
   describe("session.promptAsync", () => {
@@ -80,6 +114,46 @@ describe("OpenCode-compatible Messaging API", () => {
       // Should return quickly without waiting for completion
       expect(response.error).toBeUndefined();
     });
+
+    it("should expose the turn status and result", async () => {
+      const started = await fetch(`${handle.baseUrl}/opencode/session/${sessionId}/prompt_async`, {
+        method: "POST",
+        headers: {
+          Authorization: `Bearer ${handle.token}`,
+          "Content-Type": "application/json",
+        },
+        body: JSON.stringify({
+          model: { providerID: "mock", modelID: "mock" },
+          parts: [{ type: "text", text: "Process this asynchronously" }],
+        }),
+      });
+      expect(started.status).toBe(202);
+      const turn = await started.json();
+      expect(turn.id).toMatch(/^turn_/);
+      expect(turn.sessionID).toBe(sessionId);
+      expect(turn.status).toBe("running");
+
+      let finished: any;
+      for (let i = 0; i < 50 && finished?.status !== "completed"; i++) {
+        await new Promise((r) => setTimeout(r, 100));
+        const response = await fetch(
+          `${handle.baseUrl}/opencode/session/${sessionId}/turn/${turn.id}`,
+          { headers: { Authorization: `Bearer ${handle.token}` } },
+        );
+        expect(response.status).toBe(200);
+        finished = await response.json();
+      }
+      expect(finished.status).toBe("completed");
+      expect(finished.result?.info?.parentID).toBe(turn.messageID);
+      expect(finished.time.completed).toBeDefined();
+    });
+
+    it("should report unknown turns as not found", async () => {
+      const response = await fetch(`${handle.baseUrl}/opencode/session/${sessionId}/turn/turn_missing`, {
+        headers: { Authorization: `Bearer ${handle.token}` },
+      });
+      expect(response.status).toBe(404);
+    });
   });
 
   describe("session.messages", () => {
@@ -110,6 +184,81 @@ describe("OpenCode-compatible Messaging API", () => {
     });
   });
 
+  describe("session logs", () => {
+    it("should tag prompt logs with the turn's correlation ID", async () => {
+      await client.session.prompt({
+        path: { id: sessionId },
+        body: {
+          model: { providerID: "mock", modelID: "mock" },
+          parts: [{ type: "text", text: "Log this" }],
+        },
+      });
+      const messages = await client.session.messages({ path: { id: sessionId } });
+      const userMessage = messages.data?.find((message) => message.info.role === "user");
+      const correlationId = `${sessionId}/${userMessage?.info.id}`;
+
+      const response = await fetch(
+        `${handle.baseUrl}/opencode/session/${sessionId}/logs?correlationID=${encodeURIComponent(correlationId)}`,
+        { headers: { Authorization: `Bearer ${handle.token}` } },
+      );
+      expect(response.status).toBe(200);
+      const entries = await response.json();
+      expect(entries.length).toBeGreaterThan(0);
+      for (const entry of entries) {
+        expect(entry.correlationID).toBe(correlationId);
+        expect(entry.source).toBe("sandbox-agent");
+      }
+    });
+
+    it("should report logs for unknown sessions as not found", async () => {
+      const response = await fetch(`${handle.baseUrl}/opencode/session/ses_missing/logs`, {
+        headers: { Authorization: `Bearer ${handle.token}` },
+      });
+      expect(response.status).toBe(404);
+    });
+  });
+
+  describe("compare", () => {
+    it("should align turns and diff the final answers", async () => {
+      const other = await client.session.create();
+      const otherId = other.data?.id!;
+      for (const [id, text] of [
+        [sessionId, "first answer"],
+        [otherId, "second answer"],
+      ]) {
+        await client.session.prompt({
+          path: { id },
+          body: {
+            model: { providerID: "mock", modelID: "mock" },
+            parts: [{ type: "text", text }],
+          },
+        });
+      }
+
+      const response = await fetch(
+        `${handle.baseUrl}/opencode/compare?sessionA=${sessionId}&sessionB=${otherId}`,
+        { headers: { Authorization: `Bearer ${handle.token}` } },
+      );
+      expect(response.status).toBe(200);
+      const comparison = await response.json();
+      expect(comparison.sessionA.messages.user).toBe(1);
+      expect(comparison.sessionB.turns).toBe(1);
+      expect(comparison.turns).toHaveLength(1);
+      const [turn] = comparison.turns;
+      expect(turn.a.items[0].role).toBe("assistant");
+      expect(turn.samePrompt).toBe(false);
+      expect(turn.finalTextDiff.map((line: any) => line.op)).toContain("insert");
+    });
+
+    it("should report an unknown session as not found", async () => {
+      const response = await fetch(
+        `${handle.baseUrl}/opencode/compare?sessionA=${sessionId}&sessionB=ses_missing`,
+        { headers: { Authorization: `Bearer ${handle.token}` } },
+      );
+      expect(response.status).toBe(404);
+    });
+  });
+
   describe("session.message (get specific)", () => {
     it("should retrieve a specific message by ID", async () => {
       // Send a prompt first
@@ -167,4 +316,44 @@ describe("OpenCode-compatible Messaging API", () => {
       expect(response.error).toBeUndefined();
     });
   });
+
+  describe("session context", () => {
+    it("should inject attached context into the next prompt", async () => {
+      const added = await fetch(`${handle.baseUrl}/opencode/session/${sessionId}/context`, {
+        method: "POST",
+        headers: {
+          Authorization: `Bearer ${handle.token}`,
+          "Content-Type": "application/json",
+        },
+        body: JSON.stringify({ label: "error log", text: "TypeError: x is undefined" }),
+      });
+      expect(added.ok).toBe(true);
+      const item = await added.json();
+      expect(item.id).toBeDefined();
+
+      const pending = await fetch(`${handle.baseUrl}/opencode/session/${sessionId}/context`, {
+        headers: { Authorization: `Bearer ${handle.token}` },
+      });
+      expect(await pending.json()).toHaveLength(1);
+
+      await client.session.prompt({
+        path: { id: sessionId },
+        body: {
+          model: { providerID: "mock", modelID: "mock" },
+          parts: [{ type: "text", text: "Fix it" }],
+        },
+      });
+
+      const messages = await client.session.messages({ path: { id: sessionId } });
+      const user = messages.data?.find((message) => message.info.role === "user");
+      const first = user?.parts[0] as any;
+      expect(first?.synthetic).toBe(true);
+      expect(first?.text).toContain("TypeError: x is undefined");
+
+      const drained = await fetch(`${handle.baseUrl}/opencode/session/${sessionId}/context`, {
+        headers: { Authorization: `Bearer ${handle.token}` },
+      });
+      expect(await drained.json()).toHaveLength(0);
+    });
+  });
 });
@@ -103,6 +103,21 @@ describe("OpenCode-compatible Permission API", () => {
       expect(response.error).toBeUndefined();
     });
 
+    it("should describe the originating tool call", async () => {
+      await client.session.prompt({
+        sessionID: sessionId,
+        model: { providerID: "mock", modelID: "mock" },
+        parts: [{ type: "text", text: permissionPrompt }],
+      });
+
+      const asked = await waitForPermissionRequest();
+      const metadata = asked?.metadata as Record<string, any>;
+      expect(metadata?.tool).toBe("bash");
+      expect(metadata?.toolKind).toBe("execute");
+      expect(metadata?.input).toBe("echo permission");
+      expect(metadata?.rawInput).toEqual({ command: "echo permission" });
+    });
+
     it("should emit permission.replied with always when reply is always", async () => {
       const eventStream = await client.event.subscribe();
       const repliedEventPromise = new Promise<any>((resolve, reject) => {
@@ -10,12 +10,13 @@
  * - GET /session/{id} - Get session details
  * - PATCH /session/{id} - Update session properties
  * - DELETE /session/{id} - Delete a session
+ * - POST /session/{id}/rpc - Allowlisted agent RPC passthrough
  */
 
 import { describe, it, expect, beforeAll, afterAll, beforeEach, afterEach } from "vitest";
 import { createOpencodeClient, type OpencodeClient } from "@opencode-ai/sdk";
 import { spawnSandboxAgent, buildSandboxAgent, type SandboxAgentHandle } from "./helpers/spawn";
-import { mkdtempSync, rmSync } from "node:fs";
+import { mkdtempSync, rmSync, writeFileSync } from "node:fs";
 import { join } from "node:path";
 import { tmpdir } from "node:os";
 
@@ -186,6 +187,184 @@ describe("OpenCode-compatible Session API", () => {
       const permissionMode = await getBackingSessionPermissionMode(sessionId);
       expect(permissionMode).toBe("bypass");
     });
+
+    it("should keep the requested directory", async () => {
+      const directory = mkdtempSync(join(tmpdir(), "opencode-session-dir-"));
+      try {
+        const response = await fetch(
+          `${handle.baseUrl}/opencode/session?directory=${encodeURIComponent(directory)}`,
+          {
+            method: "POST",
+            headers: {
+              Authorization: `Bearer ${handle.token}`,
+              "Content-Type": "application/json",
+            },
+            body: "{}",
+          },
+        );
+        expect(response.ok).toBe(true);
+        const session = await response.json();
+        expect(session.directory).toBe(directory);
+      } finally {
+        rmSync(directory, { recursive: true, force: true });
+      }
+    });
+
+    it("should report the context files applied on the first prompt", async () => {
+      const directory = mkdtempSync(join(tmpdir(), "opencode-context-files-"));
+      writeFileSync(join(directory, "AGENTS.md"), "Always use tabs.\n");
+      try {
+        const response = await fetch(
+          `${handle.baseUrl}/opencode/session?directory=${encodeURIComponent(directory)}`,
+          {
+            method: "POST",
+            headers: {
+              Authorization: `Bearer ${handle.token}`,
+              "Content-Type": "application/json",
+            },
+            body: "{}",
+          },
+        );
+        const sessionId = (await response.json()).id as string;
+
+        const prompt = await client.session.prompt({
+          path: { id: sessionId },
+          body: {
+            model: { providerID: "mock", modelID: "mock" },
+            parts: [{ type: "text", text: "hello" }],
+          },
+        });
+        expect(prompt.error).toBeUndefined();
+
+        const session = await client.session.get({ path: { id: sessionId } });
+        expect((session.data as any)?.contextFiles).toEqual([
+          {
+            name: "AGENTS.md",
+            path: join(directory, "AGENTS.md"),
+            injection: "preamble",
+            bytes: 17,
+          },
+        ]);
+      } finally {
+        rmSync(directory, { recursive: true, force: true });
+      }
+    });
+
+    it("should seed the session from initialHistory", async () => {
+      const session = await createSessionViaHttp({
+        initialHistory: [
+          { role: "user", content: [{ type: "text", text: "list the files" }] },
+          {
+            role: "assistant",
+            content: [
+              { type: "tool_call", name: "bash", arguments: '{"command":"ls"}', call_id: "call_1" },
+              { type: "tool_result", call_id: "call_1", output: "README.md" },
+              { type: "text", text: "There is one file." },
+            ],
+          },
+        ],
+      });
+      const sessionId = session.id as string;
+
+      const response = await fetch(`${handle.baseUrl}/opencode/session/${sessionId}/message`, {
+        headers: { Authorization: `Bearer ${handle.token}` },
+      });
+      expect(response.ok).toBe(true);
+      const messages = await response.json();
+      expect(messages.map((message: any) => message.info.role)).toEqual(["user", "assistant"]);
+      const tool = messages[1].parts.find((part: any) => part.type === "tool");
+      expect(tool?.state?.input).toEqual({ command: "ls" });
+      expect(tool?.state?.output).toBe("README.md");
+
+      // Imported history does not pin the model; the first prompt may pick one.
+      const prompt = await client.session.prompt({
+        path: { id: sessionId },
+        body: {
+          model: { providerID: "mock", modelID: "mock" },
+          parts: [{ type: "text", text: "hello" }],
+        },
+      });
+      expect(prompt.error).toBeUndefined();
+    });
+
+    it("should mask secret env values on the session", async () => {
+      const session = await createSessionViaHttp({
+        env: {
+          API_BASE: "https://api.example.com",
+          GITHUB_TOKEN: "ghp_example",
+          FEATURE_FLAGS: { value: "beta", secret: true },
+        },
+      });
+      expect(session.env).toEqual({
+        API_BASE: "https://api.example.com",
+        GITHUB_TOKEN: "********",
+        FEATURE_FLAGS: "********",
+      });
+    });
+
+    it("should reject env names with a reserved prefix", async () => {
+      const response = await fetch(`${handle.baseUrl}/opencode/session`, {
+        method: "POST",
+        headers: {
+          Authorization: `Bearer ${handle.token}`,
+          "Content-Type": "application/json",
+        },
+        body: JSON.stringify({ env: { LD_PRELOAD: "/tmp/hook.so" } }),
+      });
+      expect(response.status).toBe(400);
+    });
+
+    it("should import retry parts from initialHistory", async () => {
+      const session = await createSessionViaHttp({
+        initialHistory: [
+          { role: "user", content: [{ type: "text", text: "summarize" }] },
+          {
+            role: "assistant",
+            content: [
+              { type: "retry", attempt: 1, error: "rate limited" },
+              { type: "text", text: "Summary." },
+            ],
+          },
+        ],
+      });
+
+      const response = await fetch(`${handle.baseUrl}/opencode/session/${session.id}/message`, {
+        headers: { Authorization: `Bearer ${handle.token}` },
+      });
+      const messages = await response.json();
+      const retry = messages[1].parts.find((part: any) => part.type === "retry");
+      expect(retry?.attempt).toBe(1);
+      expect(retry?.error?.data?.message).toBe("rate limited");
+    });
+
+    it("should reject initialHistory with an unknown role", async () => {
+      const response = await fetch(`${handle.baseUrl}/opencode/session`, {
+        method: "POST",
+        headers: {
+          Authorization: `Bearer ${handle.token}`,
+          "Content-Type": "application/json",
+        },
+        body: JSON.stringify({ initialHistory: [{ role: "narrator", content: [] }] }),
+      });
+      expect(response.status).toBe(400);
+    });
+
+    it("should reject a directory that does not exist", async () => {
+      const response = await fetch(
+        `${handle.baseUrl}/opencode/session?directory=${encodeURIComponent("/nonexistent/opencode-session-dir")}`,
+        {
+          method: "POST",
+          headers: {
+            Authorization: `Bearer ${handle.token}`,
+            "Content-Type": "application/json",
+          },
+          body: "{}",
+        },
+      );
+      expect(response.status).toBe(400);
+      const body = await response.json();
+      expect(body.errors?.[0]?.message).toContain("does not exist");
+    });
   });
 
   describe("session.list", () => {
@@ -538,4 +717,73 @@ describe("OpenCode-compatible Session API", () => {
       expect(response.data?.title).toBe("Keep");
     });
   });
+
+  describe("session.share", () => {
+    it("should share a session with a local link", async () => {
+      const created = await client.session.create();
+      const sessionId = created.data?.id!;
+
+      const shared = await client.session.share({ path: { id: sessionId } });
+      const url = shared.data?.share?.url;
+      expect(url).toMatch(/\/opencode\/share\/[0-9a-f]{32}$/);
+
+      const again = await client.session.share({ path: { id: sessionId } });
+      expect(again.data?.share?.url).toBe(url);
+
+      const fetched = await client.session.get({ path: { id: sessionId } });
+      expect(fetched.data?.share?.url).toBe(url);
+
+      const view = await fetch(url!, { headers: { Authorization: `Bearer ${handle.token}` } });
+      expect(view.status).toBe(200);
+      const body = await view.json();
+      expect(body.info.id).toBe(sessionId);
+      expect(Array.isArray(body.messages)).toBe(true);
+    });
+
+    it("should unshare a session", async () => {
+      const created = await client.session.create();
+      const sessionId = created.data?.id!;
+      const shared = await client.session.share({ path: { id: sessionId } });
+      const url = shared.data?.share?.url!;
+
+      const unshared = await client.session.unshare({ path: { id: sessionId } });
+      expect(unshared.data?.share).toBeUndefined();
+
+      const view = await fetch(url, { headers: { Authorization: `Bearer ${handle.token}` } });
+      expect(view.status).toBe(404);
+    });
+
+    it("should return error for non-existent session", async () => {
+      const response = await client.session.share({ path: { id: "ses_missing" } });
+      expect(response.error).toBeDefined();
+    });
+  });
+
+  describe("session.rpc", () => {
+    async function postRpc(sessionId: string, body: Record<string, unknown>) {
+      return fetch(`${handle.baseUrl}/opencode/session/${sessionId}/rpc`, {
+        method: "POST",
+        headers: {
+          Authorization: `Bearer ${handle.token}`,
+          "Content-Type": "application/json",
+        },
+        body: JSON.stringify(body),
+      });
+    }
+
+    it("should require a method", async () => {
+      const created = await client.session.create();
+      const response = await postRpc(created.data?.id!, { params: {} });
+      expect(response.status).toBe(400);
+    });
+
+    it("should reject methods outside the allowlist", async () => {
+      const created = await client.session.create();
+      const response = await postRpc(created.data?.id!, {
+        method: "_claude/slash_command",
+        params: { command: "/compact" },
+      });
+      expect(response.status).toBe(403);
+    });
+  });
 });
@@ -0,0 +1,162 @@
+/**
+ * Tests for permission/question webhook delivery.
+ *
+ * Webhook targets are configured through SANDBOX_AGENT_WEBHOOK_URL and
+ * SANDBOX_AGENT_WEBHOOK_SECRET. Every delivery is signed with
+ * `sha256=HMAC(secret, "{timestamp}.{body}")`, and a target may answer the
+ * request inline in its response body.
+ */
+
+import { describe, it, expect, beforeAll, beforeEach, afterEach } from "vitest";
+import { createHmac } from "node:crypto";
+import { createServer, type Server } from "node:http";
+import type { AddressInfo } from "node:net";
+import { createOpencodeClient, type OpencodeClient } from "@opencode-ai/sdk/v1";
+import { spawnSandboxAgent, buildSandboxAgent, type SandboxAgentHandle } from "./helpers/spawn";
+
+const SECRET = "webhook-test-secret";
+
+interface Delivery {
+  headers: Record<string, string | string[] | undefined>;
+  raw: string;
+  body: any;
+}
+
+describe("OpenCode-compatible webhooks", () => {
+  let handle: SandboxAgentHandle;
+  let client: OpencodeClient;
+  let receiver: Server;
+  let deliveries: Delivery[];
+  let respond: (delivery: Delivery, attempt: number) => { status: number; body?: unknown };
+
+  beforeAll(async () => {
+    await buildSandboxAgent();
+  });
+
+  beforeEach(async () => {
+    deliveries = [];
+    respond = () => ({ status: 204 });
+    receiver = createServer((req, res) => {
+      let raw = "";
+      req.on("data", (chunk) => (raw += chunk));
+      req.on("end", () => {
+        const delivery = { headers: req.headers, raw, body: JSON.parse(raw) };
+        deliveries.push(delivery);
+        const { status, body } = respond(delivery, deliveries.length);
+        res.writeHead(status, { "content-type": "application/json" });
+        res.end(body === undefined ? undefined : JSON.stringify(body));
+      });
+    });
+    await new Promise<void>((resolve) => receiver.listen(0, "127.0.0.1", resolve));
+    const { port } = receiver.address() as AddressInfo;
+
+    handle = await spawnSandboxAgent({
+      opencodeCompat: true,
+      env: {
+        SANDBOX_AGENT_WEBHOOK_URL: `http://127.0.0.1:${port}/hook`,
+        SANDBOX_AGENT_WEBHOOK_SECRET: SECRET,
+      },
+    });
+    client = createOpencodeClient({
+      baseUrl: `${handle.baseUrl}/opencode`,
+      headers: { Authorization: `Bearer ${handle.token}` },
+    });
+  });
+
+  afterEach(async () => {
+    await handle?.dispose();
+    await new Promise((resolve) => receiver?.close(resolve));
+  });
+
+  async function waitForValue<T>(
+    getValue: () => T | undefined | Promise<T | undefined>,
+    timeoutMs = 10_000,
+    intervalMs = 100,
+  ): Promise<T> {
+    const start = Date.now();
+    while (Date.now() - start < timeoutMs) {
+      const value = await getValue();
+      if (value !== undefined) {
+        return value;
+      }
+      await new Promise((r) => setTimeout(r, intervalMs));
+    }
+    throw new Error("Timed out waiting for value");
+  }
+
+  async function prompt(text: string) {
+    const session = await client.session.create();
+    const sessionId = session.data?.id!;
+    await client.session.prompt({
+      sessionID: sessionId,
+      model: { providerID: "mock", modelID: "mock" },
+      parts: [{ type: "text", text }],
+    });
+    return sessionId;
+  }
+
+  it("should deliver a signed permission.asked payload", async () => {
+    const sessionId = await prompt("permission");
+
+    const delivery = await waitForValue(() =>
+      deliveries.find((d) => d.body.type === "permission.asked"),
+    );
+    expect(delivery.headers["x-sandbox-agent-event"]).toBe("permission.asked");
+    expect(delivery.body.properties.sessionID).toBe(sessionId);
+    expect(delivery.body.replyPath).toBe(`/permission/${delivery.body.properties.id}/reply`);
+
+    const timestamp = delivery.headers["x-sandbox-agent-timestamp"] as string;
+    const expected = createHmac("sha256", SECRET).update(`${timestamp}.${delivery.raw}`).digest("hex");
+    expect(delivery.headers["x-sandbox-agent-signature"]).toBe(`sha256=${expected}`);
+
+    // The request stays pending until someone answers it.
+    const pending = await client.permission.list();
+    expect(pending.data?.some((p) => p.id === delivery.body.properties.id)).toBe(true);
+    const reply = await client.permission.reply({
+      requestID: delivery.body.properties.id,
+      reply: "once",
+    });
+    expect(reply.error).toBeUndefined();
+  });
+
+  it("should apply a permission reply returned by the webhook", async () => {
+    respond = (delivery) =>
+      delivery.body.type === "permission.asked"
+        ? { status: 200, body: { reply: "once" } }
+        : { status: 204 };
+
+    await prompt("permission");
+
+    await waitForValue(() => deliveries.find((d) => d.body.type === "permission.asked"));
+    await waitForValue(async () => {
+      const pending = await client.permission.list();
+      return pending.data?.length === 0 ? true : undefined;
+    });
+  });
+
+  it("should apply question answers returned by the webhook", async () => {
+    respond = (delivery) =>
+      delivery.body.type === "question.asked"
+        ? { status: 200, body: { answers: [["Yes"]] } }
+        : { status: 204 };
+
+    await prompt("question");
+
+    await waitForValue(() => deliveries.find((d) => d.body.type === "question.asked"));
+    await waitForValue(async () => {
+      const pending = await client.question.list();
+      return pending.data?.length === 0 ? true : undefined;
+    });
+  });
+
+  it("should retry deliveries that fail with a server error", async () => {
+    respond = (_delivery, attempt) => (attempt === 1 ? { status: 503 } : { status: 204 });
+
+    await prompt("permission");
+
+    const retried = await waitForValue(() => (deliveries.length >= 2 ? deliveries : undefined));
+    expect(retried[0].headers["x-sandbox-agent-delivery"]).toBe(
+      retried[1].headers["x-sandbox-agent-delivery"],
+    );
+  });
+});
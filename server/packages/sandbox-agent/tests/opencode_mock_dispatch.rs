@@ -0,0 +1,5216 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::extract::Path;
+use axum::http::{Method, Request, StatusCode};
+use axum::Router;
+use http_body_util::BodyExt;
+use sandbox_agent_opencode_adapter::{
+    build_opencode_router, Authorizer, AuthorizerDecision, MockAcpDispatch, ModelCatalogConfig,
+    OpenCodeAdapterConfig, PermissionContext, SequentialIds, SessionArchiveConfig,
+    SessionExpiryConfig, SessionPrewarmConfig, SessionPriority, SessionQuotaConfig,
+    TranscriptFormat, TranscriptTranslator,
+};
+use serde_json::{json, Value};
+use tower::util::ServiceExt;
+
+fn adapter(dispatch: &MockAcpDispatch, sqlite_path: &str) -> Router {
+    adapter_with(dispatch, sqlite_path, OpenCodeAdapterConfig::default())
+}
+
+fn adapter_with(
+    dispatch: &MockAcpDispatch,
+    sqlite_path: &str,
+    config: OpenCodeAdapterConfig,
+) -> Router {
+    build_opencode_router(OpenCodeAdapterConfig {
+        sqlite_path: Some(sqlite_path.to_string()),
+        acp_dispatch: Some(Arc::new(dispatch.clone())),
+        context_files: false,
+        ..config
+    })
+    .expect("build adapter")
+}
+
+/// Create a session and run one prompt, which bootstraps the agent. Returns
+/// the session ID and the agent's server ID.
+async fn bootstrapped_session(app: &Router, dispatch: &MockAcpDispatch) -> (String, String) {
+    let (status, session) = send(app, Method::POST, "/session", Some(json!({}))).await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = session["id"].as_str().expect("session id").to_string();
+    let (status, _) = send(
+        app,
+        Method::POST,
+        &format!("/session/{session_id}/message"),
+        Some(json!({"agent": "claude", "parts": [{"type": "text", "text": "hello"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let server_id = dispatch.posted()[0].server_id.clone();
+    (session_id, server_id)
+}
+
+async fn send(app: &Router, method: Method, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+        .expect("build request");
+    let response = app.clone().oneshot(request).await.expect("response");
+    let status = response.status();
+    let bytes = response
+        .into_body()
+        .collect()
+        .await
+        .expect("collect body")
+        .to_bytes();
+    (
+        status,
+        serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+    )
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("runtime")
+}
+
+#[test]
+fn translation_resumes_for_live_agents_after_adapter_restart() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let sqlite_path = sqlite_path.to_str().expect("utf-8 path");
+    let dispatch = MockAcpDispatch::new();
+
+    // The first adapter bootstraps the agent and finishes a turn. Dropping its
+    // runtime stops its translation task while the agent instance lives on.
+    let (session_id, server_id) = runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        bootstrapped_session(&app, &dispatch).await
+    });
+    assert_eq!(
+        dispatch.posted()[0].bootstrap_agent.as_deref(),
+        Some("claude")
+    );
+    assert_eq!(
+        dispatch.posted_methods(&server_id),
+        vec!["initialize", "session/new", "session/prompt"]
+    );
+
+    runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        // Any request starts the background jobs, including the resume.
+        let (status, _) = send(&app, Method::GET, "/session", None).await;
+        assert_eq!(status, StatusCode::OK);
+        dispatch.session_update(
+            &server_id,
+            &format!("{server_id}-session"),
+            json!({
+                "sessionUpdate": "agent_message_chunk",
+                "content": {"type": "text", "text": "still streaming"}
+            }),
+        );
+        dispatch.notify(
+            &server_id,
+            json!({"jsonrpc": "2.0", "id": "prompt_2", "result": {"stopReason": "end_turn"}}),
+        );
+
+        let mut texts = Vec::new();
+        for _ in 0..100 {
+            let (status, messages) = send(
+                &app,
+                Method::GET,
+                &format!("/session/{session_id}/message"),
+                None,
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+            texts = messages
+                .as_array()
+                .into_iter()
+                .flatten()
+                .flat_map(|message| message["parts"].as_array().cloned().unwrap_or_default())
+                .filter_map(|part| part["text"].as_str().map(str::to_string))
+                .collect::<Vec<_>>();
+            if texts.iter().any(|text| text == "still streaming") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(
+            texts.iter().any(|text| text == "still streaming"),
+            "{texts:?}"
+        );
+        // The resumed adapter did not bootstrap the agent again.
+        assert_eq!(dispatch.posted_methods(&server_id).len(), 3);
+    });
+}
+
+#[test]
+fn turn_links_survive_adapter_restarts() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let sqlite_path = sqlite_path.to_str().expect("utf-8 path");
+    let dispatch = MockAcpDispatch::new();
+
+    let (session_id, server_id, user_message_id) = runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+        let (_, messages) = send(
+            &app,
+            Method::GET,
+            &format!("/session/{session_id}/message"),
+            None,
+        )
+        .await;
+        let user_message_id = messages
+            .as_array()
+            .into_iter()
+            .flatten()
+            .rfind(|message| message["info"]["role"] == "user")
+            .and_then(|message| message["info"]["id"].as_str())
+            .expect("user message")
+            .to_string();
+        (session_id, server_id, user_message_id)
+    });
+
+    // The linkage is on disk, and the migration that created its table
+    // leaves it alone when it runs again.
+    runtime().block_on(async {
+        let pool = sqlx::SqlitePool::connect(&format!("sqlite://{sqlite_path}"))
+            .await
+            .expect("open database");
+        let turns = || async {
+            sqlx::query_as::<_, (String, i64, String)>(
+                "SELECT session_id, ordinal, user_message_id FROM session_turns",
+            )
+            .fetch_all(&pool)
+            .await
+            .expect("read turns")
+        };
+        let recorded = turns().await;
+        assert_eq!(
+            recorded,
+            vec![(session_id.clone(), 1, user_message_id.clone())]
+        );
+        sqlx::query(include_str!(
+            "../../opencode-adapter/migrations/0003_session_turns.sql"
+        ))
+        .execute(&pool)
+        .await
+        .expect("rerun migration");
+        assert_eq!(turns().await, recorded);
+        pool.close().await;
+    });
+
+    // The rebuilt adapter threads the agent's next message under the last
+    // user message.
+    runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        let (status, _) = send(&app, Method::GET, "/session", None).await;
+        assert_eq!(status, StatusCode::OK);
+        dispatch.session_update(
+            &server_id,
+            &format!("{server_id}-session"),
+            json!({
+                "sessionUpdate": "agent_message_chunk",
+                "content": {"type": "text", "text": "after restart"}
+            }),
+        );
+        let mut parent = Value::Null;
+        for _ in 0..100 {
+            let (_, messages) = send(
+                &app,
+                Method::GET,
+                &format!("/session/{session_id}/message"),
+                None,
+            )
+            .await;
+            let streamed = messages.as_array().into_iter().flatten().find(|message| {
+                message["parts"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .any(|part| part["text"] == "after restart")
+            });
+            if let Some(streamed) = streamed {
+                parent = streamed["info"]["parentID"].clone();
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(parent, json!(user_message_id));
+    });
+}
+
+#[test]
+fn envelopes_delivered_twice_are_applied_once_across_restarts() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let sqlite_path = sqlite_path.to_str().expect("utf-8 path");
+    let dispatch = MockAcpDispatch::new();
+    let ask = |dispatch: &MockAcpDispatch, server_id: &str| {
+        dispatch.notify(
+            server_id,
+            json!({
+                "jsonrpc": "2.0",
+                "id": "question_1",
+                "method": "_sandboxagent/session/request_question",
+                "params": {
+                    "sessionId": format!("{server_id}-session"),
+                    "questions": [{"question": "Which branch?", "options": []}]
+                }
+            }),
+        );
+    };
+    // The questions the session lists, and the `question_asked` envelopes
+    // in its event log, once both have settled.
+    let asked = |app: Router| async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let (_, questions) = send(&app, Method::GET, "/question", None).await;
+        let request = Request::builder()
+            .uri("/admin/export/events?since=0")
+            .body(Body::empty())
+            .expect("build request");
+        let response = app.oneshot(request).await.expect("response");
+        let bytes = response
+            .into_body()
+            .collect()
+            .await
+            .expect("collect body")
+            .to_bytes();
+        let export = String::from_utf8(zstd::decode_all(bytes.as_ref()).expect("zstd frame"))
+            .expect("utf-8");
+        (
+            questions.as_array().map_or(0, Vec::len),
+            export
+                .matches("_sandboxagent/opencode/question_asked")
+                .count(),
+        )
+    };
+
+    let server_id = runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        let (_, server_id) = bootstrapped_session(&app, &dispatch).await;
+        ask(&dispatch, &server_id);
+        ask(&dispatch, &server_id);
+        assert_eq!(asked(app).await, (1, 1));
+        server_id
+    });
+
+    // Rerunning the dedupe migration keeps the keys that were recorded.
+    runtime().block_on(async {
+        let pool = sqlx::SqlitePool::connect(&format!("sqlite://{sqlite_path}"))
+            .await
+            .expect("open database");
+        let keys = || async {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM event_dedupe")
+                .fetch_one(&pool)
+                .await
+                .expect("count keys")
+        };
+        let recorded = keys().await;
+        assert!(recorded > 0);
+        sqlx::query(include_str!(
+            "../../opencode-adapter/migrations/0004_event_dedupe.sql"
+        ))
+        .execute(&pool)
+        .await
+        .expect("rerun migration");
+        assert_eq!(keys().await, recorded);
+        pool.close().await;
+    });
+
+    // The agent replays the request to the restarted adapter.
+    runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        let (status, _) = send(&app, Method::GET, "/session", None).await;
+        assert_eq!(status, StatusCode::OK);
+        ask(&dispatch, &server_id);
+        assert_eq!(asked(app).await, (1, 1));
+    });
+}
+
+#[test]
+fn part_ids_stay_stable_across_tool_updates_and_adapter_restarts() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let sqlite_path = sqlite_path.to_str().expect("utf-8 path");
+    let dispatch = MockAcpDispatch::new();
+    let assistant_parts = |messages: &Value| {
+        messages
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|message| message["info"]["role"] == "assistant")
+            .flat_map(|message| message["parts"].as_array().cloned().unwrap_or_default())
+            .collect::<Vec<_>>()
+    };
+
+    let (session_id, server_id) = runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+        let acp_session_id = format!("{server_id}-session");
+        for update in [
+            json!({
+                "sessionUpdate": "agent_message_chunk",
+                "content": {"type": "text", "text": "before restart"}
+            }),
+            json!({
+                "sessionUpdate": "tool_call",
+                "toolCallId": "call_1",
+                "title": "Bash",
+                "kind": "execute",
+                "rawInput": {"command": "ls"}
+            }),
+        ] {
+            dispatch.session_update(&server_id, &acp_session_id, update);
+        }
+        for _ in 0..100 {
+            let (_, messages) = send(
+                &app,
+                Method::GET,
+                &format!("/session/{session_id}/message"),
+                None,
+            )
+            .await;
+            if assistant_parts(&messages).len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        (session_id, server_id)
+    });
+
+    runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        let (status, _) = send(&app, Method::GET, "/session", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let acp_session_id = format!("{server_id}-session");
+        for update in [
+            json!({
+                "sessionUpdate": "tool_call_update",
+                "toolCallId": "call_1",
+                "status": "completed",
+                "content": [{"type": "content", "content": {"type": "text", "text": "a.txt"}}]
+            }),
+            json!({
+                "sessionUpdate": "agent_message_chunk",
+                "content": {"type": "text", "text": "after restart"}
+            }),
+        ] {
+            dispatch.session_update(&server_id, &acp_session_id, update);
+        }
+        dispatch.notify(
+            &server_id,
+            json!({"jsonrpc": "2.0", "id": "prompt_2", "result": {"stopReason": "end_turn"}}),
+        );
+
+        let mut parts = Vec::new();
+        for _ in 0..100 {
+            let (_, messages) = send(
+                &app,
+                Method::GET,
+                &format!("/session/{session_id}/message"),
+                None,
+            )
+            .await;
+            parts = assistant_parts(&messages);
+            if parts.iter().any(|part| part["text"] == "after restart") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        // The replayed chunk and tool call update the parts recorded before
+        // the restart instead of adding new ones.
+        let message_id = parts[0]["messageID"].as_str().expect("message id");
+        let summary = parts
+            .iter()
+            .map(|part| {
+                let id = part["id"].as_str().expect("part id");
+                let suffix = id
+                    .strip_prefix(&format!("part_{message_id}_"))
+                    .unwrap_or_else(|| panic!("{id} is not a part of {message_id}"));
+                (
+                    suffix
+                        .parse::<u64>()
+                        .map_or(suffix.to_string(), |_| "N".into()),
+                    part["text"].as_str().map(str::to_string),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("N".to_string(), Some("before restart".to_string())),
+                ("tc_call_1".to_string(), None),
+                ("N".to_string(), Some("after restart".to_string())),
+            ]
+        );
+        assert_eq!(parts[1]["state"]["status"], "completed");
+        assert_eq!(parts[1]["state"]["output"], "a.txt");
+
+        let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        let tool_ids = polled["events"]
+            .as_array()
+            .expect("events")
+            .iter()
+            .filter(|event| event["type"] == "message.part.updated")
+            .filter(|event| event["properties"]["part"]["callID"] == "call_1")
+            .map(|event| event["properties"]["part"]["id"].clone())
+            .collect::<Vec<_>>();
+        assert!(!tool_ids.is_empty());
+        assert!(
+            tool_ids.iter().all(|id| *id == parts[1]["id"]),
+            "{tool_ids:?}"
+        );
+    });
+}
+
+#[tokio::test]
+async fn fast_text_chunks_are_coalesced_into_fewer_deltas() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            chunk_coalesce_window: Duration::from_millis(200),
+            chunk_coalesce_max_chars: 10,
+            ..Default::default()
+        },
+    );
+    let (_, server_id) = bootstrapped_session(&app, &dispatch).await;
+    let acp_session_id = format!("{server_id}-session");
+
+    // Five chunks reach the size limit, the next three are flushed by the
+    // turn's response and the last one when the window ends.
+    for chunk in ["ab"; 5].into_iter().chain(["cd"; 3]) {
+        dispatch.session_update(
+            &server_id,
+            &acp_session_id,
+            json!({
+                "sessionUpdate": "agent_message_chunk",
+                "content": {"type": "text", "text": chunk}
+            }),
+        );
+    }
+    dispatch.notify(
+        &server_id,
+        json!({"jsonrpc": "2.0", "id": "prompt_2", "result": {"stopReason": "end_turn"}}),
+    );
+    dispatch.session_update(
+        &server_id,
+        &acp_session_id,
+        json!({
+            "sessionUpdate": "agent_message_chunk",
+            "content": {"type": "text", "text": "ef"}
+        }),
+    );
+
+    let mut deltas = Vec::new();
+    for _ in 0..100 {
+        let (status, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        assert_eq!(status, StatusCode::OK);
+        deltas = polled["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|event| event["type"] == "message.part.updated")
+            .filter_map(|event| event["properties"]["delta"].as_str().map(str::to_string))
+            .collect::<Vec<_>>();
+        if deltas.len() >= 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(deltas, vec!["ababababab", "cdcdcd", "ef"]);
+}
+
+#[tokio::test]
+async fn chunks_pending_when_the_agent_stream_ends_are_flushed() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            chunk_coalesce_window: Duration::from_secs(60),
+            ..Default::default()
+        },
+    );
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+    let acp_session_id = format!("{server_id}-session");
+
+    let mut last_seq = 0;
+    for chunk in ["thinking", " aloud"] {
+        last_seq = dispatch.session_update(
+            &server_id,
+            &acp_session_id,
+            json!({
+                "sessionUpdate": "agent_message_chunk",
+                "content": {"type": "text", "text": chunk}
+            }),
+        );
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    dispatch.stop_instance(&server_id);
+
+    let mut deltas = Vec::new();
+    for _ in 0..100 {
+        let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        deltas = polled["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|event| event["type"] == "message.part.updated")
+            .filter_map(|event| event["properties"]["delta"].as_str().map(str::to_string))
+            .collect::<Vec<_>>();
+        if !deltas.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(deltas, vec!["thinking aloud"]);
+
+    // The stream's cursor covers the flushed chunks; a migration export
+    // carries it, so capture one.
+    let exported = Arc::new(std::sync::Mutex::new(None));
+    let capture = Router::new().route(
+        "/session/import",
+        axum::routing::post({
+            let exported = exported.clone();
+            move |axum::Json(body): axum::Json<Value>| async move {
+                *exported.lock().expect("export lock") = Some(body);
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind capture");
+    let capture_url = format!("http://{}", listener.local_addr().expect("capture address"));
+    tokio::spawn(async move { axum::serve(listener, capture).await });
+    let mut cursor = Value::Null;
+    for _ in 0..100 {
+        let (status, _) = send(
+            &app,
+            Method::POST,
+            &format!("/session/{session_id}/migrate"),
+            Some(json!({"target": capture_url})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        cursor = exported
+            .lock()
+            .expect("export lock")
+            .take()
+            .expect("exported")["acp"][0]["lastEventID"]
+            .clone();
+        if cursor == last_seq {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(cursor, last_seq);
+}
+
+#[tokio::test]
+async fn native_payloads_are_masked_capped_and_only_built_for_subscribers() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            native_event_max_bytes: 512,
+            chunk_coalesce_window: Duration::ZERO,
+            ..Default::default()
+        },
+    );
+    let (status, session) = send(
+        &app,
+        Method::POST,
+        "/session",
+        Some(json!({"env": {"API_TOKEN": "sk-live-123"}})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = session["id"].as_str().expect("session id").to_string();
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/message"),
+        Some(json!({"agent": "claude", "parts": [{"type": "text", "text": "hello"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let server_id = dispatch.posted()[0].server_id.clone();
+    let acp_session_id = format!("{server_id}-session");
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/event?include=native")
+                .body(Body::empty())
+                .expect("build request"),
+        )
+        .await
+        .expect("response");
+    let mut body = response.into_body();
+    dispatch.session_update(
+        &server_id,
+        &acp_session_id,
+        json!({
+            "sessionUpdate": "agent_message_chunk",
+            "content": {"type": "text", "text": "using sk-live-123"}
+        }),
+    );
+    dispatch.session_update(
+        &server_id,
+        &acp_session_id,
+        json!({
+            "sessionUpdate": "tool_call",
+            "toolCallId": "call_1",
+            "title": "Write",
+            "kind": "edit",
+            "rawInput": {"content": "x".repeat(2_000)}
+        }),
+    );
+
+    let mut natives = Vec::new();
+    let mut buffered = String::new();
+    while natives.len() < 2 {
+        let frame = tokio::time::timeout(Duration::from_secs(5), body.frame())
+            .await
+            .expect("event in time")
+            .expect("stream open")
+            .expect("frame");
+        let Ok(data) = frame.into_data() else {
+            continue;
+        };
+        buffered.push_str(std::str::from_utf8(&data).expect("utf-8 frame"));
+        while let Some(end) = buffered.find("\n\n") {
+            let frame = buffered[..end].to_string();
+            buffered.drain(..end + 2);
+            let Some(event) = frame
+                .lines()
+                .find_map(|line| line.strip_prefix("data: "))
+                .and_then(|data| serde_json::from_str::<Value>(data).ok())
+            else {
+                continue;
+            };
+            if event["type"] == "message.part.updated" {
+                natives.push(event["native"].clone());
+            }
+        }
+    }
+    let chunk = natives[0].to_string();
+    assert!(chunk.contains("agent_message_chunk"), "{chunk}");
+    assert!(!chunk.contains("sk-live-123"), "{chunk}");
+    assert_eq!(natives[1]["truncated"], true);
+    assert!(natives[1]["bytes"].as_u64().expect("bytes") > 2_000);
+
+    // Without a subscriber asking for them, none are built.
+    drop(body);
+    dispatch.session_update(
+        &server_id,
+        &acp_session_id,
+        json!({
+            "sessionUpdate": "agent_message_chunk",
+            "content": {"type": "text", "text": "unobserved"}
+        }),
+    );
+    let unobserved = |polled: &Value| {
+        polled["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|event| event["properties"]["delta"] == "unobserved")
+            .cloned()
+    };
+    for _ in 0..100 {
+        let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        if unobserved(&polled).is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let (_, polled) = send(
+        &app,
+        Method::GET,
+        "/event/poll?since=0&include=native",
+        None,
+    )
+    .await;
+    let event = unobserved(&polled).expect("unobserved chunk");
+    assert_eq!(event.get("native"), None);
+}
+
+#[tokio::test]
+async fn streamed_parts_are_readable_as_soon_as_they_are_emitted() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            chunk_coalesce_window: Duration::ZERO,
+            ..Default::default()
+        },
+    );
+    let (status, session) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = session["id"].as_str().expect("session id").to_string();
+
+    dispatch.respond_after(
+        "session/prompt",
+        Duration::from_secs(2),
+        json!({"stopReason": "end_turn"}),
+    );
+    let prompt = tokio::spawn({
+        let app = app.clone();
+        let uri = format!("/session/{session_id}/message");
+        async move {
+            send(
+                &app,
+                Method::POST,
+                &uri,
+                Some(json!({"agent": "claude", "parts": [{"type": "text", "text": "go"}]})),
+            )
+            .await
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let server_id = dispatch.posted()[0].server_id.clone();
+    let acp_session_id = format!("{server_id}-session");
+
+    // Every part a client was sent mid-turn is in the message list it reads
+    // right after, with the text streamed so far and the latest tool output.
+    let updates = [
+        json!({"sessionUpdate": "agent_message_chunk", "content": {"type": "text", "text": "Hel"}}),
+        json!({"sessionUpdate": "agent_message_chunk", "content": {"type": "text", "text": "lo"}}),
+        json!({
+            "sessionUpdate": "tool_call",
+            "toolCallId": "call_1",
+            "title": "Run tests",
+            "kind": "execute",
+            "status": "in_progress"
+        }),
+        json!({
+            "sessionUpdate": "tool_call_update",
+            "toolCallId": "call_1",
+            "content": [{"type": "content", "content": {"type": "text", "text": "running 3 tests"}}]
+        }),
+    ];
+    // The user message's parts went out before the prompt reached the agent.
+    let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+    let mut seen = polled["events"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|event| event["type"] == "message.part.updated")
+        .count();
+    for update in updates {
+        dispatch.session_update(&server_id, &acp_session_id, update);
+        let mut emitted = Vec::new();
+        for _ in 0..100 {
+            let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+            emitted = polled["events"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|event| event["type"] == "message.part.updated")
+                .map(|event| event["properties"]["part"].clone())
+                .collect::<Vec<_>>();
+            if emitted.len() > seen {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        seen = emitted.len();
+        let latest = emitted.last().expect("emitted part").clone();
+
+        let (status, messages) = send(
+            &app,
+            Method::GET,
+            &format!("/session/{session_id}/message"),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let read = messages
+            .as_array()
+            .into_iter()
+            .flatten()
+            .flat_map(|message| message["parts"].as_array().cloned().unwrap_or_default())
+            .find(|part| part["id"] == latest["id"])
+            .unwrap_or_else(|| panic!("part {} missing from {messages}", latest["id"]));
+        assert_eq!(read["text"], latest["text"]);
+        assert_eq!(read["state"], latest["state"]);
+    }
+
+    let (status, _) = prompt.await.expect("prompt task");
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn dry_run_sessions_deny_agent_permission_requests() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+    let (status, _) = send(
+        &app,
+        Method::PATCH,
+        &format!("/session/{session_id}"),
+        Some(json!({"dryRun": true})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    dispatch.notify(
+        &server_id,
+        json!({
+            "jsonrpc": "2.0",
+            "id": "perm_1",
+            "method": "session/request_permission",
+            "params": {
+                "sessionId": format!("{server_id}-session"),
+                "toolCall": {
+                    "toolCallId": "call_1",
+                    "title": "Bash",
+                    "kind": "execute",
+                    "rawInput": {"command": "rm -rf build"}
+                }
+            }
+        }),
+    );
+
+    let mut answer = None;
+    for _ in 0..100 {
+        answer = dispatch
+            .posted()
+            .into_iter()
+            .find(|posted| posted.payload["id"] == "perm_1");
+        if answer.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let result = answer.expect("permission answered").payload["result"].clone();
+    assert_eq!(result["selectedOption"]["kind"], "reject_once");
+    assert!(result["_meta"]["sandboxagent.dev"]["message"]
+        .as_str()
+        .is_some_and(|message| message.contains("dry-run")));
+    let (_, pending) = send(&app, Method::GET, "/permission", None).await;
+    assert_eq!(pending, json!([]));
+}
+
+/// Allows `git` commands, denies `rm` and defers everything else.
+struct CommandPolicy;
+
+impl Authorizer for CommandPolicy {
+    fn authorize<'a>(
+        &'a self,
+        context: &'a PermissionContext,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = AuthorizerDecision> + Send + 'a>> {
+        let command = context.patterns.first().cloned().unwrap_or_default();
+        Box::pin(async move {
+            if command.starts_with("git ") {
+                AuthorizerDecision::Allow { reason: None }
+            } else if command.starts_with("rm ") {
+                AuthorizerDecision::Deny {
+                    reason: Some("Deleting files needs a ticket.".to_string()),
+                }
+            } else {
+                AuthorizerDecision::Defer
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn authorizers_answer_permission_requests_before_the_user() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            authorizer: Some(Arc::new(CommandPolicy)),
+            ..OpenCodeAdapterConfig::default()
+        },
+    );
+    let (_, server_id) = bootstrapped_session(&app, &dispatch).await;
+
+    for (id, command) in [
+        ("perm_git", "git status"),
+        ("perm_rm", "rm -rf build"),
+        ("perm_ls", "ls"),
+    ] {
+        dispatch.notify(
+            &server_id,
+            json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": "session/request_permission",
+                "params": {
+                    "sessionId": format!("{server_id}-session"),
+                    "toolCall": {
+                        "toolCallId": format!("call_{id}"),
+                        "title": "Bash",
+                        "kind": "execute",
+                        "rawInput": {"command": command}
+                    }
+                }
+            }),
+        );
+    }
+
+    let answer = |id: &str| {
+        dispatch
+            .posted()
+            .into_iter()
+            .find(|posted| posted.payload["id"] == id)
+            .map(|posted| posted.payload["result"].clone())
+    };
+    let mut pending = Value::Null;
+    for _ in 0..100 {
+        (_, pending) = send(&app, Method::GET, "/permission", None).await;
+        if answer("perm_rm").is_some() && pending.as_array().is_some_and(|list| !list.is_empty()) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let allowed = answer("perm_git").expect("git answered");
+    assert_eq!(allowed["selectedOption"]["kind"], "allow_once");
+    let denied = answer("perm_rm").expect("rm answered");
+    assert_eq!(denied["selectedOption"]["kind"], "reject_once");
+    assert_eq!(
+        denied["_meta"]["sandboxagent.dev"]["message"],
+        "Deleting files needs a ticket."
+    );
+    assert!(answer("perm_ls").is_none());
+    let pending = pending.as_array().expect("pending permissions");
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0]["patterns"], json!(["ls"]));
+
+    let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+    let events = polled["events"].as_array().expect("events");
+    let decisions = events
+        .iter()
+        .filter(|event| event["type"] == "permission.authorized")
+        .map(|event| event["properties"]["decision"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(decisions, vec!["allow", "deny", "defer"]);
+    let replies = events
+        .iter()
+        .filter(|event| event["type"] == "permission.replied")
+        .map(|event| {
+            assert_eq!(event["properties"]["authorizer"], true);
+            event["properties"]["reply"].clone()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(replies, vec!["once", "reject"]);
+}
+
+#[tokio::test]
+async fn tool_lifecycle_is_emitted_as_dedicated_events() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+    let acp_session_id = format!("{server_id}-session");
+
+    dispatch.respond_after(
+        "session/prompt",
+        Duration::from_millis(300),
+        json!({"stopReason": "end_turn"}),
+    );
+    let prompt = tokio::spawn({
+        let app = app.clone();
+        let uri = format!("/session/{session_id}/message");
+        async move {
+            send(
+                &app,
+                Method::POST,
+                &uri,
+                Some(json!({"parts": [{"type": "text", "text": "build it"}]})),
+            )
+            .await
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    for update in [
+        json!({
+            "sessionUpdate": "tool_call",
+            "toolCallId": "call_1",
+            "title": "Bash",
+            "kind": "execute",
+            "rawInput": {"command": "cargo build"}
+        }),
+        json!({
+            "sessionUpdate": "tool_call_update",
+            "toolCallId": "call_1",
+            "status": "in_progress",
+            "content": [{"type": "content", "content": {"type": "text", "text": "Compiling"}}]
+        }),
+        json!({
+            "sessionUpdate": "tool_call_update",
+            "toolCallId": "call_1",
+            "status": "completed",
+            "content": [{"type": "content", "content": {"type": "text", "text": "Finished"}}]
+        }),
+        json!({
+            "sessionUpdate": "tool_call",
+            "toolCallId": "call_2",
+            "title": "Read",
+            "kind": "read"
+        }),
+    ] {
+        dispatch.session_update(&server_id, &acp_session_id, update);
+    }
+    let (status, _) = prompt.await.expect("prompt task");
+    assert_eq!(status, StatusCode::OK);
+
+    let mut tool_events = Vec::new();
+    for _ in 0..100 {
+        let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        tool_events = polled["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|event| {
+                event["type"]
+                    .as_str()
+                    .is_some_and(|kind| kind.starts_with("tool."))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if tool_events.len() >= 5 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let summary = tool_events
+        .iter()
+        .map(|event| {
+            (
+                event["type"].as_str().unwrap_or_default(),
+                event["properties"]["callID"].as_str().unwrap_or_default(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        vec![
+            ("tool.started", "call_1"),
+            ("tool.output", "call_1"),
+            ("tool.completed", "call_1"),
+            ("tool.started", "call_2"),
+            ("tool.failed", "call_2"),
+        ]
+    );
+    let started = &tool_events[0]["properties"];
+    assert_eq!(started["sessionID"], session_id.as_str());
+    assert_eq!(started["tool"], "Bash");
+    assert_eq!(started["kind"], "execute");
+    assert_eq!(tool_events[1]["properties"]["output"], "Compiling");
+    let completed = &tool_events[2]["properties"];
+    assert_eq!(completed["tool"], "Bash");
+    assert_eq!(completed["output"], "Finished");
+    assert!(completed["durationMs"].is_i64());
+    let interrupted = &tool_events[4]["properties"];
+    assert_eq!(interrupted["tool"], "Read");
+    assert_eq!(interrupted["status"], "interrupted");
+}
+
+#[tokio::test]
+async fn codex_item_statuses_drive_tool_part_states() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let (status, session) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = session["id"].as_str().expect("session id").to_string();
+
+    dispatch.respond_after(
+        "session/prompt",
+        Duration::from_millis(300),
+        json!({"stopReason": "end_turn"}),
+    );
+    let prompt = tokio::spawn({
+        let app = app.clone();
+        let uri = format!("/session/{session_id}/message");
+        async move {
+            send(
+                &app,
+                Method::POST,
+                &uri,
+                Some(
+                    json!({"agent": "codex", "parts": [{"type": "text", "text": "fix the build"}]}),
+                ),
+            )
+            .await
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let server_id = dispatch.posted()[0].server_id.clone();
+    let acp_session_id = format!("{server_id}-session");
+    // Codex announces an exec item before its arguments are parsed, streams
+    // its output, then reports the function call result; a web search item
+    // arrives already completed.
+    for update in [
+        json!({
+            "sessionUpdate": "tool_call",
+            "toolCallId": "call_exec",
+            "title": "Run command",
+            "kind": "execute",
+            "status": "pending"
+        }),
+        json!({
+            "sessionUpdate": "tool_call_update",
+            "toolCallId": "call_exec",
+            "status": "in_progress",
+            "title": "cargo build",
+            "rawInput": {"command": ["cargo", "build"]}
+        }),
+        json!({
+            "sessionUpdate": "tool_call_update",
+            "toolCallId": "call_exec",
+            "content": [{"type": "content", "content": {"type": "text", "text": "Compiling"}}]
+        }),
+        json!({
+            "sessionUpdate": "tool_call_update",
+            "toolCallId": "call_exec",
+            "status": "failed",
+            "rawOutput": {"exit_code": 101, "formatted_output": "error[E0425]: cannot find value"}
+        }),
+        json!({
+            "sessionUpdate": "tool_call",
+            "toolCallId": "call_search",
+            "title": "Web search",
+            "kind": "fetch",
+            "status": "completed",
+            "rawInput": {"query": "E0425"}
+        }),
+    ] {
+        dispatch.session_update(&server_id, &acp_session_id, update);
+    }
+    let (status, _) = prompt.await.expect("prompt task");
+    assert_eq!(status, StatusCode::OK);
+
+    let mut exec_statuses = Vec::new();
+    for _ in 0..100 {
+        let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        exec_statuses = polled["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|event| {
+                event["type"] == "message.part.updated"
+                    && event["properties"]["part"]["callID"] == "call_exec"
+            })
+            .map(|event| event["properties"]["part"]["state"]["status"].clone())
+            .collect::<Vec<_>>();
+        if exec_statuses.len() >= 4 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(
+        exec_statuses,
+        vec![
+            json!("pending"),
+            json!("running"),
+            json!("running"),
+            json!("error")
+        ]
+    );
+
+    let (_, messages) = send(
+        &app,
+        Method::GET,
+        &format!("/session/{session_id}/message"),
+        None,
+    )
+    .await;
+    let tool_parts = messages
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|message| message["parts"].as_array().cloned().unwrap_or_default())
+        .filter(|part| part["type"] == "tool")
+        .collect::<Vec<_>>();
+    assert_eq!(tool_parts.len(), 2, "{tool_parts:?}");
+    let exec = &tool_parts[0];
+    assert_eq!(
+        exec["id"],
+        format!(
+            "part_{}_tc_call_exec",
+            exec["messageID"].as_str().expect("message id")
+        )
+    );
+    assert_eq!(exec["tool"], "cargo build");
+    assert_eq!(exec["state"]["status"], "error");
+    assert_eq!(exec["state"]["error"], "error[E0425]: cannot find value");
+    assert_eq!(
+        exec["state"]["input"],
+        json!({"command": ["cargo", "build"]})
+    );
+    let time = &exec["state"]["time"];
+    assert!(time["end"].as_i64() >= time["start"].as_i64());
+    let search = &tool_parts[1];
+    assert_eq!(search["state"]["status"], "completed");
+    assert_eq!(search["state"]["input"], json!({"query": "E0425"}));
+}
+
+#[tokio::test]
+async fn composer_sessions_route_prompts_to_target_backends() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+
+    let (status, session) = send(
+        &app,
+        Method::POST,
+        "/session",
+        Some(json!({"backends": {"review": "codex"}})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(session["backends"]["review"]["agent"], "codex");
+    let session_id = session["id"].as_str().expect("session id").to_string();
+    let uri = format!("/session/{session_id}/message");
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &uri,
+        Some(json!({"agent": "claude", "parts": [{"type": "text", "text": "write it"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let primary = dispatch.posted()[0].server_id.clone();
+
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &uri,
+        Some(json!({"target": "review", "parts": [{"type": "text", "text": "review it"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let backend = format!("{primary}-review");
+    let initialize = dispatch
+        .posted()
+        .into_iter()
+        .find(|posted| posted.server_id == backend && posted.method() == Some("initialize"))
+        .expect("backend bootstrapped");
+    assert_eq!(initialize.bootstrap_agent.as_deref(), Some("codex"));
+    assert!(dispatch
+        .posted_methods(&backend)
+        .contains(&"session/prompt".to_string()));
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &uri,
+        Some(json!({"target": "docs", "parts": [{"type": "text", "text": "document it"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Requests from the backend are attributed to it and answered there.
+    dispatch.notify(
+        &backend,
+        json!({
+            "jsonrpc": "2.0",
+            "id": "perm_review",
+            "method": "session/request_permission",
+            "params": {
+                "sessionId": format!("{backend}-session"),
+                "toolCall": {"toolCallId": "call_1", "title": "Read", "kind": "read"}
+            }
+        }),
+    );
+    let mut asked = None;
+    for _ in 0..100 {
+        let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        asked = polled["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|event| {
+                event["type"] == "permission.asked"
+                    && event["properties"]["sessionID"] == session_id.as_str()
+            })
+            .cloned();
+        if asked.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let asked = asked.expect("permission asked");
+    assert_eq!(asked["properties"]["backend"], "review");
+    let request_id = asked["properties"]["id"].as_str().expect("request id");
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &format!("/permission/{request_id}/reply"),
+        Some(json!({"reply": "once"})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let answer = dispatch
+        .posted()
+        .into_iter()
+        .find(|posted| posted.payload["id"] == "perm_review")
+        .expect("permission answered");
+    assert_eq!(answer.server_id, backend);
+}
+
+#[tokio::test]
+async fn stop_reasons_are_recorded_on_messages_and_turns() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+
+    dispatch.respond("session/prompt", json!({"stopReason": "refusal"}));
+    let (status, message) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/message"),
+        Some(json!({"parts": [{"type": "text", "text": "do something bad"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(message["info"]["stopReason"], "refusal");
+    assert_eq!(message["info"]["finish"], "content-filter");
+    let mut completed = None;
+    for _ in 0..100 {
+        let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        completed = polled["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .rfind(|event| {
+                event["type"] == "turn.completed"
+                    && event["properties"]["sessionID"] == session_id.as_str()
+            })
+            .cloned();
+        if completed
+            .as_ref()
+            .is_some_and(|event| event["properties"]["stopReason"] == "refusal")
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(
+        completed.expect("turn completed")["properties"]["stopReason"],
+        "refusal"
+    );
+
+    // A streamed turn is finalized from the response on the agent's stream.
+    dispatch.session_update(
+        &server_id,
+        &format!("{server_id}-session"),
+        json!({
+            "sessionUpdate": "agent_message_chunk",
+            "content": {"type": "text", "text": "and then"}
+        }),
+    );
+    dispatch.notify(
+        &server_id,
+        json!({"jsonrpc": "2.0", "id": "prompt_3", "result": {"stopReason": "max_tokens"}}),
+    );
+    let mut finish = Value::Null;
+    for _ in 0..100 {
+        let (_, messages) = send(
+            &app,
+            Method::GET,
+            &format!("/session/{session_id}/message"),
+            None,
+        )
+        .await;
+        finish = messages
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|message| message["info"]["stopReason"] == "max_tokens")
+            .map(|message| message["info"]["finish"].clone())
+            .unwrap_or(Value::Null);
+        if !finish.is_null() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(finish, "length");
+}
+
+#[tokio::test]
+async fn event_export_streams_compressed_ndjson_with_a_watermark() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let (session_id, _) = bootstrapped_session(&app, &dispatch).await;
+    tokio::time::sleep(Duration::from_millis(5)).await;
+
+    let export = |since: i64| {
+        let app = app.clone();
+        async move {
+            let request = Request::builder()
+                .uri(format!("/admin/export/events?since={since}"))
+                .body(Body::empty())
+                .expect("build request");
+            let response = app.oneshot(request).await.expect("response");
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["content-type"], "application/zstd");
+            let watermark: i64 = response.headers()["x-sandbox-agent-export-watermark"]
+                .to_str()
+                .expect("header")
+                .parse()
+                .expect("watermark");
+            let bytes = response
+                .into_body()
+                .collect()
+                .await
+                .expect("collect body")
+                .to_bytes();
+            let ndjson = zstd::decode_all(bytes.as_ref()).expect("zstd frame");
+            let events = String::from_utf8(ndjson)
+                .expect("utf-8")
+                .lines()
+                .map(|line| serde_json::from_str::<Value>(line).expect("json line"))
+                .collect::<Vec<_>>();
+            (watermark, events)
+        }
+    };
+
+    let (watermark, events) = export(0).await;
+    assert!(events
+        .iter()
+        .any(|event| event["sessionID"] == session_id.as_str()
+            && event["payload"]["method"] == "session/prompt"));
+    let times = events
+        .iter()
+        .map(|event| event["createdAt"].as_i64().expect("createdAt"))
+        .collect::<Vec<_>>();
+    assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert!(times.iter().all(|time| *time <= watermark));
+
+    let (next, events) = export(watermark).await;
+    assert!(next >= watermark);
+    assert!(events
+        .iter()
+        .all(|event| event["createdAt"].as_i64().expect("createdAt") > watermark));
+}
+
+#[tokio::test]
+async fn turn_rollback_restores_the_workspace_snapshot() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let work = dir.path().join("work");
+    std::fs::create_dir_all(&work).expect("create work dir");
+    std::fs::write(work.join("notes.txt"), "before").expect("write");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        dir.path().join("opencode.db").to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            workspace_snapshots: true,
+            ..OpenCodeAdapterConfig::default()
+        },
+    );
+    let work_dir = work.to_str().expect("utf-8 path");
+    let (status, session) = send(
+        &app,
+        Method::POST,
+        &format!("/session?directory={work_dir}"),
+        Some(json!({})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = session["id"].as_str().expect("session id").to_string();
+
+    let (status, turn) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/prompt_async"),
+        Some(json!({"agent": "claude", "parts": [{"type": "text", "text": "edit notes"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let turn_id = turn["id"].as_str().expect("turn id").to_string();
+    let mut turn = Value::Null;
+    for _ in 0..100 {
+        (_, turn) = send(
+            &app,
+            Method::GET,
+            &format!("/session/{session_id}/turn/{turn_id}"),
+            None,
+        )
+        .await;
+        if turn["status"] == "completed" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(turn["status"], "completed");
+    let snapshot = turn["snapshot"]
+        .as_str()
+        .expect("turn snapshot")
+        .to_string();
+
+    // What the agent did during the turn.
+    std::fs::write(work.join("notes.txt"), "after").expect("write");
+    std::fs::write(work.join("scratch.txt"), "new").expect("write");
+
+    let (status, rolled_back) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/turn/{turn_id}/rollback"),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(rolled_back["snapshot"], snapshot.as_str());
+    assert_eq!(rolled_back["restored"], json!(["notes.txt"]));
+    assert_eq!(rolled_back["removed"], json!(["scratch.txt"]));
+    assert_eq!(
+        std::fs::read_to_string(work.join("notes.txt")).expect("read"),
+        "before"
+    );
+    assert!(!work.join("scratch.txt").exists());
+
+    let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+    let event = polled["events"]
+        .as_array()
+        .expect("events")
+        .iter()
+        .find(|event| event["type"] == "workspace.rolled_back")
+        .expect("workspace.rolled_back event");
+    assert_eq!(event["properties"]["turnID"], turn_id.as_str());
+    assert_eq!(event["properties"]["removed"], json!(["scratch.txt"]));
+
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/turn/turn_missing/rollback"),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn turn_artifacts_list_and_serve_the_files_a_turn_wrote() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let work = dir.path().join("work");
+    std::fs::create_dir_all(work.join("out")).expect("create work dir");
+    std::fs::write(work.join(".gitignore"), "out\n").expect("write");
+    std::fs::write(work.join("notes.txt"), "before").expect("write");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(
+        &dispatch,
+        dir.path().join("opencode.db").to_str().expect("utf-8 path"),
+    );
+    let work_dir = work.to_str().expect("utf-8 path");
+    let (_, session) = send(
+        &app,
+        Method::POST,
+        &format!("/session?directory={work_dir}"),
+        Some(json!({})),
+    )
+    .await;
+    let session_id = session["id"].as_str().expect("session id").to_string();
+
+    dispatch.respond_after(
+        "session/prompt",
+        Duration::from_millis(300),
+        json!({"stopReason": "end_turn"}),
+    );
+    let (status, turn) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/prompt_async"),
+        Some(json!({"agent": "claude", "parts": [{"type": "text", "text": "write a report"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let turn_id = turn["id"].as_str().expect("turn id").to_string();
+    let mut server_id = None;
+    for _ in 0..100 {
+        server_id = dispatch
+            .posted()
+            .into_iter()
+            .find(|posted| posted.method() == Some("session/prompt"))
+            .map(|posted| posted.server_id);
+        if server_id.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let server_id = server_id.expect("prompt sent");
+
+    // What the agent does during the turn.
+    std::fs::write(work.join("notes.txt"), "after the turn").expect("write");
+    std::fs::write(work.join("report.md"), "# Report\n").expect("write");
+    let chart = work.join("out/chart.png");
+    std::fs::write(&chart, [0x89, b'P', b'N', b'G']).expect("write");
+    dispatch.session_update(
+        &server_id,
+        &format!("{server_id}-session"),
+        json!({
+            "sessionUpdate": "tool_call",
+            "toolCallId": "call_chart",
+            "title": "Write",
+            "kind": "edit",
+            "rawInput": {"file_path": chart.to_str().expect("utf-8 path")}
+        }),
+    );
+
+    let mut listing = Value::Null;
+    for _ in 0..100 {
+        (_, listing) = send(
+            &app,
+            Method::GET,
+            &format!("/session/{session_id}/turn/{turn_id}/artifacts"),
+            None,
+        )
+        .await;
+        if listing["status"] == "completed" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(listing["status"], "completed");
+    let artifacts = listing["artifacts"].as_array().expect("artifacts");
+    let summary: Vec<_> = artifacts
+        .iter()
+        .map(|artifact| {
+            (
+                artifact["path"].as_str().unwrap_or_default(),
+                artifact["change"].as_str().unwrap_or_default(),
+                artifact["mimeType"].as_str().unwrap_or_default(),
+                artifact["callID"].as_str(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("notes.txt", "modified", "text/plain", None),
+            ("out/chart.png", "created", "image/png", Some("call_chart")),
+            ("report.md", "created", "text/markdown", None),
+        ]
+    );
+    let report = &artifacts[2];
+    assert_eq!(report["size"], 9);
+
+    let download = |uri: String| {
+        let app = app.clone();
+        async move {
+            let request = Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .expect("build request");
+            let response = app.oneshot(request).await.expect("response");
+            let status = response.status();
+            let headers = response.headers().clone();
+            let bytes = response
+                .into_body()
+                .collect()
+                .await
+                .expect("collect body")
+                .to_bytes();
+            (status, headers, bytes)
+        }
+    };
+    let url = report["url"].as_str().expect("download url").to_string();
+    let (status, headers, bytes) = download(url.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(&bytes[..], b"# Report\n");
+    assert_eq!(headers["content-type"], "text/markdown");
+    assert_eq!(
+        headers["content-disposition"],
+        "attachment; filename=\"report.md\""
+    );
+    assert_eq!(
+        headers["etag"].to_str().expect("etag"),
+        format!("\"{}\"", report["sha256"].as_str().expect("sha256"))
+    );
+
+    let mut event = None;
+    for _ in 0..100 {
+        let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        event = polled["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|event| event["type"] == "turn.artifacts")
+            .cloned();
+        if event.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let event = event.expect("turn.artifacts event");
+    assert_eq!(event["properties"]["artifacts"], listing["artifacts"]);
+
+    // A file changed since the turn is no longer the artifact it produced.
+    std::fs::write(work.join("report.md"), "# Edited\n").expect("write");
+    let (status, _, bytes) = download(url).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let body: Value = serde_json::from_slice(&bytes).expect("error body");
+    assert_eq!(body["errors"][0]["name"], "ArtifactChangedError");
+    let (status, _, _) = download(format!(
+        "/session/{session_id}/turn/{turn_id}/artifacts/art_missing"
+    ))
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn agent_authenticate_forwards_credentials_and_unblocks_prompts() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(
+        &dispatch,
+        dir.path().join("opencode.db").to_str().expect("utf-8 path"),
+    );
+    dispatch.respond_always(
+        "initialize",
+        json!({"protocolVersion": 1, "authMethods": [{"id": "api-key", "name": "API key"}]}),
+    );
+    dispatch.respond_error("session/new", -32000, "Authentication required");
+
+    let (status, session) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = session["id"].as_str().expect("session id").to_string();
+    let prompt = json!({"agent": "claude", "parts": [{"type": "text", "text": "hello"}]});
+    let message_uri = format!("/session/{session_id}/message");
+    let (status, error) = send(&app, Method::POST, &message_uri, Some(prompt.clone())).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error["errors"][0]["name"], "AgentAuthRequiredError");
+    assert_eq!(error["errors"][0]["data"]["agent"], "claude");
+    assert_eq!(
+        error["errors"][0]["data"]["authMethods"][0]["id"],
+        "api-key"
+    );
+    let server_id = error["errors"][0]["data"]["serverID"]
+        .as_str()
+        .expect("server id")
+        .to_string();
+
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        "/agents/vim/authenticate",
+        Some(json!({"methodId": "api-key"})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let credentials = json!({"methodId": "api-key", "apiKey": "sk-test"});
+    let (status, authenticated) = send(
+        &app,
+        Method::POST,
+        "/agents/claude/authenticate",
+        Some(credentials.clone()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(authenticated["serverIDs"], json!([]));
+
+    // The next bootstrap authenticates before opening the session.
+    let (status, _) = send(&app, Method::POST, &message_uri, Some(prompt.clone())).await;
+    assert_eq!(status, StatusCode::OK);
+    let methods = dispatch.posted_methods(&server_id);
+    let authenticate_at = methods
+        .iter()
+        .position(|method| method == "authenticate")
+        .expect("authenticate sent");
+    assert_eq!(
+        methods[authenticate_at + 1..].first().map(String::as_str),
+        Some("session/new")
+    );
+    let sent = dispatch
+        .posted()
+        .into_iter()
+        .find(|posted| posted.method() == Some("authenticate"))
+        .expect("authenticate payload");
+    assert_eq!(sent.payload["params"], credentials);
+    let (_, backend) = send(
+        &app,
+        Method::GET,
+        &format!("/session/{session_id}/backend"),
+        None,
+    )
+    .await;
+    assert_eq!(backend["authenticated"], "api-key");
+
+    // An agent that drops its credentials mid-session gets them again.
+    dispatch.respond_error("session/prompt", -32000, "Authentication required");
+    // The prompt reached the agent, so the 200 is already sent; the error
+    // is in the body.
+    let (_, error) = send(&app, Method::POST, &message_uri, Some(prompt.clone())).await;
+    assert_eq!(error["errors"][0]["name"], "AgentAuthRequiredError");
+    let (status, authenticated) = send(
+        &app,
+        Method::POST,
+        "/agents/claude/authenticate",
+        Some(credentials),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(authenticated["serverIDs"], json!([server_id]));
+    let (status, _) = send(&app, Method::POST, &message_uri, Some(prompt)).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn streamed_replies_report_time_to_first_token_and_tokens_per_second() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+    let acp_session_id = format!("{server_id}-session");
+
+    dispatch.respond_after(
+        "session/prompt",
+        Duration::from_millis(400),
+        json!({"stopReason": "end_turn"}),
+    );
+    let (status, turn) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/prompt_async"),
+        Some(json!({"parts": [{"type": "text", "text": "count to ten"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let turn_id = turn["id"].as_str().expect("turn id").to_string();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    for chunk in ["one two three ", "four five six ", "seven eight nine ten"] {
+        dispatch.session_update(
+            &server_id,
+            &acp_session_id,
+            json!({
+                "sessionUpdate": "agent_message_chunk",
+                "content": {"type": "text", "text": chunk}
+            }),
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let mut finalized = Value::Null;
+    for _ in 0..100 {
+        let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        if let Some(event) = polled["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|event| {
+                event["type"] == "message.updated"
+                    && event["properties"]["info"]["role"] == "assistant"
+                    && event["properties"]["info"]["time"]["completed"].is_number()
+            })
+        {
+            finalized = event["properties"]["info"].clone();
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let stats = &finalized["stats"];
+    let ttft = stats["ttftMs"].as_i64().expect("time to first token");
+    assert!((50..400).contains(&ttft), "ttft {ttft}ms");
+    assert_eq!(stats["outputTokens"], 13);
+    assert_eq!(stats["reasoningTokens"], 0);
+    assert!(stats["streamingMs"].as_i64().expect("streaming time") >= 150);
+    assert!(
+        stats["tokensPerSecond"]
+            .as_f64()
+            .expect("tokens per second")
+            > 0.0
+    );
+    assert_eq!(stats["estimated"], true);
+
+    let (_, turn) = send(
+        &app,
+        Method::GET,
+        &format!("/session/{session_id}/turn/{turn_id}"),
+        None,
+    )
+    .await;
+    assert_eq!(turn["stats"], *stats);
+}
+
+#[tokio::test]
+async fn turns_report_the_resources_their_agent_processes_used() {
+    if !std::path::Path::new("/proc/self/stat").exists() {
+        return;
+    }
+    let dir = tempfile::tempdir().expect("tempdir");
+    let work = dir.path().join("work");
+    std::fs::create_dir_all(&work).expect("create work dir");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        dir.path().join("opencode.db").to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            turn_resource_interval: Duration::from_millis(50),
+            ..OpenCodeAdapterConfig::default()
+        },
+    );
+    let work_dir = work.to_str().expect("utf-8 path");
+    let (_, session) = send(
+        &app,
+        Method::POST,
+        &format!("/session?directory={work_dir}"),
+        Some(json!({})),
+    )
+    .await;
+    let session_id = session["id"].as_str().expect("session id").to_string();
+
+    // Stands in for the agent: burns CPU in a subshell it reaps, writes a
+    // file into the session directory and idles until the turn is over.
+    let mut agent = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(
+            "sleep 0.2; (i=0; while [ $i -lt 50000 ]; do i=$((i+1)); done); \
+             head -c 65536 /dev/zero > out.bin; exec sleep 30",
+        )
+        .current_dir(&work)
+        .spawn()
+        .expect("spawn agent stand-in");
+    dispatch.set_pid(agent.id());
+    dispatch.respond_after(
+        "session/prompt",
+        Duration::from_millis(2_000),
+        json!({"stopReason": "end_turn"}),
+    );
+    let (status, turn) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/prompt_async"),
+        Some(json!({"agent": "claude", "parts": [{"type": "text", "text": "build it"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let turn_id = turn["id"].as_str().expect("turn id").to_string();
+
+    let mut completed = Value::Null;
+    for _ in 0..200 {
+        let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        if let Some(event) = polled["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|event| event["type"] == "turn.completed")
+        {
+            completed = event["properties"].clone();
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let _ = agent.kill();
+    let _ = agent.wait();
+
+    let resources = &completed["resources"];
+    assert!(
+        resources["cpuSeconds"].as_f64().expect("cpu seconds") > 0.0,
+        "{resources}"
+    );
+    assert!(resources["peakRssBytes"].as_u64().expect("peak rss") > 0);
+    assert!(resources["diskWriteBytes"].is_u64());
+    assert_eq!(resources["workspaceWriteBytes"], 65536);
+    assert!(resources["peakProcesses"].as_u64().expect("processes") >= 1);
+    assert!(resources["samples"].as_u64().expect("samples") >= 2);
+
+    let (_, turn) = send(
+        &app,
+        Method::GET,
+        &format!("/session/{session_id}/turn/{turn_id}"),
+        None,
+    )
+    .await;
+    assert_eq!(turn["resources"], *resources);
+}
+
+#[tokio::test]
+async fn agent_thoughts_stream_as_reasoning_parts_apart_from_the_text() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+    let acp_session_id = format!("{server_id}-session");
+
+    dispatch.respond_after(
+        "session/prompt",
+        Duration::from_millis(300),
+        json!({"stopReason": "end_turn"}),
+    );
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/prompt_async"),
+        Some(json!({"parts": [{"type": "text", "text": "are the tests green?"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    for update in [
+        json!({
+            "sessionUpdate": "agent_thought_chunk",
+            "content": {"type": "text", "text": "Run the tests, "}
+        }),
+        json!({
+            "sessionUpdate": "agent_message_chunk",
+            "content": {"type": "text", "text": "All green."}
+        }),
+        json!({
+            "sessionUpdate": "agent_thought_chunk",
+            "content": {"type": "text", "text": "then the lints."}
+        }),
+    ] {
+        dispatch.session_update(&server_id, &acp_session_id, update);
+    }
+
+    let mut parts = Vec::new();
+    for _ in 0..100 {
+        let (_, messages) = send(
+            &app,
+            Method::GET,
+            &format!("/session/{session_id}/message"),
+            None,
+        )
+        .await;
+        let answer = messages
+            .as_array()
+            .into_iter()
+            .flatten()
+            .rfind(|message| message["info"]["role"] == "assistant");
+        if answer.is_some_and(|answer| answer["info"]["time"]["completed"].is_number()) {
+            parts = answer
+                .and_then(|answer| answer["parts"].as_array().cloned())
+                .unwrap_or_default();
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let of_type = |kind: &str| {
+        parts
+            .iter()
+            .filter(|part| part["type"] == kind)
+            .collect::<Vec<_>>()
+    };
+    let text = of_type("text");
+    let reasoning = of_type("reasoning");
+    assert_eq!(text.len(), 1, "{parts:?}");
+    assert_eq!(text[0]["text"], "All green.");
+    let thought = reasoning
+        .iter()
+        .filter_map(|part| part["text"].as_str())
+        .collect::<String>();
+    assert_eq!(thought, "Run the tests, then the lints.");
+    assert!(reasoning.iter().all(|part| part["id"] != text[0]["id"]));
+}
+
+#[tokio::test]
+async fn claude_thinking_and_reasoning_budgets_reach_reasoning_parts_and_usage() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+    let acp_session_id = format!("{server_id}-session");
+
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/prompt_async"),
+        Some(json!({"variant": "extreme", "parts": [{"type": "text", "text": "hi"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    dispatch.respond_after(
+        "session/prompt",
+        Duration::from_millis(300),
+        json!({
+            "stopReason": "end_turn",
+            "_meta": {"usage": {"input_tokens": 50, "output_tokens": 20}},
+        }),
+    );
+    let (status, turn) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/prompt_async"),
+        Some(json!({"variant": "high", "parts": [{"type": "text", "text": "plan it"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let turn_id = turn["id"].as_str().expect("turn id").to_string();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    dispatch.session_update(
+        &server_id,
+        &acp_session_id,
+        json!({
+            "sessionUpdate": "agent_message_chunk",
+            "content": {"type": "thinking", "thinking": "Read the spec first.", "signature": "s"}
+        }),
+    );
+    dispatch.session_update(
+        &server_id,
+        &acp_session_id,
+        json!({
+            "sessionUpdate": "agent_message_chunk",
+            "content": {"type": "text", "text": "Done."}
+        }),
+    );
+
+    let mut finalized = Value::Null;
+    let mut reasoning = Value::Null;
+    for _ in 0..100 {
+        let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        let events = polled["events"].as_array().cloned().unwrap_or_default();
+        if let Some(part) = events.iter().find(|event| {
+            event["type"] == "message.part.updated"
+                && event["properties"]["part"]["type"] == "reasoning"
+        }) {
+            reasoning = part["properties"]["part"].clone();
+        }
+        if let Some(event) = events.iter().find(|event| {
+            event["type"] == "message.updated"
+                && event["properties"]["info"]["time"]["completed"].is_number()
+                && event["properties"]["info"]["role"] == "assistant"
+        }) {
+            finalized = event["properties"]["info"].clone();
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(reasoning["text"], "Read the spec first.");
+    // Claude counts thinking as output; the streamed thinking is split out.
+    assert_eq!(
+        finalized["tokens"],
+        json!({"input": 50, "output": 15, "reasoning": 5, "cache": {"read": 0, "write": 0}})
+    );
+
+    let prompt_metas = dispatch
+        .posted()
+        .into_iter()
+        .filter(|posted| posted.server_id == server_id && posted.method() == Some("session/prompt"))
+        .map(|posted| posted.payload["params"]["_meta"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        prompt_metas.last(),
+        Some(&json!({"maxThinkingTokens": 16_000}))
+    );
+    let (_, turn) = send(
+        &app,
+        Method::GET,
+        &format!("/session/{session_id}/turn/{turn_id}"),
+        None,
+    )
+    .await;
+    assert_eq!(
+        turn["reasoning"],
+        json!({"variant": "high", "budgetTokens": 16_000})
+    );
+}
+
+#[tokio::test]
+async fn long_tool_outputs_are_capped_with_the_full_output_served_separately() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            part_output_max_bytes: 512,
+            ..OpenCodeAdapterConfig::default()
+        },
+    );
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+    let acp_session_id = format!("{server_id}-session");
+
+    dispatch.respond_after(
+        "session/prompt",
+        Duration::from_millis(200),
+        json!({"stopReason": "end_turn"}),
+    );
+    let prompt = tokio::spawn({
+        let app = app.clone();
+        let uri = format!("/session/{session_id}/message");
+        async move {
+            send(
+                &app,
+                Method::POST,
+                &uri,
+                Some(json!({"parts": [{"type": "text", "text": "cat it"}]})),
+            )
+            .await
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let full_output = "line of a very large file\n".repeat(400);
+    for update in [
+        json!({
+            "sessionUpdate": "tool_call",
+            "toolCallId": "call_big",
+            "title": "Bash",
+            "kind": "execute",
+            "rawInput": {"command": "cat big.txt"}
+        }),
+        json!({
+            "sessionUpdate": "tool_call_update",
+            "toolCallId": "call_big",
+            "status": "completed",
+            "content": [{"type": "content", "content": {"type": "text", "text": full_output}}]
+        }),
+    ] {
+        dispatch.session_update(&server_id, &acp_session_id, update);
+    }
+    let (status, _) = prompt.await.expect("prompt task");
+    assert_eq!(status, StatusCode::OK);
+
+    let mut part = Value::Null;
+    let mut completed = Value::Null;
+    for _ in 0..100 {
+        let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        for event in polled["events"].as_array().into_iter().flatten() {
+            match event["type"].as_str() {
+                Some("message.part.updated")
+                    if event["properties"]["part"]["callID"] == "call_big" =>
+                {
+                    part = event["properties"]["part"].clone();
+                }
+                Some("tool.completed") => completed = event["properties"].clone(),
+                _ => {}
+            }
+        }
+        if !part.is_null() && !completed.is_null() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let full_uri = format!("/part/{}/full", part["id"].as_str().expect("part id"));
+    let output = part["state"]["output"].as_str().expect("capped output");
+    assert!(output.len() <= 512, "output is {} bytes", output.len());
+    assert!(output.starts_with("line of a very large file\n"));
+    assert!(output.ends_with(&format!("full output at {full_uri}]")));
+    assert_eq!(
+        part["state"]["metadata"]["truncated"],
+        json!({
+            "bytes": full_output.len(),
+            "limit": 512,
+            "full": full_uri,
+        })
+    );
+    assert_eq!(completed["output"], output);
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri(&full_uri)
+                .body(Body::empty())
+                .expect("build request"),
+        )
+        .await
+        .expect("response");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/plain; charset=utf-8"
+    );
+    let body = response
+        .into_body()
+        .collect()
+        .await
+        .expect("collect body")
+        .to_bytes();
+    assert_eq!(body, full_output.as_bytes());
+
+    let (status, _) = send(&app, Method::GET, "/part/part_missing/full", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn strict_schema_mode_rejects_malformed_request_bodies() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let strict = adapter_with(
+        &dispatch,
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            strict_schemas: true,
+            ..OpenCodeAdapterConfig::default()
+        },
+    );
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/session")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({"titel": "typo", "dryRun": "yes"}).to_string(),
+        ))
+        .expect("build request");
+    let response = strict.clone().oneshot(request).await.expect("response");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/problem+json"
+    );
+    let bytes = response
+        .into_body()
+        .collect()
+        .await
+        .expect("collect body")
+        .to_bytes();
+    let problem: Value = serde_json::from_slice(&bytes).expect("problem json");
+    assert_eq!(problem["type"], "urn:sandbox-agent:error:invalid_request");
+    assert_eq!(problem["status"], 400);
+    assert_eq!(
+        problem["errors"],
+        json!([
+            {"pointer": "/dryRun", "message": "expected boolean or null, found string"},
+            {"pointer": "/titel", "message": "unknown field"},
+        ])
+    );
+
+    // Well-formed bodies, and omitted optional ones, still go through.
+    let (status, _) = send(&strict, Method::POST, "/session", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, session) = send(
+        &strict,
+        Method::POST,
+        "/session",
+        Some(json!({"title": "ok"})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = session["id"].as_str().expect("session id");
+    let (status, problem) = send(
+        &strict,
+        Method::POST,
+        &format!("/session/{session_id}/prompt_async"),
+        Some(json!({"parts": "hello"})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(problem["errors"][0]["pointer"], "/parts");
+
+    // Without strict mode the same body is accepted and the typo ignored.
+    let lenient_path = dir.path().join("lenient.db");
+    let lenient = adapter(&dispatch, lenient_path.to_str().expect("utf-8 path"));
+    let (status, session) = send(
+        &lenient,
+        Method::POST,
+        "/session",
+        Some(json!({"titel": "typo"})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(session["id"].is_string());
+
+    // A body that is JSON but not a valid one is still rejected.
+    let (status, _) = send(
+        &lenient,
+        Method::POST,
+        "/session",
+        Some(json!({"title": 5})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, _) = send(&lenient, Method::POST, "/session", Some(Value::Null)).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn agent_cancellation_withdraws_pending_permission_requests() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+
+    dispatch.notify(
+        &server_id,
+        json!({
+            "jsonrpc": "2.0",
+            "id": "perm_1",
+            "method": "session/request_permission",
+            "params": {
+                "sessionId": format!("{server_id}-session"),
+                "toolCall": {
+                    "toolCallId": "call_1",
+                    "title": "Bash",
+                    "kind": "execute",
+                    "rawInput": {"command": "cargo test"}
+                }
+            }
+        }),
+    );
+    let mut pending = json!([]);
+    for _ in 0..100 {
+        pending = send(&app, Method::GET, "/permission", None).await.1;
+        if pending
+            .as_array()
+            .is_some_and(|pending| !pending.is_empty())
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let request_id = pending[0]["id"]
+        .as_str()
+        .expect("permission id")
+        .to_string();
+
+    dispatch.notify(
+        &server_id,
+        json!({
+            "jsonrpc": "2.0",
+            "method": "$/cancelRequest",
+            "params": {"id": "perm_1"}
+        }),
+    );
+    let mut cancelled = None;
+    for _ in 0..100 {
+        let (_, events) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        cancelled = events["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|event| event["type"] == "permission.cancelled")
+            .cloned();
+        if cancelled.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let cancelled = cancelled.expect("permission.cancelled emitted");
+    assert_eq!(cancelled["properties"]["sessionID"], session_id);
+    assert_eq!(cancelled["properties"]["requestID"], request_id);
+    let (_, pending) = send(&app, Method::GET, "/permission", None).await;
+    assert_eq!(pending, json!([]));
+
+    // Replying to a withdrawn request is not forwarded to the agent.
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &format!("/permission/{request_id}/reply"),
+        Some(json!({"reply": "once"})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(dispatch
+        .posted()
+        .iter()
+        .all(|posted| posted.payload["id"] != "perm_1"));
+}
+
+#[tokio::test]
+async fn rejected_models_fall_back_along_the_provider_chain() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            model_fallbacks: [(
+                "claude".to_string(),
+                vec![
+                    "default".to_string(),
+                    "sonnet".to_string(),
+                    "haiku".to_string(),
+                ],
+            )]
+            .into_iter()
+            .collect(),
+            ..OpenCodeAdapterConfig::default()
+        },
+    );
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+
+    dispatch.respond_error("session/prompt", -32603, "model default is deprecated");
+    dispatch.respond_error("session/prompt", -32603, "Model quota exceeded for sonnet");
+    let (status, response) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/message"),
+        Some(json!({"parts": [{"type": "text", "text": "again"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(response["info"]["modelID"], "haiku");
+    assert_eq!(response["info"]["modelFallback"]["requested"], "default");
+    assert!(response["info"]["modelFallback"]["error"]
+        .as_str()
+        .is_some_and(|error| error.contains("sonnet")));
+
+    let prompt_models = dispatch
+        .posted()
+        .into_iter()
+        .filter(|posted| posted.server_id == server_id && posted.method() == Some("session/prompt"))
+        .map(|posted| posted.payload["params"]["_meta"]["sandboxagent.dev"]["model"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        prompt_models,
+        vec![Value::Null, Value::Null, json!("sonnet"), json!("haiku")]
+    );
+
+    // The next turn starts on the session's own model again, and fails once
+    // the chain is used up.
+    for model in ["default", "sonnet", "haiku"] {
+        dispatch.respond_error(
+            "session/prompt",
+            -32603,
+            &format!("model {model} not found"),
+        );
+    }
+    let (_, response) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/message"),
+        Some(json!({"parts": [{"type": "text", "text": "once more"}]})),
+    )
+    .await;
+    assert!(response["errors"][0]["message"]
+        .as_str()
+        .is_some_and(|error| error.contains("model haiku not found")));
+}
+
+#[tokio::test]
+async fn idle_sessions_go_dormant_and_rebootstrap_on_the_next_prompt() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            session_expiry: Some(SessionExpiryConfig {
+                idle_ttl: Duration::from_millis(200),
+                system_idle_ttl: Duration::from_millis(200),
+                interval: Duration::from_millis(20),
+            }),
+            ..OpenCodeAdapterConfig::default()
+        },
+    );
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+    // A turn that streams output settles the session.
+    dispatch.respond_after(
+        "session/prompt",
+        Duration::from_millis(100),
+        json!({"stopReason": "end_turn"}),
+    );
+    let prompt = tokio::spawn({
+        let app = app.clone();
+        let uri = format!("/session/{session_id}/message");
+        async move {
+            send(
+                &app,
+                Method::POST,
+                &uri,
+                Some(json!({"parts": [{"type": "text", "text": "hello again"}]})),
+            )
+            .await
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    dispatch.session_update(
+        &server_id,
+        &format!("{server_id}-session"),
+        json!({"sessionUpdate": "agent_message_chunk", "content": {"type": "text", "text": "hi"}}),
+    );
+    assert_eq!(prompt.await.expect("prompt").0, StatusCode::OK);
+
+    let (status, expiry) = send(&app, Method::GET, "/session/expiry", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(expiry["policy"]["idleTtlMs"], 200);
+    assert_eq!(expiry["sessions"][0]["sessionID"], session_id);
+    assert!(expiry["sessions"][0]["lastActivity"].is_i64());
+
+    let mut timer = Value::Null;
+    for _ in 0..100 {
+        timer = send(
+            &app,
+            Method::GET,
+            &format!("/session/{session_id}/expiry"),
+            None,
+        )
+        .await
+        .1;
+        if timer["dormant"] == true {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(timer["dormant"], true);
+    assert_eq!(timer["expiresAt"], Value::Null);
+    assert_eq!(dispatch.deleted(), vec![server_id.clone()]);
+    let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+    assert!(polled["events"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(|event| event["type"] == "session.dormant"
+            && event["properties"]["sessionID"] == session_id));
+    let (_, session) = send(&app, Method::GET, &format!("/session/{session_id}"), None).await;
+    assert!(session["dormantAt"].is_i64());
+
+    // The next prompt bootstraps a fresh agent and replays the history.
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/message"),
+        Some(json!({"parts": [{"type": "text", "text": "still there?"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let methods = dispatch.posted_methods(&server_id);
+    assert_eq!(
+        methods
+            .iter()
+            .filter(|method| *method == "initialize")
+            .count(),
+        2
+    );
+    let prompt = dispatch
+        .posted()
+        .into_iter()
+        .rfind(|posted| posted.method() == Some("session/prompt"))
+        .expect("prompt posted");
+    assert!(prompt.payload["params"]["prompt"][0]["text"]
+        .as_str()
+        .is_some_and(|text| text.contains("hello")));
+    let (_, session) = send(&app, Method::GET, &format!("/session/{session_id}"), None).await;
+    assert!(session.get("dormantAt").is_none());
+}
+
+#[tokio::test]
+async fn sessions_migrate_to_another_instance_with_a_cut_over_handshake() {
+    let source_dir = tempfile::tempdir().expect("tempdir");
+    let target_dir = tempfile::tempdir().expect("tempdir");
+    let source_dispatch = MockAcpDispatch::new();
+    let target_dispatch = MockAcpDispatch::new();
+    let source = adapter(
+        &source_dispatch,
+        source_dir
+            .path()
+            .join("opencode.db")
+            .to_str()
+            .expect("utf-8 path"),
+    );
+    let target = adapter(
+        &target_dispatch,
+        target_dir
+            .path()
+            .join("opencode.db")
+            .to_str()
+            .expect("utf-8 path"),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind target");
+    let target_url = format!("http://{}", listener.local_addr().expect("target address"));
+    let server = target.clone();
+    tokio::spawn(async move { axum::serve(listener, server).await });
+
+    let (session_id, server_id) = bootstrapped_session(&source, &source_dispatch).await;
+    let (status, _) = send(
+        &source,
+        Method::POST,
+        &format!("/session/{session_id}/context"),
+        Some(json!({"text": "the deadline is friday"})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, source_messages) = send(
+        &source,
+        Method::GET,
+        &format!("/session/{session_id}/message"),
+        None,
+    )
+    .await;
+
+    // A target that cannot be reached leaves the session where it was.
+    let (status, body) = send(
+        &source,
+        Method::POST,
+        &format!("/session/{session_id}/migrate"),
+        Some(json!({"target": "http://127.0.0.1:1"})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(body["errors"][0]["name"], "SessionMigrationError");
+    let (_, session) = send(
+        &source,
+        Method::GET,
+        &format!("/session/{session_id}"),
+        None,
+    )
+    .await;
+    assert_eq!(session.get("migratedTo"), None);
+
+    let (status, migrated) = send(
+        &source,
+        Method::POST,
+        &format!("/session/{session_id}/migrate"),
+        Some(json!({"target": format!("{target_url}/")})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{migrated}");
+    assert_eq!(migrated["target"], target_url);
+    let import_id = migrated["importID"]
+        .as_str()
+        .expect("import id")
+        .to_string();
+
+    // The source hands the session over and stops its agent.
+    let (_, session) = send(
+        &source,
+        Method::GET,
+        &format!("/session/{session_id}"),
+        None,
+    )
+    .await;
+    assert_eq!(session["migratedTo"], target_url);
+    assert_eq!(source_dispatch.deleted(), vec![server_id]);
+    let (status, body) = send(
+        &source,
+        Method::POST,
+        &format!("/session/{session_id}/message"),
+        Some(json!({"parts": [{"type": "text", "text": "anyone?"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::GONE);
+    assert_eq!(body["errors"][0]["name"], "SessionMigratedError");
+    assert_eq!(body["errors"][0]["data"]["target"], target_url);
+    let (_, polled) = send(&source, Method::GET, "/event/poll?since=0", None).await;
+    assert!(polled["events"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(|event| event["type"] == "session.migrated"
+            && event["properties"]["importID"] == import_id.as_str()));
+
+    // The target serves the history and pending context, and its own agent
+    // picks up the conversation.
+    let (status, session) = send(
+        &target,
+        Method::GET,
+        &format!("/session/{session_id}"),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(session["dormantAt"].is_i64());
+    let (_, target_messages) = send(
+        &target,
+        Method::GET,
+        &format!("/session/{session_id}/message"),
+        None,
+    )
+    .await;
+    assert_eq!(target_messages, source_messages);
+    let (_, context) = send(
+        &target,
+        Method::GET,
+        &format!("/session/{session_id}/context"),
+        None,
+    )
+    .await;
+    assert_eq!(context[0]["text"], "the deadline is friday");
+    let (status, _) = send(
+        &target,
+        Method::POST,
+        &format!("/session/{session_id}/message"),
+        Some(json!({"parts": [{"type": "text", "text": "still there?"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let prompt = target_dispatch
+        .posted()
+        .into_iter()
+        .rfind(|posted| posted.method() == Some("session/prompt"))
+        .expect("prompt posted");
+    let prompt_text = prompt.payload["params"]["prompt"].to_string();
+    assert!(prompt_text.contains("hello"), "{prompt_text}");
+    assert!(
+        prompt_text.contains("the deadline is friday"),
+        "{prompt_text}"
+    );
+
+    // The committed import can no longer be aborted.
+    let (status, _) = send(
+        &target,
+        Method::DELETE,
+        &format!("/session/import/{import_id}"),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn agent_parts_delegate_to_sub_agents_in_linked_child_sessions() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+    let acp_session_id = format!("{server_id}-session");
+
+    dispatch.respond_after(
+        "session/prompt",
+        Duration::from_millis(300),
+        json!({"stopReason": "end_turn"}),
+    );
+    let prompt = tokio::spawn({
+        let app = app.clone();
+        let uri = format!("/session/{session_id}/message");
+        async move {
+            send(
+                &app,
+                Method::POST,
+                &uri,
+                Some(json!({"parts": [
+                    {"type": "text", "text": "map the crate"},
+                    {"type": "agent", "name": "explore", "source": {"value": "@explore", "start": 14, "end": 22}},
+                ]})),
+            )
+            .await
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    for update in [
+        json!({
+            "sessionUpdate": "tool_call",
+            "toolCallId": "call_task",
+            "title": "Task",
+            "kind": "think",
+            "rawInput": {"subagent_type": "explore", "description": "Map crate", "prompt": "List the modules"}
+        }),
+        json!({
+            "sessionUpdate": "tool_call_update",
+            "toolCallId": "call_task",
+            "status": "completed",
+            "content": [{"type": "content", "content": {"type": "text", "text": "Three modules"}}]
+        }),
+    ] {
+        dispatch.session_update(&server_id, &acp_session_id, update);
+    }
+    let (status, _) = prompt.await.expect("prompt task");
+    assert_eq!(status, StatusCode::OK);
+
+    // The agent is told which sub-agent to use; the user message keeps the part.
+    let prompt_payload = dispatch
+        .posted()
+        .into_iter()
+        .rfind(|posted| posted.server_id == server_id && posted.method() == Some("session/prompt"))
+        .expect("session/prompt posted")
+        .payload;
+    let prompt_text = prompt_payload["params"]["prompt"]
+        .as_array()
+        .expect("prompt blocks")
+        .iter()
+        .filter_map(|block| block["text"].as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    assert!(
+        prompt_text.contains("subagent_type \"explore\""),
+        "{prompt_text}"
+    );
+    let (_, messages) = send(
+        &app,
+        Method::GET,
+        &format!("/session/{session_id}/message"),
+        None,
+    )
+    .await;
+    let user = messages
+        .as_array()
+        .expect("messages")
+        .iter()
+        .rfind(|message| message["info"]["role"] == "user")
+        .expect("user message");
+    assert_eq!(user["parts"][1]["type"], "agent");
+    assert_eq!(user["parts"][1]["name"], "explore");
+
+    // The task call links a child session recording the sub-agent's work.
+    let mut created = Value::Null;
+    let mut child_id = Value::Null;
+    for _ in 0..100 {
+        let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        for event in polled["events"].as_array().into_iter().flatten() {
+            match event["type"].as_str() {
+                Some("session.created")
+                    if event["properties"]["info"]["parentID"] == session_id =>
+                {
+                    created = event["properties"].clone();
+                }
+                Some("message.part.updated")
+                    if event["properties"]["part"]["callID"] == "call_task"
+                        && event["properties"]["part"]["state"]["status"] == "running" =>
+                {
+                    child_id =
+                        event["properties"]["part"]["state"]["metadata"]["sessionId"].clone();
+                }
+                _ => {}
+            }
+        }
+        if !created.is_null() && !child_id.is_null() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(created["info"]["id"], child_id);
+    assert_eq!(created["info"]["title"], "Map crate (@explore subagent)");
+    assert_eq!(created["parent"]["callID"], "call_task");
+    let child_id = child_id.as_str().expect("child session id");
+
+    let (_, children) = send(
+        &app,
+        Method::GET,
+        &format!("/session/{session_id}/children"),
+        None,
+    )
+    .await;
+    assert_eq!(children[0]["id"], child_id);
+    let (_, child_messages) = send(
+        &app,
+        Method::GET,
+        &format!("/session/{child_id}/message"),
+        None,
+    )
+    .await;
+    let child_texts = child_messages
+        .as_array()
+        .expect("child messages")
+        .iter()
+        .map(|message| {
+            (
+                message["info"]["role"].clone(),
+                message["parts"][0]["text"].clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        child_texts,
+        vec![
+            (json!("user"), json!("List the modules")),
+            (json!("assistant"), json!("Three modules")),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn system_overrides_reach_the_agent_and_are_recorded_on_the_turn() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+
+    let (status, turn) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/prompt_async"),
+        Some(json!({
+            "system": "Answer in French.",
+            "parts": [{"type": "text", "text": "hello"}],
+        })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let turn_id = turn["id"].as_str().expect("turn id").to_string();
+    let mut turn = Value::Null;
+    for _ in 0..100 {
+        (_, turn) = send(
+            &app,
+            Method::GET,
+            &format!("/session/{session_id}/turn/{turn_id}"),
+            None,
+        )
+        .await;
+        if turn["status"] == "completed" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(turn["status"], "completed");
+    assert_eq!(turn["systemPrompt"]["mechanism"], "claudeSystemPrompt");
+
+    let prompt_metas = dispatch
+        .posted()
+        .into_iter()
+        .filter(|posted| posted.server_id == server_id && posted.method() == Some("session/prompt"))
+        .map(|posted| posted.payload["params"]["_meta"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        prompt_metas.last(),
+        Some(&json!({"systemPrompt": "Answer in French."}))
+    );
+}
+
+#[tokio::test]
+async fn redacted_messages_leave_the_session_exports_and_agent_replays() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let sqlite_path = sqlite_path.to_str().expect("utf-8 path");
+    let dispatch = MockAcpDispatch::new();
+    let config = || OpenCodeAdapterConfig {
+        session_expiry: Some(SessionExpiryConfig {
+            idle_ttl: Duration::from_millis(200),
+            system_idle_ttl: Duration::from_millis(200),
+            interval: Duration::from_millis(20),
+        }),
+        ..OpenCodeAdapterConfig::default()
+    };
+    let app = adapter_with(&dispatch, sqlite_path, config());
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+    // A turn that streams output settles the session.
+    dispatch.respond_after(
+        "session/prompt",
+        Duration::from_millis(100),
+        json!({"stopReason": "end_turn"}),
+    );
+    let prompt = tokio::spawn({
+        let app = app.clone();
+        let uri = format!("/session/{session_id}/message");
+        async move {
+            send(
+                &app,
+                Method::POST,
+                &uri,
+                Some(json!({"parts": [{"type": "text", "text": "my card is 4111"}]})),
+            )
+            .await
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    dispatch.session_update(
+        &server_id,
+        &format!("{server_id}-session"),
+        json!({"sessionUpdate": "agent_message_chunk", "content": {"type": "text", "text": "noted"}}),
+    );
+    assert_eq!(prompt.await.expect("prompt").0, StatusCode::OK);
+
+    for _ in 0..100 {
+        let (_, statuses) = send(&app, Method::GET, "/session/status", None).await;
+        if statuses[&session_id]["type"] == "idle" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let messages_uri = format!("/session/{session_id}/message");
+    let (_, messages) = send(&app, Method::GET, &messages_uri, None).await;
+    let message_id = messages
+        .as_array()
+        .expect("messages")
+        .iter()
+        .find(|message| message["parts"][0]["text"] == "my card is 4111")
+        .and_then(|message| message["info"]["id"].as_str())
+        .expect("message to redact")
+        .to_string();
+    let message_uri = format!("{messages_uri}/{message_id}");
+
+    let (status, body) = send(&app, Method::DELETE, &message_uri, None).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(
+        send(&app, Method::GET, &message_uri, None).await.0,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        send(&app, Method::DELETE, &message_uri, None).await.0,
+        StatusCode::NOT_FOUND
+    );
+    let (_, messages) = send(&app, Method::GET, &messages_uri, None).await;
+    assert!(!messages.to_string().contains("4111"));
+    let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+    assert!(!polled.to_string().contains("4111"));
+    assert!(polled["events"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(|event| event["type"] == "message.removed"
+            && event["properties"]["messageID"] == message_id.as_str()));
+
+    // The agent goes dormant; the history replayed to its successor leaves
+    // the message out.
+    for _ in 0..100 {
+        if dispatch.deleted().contains(&server_id) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(dispatch.deleted(), vec![server_id.clone()]);
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &messages_uri,
+        Some(json!({"parts": [{"type": "text", "text": "still there?"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let prompt = dispatch
+        .posted()
+        .into_iter()
+        .rfind(|posted| posted.method() == Some("session/prompt"))
+        .expect("prompt posted");
+    let replay = prompt.payload["params"]["prompt"][0]["text"]
+        .as_str()
+        .expect("replay text");
+    assert!(replay.contains("hello"), "{replay}");
+    assert!(!replay.contains("4111"), "{replay}");
+
+    // The persisted log no longer holds it either.
+    let request = Request::builder()
+        .uri("/admin/export/events?since=0")
+        .body(Body::empty())
+        .expect("build request");
+    let response = app.clone().oneshot(request).await.expect("response");
+    let bytes = response
+        .into_body()
+        .collect()
+        .await
+        .expect("collect body")
+        .to_bytes();
+    let export =
+        String::from_utf8(zstd::decode_all(bytes.as_ref()).expect("zstd frame")).expect("utf-8");
+    assert!(!export.contains("4111"));
+    assert!(export.contains("_sandboxagent/opencode/message_redacted"));
+
+    let restarted = adapter(&dispatch, sqlite_path);
+    let (_, messages) = send(&restarted, Method::GET, &messages_uri, None).await;
+    assert!(!messages.to_string().contains("4111"));
+    assert!(messages.to_string().contains("still there?"));
+}
+
+#[tokio::test]
+async fn prompts_rebind_sessions_whose_agent_instance_is_gone() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+
+    // The agent process dies behind the adapter's back; the next prompt
+    // bootstraps a new one within the same request and replays the history.
+    dispatch.stop_instance(&server_id);
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/message"),
+        Some(json!({"parts": [{"type": "text", "text": "still there?"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        dispatch.posted_methods(&server_id),
+        vec![
+            "initialize",
+            "session/new",
+            "session/prompt",
+            "initialize",
+            "session/new",
+            "session/prompt"
+        ]
+    );
+    let prompt = dispatch
+        .posted()
+        .into_iter()
+        .rfind(|posted| posted.method() == Some("session/prompt"))
+        .expect("prompt posted");
+    assert!(prompt.payload["params"]["prompt"][0]["text"]
+        .as_str()
+        .is_some_and(|text| text.contains("hello")));
+    assert!(dispatch.deleted().is_empty());
+}
+
+#[test]
+fn recently_active_sessions_are_prewarmed_on_startup() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let sqlite_path = sqlite_path.to_str().expect("utf-8 path");
+    let dispatch = MockAcpDispatch::new();
+    let (session_id, server_id) = runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        bootstrapped_session(&app, &dispatch).await
+    });
+    // The whole server restarted, taking the agent process with it.
+    dispatch.stop_instance(&server_id);
+
+    runtime().block_on(async {
+        let app = adapter_with(
+            &dispatch,
+            sqlite_path,
+            OpenCodeAdapterConfig {
+                session_prewarm: Some(SessionPrewarmConfig {
+                    window: Duration::from_secs(60),
+                    max_sessions: 4,
+                }),
+                ..OpenCodeAdapterConfig::default()
+            },
+        );
+        let (status, _) = send(&app, Method::GET, "/session", None).await;
+        assert_eq!(status, StatusCode::OK);
+        for _ in 0..100 {
+            if dispatch.posted().len() == 4 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        // The session was re-bound to a new agent session, whose process is
+        // already running.
+        let prewarm = dispatch.posted().pop().expect("prewarm posted");
+        assert_eq!(prewarm.method(), Some("initialize"));
+        assert_eq!(prewarm.bootstrap_agent.as_deref(), Some("claude"));
+        let rebound_id = prewarm.server_id;
+        assert_ne!(rebound_id, server_id);
+
+        // The first prompt opens a session on the running process.
+        let (status, _) = send(
+            &app,
+            Method::POST,
+            &format!("/session/{session_id}/message"),
+            Some(json!({"parts": [{"type": "text", "text": "still there?"}]})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            dispatch.posted_methods(&rebound_id),
+            vec!["initialize", "initialize", "session/new", "session/prompt"]
+        );
+        let prompt = dispatch.posted().pop().expect("prompt posted");
+        assert!(prompt.payload["params"]["prompt"][0]["text"]
+            .as_str()
+            .is_some_and(|text| text.contains("hello")));
+    });
+}
+
+#[tokio::test]
+async fn prompts_failing_preflight_are_rejected_before_reaching_the_agent() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            model_catalog: Some(ModelCatalogConfig {
+                refresh_interval: None,
+                ..ModelCatalogConfig::default()
+            }),
+            ..OpenCodeAdapterConfig::default()
+        },
+    );
+    let (status, session) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = session["id"].as_str().expect("session id");
+
+    let (status, problem) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/message"),
+        Some(json!({
+            "agent": "claude",
+            "parts": [
+                {"type": "text", "text": "hello"},
+                {"type": "text", "text": " "},
+                {"type": "subtask", "prompt": "explore"},
+                {"type": "file", "mime": "image/png", "url": "data:image/png;base64,%%"},
+            ]
+        })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(problem["type"], "urn:sandbox-agent:error:invalid_request");
+    assert_eq!(
+        problem["errors"],
+        json!([
+            {"pointer": "/parts/1/text", "partIndex": 1, "reason": "empty_text", "message": "text part is empty"},
+            {
+                "pointer": "/parts/2/type",
+                "partIndex": 2,
+                "reason": "unsupported_part_type",
+                "message": "claude does not accept subtask parts; it accepts text, file, agent"
+            },
+            {
+                "pointer": "/parts/3/url",
+                "partIndex": 3,
+                "reason": "invalid_data_url",
+                "message": "file part url is not valid base64"
+            },
+        ])
+    );
+
+    // A prompt estimated over the model's context window fails as a whole.
+    let (status, problem) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/message"),
+        Some(json!({
+            "agent": "claude",
+            "model": {"providerID": "claude", "modelID": "sonnet"},
+            "parts": [{"type": "text", "text": "word ".repeat(200_000)}]
+        })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(problem["errors"][0]["reason"], "context_exceeded");
+    assert_eq!(problem["errors"][0]["pointer"], "/parts");
+    assert!(problem["errors"][0].get("partIndex").is_none());
+    assert!(dispatch.posted().is_empty());
+
+    // Nothing was recorded; a valid prompt goes through.
+    let (status, messages) = send(
+        &app,
+        Method::GET,
+        &format!("/session/{session_id}/message"),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(messages, json!([]));
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/message"),
+        Some(json!({"agent": "claude", "parts": [{"type": "text", "text": "hello"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn bursts_of_similar_permission_requests_are_batched() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            permission_batch_window: Duration::from_secs(60),
+            ..OpenCodeAdapterConfig::default()
+        },
+    );
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+
+    let edit = |id: &str, path: &str| {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "session/request_permission",
+            "params": {
+                "sessionId": format!("{server_id}-session"),
+                "toolCall": {
+                    "toolCallId": format!("call_{id}"),
+                    "title": format!("Write {path}"),
+                    "kind": "edit",
+                    "locations": [{"path": path}]
+                }
+            }
+        })
+    };
+    dispatch.notify(&server_id, edit("perm_a", "/work/src/a.rs"));
+    dispatch.notify(&server_id, edit("perm_b", "/work/src/b.rs"));
+    dispatch.notify(&server_id, edit("perm_c", "/work/src/c.rs"));
+    dispatch.notify(&server_id, edit("perm_d", "/work/docs/d.md"));
+
+    let mut pending = json!([]);
+    for _ in 0..100 {
+        pending = send(&app, Method::GET, "/permission", None).await.1;
+        let batched = pending
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(|request| request["metadata"]["batch"]["count"] == 3);
+        if batched && pending.as_array().is_some_and(|pending| pending.len() == 2) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let pending = pending.as_array().expect("pending permissions");
+    assert_eq!(pending.len(), 2, "{pending:?}");
+    let batch = pending
+        .iter()
+        .find(|request| request["metadata"]["batch"].is_object())
+        .expect("aggregated request");
+    assert_eq!(batch["sessionID"], session_id);
+    assert_eq!(batch["metadata"]["toolKind"], "edit");
+    assert_eq!(batch["patterns"], json!(["/work/src/*"]));
+    assert_eq!(batch["always"], json!(["/work/src/*"]));
+    let batch_id = batch["id"].as_str().expect("batch id").to_string();
+
+    // The aggregated request was surfaced once, and the requests it stands
+    // for were marked as batched.
+    let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+    let events = polled["events"].as_array().expect("events");
+    let asked = events
+        .iter()
+        .filter(|event| event["type"] == "permission.asked")
+        .count();
+    assert_eq!(
+        asked, 4,
+        "first request, batch formed, batch grown, other dir"
+    );
+    let batched = events
+        .iter()
+        .filter(|event| {
+            event["type"] == "permission.batched" && event["properties"]["batchID"] == batch_id
+        })
+        .count();
+    assert_eq!(batched, 3);
+
+    // One "always" answers every underlying ACP request...
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &format!("/permission/{batch_id}/reply"),
+        Some(json!({"reply": "always"})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let answered = dispatch
+        .posted()
+        .into_iter()
+        .filter(|posted| posted.payload["result"]["outcome"] == "selected")
+        .map(|posted| {
+            posted.payload["id"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(answered, vec!["perm_a", "perm_b", "perm_c"]);
+    let (_, pending) = send(&app, Method::GET, "/permission", None).await;
+    assert_eq!(pending.as_array().map(Vec::len), Some(1));
+    assert_eq!(pending[0]["metadata"]["filepath"], "/work/docs/d.md");
+
+    // ...and approves later files in the directory.
+    dispatch.notify(&server_id, edit("perm_e", "/work/src/e.rs"));
+    let mut auto = None;
+    for _ in 0..100 {
+        auto = dispatch
+            .posted()
+            .into_iter()
+            .find(|posted| posted.payload["id"] == "perm_e");
+        if auto.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let auto = auto.expect("auto-approved");
+    assert_eq!(
+        auto.payload["result"]["selectedOption"]["kind"],
+        "allow_always"
+    );
+}
+
+#[tokio::test]
+async fn replay_stats_show_what_a_resumed_agent_would_be_told() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let (session_id, _) = bootstrapped_session(&app, &dispatch).await;
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{session_id}/message"),
+        Some(json!({"parts": [{"type": "text", "text": "and then?"}]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, stats) = send(
+        &app,
+        Method::GET,
+        &format!("/session/{session_id}/replay"),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(stats["maxEvents"], 50);
+    assert_eq!(stats["maxChars"], 12_000);
+    let total = stats["totalEvents"].as_u64().expect("total events");
+    assert!(total > 2, "{stats}");
+    assert_eq!(stats["selectedEvents"], total);
+    assert_eq!(stats["kinds"]["user_prompt"]["weight"], 100);
+    assert_eq!(stats["kinds"]["user_prompt"]["selectedEvents"], 2);
+
+    // A tight budget keeps the prompts over the protocol bookkeeping.
+    let (status, tight) = send(
+        &app,
+        Method::GET,
+        &format!("/session/{session_id}/replay?maxEvents=2"),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(tight["selectedEvents"], 2);
+    assert_eq!(tight["kinds"]["user_prompt"]["selectedEvents"], 2);
+    assert_eq!(tight["kinds"]["other"]["selectedEvents"], 0);
+
+    let (status, _) = send(&app, Method::GET, "/session/ses_missing/replay", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn cursor_sessions_run_on_the_cursor_agent() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+
+    let (_, providers) = send(&app, Method::GET, "/provider", None).await;
+    assert_eq!(providers["default"]["cursor"], "auto");
+    assert!(providers["connected"]
+        .as_array()
+        .expect("connected providers")
+        .contains(&json!("cursor")));
+
+    for prompt in [
+        json!({"agent": "cursor", "parts": [{"type": "text", "text": "hello"}]}),
+        json!({"modelID": "composer-1", "parts": [{"type": "text", "text": "hello"}]}),
+    ] {
+        let (_, session) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+        let session_id = session["id"].as_str().expect("session id").to_string();
+        let posted = dispatch.posted().len();
+        let (status, message) = send(
+            &app,
+            Method::POST,
+            &format!("/session/{session_id}/message"),
+            Some(prompt),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(message["info"]["providerID"], "cursor");
+        let initialize = dispatch.posted()[posted..]
+            .iter()
+            .find(|posted| posted.method() == Some("initialize"))
+            .cloned()
+            .expect("agent bootstrapped");
+        assert_eq!(initialize.bootstrap_agent.as_deref(), Some("cursor"));
+    }
+}
+
+#[tokio::test]
+async fn prioritized_sessions_take_turn_slots_first() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            max_concurrent_turns: 1,
+            ..OpenCodeAdapterConfig::default()
+        },
+    );
+
+    let mut sessions = Vec::new();
+    for priority in ["normal", "background", "interactive"] {
+        let (status, session) = send(
+            &app,
+            Method::POST,
+            "/session",
+            Some(json!({"priority": priority})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let session_id = session["id"].as_str().expect("session id").to_string();
+        let posted = dispatch.posted().len();
+        let (status, _) = send(
+            &app,
+            Method::POST,
+            &format!("/session/{session_id}/message"),
+            Some(json!({"agent": "claude", "parts": [{"type": "text", "text": "hello"}]})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let server_id = dispatch.posted()[posted].server_id.clone();
+        sessions.push((session_id, server_id, session));
+    }
+    let [(normal, normal_server, normal_info), (background, background_server, background_info), (interactive, interactive_server, _)] =
+        &sessions[..]
+    else {
+        unreachable!();
+    };
+    assert_eq!(normal_info.get("priority"), None);
+    assert_eq!(background_info["priority"], "background");
+    assert_eq!(dispatch.launch_priority(normal_server), None);
+    assert_eq!(
+        dispatch.launch_priority(background_server),
+        Some(SessionPriority::Background)
+    );
+    assert_eq!(
+        dispatch.launch_priority(interactive_server),
+        Some(SessionPriority::Interactive)
+    );
+
+    // The normal session holds the only slot while the others queue up.
+    dispatch.respond_after(
+        "session/prompt",
+        Duration::from_millis(300),
+        json!({"stopReason": "end_turn"}),
+    );
+    let prompts = |server_id: &str| {
+        dispatch
+            .posted()
+            .iter()
+            .filter(|posted| posted.server_id == server_id)
+            .filter(|posted| posted.method() == Some("session/prompt"))
+            .count()
+    };
+    let mut turns = Vec::new();
+    for session_id in [normal, background, interactive] {
+        let (status, turn) = send(
+            &app,
+            Method::POST,
+            &format!("/session/{session_id}/prompt_async"),
+            Some(json!({"parts": [{"type": "text", "text": "again"}]})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        turns.push((
+            session_id,
+            turn["id"].as_str().expect("turn id").to_string(),
+        ));
+        if session_id == normal {
+            for _ in 0..100 {
+                if prompts(normal_server) == 2 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        } else {
+            assert_eq!(turn["status"], "queued");
+        }
+    }
+
+    for (session_id, turn_id) in &turns {
+        let mut turn = Value::Null;
+        for _ in 0..200 {
+            (_, turn) = send(
+                &app,
+                Method::GET,
+                &format!("/session/{session_id}/turn/{turn_id}"),
+                None,
+            )
+            .await;
+            if turn["status"] == "completed" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(turn["status"], "completed");
+    }
+
+    let order = dispatch
+        .posted()
+        .iter()
+        .filter(|posted| posted.method() == Some("session/prompt"))
+        .map(|posted| posted.server_id.clone())
+        .skip(3)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        order,
+        vec![
+            normal_server.clone(),
+            interactive_server.clone(),
+            background_server.clone()
+        ]
+    );
+}
+
+#[tokio::test]
+async fn native_sidecar_hiccups_are_retried_and_fallbacks_are_flagged() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // The sidecar answers 503 while `failing` is above zero.
+    let failing = Arc::new(AtomicUsize::new(2));
+    let sidecar = Router::new().route(
+        "/command",
+        axum::routing::get({
+            let failing = failing.clone();
+            move || async move {
+                let remaining = failing.load(Ordering::SeqCst);
+                if remaining > 0 {
+                    failing.store(remaining - 1, Ordering::SeqCst);
+                    return (StatusCode::SERVICE_UNAVAILABLE, axum::Json(json!({})));
+                }
+                (StatusCode::OK, axum::Json(json!([{"name": "native"}])))
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind sidecar");
+    let base_url = format!("http://{}", listener.local_addr().expect("sidecar addr"));
+    tokio::spawn(async move { axum::serve(listener, sidecar).await });
+
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            native_proxy_base_url: Some(base_url),
+            ..OpenCodeAdapterConfig::default()
+        },
+    );
+    let list_commands = || async {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/command")
+                    .body(Body::empty())
+                    .expect("build request"),
+            )
+            .await
+            .expect("response");
+        let fallback = response.headers().get("x-sandboxagent-fallback").cloned();
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .expect("collect body")
+            .to_bytes();
+        let body: Value = serde_json::from_slice(&body).expect("json body");
+        (body, fallback)
+    };
+
+    // Two 503s are within the GET retry budget.
+    let (body, fallback) = list_commands().await;
+    assert_eq!(body, json!([{"name": "native"}]));
+    assert_eq!(fallback, None);
+
+    // A sidecar that keeps failing gets the local answer, flagged as such.
+    failing.store(usize::MAX, Ordering::SeqCst);
+    let (body, fallback) = list_commands().await;
+    assert_eq!(body, json!([]));
+    assert_eq!(fallback.expect("fallback header"), "true");
+
+    let (_, health) = send(&app, Method::GET, "/global/health", None).await;
+    assert_eq!(
+        health["checks"]["nativeSidecar"]["circuit"]["state"],
+        "closed"
+    );
+    assert_eq!(health["checks"]["nativeSidecar"]["circuit"]["failures"], 1);
+
+    // Repeated failures open the circuit, which skips the sidecar entirely.
+    for _ in 0..4 {
+        list_commands().await;
+    }
+    let (_, health) = send(&app, Method::GET, "/global/health", None).await;
+    assert_eq!(health["status"], "degraded");
+    assert_eq!(
+        health["checks"]["nativeSidecar"]["circuit"]["state"],
+        "open"
+    );
+    failing.store(0, Ordering::SeqCst);
+    let (body, fallback) = list_commands().await;
+    assert_eq!(body, json!([]));
+    assert_eq!(fallback.expect("fallback header"), "true");
+}
+
+#[tokio::test]
+async fn system_sessions_are_listed_apart_and_labelled() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+
+    let (_, user) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+    let (status, system) = send(
+        &app,
+        Method::POST,
+        "/session",
+        Some(json!({"kind": "system", "title": "Clean workspace"})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(user.get("kind"), None);
+    assert_eq!(system["kind"], "system");
+    let ids = |sessions: &Value| {
+        sessions
+            .as_array()
+            .expect("sessions")
+            .iter()
+            .map(|session| session["id"].as_str().expect("id").to_string())
+            .collect::<Vec<_>>()
+    };
+    let user_id = user["id"].as_str().expect("id").to_string();
+    let system_id = system["id"].as_str().expect("id").to_string();
+
+    let (_, listed) = send(&app, Method::GET, "/session", None).await;
+    assert_eq!(ids(&listed), vec![user_id.clone()]);
+    let (_, listed) = send(&app, Method::GET, "/session?kind=system", None).await;
+    assert_eq!(ids(&listed), vec![system_id.clone()]);
+    let (_, listed) = send(&app, Method::GET, "/session?kind=all", None).await;
+    assert_eq!(ids(&listed).len(), 2);
+    let (_, listed) = send(&app, Method::GET, "/session?limit=10", None).await;
+    assert_eq!(ids(&listed), vec![user_id.clone()]);
+    let (_, listed) = send(&app, Method::GET, "/session?limit=10&kind=system", None).await;
+    assert_eq!(ids(&listed), vec![system_id.clone()]);
+    let (status, _) = send(&app, Method::GET, "/session?kind=robot", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, _) = send(&app, Method::GET, &format!("/session/{system_id}"), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+    let created = polled["events"]
+        .as_array()
+        .expect("events")
+        .iter()
+        .filter(|event| event["type"] == "session.created")
+        .map(|event| {
+            (
+                event["properties"]["info"]["id"].clone(),
+                event["properties"].get("sessionKind").cloned(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        created,
+        vec![
+            (json!(user_id), None),
+            (json!(system_id), Some(json!("system")))
+        ]
+    );
+}
+
+#[tokio::test]
+async fn session_quotas_reject_creates_over_the_limits() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            session_quota: Some(SessionQuotaConfig {
+                max_live_sessions: 2,
+                max_sessions_per_hour: 3,
+                ..SessionQuotaConfig::default()
+            }),
+            ..OpenCodeAdapterConfig::default()
+        },
+    );
+
+    let (_, first) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+    let first_id = first["id"].as_str().expect("id").to_string();
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        &format!("/session/{first_id}/fork"),
+        Some(json!({})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["errors"][0]["name"], "SessionQuotaExceededError");
+    assert_eq!(
+        body["errors"][0]["data"],
+        json!({"limit": "liveSessions", "max": 2, "current": 2})
+    );
+
+    // Deleting a session frees a live slot but not the hourly one.
+    let (status, _) = send(&app, Method::DELETE, &format!("/session/{first_id}"), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, quota) = send(&app, Method::GET, "/admin/quota", None).await;
+    assert_eq!(
+        quota["limits"],
+        json!({"liveSessions": 2, "sessionsPerHour": 3, "totalEvents": null})
+    );
+    assert_eq!(
+        quota["usage"],
+        json!({"liveSessions": 2, "sessionsLastHour": 3, "totalEvents": 0})
+    );
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/session")
+        .header("content-type", "application/json")
+        .body(Body::from("{}"))
+        .expect("build request");
+    let response = app.clone().oneshot(request).await.expect("response");
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let (_, listed) = send(&app, Method::GET, "/session", None).await;
+    let remaining = listed[0]["id"].as_str().expect("id").to_string();
+    send(&app, Method::DELETE, &format!("/session/{remaining}"), None).await;
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/session")
+        .header("content-type", "application/json")
+        .body(Body::from("{}"))
+        .expect("build request");
+    let response = app.clone().oneshot(request).await.expect("response");
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = response.headers()["retry-after"]
+        .to_str()
+        .expect("retry-after")
+        .parse()
+        .expect("seconds");
+    assert!(retry_after > 3500 && retry_after <= 3600, "{retry_after}");
+
+    let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+    let exceeded = polled["events"]
+        .as_array()
+        .expect("events")
+        .iter()
+        .filter(|event| event["type"] == "quota.exceeded")
+        .map(|event| event["properties"]["limit"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        exceeded,
+        vec![
+            json!("liveSessions"),
+            json!("liveSessions"),
+            json!("sessionsPerHour")
+        ]
+    );
+}
+
+#[tokio::test]
+async fn quota_event_totals_follow_events_written_and_deleted() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let sqlite_path = sqlite_path.to_str().expect("utf-8 path");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path);
+    // The quota's total next to the number of events an export holds.
+    let totals = |app: Router| async move {
+        let (_, quota) = send(&app, Method::GET, "/admin/quota", None).await;
+        let request = Request::builder()
+            .uri("/admin/export/events?since=0")
+            .body(Body::empty())
+            .expect("build request");
+        let response = app.oneshot(request).await.expect("response");
+        let bytes = response
+            .into_body()
+            .collect()
+            .await
+            .expect("collect body")
+            .to_bytes();
+        let export = String::from_utf8(zstd::decode_all(bytes.as_ref()).expect("zstd frame"))
+            .expect("utf-8");
+        (
+            quota["usage"]["totalEvents"].as_u64().expect("total"),
+            export.lines().count() as u64,
+        )
+    };
+
+    bootstrapped_session(&app, &dispatch).await;
+    let (first, exported) = totals(app.clone()).await;
+    assert!(first > 0);
+    assert_eq!(first, exported);
+
+    let (session_id, _) = bootstrapped_session(&app, &dispatch).await;
+    let (both, exported) = totals(app.clone()).await;
+    assert!(both > first);
+    assert_eq!(both, exported);
+
+    let (status, _) = send(
+        &app,
+        Method::DELETE,
+        &format!("/session/{session_id}"),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (remaining, exported) = totals(app.clone()).await;
+    assert!(remaining < both);
+    assert_eq!(remaining, exported);
+    let (_, health) = send(&app, Method::GET, "/global/health", None).await;
+    assert_eq!(health["checks"]["sqlite"]["status"], "ok");
+    assert_eq!(health["checks"]["sqlite"]["events"], remaining);
+
+    // The total is kept in the database, so a restarted adapter goes on
+    // from it.
+    let restarted = adapter(&MockAcpDispatch::new(), sqlite_path);
+    let (total, exported) = totals(restarted).await;
+    assert!(total >= remaining);
+    assert_eq!(total, exported);
+}
+
+#[tokio::test]
+async fn cloned_workspaces_become_session_directories() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let origin = dir.path().join("origin");
+    std::fs::create_dir_all(origin.join("app/src")).expect("create origin");
+    std::fs::create_dir_all(origin.join("docs")).expect("create origin");
+    std::fs::write(origin.join("app/src/main.rs"), "fn main() {}").expect("write");
+    std::fs::write(origin.join("docs/guide.md"), "# Guide").expect("write");
+    std::fs::write(origin.join("README.md"), "readme").expect("write");
+    for args in [
+        &["init", "--quiet", "--initial-branch=main"][..],
+        &["add", "."],
+        &[
+            "-c",
+            "user.name=test",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "--quiet",
+            "-m",
+            "initial",
+        ],
+    ] {
+        let status = std::process::Command::new("git")
+            .args(args)
+            .current_dir(&origin)
+            .status()
+            .expect("run git");
+        assert!(status.success());
+    }
+
+    let sqlite_path = dir.path().join("opencode.db");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let target = dir.path().join("work/app");
+    let (status, workspace) = send(
+        &app,
+        Method::POST,
+        "/workspace/clone",
+        Some(json!({
+            "url": format!("file://{}", origin.display()),
+            "ref": "main",
+            "depth": 1,
+            "sparsePaths": ["app"],
+            "directory": target.to_str().expect("utf-8 path"),
+        })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(workspace["status"], "cloning");
+    let workspace_id = workspace["id"].as_str().expect("workspace id").to_string();
+
+    let mut workspace = Value::Null;
+    for _ in 0..200 {
+        (_, workspace) = send(
+            &app,
+            Method::GET,
+            &format!("/workspace/{workspace_id}"),
+            None,
+        )
+        .await;
+        if workspace["status"] != "cloning" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(workspace["status"], "ready", "{workspace}");
+    assert_eq!(workspace["ref"], "main");
+    assert_eq!(workspace["commit"].as_str().map(str::len), Some(40));
+    assert!(target.join("app/src/main.rs").is_file());
+    assert!(target.join("README.md").is_file());
+    assert!(!target.join("docs").exists());
+
+    let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+    let types = polled["events"]
+        .as_array()
+        .expect("events")
+        .iter()
+        .filter_map(|event| event["type"].as_str())
+        .filter(|kind| kind.starts_with("workspace."))
+        .collect::<Vec<_>>();
+    assert_eq!(types.last(), Some(&"workspace.cloned"));
+    assert!(types.contains(&"workspace.clone_progress"));
+
+    let (status, session) = send(
+        &app,
+        Method::POST,
+        "/session",
+        Some(json!({"workspaceID": workspace_id})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(session["directory"], target.to_str().expect("utf-8 path"));
+
+    // A non-empty directory cannot be cloned into, and a failed clone
+    // cannot back a session.
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        "/workspace/clone",
+        Some(json!({
+            "url": format!("file://{}", origin.display()),
+            "directory": target.to_str().expect("utf-8 path"),
+        })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let missing = dir.path().join("work/missing");
+    let (_, failed) = send(
+        &app,
+        Method::POST,
+        "/workspace/clone",
+        Some(json!({
+            "url": format!("file://{}", dir.path().join("nope").display()),
+            "directory": missing.to_str().expect("utf-8 path"),
+        })),
+    )
+    .await;
+    let failed_id = failed["id"].as_str().expect("workspace id").to_string();
+    for _ in 0..200 {
+        (_, workspace) = send(&app, Method::GET, &format!("/workspace/{failed_id}"), None).await;
+        if workspace["status"] != "cloning" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(workspace["status"], "failed");
+    assert!(workspace["error"]
+        .as_str()
+        .is_some_and(|error| error.starts_with("git fetch failed")));
+    assert!(!missing.exists());
+    let (status, _) = send(
+        &app,
+        Method::POST,
+        "/session",
+        Some(json!({"workspaceID": failed_id})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    // Workspaces outlive the adapter.
+    let (_, listed) = send(&app, Method::GET, "/workspace", None).await;
+    assert_eq!(listed.as_array().map(Vec::len), Some(2));
+    let restarted = adapter(&dispatch, sqlite_path.to_str().expect("utf-8 path"));
+    let (_, relisted) = send(&restarted, Method::GET, "/workspace", None).await;
+    assert_eq!(relisted, listed);
+    let (status, session) = send(
+        &restarted,
+        Method::POST,
+        "/session",
+        Some(json!({"workspaceID": workspace_id})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(session["directory"], target.to_str().expect("utf-8 path"));
+}
+
+#[test]
+fn turns_left_open_by_a_crash_are_settled_on_restart() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let sqlite_path = sqlite_path.to_str().expect("utf-8 path");
+    let dispatch = MockAcpDispatch::new();
+
+    // The first adapter dies while one turn is streaming on an agent and
+    // another is still bootstrapping its agent.
+    let (streaming, pending) = runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        let (streaming, server_id) = bootstrapped_session(&app, &dispatch).await;
+        dispatch.session_update(
+            &server_id,
+            &format!("{server_id}-session"),
+            json!({
+                "sessionUpdate": "agent_message_chunk",
+                "content": {"type": "text", "text": "half an answer"}
+            }),
+        );
+        for _ in 0..100 {
+            let (_, messages) = send(
+                &app,
+                Method::GET,
+                &format!("/session/{streaming}/message"),
+                None,
+            )
+            .await;
+            if messages
+                .as_array()
+                .is_some_and(|messages| messages.len() == 2)
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        dispatch.stop_instance(&server_id);
+
+        let (_, session) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+        let pending = session["id"].as_str().expect("session id").to_string();
+        dispatch.respond_after(
+            "initialize",
+            Duration::from_secs(60),
+            json!({"protocolVersion": 1}),
+        );
+        tokio::spawn({
+            let app = app.clone();
+            let uri = format!("/session/{pending}/message");
+            async move {
+                send(
+                    &app,
+                    Method::POST,
+                    &uri,
+                    Some(json!({"agent": "codex", "parts": [{"type": "text", "text": "lost"}]})),
+                )
+                .await
+            }
+        });
+        let mut initializing = 0;
+        for _ in 0..100 {
+            initializing = dispatch
+                .posted()
+                .iter()
+                .filter(|posted| posted.method() == Some("initialize"))
+                .count();
+            if initializing == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(initializing, 2);
+        let (_, messages) = send(
+            &app,
+            Method::GET,
+            &format!("/session/{pending}/message"),
+            None,
+        )
+        .await;
+        assert_eq!(messages.as_array().map(Vec::len), Some(1));
+        (streaming, pending)
+    });
+
+    runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        let (status, statuses) = send(&app, Method::GET, "/session/status", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(statuses[&streaming]["state"], "idle");
+        assert_eq!(statuses[&pending]["state"], "idle");
+
+        // The dispatched turn is rolled forward: the agent's partial answer
+        // is kept and closed as aborted.
+        let (_, messages) = send(
+            &app,
+            Method::GET,
+            &format!("/session/{streaming}/message"),
+            None,
+        )
+        .await;
+        let messages = messages.as_array().expect("messages");
+        assert_eq!(messages.len(), 2);
+        let assistant = &messages[1]["info"];
+        assert_eq!(assistant["role"], "assistant");
+        assert_eq!(assistant["error"]["name"], "MessageAbortedError");
+        assert!(assistant["time"]["completed"].is_i64(), "{assistant}");
+
+        // The turn the agent never received is rolled back.
+        let (_, messages) = send(
+            &app,
+            Method::GET,
+            &format!("/session/{pending}/message"),
+            None,
+        )
+        .await;
+        assert_eq!(messages, json!([]));
+
+        // Recovery is settled once; a second restart finds nothing to do.
+        let (_, again) = send(&app, Method::GET, "/session/status", None).await;
+        assert_eq!(again, statuses);
+    });
+    runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        let (_, messages) = send(
+            &app,
+            Method::GET,
+            &format!("/session/{streaming}/message"),
+            None,
+        )
+        .await;
+        assert_eq!(messages.as_array().map(Vec::len), Some(2));
+    });
+}
+
+/// Read `/event` frames until one satisfies `done`, as `(id, event)` pairs.
+async fn read_sse_until(
+    body: &mut Body,
+    mut done: impl FnMut(&Value) -> bool,
+) -> Vec<(Option<u64>, Value)> {
+    let mut buffer = String::new();
+    let mut events = Vec::new();
+    loop {
+        let frame = tokio::time::timeout(Duration::from_secs(5), body.frame())
+            .await
+            .expect("event before timeout")
+            .expect("open stream")
+            .expect("frame");
+        let Ok(data) = frame.into_data() else {
+            continue;
+        };
+        buffer.push_str(std::str::from_utf8(&data).expect("utf-8"));
+        while let Some(end) = buffer.find("\n\n") {
+            let block = buffer[..end].to_string();
+            buffer.drain(..end + 2);
+            let id = block
+                .lines()
+                .find_map(|line| line.strip_prefix("id:"))
+                .map(|id| id.trim().parse().expect("numeric id"));
+            let Some(data) = block.lines().find_map(|line| line.strip_prefix("data:")) else {
+                continue;
+            };
+            let event: Value = serde_json::from_str(data.trim()).expect("json event");
+            let finished = done(&event);
+            events.push((id, event));
+            if finished {
+                return events;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn event_streams_send_heartbeats_at_the_configured_interval() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let app = adapter_with(
+        &MockAcpDispatch::new(),
+        sqlite_path.to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            heartbeat_interval: Duration::from_secs(3),
+            ..OpenCodeAdapterConfig::default()
+        },
+    );
+    // The time between the first two heartbeats of a new stream.
+    let heartbeat_gap = |uri: &'static str| {
+        let app = app.clone();
+        async move {
+            let request = Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .expect("build request");
+            let response = app.oneshot(request).await.expect("response");
+            let mut body = response.into_body();
+            let is_heartbeat = |event: &Value| event["type"] == "server.heartbeat";
+            read_sse_until(&mut body, is_heartbeat).await;
+            let first = Instant::now();
+            read_sse_until(&mut body, is_heartbeat).await;
+            first.elapsed()
+        }
+    };
+
+    let gap = heartbeat_gap("/event").await;
+    assert!(gap >= Duration::from_millis(2_900), "{gap:?}");
+    // Per-connection overrides are clamped to at least a second.
+    let gap = heartbeat_gap("/event?heartbeatMs=1").await;
+    assert!(gap >= Duration::from_millis(900), "{gap:?}");
+    assert!(gap < Duration::from_millis(2_500), "{gap:?}");
+}
+
+#[test]
+fn event_consumers_resume_after_their_last_ack_across_restarts() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let sqlite_path = sqlite_path.to_str().expect("utf-8 path");
+    let dispatch = MockAcpDispatch::new();
+    let subscribe = |app: &Router| {
+        let app = app.clone();
+        async move {
+            let request = Request::builder()
+                .uri("/event?consumer=ci-bot")
+                .body(Body::empty())
+                .expect("build request");
+            let response = app.oneshot(request).await.expect("response");
+            assert_eq!(response.status(), StatusCode::OK);
+            response.into_body()
+        }
+    };
+    let session_created = |session_id: &str| {
+        let session_id = session_id.to_string();
+        move |event: &Value| {
+            event["type"] == "session.created"
+                && event["properties"]["info"]["id"] == session_id.as_str()
+        }
+    };
+
+    let (acked, unacked_session) = runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        let mut body = subscribe(&app).await;
+        let (_, first) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+        let first_id = first["id"].as_str().expect("session id");
+        let events = read_sse_until(&mut body, session_created(first_id)).await;
+        let acked = events.last().and_then(|(id, _)| *id).expect("event id");
+
+        let (status, ack) = send(
+            &app,
+            Method::POST,
+            "/event/ack",
+            Some(json!({"consumer": "ci-bot", "id": acked})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ack["ackedID"], acked);
+        // Older acks leave the consumer where it is; acks ahead of the
+        // newest event are rejected.
+        let (_, ack) = send(
+            &app,
+            Method::POST,
+            "/event/ack",
+            Some(json!({"consumer": "ci-bot", "id": 1})),
+        )
+        .await;
+        assert_eq!(ack["ackedID"], acked);
+        let (status, _) = send(
+            &app,
+            Method::POST,
+            "/event/ack",
+            Some(json!({"consumer": "ci-bot", "id": acked + 1_000_000})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Events emitted while the consumer is away wait for it.
+        drop(body);
+        let (_, second) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        (
+            acked,
+            second["id"].as_str().expect("session id").to_string(),
+        )
+    });
+
+    runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        let mut body = subscribe(&app).await;
+        let events = read_sse_until(&mut body, session_created(&unacked_session)).await;
+        let ids = events.iter().filter_map(|(id, _)| *id).collect::<Vec<_>>();
+        assert_eq!(ids.first(), Some(&(acked + 1)));
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "{ids:?}");
+        // The restarted adapter numbers its events after the recorded ones.
+        let connected =
+            read_sse_until(&mut body, |event| event["type"] == "server.connected").await;
+        let connected_id = connected.last().and_then(|(id, _)| *id).expect("event id");
+        assert!(connected_id > *ids.last().expect("replayed"));
+    });
+}
+
+#[test]
+fn consumer_backlogs_longer_than_a_page_are_replayed_in_full() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let sqlite_path = sqlite_path.to_str().expect("utf-8 path");
+    let dispatch = MockAcpDispatch::new();
+
+    let (acked, sessions) = runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        let (_, polled) = send(&app, Method::GET, "/event/poll?waitMs=0", None).await;
+        let acked = polled["cursor"].as_u64().expect("cursor");
+        let (status, _) = send(
+            &app,
+            Method::POST,
+            "/event/ack",
+            Some(json!({"consumer": "ci-bot", "id": acked})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let mut sessions = Vec::new();
+        for _ in 0..1100 {
+            let (_, created) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+            sessions.push(created["id"].as_str().expect("session id").to_string());
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        (acked, sessions)
+    });
+
+    runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        let request = Request::builder()
+            .uri("/event?consumer=ci-bot")
+            .body(Body::empty())
+            .expect("build request");
+        let response = app.oneshot(request).await.expect("response");
+        let mut body = response.into_body();
+        let last = sessions.last().expect("sessions").clone();
+        let events = read_sse_until(&mut body, |event| {
+            event["type"] == "session.created" && event["properties"]["info"]["id"] == last.as_str()
+        })
+        .await;
+        let ids = events.iter().filter_map(|(id, _)| *id).collect::<Vec<_>>();
+        assert!(ids.len() > 1024, "{} events", ids.len());
+        assert_eq!(ids.first(), Some(&(acked + 1)));
+        assert!(ids.windows(2).all(|pair| pair[1] == pair[0] + 1), "{ids:?}");
+        assert!(events
+            .iter()
+            .all(|(_, event)| event["type"] != "server.gap"));
+        let created = events
+            .iter()
+            .filter(|(_, event)| event["type"] == "session.created")
+            .count();
+        assert_eq!(created, sessions.len());
+    });
+}
+
+#[test]
+fn polled_cursors_survive_adapter_restarts() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let sqlite_path = sqlite_path.to_str().expect("utf-8 path");
+    let dispatch = MockAcpDispatch::new();
+    let poll = |app: &Router, query: String| {
+        let app = app.clone();
+        async move {
+            let (status, polled) =
+                send(&app, Method::GET, &format!("/event/poll?{query}"), None).await;
+            assert_eq!(status, StatusCode::OK);
+            let cursor = polled["cursor"].as_u64().expect("cursor");
+            (polled["events"].as_array().expect("events").clone(), cursor)
+        }
+    };
+
+    let (cursor, unpolled_session) = runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        let (events, cursor) = poll(&app, "waitMs=0".to_string()).await;
+        assert!(events.is_empty());
+        let (_, first) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+        let (events, cursor) = poll(&app, format!("since={cursor}&waitMs=0")).await;
+        assert!(events.iter().any(|event| event["type"] == "session.created"
+            && event["properties"]["info"]["id"] == first["id"]));
+
+        // Emitted between polls, and still pending when the adapter stops.
+        let (_, second) = send(&app, Method::POST, "/session", Some(json!({}))).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        (cursor, second["id"].clone())
+    });
+
+    runtime().block_on(async {
+        let app = adapter(&dispatch, sqlite_path);
+        // Batches are served from the log a page at a time.
+        let (events, next) = poll(&app, format!("since={cursor}&waitMs=0&limit=1")).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(next, cursor + 1);
+        assert_ne!(events[0]["type"], "server.gap");
+
+        let (events, _) = poll(&app, format!("since={cursor}&waitMs=0")).await;
+        assert!(events.iter().any(|event| event["type"] == "session.created"
+            && event["properties"]["info"]["id"] == unpolled_session));
+    });
+}
+
+#[tokio::test]
+async fn archived_sessions_are_hydrated_for_every_session_read() {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    // An S3 stand-in keeping objects in memory.
+    let objects: Arc<Mutex<HashMap<String, Vec<u8>>>> = Arc::default();
+    let s3 = Router::new().route(
+        "/*key",
+        axum::routing::put({
+            let objects = objects.clone();
+            move |Path(key): Path<String>, body: axum::body::Bytes| async move {
+                objects.lock().unwrap().insert(key, body.to_vec());
+                StatusCode::OK
+            }
+        })
+        .get({
+            let objects = objects.clone();
+            move |Path(key): Path<String>| async move {
+                match objects.lock().unwrap().get(&key) {
+                    Some(body) => (StatusCode::OK, body.clone()),
+                    None => (StatusCode::NOT_FOUND, Vec::new()),
+                }
+            }
+        })
+        .delete({
+            let objects = objects.clone();
+            move |Path(key): Path<String>| async move {
+                objects.lock().unwrap().remove(&key);
+                StatusCode::NO_CONTENT
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind s3");
+    let endpoint = format!("http://{}", listener.local_addr().expect("s3 addr"));
+    tokio::spawn(async move { axum::serve(listener, s3).await });
+
+    let dir = tempfile::tempdir().expect("tempdir");
+    let dispatch = MockAcpDispatch::new();
+    let app = adapter_with(
+        &dispatch,
+        dir.path().join("opencode.db").to_str().expect("utf-8 path"),
+        OpenCodeAdapterConfig {
+            archive: Some(SessionArchiveConfig {
+                endpoint,
+                bucket: "archive".to_string(),
+                region: "us-east-1".to_string(),
+                prefix: "sessions/".to_string(),
+                access_key_id: "key".to_string(),
+                secret_access_key: "secret".to_string(),
+                session_token: None,
+                archive_after: Duration::ZERO,
+                interval: Duration::from_millis(50),
+            }),
+            ..OpenCodeAdapterConfig::default()
+        },
+    );
+    let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+    // A turn that streams output settles the session.
+    dispatch.respond_after(
+        "session/prompt",
+        Duration::from_millis(100),
+        json!({"stopReason": "end_turn"}),
+    );
+    let prompt = tokio::spawn({
+        let app = app.clone();
+        let uri = format!("/session/{session_id}/message");
+        async move {
+            send(
+                &app,
+                Method::POST,
+                &uri,
+                Some(json!({"parts": [{"type": "text", "text": "hello again"}]})),
+            )
+            .await
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    dispatch.session_update(
+        &server_id,
+        &format!("{server_id}-session"),
+        json!({"sessionUpdate": "agent_message_chunk", "content": {"type": "text", "text": "hi"}}),
+    );
+    assert_eq!(prompt.await.expect("prompt").0, StatusCode::OK);
+    let messages_uri = format!("/session/{session_id}/message");
+    let (_, before) = send(&app, Method::GET, &messages_uri, None).await;
+    let key = format!("archive/sessions/{session_id}.json");
+    for _ in 0..100 {
+        if objects.lock().unwrap().contains_key(&key) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(objects.lock().unwrap().contains_key(&key), "never archived");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Handlers that read the event log see the archived history.
+    let (status, replay) = send(
+        &app,
+        Method::GET,
+        &format!("/session/{session_id}/replay"),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(
+        replay["totalEvents"].as_u64().expect("total events") > 0,
+        "{replay}"
+    );
+    let (_, messages) = send(&app, Method::GET, &messages_uri, None).await;
+    assert_eq!(messages, before);
+
+    // A redacted message leaves the archive too, however often the sweep
+    // archives the session meanwhile.
+    let message_id = messages
+        .as_array()
+        .expect("messages")
+        .iter()
+        .find(|message| message.to_string().contains("hello again"))
+        .and_then(|message| message["info"]["id"].as_str())
+        .expect("prompt message")
+        .to_string();
+    let (status, _) = send(
+        &app,
+        Method::DELETE,
+        &format!("/session/{session_id}/message/{message_id}"),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    for _ in 0..5 {
+        let archived = objects.lock().unwrap().get(&key).cloned();
+        if let Some(archived) = archived {
+            assert!(!String::from_utf8_lossy(&archived).contains("hello again"));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(
+        objects.lock().unwrap().contains_key(&key),
+        "never archived again"
+    );
+}
+
+/// Renders each rebuilt event as its type and persistence time.
+struct EventTypes;
+
+impl TranscriptFormat for EventTypes {
+    fn translator(&self, _session_id: &str) -> Box<dyn TranscriptTranslator> {
+        Box::new(EventTypes)
+    }
+}
+
+impl TranscriptTranslator for EventTypes {
+    fn translate(&mut self, event: &Value, created_at: i64) -> Vec<Value> {
+        vec![json!({"type": event["type"], "createdAt": created_at})]
+    }
+}
+
+async fn transcript(app: &Router, session_id: &str) -> (StatusCode, Vec<Value>) {
+    let request = Request::builder()
+        .uri(format!("/session/{session_id}/transcript.ndjson"))
+        .body(Body::empty())
+        .expect("build request");
+    let response = app.clone().oneshot(request).await.expect("response");
+    let status = response.status();
+    let bytes = response
+        .into_body()
+        .collect()
+        .await
+        .expect("collect body")
+        .to_bytes();
+    let lines = String::from_utf8_lossy(&bytes)
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("ndjson line"))
+        .collect();
+    (status, lines)
+}
+
+#[test]
+fn transcripts_are_rebuilt_from_the_event_log_after_a_restart() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let sqlite_path = sqlite_path.to_str().expect("utf-8 path");
+    let dispatch = MockAcpDispatch::new();
+    let config = || OpenCodeAdapterConfig {
+        transcript_format: Some(Arc::new(EventTypes)),
+        ..OpenCodeAdapterConfig::default()
+    };
+
+    let (session_id, before) = runtime().block_on(async {
+        let app = adapter_with(&dispatch, sqlite_path, config());
+        let (session_id, _) = bootstrapped_session(&app, &dispatch).await;
+        let (status, lines) = transcript(&app, &session_id).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = transcript(&app, "ses_missing").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        (session_id, lines)
+    });
+    let types: Vec<&str> = before
+        .iter()
+        .filter_map(|line| line["type"].as_str())
+        .collect();
+    assert_eq!(
+        types[..3],
+        ["turn.started", "message.updated", "message.part.updated"]
+    );
+
+    runtime().block_on(async {
+        let app = adapter_with(&dispatch, sqlite_path, config());
+        let (status, after) = transcript(&app, &session_id).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(after, before);
+    });
+}
+
+#[test]
+fn turns_in_flight_across_an_adapter_restart_complete_without_duplicates() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let sqlite_path = sqlite_path.to_str().expect("utf-8 path");
+    let dispatch = MockAcpDispatch::new();
+    let config = || OpenCodeAdapterConfig {
+        transcript_format: Some(Arc::new(EventTypes)),
+        ..OpenCodeAdapterConfig::default()
+    };
+
+    // The first adapter dies mid-turn, after the agent streamed part of its
+    // answer and asked for a permission.
+    let (session_id, server_id, prompt_id) = runtime().block_on(async {
+        let app = adapter_with(&dispatch, sqlite_path, config());
+        let (session_id, server_id) = bootstrapped_session(&app, &dispatch).await;
+        dispatch.respond_after(
+            "session/prompt",
+            Duration::from_secs(60),
+            json!({"stopReason": "end_turn"}),
+        );
+        tokio::spawn({
+            let app = app.clone();
+            let uri = format!("/session/{session_id}/message");
+            async move {
+                send(
+                    &app,
+                    Method::POST,
+                    &uri,
+                    Some(json!({"parts": [{"type": "text", "text": "run the tests"}]})),
+                )
+                .await
+            }
+        });
+        let mut prompts = Vec::new();
+        for _ in 0..100 {
+            prompts = dispatch
+                .posted()
+                .into_iter()
+                .filter(|posted| posted.method() == Some("session/prompt"))
+                .collect();
+            if prompts.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let prompt_id = prompts[1].payload["id"].clone();
+
+        let acp_session_id = format!("{server_id}-session");
+        for update in [
+            json!({
+                "sessionUpdate": "agent_message_chunk",
+                "content": {"type": "text", "text": "running "}
+            }),
+            json!({
+                "sessionUpdate": "tool_call",
+                "toolCallId": "call_1",
+                "title": "Bash",
+                "kind": "execute",
+                "rawInput": {"command": "cargo test"}
+            }),
+        ] {
+            dispatch.session_update(&server_id, &acp_session_id, update);
+        }
+        dispatch.notify(
+            &server_id,
+            json!({
+                "jsonrpc": "2.0",
+                "id": "perm_1",
+                "method": "session/request_permission",
+                "params": {
+                    "sessionId": acp_session_id,
+                    "toolCall": {"toolCallId": "call_1", "title": "Bash", "kind": "execute"}
+                }
+            }),
+        );
+        for _ in 0..100 {
+            let (_, pending) = send(&app, Method::GET, "/permission", None).await;
+            if pending.as_array().is_some_and(|pending| pending.len() == 1) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        (session_id, server_id, prompt_id)
+    });
+
+    // The restarted adapter picks the stream up where it was and the agent
+    // finishes the turn.
+    runtime().block_on(async {
+        let app = adapter_with(&dispatch, sqlite_path, config());
+        let (status, _) = send(&app, Method::GET, "/session", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let acp_session_id = format!("{server_id}-session");
+        dispatch.session_update(
+            &server_id,
+            &acp_session_id,
+            json!({
+                "sessionUpdate": "tool_call_update",
+                "toolCallId": "call_1",
+                "status": "completed",
+                "content": [{"type": "content", "content": {"type": "text", "text": "ok"}}]
+            }),
+        );
+        let tool_status = |messages: &Value| {
+            messages
+                .as_array()
+                .and_then(|messages| messages.last())
+                .and_then(|answer| answer["parts"].as_array())
+                .and_then(|parts| parts.iter().find(|part| part["type"] == "tool"))
+                .map(|tool| tool["state"]["status"].clone())
+        };
+        for _ in 0..100 {
+            let (_, messages) = send(
+                &app,
+                Method::GET,
+                &format!("/session/{session_id}/message"),
+                None,
+            )
+            .await;
+            if tool_status(&messages) == Some(json!("completed")) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // The permission request replayed from the agent's stream is not
+        // asked a second time, and answering it still reaches the agent.
+        let (_, pending) = send(&app, Method::GET, "/permission", None).await;
+        assert_eq!(pending.as_array().map(Vec::len), Some(1), "{pending}");
+        let request_id = pending[0]["id"].as_str().expect("permission id");
+        let (status, _) = send(
+            &app,
+            Method::POST,
+            &format!("/permission/{request_id}/reply"),
+            Some(json!({"reply": "once"})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(dispatch
+            .posted()
+            .iter()
+            .any(|posted| posted.payload["id"] == "perm_1"));
+
+        dispatch.session_update(
+            &server_id,
+            &acp_session_id,
+            json!({
+                "sessionUpdate": "agent_message_chunk",
+                "content": {"type": "text", "text": "all passed"}
+            }),
+        );
+        dispatch.notify(
+            &server_id,
+            json!({"jsonrpc": "2.0", "id": prompt_id, "result": {"stopReason": "end_turn"}}),
+        );
+
+        let mut statuses = Value::Null;
+        for _ in 0..100 {
+            statuses = send(&app, Method::GET, "/session/status", None).await.1;
+            if statuses[&session_id]["state"] == "idle" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(statuses[&session_id]["state"], "idle", "{statuses}");
+
+        let (_, messages) = send(
+            &app,
+            Method::GET,
+            &format!("/session/{session_id}/message"),
+            None,
+        )
+        .await;
+        let answer = messages
+            .as_array()
+            .and_then(|messages| messages.last())
+            .expect("answer");
+        assert_eq!(answer["info"]["role"], "assistant");
+        assert!(answer["info"]["parentID"]
+            .as_str()
+            .is_some_and(|parent| !parent.is_empty()));
+        assert!(answer["info"]["time"]["completed"].is_i64(), "{answer}");
+        assert_eq!(answer["info"].get("error"), None, "{answer}");
+        let parts = answer["parts"].as_array().expect("parts");
+        let texts: Vec<&str> = parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect();
+        assert_eq!(texts.concat(), "running all passed", "{parts:?}");
+        let tools: Vec<&Value> = parts.iter().filter(|part| part["type"] == "tool").collect();
+        assert_eq!(tools.len(), 1, "{parts:?}");
+        assert_eq!(tools[0]["state"]["status"], "completed");
+
+        let (_, pending) = send(&app, Method::GET, "/permission", None).await;
+        assert_eq!(pending, json!([]));
+        let (_, polled) = send(&app, Method::GET, "/event/poll?since=0", None).await;
+        assert!(!polled["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(|event| event["type"] == "permission.asked"));
+        let (_, lines) = transcript(&app, &session_id).await;
+        let count = |event_type: &str| {
+            lines
+                .iter()
+                .filter(|line| line["type"] == event_type)
+                .count()
+        };
+        assert_eq!(count("permission.asked"), 1);
+        assert_eq!(count("turn.completed"), 1);
+        assert_eq!(
+            lines.last().map(|line| &line["type"]),
+            Some(&json!("turn.completed"))
+        );
+    });
+}
+
+#[tokio::test]
+async fn session_migrations_carry_pending_requests_and_refuse_colliding_events() {
+    let dirs: Vec<_> = (0..3)
+        .map(|_| tempfile::tempdir().expect("tempdir"))
+        .collect();
+    let path = |index: usize| {
+        dirs[index]
+            .path()
+            .join("opencode.db")
+            .to_str()
+            .expect("utf-8 path")
+            .to_string()
+    };
+    // The source and the first target count IDs up from the same start, so
+    // their event IDs overlap.
+    let ids_from = |start| OpenCodeAdapterConfig {
+        id_generator: Arc::new(SequentialIds::starting_at(start)),
+        ..OpenCodeAdapterConfig::default()
+    };
+    let source_dispatch = MockAcpDispatch::new();
+    let source = adapter_with(&source_dispatch, &path(0), ids_from(1_000));
+    let colliding_dispatch = MockAcpDispatch::new();
+    let colliding = adapter_with(&colliding_dispatch, &path(1), ids_from(1_000));
+    let target_dispatch = MockAcpDispatch::new();
+    let target = adapter(&target_dispatch, &path(2));
+    let mut urls = Vec::new();
+    for app in [colliding.clone(), target.clone()] {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind target");
+        urls.push(format!(
+            "http://{}",
+            listener.local_addr().expect("target address")
+        ));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+    }
+
+    let (status, _) = send(&source, Method::POST, "/session", Some(json!({}))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (session_id, server_id) = bootstrapped_session(&source, &source_dispatch).await;
+    let (colliding_id, _) = bootstrapped_session(&colliding, &colliding_dispatch).await;
+    assert_ne!(colliding_id, session_id);
+
+    source_dispatch.notify(
+        &server_id,
+        json!({
+            "jsonrpc": "2.0",
+            "id": "perm_1",
+            "method": "session/request_permission",
+            "params": {
+                "sessionId": format!("{server_id}-session"),
+                "toolCall": {
+                    "toolCallId": "call_1",
+                    "title": "Bash",
+                    "kind": "execute",
+                    "rawInput": {"command": "cargo test"}
+                }
+            }
+        }),
+    );
+    let mut pending = json!([]);
+    for _ in 0..100 {
+        pending = send(&source, Method::GET, "/permission", None).await.1;
+        if pending
+            .as_array()
+            .is_some_and(|pending| !pending.is_empty())
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(pending[0]["sessionID"], session_id.as_str());
+
+    // An event log that would overwrite another session's events is refused
+    // and the session stays where it was.
+    let (status, body) = send(
+        &source,
+        Method::POST,
+        &format!("/session/{session_id}/migrate"),
+        Some(json!({"target": urls[0]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY, "{body}");
+    assert_eq!(body["errors"][0]["name"], "SessionMigrationError");
+    let (_, session) = send(
+        &source,
+        Method::GET,
+        &format!("/session/{session_id}"),
+        None,
+    )
+    .await;
+    assert_eq!(session.get("migratedTo"), None);
+    let (status, _) = send(
+        &colliding,
+        Method::GET,
+        &format!("/session/{session_id}"),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // The pending permission request moves along with the session.
+    let (status, migrated) = send(
+        &source,
+        Method::POST,
+        &format!("/session/{session_id}/migrate"),
+        Some(json!({"target": urls[1]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{migrated}");
+    let (_, moved) = send(&target, Method::GET, "/permission", None).await;
+    assert_eq!(moved, pending);
+}
+
+#[tokio::test]
+async fn imported_history_is_replayed_on_the_first_turn_after_a_restart() {
+    let dir = tempfile::tempdir().expect("tempdir");
+    let sqlite_path = dir.path().join("opencode.db");
+    let sqlite_path = sqlite_path.to_str().expect("utf-8 path");
+    let app = adapter(&MockAcpDispatch::new(), sqlite_path);
+    let (status, session) = send(
+        &app,
+        Method::POST,
+        "/session",
+        Some(json!({"initialHistory": [
+            {"role": "user", "content": [{"type": "text", "text": "the port is 8123"}]},
+            {"role": "assistant", "content": [{"type": "text", "text": "noted"}]},
+        ]})),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = session["id"].as_str().expect("session id").to_string();
+
+    let dispatch = MockAcpDispatch::new();
+    let restarted = adapter(&dispatch, sqlite_path);
+    let messages_uri = format!("/session/{session_id}/message");
+    for text in ["which port?", "and again?"] {
+        let (status, _) = send(
+            &restarted,
+            Method::POST,
+            &messages_uri,
+            Some(json!({"agent": "claude", "parts": [{"type": "text", "text": text}]})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let prompts = dispatch
+        .posted()
+        .into_iter()
+        .filter(|posted| posted.method() == Some("session/prompt"))
+        .map(|posted| posted.payload["params"]["prompt"].to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(prompts.len(), 2);
+    assert!(prompts[0].contains("the port is 8123"), "{}", prompts[0]);
+    assert!(!prompts[1].contains("the port is 8123"), "{}", prompts[1]);
+}
@@ -287,7 +287,15 @@ fn parse_sse_event_id(chunk: &str) -> u64 {
 
 #[path = "v1_api/acp_transport.rs"]
 mod acp_transport;
+#[path = "v1_api/chat_completions.rs"]
+mod chat_completions;
 #[path = "v1_api/config_endpoints.rs"]
 mod config_endpoints;
 #[path = "v1_api/control_plane.rs"]
 mod control_plane;
+#[path = "v1_api/event_sinks.rs"]
+mod event_sinks;
+#[path = "v1_api/federation.rs"]
+mod federation;
+#[path = "v1_api/transcripts.rs"]
+mod transcripts;
@@ -115,6 +115,51 @@ async fn acp_round_trip_and_replay() {
     assert!(second_event_id > first_event_id);
 }
 
+#[cfg(unix)]
+#[tokio::test]
+#[serial]
+async fn acp_request_past_route_budget_returns_timeout_problem() {
+    let test_app = {
+        let _budget = EnvVarGuard::set("SANDBOX_AGENT_TIMEOUT_ACP_MS", "300");
+        TestApp::with_setup(AuthConfig::disabled(), |install_dir| {
+            write_stub_native(&install_dir.join("codex"), "codex");
+            let agent_processes = install_dir.join("agent_processes");
+            fs::create_dir_all(&agent_processes).expect("create agent processes dir");
+            write_executable(
+                &agent_processes.join("codex-acp"),
+                "#!/usr/bin/env sh\nwhile IFS= read -r _line; do :; done\n",
+            );
+        })
+    };
+
+    let (status, headers, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/v1/acp/server-hung?agent=codex",
+        Some(initialize_payload()),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(
+        headers
+            .get("x-request-timeout")
+            .and_then(|value| value.to_str().ok()),
+        Some("300")
+    );
+    let problem = parse_json(&body);
+    assert_eq!(problem["type"], "urn:sandbox-agent:error:timeout");
+    assert_eq!(problem["title"], "Timeout");
+
+    let (_, headers, _) = send_request(&test_app.app, Method::GET, "/v1/health", None, &[]).await;
+    assert_eq!(
+        headers
+            .get("x-request-timeout")
+            .and_then(|value| value.to_str().ok()),
+        Some("30000")
+    );
+}
+
 #[cfg(unix)]
 #[tokio::test]
 async fn acp_agent_mismatch_returns_conflict() {
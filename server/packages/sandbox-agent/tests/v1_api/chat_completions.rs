@@ -0,0 +1,289 @@
+use super::*;
+
+const SESSION_HEADER: &str = "x-sandbox-agent-session-id";
+const KEEP_SESSION_HEADER: &str = "x-sandbox-agent-keep-session";
+
+/// The `data:` payloads of an SSE body.
+fn sse_data(bytes: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(bytes)
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| data.trim().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn chat_completions_run_as_session_prompts() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+
+    let (status, headers, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/v1/chat/completions",
+        Some(json!({
+            "model": "mock/mock",
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": [{"type": "text", "text": "hello there"}]},
+            ],
+        })),
+        &[(KEEP_SESSION_HEADER, "true")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let completion = parse_json(&body);
+    assert_eq!(completion["object"], "chat.completion");
+    assert_eq!(completion["model"], "mock/mock");
+    assert!(completion["id"]
+        .as_str()
+        .is_some_and(|id| id.starts_with("chatcmpl-turn_")));
+    assert_eq!(completion["choices"][0]["message"]["role"], "assistant");
+    assert_eq!(
+        completion["choices"][0]["message"]["content"],
+        "hello there"
+    );
+    assert_eq!(completion["choices"][0]["finish_reason"], "stop");
+    let session_id = headers[SESSION_HEADER]
+        .to_str()
+        .expect("session header")
+        .to_string();
+
+    // Continuing the session streams the next turn as completion chunks.
+    let (status, headers, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/v1/chat/completions",
+        Some(json!({
+            "model": "mock/mock",
+            "stream": true,
+            "messages": [
+                {"role": "user", "content": "hello there"},
+                {"role": "assistant", "content": "hello there"},
+                {"role": "user", "content": "and again"},
+            ],
+        })),
+        &[(SESSION_HEADER, &session_id)],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[SESSION_HEADER], session_id.as_str());
+    let data = sse_data(&body);
+    assert_eq!(data.last().map(String::as_str), Some("[DONE]"));
+    let chunks = data[..data.len() - 1]
+        .iter()
+        .map(|data| serde_json::from_str::<Value>(data).expect("chunk"))
+        .collect::<Vec<_>>();
+    assert!(chunks
+        .iter()
+        .all(|chunk| chunk["object"] == "chat.completion.chunk"));
+    assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+    let content = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+        .collect::<String>();
+    assert_eq!(content, "and again");
+    assert_eq!(
+        chunks.last().expect("final chunk")["choices"][0]["finish_reason"],
+        "stop"
+    );
+
+    let (_, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        &format!("/opencode/session/{session_id}/message"),
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(parse_json(&body).as_array().map(Vec::len), Some(4));
+
+    // A new session is told the conversation so far; the mock agent echoes
+    // the first part of its prompt. It is not kept, so the session is
+    // deleted once the completion is answered.
+    let session_ids = |body: &[u8]| {
+        parse_json(body)
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|session| session["id"].as_str().map(str::to_string))
+            .collect::<Vec<_>>()
+    };
+    let (_, _, body) =
+        send_request(&test_app.app, Method::GET, "/opencode/session", None, &[]).await;
+    let before = session_ids(&body);
+    let (status, headers, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/v1/chat/completions",
+        Some(json!({
+            "model": "mock/mock",
+            "messages": [
+                {"role": "user", "content": "one plus one?"},
+                {"role": "assistant", "content": "two"},
+                {"role": "user", "content": "times three?"},
+            ],
+        })),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(headers.get(SESSION_HEADER).is_none());
+    let content = parse_json(&body)["choices"][0]["message"]["content"]
+        .as_str()
+        .expect("content")
+        .to_string();
+    assert!(content.starts_with("Conversation so far:"), "{content}");
+    assert!(content.contains("assistant: two"), "{content}");
+    let mut remaining = Vec::new();
+    for _ in 0..50 {
+        // Other tests create sessions in the same database meanwhile.
+        let (_, _, body) =
+            send_request(&test_app.app, Method::GET, "/opencode/session", None, &[]).await;
+        remaining.clear();
+        for id in session_ids(&body) {
+            if before.contains(&id) {
+                continue;
+            }
+            let (_, _, body) = send_request(
+                &test_app.app,
+                Method::GET,
+                &format!("/opencode/session/{id}/message"),
+                None,
+                &[],
+            )
+            .await;
+            if String::from_utf8_lossy(&body).contains("times three?") {
+                remaining.push(id);
+            }
+        }
+        if remaining.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(remaining.is_empty(), "{remaining:?}");
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/v1/chat/completions",
+        Some(json!({
+            "model": "mock/mock",
+            "messages": [{"role": "assistant", "content": "nothing to answer"}],
+        })),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(parse_json(&body)["error"]["type"], "invalid_request_error");
+}
+
+/// The `event:` names of an SSE body.
+fn sse_events(bytes: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(bytes)
+        .lines()
+        .filter_map(|line| line.strip_prefix("event:"))
+        .map(|event| event.trim().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn anthropic_messages_run_as_session_prompts() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+
+    let (status, headers, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/v1/messages",
+        Some(json!({
+            "model": "mock/mock",
+            "max_tokens": 1024,
+            "system": "Be brief.",
+            "messages": [
+                {"role": "user", "content": [{"type": "text", "text": "hello there"}]},
+            ],
+        })),
+        &[(KEEP_SESSION_HEADER, "true")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let message = parse_json(&body);
+    assert_eq!(message["type"], "message");
+    assert_eq!(message["role"], "assistant");
+    assert_eq!(message["model"], "mock/mock");
+    assert!(message["id"]
+        .as_str()
+        .is_some_and(|id| id.starts_with("msg_turn_")));
+    assert_eq!(
+        message["content"],
+        json!([{"type": "text", "text": "hello there"}])
+    );
+    assert_eq!(message["stop_reason"], "end_turn");
+    let session_id = headers[SESSION_HEADER]
+        .to_str()
+        .expect("session header")
+        .to_string();
+
+    // Continuing the session streams the next turn as message events.
+    let (status, headers, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/v1/messages",
+        Some(json!({
+            "model": "mock/mock",
+            "max_tokens": 1024,
+            "stream": true,
+            "messages": [
+                {"role": "user", "content": "hello there"},
+                {"role": "assistant", "content": "hello there"},
+                {"role": "user", "content": "and again"},
+            ],
+        })),
+        &[(SESSION_HEADER, &session_id)],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[SESSION_HEADER], session_id.as_str());
+    let names = sse_events(&body);
+    let data = sse_data(&body)
+        .iter()
+        .map(|data| serde_json::from_str::<Value>(data).expect("event"))
+        .collect::<Vec<_>>();
+    assert_eq!(names.len(), data.len());
+    for (name, data) in names.iter().zip(&data) {
+        assert_eq!(data["type"], name.as_str());
+    }
+    assert_eq!(names[..2], ["message_start", "content_block_start"]);
+    assert_eq!(
+        names[names.len() - 3..],
+        ["content_block_stop", "message_delta", "message_stop"]
+    );
+    assert_eq!(
+        data[0]["message"]["id"].as_str().map(|id| &id[..4]),
+        Some("msg_")
+    );
+    let text = data
+        .iter()
+        .filter(|data| data["type"] == "content_block_delta")
+        .filter_map(|data| data["delta"]["text"].as_str())
+        .collect::<String>();
+    assert_eq!(text, "and again");
+    assert_eq!(data[data.len() - 2]["delta"]["stop_reason"], "end_turn");
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/v1/messages",
+        Some(json!({
+            "model": "mock/mock",
+            "max_tokens": 1024,
+            "messages": [{"role": "assistant", "content": "nothing to answer"}],
+        })),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let error = parse_json(&body);
+    assert_eq!(error["type"], "error");
+    assert_eq!(error["error"]["type"], "invalid_request_error");
+}
@@ -17,6 +17,141 @@ async fn v1_health_removed_legacy_and_opencode_unmounted() {
     assert_eq!(status, StatusCode::OK);
 }
 
+#[tokio::test]
+async fn opencode_health_reports_sidecar_supervisor() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/opencode/global/health",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let sidecar = &parse_json(&body)["checks"]["nativeSidecar"];
+    assert_eq!(sidecar["status"], "stopped");
+    assert_eq!(sidecar["supervisor"]["state"], "stopped");
+    assert_eq!(sidecar["supervisor"]["restarts"], 0);
+}
+
+#[tokio::test]
+async fn opencode_session_backend_reports_mapping() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/opencode/session",
+        Some(json!({})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = parse_json(&body)["id"]
+        .as_str()
+        .expect("session id")
+        .to_string();
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        &format!("/opencode/session/{session_id}/backend"),
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let backend = parse_json(&body);
+    assert_eq!(backend["sessionID"], session_id);
+    assert!(backend["serverID"]
+        .as_str()
+        .is_some_and(|id| id.starts_with("acp_")));
+    assert_eq!(backend["generation"], 0);
+    assert_eq!(backend["running"], false);
+    assert!(backend["acpSessionID"].is_null());
+
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/opencode/session/ses_no_such_backend/backend",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn opencode_session_list_paginates_with_link_headers() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+
+    let mut created = Vec::new();
+    for _ in 0..3 {
+        let (status, _, body) = send_request(
+            &test_app.app,
+            Method::POST,
+            "/opencode/session",
+            Some(json!({})),
+            &[],
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        created.push(
+            parse_json(&body)["id"]
+                .as_str()
+                .expect("session id")
+                .to_string(),
+        );
+    }
+
+    let mut seen = Vec::new();
+    let mut path = "/opencode/session?limit=2".to_string();
+    loop {
+        let (status, headers, body) =
+            send_request(&test_app.app, Method::GET, &path, None, &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        let total: usize = headers
+            .get("x-total-count")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .expect("x-total-count");
+        assert!(total >= created.len());
+        let page = parse_json(&body);
+        let page = page.as_array().expect("session array");
+        assert!(page.len() <= 2);
+        seen.extend(
+            page.iter()
+                .map(|session| session["id"].as_str().unwrap_or_default().to_string()),
+        );
+        let Some(link) = headers.get("link").and_then(|value| value.to_str().ok()) else {
+            break;
+        };
+        path = link
+            .trim_start_matches('<')
+            .split_once('>')
+            .map(|(next, _)| next.to_string())
+            .expect("link target");
+        assert!(path.starts_with("/opencode/session?limit=2&cursor="));
+    }
+    let unique: std::collections::HashSet<_> = seen.iter().collect();
+    assert_eq!(unique.len(), seen.len());
+    for id in &created {
+        assert!(seen.contains(id), "{id} missing from paginated listing");
+    }
+
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/opencode/session?cursor=not-a-cursor",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
 #[tokio::test]
 async fn v1_auth_enforced_when_token_configured() {
     let test_app = TestApp::new(AuthConfig::with_token("secret-token".to_string()));
@@ -36,6 +171,195 @@ async fn v1_auth_enforced_when_token_configured() {
     assert_eq!(parse_json(&body)["status"], "ok");
 }
 
+#[tokio::test]
+async fn rotated_tokens_keep_working_until_their_grace_deadline() {
+    let test_app = TestApp::new(AuthConfig::with_token("token-one".to_string()));
+    let bearer = |token: &str| format!("Bearer {token}");
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/admin/tokens/rotate",
+        Some(json!({"token": "token-two", "graceSeconds": 60})),
+        &[("authorization", &bearer("token-one"))],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let rotated = parse_json(&body);
+    assert_eq!(rotated["token"], "token-two");
+    assert_eq!(rotated["previous"]["fingerprint"], "…-one");
+    assert_eq!(rotated["graceSeconds"], 60);
+
+    // Both tokens work on every authenticated surface during the grace period.
+    for token in ["token-one", "token-two"] {
+        for path in ["/v1/health", "/opencode/session", "/metrics"] {
+            let (status, _, _) = send_request(
+                &test_app.app,
+                Method::GET,
+                path,
+                None,
+                &[("authorization", &bearer(token))],
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK, "{token} on {path}");
+        }
+    }
+
+    // A replaced token cannot rotate, so a leaked one cannot mint a
+    // successor.
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/admin/tokens/rotate",
+        Some(json!({"token": "token-evil", "graceSeconds": u64::MAX})),
+        &[("authorization", &bearer("token-one"))],
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // A rotation with no grace retires the replaced token at once; a token
+    // is generated when none is given.
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/admin/tokens/rotate",
+        Some(json!({"graceSeconds": 0})),
+        &[("authorization", &bearer("token-two"))],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let token_three = parse_json(&body)["token"]
+        .as_str()
+        .expect("generated token")
+        .to_string();
+    assert_eq!(token_three.len(), 48);
+    for (token, expected) in [
+        ("token-one", StatusCode::OK),
+        ("token-two", StatusCode::UNAUTHORIZED),
+        (token_three.as_str(), StatusCode::OK),
+    ] {
+        let (status, _, _) = send_request(
+            &test_app.app,
+            Method::GET,
+            "/opencode/session",
+            None,
+            &[("authorization", &bearer(token))],
+        )
+        .await;
+        assert_eq!(status, expected, "{token}");
+    }
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/admin/tokens",
+        None,
+        &[("authorization", &bearer(&token_three))],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let tokens = parse_json(&body);
+    assert_eq!(tokens["retiring"].as_array().map(Vec::len), Some(1));
+    assert!(!tokens.to_string().contains("token-two"));
+    let audit = tokens["audit"]
+        .as_array()
+        .expect("audit log")
+        .iter()
+        .map(|event| event["type"].as_str().unwrap_or_default())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        audit,
+        [
+            "auth.token.rotated",
+            "auth.token.rotated",
+            "auth.token.expired"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn metrics_endpoint_serves_prometheus_text() {
+    let test_app = TestApp::new(AuthConfig::with_token("secret-token".to_string()));
+
+    let (status, _, _) = send_request(&test_app.app, Method::GET, "/metrics", None, &[]).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, headers, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/metrics",
+        None,
+        &[("authorization", "Bearer secret-token")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/plain")));
+    let text = String::from_utf8(body).expect("utf8 body");
+    for name in [
+        "sandbox_agent_prompt_duration_seconds",
+        "sandbox_agent_acp_bootstrap_duration_seconds",
+        "sandbox_agent_sse_subscribers",
+        "sandbox_agent_sqlite_write_duration_seconds",
+        "sandbox_agent_broadcast_lagged_events_total",
+        "sandbox_agent_agent_restarts_total",
+        "sandbox_agent_sse_heartbeat_lag_seconds",
+        "sandbox_agent_opencode_schema_drift_total",
+        "sandbox_agent_turn_cpu_seconds",
+        "sandbox_agent_turn_disk_write_bytes_total",
+    ] {
+        assert!(text.contains(&format!("# TYPE {name} ")), "missing {name}");
+    }
+    assert!(text.contains("sandbox_agent_agent_restarts_total{agent=\"opencode\"} 0"));
+}
+
+#[tokio::test]
+async fn schema_endpoints_serve_exported_json_schemas() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/schema/universal-event.json",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let schema = parse_json(&body);
+    assert_eq!(schema["title"], "UniversalEvent");
+    assert!(schema["definitions"]["UniversalEventType"].is_object());
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/schema/problem-details.json",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(parse_json(&body)["title"], "ProblemDetails");
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/schema/opencode-event.json",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(parse_json(&body)["required"], json!(["properties", "type"]));
+
+    for path in ["/schema/nope.json", "/schema/universal-event"] {
+        let (status, _, _) = send_request(&test_app.app, Method::GET, path, None, &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{path}");
+    }
+}
+
 #[tokio::test]
 async fn v1_filesystem_endpoints_round_trip() {
     let test_app = TestApp::new(AuthConfig::disabled());
@@ -216,3 +540,930 @@ async fn lazy_install_runs_on_first_bootstrap() {
         .join("agent_processes/codex-acp")
         .exists());
 }
+
+#[tokio::test]
+async fn opencode_injected_clock_and_ids_make_payloads_reproducible() {
+    use sandbox_agent_opencode_adapter::{
+        build_opencode_router, FixedClock, OpenCodeAdapterConfig, SequentialIds,
+    };
+
+    let db_dir = tempfile::tempdir().expect("create temp db dir");
+    let app = build_opencode_router(OpenCodeAdapterConfig {
+        sqlite_path: Some(
+            db_dir
+                .path()
+                .join("opencode.db")
+                .to_string_lossy()
+                .into_owned(),
+        ),
+        clock: std::sync::Arc::new(FixedClock::new(1_700_000_000_000, 1)),
+        id_generator: std::sync::Arc::new(SequentialIds::starting_at(1)),
+        ..OpenCodeAdapterConfig::default()
+    })
+    .expect("build opencode router");
+
+    let (status, _, body) = send_request(
+        &app,
+        Method::POST,
+        "/session",
+        Some(json!({"title": "Snapshot"})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session = parse_json(&body);
+    assert_eq!(session["id"], "ses_1");
+    assert_eq!(
+        session["time"],
+        json!({"created": 1_700_000_000_001_i64, "updated": 1_700_000_000_001_i64})
+    );
+
+    let (status, _, body) = send_request(
+        &app,
+        Method::POST,
+        "/session/ses_1/message",
+        Some(json!({
+            "model": {"providerID": "mock", "modelID": "mock"},
+            "parts": [{"type": "text", "text": "hello"}]
+        })),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let mut reply = parse_json(&body);
+    reply["info"]
+        .as_object_mut()
+        .expect("message info")
+        .remove("path");
+    assert_eq!(
+        reply,
+        json!({
+            "info": {
+                "agent": "mock",
+                "cost": 0,
+                "finish": "stop",
+                "id": "msg_4_assistant",
+                "mode": "default",
+                "modelID": "mock",
+                "parentID": "msg_4",
+                "providerID": "mock",
+                "role": "assistant",
+                "sessionID": "ses_1",
+                "time": {"created": 1_700_000_000_005_i64, "completed": 1_700_000_000_005_i64},
+                "tokens": {
+                    "cache": {"read": 0, "write": 0},
+                    "input": 0,
+                    "output": 0,
+                    "reasoning": 0
+                }
+            },
+            "parts": [{
+                "id": "part_msg_4_assistant_0",
+                "messageID": "msg_4_assistant",
+                "sessionID": "ses_1",
+                "text": "hello",
+                "type": "text"
+            }]
+        })
+    );
+}
+
+#[tokio::test]
+async fn opencode_event_poll_returns_events_after_the_cursor() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/opencode/event/poll?waitMs=0",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let first = parse_json(&body);
+    assert_eq!(first["events"], json!([]));
+    let cursor = first["cursor"].as_u64().expect("cursor");
+
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/opencode/session",
+        Some(json!({})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        &format!("/opencode/event/poll?since={cursor}&waitMs=0"),
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let polled = parse_json(&body);
+    let types: Vec<&str> = polled["events"]
+        .as_array()
+        .expect("events")
+        .iter()
+        .filter_map(|event| event["type"].as_str())
+        .collect();
+    assert!(types.contains(&"session.created"), "{types:?}");
+    let cursor = polled["cursor"].as_u64().expect("cursor");
+
+    // A poll with nothing pending waits for the next event.
+    let app = test_app.app.clone();
+    let waiting = tokio::spawn(async move {
+        send_request(
+            &app,
+            Method::GET,
+            &format!("/opencode/event/poll?since={cursor}&waitMs=10000"),
+            None,
+            &[],
+        )
+        .await
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/opencode/session",
+        Some(json!({})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, body) = waiting.await.expect("poll task");
+    assert_eq!(status, StatusCode::OK);
+    let polled = parse_json(&body);
+    assert_eq!(polled["events"][0]["type"], "session.created");
+    assert!(polled["cursor"].as_u64().expect("cursor") > cursor);
+}
+
+#[tokio::test]
+async fn opencode_session_directory_can_be_changed() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+    let repo = tempfile::tempdir().expect("create repo dir");
+    let package = repo.path().join("packages/web");
+    fs::create_dir_all(&package).expect("create package dir");
+    let repo_dir = repo.path().to_string_lossy().into_owned();
+    let package_dir = package.to_string_lossy().into_owned();
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        &format!("/opencode/session?directory={repo_dir}"),
+        Some(json!({})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session = parse_json(&body);
+    assert_eq!(session["directory"], repo_dir.as_str());
+    let session_id = session["id"].as_str().expect("session id");
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::PATCH,
+        &format!("/opencode/session/{session_id}"),
+        Some(json!({"directory": package_dir})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(parse_json(&body)["directory"], package_dir.as_str());
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        &format!("/opencode/session/{session_id}"),
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(parse_json(&body)["directory"], package_dir.as_str());
+
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::PATCH,
+        &format!("/opencode/session/{session_id}"),
+        Some(json!({"directory": repo.path().join("missing").to_string_lossy()})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn opencode_client_logs_join_the_session_logs() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/opencode/session",
+        Some(json!({})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = parse_json(&body)["id"]
+        .as_str()
+        .expect("session id")
+        .to_string();
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/opencode/log",
+        Some(json!({
+            "service": "tui",
+            "level": "warn",
+            "message": "render took 120ms",
+            "extra": {"sessionID": session_id, "messageID": "msg_1"}
+        })),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(parse_json(&body), json!(true));
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        &format!("/opencode/session/{session_id}/logs?source=client"),
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let entries = parse_json(&body);
+    assert_eq!(entries.as_array().map(Vec::len), Some(1));
+    assert_eq!(entries[0]["level"], "warn");
+    assert_eq!(entries[0]["message"], "render took 120ms");
+    assert_eq!(entries[0]["fields"]["service"], "tui");
+    assert_eq!(
+        entries[0]["correlationID"],
+        format!("{session_id}/msg_1").as_str()
+    );
+
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/opencode/log",
+        Some(json!({"level": "verbose", "message": "x"})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn opencode_concurrent_prompts_queue_behind_the_running_turn() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/opencode/session",
+        Some(json!({})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = parse_json(&body)["id"]
+        .as_str()
+        .expect("session id")
+        .to_string();
+
+    let prompt_uri = format!("/opencode/session/{session_id}/prompt_async?queue=true");
+    let prompt = |text: &str| {
+        json!({
+            "model": {"providerID": "mock", "modelID": "mock"},
+            "parts": [{"type": "text", "text": text}]
+        })
+    };
+    let (first, second) = tokio::join!(
+        send_request(
+            &test_app.app,
+            Method::POST,
+            &prompt_uri,
+            Some(prompt("first")),
+            &[]
+        ),
+        send_request(
+            &test_app.app,
+            Method::POST,
+            &prompt_uri,
+            Some(prompt("second")),
+            &[]
+        ),
+    );
+    assert_eq!(first.0, StatusCode::ACCEPTED);
+    assert_eq!(second.0, StatusCode::ACCEPTED);
+
+    for (_, _, body) in [&first, &second] {
+        let turn_id = parse_json(body)["id"]
+            .as_str()
+            .expect("turn id")
+            .to_string();
+        let mut status = String::new();
+        for _ in 0..100 {
+            let (_, _, body) = send_request(
+                &test_app.app,
+                Method::GET,
+                &format!("/opencode/session/{session_id}/turn/{turn_id}"),
+                None,
+                &[],
+            )
+            .await;
+            status = parse_json(&body)["status"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            if status == "completed" {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(status, "completed");
+    }
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        &format!("/opencode/session/{session_id}/message"),
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let roles: Vec<String> = parse_json(&body)
+        .as_array()
+        .expect("messages")
+        .iter()
+        .map(|message| {
+            message["info"]["role"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string()
+        })
+        .collect();
+    assert_eq!(roles, ["user", "assistant", "user", "assistant"]);
+}
+
+#[tokio::test]
+async fn opencode_prompt_attachments_are_sniffed() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/opencode/session",
+        Some(json!({})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = parse_json(&body)["id"]
+        .as_str()
+        .expect("session id")
+        .to_string();
+
+    // A PNG header declared as an opaque binary.
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::POST,
+        &format!("/opencode/session/{session_id}/message"),
+        Some(json!({
+            "model": {"providerID": "mock", "modelID": "mock"},
+            "parts": [
+                {"type": "text", "text": "what is this?"},
+                {
+                    "type": "file",
+                    "mime": "application/octet-stream",
+                    "filename": "shot",
+                    "url": "data:application/octet-stream;base64,iVBORw0KGgoAAAANSUhEUg=="
+                }
+            ]
+        })),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        &format!("/opencode/session/{session_id}/message"),
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let messages = parse_json(&body);
+    let file = messages[0]["parts"]
+        .as_array()
+        .expect("user parts")
+        .iter()
+        .find(|part| part["type"] == "file")
+        .expect("file part")
+        .clone();
+    assert_eq!(file["mime"], "image/png");
+    assert!(file["url"]
+        .as_str()
+        .expect("url")
+        .starts_with("data:image/png;base64,"));
+
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::POST,
+        &format!("/opencode/session/{session_id}/message"),
+        Some(json!({
+            "model": {"providerID": "mock", "modelID": "mock"},
+            "parts": [{"type": "file", "mime": "image/png", "url": "data:image/png;base64,@@@"}]
+        })),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn opencode_event_select_projects_each_event() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/opencode/event/poll?waitMs=0",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let cursor = parse_json(&body)["cursor"].as_u64().expect("cursor");
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/opencode/session",
+        Some(json!({"title": "Selected"})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = parse_json(&body)["id"].clone();
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        &format!(
+            "/opencode/event/poll?since={cursor}&waitMs=0&select=/properties/info/id,/properties/info/title"
+        ),
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let polled = parse_json(&body);
+    let created = polled["events"]
+        .as_array()
+        .expect("events")
+        .iter()
+        .find(|event| event["type"] == "session.created")
+        .expect("session.created")
+        .clone();
+    assert_eq!(
+        created,
+        json!({
+            "type": "session.created",
+            "properties": {"info": {"id": session_id, "title": "Selected"}}
+        })
+    );
+
+    for uri in [
+        "/opencode/event?select=properties",
+        "/opencode/event/poll?waitMs=0&select=properties",
+    ] {
+        let (status, _, _) = send_request(&test_app.app, Method::GET, uri, None, &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn opencode_turn_timeline_reports_the_finished_turn() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/opencode/session",
+        Some(json!({})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = parse_json(&body)["id"]
+        .as_str()
+        .expect("session id")
+        .to_string();
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        &format!("/opencode/session/{session_id}/prompt_async"),
+        Some(json!({
+            "model": {"providerID": "mock", "modelID": "mock"},
+            "parts": [{"type": "text", "text": "hello"}]
+        })),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let turn_id = parse_json(&body)["id"]
+        .as_str()
+        .expect("turn id")
+        .to_string();
+
+    let mut timeline = Value::Null;
+    for _ in 0..100 {
+        let (status, _, body) = send_request(
+            &test_app.app,
+            Method::GET,
+            &format!("/opencode/session/{session_id}/turn/{turn_id}/timeline"),
+            None,
+            &[],
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        timeline = parse_json(&body);
+        if timeline["status"] == "completed" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(timeline["status"], "completed");
+    assert_eq!(timeline["turnID"], turn_id.as_str());
+    assert_eq!(timeline["sessionID"], session_id.as_str());
+    assert_eq!(timeline["tools"], json!([]));
+    assert_eq!(timeline["toolMs"], 0);
+    assert!(timeline["time"]["durationMs"].as_i64().is_some());
+
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::GET,
+        &format!("/opencode/session/{session_id}/turn/turn_missing/timeline"),
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn opencode_always_permission_reply_auto_approves_matching_requests() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/opencode/session",
+        Some(json!({})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = parse_json(&body)["id"]
+        .as_str()
+        .expect("session id")
+        .to_string();
+    let prompt = json!({
+        "model": {"providerID": "mock", "modelID": "mock"},
+        "parts": [{"type": "text", "text": "permission"}]
+    });
+
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::POST,
+        &format!("/opencode/session/{session_id}/message"),
+        Some(prompt.clone()),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/opencode/permission",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let pending = parse_json(&body);
+    let request = pending
+        .as_array()
+        .expect("permission list")
+        .iter()
+        .find(|request| request["sessionID"] == session_id.as_str())
+        .expect("pending permission");
+    assert_eq!(request["patterns"], json!(["echo permission"]));
+    assert_eq!(request["always"], json!(["echo permission *"]));
+    let request_id = request["id"].as_str().expect("request id").to_string();
+
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::POST,
+        &format!("/opencode/permission/{request_id}/reply"),
+        Some(json!({"reply": "always"})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // The same command is approved server-side and never listed as pending.
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::POST,
+        &format!("/opencode/session/{session_id}/message"),
+        Some(prompt),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/opencode/permission",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(pending_permissions(&body, &session_id), 0);
+}
+
+#[tokio::test]
+async fn opencode_dry_run_sessions_deny_side_effecting_tools() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/opencode/session",
+        Some(json!({"dryRun": true})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session = parse_json(&body);
+    assert_eq!(session["dryRun"], true);
+    let session_id = session["id"].as_str().expect("session id").to_string();
+    let prompt = json!({
+        "model": {"providerID": "mock", "modelID": "mock"},
+        "parts": [{"type": "text", "text": "permission"}]
+    });
+
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::POST,
+        &format!("/opencode/session/{session_id}/message"),
+        Some(prompt.clone()),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/opencode/permission",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(pending_permissions(&body, &session_id), 0);
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/opencode/event/poll?since=0",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let events = parse_json(&body)["events"].clone();
+    let replied = events
+        .as_array()
+        .expect("events")
+        .iter()
+        .find(|event| {
+            event["type"] == "permission.replied" && event["properties"]["sessionID"] == session_id
+        })
+        .expect("permission.replied");
+    assert_eq!(replied["properties"]["reply"], "reject");
+    assert_eq!(replied["properties"]["dryRun"], true);
+    assert!(replied["properties"]["message"]
+        .as_str()
+        .is_some_and(|message| message.contains("dry-run")));
+
+    // Leaving dry-run mode asks again.
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::PATCH,
+        &format!("/opencode/session/{session_id}"),
+        Some(json!({"dryRun": false})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(parse_json(&body).get("dryRun").is_none());
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::POST,
+        &format!("/opencode/session/{session_id}/message"),
+        Some(prompt),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/opencode/permission",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(pending_permissions(&body, &session_id), 1);
+}
+
+fn pending_permissions(body: &[u8], session_id: &str) -> usize {
+    parse_json(body)
+        .as_array()
+        .expect("permission list")
+        .iter()
+        .filter(|request| request["sessionID"] == session_id)
+        .count()
+}
+
+#[tokio::test]
+async fn startup_credential_validation_reports_status_per_agent() {
+    use axum::routing::get;
+    use sandbox_agent::router::CredentialCheckConfig;
+    use sandbox_agent_agent_credentials::{AuthType, ExtractedCredentials, ProviderCredentials};
+
+    let provider = Router::new()
+        .route(
+            "/v1/models",
+            get(|headers: HeaderMap| async move {
+                if headers.get("x-api-key").and_then(|v| v.to_str().ok()) == Some("sk-ant-good") {
+                    (StatusCode::OK, axum::Json(json!({"data": []})))
+                } else {
+                    (
+                        StatusCode::UNAUTHORIZED,
+                        axum::Json(json!({"error": {"message": "invalid x-api-key"}})),
+                    )
+                }
+            }),
+        )
+        .route(
+            "/openai/v1/models",
+            get(|| async {
+                (
+                    StatusCode::UNAUTHORIZED,
+                    axum::Json(json!({"error": {"message": "Incorrect API key provided"}})),
+                )
+            }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind provider");
+    let address = listener.local_addr().expect("provider address");
+    tokio::spawn(async move {
+        axum::serve(listener, provider)
+            .await
+            .expect("serve provider");
+    });
+
+    let api_key = |provider: &str, key: &str| ProviderCredentials {
+        api_key: key.to_string(),
+        source: "test".to_string(),
+        auth_type: AuthType::ApiKey,
+        provider: provider.to_string(),
+    };
+    let credentials = ExtractedCredentials {
+        anthropic: Some(api_key("anthropic", "sk-ant-good")),
+        openai: Some(api_key("openai", "sk-bad")),
+        ..Default::default()
+    };
+    let install_dir = tempfile::tempdir().expect("create temp install dir");
+    let manager = AgentManager::new(install_dir.path()).expect("create agent manager");
+    let state = AppState::new(AuthConfig::disabled(), manager);
+    state
+        .validate_agent_credentials(
+            &credentials,
+            &CredentialCheckConfig {
+                anthropic_base_url: format!("http://{address}"),
+                openai_base_url: format!("http://{address}/openai/v1"),
+                timeout: Duration::from_secs(5),
+            },
+        )
+        .await;
+    let app = build_router(state);
+
+    let (status, _, body) = send_request(&app, Method::GET, "/v1/agents", None, &[]).await;
+    assert_eq!(status, StatusCode::OK);
+    let agents = parse_json(&body)["agents"].clone();
+    let check = |id: &str| {
+        agents
+            .as_array()
+            .expect("agents")
+            .iter()
+            .find(|agent| agent["id"] == id)
+            .expect("agent listed")["credentialCheck"]
+            .clone()
+    };
+    assert_eq!(check("claude")["status"], "valid");
+    assert_eq!(check("codex")["status"], "invalid");
+    assert_eq!(
+        check("codex")["message"],
+        "HTTP 401: Incorrect API key provided"
+    );
+    assert_eq!(check("opencode")["status"], "valid");
+    assert_eq!(check("opencode")["provider"], "anthropic");
+    assert!(check("mock").is_null());
+
+    let (status, _, body) = send_request(&app, Method::GET, "/v1/health", None, &[]).await;
+    assert_eq!(status, StatusCode::OK);
+    let health = parse_json(&body);
+    assert_eq!(health["status"], "ok");
+    assert_eq!(health["agentCredentials"]["amp"]["status"], "valid");
+    assert_eq!(health["agentCredentials"]["codex"]["status"], "invalid");
+
+    let unchecked = TestApp::new(AuthConfig::disabled());
+    let (_, _, body) = send_request(&unchecked.app, Method::GET, "/v1/health", None, &[]).await;
+    assert!(parse_json(&body).get("agentCredentials").is_none());
+}
+
+#[tokio::test]
+async fn opencode_database_lock_refuses_second_server_until_takeover() {
+    use sandbox_agent_opencode_adapter::{DatabaseLock, DatabaseLockError};
+
+    let dir = tempfile::tempdir().expect("tempdir");
+    let db_path = dir.path().join("opencode.db").to_string_lossy().to_string();
+    let app_with_lock = |lock: DatabaseLock| {
+        let install_dir = tempfile::tempdir().expect("create temp install dir");
+        let manager = AgentManager::new(install_dir.path()).expect("create agent manager");
+        build_router(
+            AppState::new(AuthConfig::disabled(), manager).with_opencode_database_lock(lock),
+        )
+    };
+
+    let first = app_with_lock(DatabaseLock::acquire(&db_path).expect("lock database"));
+    let (status, _, _) = send_request(
+        &first,
+        Method::POST,
+        "/opencode/session",
+        Some(json!({})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    match DatabaseLock::acquire(&db_path) {
+        Err(DatabaseLockError::Held { owner, .. }) => assert_eq!(owner.pid, std::process::id()),
+        other => panic!("expected the database to be held, got {other:?}"),
+    }
+
+    let takeover_path = db_path.clone();
+    let second_lock = tokio::task::spawn_blocking(move || {
+        DatabaseLock::acquire_with_takeover(&takeover_path, Some(Duration::from_secs(10)))
+    })
+    .await
+    .expect("join takeover")
+    .expect("take database over");
+    let second = app_with_lock(second_lock);
+
+    let (status, _, body) = send_request(
+        &first,
+        Method::POST,
+        "/opencode/session",
+        Some(json!({})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(String::from_utf8_lossy(&body).contains("taken over"));
+
+    let (status, _, body) =
+        send_request(&second, Method::GET, "/opencode/session", None, &[]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(parse_json(&body).as_array().map(Vec::len), Some(1));
+}
@@ -0,0 +1,173 @@
+use std::sync::{Arc, Mutex};
+
+use sandbox_agent::router::{EventSinkConfig, EventSinkTarget};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use super::*;
+
+/// Accept webhook deliveries and record each one's ordering key and body.
+async fn serve_webhook(received: Arc<Mutex<Vec<(String, Value)>>>) -> String {
+    let app = Router::new().route(
+        "/events",
+        axum::routing::post(move |headers: HeaderMap, body: axum::body::Bytes| {
+            let received = received.clone();
+            async move {
+                let key = headers
+                    .get("x-sandbox-agent-ordering-key")
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+                let event: Value = serde_json::from_slice(&body).expect("event body");
+                received.lock().unwrap().push((key, event));
+                StatusCode::NO_CONTENT
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind webhook");
+    let addr = listener.local_addr().expect("webhook addr");
+    tokio::spawn(async move {
+        let _ = axum::serve(listener, app).await;
+    });
+    format!("http://{addr}/events")
+}
+
+/// A verbose NATS server that acknowledges everything and records the
+/// subject of each `PUB`.
+async fn serve_nats(subjects: Arc<Mutex<Vec<String>>>) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind nats");
+    let addr = listener.local_addr().expect("nats addr");
+    tokio::spawn(async move {
+        let Ok((stream, _)) = listener.accept().await else {
+            return;
+        };
+        let mut stream = BufReader::new(stream);
+        let _ = stream.get_mut().write_all(b"INFO {}\r\n").await;
+        let mut line = String::new();
+        while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+            if let Some(publish) = line.strip_prefix("PUB ") {
+                let subject = publish.split(' ').next().unwrap_or_default();
+                subjects.lock().unwrap().push(subject.to_string());
+                let mut payload = String::new();
+                let _ = stream.read_line(&mut payload).await;
+            }
+            line.clear();
+            let _ = stream.get_mut().write_all(b"+OK\r\n").await;
+        }
+    });
+    format!("nats://{addr}")
+}
+
+#[tokio::test]
+async fn universal_events_are_published_to_sinks() {
+    let webhook_events = Arc::new(Mutex::new(Vec::new()));
+    let nats_subjects = Arc::new(Mutex::new(Vec::new()));
+    let webhook_url = serve_webhook(webhook_events.clone()).await;
+    let nats_url = serve_nats(nats_subjects.clone()).await;
+
+    let install_dir = tempfile::tempdir().expect("create temp install dir");
+    let manager = AgentManager::new(install_dir.path()).expect("create agent manager");
+    let state = AppState::new(AuthConfig::disabled(), manager).with_event_sinks(EventSinkConfig {
+        sinks: vec![
+            EventSinkTarget::Webhook {
+                url: webhook_url,
+                secret: Some("sink-secret".to_string()),
+            },
+            EventSinkTarget::Nats {
+                url: nats_url,
+                subject: "sandbox.events".to_string(),
+            },
+            EventSinkTarget::Redis {
+                url: "redis://127.0.0.1:1".to_string(),
+                stream: "sandbox-events".to_string(),
+            },
+        ],
+        max_attempts: 2,
+        initial_backoff: Duration::from_millis(10),
+        ..EventSinkConfig::default()
+    });
+    let app = build_router(state);
+
+    let (status, _, body) = send_request(
+        &app,
+        Method::POST,
+        "/opencode/session",
+        Some(json!({})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = parse_json(&body)["id"]
+        .as_str()
+        .expect("session id")
+        .to_string();
+    let (status, _, _) = send_request(
+        &app,
+        Method::POST,
+        &format!("/opencode/session/{session_id}/message"),
+        Some(json!({
+            "model": {"providerID": "mock", "modelID": "mock"},
+            "parts": [{"type": "text", "text": "hello"}]
+        })),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let mut health = Value::Null;
+    for _ in 0..200 {
+        let (_, _, body) = send_request(&app, Method::GET, "/v1/health", None, &[]).await;
+        health = parse_json(&body);
+        let settled = health["eventSinks"]
+            .as_array()
+            .is_some_and(|sinks| sinks[2]["deadLetters"].as_u64() > Some(0))
+            && health["eventSinks"][0]["delivered"] == health["eventSinks"][1]["delivered"]
+            && health["eventSinks"][0]["pending"] == 0;
+        if settled {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let sinks = health["eventSinks"].as_array().expect("event sink health");
+    assert_eq!(sinks[0]["type"], "webhook");
+    assert_eq!(sinks[0]["status"], "healthy");
+    assert_eq!(sinks[1]["type"], "nats");
+    assert_eq!(sinks[1]["destination"], "sandbox.events");
+    assert_eq!(sinks[2]["type"], "redis");
+    assert_eq!(sinks[2]["status"], "failing");
+    assert!(sinks[2]["lastError"].is_string());
+
+    // Every sink sees the same universal events, in sequence order.
+    let received = webhook_events.lock().unwrap().clone();
+    assert!(!received.is_empty());
+    assert_eq!(sinks[0]["delivered"], received.len() as u64);
+    for (index, (key, event)) in received.iter().enumerate() {
+        assert_eq!(key, &session_id);
+        assert_eq!(event["session_id"], session_id.as_str());
+        assert_eq!(event["sequence"], index as u64 + 1);
+    }
+    assert!(received
+        .iter()
+        .any(|(_, event)| event["type"] == "item.completed"));
+    let subjects = nats_subjects.lock().unwrap().clone();
+    assert_eq!(subjects.len(), received.len());
+    assert!(subjects
+        .iter()
+        .all(|subject| subject == &format!("sandbox.events.{session_id}")));
+
+    // Events the unreachable sink gave up on are kept for inspection.
+    let (status, _, body) =
+        send_request(&app, Method::GET, "/v1/event-sinks/dead-letters", None, &[]).await;
+    assert_eq!(status, StatusCode::OK);
+    let dead = parse_json(&body);
+    assert_eq!(dead["sinks"][2]["type"], "redis");
+    let first = &dead["sinks"][2]["events"][0];
+    assert_eq!(first["event"]["sequence"], 1);
+    assert_eq!(first["attempts"], 2);
+    assert!(dead["sinks"][0]["events"]
+        .as_array()
+        .is_some_and(Vec::is_empty));
+}
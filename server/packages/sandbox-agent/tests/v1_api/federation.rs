@@ -0,0 +1,181 @@
+use super::*;
+use sandbox_agent::router::{FederatedSandbox, FederationConfig};
+
+/// Serve `app` on an ephemeral local port and return its base URL.
+async fn serve(app: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind downstream");
+    let addr = listener.local_addr().expect("downstream addr");
+    tokio::spawn(async move {
+        let _ = axum::serve(listener, app).await;
+    });
+    format!("http://{addr}")
+}
+
+fn controller(sandboxes: Vec<FederatedSandbox>) -> (Router, TempDir) {
+    let install_dir = tempfile::tempdir().expect("create temp install dir");
+    let manager = AgentManager::new(install_dir.path()).expect("create agent manager");
+    let state = AppState::new(AuthConfig::disabled(), manager).with_federation(FederationConfig {
+        sandboxes,
+        timeout: Duration::from_secs(2),
+        ..FederationConfig::default()
+    });
+    (build_router(state), install_dir)
+}
+
+#[tokio::test]
+async fn federation_controller_fronts_downstream_sandboxes() {
+    let downstream = TestApp::new(AuthConfig::disabled());
+    let url = serve(downstream.app.clone()).await;
+    let (app, _install_dir) = controller(vec![
+        format!("east={url}/").parse().expect("sandbox"),
+        "down=http://127.0.0.1:1".parse().expect("sandbox"),
+    ]);
+
+    let (status, _, body) =
+        send_request(&app, Method::GET, "/v1/federation/sandboxes", None, &[]).await;
+    assert_eq!(status, StatusCode::OK);
+    let sandboxes = parse_json(&body)["sandboxes"].clone();
+    assert_eq!(sandboxes[0]["name"], "east");
+    assert_eq!(sandboxes[0]["url"], url.as_str());
+    assert_eq!(sandboxes[0]["status"], "healthy");
+    assert!(sandboxes[0]["health"].is_object());
+    assert_eq!(sandboxes[1]["name"], "down");
+    assert_eq!(sandboxes[1]["status"], "unreachable");
+
+    // Requests under the prefix are proxied, bodies and all.
+    let (status, _, body) = send_request(
+        &app,
+        Method::POST,
+        "/v1/federation/sandboxes/east/opencode/session",
+        Some(json!({"title": "federated"})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = parse_json(&body)["id"]
+        .as_str()
+        .expect("session id")
+        .to_string();
+
+    let (status, _, body) =
+        send_request(&app, Method::GET, "/v1/federation/sessions", None, &[]).await;
+    assert_eq!(status, StatusCode::OK);
+    let listed = parse_json(&body);
+    let session = listed["sessions"]
+        .as_array()
+        .expect("sessions")
+        .iter()
+        .find(|session| session["id"] == session_id.as_str())
+        .expect("federated session listed");
+    assert_eq!(session["sandbox"], "east");
+    assert_eq!(
+        session["path"],
+        format!("/v1/federation/sandboxes/east/opencode/session/{session_id}")
+    );
+    assert_eq!(listed["errors"][0]["sandbox"], "down");
+
+    let (status, _, body) = send_request(
+        &app,
+        Method::GET,
+        &format!("/v1/federation/sandboxes/east/opencode/session/{session_id}"),
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(parse_json(&body)["title"], "federated");
+
+    let (status, _, _) = send_request(
+        &app,
+        Method::GET,
+        "/v1/federation/sandboxes/west/v1/health",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn federation_event_stream_merges_and_tags_sandbox_events() {
+    let downstream = TestApp::new(AuthConfig::disabled());
+    let url = serve(downstream.app.clone()).await;
+    let (app, _install_dir) = controller(vec![
+        format!("east={url}").parse().expect("sandbox"),
+        "down=http://127.0.0.1:1".parse().expect("sandbox"),
+    ]);
+
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri("/v1/federation/event")
+        .body(Body::empty())
+        .expect("build request");
+    let response = app.clone().oneshot(request).await.expect("sse response");
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut stream = response.into_body().into_data_stream();
+    let mut text = String::new();
+    let events = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let chunk = stream.next().await.expect("stream open").expect("chunk");
+            text.push_str(&String::from_utf8_lossy(&chunk));
+            let events: Vec<Value> = text
+                .split("\n\n")
+                .filter(|block| block.contains("data:"))
+                .map(parse_sse_data)
+                .collect();
+            let from = |sandbox: &str| events.iter().any(|event| event["sandbox"] == sandbox);
+            if from("east") && from("down") {
+                return events;
+            }
+        }
+    })
+    .await
+    .expect("events from both sandboxes");
+
+    let down = events
+        .iter()
+        .find(|event| event["sandbox"] == "down")
+        .expect("down event");
+    assert_eq!(down["type"], "federation.sandbox_disconnected");
+    let east = events
+        .iter()
+        .find(|event| event["sandbox"] == "east")
+        .expect("east event");
+    assert!(east["type"].is_string());
+}
+
+#[tokio::test]
+async fn federation_routes_are_absent_without_sandboxes() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/v1/federation/sandboxes",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn federated_sandboxes_parse_from_name_and_url() {
+    let sandbox: FederatedSandbox = "eu-1=https://eu.example.com/".parse().expect("sandbox");
+    assert_eq!(sandbox.name, "eu-1");
+    assert_eq!(sandbox.url, "https://eu.example.com");
+    assert!("eu.example.com".parse::<FederatedSandbox>().is_err());
+    assert!("eu/1=https://eu.example.com"
+        .parse::<FederatedSandbox>()
+        .is_err());
+    assert!("eu=ftp://eu.example.com"
+        .parse::<FederatedSandbox>()
+        .is_err());
+
+    let duplicate = FederationConfig {
+        sandboxes: vec![sandbox.clone(), sandbox],
+        ..FederationConfig::default()
+    };
+    assert!(duplicate.validate().is_err());
+}
@@ -0,0 +1,131 @@
+use super::*;
+
+fn ndjson(bytes: &[u8]) -> Vec<Value> {
+    String::from_utf8_lossy(bytes)
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("ndjson line"))
+        .collect()
+}
+
+#[tokio::test]
+async fn session_transcripts_render_the_event_log_as_ndjson() {
+    let test_app = TestApp::new(AuthConfig::disabled());
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::POST,
+        "/opencode/session",
+        Some(json!({})),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let session_id = parse_json(&body)["id"]
+        .as_str()
+        .expect("session id")
+        .to_string();
+
+    // A tail opened before the turn sees it as it happens.
+    let request = Request::builder()
+        .uri(format!(
+            "/opencode/session/{session_id}/transcript.ndjson?follow=true"
+        ))
+        .body(Body::empty())
+        .expect("build request");
+    let response = test_app
+        .app
+        .clone()
+        .oneshot(request)
+        .await
+        .expect("request handled");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "application/x-ndjson"
+    );
+    let mut tail = response.into_body().into_data_stream();
+
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::POST,
+        &format!("/opencode/session/{session_id}/message"),
+        Some(json!({
+            "model": {"providerID": "mock", "modelID": "mock"},
+            "parts": [{"type": "text", "text": "hello"}]
+        })),
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let mut followed = Vec::new();
+    let mut buffer = Vec::new();
+    while !followed
+        .iter()
+        .any(|event: &Value| event["type"] == "turn.ended")
+    {
+        let chunk = tokio::time::timeout(Duration::from_secs(10), tail.next())
+            .await
+            .expect("transcript event")
+            .expect("transcript open")
+            .expect("transcript chunk");
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+            let line = buffer.drain(..=end).collect::<Vec<_>>();
+            followed.push(serde_json::from_slice::<Value>(&line).expect("ndjson line"));
+        }
+    }
+    for (index, event) in followed.iter().enumerate() {
+        assert_eq!(event["session_id"], session_id.as_str());
+        assert_eq!(event["sequence"], index as u64 + 1);
+    }
+    assert_eq!(followed[0]["type"], "turn.started");
+
+    // Without `follow`, the events so far after `offset` are returned, the
+    // same as they were streamed.
+    let (status, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        &format!("/opencode/session/{session_id}/transcript.ndjson"),
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let transcript = ndjson(&body);
+    assert_eq!(transcript, followed);
+    let (_, _, body) = send_request(
+        &test_app.app,
+        Method::GET,
+        &format!("/opencode/session/{session_id}/transcript.ndjson?offset=2"),
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(ndjson(&body), transcript[2..]);
+
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::GET,
+        "/opencode/session/ses_missing/transcript.ndjson?follow=true",
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Deleting the session ends the tail.
+    let (status, _, _) = send_request(
+        &test_app.app,
+        Method::DELETE,
+        &format!("/opencode/session/{session_id}"),
+        None,
+        &[],
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let end = tokio::time::timeout(Duration::from_secs(10), async {
+        while tail.next().await.is_some() {}
+    })
+    .await;
+    assert!(end.is_ok());
+}